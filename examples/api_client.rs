@@ -0,0 +1,96 @@
+//! Reads the stats of a cache, then purges its entries matching a glob and
+//! waits for the purge job to finish:
+//!
+//! ```sh
+//! MIRROR_CACHE_TOKEN=<admin token> cargo run --example api_client -- \
+//!     http://localhost:9000 policy_lru 'pypi/packages/**/flask-*'
+//! ```
+
+use mirror_cache::api::{ApiError, CacheStats, JobAccepted, JobState, JobStatus, BASE_PATH};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Client of the management API of a mirror
+struct Client {
+    http: reqwest::Client,
+    /// e.g. `http://localhost:9000`
+    base_url: String,
+    token: String,
+}
+
+impl Client {
+    async fn send<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> Result<T> {
+        let resp = request.bearer_auth(&self.token).send().await?;
+        let status = resp.status();
+        let body = resp.bytes().await?;
+        if !status.is_success() {
+            let error: ApiError = serde_json::from_slice(&body)?;
+            return Err(format!(
+                "{}: {} {}",
+                status,
+                error.error,
+                error.detail.unwrap_or_default()
+            )
+            .into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}{}", self.base_url, BASE_PATH, path)
+    }
+
+    async fn stats(&self, cache: &str) -> Result<CacheStats> {
+        let url = self.url(&format!("/caches/{}/stats", cache));
+        self.send(self.http.get(url)).await
+    }
+
+    async fn purge(&self, cache: &str, pattern: &str) -> Result<JobAccepted> {
+        let url = self.url(&format!("/caches/{}/entries", cache));
+        self.send(self.http.delete(url).query(&[("pattern", pattern)]))
+            .await
+    }
+
+    /// The status of a job at `location`, e.g. `/api/v1/jobs/1`
+    async fn job(&self, location: &str) -> Result<JobStatus> {
+        let url = format!("{}{}", self.base_url, location);
+        self.send(self.http.get(url)).await
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: {} <mirror url> <cache> <glob of keys>", args[0]);
+        std::process::exit(2);
+    }
+    let client = Client {
+        http: reqwest::Client::new(),
+        base_url: args[1].trim_end_matches('/').to_string(),
+        token: std::env::var("MIRROR_CACHE_TOKEN")?,
+    };
+    let (cache, pattern) = (&args[2], &args[3]);
+
+    let stats = client.stats(cache).await?;
+    println!(
+        "{}: {} entries, {} bytes",
+        stats.cache, stats.entries, stats.bytes
+    );
+
+    let accepted = client.purge(cache, pattern).await?;
+    println!("purging {} of {} in job #{}", pattern, cache, accepted.job);
+    loop {
+        let job = client.job(&accepted.location).await?;
+        match job.state {
+            JobState::Running => tokio::time::sleep(Duration::from_millis(500)).await,
+            JobState::Completed => {
+                println!("{} of {} entries purged", job.removed, job.scanned);
+                return Ok(());
+            }
+            JobState::Failed(e) => return Err(e.into()),
+        }
+    }
+}
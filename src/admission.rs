@@ -0,0 +1,141 @@
+//! Admission filter in front of the cache: an object is cached only once it
+//! has been requested often enough, so that one-hit wonders do not evict
+//! popular entries. Request frequencies are estimated with a count-min sketch
+//! whose counters are halved every window, like TinyLFU.
+
+use crate::settings::Admission;
+use crate::util;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Secs after which request counts are halved
+pub const DEFAULT_WINDOW: u64 = 3600;
+/// Number of counters of each row of the sketch
+const SKETCH_WIDTH: usize = 1 << 14;
+/// Number of rows of the sketch, i.e. of hash functions
+const SKETCH_DEPTH: usize = 4;
+
+/// Count-min sketch of request frequencies, with saturating 8-bit counters
+struct Sketch {
+    counters: Vec<u8>,
+    /// When the counters are halved next
+    next_aging: Instant,
+}
+
+impl Sketch {
+    fn slots(key: &str) -> [usize; SKETCH_DEPTH] {
+        let hash = util::fnv1a_64(key.as_bytes());
+        // double hashing: the rows use h1 + i * h2
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        let mut slots = [0; SKETCH_DEPTH];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            *slot = row * SKETCH_WIDTH + h1.wrapping_add(row.wrapping_mul(h2)) % SKETCH_WIDTH;
+        }
+        slots
+    }
+
+    /// Count a request of `key`, and return its estimated frequency
+    fn increment(&mut self, key: &str) -> u8 {
+        let slots = Self::slots(key);
+        let estimate = slots.iter().map(|&s| self.counters[s]).min().unwrap_or(0);
+        // conservative update: only the smallest counters are incremented
+        for &slot in &slots {
+            if self.counters[slot] == estimate {
+                self.counters[slot] = estimate.saturating_add(1);
+            }
+        }
+        estimate.saturating_add(1)
+    }
+
+    fn age(&mut self) {
+        for counter in self.counters.iter_mut() {
+            *counter /= 2;
+        }
+    }
+}
+
+/// Admission filter of a rule
+pub struct AdmissionFilter {
+    admit_after: u32,
+    window: Duration,
+    sketch: Mutex<Sketch>,
+}
+
+impl AdmissionFilter {
+    pub fn new(admit_after: u32, window: Duration) -> Self {
+        Self {
+            admit_after,
+            window,
+            sketch: Mutex::new(Sketch {
+                counters: vec![0; SKETCH_WIDTH * SKETCH_DEPTH],
+                next_aging: Instant::now() + window,
+            }),
+        }
+    }
+
+    pub fn from_settings(admission: &Admission) -> Self {
+        Self::new(
+            admission.admit_after,
+            Duration::from_secs(admission.window.unwrap_or(DEFAULT_WINDOW)),
+        )
+    }
+
+    /// Count a request of `key`, and return whether it may be cached
+    pub fn admit(&self, key: &str) -> bool {
+        self.admit_at(key, Instant::now())
+    }
+
+    fn admit_at(&self, key: &str, now: Instant) -> bool {
+        let mut sketch = self.sketch.lock().unwrap();
+        if now >= sketch.next_aging {
+            sketch.age();
+            sketch.next_aging = now + self.window;
+        }
+        sketch.increment(key) as u32 >= self.admit_after
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn admit_after_requests() {
+        let filter = AdmissionFilter::new(2, Duration::from_secs(3600));
+        assert!(!filter.admit("a/1"));
+        assert!(!filter.admit("b/1"));
+        assert!(filter.admit("a/1"));
+        assert!(filter.admit("a/1"));
+        assert!(filter.admit("b/1"));
+        assert!(!filter.admit("c/1"));
+
+        let filter = AdmissionFilter::new(1, Duration::from_secs(3600));
+        assert!(filter.admit("a/1"));
+    }
+
+    #[test]
+    fn counters_are_halved_every_window() {
+        let window = Duration::from_secs(60);
+        let filter = AdmissionFilter::new(3, window);
+        let start = Instant::now();
+        assert!(!filter.admit_at("a", start));
+        assert!(!filter.admit_at("a", start));
+        // 2 requests are halved to 1
+        assert!(!filter.admit_at("a", start + window));
+        assert!(filter.admit_at("a", start + window));
+        // a single request is forgotten
+        assert!(!filter.admit_at("b", start + window));
+        assert!(!filter.admit_at("b", start + window * 2));
+        assert!(!filter.admit_at("b", start + window * 2));
+    }
+
+    #[test]
+    fn counters_saturate() {
+        let filter = AdmissionFilter::new(2, Duration::from_secs(3600));
+        for _ in 0..1000 {
+            filter.admit("a");
+        }
+        assert!(filter.admit("a"));
+        assert!(!filter.admit("b"));
+    }
+}
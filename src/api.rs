@@ -0,0 +1,503 @@
+//! Requests and responses of the management API served under `/api/v1/`.
+//! Clients depend on the crate for these types. Each type describes its
+//! fields in the spec served at `/api/v1/spec`, see [`spec`].
+
+use std::collections::BTreeMap;
+
+/// Version of the management API
+pub const VERSION: &str = "v1";
+
+/// Path prefix of the management API
+pub const BASE_PATH: &str = "/api/v1";
+
+pub type JobId = u64;
+
+/// A field of a type in the spec, e.g. `key: String`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldSpec {
+    pub name: String,
+    /// The Rust type of the field
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub optional: bool,
+}
+
+impl FieldSpec {
+    fn new(name: &str, typ: &str) -> Self {
+        let typ = typ.replace(' ', "");
+        Self {
+            name: name.to_string(),
+            optional: typ.starts_with("Option<"),
+            typ,
+        }
+    }
+}
+
+impl Schema for FieldSpec {
+    fn name() -> &'static str {
+        "FieldSpec"
+    }
+
+    fn fields() -> Vec<FieldSpec> {
+        vec![
+            FieldSpec::new("name", "String"),
+            FieldSpec::new("type", "String"),
+            FieldSpec::new("optional", "bool"),
+        ]
+    }
+}
+
+/// A type of the API that describes its fields
+pub trait Schema {
+    fn name() -> &'static str;
+    fn fields() -> Vec<FieldSpec>;
+}
+
+/// Declare structs of the API, and implement `Schema` from their fields so
+/// that the spec never drifts from the types
+macro_rules! api_structs {
+    ($(
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                pub $field:ident: $ty:ty,
+            )*
+        }
+    )*) => {
+        $(
+            $(#[$attr])*
+            pub struct $name {
+                $(
+                    $(#[$field_attr])*
+                    pub $field: $ty,
+                )*
+            }
+
+            impl Schema for $name {
+                fn name() -> &'static str {
+                    stringify!($name)
+                }
+
+                fn fields() -> Vec<FieldSpec> {
+                    vec![$(FieldSpec::new(stringify!($field), stringify!($ty))),*]
+                }
+            }
+        )*
+    };
+}
+
+api_structs! {
+    /// Body of failed requests. Messages never expose internal details like
+    /// file paths, redis urls and tokens.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ApiError {
+        pub error: String,
+        pub detail: Option<String>,
+    }
+
+    /// Entries and bytes of a cache, as of `generated_at`. Counted by a scan
+    /// that is reused for `usage_report_ttl` secs.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct CacheStats {
+        /// Name of the policy of the cache
+        pub cache: String,
+        /// `LRU`, `TTL` or `NONE`
+        pub policy_type: String,
+        pub entries: u64,
+        pub bytes: u64,
+        /// Size limit of an LRU cache in bytes
+        pub size_limit: Option<u64>,
+        /// Current number of entries of an LRU cache, counted when the stats
+        /// are requested
+        #[serde(default)]
+        pub current_entries: Option<u64>,
+        /// Entry limit of an LRU cache, or of each of its shards
+        #[serde(default)]
+        pub max_entries: Option<u64>,
+        /// Estimated bytes of redis memory used by the metadata of an LRU
+        /// cache, sampled at most once a minute
+        #[serde(default)]
+        pub redis_memory: Option<u64>,
+        /// Redis memory limit of an LRU cache, or of each of its shards
+        #[serde(default)]
+        pub max_redis_memory: Option<u64>,
+        /// Entries missing from some replicas of the storage of the cache,
+        /// absent if it is not replicated
+        #[serde(default)]
+        pub degraded_entries: Option<u64>,
+        /// Bytes of the files of the cache on disk, temporary files aside, as
+        /// of the last walk of its filesystem storage, absent if there was
+        /// none. See `disk_usage`
+        #[serde(default)]
+        pub disk_bytes_actual: Option<u64>,
+        /// Bytes of the temporary files of interrupted or ongoing writes, as
+        /// of the last walk
+        #[serde(default)]
+        pub disk_temp_bytes: Option<u64>,
+        /// `disk_bytes_actual` less the bytes recorded by the metadata of an
+        /// LRU cache when the walk completed, negative if files are missing
+        #[serde(default)]
+        pub accounting_drift_bytes: Option<i64>,
+        /// Unix timestamp in seconds
+        pub generated_at: i64,
+    }
+
+    /// Query of a page of entries
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct EntryQuery {
+        /// `next_cursor` of the previous page, absent for the first page
+        pub cursor: Option<String>,
+        /// About this many entries are returned
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct EntryInfo {
+        pub key: String,
+        /// Zero if the size is not recorded, e.g. by TTL caches
+        pub size: u64,
+        /// The url of an entry whose key is a digest of it, if recorded
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub url: Option<String>,
+    }
+
+    /// A page of the entries of a cache, in no particular order. Entries
+    /// removed in between pages do not disturb the listing, but a key may be
+    /// listed twice.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct EntryPage {
+        pub cache: String,
+        pub entries: Vec<EntryInfo>,
+        /// Absent on the last page
+        pub next_cursor: Option<String>,
+    }
+
+    /// Entries to purge, by either a glob of keys (`*` matches any characters
+    /// but `/`, `**` any characters) or a regex
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct PurgeQuery {
+        pub pattern: Option<String>,
+        pub regex: Option<String>,
+    }
+
+    /// A job started in the background, answered with `202 Accepted`
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct JobAccepted {
+        pub job: JobId,
+        /// Path of the status of the job
+        pub location: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct PinRequest {
+        pub key: String,
+    }
+
+    /// A pinned entry of an LRU cache is marked as just used, without
+    /// counting a hit, so that it is evicted last. It is evicted eventually
+    /// if it is not used again.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct PinResult {
+        pub cache: String,
+        pub key: String,
+    }
+
+    /// Files to fetch into the caches, by paths of the mirror like
+    /// `pypi/packages/ab/cd/flask-2.0.whl`
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct WarmupRequest {
+        pub paths: Vec<String>,
+    }
+
+    /// Progress of a long-running operation, e.g. a purge or a warmup
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct JobStatus {
+        pub id: JobId,
+        /// Label of the admin token the job is started with
+        pub principal: String,
+        pub operation: String,
+        pub targets: Vec<String>,
+        /// Unix timestamps in seconds
+        pub started_at: i64,
+        pub finished_at: Option<i64>,
+        #[serde(flatten)]
+        pub state: JobState,
+        /// Number of cache keys scanned so far
+        pub scanned: u64,
+        /// Number of cache entries removed so far
+        pub removed: u64,
+        /// Number of files queued to be fetched so far
+        #[serde(default)]
+        pub queued: u64,
+    }
+
+    /// Running and recently finished jobs, by id
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct JobList {
+        pub jobs: Vec<JobStatus>,
+    }
+
+    /// A file being fetched from an upstream in the background
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct TaskInfo {
+        /// Name of the rule of the file
+        pub rule: String,
+        pub url: String,
+        pub key: String,
+        pub running_secs: u64,
+    }
+
+    /// Background fetches in progress, the longest running first
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct TaskList {
+        pub tasks: Vec<TaskInfo>,
+    }
+
+    /// An endpoint of the spec. Paths are relative to `base_path`.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct EndpointSpec {
+        pub method: String,
+        pub path: String,
+        pub summary: String,
+        /// Type of the query string
+        pub query: Option<String>,
+        /// Type of the JSON body
+        pub body: Option<String>,
+        pub response: String,
+    }
+
+    /// Self-description of the API, served at `/api/v1/spec`
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ApiSpec {
+        pub version: String,
+        pub base_path: String,
+        /// Requests are authenticated by an `Authorization: Bearer <token>`
+        /// header with an admin token, except the spec itself
+        pub authentication: String,
+        pub endpoints: Vec<EndpointSpec>,
+        /// Type of the body of failed requests
+        pub error: String,
+        /// Fields of the types, by name
+        pub schemas: BTreeMap<String, Vec<FieldSpec>>,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "state", content = "error")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed(String),
+}
+
+impl Schema for JobState {
+    fn name() -> &'static str {
+        "JobState"
+    }
+
+    fn fields() -> Vec<FieldSpec> {
+        vec![
+            // `running`, `completed` or `failed`
+            FieldSpec::new("state", "String"),
+            FieldSpec::new("error", "Option<String>"),
+        ]
+    }
+}
+
+/// Collects the endpoints of the spec and the schemas of their types
+struct SpecBuilder {
+    endpoints: Vec<EndpointSpec>,
+    schemas: BTreeMap<String, Vec<FieldSpec>>,
+}
+
+impl SpecBuilder {
+    fn schema<T: Schema>(&mut self) -> String {
+        self.schemas.insert(T::name().to_string(), T::fields());
+        T::name().to_string()
+    }
+
+    fn endpoint(
+        &mut self,
+        method: &str,
+        path: &str,
+        summary: &str,
+        query: Option<String>,
+        body: Option<String>,
+        response: String,
+    ) {
+        self.endpoints.push(EndpointSpec {
+            method: method.to_string(),
+            path: path.to_string(),
+            summary: summary.to_string(),
+            query,
+            body,
+            response,
+        });
+    }
+}
+
+/// The spec of the API
+pub fn spec() -> ApiSpec {
+    let mut b = SpecBuilder {
+        endpoints: Vec::new(),
+        schemas: BTreeMap::new(),
+    };
+    let error = b.schema::<ApiError>();
+    b.schema::<JobState>();
+    b.schema::<EntryInfo>();
+    b.schema::<TaskInfo>();
+    b.schema::<FieldSpec>();
+    b.schema::<EndpointSpec>();
+    let response = b.schema::<ApiSpec>();
+    b.endpoint("GET", "/spec", "This spec", None, None, response);
+    let response = b.schema::<CacheStats>();
+    b.endpoint(
+        "GET",
+        "/caches/{cache}/stats",
+        "Entries and bytes of a cache",
+        None,
+        None,
+        response,
+    );
+    let (query, response) = (b.schema::<EntryQuery>(), b.schema::<EntryPage>());
+    b.endpoint(
+        "GET",
+        "/caches/{cache}/entries",
+        "List the entries of a cache by pages",
+        Some(query),
+        None,
+        response,
+    );
+    let (query, response) = (b.schema::<PurgeQuery>(), b.schema::<JobAccepted>());
+    b.endpoint(
+        "DELETE",
+        "/caches/{cache}/entries",
+        "Start a job purging the matching entries of a cache",
+        Some(query),
+        None,
+        response.clone(),
+    );
+    let (body, pinned) = (b.schema::<PinRequest>(), b.schema::<PinResult>());
+    b.endpoint(
+        "PUT",
+        "/caches/{cache}/pins",
+        "Mark an entry of an LRU cache as just used",
+        None,
+        Some(body),
+        pinned,
+    );
+    let body = b.schema::<WarmupRequest>();
+    b.endpoint(
+        "POST",
+        "/warmup",
+        "Start a job fetching files into the caches",
+        None,
+        Some(body),
+        response,
+    );
+    let response = b.schema::<JobList>();
+    b.endpoint(
+        "GET",
+        "/jobs",
+        "Running and recent jobs",
+        None,
+        None,
+        response,
+    );
+    let response = b.schema::<JobStatus>();
+    b.endpoint(
+        "GET",
+        "/jobs/{id}",
+        "Progress of a job",
+        None,
+        None,
+        response,
+    );
+    let response = b.schema::<TaskList>();
+    b.endpoint(
+        "GET",
+        "/tasks",
+        "Background fetches in progress",
+        None,
+        None,
+        response,
+    );
+    ApiSpec {
+        version: VERSION.to_string(),
+        base_path: BASE_PATH.to_string(),
+        authentication: "bearer admin token".to_string(),
+        endpoints: b.endpoints,
+        error,
+        schemas: b.schemas,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schema_of_fields() {
+        assert_eq!(
+            PinResult::fields(),
+            vec![
+                FieldSpec::new("cache", "String"),
+                FieldSpec::new("key", "String")
+            ]
+        );
+        let fields = EntryPage::fields();
+        assert_eq!(fields[1].typ, "Vec<EntryInfo>");
+        assert!(!fields[1].optional);
+        assert_eq!(fields[2].typ, "Option<String>");
+        assert!(fields[2].optional);
+    }
+
+    #[test]
+    fn spec_describes_all_types() {
+        let spec = spec();
+        assert_eq!(spec.base_path, "/api/v1");
+        let known = |typ: &str| {
+            // e.g. `Vec<JobStatus>` and `Option<String>`
+            let inner = typ
+                .trim_start_matches("Option<")
+                .trim_start_matches("Vec<")
+                .trim_end_matches('>');
+            let primitives = ["String", "u64", "i64", "usize", "bool", "JobId"];
+            primitives.contains(&inner)
+                || inner.starts_with("BTreeMap<")
+                || spec.schemas.contains_key(inner)
+        };
+        for endpoint in &spec.endpoints {
+            let types = endpoint.query.iter().chain(&endpoint.body);
+            for typ in types.chain(std::iter::once(&endpoint.response)) {
+                assert!(spec.schemas.contains_key(typ), "{}", typ);
+            }
+        }
+        for (name, fields) in &spec.schemas {
+            for field in fields {
+                assert!(known(&field.typ), "{}.{}: {}", name, field.name, field.typ);
+            }
+        }
+    }
+
+    #[test]
+    fn job_status_round_trip() {
+        let job = JobStatus {
+            id: 1,
+            principal: "ops".to_string(),
+            operation: "purge".to_string(),
+            targets: vec!["policy_lru/flask-*".to_string()],
+            started_at: 1650000000,
+            finished_at: Some(1650000001),
+            state: JobState::Failed("redis is gone".to_string()),
+            scanned: 3,
+            removed: 1,
+            queued: 0,
+        };
+        let json = serde_json::to_value(&job).unwrap();
+        assert_eq!(json["state"], "failed");
+        assert_eq!(json["error"], "redis is gone");
+        assert_eq!(serde_json::from_value::<JobStatus>(json).unwrap(), job);
+    }
+}
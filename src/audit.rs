@@ -0,0 +1,229 @@
+use crate::error::Error;
+use crate::error::Result;
+use crate::settings::Audit;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Size of the audit log file at which it is rotated, if not configured
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Number of rotated audit log files kept, if not configured
+const DEFAULT_MAX_FILES: usize = 5;
+/// Maximum number of entries returned in a page
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// A record of an admin operation
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditEntry {
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    /// Label of the admin token the request is authenticated with
+    pub principal: String,
+    pub operation: String,
+    /// Cache keys or rules the operation applies to
+    pub targets: Vec<String>,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase", tag = "status", content = "error")]
+pub enum Outcome {
+    Success,
+    Failure(String),
+}
+
+/// An append-only audit log in JSON lines.
+///
+/// The file is rotated when it grows over `max_size`: `<path>` is renamed to
+/// `<path>.1`, `<path>.1` to `<path>.2`, and so on. At most `max_files`
+/// rotated files are kept.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    /// Serializes appends, rotations and reads
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: &str, max_size: u64, max_files: usize) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            max_size,
+            max_files,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Create the audit log of the settings, `None` if auditing is disabled.
+    pub fn from_settings(config: Option<&Audit>) -> Option<Self> {
+        let config = config?;
+        if !config.enabled.unwrap_or(true) {
+            return None;
+        }
+        let max_size = config
+            .max_size
+            .as_ref()
+            .map_or(DEFAULT_MAX_SIZE, |x| bytefmt::parse(x).unwrap());
+        Some(Self::new(
+            &config.path,
+            max_size,
+            config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+        ))
+    }
+
+    /// Append an entry, and flush it to the disk before returning.
+    pub fn record(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line =
+            serde_json::to_string(entry).map_err(|e| Error::OtherError(e.to_string()))?;
+        line.push('\n');
+        let _guard = self.lock.lock().unwrap();
+        let size = fs::metadata(&self.path).map_or(0, |m| m.len());
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Read entries recorded at or after `since`, oldest first, skipping the
+    /// first `offset` of them. Returns at most `limit` entries, and the offset
+    /// of the next page if there are more.
+    pub fn read(
+        &self,
+        since: i64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<AuditEntry>, Option<usize>)> {
+        let _guard = self.lock.lock().unwrap();
+        let mut files: Vec<PathBuf> = (1..=self.max_files)
+            .rev()
+            .map(|n| self.rotated_path(n))
+            .collect();
+        files.push(self.path.clone());
+        let mut entries = Vec::new();
+        let mut skipped = 0;
+        for path in files.iter().filter(|path| path.exists()) {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let entry: AuditEntry = match serde_json::from_str(&line?) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("skipped a malformed audit entry in {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                if entry.timestamp < since {
+                    continue;
+                }
+                if skipped < offset {
+                    skipped += 1;
+                    continue;
+                }
+                if entries.len() == limit {
+                    return Ok((entries, Some(offset + limit)));
+                }
+                entries.push(entry);
+            }
+        }
+        Ok((entries, None))
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+        let _ = fs::remove_file(self.rotated_path(self.max_files));
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(timestamp: i64, operation: &str) -> AuditEntry {
+        AuditEntry {
+            timestamp,
+            principal: "ops".to_string(),
+            operation: operation.to_string(),
+            targets: vec!["pypi/packages/a.whl".to_string()],
+            outcome: Outcome::Success,
+        }
+    }
+
+    fn new_log(name: &str, max_size: u64, max_files: usize) -> AuditLog {
+        let dir = format!("cache/test/audit_{}", name);
+        let _ = fs::remove_dir_all(&dir);
+        AuditLog::new(&format!("{}/audit.log", dir), max_size, max_files)
+    }
+
+    #[test]
+    fn audit_read_since_with_pagination() {
+        let log = new_log("pagination", DEFAULT_MAX_SIZE, DEFAULT_MAX_FILES);
+        for timestamp in 0..5 {
+            log.record(&entry(timestamp, "purge")).unwrap();
+        }
+        let (entries, next) = log.read(1, 0, 3).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(next, Some(3));
+        let (entries, next) = log.read(1, 3, 3).unwrap();
+        assert_eq!(entries, vec![entry(4, "purge")]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn audit_outcome_json() {
+        let mut failed = entry(0, "pin");
+        failed.outcome = Outcome::Failure("no such key".to_string());
+        let json = serde_json::to_value(&failed).unwrap();
+        assert_eq!(json["outcome"]["status"], "failure");
+        assert_eq!(json["outcome"]["error"], "no such key");
+        let json = serde_json::to_value(&entry(0, "pin")).unwrap();
+        assert_eq!(json["outcome"]["status"], "success");
+    }
+
+    #[test]
+    fn audit_rotate_by_size() {
+        let line_len = serde_json::to_string(&entry(0, "purge")).unwrap().len() as u64 + 1;
+        // two entries per file, one rotated file
+        let log = new_log("rotate", line_len * 2, 1);
+        for timestamp in 0..5 {
+            log.record(&entry(timestamp, "purge")).unwrap();
+        }
+        assert!(log.rotated_path(1).exists());
+        assert!(!log.rotated_path(2).exists());
+        // the oldest file is dropped
+        let (entries, _) = log.read(0, 0, MAX_PAGE_SIZE).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+    }
+}
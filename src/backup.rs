@@ -0,0 +1,295 @@
+//! Backups of the metadata of LRU caches kept in redis. Taken along with a
+//! backup of the files of a cache, e.g. with rsync, they restore a
+//! consistent cache. Run by the `backup-metadata` and `restore-metadata`
+//! subcommands.
+
+use crate::cache::{CacheSizeType, LruMetadataStore, RawLruEntry, RedisMetadataDb};
+use crate::error::{Error, Result};
+use crate::settings::{MetadataDb, PolicyType, Settings};
+use crate::storage::{size_mismatch, StorageBackend};
+use crate::task::TaskManager;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Version of the format of the dumps written
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The first bytes of zstd frames
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The metadata of an LRU cache at some point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetadataDump {
+    /// `FORMAT_VERSION` of the version writing the dump
+    pub version: u32,
+    /// Id of the cache the metadata is dumped from
+    pub cache: String,
+    /// Secs since the epoch
+    pub created_at: i64,
+    /// Total size of the entries, as recorded by the cache
+    pub total_size: CacheSizeType,
+    /// From the least recently used one
+    pub entries: Vec<DumpedEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpedEntry {
+    pub key: String,
+    /// The fields of the hash of the entry, e.g. `size`, `atime` and `sha256`
+    pub fields: BTreeMap<String, String>,
+    /// The score of the entry in the LRU list, its atime in millisecs
+    pub score: i64,
+}
+
+impl DumpedEntry {
+    fn size(&self) -> Option<CacheSizeType> {
+        self.fields.get("size").and_then(|size| size.parse().ok())
+    }
+}
+
+/// The outcome of a restore. Entries whose file is missing or has another
+/// size than recorded are not restored.
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: usize,
+    /// Total size of the restored entries
+    pub total_size: CacheSizeType,
+    /// Keys of entries whose file is missing
+    pub missing: Vec<String>,
+    /// (key, recorded size, size of the file) of entries whose file has
+    /// another size
+    pub size_mismatches: Vec<(String, CacheSizeType, CacheSizeType)>,
+}
+
+/// Dump all entries of the cache `id`
+pub fn dump(db: &RedisMetadataDb, id: &str) -> Result<MetadataDump> {
+    let (entries, total_size) = db.dump_lru_entries()?;
+    Ok(MetadataDump {
+        version: FORMAT_VERSION,
+        cache: id.to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+        total_size,
+        entries: entries
+            .into_iter()
+            .map(|(key, fields, score)| DumpedEntry {
+                key,
+                fields: fields.into_iter().collect(),
+                score,
+            })
+            .collect(),
+    })
+}
+
+/// Replace the entries of a cache with the entries of `dump` whose files
+/// are in `storage` with the recorded size, in a single transaction. Fails
+/// if the cache has entries, unless `force` is set. The dump may be of
+/// another cache id, e.g. of a renamed policy.
+pub async fn restore(
+    db: &RedisMetadataDb,
+    storage: &dyn StorageBackend,
+    dump: &MetadataDump,
+    force: bool,
+) -> Result<RestoreReport> {
+    let existing = db.lru_len()?;
+    if existing > 0 && !force {
+        return Err(Error::OtherError(format!(
+            "the cache has {} entries, use --force to replace them",
+            existing
+        )));
+    }
+    let mut report = RestoreReport::default();
+    let mut entries: Vec<RawLruEntry> = Vec::new();
+    for entry in &dump.entries {
+        let actual = match storage.metadata(&entry.key).await {
+            Ok(meta) => meta.size,
+            Err(_) => {
+                report.missing.push(entry.key.clone());
+                continue;
+            }
+        };
+        let recorded = entry.size().unwrap_or(0);
+        if size_mismatch(Some(recorded), actual) {
+            report
+                .size_mismatches
+                .push((entry.key.clone(), recorded, actual));
+            continue;
+        }
+        report.restored += 1;
+        report.total_size += recorded;
+        let fields = entry.fields.clone().into_iter().collect();
+        entries.push((entry.key.clone(), fields, entry.score));
+    }
+    db.replace_lru_entries(&entries, report.total_size)?;
+    Ok(report)
+}
+
+/// Write a dump as JSON, compressed with zstd if the file name ends with
+/// `.zst`
+pub fn write_dump(path: &Path, dump: &MetadataDump) -> Result<()> {
+    let json = serde_json::to_vec(dump).map_err(|e| Error::OtherError(e.to_string()))?;
+    let content = match path.extension() {
+        Some(extension) if extension == "zst" => zstd::stream::encode_all(&json[..], 0)?,
+        _ => json,
+    };
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Read a dump written by `write_dump`, compressed or not
+pub fn read_dump(path: &Path) -> Result<MetadataDump> {
+    let mut content = fs::read(path)?;
+    if content.starts_with(ZSTD_MAGIC) {
+        content = zstd::stream::decode_all(&content[..])?;
+    }
+    let dump: MetadataDump = serde_json::from_slice(&content)
+        .map_err(|e| Error::OtherError(format!("{}: {}", path.display(), e)))?;
+    if dump.version > FORMAT_VERSION {
+        return Err(Error::OtherError(format!(
+            "{}: version {} of the format is not supported, up to {} is",
+            path.display(),
+            dump.version,
+            FORMAT_VERSION
+        )));
+    }
+    Ok(dump)
+}
+
+/// The metadata and the storage of the LRU cache `id` of the settings,
+/// which must keep its metadata in redis
+pub fn redis_lru_cache(
+    settings: &Settings,
+    id: &str,
+) -> Result<(RedisMetadataDb, Arc<dyn StorageBackend>)> {
+    let policy = settings.policies.iter().find(|policy| {
+        policy.name == id
+            || policy
+                .shards
+                .iter()
+                .flatten()
+                .any(|shard| format!("{}_{}", policy.name, shard.storage) == id)
+    });
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return Err(Error::NotFound(format!("cache {}", id))),
+    };
+    if policy.typ != PolicyType::Lru || !matches!(policy.metadata_db, MetadataDb::Redis) {
+        return Err(Error::OtherError(format!(
+            "cache {} is not an LRU cache with redis metadata",
+            id
+        )));
+    }
+    let storage_name = match &policy.shards {
+        Some(shards) => shards
+            .iter()
+            .find(|shard| format!("{}_{}", policy.name, shard.storage) == id)
+            .map(|shard| shard.storage.as_str()),
+        None => Some(policy.storage.as_str()),
+    };
+    let storage = settings
+        .storages
+        .iter()
+        .find(|storage| Some(storage.name.as_str()) == storage_name)
+        .map(TaskManager::create_storage)
+        .ok_or_else(|| Error::NotFound(format!("storage of cache {}", id)))?;
+    // the root_dir of a policy does not apply to its shards
+    let root_dir = match policy.shards {
+        Some(_) => None,
+        None => policy.root_dir.as_deref(),
+    };
+    let client = redis::Client::open(settings.get_redis_url().as_str())?;
+    let db = RedisMetadataDb::new(client, id)?;
+    Ok((db, storage.for_cache(id, root_dir)))
+}
+
+/// A human readable summary of a restore, with a line per mismatch
+pub fn format_report(report: &RestoreReport) -> String {
+    let mut text = format!(
+        "restored {} entries, {} bytes\n",
+        report.restored, report.total_size
+    );
+    for key in &report.missing {
+        text.push_str(&format!("missing file: {}\n", key));
+    }
+    for (key, recorded, actual) in &report.size_mismatches {
+        text.push_str(&format!(
+            "size mismatch: {} ({} bytes recorded, {} bytes on disk)\n",
+            key, recorded, actual
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{Cache, LruCache};
+    use crate::storage::FsBackend;
+
+    fn lru_cache(id: &str, dir: &str) -> (LruCache, RedisMetadataDb, Arc<FsBackend>) {
+        let client = redis::Client::open("redis://localhost:3001/").unwrap();
+        let storage = Arc::new(FsBackend {
+            root_dir: dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: Default::default(),
+        });
+        let cache = LruCache::new(
+            1024,
+            Arc::new(RedisMetadataDb::new(client.clone(), id).unwrap()),
+            storage.clone(),
+            id,
+        );
+        (cache, RedisMetadataDb::new(client, id).unwrap(), storage)
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let dir = "cache/backup_test";
+        let _ = fs::remove_dir_all(dir);
+        let (mut cache, db, storage) = lru_cache("backup_test", dir);
+        db.replace_lru_entries(&[], 0).unwrap();
+        cache.put("a.whl", vec![1; 3].into()).await;
+        cache.put("b/c.whl", vec![2; 5].into()).await;
+        cache.put("d.whl", vec![3; 7].into()).await;
+
+        let dumped = dump(&db, "backup_test").unwrap();
+        assert_eq!(dumped.total_size, 15);
+        let keys: Vec<&str> = dumped.entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["a.whl", "b/c.whl", "d.whl"]);
+        assert_eq!(dumped.entries[1].fields["size"], "5");
+        for file in &["backup_test.json", "backup_test.json.zst"] {
+            let path = Path::new(dir).join(file);
+            write_dump(&path, &dumped).unwrap();
+            assert_eq!(read_dump(&path).unwrap(), dumped);
+        }
+        let compressed = fs::read(Path::new(dir).join("backup_test.json.zst")).unwrap();
+        assert!(compressed.starts_with(ZSTD_MAGIC));
+
+        // the cache is not empty
+        assert!(restore(&db, &*storage, &dumped, false).await.is_err());
+        // files changed since the backup
+        fs::remove_file(format!("{}/a.whl", dir)).unwrap();
+        fs::write(format!("{}/d.whl", dir), vec![3; 6]).unwrap();
+        let report = restore(&db, &*storage, &dumped, true).await.unwrap();
+        assert_eq!(report.restored, 1);
+        assert_eq!(report.total_size, 5);
+        assert_eq!(report.missing, vec!["a.whl"]);
+        assert_eq!(report.size_mismatches, vec![("d.whl".to_string(), 7, 6)]);
+        let restored = dump(&db, "backup_test").unwrap();
+        assert_eq!(restored.total_size, 5);
+        assert_eq!(restored.entries, vec![dumped.entries[1].clone()]);
+        assert!(cache.get("b/c.whl").await.is_some());
+
+        let future = MetadataDump {
+            version: FORMAT_VERSION + 1,
+            ..dumped
+        };
+        let path = Path::new(dir).join("future.json");
+        write_dump(&path, &future).unwrap();
+        assert!(read_dump(&path).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Cache bypass: a kill switch serving requests straight from their upstream,
+//! without reading or filling the caches, e.g. while a storage or its
+//! metadata is suspected to be corrupt. It is switched globally or per rule
+//! through the admin API, and kept in redis with `persist_bypass` so that all
+//! instances and restarts agree.
+
+use crate::error::Result;
+use crate::keys::BYPASS_ID;
+use crate::models;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Member of the redis set of the switches standing for all rules
+const ALL_RULES: &str = "*";
+
+/// How often the switches kept in redis are read again, so that instances
+/// pick up the changes of others
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether each rule bypasses the caches, and the switches it is computed with
+#[derive(Debug, Clone, Serialize)]
+pub struct BypassStatus {
+    /// Bypass of all rules
+    pub global: bool,
+    /// Whether each rule bypasses the caches, by rule name
+    pub rules: BTreeMap<String, bool>,
+    /// Whether the switches are kept in redis
+    pub persisted: bool,
+}
+
+/// Switches set through the admin API, kept across config reloads
+pub struct BypassSwitch {
+    global: AtomicBool,
+    /// Names of the rules bypassing the caches
+    rules: RwLock<BTreeSet<String>>,
+}
+
+impl BypassSwitch {
+    pub fn new() -> Self {
+        Self {
+            global: AtomicBool::new(false),
+            rules: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.global.load(Ordering::Relaxed)
+    }
+
+    /// Whether the rule named `rule` bypasses the caches, by its own switch
+    /// or the global one
+    pub fn is_bypassed(&self, rule: &str) -> bool {
+        self.is_global() || self.rules.read().unwrap().contains(rule)
+    }
+
+    /// Switch the bypass of `rule`, or of all rules if `None`
+    pub fn set(&self, rule: Option<&str>, enabled: bool) {
+        match rule {
+            Some(rule) if enabled => {
+                self.rules.write().unwrap().insert(rule.to_string());
+            }
+            Some(rule) => {
+                self.rules.write().unwrap().remove(rule);
+            }
+            None => self.global.store(enabled, Ordering::Relaxed),
+        }
+    }
+
+    /// Switch the bypass of `rule`, or of all rules if `None`, in the set
+    /// kept in redis
+    pub fn persist(client: &redis::Client, rule: Option<&str>, enabled: bool) -> Result<()> {
+        let mut con = models::get_sync_con(client)?;
+        let member = vec![rule.unwrap_or(ALL_RULES).to_string()];
+        if enabled {
+            models::add_to_set(&mut con, BYPASS_ID, &member)?;
+        } else {
+            models::remove_from_set(&mut con, BYPASS_ID, &member)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the switches by the ones kept in redis
+    pub fn load(&self, client: &redis::Client) -> Result<()> {
+        let mut con = models::get_sync_con(client)?;
+        let mut rules: BTreeSet<String> = models::set_members(&mut con, BYPASS_ID)?
+            .into_iter()
+            .collect();
+        let global = rules.remove(ALL_RULES);
+        self.global.store(global, Ordering::Relaxed);
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bypass_switches() {
+        let switch = BypassSwitch::new();
+        assert!(!switch.is_bypassed("pypi"));
+        switch.set(Some("pypi"), true);
+        assert!(switch.is_bypassed("pypi"));
+        assert!(!switch.is_bypassed("conda"));
+        // all rules, whatever their own switch
+        switch.set(None, true);
+        switch.set(Some("pypi"), false);
+        assert!(switch.is_bypassed("pypi"));
+        assert!(switch.is_bypassed("conda"));
+        switch.set(None, false);
+        assert!(!switch.is_bypassed("pypi"));
+    }
+
+    #[test]
+    fn persisted_switches() {
+        let client = redis::Client::open("redis://localhost:3001/").unwrap();
+        let mut con = models::get_sync_con(&client).unwrap();
+        models::del(&mut con, BYPASS_ID).unwrap();
+        BypassSwitch::persist(&client, Some("pypi"), true).unwrap();
+        BypassSwitch::persist(&client, None, true).unwrap();
+        // another instance
+        let switch = BypassSwitch::new();
+        switch.load(&client).unwrap();
+        assert!(switch.is_global());
+        assert!(switch.is_bypassed("pypi"));
+        BypassSwitch::persist(&client, None, false).unwrap();
+        switch.load(&client).unwrap();
+        assert!(!switch.is_global());
+        assert!(switch.is_bypassed("pypi"));
+        assert!(!switch.is_bypassed("conda"));
+        models::del(&mut con, BYPASS_ID).unwrap();
+    }
+}
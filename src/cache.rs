@@ -1,28 +1,35 @@
 use crate::error::Error;
 use crate::error::Result;
+use crate::hotkeys::{HotKey, HotKeys};
+use crate::keys::{self, CacheId, CacheKey, Lru, PrefixedKey, Ttl};
 use crate::metric;
 use crate::models;
-use crate::models::SledMetadata;
-use crate::storage::Storage;
+use crate::models::{EntryMetadata, SledMetadata};
+use crate::slowlog::{self, Category, Timer};
+use crate::storage::{self, PersistReport, Staged, StorageBackend, Tier};
 use crate::util;
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::{future, stream, Stream, StreamExt};
-use metrics::{histogram, increment_counter, register_histogram};
+use futures::{future, stream, Future, Stream, StreamExt};
+use metrics::{counter, gauge, histogram, increment_counter, register_histogram};
 use redis::Commands;
 use sled::transaction::{TransactionError, TransactionResult};
 use sled::Transactional;
+use std::collections::{HashMap, HashSet};
 use std::convert::AsRef;
 use std::convert::TryInto;
 use std::fmt;
 use std::marker::Send;
-use std::path::Path;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::str;
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
+use tracing::{debug, error, info, trace, warn};
 
 /// Datatype of cache size.
 /// Note: It is persistent in some database, so changes may not be backward compatible.
@@ -138,57 +145,819 @@ impl fmt::Debug for CacheData {
 #[async_trait]
 pub trait Cache: Sync + Send {
     async fn put(&mut self, key: &str, entry: CacheData);
+    /// Like `put`, but the entry expires after `ttl` secs instead of the TTL
+    /// of the cache. Caches without a TTL ignore it.
+    async fn put_with_ttl(&mut self, key: &str, entry: CacheData, _ttl: u64) {
+        self.put(key, entry).await
+    }
     async fn get(&self, key: &str) -> Option<CacheData>;
+    /// Like `get`, but returns the path relative to the storage root and the
+    /// size of the cached file instead of its content. Only available if the
+    /// storage is the local filesystem.
+    async fn get_local_path(&self, _key: &str) -> Option<(String, CacheSizeType)> {
+        None
+    }
+    /// The file `put` writes the value of `key` to, if the storage is the
+    /// local filesystem. Lets concurrent readers follow a download in progress.
+    fn write_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+    /// Like `get`, but also accepts entries that have expired recently.
+    /// Used to serve something when the upstream is unavailable.
+    async fn get_stale(&self, key: &str) -> Option<CacheData> {
+        self.get(key).await
+    }
+    /// How long ago the entry of `key` expired, zero if it has not expired.
+    /// `None` if unknown, e.g. the entry has been removed or never expires.
+    async fn staleness(&self, _key: &str) -> Option<Duration> {
+        None
+    }
+    /// Time since the entry of `key` is cached. `None` if unknown.
+    async fn age(&self, _key: &str) -> Option<Duration> {
+        None
+    }
+    /// Scan the keys of the cache in batches of about `count` keys, starting
+    /// at `cursor`, empty for the first batch. Returns the keys and the cursor
+    /// of the next batch, empty once the scan is complete. Entries removed
+    /// during the scan do not disturb it, but a key may be returned twice.
+    fn scan_keys(&self, _cursor: &str, _count: usize) -> Result<(Vec<String>, String)> {
+        Ok((vec![], String::new()))
+    }
+    /// Remove the entry of `key` and its file. Returns whether it was cached.
+    async fn remove(&self, _key: &str) -> Result<bool> {
+        Ok(false)
+    }
+    /// Sizes of the entries of `keys`, e.g. returned by `scan_keys`. Zero for
+    /// entries that are not cached, or whose size is not recorded.
+    fn entry_sizes(&self, keys: &[String]) -> Result<Vec<CacheSizeType>> {
+        Ok(vec![0; keys.len()])
+    }
+    /// The hex encoded SHA-256 of the files of `keys`, `None` for entries that
+    /// are not cached, or whose hash is not recorded. Looked up in a batch.
+    fn entry_sha256s(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        Ok(vec![None; keys.len()])
+    }
+    /// Record the `url` of the entry of `key`, a digest of it (see
+    /// `Task::to_key`), so that the entry listing tells what it is. Returns
+    /// whether it is recorded: the entry exists, and its metadata is in redis.
+    fn set_entry_url(&self, _key: &str, _url: &str) -> Result<bool> {
+        Ok(false)
+    }
+    /// The urls recorded by `set_entry_url` of the entries of `keys`, `None`
+    /// for entries without one. Looked up in a batch.
+    fn entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        Ok(vec![None; keys.len()])
+    }
+    /// Whether the entries of `keys` are cached, i.e. a `get` would hit,
+    /// looked up in a batch. Unlike `get`, entries are not touched: their
+    /// atimes and hit counts are left as they are.
+    fn contains_many(&self, keys: &[String]) -> Result<Vec<bool>> {
+        Ok(vec![false; keys.len()])
+    }
+    /// Remove about `count` entries and their files, starting at `cursor`,
+    /// empty for the first batch, to reset the cache batch by batch. Returns
+    /// the removed entries and the cursor of the next batch, empty once all
+    /// the entries are removed. Gets of removed entries are misses, entries
+    /// put meanwhile may be kept.
+    async fn purge_batch(&self, cursor: &str, count: usize) -> Result<(PurgeReport, String)> {
+        let (keys, next) = self.scan_keys(cursor, count)?;
+        let sizes = self.entry_sizes(&keys)?;
+        let mut report = PurgeReport::default();
+        for (key, size) in keys.iter().zip(sizes) {
+            if self.remove(key).await? {
+                report.entries += 1;
+                report.bytes += size;
+            }
+        }
+        Ok((report, next))
+    }
+    /// Entries an eviction would remove for the cache to fit in `target_size`
+    /// bytes, from the least recently used one. Nothing is removed.
+    fn preview_eviction(&self, _target_size: CacheSizeType) -> Result<Vec<EvictedEntry>> {
+        Err(Error::BadRequest(
+            "entries of this cache are not evicted by size".to_string(),
+        ))
+    }
+    /// The least recently used `percent` of the entries, i.e. the next ones to
+    /// be evicted, with their hit counts
+    fn coldest_entries(&self, _percent: u64) -> Result<Vec<LruEntryStats>> {
+        Err(Error::BadRequest(
+            "entries of this cache are not evicted by size".to_string(),
+        ))
+    }
+    /// Mark the entry of `key` as just used, without counting a hit, so that it
+    /// is evicted last. Returns whether it is cached.
+    fn protect(&self, _key: &str) -> Result<bool> {
+        Ok(false)
+    }
+    /// Restart the age of the entry of `key`, whose content the upstream
+    /// confirmed to be current. Returns whether it is cached.
+    fn confirm_current(&self, _key: &str) -> Result<bool> {
+        Ok(false)
+    }
+    /// The number of entries, counted without scanning the keys. `None` if
+    /// the cache does not keep count.
+    fn entry_count(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Estimated bytes of redis memory used by the metadata, `None` if it is
+    /// not kept in redis. See `RedisMemoryEstimate`.
+    fn redis_memory(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Bytes of the entries as recorded by the metadata, `None` if the cache
+    /// does not keep count. See `diskusage`.
+    fn total_size(&self) -> Option<CacheSizeType> {
+        None
+    }
+    /// Number of entries missing from some replicas of the storage, `None`
+    /// if it is not replicated. See `ReplicatedBackend`.
+    fn degraded_entries(&self) -> Option<u64> {
+        None
+    }
+    /// The hot keys of the cache, the hottest first. `None` if their content
+    /// is not kept in memory. See `HotKeys`.
+    fn hot_keys(&self) -> Option<Vec<HotKey>> {
+        None
+    }
+    /// Stop the background work of the cache, e.g. on shutdown. The cache
+    /// should not be used afterwards.
+    async fn close(&mut self) {}
 }
 
 /// `LruMetadataStore` defines required behavior for an LRU cache
 pub trait LruMetadataStore: Sync + Send {
+    /// Check whether an entry exists, and update its atime and hit count on hit
     fn get_lru_entry(&self, key: &str) -> CacheHitMiss;
-    fn set_lru_entry(&self, key: &str, value: &CacheData);
-    /// Run eviction policy if needed, reserve at least `size` for new cache entry.
-    /// Return a list of evicted keys.
-    fn evict(
+    /// Set an entry of `size` bytes, whose file has the hex encoded `sha256`
+    /// if known, and add its size to the total size. An entry already
+    /// recorded with the same size and `sha256` only gets its atime and
+    /// `created_at` updated, as its file is unchanged. Fails if the entry is
+    /// not set, e.g. when redis is unavailable.
+    fn set_lru_entry(&self, key: &str, size: CacheSizeType, sha256: Option<&str>) -> Result<()>;
+    /// The hex encoded SHA-256 of the file of an entry, `None` if there is
+    /// no such entry or it is not recorded
+    fn lru_entry_sha256(&self, key: &str) -> Result<Option<String>>;
+    /// Select the entries to evict, if needed, to reserve at least `size` for
+    /// new cache entry, and room for one more entry if the number of entries
+    /// is limited. The entry of `new_key`, if any, is replaced by the new one,
+    /// so only the difference of their sizes is reserved. Return the entries
+    /// to evict, from the least recently used one, and the recorded hash of
+    /// the replaced entry. Nothing is removed, see `LruCache::retract_evicted`.
+    fn eviction_candidates(
         &self,
         new_size: CacheSizeType,
         new_key: &str,
         size_limit: CacheSizeType,
-    ) -> Vec<String>;
+        max_entries: Option<u64>,
+    ) -> Result<Eviction>;
     fn get_total_size(&self) -> CacheSizeType;
+    /// Entries an eviction would remove for `new_size` more bytes to fit in
+    /// `size_limit`, see `eviction_candidates`
+    fn preview_eviction(
+        &self,
+        new_size: CacheSizeType,
+        size_limit: CacheSizeType,
+    ) -> Result<Vec<EvictedEntry>> {
+        Ok(self
+            .eviction_candidates(new_size, "", size_limit, None)?
+            .evicted)
+    }
+    /// Return up to `count` keys ordered from least to most recently used,
+    /// skipping the first `offset` ones.
+    fn lru_keys(&self, offset: usize, count: usize) -> Vec<String>;
+    /// The tier of a tiered storage the file of an entry is recorded in,
+    /// `None` if there is no such entry or it is not recorded
+    fn lru_entry_tier(&self, _key: &str) -> Result<Option<Tier>> {
+        Ok(None)
+    }
+    /// Record the tier the file of an entry is in. Returns whether the entry
+    /// exists.
+    fn set_lru_entry_tier(&self, _key: &str, _tier: Tier) -> Result<bool> {
+        Ok(false)
+    }
+    /// Like `lru_keys`, with the size, atime and hit count of the entries
+    fn lru_entry_stats(&self, offset: usize, count: usize) -> Result<Vec<LruEntryStats>>;
+    /// The number of entries
+    fn lru_len(&self) -> Result<usize>;
+    /// Update the atime of an entry without counting a hit. Returns whether it
+    /// exists.
+    fn touch_lru_entry(&self, key: &str) -> Result<bool>;
+    /// Like `touch_lru_entry`, and set the `created_at` of the entry to now, as
+    /// its file is known to be current
+    fn refresh_lru_entry(&self, key: &str) -> Result<bool>;
+    /// Time the file of an entry is cached or last known to be current, in
+    /// secs. `None` if there is no such entry, or it is not recorded.
+    fn lru_entry_created_at(&self, key: &str) -> Result<Option<i64>>;
+    /// See `Cache::scan_keys`
+    fn scan_lru_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)>;
+    /// Remove an entry and update the total size. Returns whether it existed.
+    fn remove_lru_entry(&self, key: &str) -> Result<bool>;
+    /// Whether there is an entry, without touching it
+    fn lru_entry_exists(&self, key: &str) -> Result<bool>;
+    /// See `Cache::entry_sizes`
+    fn lru_entry_sizes(&self, keys: &[String]) -> Result<Vec<CacheSizeType>>;
+    /// Like `lru_entry_exists` for each of `keys`, see `Cache::contains_many`
+    fn lru_entries_exist(&self, keys: &[String]) -> Result<Vec<bool>> {
+        keys.iter().map(|key| self.lru_entry_exists(key)).collect()
+    }
+    /// The memory used by the metadata in redis, `None` if it is not kept in
+    /// redis
+    fn redis_memory(&self) -> Result<Option<RedisMemoryEstimate>> {
+        Ok(None)
+    }
+    /// Queue the files of evicted entries that failed to be removed, to be
+    /// removed by a later eviction. Returns the number of queued files.
+    fn queue_orphans(&self, _files: &[String]) -> Result<usize> {
+        Err(Error::OtherError(
+            "orphaned files are not queued without redis".to_string(),
+        ))
+    }
+    /// Take up to `count` queued files, leaving out the files whose keys have
+    /// been cached again. Returns them with the number of files left queued.
+    fn take_orphans(&self, _count: usize) -> Result<(Vec<String>, usize)> {
+        Ok((vec![], 0))
+    }
+    /// Like `lru_entry_sha256` for many entries, in a single round trip if
+    /// possible
+    fn lru_entry_sha256s(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.lru_entry_sha256(key)).collect()
+    }
+    /// See `Cache::set_entry_url`
+    fn set_lru_entry_url(&self, _key: &str, _url: &str) -> Result<bool> {
+        Ok(false)
+    }
+    /// See `Cache::entry_urls`
+    fn lru_entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        Ok(vec![None; keys.len()])
+    }
+    /// Like `remove_lru_entry` for each of `keys`, in a single round trip if
+    /// possible
+    fn remove_lru_entries(&self, keys: &[String]) -> Result<Vec<bool>> {
+        keys.iter().map(|key| self.remove_lru_entry(key)).collect()
+    }
+}
+
+/// Memory used in redis by the metadata of an LRU cache. The metadata of an
+/// entry costs about the same whatever the entry, so the memory is estimated
+/// from a sample of entries, and capping the entries caps the memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RedisMemoryEstimate {
+    /// Bytes of an entry: its hash, and its share of the LRU list
+    pub per_entry: u64,
+    /// Bytes of the keys that are not of an entry, e.g. the total size
+    pub overhead: u64,
+}
+
+impl RedisMemoryEstimate {
+    /// Estimated bytes of `entries` entries
+    pub fn bytes(&self, entries: u64) -> u64 {
+        self.overhead + self.per_entry * entries
+    }
+
+    /// Most entries whose metadata fits in `max_bytes`, at least one
+    pub fn entry_limit(&self, max_bytes: u64) -> u64 {
+        (max_bytes.saturating_sub(self.overhead) / self.per_entry.max(1)).max(1)
+    }
+}
+
+/// An entry removed from an LRU cache to make room for a new one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EvictedEntry {
+    pub key: String,
+    pub size: CacheSizeType,
+    /// Last access time in secs
+    pub atime: i64,
+}
+
+/// The result of `LruMetadataStore::evict`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Eviction {
+    pub evicted: Vec<EvictedEntry>,
+    /// Hex encoded SHA-256 of the file of the entry the new one replaces,
+    /// `None` if there is no such entry, it is evicted, or its hash is not
+    /// recorded
+    pub replaced_sha256: Option<String>,
+}
+
+/// Entries removed by purging a cache, see `Cache::purge_batch`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PurgeReport {
+    pub entries: u64,
+    /// Bytes of the removed entries, as far as their sizes are recorded
+    pub bytes: CacheSizeType,
+}
+
+impl std::ops::AddAssign for PurgeReport {
+    fn add_assign(&mut self, other: Self) {
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+    }
+}
+
+/// An LRU entry with the statistics deciding whether it is worth keeping
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LruEntryStats {
+    pub key: String,
+    pub size: CacheSizeType,
+    /// Last access time in secs
+    pub atime: i64,
+    /// Number of cache hits since the entry was first cached
+    pub hits: u64,
+}
+
+/// Number of LRU entries read at a time when previewing an eviction
+const PREVIEW_BATCH_SIZE: usize = 256;
+
+/// Select the entries to evict from an LRU cache of `total_size` bytes and
+/// `entry_count` entries, so that `new_size` more bytes fit in `size_limit`,
+/// and one more entry in `max_entries` if set. The key and size of the entry
+/// the new one replaces, if any, are `replaced`: its size is freed and no
+/// entry is added, unless it is evicted itself. `next_lru` returns the least
+/// recently used entry not selected yet, and removes it for a real eviction.
+/// Evictions and their previews share this, so that a preview lists what an
+/// eviction would remove.
+fn select_eviction_candidates<E>(
+    total_size: CacheSizeType,
+    entry_count: u64,
+    new_size: CacheSizeType,
+    replaced: Option<(&str, CacheSizeType)>,
+    size_limit: CacheSizeType,
+    max_entries: Option<u64>,
+    mut next_lru: impl FnMut() -> std::result::Result<Option<EvictedEntry>, E>,
+) -> std::result::Result<Vec<EvictedEntry>, E> {
+    let mut remaining = total_size;
+    let mut remaining_entries = entry_count;
+    let (replaced_key, mut freed) = replaced.unwrap_or(("", 0));
+    let mut added = if replaced.is_some() { 0 } else { 1 };
+    let mut candidates = Vec::new();
+    while remaining.saturating_sub(freed) + new_size > size_limit
+        || max_entries.map_or(false, |max| remaining_entries + added > max)
+    {
+        match next_lru()? {
+            Some(entry) => {
+                remaining = remaining.saturating_sub(entry.size);
+                remaining_entries = remaining_entries.saturating_sub(1);
+                if replaced.is_some() && entry.key == replaced_key {
+                    freed = 0;
+                    added = 1;
+                }
+                candidates.push(entry);
+            }
+            None => {
+                info!("some files need to be evicted but they are missing from the LRU list. The cache metadata is inconsistent.");
+                break;
+            }
+        }
+    }
+    Ok(candidates)
 }
 
 /// `TtlMetadataStore` defines required behavior for a TTL cache
 pub trait TtlMetadataStore: Sync + Send {
     fn get_ttl_entry(&self, key: &str) -> CacheHitMiss;
-    fn set_ttl_entry(&self, key: &str, value: &CacheData, ttl: u64);
+    /// Like `get_ttl_entry`, and extend the expiration of a hit entry, see
+    /// `Sliding`. `ttl` is the ttl of entries set without one of their own.
+    fn get_sliding_ttl_entry(&self, key: &str, ttl: u64, sliding: Sliding) -> CacheHitMiss;
+    /// How long ago the entry expired, zero if it has not expired. `None` if
+    /// there is no such entry.
+    fn staleness(&self, key: &str) -> Option<Duration>;
+    /// Time the entry is set in secs. `None` if there is no such entry, or
+    /// the time is not recorded.
+    fn created_at(&self, key: &str) -> Option<i64>;
+    /// Set an entry that expires after `ttl` secs, and is removed `grace` secs later.
+    fn set_ttl_entry(&self, key: &str, ttl: u64, grace: u64);
+    /// See `Cache::scan_keys`
+    fn scan_ttl_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)>;
+    /// Whether each of `keys` has an entry that has not expired, without
+    /// extending it, see `Cache::contains_many`
+    fn ttl_entries_exist(&self, keys: &[String]) -> Result<Vec<bool>> {
+        Ok(keys
+            .iter()
+            .map(|key| matches!(self.get_ttl_entry(key), CacheHitMiss::Hit))
+            .collect())
+    }
+    /// See `Cache::set_entry_url`
+    fn set_ttl_entry_url(&self, _key: &str, _url: &str) -> Result<bool> {
+        Ok(false)
+    }
+    /// See `Cache::entry_urls`
+    fn ttl_entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        Ok(vec![None; keys.len()])
+    }
+    /// Remove an entry before it expires. Returns whether it existed.
+    fn remove_ttl_entry(&self, key: &str) -> Result<bool>;
+    /// Id of the cache of the entries, which their locks are keyed by
+    fn cache_id(&self) -> &str;
     fn spawn_expiration_cleanup_thread(
         &self,
-        storage: &Storage,
+        storage: &Arc<dyn StorageBackend>,
         pending_close: Arc<AtomicBool>,
     ) -> Result<JoinHandle<()>>;
 }
 
+/// Number of LRU keys fetched at a time when demoting files in tiered storage
+const DEMOTION_BATCH_SIZE: usize = 256;
+
+/// Most bytes of an entry of unknown size, e.g. a rewritten page, read in
+/// memory to learn its size before it is put
+const MAX_UNSIZED_ENTRY: CacheSizeType = 64 << 20;
+
+/// Most files of evicted entries removed at a time
+const EVICTION_REMOVALS: usize = 8;
+
+/// Most orphaned files retried by an eviction, see `LruMetadataStore::queue_orphans`
+const ORPHAN_RETRIES: usize = 32;
+
 /// Wrapper of an LRU cache object
 pub struct LruCache {
     pub size_limit: CacheSizeType,
+    /// Most entries kept, no limit if `None`
+    pub max_entries: Option<u64>,
+    /// Most bytes of redis memory used by the metadata, enforced by evicting
+    /// entries like `max_entries`. No limit if `None`.
+    pub max_redis_memory: Option<u64>,
+    /// Identifies the cache in metrics
+    id: String,
     metadata_db: Arc<dyn LruMetadataStore>,
-    storage: Arc<Storage>,
+    storage: Arc<dyn StorageBackend>,
+    /// Whether a demotion task of tiered storage is running
+    demoting: Arc<AtomicBool>,
+    /// Content of the most hit keys, served without looking up their entries
+    hot_keys: Option<Arc<HotKeys>>,
 }
 
 impl LruCache {
     pub fn new(
         size_limit: CacheSizeType,
         metadata_db: Arc<dyn LruMetadataStore>,
-        storage: Arc<Storage>,
+        storage: Arc<dyn StorageBackend>,
         metric_id: &str,
     ) -> Self {
         register_histogram!(
             metric::get_cache_size_metrics_key(metric_id),
             metrics::Unit::Bytes,
         );
+        register_histogram!(
+            metric::get_evicted_entry_age_metrics_key(metric_id),
+            metrics::Unit::Seconds,
+        );
+        register_histogram!(
+            metric::get_cache_entries_metrics_key(metric_id),
+            metrics::Unit::Count,
+        );
         Self {
             size_limit,
+            max_entries: None,
+            max_redis_memory: None,
+            id: metric_id.to_string(),
             metadata_db,
             storage,
+            demoting: Arc::new(AtomicBool::new(false)),
+            hot_keys: None,
+        }
+    }
+
+    /// Evict entries when there are `max_entries` of them, in addition to
+    /// the size limit
+    pub fn with_max_entries(mut self, max_entries: Option<u64>) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Evict entries when their metadata is estimated to use `max_redis_memory`
+    /// bytes of redis memory, in addition to the other limits
+    pub fn with_max_redis_memory(mut self, max_redis_memory: Option<u64>) -> Self {
+        self.max_redis_memory = max_redis_memory;
+        self
+    }
+
+    /// Keep the content of the most hit keys in memory
+    pub fn with_hot_keys(mut self, hot_keys: Option<HotKeys>) -> Self {
+        self.hot_keys = hot_keys.map(Arc::new);
+        self
+    }
+
+    /// Update the atimes of the entries of hot keys served from memory, in a
+    /// batch off the runtime. Keys whose entries are gone, e.g. evicted by
+    /// another instance, are dropped from memory.
+    fn touch_hot_keys(&self, hot_keys: &Arc<HotKeys>) {
+        let keys = hot_keys.take_touched();
+        if keys.is_empty() {
+            return;
+        }
+        let metadata_db = self.metadata_db.clone();
+        let hot_keys = hot_keys.clone();
+        tokio::task::spawn_blocking(move || {
+            for key in keys {
+                match metadata_db.touch_lru_entry(&key) {
+                    Ok(true) => {}
+                    Ok(false) => hot_keys.invalidate(&key),
+                    Err(e) => info!("Failed to update the atime of {}: {}", key, e),
+                }
+            }
+        });
+    }
+
+    /// Drop the content of `key` from memory, once its entry is put or removed
+    fn invalidate_hot_key(&self, key: &str) {
+        if let Some(hot_keys) = &self.hot_keys {
+            hot_keys.invalidate(key);
+        }
+    }
+
+    /// Most entries kept: `max_entries`, or fewer if their metadata would use
+    /// more than `max_redis_memory`. The estimate is also sampled without a
+    /// limit, to keep its gauge up to date.
+    fn entry_limit(&self) -> Option<u64> {
+        let estimate = match self.metadata_db.redis_memory() {
+            Ok(estimate) => estimate,
+            Err(e) => {
+                info!("Failed to estimate the redis memory of {}: {}", self.id, e);
+                None
+            }
+        };
+        let memory_limit = self
+            .max_redis_memory
+            .zip(estimate)
+            .map(|(max, estimate)| estimate.entry_limit(max));
+        match (self.max_entries, memory_limit) {
+            (Some(max), Some(limit)) => Some(max.min(limit)),
+            (max, limit) => max.or(limit),
+        }
+    }
+
+    /// Log and record metrics of an eviction batch, made to put `key` of `size`.
+    /// Evicting recently used entries means the cache is undersized.
+    fn report_eviction(&self, key: &str, size: CacheSizeType, evicted: &[EvictedEntry]) {
+        if evicted.is_empty() {
+            return;
+        }
+        let now = util::now();
+        let evicted_bytes: CacheSizeType = evicted.iter().map(|entry| entry.size).sum();
+        let ages: Vec<i64> = evicted.iter().map(|entry| now - entry.atime).collect();
+        for age in &ages {
+            histogram!(
+                metric::get_evicted_entry_age_metrics_key(&self.id),
+                *age as f64
+            );
+        }
+        counter!(metric::CNT_EVICTED_ENTRIES, evicted.len() as u64, "cache" => self.id.clone());
+        counter!(metric::CNT_EVICTED_BYTES, evicted_bytes, "cache" => self.id.clone());
+        info!(
+            cache_id = %self.id,
+            key,
+            size,
+            evicted = evicted.len(),
+            evicted_bytes,
+            "LRU cache {} evicted {} entries ({} bytes) for {} ({} bytes), age of evicted entries: {}s - {}s",
+            self.id,
+            evicted.len(),
+            evicted_bytes,
+            key,
+            size,
+            ages.iter().min().unwrap(),
+            ages.iter().max().unwrap(),
+        );
+    }
+
+    /// Evict entries to make room for `key` of `size`, see
+    /// `LruMetadataStore::eviction_candidates`. Candidates retracted meanwhile
+    /// by another task made room for that task, so others are selected until
+    /// all the selected ones are retracted here. Returns the recorded hash of
+    /// the replaced entry.
+    async fn make_room(&self, key: &str, size: CacheSizeType) -> Option<String> {
+        let entry_limit = self.entry_limit();
+        let mut evicted = Vec::new();
+        let replaced_sha256 = loop {
+            let eviction =
+                match self
+                    .metadata_db
+                    .eviction_candidates(size, key, self.size_limit, entry_limit)
+                {
+                    Ok(eviction) => eviction,
+                    Err(e) => {
+                        info!(cache_id = %self.id, key, "failed to select entries to evict: {}", e);
+                        break None;
+                    }
+                };
+            let candidates = eviction.evicted.len();
+            match self.retract_evicted(eviction.evicted).await {
+                Ok(retracted) => {
+                    let complete = retracted.len() == candidates;
+                    evicted.extend(retracted);
+                    if complete {
+                        break eviction.replaced_sha256;
+                    }
+                }
+                Err(e) => {
+                    error!(cache_id = %self.id, key, "failed to evict entries: {}", e);
+                    break eviction.replaced_sha256;
+                }
+            }
+        };
+        self.report_eviction(key, size, &evicted);
+        replaced_sha256
+    }
+
+    /// Retract the entries selected by `eviction_candidates`, like
+    /// `retract_entry`: their metadata is removed, then their files, under
+    /// the locks of the entries. Returns the retracted entries, leaving out
+    /// the ones removed meanwhile, e.g. by another eviction.
+    async fn retract_evicted(&self, candidates: Vec<EvictedEntry>) -> Result<Vec<EvictedEntry>> {
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+        let keys: Vec<String> = candidates.iter().map(|entry| entry.key.clone()).collect();
+        let guards = lock_entries(&self.id, &keys).await;
+        let existed = self.metadata_db.remove_lru_entries(&keys)?;
+        let evicted: Vec<EvictedEntry> = candidates
+            .into_iter()
+            .zip(existed)
+            .filter_map(|(entry, existed)| if existed { Some(entry) } else { None })
+            .collect();
+        for entry in &evicted {
+            self.invalidate_hot_key(&entry.key);
+        }
+        let files = evicted.iter().map(|entry| entry.key.clone());
+        let mut failed = self.remove_files(files.collect(), false).await;
+        drop(guards);
+        // the orphans are locked one at a time, as their stripes are not in
+        // the order of the ones locked above
+        let orphans = match self.metadata_db.take_orphans(ORPHAN_RETRIES) {
+            Ok((orphans, queued)) => {
+                gauge!(metric::GAUGE_ORPHAN_FILES, queued as f64, "cache" => self.id.clone());
+                orphans
+            }
+            Err(e) => {
+                info!("Failed to get the orphaned files of {}: {}", self.id, e);
+                vec![]
+            }
+        };
+        failed.extend(self.remove_files(orphans, true).await);
+        if !failed.is_empty() {
+            match self.metadata_db.queue_orphans(&failed) {
+                Ok(queued) => {
+                    gauge!(metric::GAUGE_ORPHAN_FILES, queued as f64, "cache" => self.id.clone());
+                }
+                Err(e) => error!(
+                    cache_id = %self.id,
+                    "{} files of {} are left behind: {}",
+                    failed.len(),
+                    self.id,
+                    e
+                ),
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Remove the files of entries whose metadata is removed, up to
+    /// `EVICTION_REMOVALS` at a time on blocking threads, so that large files
+    /// on a slow disk do not hold up the runtime. Returns the files that
+    /// failed to be removed, to be queued and retried by a later eviction.
+    ///
+    /// Orphaned files of earlier evictions are removed under the locks of
+    /// their entries if `lock`, and kept if their entries have been committed
+    /// again meanwhile, e.g. by the cache object of a reloaded config.
+    async fn remove_files(&self, files: Vec<String>, lock: bool) -> Vec<String> {
+        let runtime = tokio::runtime::Handle::current();
+        let removals = files.into_iter().map(|file| {
+            let storage = self.storage.clone();
+            let metadata_db = self.metadata_db.clone();
+            let runtime = runtime.clone();
+            let id = self.id.clone();
+            async move {
+                let guard = match lock {
+                    true => Some(lock_entry(&id, &file).await),
+                    false => None,
+                };
+                if guard.is_some() {
+                    match metadata_db.lru_entry_exists(&file) {
+                        Ok(false) => {}
+                        Ok(true) => {
+                            debug!("{} is cached again, its file is kept", &file);
+                            return None;
+                        }
+                        Err(e) => {
+                            warn!("failed to check whether {} is cached again: {}", file, e);
+                            return Some(file);
+                        }
+                    }
+                }
+                let started = Instant::now();
+                let name = file.clone();
+                let result =
+                    tokio::task::spawn_blocking(move || runtime.block_on(storage.remove(&name)))
+                        .await
+                        .unwrap_or_else(|e| Err(Error::OtherError(e.to_string())));
+                histogram!(
+                    metric::HG_REMOVAL_LATENCY,
+                    started.elapsed().as_secs_f64(),
+                    "cache" => id
+                );
+                match result {
+                    Ok(_) => {
+                        increment_counter!(metric::CNT_RM_FILES);
+                        info!("LRU cache removed {}", &file);
+                        None
+                    }
+                    Err(e) => {
+                        warn!("failed to remove file {}: {:?}", file, e);
+                        Some(file)
+                    }
+                }
+            }
+        });
+        stream::iter(removals)
+            .buffer_unordered(EVICTION_REMOVALS)
+            .filter_map(future::ready)
+            .collect()
+            .await
+    }
+
+    /// Spawn a task to move least recently used files to the slow tier,
+    /// if the storage is tiered and the fast tier is over budget.
+    fn spawn_demotion(&self) {
+        if !self.storage.needs_demotion() || self.demoting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let metadata_db = self.metadata_db.clone();
+        let storage = self.storage.clone();
+        let demoting = self.demoting.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut offset = 0;
+            while storage.needs_demotion() {
+                let keys = metadata_db.lru_keys(offset, DEMOTION_BATCH_SIZE);
+                if keys.is_empty() {
+                    break;
+                }
+                offset += keys.len();
+                for key in storage.demote(&keys) {
+                    if let Err(e) = metadata_db.set_lru_entry_tier(&key, Tier::Slow) {
+                        warn!("failed to record the tier of {}: {}", key, e);
+                    }
+                }
+            }
+            demoting.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Read the file of an entry, from the tier recorded in the entry if the
+    /// storage is tiered, and record the tier it is read from if it moved
+    async fn read_entry(&self, key: &str) -> Result<CacheData> {
+        if !self.storage.is_tiered() {
+            return self.storage.read(key).await;
+        }
+        let recorded = self
+            .metadata_db
+            .lru_entry_tier(key)
+            .unwrap_or_default()
+            .unwrap_or(Tier::Fast);
+        let (data, tier) = self.storage.read_tier(key, recorded).await?;
+        if tier != recorded {
+            if let Err(e) = self.metadata_db.set_lru_entry_tier(key, tier) {
+                warn!(cache_id = %self.id, key, "failed to record the tier of {}: {}", key, e);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Whether the file of `key`, of `size` bytes, is not the one recorded,
+    /// e.g. truncated. The entry is removed then, so that the file is
+    /// fetched again.
+    async fn is_corrupted(&self, key: &str, size: CacheSizeType) -> bool {
+        let recorded = Some(self.recorded_size(key)).filter(|recorded| *recorded != 0);
+        if !storage::size_mismatch(recorded, size) {
+            return false;
+        }
+        warn!(
+            cache_id = %self.id,
+            key,
+            size,
+            "cache entry {} is corrupted: {} bytes recorded, {} stored",
+            key,
+            recorded.unwrap_or_default(),
+            size
+        );
+        increment_counter!(
+            metric::CNT_CORRUPT_ENTRIES,
+            "cache" => self.id.clone()
+        );
+        if let Err(e) = self.remove(key).await {
+            error!("failed to remove corrupted entry {}: {}", key, e);
+        }
+        true
+    }
+
+    /// The size of an entry recorded when it was put, zero if unknown
+    fn recorded_size(&self, key: &str) -> CacheSizeType {
+        match self.metadata_db.lru_entry_sizes(&[key.to_string()]) {
+            Ok(sizes) => sizes.first().copied().unwrap_or(0),
+            Err(e) => {
+                info!("Failed to get the recorded size of {}: {}", key, e);
+                0
+            }
         }
     }
 }
@@ -196,301 +965,1636 @@ impl LruCache {
 #[async_trait]
 impl Cache for LruCache {
     async fn put(&mut self, key: &str, entry: CacheData) {
+        let entry = match entry {
+            CacheData::ByteStream(_, None) => {
+                match read_bounded(entry, self.size_limit.min(MAX_UNSIZED_ENTRY)).await {
+                    Some(bytes) => CacheData::BytesData(bytes),
+                    None => {
+                        info!(
+                            cache_id = %self.id,
+                            key,
+                            "skip cache for {}, because its size is unknown and too large, \
+                             or it fails to be read",
+                            key
+                        );
+                        return;
+                    }
+                }
+            }
+            entry => entry,
+        };
         let file_size = entry.len() as CacheSizeType;
 
         if file_size > self.size_limit {
             info!(
+                cache_id = %self.id,
+                key,
+                size = file_size,
                 "skip cache for {}, because its size exceeds cache size limit({})",
-                key, self.size_limit
+                key,
+                self.size_limit
             );
             return;
         }
-        // Run eviction, set new entry
-        let evicted_keys = self.metadata_db.evict(file_size, key, self.size_limit);
-        for file in evicted_keys {
-            match self.storage.remove(&file).await {
-                Ok(_) => {
-                    increment_counter!(metric::CNT_RM_FILES);
-                    info!("LRU cache removed {}", &file);
-                }
-                Err(e) => {
-                    warn!("failed to remove file: {:?}", e);
-                }
-            };
+        // A put runs in three phases, so that the metadata only describes
+        // complete files:
+        // 1. make room by evicting entries, each one retracted under its lock,
+        //    see `make_room`. They are removed for good, but the size of
+        //    the new entry is only added to the total size by the commit, so
+        //    there is nothing to roll back if a later phase fails.
+        // 2. stage the file, see `StorageBackend::stage`: it is written to a
+        //    temporary file and synced, without holding the lock of the entry.
+        // 3. publish the file, moving it in place, and commit the metadata. If
+        //    the commit fails, the file is removed.
+        // Phase 3 runs under the lock of the entry, see `commit_entry`.
+        // An entry put again only needs room for the difference of sizes,
+        // so the same content evicts nothing
+        let known_sha256 = self.make_room(key, file_size).await;
+        let metadata_db = &self.metadata_db;
+        // an entry refreshed with the same content keeps its file
+        let stage = self.storage.stage(key, entry, known_sha256.as_deref());
+        let commit = |report: &PersistReport| {
+            // record the size actually written, only once the file is
+            // complete. The entry of an unchanged file only gets its atime
+            // and age updated.
+            let sha256 = Some(report.sha256.as_str());
+            metadata_db.set_lru_entry(key, report.bytes_written, sha256)
+        };
+        let retract = || metadata_db.remove_lru_entry(key);
+        match commit_entry(&*self.storage, &self.id, key, stage, commit, retract).await {
+            Ok(None) => debug!(cache_id = %self.id, key, "{} is already being put", key),
+            Ok(Some(report)) if report.unchanged => {
+                debug!(
+                    cache_id = %self.id,
+                    key,
+                    "{} is unchanged, only its atime and age are updated",
+                    key
+                );
+                increment_counter!(metric::CNT_UNCHANGED_PUTS, "cache" => self.id.clone());
+            }
+            Ok(Some(report)) => {
+                trace!(
+                    cache_id = %self.id,
+                    key,
+                    size = report.bytes_written,
+                    "persisted {}: {:?}",
+                    key,
+                    report
+                );
+                self.spawn_demotion();
+            }
+            // a file that failed to be persisted leaves a previous file of the
+            // key untouched, and a file without metadata would never be evicted
+            Err(e) => error!(cache_id = %self.id, key, "failed to cache {}: {}", key, e),
         }
-        self.metadata_db.set_lru_entry(key, &entry);
-        // self.metadata_db.set(key, &mut entry);
-        self.storage.persist(key, entry).await;
+        self.invalidate_hot_key(key);
     }
 
+    /// Hot keys are served from memory, see `HotKeys`
     async fn get(&self, key: &str) -> Option<CacheData> {
+        let mut generation = None;
+        if let Some(hot_keys) = &self.hot_keys {
+            hot_keys.record(key);
+            if let Some(bytes) = hot_keys.get(key) {
+                increment_counter!(metric::CNT_HOT_KEY_HITS, "cache" => self.id.clone());
+                self.touch_hot_keys(hot_keys);
+                return Some(sized_stream(bytes));
+            }
+            // taken before the content is read, so that a put meanwhile
+            // keeps it out of memory
+            generation = hot_keys.wants(key);
+        }
         match self.metadata_db.get_lru_entry(key) {
             CacheHitMiss::Hit => {
-                return match self.storage.read(key).await {
-                    Ok(data) => {
-                        // trace!("CACHE GET [HIT] {} -> {:?} ", redis_key, &cache_result);
-                        Some(data)
+                let data = self.read_entry(key).await.ok()?;
+                // trace!("CACHE GET [HIT] {} -> {:?} ", redis_key, &cache_result);
+                match data {
+                    CacheData::ByteStream(stream, Some(size)) => {
+                        if self.is_corrupted(key, size).await {
+                            return None;
+                        }
+                        let data = CacheData::ByteStream(stream, Some(size));
+                        match (&self.hot_keys, generation) {
+                            (Some(hot_keys), Some(generation))
+                                if size <= hot_keys.max_entry_size() =>
+                            {
+                                let bytes = read_bounded(data, size).await?;
+                                hot_keys.keep(key, bytes.clone(), generation);
+                                Some(sized_stream(bytes))
+                            }
+                            _ => Some(data),
+                        }
                     }
-                    Err(_) => None,
-                };
+                    data => Some(data),
+                }
+            }
+            CacheHitMiss::Miss => {
+                // trace!("CACHE GET [MISS] {} -> {:?} ", redis_key, &cache_result);
+                None
+            }
+        }
+    }
+
+    async fn get_local_path(&self, key: &str) -> Option<(String, CacheSizeType)> {
+        let local_path = self.storage.local_path(key)?;
+        match self.metadata_db.get_lru_entry(key) {
+            CacheHitMiss::Hit if !self.is_corrupted(key, local_path.1).await => Some(local_path),
+            _ => None,
+        }
+    }
+
+    fn write_path(&self, key: &str) -> Option<PathBuf> {
+        self.storage.write_path(key)
+    }
+
+    fn scan_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        self.metadata_db.scan_lru_keys(cursor, count)
+    }
+
+    fn entry_sizes(&self, keys: &[String]) -> Result<Vec<CacheSizeType>> {
+        self.metadata_db.lru_entry_sizes(keys)
+    }
+
+    fn entry_sha256s(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.metadata_db.lru_entry_sha256s(keys)
+    }
+
+    fn set_entry_url(&self, key: &str, url: &str) -> Result<bool> {
+        self.metadata_db.set_lru_entry_url(key, url)
+    }
+
+    fn entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.metadata_db.lru_entry_urls(keys)
+    }
+
+    fn contains_many(&self, keys: &[String]) -> Result<Vec<bool>> {
+        self.metadata_db.lru_entries_exist(keys)
+    }
+
+    fn preview_eviction(&self, target_size: CacheSizeType) -> Result<Vec<EvictedEntry>> {
+        self.metadata_db.preview_eviction(0, target_size)
+    }
+
+    fn coldest_entries(&self, percent: u64) -> Result<Vec<LruEntryStats>> {
+        let len = self.metadata_db.lru_len()? as u64;
+        // round up, so that a few entries are inspected in small caches
+        let count = ((len * percent.min(100) + 99) / 100) as usize;
+        let mut entries = Vec::with_capacity(count);
+        while entries.len() < count {
+            let batch = self.metadata_db.lru_entry_stats(
+                entries.len(),
+                std::cmp::min(count - entries.len(), PREVIEW_BATCH_SIZE),
+            )?;
+            if batch.is_empty() {
+                break;
+            }
+            entries.extend(batch);
+        }
+        Ok(entries)
+    }
+
+    fn protect(&self, key: &str) -> Result<bool> {
+        self.metadata_db.touch_lru_entry(key)
+    }
+
+    fn confirm_current(&self, key: &str) -> Result<bool> {
+        self.metadata_db.refresh_lru_entry(key)
+    }
+
+    /// Entries cached by earlier versions have no recorded age
+    async fn age(&self, key: &str) -> Option<Duration> {
+        let created_at = match self.metadata_db.lru_entry_created_at(key) {
+            Ok(created_at) => created_at?,
+            Err(e) => {
+                info!("Failed to get the age of {}: {}", key, e);
+                return None;
+            }
+        };
+        Some(Duration::from_secs((util::now() - created_at).max(0) as u64))
+    }
+
+    fn entry_count(&self) -> Result<Option<u64>> {
+        Ok(Some(self.metadata_db.lru_len()? as u64))
+    }
+
+    fn redis_memory(&self) -> Result<Option<u64>> {
+        match self.metadata_db.redis_memory()? {
+            Some(estimate) => Ok(Some(estimate.bytes(self.metadata_db.lru_len()? as u64))),
+            None => Ok(None),
+        }
+    }
+
+    fn total_size(&self) -> Option<CacheSizeType> {
+        Some(self.metadata_db.get_total_size())
+    }
+
+    fn degraded_entries(&self) -> Option<u64> {
+        self.storage.degraded_entries()
+    }
+
+    fn hot_keys(&self) -> Option<Vec<HotKey>> {
+        Some(self.hot_keys.as_ref()?.report())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool> {
+        let removed = retract_entry(&*self.storage, &self.id, key, || {
+            self.metadata_db.remove_lru_entry(key)
+        })
+        .await;
+        self.invalidate_hot_key(key);
+        removed
+    }
+
+    /// Entries are removed from the least recently used one like evicted
+    /// ones, each batch in a single redis round trip, so there is no cursor:
+    /// the cursor of the next batch is not empty until a batch empties the
+    /// cache.
+    async fn purge_batch(&self, _cursor: &str, count: usize) -> Result<(PurgeReport, String)> {
+        let batch: Vec<EvictedEntry> = self
+            .metadata_db
+            .lru_entry_stats(0, count)?
+            .into_iter()
+            .map(|entry| EvictedEntry {
+                key: entry.key,
+                size: entry.size,
+                atime: entry.atime,
+            })
+            .collect();
+        let next = if batch.len() < count { "" } else { "lru" };
+        let purged = self.retract_evicted(batch).await?;
+        let report = PurgeReport {
+            entries: purged.len() as u64,
+            bytes: purged.iter().map(|entry| entry.size).sum(),
+        };
+        Ok((report, next.to_string()))
+    }
+}
+
+/// Number of locks the entries of all caches are striped over
+const ENTRY_LOCK_STRIPES: usize = 256;
+
+lazy_static::lazy_static! {
+    /// Locks of cache entries, striped by cache id and key. They are shared by
+    /// all cache objects, so that the objects before and after a config
+    /// reload, and the expiration threads, mutate an entry one at a time.
+    static ref ENTRY_LOCKS: Vec<tokio::sync::Mutex<()>> =
+        (0..ENTRY_LOCK_STRIPES).map(|_| tokio::sync::Mutex::new(())).collect();
+    /// Cache id and key of the entries whose files are being staged, see
+    /// `commit_entry`
+    static ref STAGING: Mutex<HashSet<(String, String)>> = Mutex::new(HashSet::new());
+}
+
+/// The stripe of the lock of the entry of `key` in the cache `cache_id`
+fn entry_stripe(cache_id: &str, key: &str) -> usize {
+    let hash = util::fnv1a_64(format!("{}/{}", cache_id, key).as_bytes());
+    (hash % ENTRY_LOCK_STRIPES as u64) as usize
+}
+
+/// Lock the entry of `key` in the cache `cache_id`. No other entry is locked
+/// while it is held, so that mutations of entries sharing a stripe cannot
+/// deadlock, see `lock_entries`. It is never held while a file is downloaded.
+async fn lock_entry(cache_id: &str, key: &str) -> tokio::sync::MutexGuard<'static, ()> {
+    ENTRY_LOCKS[entry_stripe(cache_id, key)].lock().await
+}
+
+/// Lock the entries of `keys` in the cache `cache_id`, e.g. evicted ones.
+/// Their stripes are locked once each and in order, so that two tasks
+/// locking several entries cannot deadlock either.
+async fn lock_entries(
+    cache_id: &str,
+    keys: &[String],
+) -> Vec<tokio::sync::MutexGuard<'static, ()>> {
+    let mut stripes: Vec<usize> = keys.iter().map(|key| entry_stripe(cache_id, key)).collect();
+    stripes.sort_unstable();
+    stripes.dedup();
+    let mut guards = Vec::with_capacity(stripes.len());
+    for stripe in stripes {
+        guards.push(ENTRY_LOCKS[stripe].lock().await);
+    }
+    guards
+}
+
+/// The file of an entry being staged, released when dropped
+struct StagingGuard((String, String));
+
+impl StagingGuard {
+    /// `None` if the file of the entry is already being staged
+    fn acquire(cache_id: &str, key: &str) -> Option<Self> {
+        let entry = (cache_id.to_string(), key.to_string());
+        if !STAGING.lock().unwrap().insert(entry.clone()) {
+            return None;
+        }
+        Some(StagingGuard(entry))
+    }
+}
+
+impl Drop for StagingGuard {
+    fn drop(&mut self) {
+        STAGING.lock().unwrap().remove(&self.0);
+    }
+}
+
+// Every mutation of an entry, whether a put, an eviction, a purge, a removal
+// or an expiration, follows the same order under the lock of the entry:
+// - insert: the staged file is published first, then the metadata is committed
+// - delete: the metadata is removed first, then the file
+// so that metadata never describes a missing or partial file, and a reader
+// finding the metadata finds the file, or at worst a miss.
+
+/// Insert an entry: stage its file without any lock, as it takes as long as
+/// the download, then publish it and commit its metadata under the lock of
+/// the entry. If the commit fails, the metadata and the file are retracted,
+/// as a previous entry of the key describes the replaced file. `None` if the
+/// file of the entry is already being staged by another put.
+async fn commit_entry(
+    storage: &dyn StorageBackend,
+    cache_id: &str,
+    key: &str,
+    stage: impl Future<Output = Result<Staged>>,
+    commit: impl FnOnce(&PersistReport) -> Result<()>,
+    retract: impl FnOnce() -> Result<bool>,
+) -> Result<Option<PersistReport>> {
+    let _staging = match StagingGuard::acquire(cache_id, key) {
+        Some(staging) => staging,
+        None => return Ok(None),
+    };
+    let staged = stage.await?;
+    let _guard = lock_entry(cache_id, key).await;
+    let report = storage.publish(key, staged).await?;
+    if let Err(e) = commit(&report) {
+        if let Err(e) = retract() {
+            warn!(key, "failed to remove the previous entry of {}: {}", key, e);
+        }
+        remove_file(storage, key).await;
+        return Err(e);
+    }
+    Ok(Some(report))
+}
+
+/// Delete an entry: remove its metadata, then its file, under the lock of the
+/// entry. Returns whether it existed.
+async fn retract_entry(
+    storage: &dyn StorageBackend,
+    cache_id: &str,
+    key: &str,
+    retract: impl FnOnce() -> Result<bool>,
+) -> Result<bool> {
+    let _guard = lock_entry(cache_id, key).await;
+    if !retract()? {
+        return Ok(false);
+    }
+    remove_file(storage, key).await;
+    Ok(true)
+}
+
+/// Remove the file of an entry whose metadata is removed
+async fn remove_file(storage: &dyn StorageBackend, key: &str) {
+    match storage.remove(key).await {
+        Ok(_) => {
+            increment_counter!(metric::CNT_RM_FILES);
+            info!(key, "removed {}", key);
+        }
+        Err(e) => {
+            warn!(key, "failed to remove file of {}: {}", key, e);
+        }
+    }
+}
+
+/// Longest time to wait for the expiration thread of a TTL cache to exit
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Sliding expiration of the entries of a TTL cache: a hit pushes back the
+/// expiration of an entry to its ttl from now, up to `max_lifetime` secs
+/// after it is set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sliding {
+    pub max_lifetime: Option<u64>,
+}
+
+impl Sliding {
+    /// The new expiration time of an entry of `ttl`, set at `created_at` and
+    /// expiring at `expires_at`, hit at `now` (secs). `None` if it is left
+    /// as is: more than half its ttl remains, so that hot entries are not
+    /// written on every hit, or it reached its max lifetime.
+    pub fn extended(&self, ttl: u64, created_at: i64, expires_at: i64, now: i64) -> Option<i64> {
+        if (expires_at - now) * 2 > ttl as i64 {
+            return None;
+        }
+        let mut extended = now + ttl as i64;
+        if let Some(max_lifetime) = self.max_lifetime {
+            extended = extended.min(created_at + max_lifetime as i64);
+        }
+        Some(extended).filter(|extended| *extended > expires_at)
+    }
+}
+
+pub struct TtlCache {
+    pub ttl: u64,
+    /// How long an expired entry is kept to be served if the upstream fails
+    pub stale_window: u64,
+    /// Extend entries on hits, `None` if they expire after their ttl
+    pub sliding: Option<Sliding>,
+    metadata_db: Arc<dyn TtlMetadataStore>,
+    storage: Arc<dyn StorageBackend>,
+    pub pending_close: Arc<AtomicBool>,
+    pub expiration_thread_handler: Option<JoinHandle<()>>,
+}
+
+impl TtlCache {
+    pub fn new(
+        ttl: u64,
+        metadata_db: Arc<dyn TtlMetadataStore>,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Self {
+        let mut cache = Self {
+            ttl,
+            stale_window: 0,
+            sliding: None,
+            metadata_db,
+            storage,
+            pending_close: Arc::new(AtomicBool::new(false)),
+            expiration_thread_handler: None,
+        };
+        let thread_handler = cache
+            .metadata_db
+            .spawn_expiration_cleanup_thread(&cache.storage, cache.pending_close.clone())
+            .unwrap();
+        cache.expiration_thread_handler = Some(thread_handler);
+        cache
+    }
+
+    pub fn with_stale_window(mut self, stale_window: u64) -> Self {
+        self.stale_window = stale_window;
+        self
+    }
+
+    pub fn with_sliding(mut self, sliding: Option<Sliding>) -> Self {
+        self.sliding = sliding;
+        self
+    }
+
+    /// Whether an entry is hit, extending it with sliding expiration
+    fn get_entry(&self, key: &str) -> CacheHitMiss {
+        match self.sliding {
+            Some(sliding) => self
+                .metadata_db
+                .get_sliding_ttl_entry(key, self.ttl, sliding),
+            None => self.metadata_db.get_ttl_entry(key),
+        }
+    }
+
+    /// Tell the expiration thread to exit. Returns its handle if it is running.
+    fn stop_expiration_thread(&mut self) -> Option<JoinHandle<()>> {
+        self.pending_close.store(true, Ordering::SeqCst);
+        let thread_handler = self.expiration_thread_handler.take()?;
+        // wake up the sled cleaner parked until the next clean interval
+        thread_handler.thread().unpark();
+        Some(thread_handler)
+    }
+}
+
+/// Wait for an expiration thread to exit, but at most `CLOSE_TIMEOUT`, after
+/// which the thread is detached. A panic of the thread is logged.
+fn join_expiration_thread(thread_handler: JoinHandle<()>) {
+    let deadline = std::time::Instant::now() + CLOSE_TIMEOUT;
+    while !thread_handler.is_finished() {
+        if std::time::Instant::now() >= deadline {
+            warn!(
+                "TTL expiration thread did not exit in {:?}, detaching it",
+                CLOSE_TIMEOUT
+            );
+            return;
+        }
+        util::sleep_ms(10);
+    }
+    match thread_handler.join() {
+        Ok(_) => trace!("spawned thread dropped."),
+        Err(e) => error!(
+            "TTL expiration thread panicked: {}",
+            util::panic_message(&*e)
+        ),
+    }
+}
+
+#[async_trait]
+impl Cache for TtlCache {
+    async fn get(&self, key: &str) -> Option<CacheData> {
+        match self.get_entry(key) {
+            CacheHitMiss::Hit => {
+                return match self.storage.read(key).await {
+                    Ok(data) => {
+                        trace!("CACHE GET [HIT] {} -> {:?} ", key, data);
+                        Some(data)
+                    }
+                    Err(_) => None,
+                };
+            }
+            CacheHitMiss::Miss => {
+                trace!("CACHE GET [MISS] {}", key);
+                None
+            }
+        }
+    }
+    async fn put(&mut self, key: &str, entry: CacheData) {
+        let ttl = self.ttl;
+        self.put_with_ttl(key, entry, ttl).await
+    }
+
+    async fn put_with_ttl(&mut self, key: &str, entry: CacheData, ttl: u64) {
+        let commit = |_: &PersistReport| {
+            self.metadata_db.set_ttl_entry(key, ttl, self.stale_window);
+            Ok(())
+        };
+        let retract = || self.metadata_db.remove_ttl_entry(key);
+        let cache_id = self.metadata_db.cache_id();
+        let stage = self.storage.stage(key, entry, None);
+        match commit_entry(&*self.storage, cache_id, key, stage, commit, retract).await {
+            Ok(None) => debug!("{} is already being put", key),
+            Ok(Some(report)) => trace!("persisted {}: {:?}", key, report),
+            Err(e) => error!("failed to persist {}: {}", key, e),
+        }
+    }
+
+    async fn get_local_path(&self, key: &str) -> Option<(String, CacheSizeType)> {
+        let local_path = self.storage.local_path(key)?;
+        match self.get_entry(key) {
+            CacheHitMiss::Hit => Some(local_path),
+            CacheHitMiss::Miss => None,
+        }
+    }
+
+    fn write_path(&self, key: &str) -> Option<PathBuf> {
+        self.storage.write_path(key)
+    }
+
+    async fn get_stale(&self, key: &str) -> Option<CacheData> {
+        match self.metadata_db.staleness(key) {
+            Some(staleness) if staleness <= Duration::from_secs(self.stale_window) => {
+                self.storage.read(key).await.ok()
+            }
+            _ => None,
+        }
+    }
+
+    async fn staleness(&self, key: &str) -> Option<Duration> {
+        self.metadata_db.staleness(key)
+    }
+
+    fn contains_many(&self, keys: &[String]) -> Result<Vec<bool>> {
+        self.metadata_db.ttl_entries_exist(keys)
+    }
+
+    fn set_entry_url(&self, key: &str, url: &str) -> Result<bool> {
+        self.metadata_db.set_ttl_entry_url(key, url)
+    }
+
+    fn entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.metadata_db.ttl_entry_urls(keys)
+    }
+
+    async fn age(&self, key: &str) -> Option<Duration> {
+        let created_at = self.metadata_db.created_at(key)?;
+        Some(Duration::from_secs((util::now() - created_at).max(0) as u64))
+    }
+
+    fn scan_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        self.metadata_db.scan_ttl_keys(cursor, count)
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool> {
+        let cache_id = self.metadata_db.cache_id();
+        retract_entry(&*self.storage, cache_id, key, || {
+            self.metadata_db.remove_ttl_entry(key)
+        })
+        .await
+    }
+
+    fn degraded_entries(&self) -> Option<u64> {
+        self.storage.degraded_entries()
+    }
+
+    /// Stop the expiration thread, and wait for it to exit off the runtime
+    async fn close(&mut self) {
+        if let Some(thread_handler) = self.stop_expiration_thread() {
+            let joined =
+                tokio::task::spawn_blocking(move || join_expiration_thread(thread_handler));
+            if let Err(e) = joined.await {
+                error!("failed to wait for the TTL expiration thread: {}", e);
+            }
+        }
+    }
+}
+
+/// Number of lazy touches flushed in a single script call
+const LAZY_TOUCH_BATCH: usize = 128;
+
+/// Number of lazy touches queued at most, further ones are dropped until the
+/// queue is flushed, so that hits do not pile up while redis is slow
+const LAZY_TOUCH_QUEUE_LIMIT: usize = 8192;
+
+/// Time lazy touches are queued at most before they are flushed
+const LAZY_TOUCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Atimes and hit counts of LRU entries hit with `lazy_atime`, updated in
+/// batches in the background
+struct LazyTouches {
+    redis_client: redis::Client,
+    zlist_key: String,
+    /// Keys of the entries hit, with the atime of the hits
+    queue: Mutex<Vec<(String, i64)>>,
+    /// Whether the task flushing the queue every `LAZY_TOUCH_INTERVAL` is
+    /// spawned
+    flusher: AtomicBool,
+}
+
+impl LazyTouches {
+    fn new(redis_client: redis::Client, zlist_key: String) -> Self {
+        Self {
+            redis_client,
+            zlist_key,
+            queue: Mutex::new(Vec::new()),
+            flusher: AtomicBool::new(false),
+        }
+    }
+
+    /// Queue a hit of `redis_key`, a full batch is flushed right away
+    fn push(self: &Arc<Self>, redis_key: &str, atime: i64) {
+        if !self.flusher.swap(true, Ordering::SeqCst) {
+            let touches = Arc::downgrade(self);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(LAZY_TOUCH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let touches = match touches.upgrade() {
+                        Some(touches) => touches,
+                        None => return,
+                    };
+                    let batch = touches.take();
+                    if !batch.is_empty() {
+                        let _ = tokio::task::spawn_blocking(move || touches.flush(&batch)).await;
+                    }
+                }
+            });
+        }
+        let batch = {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.len() >= LAZY_TOUCH_QUEUE_LIMIT {
+                trace!("lazy touch of {} dropped, the queue is full", redis_key);
+                return;
+            }
+            queue.push((redis_key.to_string(), atime));
+            if queue.len() < LAZY_TOUCH_BATCH {
+                return;
+            }
+            std::mem::take(&mut *queue)
+        };
+        let touches = self.clone();
+        tokio::task::spawn_blocking(move || touches.flush(&batch));
+    }
+
+    fn take(&self) -> Vec<(String, i64)> {
+        std::mem::take(&mut *self.queue.lock().unwrap())
+    }
+
+    fn flush(&self, batch: &[(String, i64)]) {
+        let result = models::get_sync_con(&self.redis_client)
+            .and_then(|mut con| models::touch_lru_cache_entries(&mut con, batch, &self.zlist_key));
+        if let Err(e) = result {
+            info!(
+                "Failed to update the atime of {} cache entries: {}",
+                batch.len(),
+                e
+            );
+        }
+    }
+}
+
+impl Drop for LazyTouches {
+    fn drop(&mut self) {
+        let batch = self.take();
+        if !batch.is_empty() {
+            self.flush(&batch);
+        }
+    }
+}
+
+/// An LRU entry kept in redis: its key, the fields of its hash, e.g. `size`
+/// and `atime`, and its score in the zlist
+pub type RawLruEntry = (String, Vec<(String, String)>, i64);
+
+/// Time a `RedisMemoryEstimate` is reused, so that stats requests and puts do
+/// not sample redis each time
+const REDIS_MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of entries whose memory is sampled
+const REDIS_MEMORY_SAMPLES: usize = 32;
+
+pub struct RedisMetadataDb {
+    redis_client: redis::Client,
+    id: CacheId,
+    /// Update the atime of LRU entries without waiting for redis on cache
+    /// hits, see `with_lazy_atime`
+    lazy_touches: Option<Arc<LazyTouches>>,
+    /// The last estimate of `redis_memory`, and when it was sampled
+    memory_estimate: Mutex<Option<(Instant, RedisMemoryEstimate)>>,
+    memory_sample_interval: Duration,
+    /// The total size last read from or written to redis, used instead while
+    /// redis is unavailable so that evictions keep the cache in its limit
+    last_total_size: AtomicU64,
+    /// Whether redis is unavailable since `last_total_size` was recorded
+    total_size_stale: AtomicBool,
+}
+
+impl RedisMetadataDb {
+    pub fn new(redis_client: redis::Client, id: &str) -> Result<Self> {
+        Ok(Self {
+            redis_client,
+            id: CacheId::new(id)?,
+            lazy_touches: None,
+            memory_estimate: Mutex::new(None),
+            memory_sample_interval: REDIS_MEMORY_SAMPLE_INTERVAL,
+            last_total_size: AtomicU64::new(0),
+            total_size_stale: AtomicBool::new(false),
+        })
+    }
+
+    /// Cache hits only wait for the entry to be found, its atime and hit
+    /// count are updated in batches in the background, at most
+    /// `LAZY_TOUCH_INTERVAL` later
+    pub fn with_lazy_atime(mut self, lazy_atime: bool) -> Self {
+        self.lazy_touches = match lazy_atime {
+            true => Some(Arc::new(LazyTouches::new(
+                self.redis_client.clone(),
+                self.entries_zlist_key(),
+            ))),
+            false => None,
+        };
+        self
+    }
+
+    /// The key of an LRU entry, if `redis_key` is a key of this cache
+    fn from_prefixed_key(&self, redis_key: String) -> Result<String> {
+        PrefixedKey::<Lru>::from_redis(redis_key)
+            .try_strip_prefix(&self.id)
+            .map(CacheKey::into_string)
+    }
+
+    /// The keys of LRU entries of `redis_keys`, malformed keys are logged and
+    /// skipped
+    fn from_prefixed_keys(&self, redis_keys: Vec<String>) -> Vec<String> {
+        redis_keys
+            .into_iter()
+            .filter_map(|redis_key| match self.from_prefixed_key(redis_key) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    warn!("skipped LRU entry: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn to_prefixed_key(&self, cache_key: &str) -> String {
+        PrefixedKey::<Lru>::new(&self.id, &cache_key.into()).into_string()
+    }
+
+    fn total_size_key(&self) -> String {
+        self.to_prefixed_key("total_size")
+    }
+
+    /// returns the key to the zlist that stores the cache entries
+    fn entries_zlist_key(&self) -> String {
+        self.to_prefixed_key("cache_keys")
+    }
+
+    /// Key expiring with a TTL entry
+    fn get_redis_key(id: &CacheId, cache_key: &str) -> String {
+        PrefixedKey::<Ttl>::new(id, &cache_key.into()).into_string()
+    }
+
+    /// The key of the TTL entry of a keyspace notification channel
+    fn ttl_key_of_channel(id: &CacheId, channel: &str) -> Result<String> {
+        let redis_key = keys::keyspace_channel_key(channel)?;
+        PrefixedKey::<Ttl>::from_redis(redis_key.to_string())
+            .try_strip_prefix(id)
+            .map(CacheKey::into_string)
+    }
+
+    /// Key of the hash keeping the creation and expiration time of a TTL entry.
+    /// It does not start with `id`, so it is not watched by the cleaner.
+    fn get_ttl_meta_key(id: &CacheId, cache_key: &str) -> String {
+        format!("ttl_meta/{}/{}", id, cache_key)
+    }
+
+    /// Rescale atimes of LRU entries recorded in secs by earlier versions to
+    /// millisecs, so that they are ordered correctly with new entries.
+    /// Entries recorded in millisecs are left untouched, so it is safe to run
+    /// more than once. Returns the number of entries rescaled.
+    pub fn migrate_atime_to_millis(&self) -> Result<usize> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        models::rescale_lru_atime_secs(&mut con, &self.entries_zlist_key())
+    }
+
+    /// All LRU entries with the fields of their hashes and their zlist
+    /// scores, from the least recently used one, and the recorded total size.
+    /// The entries are read in batches, so they should not change meanwhile.
+    pub fn dump_lru_entries(&self) -> Result<(Vec<RawLruEntry>, CacheSizeType)> {
+        const BATCH: usize = 1000;
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let zlist_key = self.entries_zlist_key();
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let batch = models::zrange_with_scores(&mut con, &zlist_key, offset, BATCH)?;
+            let redis_keys: Vec<String> = batch.iter().map(|(key, _)| key.clone()).collect();
+            let hashes = models::get_hashes(&mut con, &redis_keys)?;
+            offset += batch.len();
+            let len = batch.len();
+            for ((redis_key, score), fields) in batch.into_iter().zip(hashes) {
+                match self.from_prefixed_key(redis_key) {
+                    Ok(key) => entries.push((key, fields, score)),
+                    Err(e) => warn!("skipped LRU entry: {}", e),
+                }
+            }
+            if len < BATCH {
+                break;
+            }
+        }
+        let total_size = models::get(&mut con, &self.total_size_key())?
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(0);
+        self.mirror_total_size(total_size);
+        Ok((entries, total_size))
+    }
+
+    /// Replace all LRU entries and the total size in a single transaction,
+    /// e.g. with entries of `dump_lru_entries`
+    pub fn replace_lru_entries(
+        &self,
+        entries: &[RawLruEntry],
+        total_size: CacheSizeType,
+    ) -> Result<()> {
+        let entries: Vec<RawLruEntry> = entries
+            .iter()
+            .map(|(key, fields, score)| (self.to_prefixed_key(key), fields.clone(), *score))
+            .collect();
+        self.with_con(|con| {
+            models::replace_lru_entries(
+                con,
+                &self.total_size_key(),
+                &self.entries_zlist_key(),
+                &entries,
+                total_size,
+            )
+        })?;
+        self.mirror_total_size(total_size);
+        Ok(())
+    }
+
+    /// The total size recorded in redis, `None` if there is none
+    pub fn recorded_total_size(&self) -> Result<Option<CacheSizeType>> {
+        let size = self.with_con(|con| models::get(con, &self.total_size_key()))?;
+        Ok(size.and_then(|size| size.parse().ok()))
+    }
+
+    /// Move up to `count` LRU entries, from the least recently used one, to
+    /// the cache of `to` in a single transaction, see
+    /// `models::move_lru_entries`. Entries without a hash, or whose member of
+    /// the zlist is not a key of this cache, are dropped. Returns the number
+    /// of entries moved or dropped, 0 once none are left.
+    pub fn move_lru_entries(
+        &self,
+        to: &RedisMetadataDb,
+        count: usize,
+        progress_key: &str,
+    ) -> Result<usize> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let zlist_key = self.entries_zlist_key();
+        let batch = models::zrange_with_scores(&mut con, &zlist_key, 0, count)?;
+        let members: Vec<String> = batch.iter().map(|(member, _)| member.clone()).collect();
+        let exist = models::keys_exist(&mut con, &members)?;
+        let moves: Vec<(String, Option<String>, i64)> = batch
+            .into_iter()
+            .zip(exist)
+            .map(|((member, score), exists)| {
+                let target = match self.from_prefixed_key(member.clone()) {
+                    Ok(key) if exists => Some(to.to_prefixed_key(&key)),
+                    Ok(key) => {
+                        warn!("dropped LRU entry {} of {}: no metadata", key, self.id);
+                        None
+                    }
+                    Err(e) => {
+                        warn!("dropped LRU entry: {}", e);
+                        None
+                    }
+                };
+                (member, target, score)
+            })
+            .collect();
+        models::move_lru_entries(
+            &mut con,
+            &zlist_key,
+            &to.entries_zlist_key(),
+            &moves,
+            progress_key,
+        )?;
+        Ok(moves.len())
+    }
+
+    /// Move the total size and the queued orphaned files to the cache of
+    /// `to`, once the entries are moved
+    pub fn move_lru_totals(&self, to: &RedisMetadataDb) -> Result<()> {
+        let renames = [
+            (self.total_size_key(), to.total_size_key()),
+            (self.orphans_key(), to.orphans_key()),
+        ];
+        self.with_con(|con| models::rename_existing(con, &renames))
+    }
+
+    /// The number of LRU entries whose hash is missing, read in batches
+    pub fn missing_lru_hashes(&self) -> Result<usize> {
+        const BATCH: usize = 1000;
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let zlist_key = self.entries_zlist_key();
+        let mut missing = 0;
+        let mut offset = 0;
+        loop {
+            let batch = models::zrange_with_scores(&mut con, &zlist_key, offset, BATCH)?;
+            let members: Vec<String> = batch.into_iter().map(|(member, _)| member).collect();
+            let exist = models::keys_exist(&mut con, &members)?;
+            missing += exist.iter().filter(|exists| !**exists).count();
+            offset += members.len();
+            if members.len() < BATCH {
+                return Ok(missing);
+            }
+        }
+    }
+
+    /// Sample the memory of up to `REDIS_MEMORY_SAMPLES` entries, spread over
+    /// the LRU list, and of the other keys of the cache. Returns the estimate
+    /// and the number of entries.
+    fn sample_redis_memory(&self) -> Result<(RedisMemoryEstimate, u64)> {
+        let zlist_key = self.entries_zlist_key();
+        let (len, mut usage) = self.with_con(|con| {
+            let len = models::zcard(con, &zlist_key)?;
+            let mut keys = models::sample_zset_members(con, &zlist_key, len, REDIS_MEMORY_SAMPLES)?;
+            keys.push(zlist_key.clone());
+            keys.push(self.total_size_key());
+            Ok((len, models::memory_usage(con, &keys)?))
+        })?;
+        let total_size_bytes = usage.pop().flatten().unwrap_or(0);
+        let zlist_bytes = usage.pop().flatten().unwrap_or(0);
+        // entries removed in between are skipped
+        let hashes: Vec<u64> = usage.into_iter().flatten().collect();
+        let per_entry = match (hashes.len() as u64, len as u64) {
+            (0, _) | (_, 0) => 0,
+            (sampled, len) => hashes.iter().sum::<u64>() / sampled + zlist_bytes / len,
+        };
+        let estimate = RedisMemoryEstimate {
+            per_entry,
+            overhead: total_size_bytes,
+        };
+        Ok((estimate, len as u64))
+    }
+
+    /// Key of the set of orphaned files, see `LruMetadataStore::queue_orphans`.
+    /// It does not start with `id`, so it is not mistaken for an entry.
+    fn orphans_key(&self) -> String {
+        format!("orphans/{}", self.id)
+    }
+
+    /// Record the total size known to redis
+    fn mirror_total_size(&self, size: CacheSizeType) {
+        self.last_total_size.store(size, Ordering::Relaxed);
+        if self.total_size_stale.swap(false, Ordering::Relaxed) {
+            info!(
+                cache_id = %self.id,
+                "redis is back, the total size of {} is {} bytes",
+                self.id,
+                size
+            );
+        }
+    }
+
+    /// The last known total size, while the total size cannot be read from
+    /// redis. The first failure of an outage is logged.
+    fn stale_total_size(&self, e: Error) -> CacheSizeType {
+        let size = self.last_total_size.load(Ordering::Relaxed);
+        if !self.total_size_stale.swap(true, Ordering::Relaxed) {
+            warn!(
+                cache_id = %self.id,
+                "failed to get the total size of {}, the last known size {} bytes is used \
+                 until redis is back: {}",
+                self.id,
+                size,
+                e
+            );
+        }
+        size
+    }
+
+    /// Connect to redis, or log the error if redis is unavailable
+    fn sync_con(&self) -> Option<redis::Connection> {
+        match models::get_sync_con(&self.redis_client) {
+            Ok(con) => Some(con),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// Run `f` with a connection, or fail if redis is unavailable
+    fn with_con<T>(&self, f: impl FnOnce(&mut redis::Connection) -> Result<T>) -> Result<T> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        f(&mut con)
+    }
+
+    /// (expiration time in secs, grace period in secs) of a TTL entry.
+    /// Entries set by earlier versions have no hash, the value of their
+    /// expiring key is the expiration time, if any.
+    fn ttl_expiration(&self, key: &str) -> Option<(i64, i64)> {
+        let mut sync_con = self.sync_con()?;
+        let meta_key = Self::get_ttl_meta_key(&self.id, key);
+        let result = slowlog::time(Category::Redis, "ttl_get", key, || {
+            models::get_ttl_cache_entry(&mut sync_con, &meta_key).and_then(|expiration| {
+                match expiration {
+                    Some(expiration) => Ok(Some(expiration)),
+                    None => models::get(&mut sync_con, &Self::get_redis_key(&self.id, key))
+                        .map(|value| value.map(|value| (value.parse().unwrap_or(i64::MAX), 0))),
+                }
+            })
+        });
+        match result {
+            Ok(expiration) => expiration,
+            Err(e) => {
+                info!("get cache entry key={} failed: {}", key, e);
+                None
+            }
+        }
+    }
+}
+
+impl LruMetadataStore for RedisMetadataDb {
+    fn get_lru_entry(&self, key: &str) -> CacheHitMiss {
+        let redis_key = &self.to_prefixed_key(key);
+        let mut sync_con = match self.sync_con() {
+            Some(con) => con,
+            None => return CacheHitMiss::Miss,
+        };
+        let new_atime = util::atime_millis();
+        let timer = Timer::start(Category::Redis, "lru_get");
+        let hit = if let Some(touches) = &self.lazy_touches {
+            // update the atime in the background, the hit path only waits for EXISTS
+            let exists = models::cache_entry_exists(&mut sync_con, redis_key);
+            if let Ok(true) = exists {
+                touches.push(redis_key, new_atime);
+            }
+            exists
+        } else {
+            models::touch_lru_cache_entry(
+                &mut sync_con,
+                redis_key,
+                new_atime,
+                &self.entries_zlist_key(),
+                true,
+                None,
+            )
+        };
+        timer.finish(key, None);
+        match hit {
+            Ok(true) => {
+                trace!("CACHE GET [HIT] {}", redis_key);
+                CacheHitMiss::Hit
+            }
+            Ok(false) => {
+                trace!("CACHE GET [MISS] {}", redis_key);
+                CacheHitMiss::Miss
+            }
+            Err(e) => {
+                info!("Failed to get cache entry {}: {}", redis_key, e);
+                CacheHitMiss::Miss
+            }
+        }
+    }
+
+    fn set_lru_entry(&self, key: &str, size: CacheSizeType, sha256: Option<&str>) -> Result<()> {
+        let redis_key = &self.to_prefixed_key(key);
+        // the file of the entry in filesystem storages, for tools reading redis
+        let entry = &CacheEntry::new(&storage::encode_file_path(key), size, sha256);
+        let total_size = self.with_con(|con| {
+            slowlog::time(Category::Redis, "lru_set", key, || {
+                models::set_lru_cache_entry(
+                    con,
+                    redis_key,
+                    entry,
+                    &self.total_size_key(),
+                    &self.entries_zlist_key(),
+                )
+            })
+        })?;
+        self.mirror_total_size(total_size);
+        trace!("CACHE SET {} -> {} bytes", &redis_key, size);
+        Ok(())
+    }
+
+    fn eviction_candidates(
+        &self,
+        new_size: CacheSizeType,
+        new_key: &str,
+        size_limit: CacheSizeType,
+        max_entries: Option<u64>,
+    ) -> Result<Eviction> {
+        let redis_key = &self.to_prefixed_key(new_key);
+        let zlist_key = self.entries_zlist_key();
+        let timer = Timer::start(Category::Redis, "lru_evict");
+        let (candidates, replaced_sha256) = self.with_con(|con| {
+            // the state of the cache and of the replaced entry in a single
+            // round trip
+            let (total_size, entries, (replaced_size, replaced_sha256)): (
+                Option<CacheSizeType>,
+                u64,
+                (Option<CacheSizeType>, Option<String>),
+            ) = redis::pipe()
+                .get(self.total_size_key())
+                .zcard(&zlist_key)
+                .hget(redis_key, &["size", "sha256"])
+                .query(con)
+                .map_err(Error::RedisCMDError)?;
+            let total_size = total_size.unwrap_or(0);
+            self.mirror_total_size(total_size);
+            trace!(
+                "current {} + new {} replacing {:?}, limit {}, entries {}, max {:?}",
+                total_size,
+                new_size,
+                replaced_size,
+                size_limit,
+                entries,
+                max_entries
+            );
+            // LRU eviction, the score is the atime
+            let mut batch = std::collections::VecDeque::new();
+            let mut offset = 0;
+            let candidates = select_eviction_candidates::<Error>(
+                total_size,
+                entries,
+                new_size,
+                replaced_size.map(|size| (redis_key.as_str(), size)),
+                size_limit,
+                max_entries,
+                || {
+                    if batch.is_empty() {
+                        let members = models::zrange_with_scores(
+                            con,
+                            &zlist_key,
+                            offset,
+                            PREVIEW_BATCH_SIZE,
+                        )?;
+                        if members.is_empty() {
+                            return Ok(None);
+                        }
+                        offset += members.len();
+                        let keys: Vec<String> =
+                            members.iter().map(|(key, _)| key.clone()).collect();
+                        let sizes = models::get_lru_entry_sizes(con, &keys)?;
+                        batch.extend(members.into_iter().zip(sizes).map(|((key, atime), size)| {
+                            EvictedEntry {
+                                key,
+                                size: size.unwrap_or(0),
+                                // atime in redis is in millisecs
+                                atime: atime / 1000,
+                            }
+                        }));
+                    }
+                    Ok(batch.pop_front())
+                },
+            )?;
+            if max_entries.is_some() {
+                // entries before the new one is set
+                histogram!(
+                    metric::get_cache_entries_metrics_key(self.id.as_str()),
+                    entries.saturating_sub(candidates.len() as u64) as f64
+                );
+            }
+            let replaced = candidates.iter().any(|entry| &entry.key == redis_key);
+            Ok((candidates, replaced_sha256.filter(|_| !replaced)))
+        })?;
+        timer.finish(new_key, Some(new_size));
+        let mut evicted = Vec::with_capacity(candidates.len());
+        let mut malformed = Vec::new();
+        for entry in candidates {
+            match self.from_prefixed_key(entry.key.clone()) {
+                Ok(key) => evicted.push(EvictedEntry { key, ..entry }),
+                Err(e) => {
+                    error!("malformed LRU entry: {}", e);
+                    malformed.push(entry.key);
+                }
+            }
+        }
+        // the metadata of malformed entries is removed, but their files are unknown
+        if !malformed.is_empty() {
+            self.with_con(|con| {
+                models::remove_lru_cache_entries(
+                    con,
+                    &malformed,
+                    &self.total_size_key(),
+                    &zlist_key,
+                )
+            })?;
+        }
+        Ok(Eviction {
+            evicted,
+            replaced_sha256,
+        })
+    }
+
+    fn get_total_size(&self) -> CacheSizeType {
+        let key = self.total_size_key();
+        let size = self.with_con(|con| {
+            con.get::<&str, Option<CacheSizeType>>(&key)
+                .map_err(Error::RedisCMDError)
+        });
+        let size = match size {
+            Ok(size) => {
+                let size = size.unwrap_or(0);
+                self.mirror_total_size(size);
+                size
+            }
+            Err(e) => self.stale_total_size(e),
+        };
+        histogram!(
+            metric::get_cache_size_metrics_key(self.id.as_str()),
+            size as f64
+        );
+        size
+    }
+
+    fn lru_keys(&self, offset: usize, count: usize) -> Vec<String> {
+        let mut con = match models::get_sync_con(&self.redis_client) {
+            Ok(con) => con,
+            Err(e) => {
+                error!("Failed to get LRU keys: {}", e);
+                return vec![];
             }
-            CacheHitMiss::Miss => {
-                // trace!("CACHE GET [MISS] {} -> {:?} ", redis_key, &cache_result);
-                None
+        };
+        match con.zrange::<&str, Vec<String>>(
+            &self.entries_zlist_key(),
+            offset as isize,
+            (offset + count) as isize - 1,
+        ) {
+            Ok(keys) => self.from_prefixed_keys(keys),
+            Err(e) => {
+                error!("Failed to get LRU keys: {}", e);
+                vec![]
             }
         }
     }
-}
 
-pub struct TtlCache {
-    pub ttl: u64,
-    metadata_db: Arc<dyn TtlMetadataStore>,
-    storage: Arc<Storage>,
-    pub pending_close: Arc<AtomicBool>,
-    pub expiration_thread_handler: Option<JoinHandle<()>>,
-}
+    fn lru_entry_tier(&self, key: &str) -> Result<Option<Tier>> {
+        let redis_key = self.to_prefixed_key(key);
+        self.with_con(|con| models::get_lru_cache_entry(con, &redis_key))
+            .map(|entry| entry.and_then(|entry| entry.tier))
+    }
 
-impl TtlCache {
-    pub fn new(ttl: u64, metadata_db: Arc<dyn TtlMetadataStore>, storage: Arc<Storage>) -> Self {
-        let mut cache = Self {
-            ttl,
-            metadata_db,
-            storage,
-            pending_close: Arc::new(AtomicBool::new(false)),
-            expiration_thread_handler: None,
-        };
-        let thread_handler = cache
-            .metadata_db
-            .spawn_expiration_cleanup_thread(&cache.storage, cache.pending_close.clone())
-            .unwrap();
-        cache.expiration_thread_handler = Some(thread_handler);
-        cache
+    fn set_lru_entry_tier(&self, key: &str, tier: Tier) -> Result<bool> {
+        let redis_key = self.to_prefixed_key(key);
+        self.with_con(|con| models::set_existing_hash_field(con, &redis_key, "tier", tier.as_str()))
     }
-}
 
-#[async_trait]
-impl Cache for TtlCache {
-    async fn get(&self, key: &str) -> Option<CacheData> {
-        match self.metadata_db.get_ttl_entry(key) {
-            CacheHitMiss::Hit => {
-                return match self.storage.read(key).await {
-                    Ok(data) => {
-                        trace!("CACHE GET [HIT] {} -> {:?} ", key, data);
-                        Some(data)
+    fn lru_entry_stats(&self, offset: usize, count: usize) -> Result<Vec<LruEntryStats>> {
+        let zlist_key = self.entries_zlist_key();
+        let (members, stats) = self.with_con(|con| {
+            let members = models::zrange_with_scores(con, &zlist_key, offset, count)?;
+            let keys: Vec<String> = members.iter().map(|(key, _)| key.clone()).collect();
+            let stats = models::get_lru_entry_stats(con, &keys)?;
+            Ok((members, stats))
+        })?;
+        // entries removed in between are skipped
+        Ok(members
+            .into_iter()
+            .zip(stats)
+            .filter_map(|((redis_key, atime), stats)| {
+                let (size, hits) = stats?;
+                match self.from_prefixed_key(redis_key) {
+                    Ok(key) => Some(LruEntryStats {
+                        key,
+                        size,
+                        // atime in redis is in millisecs
+                        atime: atime / 1000,
+                        hits,
+                    }),
+                    Err(e) => {
+                        warn!("skipped LRU entry: {}", e);
+                        None
                     }
-                    Err(_) => None,
-                };
-            }
-            CacheHitMiss::Miss => {
-                trace!("CACHE GET [MISS] {}", key);
-                None
-            }
-        }
-    }
-    async fn put(&mut self, key: &str, entry: CacheData) {
-        self.metadata_db.set_ttl_entry(key, &entry, self.ttl);
-        self.storage.persist(key, entry).await;
+                }
+            })
+            .collect())
     }
-}
 
-pub struct RedisMetadataDb {
-    redis_client: redis::Client,
-    id: String,
-}
+    fn lru_len(&self) -> Result<usize> {
+        self.with_con(|con| models::zcard(con, &self.entries_zlist_key()))
+    }
 
-impl RedisMetadataDb {
-    pub fn new(redis_client: redis::Client, id: &str) -> Self {
-        Self {
-            redis_client,
-            id: id.into(),
+    /// Sampled at most every `memory_sample_interval`, otherwise the last
+    /// estimate is returned. Sampling also sets the gauge of the cache.
+    fn redis_memory(&self) -> Result<Option<RedisMemoryEstimate>> {
+        let mut last = self.memory_estimate.lock().unwrap();
+        if let Some((sampled_at, estimate)) = *last {
+            if sampled_at.elapsed() < self.memory_sample_interval {
+                return Ok(Some(estimate));
+            }
         }
+        let (estimate, entries) = self.sample_redis_memory()?;
+        gauge!(
+            metric::GAUGE_REDIS_MEMORY,
+            estimate.bytes(entries) as f64,
+            "cache" => self.id.to_string()
+        );
+        *last = Some((Instant::now(), estimate));
+        Ok(Some(estimate))
     }
 
-    pub fn from_prefixed_key(&self, cache_key: &str) -> String {
-        let cache_key = &cache_key[self.id.len() + 1..];
-        cache_key.to_string()
+    fn lru_entry_sha256(&self, key: &str) -> Result<Option<String>> {
+        self.with_con(|con| models::lru_entry_sha256(con, &self.to_prefixed_key(key)))
     }
 
-    fn to_prefixed_key(&self, cache_key: &str) -> String {
-        format!("{}_{}", self.id, cache_key)
+    fn lru_entry_sha256s(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let redis_keys: Vec<String> = keys.iter().map(|k| self.to_prefixed_key(k)).collect();
+        self.with_con(|con| models::lru_entry_sha256s(con, &redis_keys))
     }
 
-    fn total_size_key(&self) -> String {
-        self.to_prefixed_key("total_size")
+    fn set_lru_entry_url(&self, key: &str, url: &str) -> Result<bool> {
+        let redis_key = self.to_prefixed_key(key);
+        self.with_con(|con| models::set_existing_hash_field(con, &redis_key, "url", url))
     }
 
-    /// returns the key to the zlist that stores the cache entries
-    fn entries_zlist_key(&self) -> String {
-        self.to_prefixed_key("cache_keys")
+    fn lru_entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let redis_keys: Vec<String> = keys.iter().map(|k| self.to_prefixed_key(k)).collect();
+        self.with_con(|con| models::get_hash_field_of_keys(con, &redis_keys, "url"))
     }
 
-    pub fn get_redis_key(id: &str, cache_key: &str) -> String {
-        format!("{}/{}", id, cache_key)
+    /// The total size is reset once there are no entries left, so that any
+    /// drift is dropped
+    fn remove_lru_entries(&self, keys: &[String]) -> Result<Vec<bool>> {
+        let redis_keys: Vec<String> = keys.iter().map(|key| self.to_prefixed_key(key)).collect();
+        self.with_con(|con| {
+            models::remove_lru_cache_entries(
+                con,
+                &redis_keys,
+                &self.total_size_key(),
+                &self.entries_zlist_key(),
+            )
+        })
     }
 
-    pub fn from_redis_key(id: &str, key: &str) -> String {
-        String::from(&key[id.len() + 1..])
+    fn queue_orphans(&self, files: &[String]) -> Result<usize> {
+        self.with_con(|con| models::add_to_set(con, &self.orphans_key(), files))
     }
-}
 
-impl LruMetadataStore for RedisMetadataDb {
-    fn get_lru_entry(&self, key: &str) -> CacheHitMiss {
-        let redis_key = &self.to_prefixed_key(key);
-        let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
-        let cache_result = models::get_cache_entry(&mut sync_con, redis_key).unwrap();
-        match cache_result {
-            Some(_) => {
-                // cache hit
-                // update cache entry in db
-                let new_atime = util::now();
-                match models::update_cache_entry_atime(
-                    &mut sync_con,
-                    redis_key,
-                    new_atime,
-                    &self.entries_zlist_key(),
-                ) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        info!("Failed to update cache entry atime: {}", e);
-                    }
+    fn take_orphans(&self, count: usize) -> Result<(Vec<String>, usize)> {
+        self.with_con(|con| {
+            let (files, left) = models::pop_from_set(con, &self.orphans_key(), count)?;
+            let mut orphans = Vec::with_capacity(files.len());
+            for file in files {
+                // the file of an entry cached again is not an orphan anymore
+                if !models::cache_entry_exists(con, &self.to_prefixed_key(&file))? {
+                    orphans.push(file);
                 }
-                trace!("CACHE GET [HIT] {} -> {:?} ", redis_key, &cache_result);
-                CacheHitMiss::Hit
-            }
-            None => {
-                trace!("CACHE GET [MISS] {} -> {:?} ", redis_key, &cache_result);
-                CacheHitMiss::Miss
             }
-        }
+            Ok((orphans, left))
+        })
     }
 
-    fn set_lru_entry(&self, key: &str, value: &CacheData) {
-        let redis_key = &self.to_prefixed_key(key);
-        let mut con = models::get_sync_con(&self.redis_client).unwrap();
-        let entry = &CacheEntry::new(redis_key, value.len() as CacheSizeType);
-        let _redis_resp_str = models::set_lru_cache_entry(
-            &mut con,
-            redis_key,
-            entry,
-            &self.total_size_key(),
-            &self.entries_zlist_key(),
-        );
-        trace!("CACHE SET {} -> {:?}", &redis_key, value);
+    fn touch_lru_entry(&self, key: &str) -> Result<bool> {
+        self.with_con(|con| {
+            models::touch_lru_cache_entry(
+                con,
+                &self.to_prefixed_key(key),
+                util::atime_millis(),
+                &self.entries_zlist_key(),
+                false,
+                None,
+            )
+        })
     }
 
-    fn evict(
-        &self,
-        new_size: CacheSizeType,
-        new_key: &str,
-        size_limit: CacheSizeType,
-    ) -> Vec<String> {
-        let mut files_to_remove = Vec::new();
-        let redis_key = &self.to_prefixed_key(new_key);
-        let file_size = new_size;
-        let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
-        // evict cache entry if necessary
-        let _tx_result = redis::transaction(
-            &mut sync_con,
-            &[redis_key, &self.total_size_key(), &self.entries_zlist_key()],
-            |con, _pipe| {
-                let mut cur_cache_size = self.get_total_size();
-                while cur_cache_size + file_size > size_limit {
-                    // LRU eviction
-                    trace!(
-                        "current {} + new {} > limit {}",
-                        con.get::<&str, Option<CacheSizeType>>(&self.total_size_key())
-                            .unwrap()
-                            .unwrap_or(0),
-                        file_size,
-                        size_limit
-                    );
-                    let pkg_to_remove: Vec<(String, CacheSizeType)> =
-                        con.zpopmin(&self.entries_zlist_key(), 1).unwrap();
-                    trace!("pkg_to_remove: {:?}", pkg_to_remove);
-                    if pkg_to_remove.is_empty() {
-                        info!("some files need to be evicted but they are missing from redis filelist. The cache metadata is inconsistent.");
-                        return Err(redis::RedisError::from(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "cache metadata inconsistent",
-                        )));
-                    }
-                    files_to_remove.append(
-                        &mut pkg_to_remove
-                            .iter()
-                            .map(|(k, _)| self.from_prefixed_key(k))
-                            .collect(),
-                    );
-                    // remove metadata in redis
-                    for (f, _) in pkg_to_remove {
-                        let pkg_size: Option<CacheSizeType> = con.hget(&f, "size").unwrap();
-                        let _del_cnt = con.del::<&str, isize>(&f);
-                        cur_cache_size = con
-                            .decr::<&str, CacheSizeType, CacheSizeType>(
-                                &self.total_size_key(),
-                                pkg_size.unwrap_or(0),
-                            )
-                            .unwrap();
-                        trace!("total_size -= {:?} -> {}", pkg_size, cur_cache_size);
-                    }
-                }
-                Ok(Some(()))
-            },
-        );
-        files_to_remove
+    fn refresh_lru_entry(&self, key: &str) -> Result<bool> {
+        self.with_con(|con| {
+            models::touch_lru_cache_entry(
+                con,
+                &self.to_prefixed_key(key),
+                util::atime_millis(),
+                &self.entries_zlist_key(),
+                false,
+                Some(util::now()),
+            )
+        })
     }
 
-    fn get_total_size(&self) -> CacheSizeType {
-        let key = self.total_size_key();
-        let mut con = self.redis_client.get_connection().unwrap();
-        let size = con
-            .get::<&str, Option<CacheSizeType>>(&key)
-            .unwrap()
-            .unwrap_or(0);
-        histogram!(metric::get_cache_size_metrics_key(&self.id), size as f64);
-        size
+    fn lru_entry_created_at(&self, key: &str) -> Result<Option<i64>> {
+        self.with_con(|con| models::lru_entry_created_at(con, &self.to_prefixed_key(key)))
+    }
+
+    fn scan_lru_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        let (next, members) = self.with_con(|con| {
+            models::zscan_members(
+                con,
+                &self.entries_zlist_key(),
+                parse_redis_cursor(cursor)?,
+                count,
+            )
+        })?;
+        Ok((self.from_prefixed_keys(members), format_redis_cursor(next)))
+    }
+
+    fn remove_lru_entry(&self, key: &str) -> Result<bool> {
+        self.with_con(|con| {
+            models::remove_lru_cache_entry(
+                con,
+                &self.to_prefixed_key(key),
+                &self.total_size_key(),
+                &self.entries_zlist_key(),
+            )
+        })
+    }
+
+    fn lru_entry_exists(&self, key: &str) -> Result<bool> {
+        self.with_con(|con| models::cache_entry_exists(con, &self.to_prefixed_key(key)))
+    }
+
+    fn lru_entry_sizes(&self, keys: &[String]) -> Result<Vec<CacheSizeType>> {
+        let redis_keys: Vec<String> = keys.iter().map(|k| self.to_prefixed_key(k)).collect();
+        let sizes = self.with_con(|con| models::get_lru_entry_sizes(con, &redis_keys))?;
+        Ok(sizes.into_iter().map(|size| size.unwrap_or(0)).collect())
+    }
+
+    /// A pipelined `EXISTS` of the hashes of the entries
+    fn lru_entries_exist(&self, keys: &[String]) -> Result<Vec<bool>> {
+        let redis_keys: Vec<String> = keys.iter().map(|k| self.to_prefixed_key(k)).collect();
+        self.with_con(|con| models::keys_exist(con, &redis_keys))
     }
 }
 
 impl TtlMetadataStore for RedisMetadataDb {
     fn get_ttl_entry(&self, key: &str) -> CacheHitMiss {
-        let redis_key = Self::get_redis_key(&self.id, key);
-        let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
-        match models::get(&mut sync_con, &redis_key) {
-            Ok(res) => match res {
-                Some(_) => CacheHitMiss::Hit,
-                None => CacheHitMiss::Miss,
-            },
+        match self.ttl_expiration(key) {
+            Some((expires_at, _)) if expires_at >= util::now() => CacheHitMiss::Hit,
+            _ => CacheHitMiss::Miss,
+        }
+    }
+    fn get_sliding_ttl_entry(&self, key: &str, ttl: u64, sliding: Sliding) -> CacheHitMiss {
+        let mut sync_con = match self.sync_con() {
+            Some(con) => con,
+            None => return CacheHitMiss::Miss,
+        };
+        let meta_key = Self::get_ttl_meta_key(&self.id, key);
+        let result = slowlog::time(Category::Redis, "ttl_get", key, || {
+            models::get_ttl_cache_times(&mut sync_con, &meta_key)
+        });
+        let times = match result {
+            Ok(Some(times)) => times,
+            // set by an earlier version, without a hash
+            Ok(None) => return self.get_ttl_entry(key),
             Err(e) => {
                 info!("get cache entry key={} failed: {}", key, e);
-                CacheHitMiss::Miss
+                return CacheHitMiss::Miss;
+            }
+        };
+        let now = util::now();
+        if times.expires_at < now {
+            return CacheHitMiss::Miss;
+        }
+        let extended = times.created_at.and_then(|created_at| {
+            sliding.extended(times.ttl.unwrap_or(ttl), created_at, times.expires_at, now)
+        });
+        if let Some(expires_at) = extended {
+            let redis_key = Self::get_redis_key(&self.id, key);
+            let result = slowlog::time(Category::Redis, "ttl_extend", key, || {
+                models::extend_ttl_cache_entry(
+                    &mut sync_con,
+                    &redis_key,
+                    &meta_key,
+                    &times,
+                    expires_at,
+                    now,
+                )
+            });
+            match result {
+                Ok(true) => trace!("CACHE EXTEND {} until {}", key, expires_at),
+                Ok(false) => {}
+                Err(e) => warn!("failed to extend the expiration of {}: {}", key, e),
             }
         }
+        CacheHitMiss::Hit
     }
-    fn set_ttl_entry(&self, key: &str, _value: &CacheData, ttl: u64) {
-        let redis_key = Self::get_redis_key(&self.id, key);
-        let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
-        match models::set(&mut sync_con, &redis_key, "") {
-            Ok(_) => {}
+    /// A pipelined `GET` of the expiration times kept as the values of the
+    /// keys of the entries, the hashes of entries set by earlier versions
+    /// are not read
+    fn ttl_entries_exist(&self, keys: &[String]) -> Result<Vec<bool>> {
+        let redis_keys: Vec<String> = keys
+            .iter()
+            .map(|key| Self::get_redis_key(&self.id, key))
+            .collect();
+        let expirations = self.with_con(|con| models::get_ttl_expirations(con, &redis_keys))?;
+        let now = util::now();
+        Ok(expirations
+            .into_iter()
+            .map(|expires_at| expires_at.map_or(false, |expires_at| expires_at >= now))
+            .collect())
+    }
+    /// The url is kept in the hash of the times of the entry, removed with it
+    fn set_ttl_entry_url(&self, key: &str, url: &str) -> Result<bool> {
+        let meta_key = Self::get_ttl_meta_key(&self.id, key);
+        self.with_con(|con| models::set_existing_hash_field(con, &meta_key, "url", url))
+    }
+    fn ttl_entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let meta_keys: Vec<String> = keys
+            .iter()
+            .map(|key| Self::get_ttl_meta_key(&self.id, key))
+            .collect();
+        self.with_con(|con| models::get_hash_field_of_keys(con, &meta_keys, "url"))
+    }
+    fn staleness(&self, key: &str) -> Option<Duration> {
+        let (expires_at, _) = self.ttl_expiration(key)?;
+        Some(Duration::from_secs((util::now() - expires_at).max(0) as u64))
+    }
+    fn created_at(&self, key: &str) -> Option<i64> {
+        let mut sync_con = self.sync_con()?;
+        let meta_key = Self::get_ttl_meta_key(&self.id, key);
+        match models::get_ttl_created_at(&mut sync_con, &meta_key) {
+            Ok(created_at) => created_at,
             Err(e) => {
-                error!("set cache entry for {} failed: {}", key, e);
+                info!("get creation time of {} failed: {}", key, e);
+                None
             }
         }
-        match models::expire(&mut sync_con, &redis_key, ttl as usize) {
+    }
+    fn set_ttl_entry(&self, key: &str, ttl: u64, grace: u64) {
+        let redis_key = Self::get_redis_key(&self.id, key);
+        let meta_key = Self::get_ttl_meta_key(&self.id, key);
+        let mut sync_con = match self.sync_con() {
+            Some(con) => con,
+            None => return,
+        };
+        let created_at = util::now();
+        let result = slowlog::time(Category::Redis, "ttl_set", key, || {
+            models::set_ttl_cache_entry(
+                &mut sync_con,
+                &redis_key,
+                &meta_key,
+                created_at,
+                ttl,
+                grace,
+            )
+        });
+        match result {
             Ok(_) => {}
             Err(e) => {
-                error!("set cache entry ttl for {} failed: {}", key, e);
+                error!("set cache entry for {} failed: {}", key, e);
             }
         }
         trace!("CACHE SET {} TTL={}", &key, ttl);
     }
 
+    fn scan_ttl_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        let prefix = PrefixedKey::<Ttl>::prefix(&self.id);
+        let (next, redis_keys) = self.with_con(|con| {
+            models::scan_prefixed_keys(con, &prefix, parse_redis_cursor(cursor)?, count)
+        })?;
+        let keys = redis_keys
+            .into_iter()
+            .filter_map(|redis_key| {
+                PrefixedKey::<Ttl>::from_redis(redis_key)
+                    .try_strip_prefix(&self.id)
+                    .ok()
+            })
+            .map(CacheKey::into_string)
+            .collect();
+        Ok((keys, format_redis_cursor(next)))
+    }
+
+    fn remove_ttl_entry(&self, key: &str) -> Result<bool> {
+        self.with_con(|con| {
+            models::remove_ttl_cache_entry(
+                con,
+                &Self::get_redis_key(&self.id, key),
+                &Self::get_ttl_meta_key(&self.id, key),
+            )
+        })
+    }
+
+    fn cache_id(&self) -> &str {
+        self.id.as_str()
+    }
+
     fn spawn_expiration_cleanup_thread(
         &self,
-        storage: &Storage,
+        storage: &Arc<dyn StorageBackend>,
         pending_close: Arc<AtomicBool>,
     ) -> Result<JoinHandle<()>> {
         let cloned_client = self.redis_client.clone();
-        let id_clone = self.id.to_string();
+        let id_clone = self.id.clone();
         let storage_clone = storage.clone();
         let pending_close_clone = pending_close;
 
@@ -503,9 +2607,12 @@ impl TtlMetadataStore for RedisMetadataDb {
                     }
                     match cloned_client.get_connection() {
                         Ok(mut con) => {
+                            // the subscribed connection cannot send other commands
+                            let mut cmd_con = cloned_client.get_connection().ok();
                             let mut pubsub = con.as_pubsub();
                             trace!("subscribe to cache key pattern: {}", &id_clone);
-                            match pubsub.psubscribe(format!("__keyspace*__:{}*", &id_clone)) {
+                            let prefix = PrefixedKey::<Ttl>::prefix(&id_clone);
+                            match pubsub.psubscribe(keys::keyspace_pattern(&prefix)) {
                                 Ok(_) => {}
                                 Err(e) => {
                                     error!("Failed to psubscribe: {}", e);
@@ -524,8 +2631,17 @@ impl TtlMetadataStore for RedisMetadataDb {
                                     Ok(msg) => {
                                         let channel: String = msg.get_channel().unwrap();
                                         let payload: String = msg.get_payload().unwrap();
-                                        let redis_key = &channel[channel.find(':').unwrap() + 1..];
-                                        let file = Self::from_redis_key(&id_clone, redis_key);
+                                        let file =
+                                            match Self::ttl_key_of_channel(&id_clone, &channel) {
+                                                Ok(key) => key,
+                                                Err(e) => {
+                                                    warn!(
+                                                        "TTL cache ignored a notification: {}",
+                                                        e
+                                                    );
+                                                    continue;
+                                                }
+                                            };
                                         trace!(
                                             "channel '{}': payload {}, file: {}",
                                             msg.get_channel_name(),
@@ -535,14 +2651,43 @@ impl TtlMetadataStore for RedisMetadataDb {
                                         if payload != "expired" {
                                             continue;
                                         }
-                                        match storage_clone.remove(&file).await {
-                                            Ok(_) => {
-                                                increment_counter!(metric::CNT_RM_FILES);
-                                                info!("TTL cache removed {}", &file);
+                                        let meta_key = Self::get_ttl_meta_key(&id_clone, &file);
+                                        let retract = || {
+                                            // without a connection, the metadata is left
+                                            // behind past its stale window, never served
+                                            let con = match cmd_con.as_mut() {
+                                                Some(con) => con,
+                                                None => return Ok(true),
+                                            };
+                                            let expiration =
+                                                models::get_ttl_cache_entry(con, &meta_key);
+                                            if let Ok(Some((expires_at, grace))) = expiration {
+                                                // the entry is set again after the key expired
+                                                if util::now() < expires_at + grace {
+                                                    debug!(
+                                                        "TTL cache kept {}, it is set again",
+                                                        &file
+                                                    );
+                                                    return Ok(false);
+                                                }
                                             }
-                                            Err(e) => {
-                                                warn!("Failed to remove {}: {}", &file, e);
+                                            if let Err(e) = models::del(con, &meta_key) {
+                                                warn!(
+                                                    "Failed to remove metadata of {}: {}",
+                                                    &file, e
+                                                );
                                             }
+                                            Ok(true)
+                                        };
+                                        if let Ok(true) = retract_entry(
+                                            &*storage_clone,
+                                            id_clone.as_str(),
+                                            &file,
+                                            retract,
+                                        )
+                                        .await
+                                        {
+                                            info!("TTL cache expired {}", &file);
                                         }
                                     }
                                     Err(e) => {
@@ -573,17 +2718,34 @@ impl TtlMetadataStore for RedisMetadataDb {
     }
 }
 
+/// Redis cursors are numbers, the scan starts and ends at 0
+fn parse_redis_cursor(cursor: &str) -> Result<u64> {
+    if cursor.is_empty() {
+        return Ok(0);
+    }
+    cursor
+        .parse()
+        .map_err(|_| Error::OtherError(format!("invalid scan cursor: {}", cursor)))
+}
+
+fn format_redis_cursor(cursor: u64) -> String {
+    match cursor {
+        0 => String::new(),
+        cursor => cursor.to_string(),
+    }
+}
+
 impl Drop for TtlCache {
-    /// The spawned key expiration handler thread needs to be dropped.
+    /// The spawned key expiration handler thread needs to be dropped, if the
+    /// cache is not closed. Never blocks the async runtime, nor panics.
     fn drop(&mut self) {
-        self.pending_close
-            .store(true, std::sync::atomic::Ordering::SeqCst);
-        if let Some(thread_handler) = self.expiration_thread_handler.take() {
-            thread_handler.thread().unpark();
-            thread_handler.join().unwrap();
-            trace!("spawned thread dropped.");
-        } else {
-            warn!("expiration_thread_handler is None! If the thread is not spawned in the first place, the cache may have not been working properly. Otherwise, a thread is leaked.");
+        if let Some(thread_handler) = self.stop_expiration_thread() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn_blocking(move || join_expiration_thread(thread_handler));
+                }
+                Err(_) => join_expiration_thread(thread_handler),
+            }
         }
     }
 }
@@ -632,6 +2794,48 @@ impl SledMetadataDb {
         }
     }
 
+    /// Update the atime of an LRU entry without counting a hit, and replace
+    /// its `created_at` if set. Returns whether it exists.
+    fn update_lru_entry_atime(&self, key: &str, created_at: Option<i64>) -> Result<bool> {
+        let tx_result: TransactionResult<_, TransactionError> =
+            (&self.metadata_tree, &self.atime_tree).transaction(|(metadata_tree, atime_tree)| {
+                if metadata_tree.get(key)?.is_none() {
+                    return Ok(false);
+                }
+                models::sled_update_cache_entry_atime(
+                    metadata_tree,
+                    atime_tree,
+                    key,
+                    util::atime_nanos(),
+                    false,
+                    created_at,
+                );
+                Ok(true)
+            });
+        tx_result.map_err(|e| Error::OtherError(format!("failed to touch {}: {:?}", key, e)))
+    }
+
+    /// Scan the keys of the metadata tree in order. The cursor is the last
+    /// key of the previous batch.
+    fn scan_metadata_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        let entries = match cursor {
+            "" => self.metadata_tree.iter(),
+            cursor => self
+                .metadata_tree
+                .range::<&[u8], _>((Bound::Excluded(cursor.as_bytes()), Bound::Unbounded)),
+        };
+        let mut keys = Vec::new();
+        for entry in entries.take(count) {
+            let (key, _) = entry.map_err(Error::SledError)?;
+            keys.push(String::from_utf8_lossy(key.as_ref()).into_owned());
+        }
+        let next = match keys.last() {
+            Some(last) if keys.len() == count => last.clone(),
+            _ => String::new(),
+        };
+        Ok((keys, next))
+    }
+
     /// Open db, and retry if fails
     /// Reference: https://github.com/spacejam/sled/issues/1234
     fn open_db(path: impl AsRef<Path>) -> Result<sled::Db> {
@@ -669,12 +2873,14 @@ impl LruMetadataStore for SledMetadataDb {
                 match metadata_tree.get(key) {
                     Ok(Some(_)) => {
                         // update cache entry in db
-                        let new_atime = util::now_nanos();
+                        let new_atime = util::atime_nanos();
                         models::sled_update_cache_entry_atime(
                             metadata_tree,
                             atime_tree,
                             key,
                             new_atime,
+                            true,
+                            None,
                         );
                         Ok(CacheHitMiss::Hit)
                     }
@@ -690,25 +2896,49 @@ impl LruMetadataStore for SledMetadataDb {
         }
     }
 
-    fn set_lru_entry(&self, key: &str, value: &CacheData) {
-        let atime = util::now_nanos();
+    fn set_lru_entry(&self, key: &str, size: CacheSizeType, sha256: Option<&str>) -> Result<()> {
+        // atimes are keys of the atime tree, so they must be unique
+        let atime = util::atime_nanos();
         let db_tree: &sled::Tree = &self.db;
         let tx_result: TransactionResult<_, TransactionError> =
             (db_tree, &self.metadata_tree, &self.atime_tree).transaction(
                 |(db, metadata_tree, atime_tree)| {
+                    let unchanged = match metadata_tree.get(key)? {
+                        Some(old) => {
+                            let old = SledMetadata::from(old);
+                            sha256.is_some() && old.sha256.as_deref() == sha256 && old.size == size
+                        }
+                        None => false,
+                    };
+                    if unchanged {
+                        models::sled_update_cache_entry_atime(
+                            metadata_tree,
+                            atime_tree,
+                            key,
+                            atime,
+                            false,
+                            Some(util::now()),
+                        );
+                        return Ok(());
+                    }
                     models::sled_insert_cache_entry(
                         db,
                         &self.cf,
                         metadata_tree,
                         atime_tree,
                         key,
-                        value.len() as CacheSizeType,
-                        atime,
+                        SledMetadata {
+                            atime,
+                            size,
+                            hits: 0,
+                            sha256: sha256.map(String::from),
+                            created_at: Some(util::now()),
+                        },
                     );
                     let current_size = models::sled_lru_get_current_size(db, &self.cf)
                         .unwrap()
                         .unwrap()
-                        + value.len() as CacheSizeType;
+                        + size;
                     models::sled_lru_set_current_size(db, &self.cf, current_size);
                     histogram!(
                         metric::get_cache_size_metrics_key(&self.cf),
@@ -717,83 +2947,184 @@ impl LruMetadataStore for SledMetadataDb {
                     Ok(())
                 },
             );
-        match tx_result {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to set_lru_entry: {}", e);
-            }
-        };
+        tx_result.map_err(|e| Error::OtherError(format!("failed to set {}: {:?}", key, e)))
     }
 
-    /// Run eviction policy if needed, reserve at least `size` for new cache entry.
-    fn evict(
+    /// Counting the entries takes a scan, so they are only counted if there
+    /// is a limit on them.
+    fn eviction_candidates(
         &self,
-        evict_size: CacheSizeType,
-        _new_key: &str,
+        new_size: CacheSizeType,
+        new_key: &str,
         size_limit: CacheSizeType,
-    ) -> Vec<String> {
-        let mut files_to_remove = Vec::new();
-        let db = &self.db;
-        let prefix = &self.cf;
-        let default_tree: &sled::Tree = db;
-        let atime_tree = &self.atime_tree;
-        let metadata_tree = &self.metadata_tree;
-        while models::sled_lru_get_current_size_notx(db, prefix)
-            .unwrap()
-            .unwrap()
-            + evict_size
-            > size_limit
-        {
-            // read a possible eviction candidate, multiple threads may read the same one
-            if let Ok(Some(atime_tree_val)) = atime_tree.first() {
-                // An eviction is atomic
-                let tx_result: sled::transaction::TransactionResult<_, ()> =
-                    (default_tree, atime_tree, metadata_tree).transaction::<_, _>(
-                        |(db, atime_tree, metadata_tree)| {
-                            match atime_tree.get(&atime_tree_val.0) {
-                                Ok(Some(_)) => {
-                                    // transactions in sled are serializable, continue
-                                    let filename: &str =
-                                        std::str::from_utf8(atime_tree_val.1.as_ref()).unwrap();
-                                    let entry: SledMetadata =
-                                        metadata_tree.get(filename).unwrap().unwrap().into();
-                                    let file_size = entry.size;
-                                    let cache_size = models::sled_lru_get_current_size(db, prefix)
-                                        .unwrap()
-                                        .unwrap()
-                                        - file_size;
-                                    models::sled_lru_set_current_size(db, prefix, cache_size);
-                                    histogram!(
-                                        metric::get_cache_size_metrics_key(&self.cf),
-                                        cache_size as f64
-                                    );
-                                    metadata_tree.remove(filename).unwrap();
-                                    atime_tree.remove(&atime_tree_val.0).unwrap();
-                                    Ok(Some(filename.to_string()))
-                                }
-                                _ => {
-                                    // some other thread would remove the entry
-                                    Ok(None)
-                                }
-                            }
-                        },
-                    );
-                if let Some(filename) = tx_result.unwrap() {
-                    files_to_remove.push(filename);
+        max_entries: Option<u64>,
+    ) -> Result<Eviction> {
+        let entries = match max_entries {
+            Some(_) => self.atime_tree.len() as u64,
+            None => 0,
+        };
+        // the entry replaced by the new one leaves its size and its place
+        let replaced = self
+            .metadata_tree
+            .get(new_key)
+            .map_err(Error::SledError)?
+            .map(SledMetadata::from);
+        let replaced_size = replaced.as_ref().map(|entry| entry.size);
+        let replaced_sha256 = replaced.and_then(|entry| entry.sha256);
+        let mut lru = self.atime_tree.iter();
+        let candidates = select_eviction_candidates::<Error>(
+            self.get_total_size(),
+            entries,
+            new_size,
+            replaced_size.map(|size| (new_key, size)),
+            size_limit,
+            max_entries,
+            || {
+                for entry in lru.by_ref() {
+                    let (atime_key, key) = entry.map_err(Error::SledError)?;
+                    let key = String::from_utf8_lossy(key.as_ref()).into_owned();
+                    match self.metadata_tree.get(&key).map_err(Error::SledError)? {
+                        Some(entry) => {
+                            let entry = SledMetadata::from(entry);
+                            return Ok(Some(EvictedEntry {
+                                key,
+                                size: entry.size,
+                                // atime in sled is in nanosecs
+                                atime: entry.atime / 1_000_000_000,
+                            }));
+                        }
+                        None => {
+                            // drop the dangling atime entry
+                            error!("{}", Error::CacheMetadataInconsistent(key));
+                            self.atime_tree
+                                .remove(atime_key)
+                                .map_err(Error::SledError)?;
+                        }
+                    }
                 }
-            }
+                Ok(None)
+            },
+        )?;
+        if max_entries.is_some() {
+            // entries before the new one is set
+            histogram!(
+                metric::get_cache_entries_metrics_key(&self.cf),
+                entries.saturating_sub(candidates.len() as u64) as f64
+            );
         }
-        files_to_remove
+        let replaced = candidates.iter().any(|entry| entry.key == new_key);
+        Ok(Eviction {
+            evicted: candidates,
+            replaced_sha256: replaced_sha256.filter(|_| !replaced),
+        })
     }
 
     fn get_total_size(&self) -> CacheSizeType {
-        self.db
-            .transaction::<_, _, ()>(|tx_db| {
-                Ok(models::sled_lru_get_current_size(tx_db, &self.cf)
-                    .unwrap()
-                    .unwrap())
+        match models::sled_lru_get_current_size_notx(&self.db, &self.cf) {
+            Ok(size) => size.unwrap_or(0),
+            Err(e) => {
+                error!("Failed to get total size: {}", e);
+                0
+            }
+        }
+    }
+
+    fn lru_keys(&self, offset: usize, count: usize) -> Vec<String> {
+        self.atime_tree
+            .iter()
+            .skip(offset)
+            .take(count)
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(_, key)| str::from_utf8(key.as_ref()).ok().map(String::from))
+            .collect()
+    }
+
+    fn lru_entry_stats(&self, offset: usize, count: usize) -> Result<Vec<LruEntryStats>> {
+        let mut entries = Vec::new();
+        for entry in self.atime_tree.iter().skip(offset).take(count) {
+            let (_, key) = entry.map_err(Error::SledError)?;
+            let key = String::from_utf8_lossy(key.as_ref()).into_owned();
+            // evictions drop atime entries without metadata
+            if let Some(entry) = self.metadata_tree.get(&key).map_err(Error::SledError)? {
+                let entry = SledMetadata::from(entry);
+                entries.push(LruEntryStats {
+                    key,
+                    size: entry.size,
+                    atime: entry.atime / 1_000_000_000,
+                    hits: entry.hits,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn lru_len(&self) -> Result<usize> {
+        Ok(self.atime_tree.len())
+    }
+
+    fn lru_entry_sha256(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .metadata_tree
+            .get(key)
+            .map_err(Error::SledError)?
+            .and_then(|entry| SledMetadata::from(entry).sha256))
+    }
+
+    fn touch_lru_entry(&self, key: &str) -> Result<bool> {
+        self.update_lru_entry_atime(key, None)
+    }
+
+    fn refresh_lru_entry(&self, key: &str) -> Result<bool> {
+        self.update_lru_entry_atime(key, Some(util::now()))
+    }
+
+    fn lru_entry_created_at(&self, key: &str) -> Result<Option<i64>> {
+        Ok(self
+            .metadata_tree
+            .get(key)
+            .map_err(Error::SledError)?
+            .and_then(|entry| SledMetadata::from(entry).created_at))
+    }
+
+    fn scan_lru_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        self.scan_metadata_keys(cursor, count)
+    }
+
+    fn remove_lru_entry(&self, key: &str) -> Result<bool> {
+        let default_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> =
+            (default_tree, &self.atime_tree, &self.metadata_tree).transaction(
+                |(db, atime_tree, metadata_tree)| {
+                    let entry: SledMetadata = match metadata_tree.remove(key)? {
+                        Some(entry) => entry.into(),
+                        None => return Ok(false),
+                    };
+                    atime_tree.remove(&entry.atime.to_be_bytes())?;
+                    let cache_size = models::sled_lru_get_current_size(db, &self.cf)
+                        .unwrap()
+                        .unwrap_or(0)
+                        .saturating_sub(entry.size);
+                    models::sled_lru_set_current_size(db, &self.cf, cache_size);
+                    Ok(true)
+                },
+            );
+        tx_result.map_err(|e| Error::OtherError(format!("failed to remove {}: {:?}", key, e)))
+    }
+
+    fn lru_entry_sizes(&self, keys: &[String]) -> Result<Vec<CacheSizeType>> {
+        keys.iter()
+            .map(|key| match self.metadata_tree.get(key) {
+                Ok(Some(entry)) => Ok(SledMetadata::from(entry).size),
+                Ok(None) => Ok(0),
+                Err(e) => Err(Error::SledError(e)),
             })
-            .unwrap()
+            .collect()
+    }
+
+    fn lru_entry_exists(&self, key: &str) -> Result<bool> {
+        self.metadata_tree
+            .contains_key(key)
+            .map_err(Error::SledError)
     }
 }
 
@@ -816,24 +3147,82 @@ impl TtlMetadataStore for SledMetadataDb {
         }
     }
 
-    fn set_ttl_entry(&self, key: &str, _value: &CacheData, ttl: u64) {
+    /// The creation time of entries is not kept, so settings validation
+    /// allows sliding expiration with redis only
+    fn get_sliding_ttl_entry(&self, key: &str, _ttl: u64, _sliding: Sliding) -> CacheHitMiss {
+        self.get_ttl_entry(key)
+    }
+
+    fn staleness(&self, key: &str) -> Option<Duration> {
+        // entries are kept until removed by the cleanup thread
+        match self.metadata_tree.get(key) {
+            Ok(Some(val)) => {
+                let exp_time: i64 = i64::from_be_bytes(val.as_ref().try_into().unwrap());
+                Some(Duration::from_nanos(
+                    (util::now_nanos() - exp_time).max(0) as u64
+                ))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("failed to get ttl entry {}: {:?}", key, e);
+                None
+            }
+        }
+    }
+
+    fn created_at(&self, _key: &str) -> Option<i64> {
+        // only the expiration time is kept
+        None
+    }
+
+    fn set_ttl_entry(&self, key: &str, ttl: u64, grace: u64) {
         let _tx_result: TransactionResult<_, ()> = (&self.atime_tree, &self.metadata_tree)
             .transaction(|(atime_tree, metadata_tree)| {
-                let expire_time = (util::now_nanos() + ttl as i64 * 1_000_000_000).to_be_bytes();
-                atime_tree.insert(&expire_time, key).unwrap();
-                metadata_tree.insert(key, &expire_time).unwrap();
+                let expire_time = util::now_nanos() + ttl as i64 * 1_000_000_000;
+                // the atime tree is ordered by the time the entry is removed
+                let remove_time = expire_time + grace as i64 * 1_000_000_000;
+                // an entry set again is not removed at the time of the previous one
+                if let Some(previous) = metadata_tree.get(key)? {
+                    let previous = i64::from_be_bytes(previous.as_ref().try_into().unwrap());
+                    let previous = (previous + grace as i64 * 1_000_000_000).to_be_bytes();
+                    if let Some(owner) = atime_tree.get(&previous[..])? {
+                        if owner.as_ref() == key.as_bytes() {
+                            atime_tree.remove(&previous[..])?;
+                        }
+                    }
+                }
+                atime_tree.insert(&remove_time.to_be_bytes(), key).unwrap();
+                metadata_tree
+                    .insert(key, &expire_time.to_be_bytes())
+                    .unwrap();
                 Ok(())
             });
         trace!("CACHE SET {} TTL={}", &key, ttl);
     }
 
+    fn scan_ttl_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        self.scan_metadata_keys(cursor, count)
+    }
+
+    /// The entry in the atime tree is left to the cleanup thread, which
+    /// finds the metadata and the file already removed.
+    fn remove_ttl_entry(&self, key: &str) -> Result<bool> {
+        let removed = self.metadata_tree.remove(key).map_err(Error::SledError)?;
+        Ok(removed.is_some())
+    }
+
+    fn cache_id(&self) -> &str {
+        &self.cf
+    }
+
     fn spawn_expiration_cleanup_thread(
         &self,
-        storage: &Storage,
+        storage: &Arc<dyn StorageBackend>,
         pending_close: Arc<AtomicBool>,
     ) -> Result<JoinHandle<()>> {
         let storage_clone = storage.clone();
         let pending_close_clone = pending_close;
+        let cf = self.cf.clone();
         let atime_tree = self.atime_tree.clone();
         let metadata_tree = self.metadata_tree.clone();
         let clean_interval = self.clean_interval;
@@ -845,31 +3234,40 @@ impl TtlMetadataStore for SledMetadataDb {
                         return;
                     }
                     let time = util::now_nanos();
-                    let files_to_remove: Vec<String> = atime_tree
+                    let expired: Vec<(sled::IVec, sled::IVec)> = atime_tree
                         .range(..time.to_be_bytes())
-                        .map(|e| {
-                            let e = e.unwrap();
-                            let key = std::str::from_utf8(e.1.as_ref()).unwrap();
-                            let _tx_result: TransactionResult<_, ()> =
-                                (&atime_tree, &metadata_tree).transaction(
-                                    |(atime_tree, metadata_tree)| {
-                                        atime_tree.remove(&e.0).unwrap();
-                                        metadata_tree.remove(&e.1).unwrap();
-                                        Ok(())
-                                    },
-                                );
-                            key.to_string()
-                        })
+                        .map(|e| e.unwrap())
                         .collect();
-                    for key in files_to_remove {
-                        match storage_clone.remove(&key).await {
-                            Ok(_) => {
-                                increment_counter!(metric::CNT_RM_FILES);
-                                info!("TTL cache removed {}", &key);
-                            }
-                            Err(e) => {
-                                warn!("Failed to remove {}: {}.", &key, e);
-                            }
+                    for (remove_time, key) in expired {
+                        let key = String::from_utf8_lossy(&key).into_owned();
+                        let retract = || {
+                            let tx_result: TransactionResult<_, ()> = (&atime_tree, &metadata_tree)
+                                .transaction(|(atime_tree, metadata_tree)| {
+                                    atime_tree.remove(&remove_time)?;
+                                    let expire_time = match metadata_tree.get(&key)? {
+                                        Some(val) => {
+                                            i64::from_be_bytes(val.as_ref().try_into().unwrap())
+                                        }
+                                        None => return Ok(false),
+                                    };
+                                    // the entry is set again to expire later
+                                    let removed_at = i64::from_be_bytes(
+                                        remove_time.as_ref().try_into().unwrap(),
+                                    );
+                                    if expire_time > removed_at {
+                                        return Ok(false);
+                                    }
+                                    metadata_tree.remove(key.as_bytes())?;
+                                    Ok(true)
+                                });
+                            tx_result.map_err(|e| {
+                                Error::OtherError(format!("failed to expire {}: {:?}", key, e))
+                            })
+                        };
+                        match retract_entry(&*storage_clone, &cf, &key, retract).await {
+                            Ok(true) => info!("TTL cache expired {}", &key),
+                            Ok(false) => {}
+                            Err(e) => warn!("Failed to remove {}: {}.", &key, e),
                         }
                     }
                     // park the thread, and unpark it when `drop` is called so that
@@ -889,56 +3287,384 @@ pub struct CacheEntry<Metadata, Key, Value> {
     pub value: Value,
 }
 
-#[derive(Debug)]
-pub struct LruCacheMetadata {
-    pub size: CacheSizeType,
-    pub atime: i64, // last access timestamp
-}
-
-impl CacheEntry<LruCacheMetadata, String, ()> {
-    pub fn new(path: &str, size: u64) -> CacheEntry<LruCacheMetadata, String, ()> {
+impl CacheEntry<EntryMetadata, String, ()> {
+    pub fn new(
+        path: &str,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> CacheEntry<EntryMetadata, String, ()> {
         CacheEntry {
-            metadata: LruCacheMetadata {
+            metadata: EntryMetadata {
+                path: String::from(path),
                 size,
-                atime: util::now(),
+                atime: util::atime_millis(),
+                sha256: sha256.map(String::from),
+                created_at: Some(util::now()),
+                ..Default::default()
             },
             key: String::from(path),
             value: (),
         }
     }
+}
+
+/// Number of points of each shard on the hash ring
+const VIRTUAL_NODES: usize = 160;
+
+/// A consistent hash ring. Each shard is placed on the ring at points derived
+/// from its id, so adding a shard only moves keys to the new shard.
+pub struct HashRing {
+    /// (point, shard index), sorted by point
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new(ids: &[String]) -> Self {
+        let mut points: Vec<(u64, usize)> = ids
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, id)| {
+                (0..VIRTUAL_NODES).map(move |vnode| (ring_hash(&format!("{}#{}", id, vnode)), idx))
+            })
+            .collect();
+        points.sort_unstable();
+        Self { points }
+    }
+
+    /// Index of the shard `key` is assigned to
+    pub fn get(&self, key: &str) -> usize {
+        let hash = ring_hash(key);
+        let idx = self.points.partition_point(|(point, _)| *point < hash);
+        self.points[idx % self.points.len()].1
+    }
+}
+
+/// FNV-1a followed by the murmur3 finalizer, which spreads similar keys
+/// evenly over the ring
+fn ring_hash(key: &str) -> u64 {
+    let mut hash = util::fnv1a_64(key.as_bytes());
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// An LRU cache split into shards with independent size limits, e.g. on
+/// different volumes. Keys are routed to shards with a `HashRing`.
+pub struct ShardedCache {
+    shards: Vec<LruCache>,
+    ring: HashRing,
+}
+
+impl ShardedCache {
+    /// Create a cache from (shard id, shard) pairs. The ids determine the
+    /// placement of keys, so they must be stable across restarts.
+    pub fn new(shards: Vec<(String, LruCache)>) -> Self {
+        let ids: Vec<String> = shards.iter().map(|(id, _)| id.clone()).collect();
+        Self {
+            shards: shards.into_iter().map(|(_, shard)| shard).collect(),
+            ring: HashRing::new(&ids),
+        }
+    }
+
+    /// Look up `keys` with `lookup` in a batch per shard, returning the values
+    /// in the order of the keys
+    fn lookup_by_shard<T: Clone + Default>(
+        &self,
+        keys: &[String],
+        lookup: impl Fn(&LruCache, &[String]) -> Result<Vec<T>>,
+    ) -> Result<Vec<T>> {
+        let mut values = vec![T::default(); keys.len()];
+        for (idx, shard) in self.shards.iter().enumerate() {
+            let (positions, shard_keys): (Vec<usize>, Vec<String>) = keys
+                .iter()
+                .enumerate()
+                .filter(|(_, key)| self.ring.get(key) == idx)
+                .map(|(pos, key)| (pos, key.clone()))
+                .unzip();
+            if shard_keys.is_empty() {
+                continue;
+            }
+            for (pos, value) in positions.into_iter().zip(lookup(shard, &shard_keys)?) {
+                values[pos] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// The cursor following `next`, the cursor of the shard `idx`: the start
+    /// of the next shard once the shard is done
+    fn next_shard_cursor(&self, idx: usize, next: String) -> String {
+        match (next.is_empty(), idx + 1 < self.shards.len()) {
+            (false, _) => format!("{}:{}", idx, next),
+            (true, true) => format!("{}:", idx + 1),
+            (true, false) => String::new(),
+        }
+    }
+}
+
+/// The shard index and the cursor in the shard of a cursor of a
+/// `ShardedCache`, the first shard for an empty cursor
+fn parse_shard_cursor(cursor: &str) -> Result<(usize, &str)> {
+    match cursor.split_once(':') {
+        Some((idx, shard_cursor)) => {
+            let idx = idx
+                .parse::<usize>()
+                .map_err(|_| Error::OtherError(format!("invalid scan cursor: {}", cursor)))?;
+            Ok((idx, shard_cursor))
+        }
+        None => Ok((0, "")),
+    }
+}
+
+#[async_trait]
+impl Cache for ShardedCache {
+    async fn put(&mut self, key: &str, entry: CacheData) {
+        let idx = self.ring.get(key);
+        self.shards[idx].put(key, entry).await
+    }
+
+    async fn get(&self, key: &str) -> Option<CacheData> {
+        self.shards[self.ring.get(key)].get(key).await
+    }
+
+    fn write_path(&self, key: &str) -> Option<PathBuf> {
+        self.shards[self.ring.get(key)].write_path(key)
+    }
+
+    /// Shards are scanned one after another, the cursor is
+    /// `<shard index>:<cursor in the shard>`
+    fn scan_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+        let (idx, shard_cursor) = parse_shard_cursor(cursor)?;
+        let shard = match self.shards.get(idx) {
+            Some(shard) => shard,
+            None => return Ok((vec![], String::new())),
+        };
+        let (keys, next) = shard.scan_keys(shard_cursor, count)?;
+        Ok((keys, self.next_shard_cursor(idx, next)))
+    }
+
+    /// Shards are purged one after another, with cursors like `scan_keys`
+    async fn purge_batch(&self, cursor: &str, count: usize) -> Result<(PurgeReport, String)> {
+        let (idx, shard_cursor) = parse_shard_cursor(cursor)?;
+        let shard = match self.shards.get(idx) {
+            Some(shard) => shard,
+            None => return Ok((PurgeReport::default(), String::new())),
+        };
+        let (report, next) = shard.purge_batch(shard_cursor, count).await?;
+        Ok((report, self.next_shard_cursor(idx, next)))
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool> {
+        self.shards[self.ring.get(key)].remove(key).await
+    }
+
+    /// Shards have sizes of their own, so the coldest entries of each shard
+    /// are returned, one shard after another
+    fn coldest_entries(&self, percent: u64) -> Result<Vec<LruEntryStats>> {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(shard.coldest_entries(percent)?);
+        }
+        Ok(entries)
+    }
+
+    fn protect(&self, key: &str) -> Result<bool> {
+        self.shards[self.ring.get(key)].protect(key)
+    }
+
+    fn confirm_current(&self, key: &str) -> Result<bool> {
+        self.shards[self.ring.get(key)].confirm_current(key)
+    }
+
+    async fn age(&self, key: &str) -> Option<Duration> {
+        self.shards[self.ring.get(key)].age(key).await
+    }
+
+    fn entry_count(&self) -> Result<Option<u64>> {
+        let mut count = 0;
+        for shard in &self.shards {
+            count += shard.entry_count()?.unwrap_or(0);
+        }
+        Ok(Some(count))
+    }
+
+    fn redis_memory(&self) -> Result<Option<u64>> {
+        let mut total = None;
+        for shard in &self.shards {
+            if let Some(bytes) = shard.redis_memory()? {
+                total = Some(total.unwrap_or(0) + bytes);
+            }
+        }
+        Ok(total)
+    }
+
+    fn total_size(&self) -> Option<CacheSizeType> {
+        self.shards.iter().map(|shard| shard.total_size()).sum()
+    }
+
+    /// Each shard has hot keys of its own
+    fn hot_keys(&self) -> Option<Vec<HotKey>> {
+        let mut hot_keys = Vec::new();
+        for shard in &self.shards {
+            hot_keys.extend(shard.hot_keys()?);
+        }
+        hot_keys.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.key.cmp(&b.key)));
+        Some(hot_keys)
+    }
+
+    /// Keys are looked up in batches, one per shard
+    fn entry_sizes(&self, keys: &[String]) -> Result<Vec<CacheSizeType>> {
+        self.lookup_by_shard(keys, |shard, keys| shard.entry_sizes(keys))
+    }
+
+    /// Keys are looked up in batches, one per shard
+    fn entry_sha256s(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.lookup_by_shard(keys, |shard, keys| shard.entry_sha256s(keys))
+    }
 
-    /**
-     * Convert a cache entry to an array keys and values to be stored as redis hash
-     */
-    pub fn to_redis_multiple_fields(&self) -> Vec<(&str, String)> {
-        vec![
-            ("path", self.key.clone()),
-            ("size", self.metadata.size.to_string()),
-            ("atime", self.metadata.atime.to_string()),
-        ]
+    fn set_entry_url(&self, key: &str, url: &str) -> Result<bool> {
+        self.shards[self.ring.get(key)].set_entry_url(key, url)
+    }
+
+    /// Keys are looked up in batches, one per shard
+    fn entry_urls(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.lookup_by_shard(keys, |shard, keys| shard.entry_urls(keys))
+    }
+
+    /// Keys are looked up in batches, one per shard
+    fn contains_many(&self, keys: &[String]) -> Result<Vec<bool>> {
+        self.lookup_by_shard(keys, |shard, keys| shard.contains_many(keys))
     }
 }
 
-pub struct NoCache {}
+/// Pass-through, nothing is cached. Unless it has a micro-cache: responses
+/// up to `max_entry_size` are then kept in memory for a few secs.
+#[derive(Default)]
+pub struct NoCache {
+    micro_cache: Option<MicroCache<Bytes>>,
+    max_entry_size: u64,
+}
+
+impl NoCache {
+    pub fn with_micro_cache(micro_cache: MicroCache<Bytes>, max_entry_size: u64) -> Self {
+        Self {
+            micro_cache: Some(micro_cache),
+            max_entry_size,
+        }
+    }
+}
 
 #[async_trait]
 impl Cache for NoCache {
-    async fn put(&mut self, _key: &str, _entry: CacheData) {}
-    async fn get(&self, _key: &str) -> Option<CacheData> {
-        None
+    async fn put(&mut self, key: &str, entry: CacheData) {
+        let micro_cache = match &self.micro_cache {
+            Some(micro_cache) => micro_cache,
+            None => return,
+        };
+        if let Some(bytes) = read_bounded(entry, self.max_entry_size).await {
+            micro_cache.put(key, bytes);
+        }
+    }
+    async fn get(&self, key: &str) -> Option<CacheData> {
+        let bytes = self.micro_cache.as_ref()?.get(key)?;
+        Some(CacheData::BytesData(bytes))
+    }
+}
+
+/// A stream of `bytes` with its size, like the content read from a storage,
+/// so that range requests are answered the same
+fn sized_stream(bytes: Bytes) -> CacheData {
+    let size = bytes.len() as CacheSizeType;
+    CacheData::ByteStream(Box::new(stream::iter(vec![Ok(bytes)])), Some(size))
+}
+
+/// The content of `entry`, `None` if it is larger than `max_size` bytes or
+/// fails to be read. A stream is read to the end anyway, as it may be teed
+/// to a client.
+async fn read_bounded(entry: CacheData, max_size: u64) -> Option<Bytes> {
+    let bytes = match entry {
+        CacheData::TextData(text) => Bytes::from(text),
+        CacheData::BytesData(bytes) => bytes,
+        CacheData::ByteStream(mut stream, _) => {
+            let mut content = Some(Vec::new());
+            while let Some(bytes) = stream.next().await {
+                let bytes = bytes.ok()?;
+                if let Some(buf) = &mut content {
+                    if (buf.len() + bytes.len()) as u64 > max_size {
+                        content = None;
+                    } else {
+                        buf.extend_from_slice(&bytes);
+                    }
+                }
+            }
+            content?.into()
+        }
+    };
+    Some(bytes).filter(|bytes| bytes.len() as u64 <= max_size)
+}
+
+/// A tiny in-memory cache, entries expire `ttl` after they are put. At most
+/// `max_entries` are kept: expired entries, then the oldest ones, make room
+/// for new ones.
+pub struct MicroCache<V> {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (Instant, V)>>,
+}
+
+impl<V: Clone> MicroCache<V> {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((put_at, value)) if put_at.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn put(&self, key: &str, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            let ttl = self.ttl;
+            entries.retain(|_, (put_at, _)| put_at.elapsed() < ttl);
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (put_at, _))| *put_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key.to_string(), (Instant::now(), value));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::{FsBackend, TieredFsBackend};
+    use crate::test_util::{MockBackend, StorageOp, TempDir};
     use futures::stream::{self};
     use futures::StreamExt;
     use lazy_static::lazy_static;
     use std::fs;
     use std::io;
     use std::io::prelude::*;
-    use std::thread;
     use std::time;
     use tokio::sync::RwLock;
 
@@ -1011,9 +3737,13 @@ mod tests {
         ($dir: expr, $size: expr, $redis_client: expr, $id: expr) => {
             LruCache::new(
                 $size,
-                Arc::new(RedisMetadataDb::new($redis_client, $id)),
-                Arc::new(Storage::FileSystem {
+                Arc::new(RedisMetadataDb::new($redis_client, $id).unwrap()),
+                Arc::new(FsBackend {
                     root_dir: $dir.to_string(),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
                 }),
                 $id,
             )
@@ -1025,8 +3755,12 @@ mod tests {
             LruCache::new(
                 $size,
                 Arc::new(SledMetadataDb::new_lru(&format!("{}/sled", $dir), $id)),
-                Arc::new(Storage::FileSystem {
+                Arc::new(FsBackend {
                     root_dir: $dir.to_string(),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
                 }),
                 $id,
             )
@@ -1037,9 +3771,13 @@ mod tests {
         ($dir: expr, $ttl: expr, $redis_client:expr, $id: expr) => {
             TtlCache::new(
                 $ttl,
-                Arc::new(RedisMetadataDb::new($redis_client, $id)),
-                Arc::new(Storage::FileSystem {
+                Arc::new(RedisMetadataDb::new($redis_client, $id).unwrap()),
+                Arc::new(FsBackend {
                     root_dir: $dir.to_string(),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
                 }),
             )
         };
@@ -1050,8 +3788,12 @@ mod tests {
             TtlCache::new(
                 $ttl,
                 Arc::new(SledMetadataDb::new_ttl($dir, $id, $interval)),
-                Arc::new(Storage::FileSystem {
+                Arc::new(FsBackend {
                     root_dir: $dir.to_string(),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
                 }),
             )
         };
@@ -1069,6 +3811,48 @@ mod tests {
         };
     }
 
+    #[tokio::test]
+    async fn lru_redis_tiered_read_recorded_tier() {
+        setup();
+        let id = "lru_redis_tiered_read_recorded_tier";
+        let redis_client = new_redis_client();
+        let mut con = redis_client.get_connection().unwrap();
+        let _: () = redis::cmd("DEL")
+            .arg(&[
+                format!("{}_old", id),
+                format!("{}_new", id),
+                format!("{}_total_size", id),
+                format!("{}_cache_keys", id),
+            ])
+            .query(&mut con)
+            .unwrap();
+        let db = Arc::new(RedisMetadataDb::new(redis_client, id).unwrap());
+        let fast_root = format!("{}/{}/fast", TEST_CACHE_DIR, id);
+        let slow_root = format!("{}/{}/slow", TEST_CACHE_DIR, id);
+        let _ = fs::remove_dir_all(format!("{}/{}", TEST_CACHE_DIR, id));
+        let storage = TieredFsBackend::new(&fast_root, &slow_root, 4, false, Default::default());
+        let mut lru_cache = LruCache::new(1024, db.clone(), Arc::new(storage), id);
+        cache_put!(lru_cache, "old", vec![1; 3].into());
+        cache_put!(lru_cache, "new", vec![2; 3].into());
+        for _ in 0..100 {
+            if db.lru_entry_tier("old").unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(db.lru_entry_tier("old").unwrap(), Some(Tier::Slow));
+        assert_eq!(db.lru_entry_tier("new").unwrap(), None);
+        // read from the recorded tier, without looking at the fast one
+        fs::write(format!("{}/old", fast_root), vec![3; 3]).unwrap();
+        let data = cache_get!(lru_cache, "old").unwrap();
+        assert_eq!(data.into_vec_u8().await, vec![1; 3]);
+        // written again to the fast tier
+        fs::remove_file(format!("{}/old", fast_root)).unwrap();
+        cache_put!(lru_cache, "old", vec![1; 3].into());
+        assert_eq!(db.lru_entry_tier("old").unwrap(), None);
+        assert!(cache_get!(lru_cache, "old").is_some());
+    }
+
     #[tokio::test]
     async fn lru_redis_cache_entry_set_success() {
         let redis_client = new_redis_client();
@@ -1107,11 +3891,70 @@ mod tests {
         assert_eq!(&cached_data_actual, &cached_data);
     }
 
+    #[tokio::test]
+    async fn lru_sled_cache_unsized_entry() {
+        setup();
+        let dir = format!("{}/lru_unsized_entry", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let mut lru_cache = new_lru_sled_cache!(dir, 8, "lru_unsized_entry");
+        let stream = |chunks: Vec<&'static [u8]>| {
+            let chunks = chunks
+                .into_iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk)));
+            CacheData::ByteStream(Box::new(stream::iter(chunks)), None)
+        };
+        // e.g. a rewritten page, whose size is only known once read
+        cache_put!(lru_cache, "page", stream(vec![b"ab", b"cd"]));
+        assert_eq!(lru_cache.get_total_size(), 4);
+        assert_eq!(get_file_all(&format!("{}/page", dir)), b"abcd");
+        cache_put!(lru_cache, "large", stream(vec![b"abcd", b"efgh", b"i"]));
+        assert!(cache_get!(lru_cache, "large").is_none());
+        assert_eq!(lru_cache.get_total_size(), 4);
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_storage_faults() {
+        setup();
+        let dir = TempDir::new("lru_storage_faults");
+        let storage = Arc::new(MockBackend::new());
+        let mut lru_cache = LruCache::new(
+            1024,
+            Arc::new(SledMetadataDb::new_lru(
+                &dir.path().join("sled").display().to_string(),
+                "lru_storage_faults",
+            )),
+            storage.clone(),
+            "lru_storage_faults",
+        );
+        // a file that cannot be persisted is not recorded
+        storage.fail(StorageOp::Persist, true);
+        cache_put!(lru_cache, "a", vec![1; 4].into());
+        assert_eq!(storage.calls(StorageOp::Persist), 1);
+        assert_eq!(lru_cache.get_total_size(), 0);
+        assert!(cache_get!(lru_cache, "a").is_none());
+        storage.fail(StorageOp::Persist, false);
+        cache_put!(lru_cache, "a", vec![1; 4].into());
+        assert_eq!(lru_cache.get_total_size(), 4);
+        // a file that cannot be read is a miss, and its entry is kept
+        storage.fail(StorageOp::Read, true);
+        assert!(cache_get!(lru_cache, "a").is_none());
+        assert_eq!(lru_cache.get_total_size(), 4);
+        storage.fail(StorageOp::Read, false);
+        assert_eq!(
+            cache_get!(lru_cache, "a").unwrap().to_vec().await,
+            vec![1; 4]
+        );
+        // the entry is removed even if its file is not
+        storage.fail(StorageOp::Remove, true);
+        assert!(lru_cache.remove("a").await.unwrap());
+        assert_eq!(storage.calls(StorageOp::Remove), 1);
+        assert!(cache_get!(lru_cache, "a").is_none());
+    }
+
     async fn lru_cache_size_constaint_tester(mut lru_cache: LruCache, cached_path: &str) {
         cache_put!(lru_cache, "tsu_ki", vec![0; 5].into());
         let total_size_actual: CacheSizeType = lru_cache.get_total_size();
         assert_eq!(total_size_actual, 5);
-        thread::sleep(time::Duration::from_secs(1));
         cache_put!(lru_cache, "kirei", vec![0; 11].into());
         let total_size_actual: CacheSizeType = lru_cache.get_total_size();
         assert_eq!(total_size_actual, 16);
@@ -1139,24 +3982,456 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn lru_redis_cache_size_constraint() {
+    async fn lru_redis_cache_size_constraint() {
+        setup();
+        let redis_client = new_redis_client();
+        let lru_cache = new_lru_redis_cache!(
+            TEST_CACHE_DIR,
+            16,
+            redis_client,
+            "lru_cache_size_constraint"
+        );
+        lru_cache_size_constaint_tester(lru_cache, TEST_CACHE_DIR).await;
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_size_constraint() {
+        setup();
+        let cached_dir = &format!("{}/size_constraint", TEST_CACHE_DIR);
+        let sled_lru_cache = new_lru_sled_cache!(cached_dir, 16, "lru_cache_size_constraint");
+        lru_cache_size_constaint_tester(sled_lru_cache, cached_dir).await;
+    }
+
+    async fn lru_cache_entry_constraint_tester(mut lru_cache: LruCache, cached_path: &str) {
+        for key in &["ichi", "ni", "san"] {
+            cache_put!(lru_cache, key, vec![0; 4].into());
+        }
+        assert_eq!(lru_cache.entry_count().unwrap(), Some(3));
+        assert_eq!(lru_cache.get_total_size(), 12);
+        // 3 entries at most, evict ichi although the size limit is far
+        cache_put!(lru_cache, "yon", vec![1; 4].into());
+        assert_eq!(lru_cache.entry_count().unwrap(), Some(3));
+        assert_eq!(lru_cache.get_total_size(), 12);
+        assert!(file_not_exist(&format!("{}/{}", cached_path, "ichi")));
+        // get ni, update atime, evict san
+        assert!(cache_get!(lru_cache, "ni").is_some());
+        cache_put!(lru_cache, "go", vec![2; 4].into());
+        assert_eq!(lru_cache.entry_count().unwrap(), Some(3));
+        assert!(file_not_exist(&format!("{}/{}", cached_path, "san")));
+        for key in &["ni", "yon", "go"] {
+            assert_eq!(
+                get_file_all(&format!("{}/{}", cached_path, key)).len(),
+                4,
+                "{} is evicted",
+                key
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn lru_redis_cache_entry_constraint() {
+        setup();
+        let redis_client = new_redis_client();
+        let lru_cache = new_lru_redis_cache!(
+            TEST_CACHE_DIR,
+            1024,
+            redis_client,
+            "lru_cache_entry_constraint"
+        )
+        .with_max_entries(Some(3));
+        lru_cache_entry_constraint_tester(lru_cache, TEST_CACHE_DIR).await;
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_entry_constraint() {
+        setup();
+        let cached_dir = &format!("{}/entry_constraint", TEST_CACHE_DIR);
+        let sled_lru_cache = new_lru_sled_cache!(cached_dir, 1024, "lru_cache_entry_constraint")
+            .with_max_entries(Some(3));
+        lru_cache_entry_constraint_tester(sled_lru_cache, cached_dir).await;
+    }
+
+    #[test]
+    fn redis_memory_entry_limit() {
+        let estimate = RedisMemoryEstimate {
+            per_entry: 100,
+            overhead: 50,
+        };
+        assert_eq!(estimate.bytes(0), 50);
+        assert_eq!(estimate.bytes(10), 1050);
+        assert_eq!(estimate.entry_limit(1050), 10);
+        assert_eq!(estimate.entry_limit(1149), 10);
+        // at least an entry is kept
+        assert_eq!(estimate.entry_limit(10), 1);
+        let empty = RedisMemoryEstimate {
+            per_entry: 0,
+            overhead: 0,
+        };
+        assert_eq!(empty.entry_limit(1000), 1000);
+    }
+
+    #[tokio::test]
+    async fn lru_redis_memory_limit() {
+        setup();
+        let id = "lru_redis_memory_limit";
+        let mut db = RedisMetadataDb::new(new_redis_client(), id).unwrap();
+        db.memory_sample_interval = Duration::from_secs(0);
+        db.replace_lru_entries(&[], 0).unwrap();
+        let db = Arc::new(db);
+        let storage = FsBackend {
+            root_dir: format!("{}/{}", TEST_CACHE_DIR, id),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: Default::default(),
+        };
+        let mut lru_cache = LruCache::new(1024, db.clone(), Arc::new(storage), id);
+        for key in &["ichi", "ni", "san", "yon"] {
+            cache_put!(lru_cache, key, vec![1; 4].into());
+        }
+        let estimate = db.redis_memory().unwrap().unwrap();
+        assert!(estimate.per_entry > 0);
+        assert_eq!(lru_cache.redis_memory().unwrap(), Some(estimate.bytes(4)));
+        // room for the metadata of two entries and a half
+        let max = estimate.bytes(2) + estimate.per_entry / 2;
+        let mut lru_cache = lru_cache.with_max_redis_memory(Some(max));
+        cache_put!(lru_cache, "go", vec![1; 4].into());
+        assert_eq!(lru_cache.entry_count().unwrap(), Some(2));
+        assert!(cache_get!(lru_cache, "yon").is_some());
+        assert!(cache_get!(lru_cache, "go").is_some());
+    }
+
+    #[tokio::test]
+    async fn lru_redis_total_size_while_unavailable() {
+        setup();
+        let id = "lru_redis_total_size_while_unavailable";
+        let mut db = RedisMetadataDb::new(new_redis_client(), id).unwrap();
+        db.replace_lru_entries(&[], 0).unwrap();
+        db.set_lru_entry("ichi", 4, None).unwrap();
+        db.set_lru_entry("ni", 3, None).unwrap();
+        assert_eq!(db.get_total_size(), 7);
+        // nothing listens on the port
+        let unavailable = redis::Client::open("redis://localhost:1/").unwrap();
+        db.redis_client = unavailable.clone();
+        assert_eq!(db.get_total_size(), 7);
+        assert!(db.set_lru_entry("san", 2, None).is_err());
+        assert!(db.eviction_candidates(2, "san", 8, None).is_err());
+        assert_eq!(db.get_total_size(), 7);
+        // reconciled with the entries set meanwhile by other instances
+        db.redis_client = new_redis_client();
+        let other = RedisMetadataDb::new(new_redis_client(), id).unwrap();
+        other.set_lru_entry("yon", 1, None).unwrap();
+        assert_eq!(db.get_total_size(), 8);
+
+        // puts are skipped while redis is unavailable
+        db.redis_client = unavailable;
+        let storage = FsBackend {
+            root_dir: format!("{}/{}", TEST_CACHE_DIR, id),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: Default::default(),
+        };
+        let mut lru_cache = LruCache::new(16, Arc::new(db), Arc::new(storage), id);
+        cache_put!(lru_cache, "go", vec![1; 4].into());
+        assert!(cache_get!(lru_cache, "go").is_none());
+        assert_eq!(lru_cache.metadata_db.get_total_size(), 8);
+    }
+
+    #[tokio::test]
+    async fn lru_cache_slow_and_failing_removals() {
+        setup();
+        let id = "lru_cache_slow_and_failing_removals";
+        let db = RedisMetadataDb::new(new_redis_client(), id).unwrap();
+        db.replace_lru_entries(&[], 0).unwrap();
+        db.take_orphans(100).unwrap();
+        let db = Arc::new(db);
+        let storage = MockBackend::new();
+        let mut lru_cache = LruCache::new(16, db.clone(), Arc::new(storage.clone()), id);
+        for key in &["ichi", "ni", "san", "yon"] {
+            cache_put!(lru_cache, key, vec![1; 4].into());
+        }
+        // the four evicted files are removed at once
+        storage.delay(StorageOp::Remove, Duration::from_millis(300));
+        let started = Instant::now();
+        cache_put!(lru_cache, "go", vec![1; 16].into());
+        assert!(started.elapsed() < Duration::from_millis(900));
+        assert_eq!(storage.calls(StorageOp::Remove), 4);
+        assert!(cache_get!(lru_cache, "go").is_some());
+
+        // a file failing to be removed is removed by the next eviction
+        storage.delay(StorageOp::Remove, Duration::from_millis(0));
+        storage.fail(StorageOp::Remove, true);
+        cache_put!(lru_cache, "roku", vec![1; 16].into());
+        assert!(cache_get!(lru_cache, "roku").is_some());
+        storage.fail(StorageOp::Remove, false);
+        cache_put!(lru_cache, "nana", vec![1; 16].into());
+        assert_eq!(storage.calls(StorageOp::Remove), 7);
+        assert!(storage.read("go").await.is_err());
+        assert!(storage.read("roku").await.is_err());
+        assert_eq!(db.take_orphans(100).unwrap(), (vec![], 0));
+    }
+
+    #[tokio::test]
+    async fn lru_redis_cache_purge_batches() {
+        setup();
+        let id = "lru_redis_cache_purge_batches";
+        let db = RedisMetadataDb::new(new_redis_client(), id).unwrap();
+        db.replace_lru_entries(&[], 0).unwrap();
+        let storage = MockBackend::new();
+        let mut lru_cache = LruCache::new(1024, Arc::new(db), Arc::new(storage.clone()), id);
+        let keys = ["ichi", "ni", "san", "yon", "go"];
+        for key in &keys {
+            cache_put!(lru_cache, key, vec![1; 4].into());
+        }
+        let mut report = PurgeReport::default();
+        let mut cursor = String::new();
+        let mut batches = 0;
+        loop {
+            let (batch, next) = lru_cache.purge_batch(&cursor, 2).await.unwrap();
+            report += batch;
+            batches += 1;
+            if next.is_empty() {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(batches, 3);
+        assert_eq!(
+            report,
+            PurgeReport {
+                entries: 5,
+                bytes: 20
+            }
+        );
+        for key in &keys {
+            assert!(cache_get!(lru_cache, key).is_none(), "{}", key);
+            assert!(storage.read(key).await.is_err(), "{}", key);
+        }
+        assert_eq!(lru_cache.metadata_db.get_total_size(), 0);
+        assert_eq!(lru_cache.entry_count().unwrap(), Some(0));
+        cache_put!(lru_cache, "roku", vec![1; 4].into());
+        assert!(cache_get!(lru_cache, "roku").is_some());
+    }
+
+    /// The metadata of an LRU cache whose commits fail when `fail_commit` is
+    /// set, like redis going away between the persist and the commit of a put
+    struct FailingCommit {
+        inner: RedisMetadataDb,
+        fail_commit: AtomicBool,
+    }
+
+    impl LruMetadataStore for FailingCommit {
+        fn get_lru_entry(&self, key: &str) -> CacheHitMiss {
+            self.inner.get_lru_entry(key)
+        }
+        fn set_lru_entry(
+            &self,
+            key: &str,
+            size: CacheSizeType,
+            sha256: Option<&str>,
+        ) -> Result<()> {
+            if self.fail_commit.load(Ordering::SeqCst) {
+                return Err(Error::OtherError("commit failed".to_string()));
+            }
+            self.inner.set_lru_entry(key, size, sha256)
+        }
+        fn lru_entry_sha256(&self, key: &str) -> Result<Option<String>> {
+            self.inner.lru_entry_sha256(key)
+        }
+        fn eviction_candidates(
+            &self,
+            new_size: CacheSizeType,
+            new_key: &str,
+            size_limit: CacheSizeType,
+            max_entries: Option<u64>,
+        ) -> Result<Eviction> {
+            self.inner
+                .eviction_candidates(new_size, new_key, size_limit, max_entries)
+        }
+        fn get_total_size(&self) -> CacheSizeType {
+            self.inner.get_total_size()
+        }
+        fn lru_keys(&self, offset: usize, count: usize) -> Vec<String> {
+            self.inner.lru_keys(offset, count)
+        }
+        fn lru_entry_stats(&self, offset: usize, count: usize) -> Result<Vec<LruEntryStats>> {
+            self.inner.lru_entry_stats(offset, count)
+        }
+        fn lru_len(&self) -> Result<usize> {
+            self.inner.lru_len()
+        }
+        fn touch_lru_entry(&self, key: &str) -> Result<bool> {
+            self.inner.touch_lru_entry(key)
+        }
+        fn refresh_lru_entry(&self, key: &str) -> Result<bool> {
+            self.inner.refresh_lru_entry(key)
+        }
+        fn lru_entry_created_at(&self, key: &str) -> Result<Option<i64>> {
+            self.inner.lru_entry_created_at(key)
+        }
+        fn scan_lru_keys(&self, cursor: &str, count: usize) -> Result<(Vec<String>, String)> {
+            self.inner.scan_lru_keys(cursor, count)
+        }
+        fn remove_lru_entry(&self, key: &str) -> Result<bool> {
+            self.inner.remove_lru_entry(key)
+        }
+        fn remove_lru_entries(&self, keys: &[String]) -> Result<Vec<bool>> {
+            self.inner.remove_lru_entries(keys)
+        }
+        fn lru_entry_exists(&self, key: &str) -> Result<bool> {
+            self.inner.lru_entry_exists(key)
+        }
+        fn lru_entry_sizes(&self, keys: &[String]) -> Result<Vec<CacheSizeType>> {
+            self.inner.lru_entry_sizes(keys)
+        }
+    }
+
+    /// A download failing after its first bytes
+    fn failing_download() -> CacheData {
+        let chunks: Vec<Result<Bytes>> = vec![
+            Ok(Bytes::from_static(b"part")),
+            Err(Error::OtherError("connection reset".to_string())),
+        ];
+        CacheData::ByteStream(Box::new(stream::iter(chunks)), Some(8))
+    }
+
+    #[tokio::test]
+    async fn entry_lock_is_not_held_while_staging() {
+        let storage = storage::MemBackend::new();
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+        let stage = async {
+            rx.await.unwrap();
+            storage.stage("staged", vec![1; 4].into(), None).await
+        };
+        let commit = |_: &PersistReport| Ok(());
+        let put = commit_entry(&storage, "staging", "staged", stage, commit, || Ok(false));
+        let mut put = Box::pin(put);
+        assert!(futures::poll!(&mut put).is_pending());
+        // the entry is removed while its file is downloaded, and another put
+        // of it is skipped
+        let removal = retract_entry(&storage, "staging", "staged", || Ok(false));
+        let removal = tokio::time::timeout(Duration::from_secs(1), removal).await;
+        assert!(!removal.unwrap().unwrap());
+        let stage = storage.stage("staged", vec![2; 4].into(), None);
+        let skipped = commit_entry(&storage, "staging", "staged", stage, commit, || Ok(false));
+        assert!(skipped.await.unwrap().is_none());
+        tx.send(()).unwrap();
+        assert_eq!(put.await.unwrap().unwrap().bytes_written, 4);
+        let read = storage.read("staged").await.unwrap().into_vec_u8().await;
+        assert_eq!(read, vec![1; 4]);
+    }
+
+    #[tokio::test]
+    async fn lru_put_failures_keep_metadata_consistent() {
         setup();
-        let redis_client = new_redis_client();
-        let lru_cache = new_lru_redis_cache!(
-            TEST_CACHE_DIR,
-            16,
-            redis_client,
-            "lru_cache_size_constraint"
+        let id = "lru_put_failures";
+        let dir = format!("{}/{}", TEST_CACHE_DIR, id);
+        let _ = fs::remove_dir_all(&dir);
+        let inner = RedisMetadataDb::new(new_redis_client(), id).unwrap();
+        inner.replace_lru_entries(&[], 0).unwrap();
+        let db = Arc::new(FailingCommit {
+            inner,
+            fail_commit: AtomicBool::new(false),
+        });
+        let storage = FsBackend {
+            root_dir: dir.clone(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: Default::default(),
+        };
+        let mut lru_cache = LruCache::new(1024, db.clone(), Arc::new(storage), id);
+        let files = || {
+            let mut names: Vec<String> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        cache_put!(lru_cache, "kept", vec![1; 4].into());
+        cache_put!(lru_cache, "replaced", vec![2; 4].into());
+
+        // the persist fails: nothing changes, previous files are kept
+        for key in &["new", "kept"] {
+            cache_put!(lru_cache, key, failing_download());
+            assert_eq!(lru_cache.get_total_size(), 8, "{}", key);
+            assert_eq!(files(), vec!["kept", "replaced"], "{}", key);
+        }
+        assert!(cache_get!(lru_cache, "new").is_none());
+        assert_eq!(get_file_all(&format!("{}/kept", dir)), vec![1; 4]);
+
+        // the commit fails: the file is removed, with a previous entry of the
+        // key describing the replaced file
+        db.fail_commit.store(true, Ordering::SeqCst);
+        cache_put!(lru_cache, "new", vec![3; 5].into());
+        assert_eq!(lru_cache.get_total_size(), 8);
+        assert_eq!(files(), vec!["kept", "replaced"]);
+        cache_put!(lru_cache, "replaced", vec![3; 5].into());
+        assert_eq!(lru_cache.get_total_size(), 4);
+        assert_eq!(files(), vec!["kept"]);
+        assert!(cache_get!(lru_cache, "replaced").is_none());
+        assert_eq!(lru_cache.entry_count().unwrap(), Some(1));
+
+        // both succeed again
+        db.fail_commit.store(false, Ordering::SeqCst);
+        cache_put!(lru_cache, "new", vec![3; 5].into());
+        assert_eq!(lru_cache.get_total_size(), 9);
+        assert_eq!(files(), vec!["kept", "new"]);
+        assert_eq!(
+            cache_get!(lru_cache, "new").unwrap().to_vec().await,
+            vec![3; 5]
         );
-        lru_cache_size_constaint_tester(lru_cache, TEST_CACHE_DIR).await;
     }
 
-    #[tokio::test]
-    async fn lru_sled_cache_size_constraint() {
-        setup();
-        let cached_dir = &format!("{}/size_constraint", TEST_CACHE_DIR);
-        let sled_lru_cache = new_lru_sled_cache!(cached_dir, 16, "lru_cache_size_constraint");
-        lru_cache_size_constaint_tester(sled_lru_cache, cached_dir).await;
+    #[test]
+    fn eviction_satisfies_both_limits() {
+        let lru = |sizes: &[CacheSizeType]| {
+            let mut entries = sizes
+                .iter()
+                .enumerate()
+                .map(|(i, &size)| EvictedEntry {
+                    key: i.to_string(),
+                    size,
+                    atime: 0,
+                })
+                .collect::<Vec<_>>()
+                .into_iter();
+            move || Ok::<_, ()>(entries.next())
+        };
+        let evicted_keys =
+            |evicted: Vec<EvictedEntry>| evicted.into_iter().map(|e| e.key).collect::<Vec<_>>();
+        // the entry limit is violated
+        let evicted =
+            select_eviction_candidates(6, 3, 1, None, 100, Some(3), lru(&[2, 2, 2])).unwrap();
+        assert_eq!(evicted_keys(evicted), vec!["0"]);
+        // the size limit is violated
+        let evicted =
+            select_eviction_candidates(6, 3, 5, None, 10, Some(3), lru(&[2, 2, 2])).unwrap();
+        assert_eq!(evicted_keys(evicted), vec!["0"]);
+        // the size limit takes more evictions than the entry limit
+        let evicted =
+            select_eviction_candidates(6, 3, 9, None, 10, Some(3), lru(&[2, 2, 2])).unwrap();
+        assert_eq!(evicted_keys(evicted), vec!["0", "1", "2"]);
+        // no limit on entries
+        let evicted =
+            select_eviction_candidates(6, 3, 1, None, 100, None, lru(&[2, 2, 2])).unwrap();
+        assert!(evicted.is_empty());
+        // a replaced entry leaves its size and its place to the new one
+        let evicted =
+            select_eviction_candidates(6, 3, 2, Some(("1", 2)), 6, Some(3), lru(&[2, 2, 2]))
+                .unwrap();
+        assert!(evicted.is_empty());
+        let evicted =
+            select_eviction_candidates(6, 3, 3, Some(("1", 2)), 6, Some(3), lru(&[2, 2, 2]))
+                .unwrap();
+        assert_eq!(evicted_keys(evicted), vec!["0"]);
+        // unless it is evicted itself
+        let evicted =
+            select_eviction_candidates(6, 3, 3, Some(("0", 2)), 6, Some(3), lru(&[2, 2, 2]))
+                .unwrap();
+        assert_eq!(evicted_keys(evicted), vec!["0", "1"]);
     }
 
     async fn test_lru_cache_no_evict_recent_tester(mut lru_cache: LruCache) {
@@ -1165,23 +4440,18 @@ mod tests {
         let key3 = "3三号去餐厅";
         let key4 = "4然后看电影";
         cache_put!(lru_cache, key1, vec![1].into());
-        thread::sleep(time::Duration::from_secs(1));
         cache_put!(lru_cache, key2, vec![2].into());
-        thread::sleep(time::Duration::from_secs(1));
         cache_put!(lru_cache, key3, vec![3].into());
         assert_eq!(lru_cache.get_total_size(), 3);
         // set key4, evict key1
-        thread::sleep(time::Duration::from_secs(1));
         cache_put!(lru_cache, key4, vec![4].into());
         assert!(cache_get!(lru_cache, key1).is_none());
         // assert
         assert_eq!(lru_cache.get_total_size(), 3);
         // get key2, update atime
-        thread::sleep(time::Duration::from_secs(1));
         assert_eq!(cache_get!(lru_cache, key2).unwrap().to_vec().await, vec![2]);
         assert_eq!(lru_cache.get_total_size(), 3);
         // set key1, evict key3
-        thread::sleep(time::Duration::from_secs(1));
         cache_put!(lru_cache, key1, vec![11].into());
         assert_eq!(lru_cache.get_total_size(), 3);
         assert!(cache_get!(lru_cache, key3).is_none());
@@ -1228,6 +4498,86 @@ mod tests {
         assert_eq!(lru_cache.get_total_size(), 2);
     }
 
+    #[cfg(unix)]
+    async fn lru_cache_put_same_content_tester(mut lru_cache: LruCache, cached_path: &str) {
+        use std::os::unix::fs::MetadataExt;
+
+        let inode = |key: &str| {
+            fs::metadata(format!("{}/{}", cached_path, key))
+                .unwrap()
+                .ino()
+        };
+        cache_put!(lru_cache, "shrink", vec![0; 10].into());
+        cache_put!(lru_cache, "same", vec![1; 4].into());
+        assert_eq!(lru_cache.get_total_size(), 14);
+        // the old size is subtracted
+        cache_put!(lru_cache, "shrink", vec![0; 2].into());
+        assert_eq!(lru_cache.get_total_size(), 6);
+        let same_inode = inode("same");
+        let shrink_inode = inode("shrink");
+        // the file of the same content is kept, and the entry is used last
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        cache_put!(lru_cache, "same", vec![1; 4].into());
+        assert_eq!(lru_cache.get_total_size(), 6);
+        assert_eq!(inode("same"), same_inode);
+        assert_eq!(lru_cache.metadata_db.lru_keys(0, 2), vec!["shrink", "same"]);
+        // other content replaces the file
+        cache_put!(lru_cache, "shrink", vec![2; 2].into());
+        assert_eq!(lru_cache.get_total_size(), 6);
+        assert_ne!(inode("shrink"), shrink_inode);
+        assert_eq!(
+            get_file_all(&format!("{}/{}", cached_path, "shrink")),
+            vec![2; 2]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lru_cache_put_same_content() {
+        setup();
+        let cached_dir = &format!("{}/same_content_redis", TEST_CACHE_DIR);
+        let lru_cache =
+            new_lru_redis_cache!(cached_dir, 16, new_redis_client(), "put_same_content");
+        lru_cache_put_same_content_tester(lru_cache, cached_dir).await;
+        let cached_dir = &format!("{}/same_content_sled", TEST_CACHE_DIR);
+        let lru_cache = new_lru_sled_cache!(cached_dir, 16, "put_same_content");
+        lru_cache_put_same_content_tester(lru_cache, cached_dir).await;
+    }
+
+    async fn lru_cache_put_same_content_full_tester(mut lru_cache: LruCache) {
+        cache_put!(lru_cache, "k2", vec![2].into());
+        cache_put!(lru_cache, "k1", vec![1].into());
+        // the full cache has room for the same content
+        cache_put!(lru_cache, "k1", vec![1].into());
+        assert_eq!(lru_cache.get_total_size(), 2);
+        assert_eq!(cache_get!(lru_cache, "k2").unwrap().to_vec().await, vec![2]);
+        // and for a replacement of the same size
+        cache_put!(lru_cache, "k2", vec![3].into());
+        assert_eq!(lru_cache.get_total_size(), 2);
+        assert_eq!(cache_get!(lru_cache, "k1").unwrap().to_vec().await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn lru_redis_cache_put_same_content_full() {
+        let lru_cache = new_lru_redis_cache!(
+            TEST_CACHE_DIR,
+            2,
+            new_redis_client(),
+            "put_same_content_full"
+        );
+        lru_cache_put_same_content_full_tester(lru_cache).await;
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_put_same_content_full() {
+        let lru_cache = new_lru_sled_cache!(
+            &format!("{}/sled/{}", TEST_CACHE_DIR, "same_content_full"),
+            2,
+            "put_same_content_full"
+        );
+        lru_cache_put_same_content_full_tester(lru_cache).await;
+    }
+
     async fn lru_cache_isolation_tester(mut lru_cache_1: LruCache, mut lru_cache_2: LruCache) {
         cache_put!(lru_cache_1, "1", vec![1].into());
         cache_put!(lru_cache_2, "2", vec![2].into());
@@ -1301,6 +4651,103 @@ mod tests {
         assert_eq!(arc_cache.read().await.get_total_size(), 2);
     }
 
+    /// Two LRU cache objects sharing their metadata and files, like the ones
+    /// before and after a config reload
+    fn shared_lru_sled_caches(dir: &str, size: CacheSizeType, id: &str) -> (LruCache, LruCache) {
+        let _ = fs::remove_dir_all(dir);
+        let metadata_db = Arc::new(SledMetadataDb::new_lru(&format!("{}/sled", dir), id));
+        let storage = Arc::new(FsBackend {
+            root_dir: dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: Default::default(),
+        });
+        (
+            LruCache::new(size, metadata_db.clone(), storage.clone(), id),
+            LruCache::new(size, metadata_db, storage, id),
+        )
+    }
+
+    /// Every entry left has its file, and every file left has an entry
+    fn assert_entries_match_files(cache: &LruCache, dir: &str, keys: &[String]) {
+        for key in keys {
+            let cached = cache.metadata_db.lru_entry_exists(key).unwrap();
+            let stored = std::path::Path::new(&format!("{}/{}", dir, key)).exists();
+            assert_eq!(cached, stored, "{}: entry {}, file {}", key, cached, stored);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn lru_sled_cache_purge_vs_get() {
+        setup();
+        let dir = format!("{}/purge_vs_get", TEST_CACHE_DIR);
+        let (mut writer, purger) = shared_lru_sled_caches(&dir, 1024, "purge_vs_get");
+        let purger = Arc::new(purger);
+        let keys: Vec<String> = (0..8).map(|i| format!("k{}", i)).collect();
+        for round in 0..64u8 {
+            for key in &keys {
+                cache_put!(writer, key, vec![round; 16].into());
+            }
+            let purge = {
+                let purger = purger.clone();
+                tokio::spawn(async move { purger.purge_batch("", 4).await.unwrap() })
+            };
+            // a get misses, or reads the whole file
+            for key in &keys {
+                if let Some(data) = purger.get(key).await {
+                    assert_eq!(data.to_vec().await, vec![round; 16]);
+                }
+            }
+            purge.await.unwrap();
+            assert_entries_match_files(&purger, &dir, &keys);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn lru_sled_cache_evict_vs_put() {
+        setup();
+        let dir = format!("{}/evict_vs_put", TEST_CACHE_DIR);
+        // room for two entries, so that most puts evict
+        let (cache1, cache2) = shared_lru_sled_caches(&dir, 32, "evict_vs_put");
+        let keys: Vec<String> = (0..4).map(|i| format!("k{}", i)).collect();
+        // each cache evicts the keys the other one puts
+        async fn put_rounds(mut cache: LruCache, keys: Vec<String>, offset: usize) -> LruCache {
+            for round in 0..128 {
+                let key = &keys[(round + offset) % keys.len()];
+                cache_put!(cache, key, vec![7; 16].into());
+            }
+            cache
+        }
+        let puts1 = tokio::spawn(put_rounds(cache1, keys.clone(), 0));
+        let puts2 = tokio::spawn(put_rounds(cache2, keys.clone(), 1));
+        let cache = puts1.await.unwrap();
+        puts2.await.unwrap();
+        assert_entries_match_files(&cache, &dir, &keys);
+        for key in &keys {
+            if let Some(data) = cache_get!(cache, key) {
+                assert_eq!(data.to_vec().await, vec![7; 16]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ttl_sled_cache_set_again() {
+        setup();
+        let mut cache = new_ttl_sled_cache!(
+            &format!("{}/sled_set_again", TEST_CACHE_DIR),
+            1,
+            "ttl_sled_set_again",
+            0
+        );
+        cache_put!(cache, "key", vec![1].into());
+        util::sleep_ms(500);
+        cache_put!(cache, "key", vec![2].into());
+        // the cleanup of the first put leaves the second one alone
+        util::sleep_ms(700);
+        assert_eq!(cache_get!(cache, "key").unwrap().to_vec().await, vec![2]);
+    }
+
     #[tokio::test]
     async fn cache_stream_size_valid() {
         let mut lru_cache =
@@ -1313,6 +4760,295 @@ mod tests {
         assert_eq!(size, 3);
     }
 
+    #[tokio::test]
+    async fn lru_sled_cache_truncated_file() {
+        let dir = &format!("{}/truncated", TEST_CACHE_DIR);
+        let path = format!("{}/deb", dir);
+        let mut lru_cache = new_lru_sled_cache!(dir, 1024, "truncated_file");
+        cache_put!(lru_cache, "deb", vec![7; 16].into());
+        assert_eq!(cache_get!(lru_cache, "deb").unwrap().len(), 16);
+        // cut the file behind the back of the cache
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(10).unwrap();
+        // a miss, and the entry is removed to be fetched again
+        assert!(cache_get!(lru_cache, "deb").is_none());
+        assert_eq!(lru_cache.get_total_size(), 0);
+        assert!(!std::path::Path::new(&path).exists());
+        // an empty file left by a power cut with `fsync: never`, served with
+        // sendfile
+        cache_put!(lru_cache, "deb", vec![7; 16].into());
+        std::fs::File::create(&path).unwrap();
+        assert!(lru_cache.get_local_path("deb").await.is_none());
+        assert_eq!(lru_cache.get_total_size(), 0);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_hot_keys() {
+        let dir = &format!("{}/hot_keys", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(dir);
+        let hot_keys = HotKeys::new(2, Duration::from_secs(60), 8);
+        let mut lru_cache =
+            new_lru_sled_cache!(dir, 1024, "hot_keys").with_hot_keys(Some(hot_keys));
+        cache_put!(lru_cache, "idx", b"v1".to_vec().into());
+        cache_put!(lru_cache, "big", vec![1; 16].into());
+        for _ in 0..3 {
+            let hit = cache_get!(lru_cache, "idx").unwrap();
+            assert_eq!(hit.into_vec_u8().await, b"v1");
+            assert_eq!(cache_get!(lru_cache, "big").unwrap().len(), 16);
+        }
+        // served from memory, without reading the file
+        fs::remove_file(format!("{}/idx", dir)).unwrap();
+        let hit = cache_get!(lru_cache, "idx").unwrap();
+        assert_eq!(hit.len(), 2);
+        assert_eq!(hit.into_vec_u8().await, b"v1");
+        let report = lru_cache.hot_keys().unwrap();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].key, "idx");
+        assert_eq!(report[0].cached_bytes, Some(2));
+        // too large to be kept
+        assert_eq!(report[1].key, "big");
+        assert_eq!(report[1].cached_bytes, None);
+        // a put replaces the content in memory
+        cache_put!(lru_cache, "idx", b"v2".to_vec().into());
+        for _ in 0..2 {
+            let hit = cache_get!(lru_cache, "idx").unwrap();
+            assert_eq!(hit.into_vec_u8().await, b"v2");
+        }
+        // and a removal drops it
+        assert!(lru_cache.remove("idx").await.unwrap());
+        assert!(cache_get!(lru_cache, "idx").is_none());
+        // the sled db of the directory is locked until the cache is dropped
+        drop(lru_cache);
+        let cold_cache = new_lru_sled_cache!(dir, 1024, "hot_keys_off");
+        assert!(cold_cache.hot_keys().is_none());
+    }
+
+    #[tokio::test]
+    async fn lru_redis_cache_non_ascii_keys() {
+        let id = "non_ascii_keys";
+        let dir = format!("{}/{}", TEST_CACHE_DIR, id);
+        let long = "长".repeat(300);
+        let keys = [
+            "emoji/🦀.whl",
+            "cjk/中文包-1.0.tar.gz",
+            "a b/100%.txt",
+            long.as_str(),
+        ];
+        let metadata_db = RedisMetadataDb::new(new_redis_client(), id).unwrap();
+        for key in keys.iter().chain(&["large"]) {
+            metadata_db.remove_lru_entry(key).unwrap();
+        }
+        let mut lru_cache = new_lru_redis_cache!(dir, 16, new_redis_client(), id);
+        let mut con = new_redis_client().get_connection().unwrap();
+        for key in &keys {
+            cache_put!(lru_cache, key, vec![1; 4].into());
+            assert!(cache_get!(lru_cache, key).is_some(), "{}", key);
+            // the hash of the entry records its file
+            let path: String = con.hget(format!("{}_{}", id, key), "path").unwrap();
+            assert_eq!(path, storage::encode_file_path(key));
+            assert!(Path::new(&dir).join(&path).exists(), "{}", key);
+        }
+        // evict all of them
+        cache_put!(lru_cache, "large", vec![1; 16].into());
+        for key in &keys {
+            let path = Path::new(&dir).join(storage::encode_file_path(key));
+            assert!(!path.exists(), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn lru_redis_cache_many_small_entries() {
+        let mut lru_cache = LruCache::new(
+            1024 * 1024,
+            Arc::new(RedisMetadataDb::new(new_redis_client(), "many_small_entries").unwrap()),
+            Arc::new(FsBackend {
+                root_dir: format!("{}/many_small_entries", TEST_CACHE_DIR),
+                sharded: true,
+                chunk_size: None,
+                fsync: Default::default(),
+                permissions: Default::default(),
+            }),
+            "many_small_entries",
+        );
+        let start = time::Instant::now();
+        for i in 0..5000 {
+            let key = format!("tlnet/archive/pkg{}.tar.xz", i);
+            cache_put!(lru_cache, &key, vec![0; 10].into());
+        }
+        assert_eq!(lru_cache.get_total_size(), 5000 * 10);
+        assert!(start.elapsed() < time::Duration::from_secs(60));
+    }
+
+    fn test_evicted_entries(metadata_db: &dyn LruMetadataStore) {
+        metadata_db.set_lru_entry("old", 5, None).unwrap();
+        util::sleep_ms(1000);
+        metadata_db.set_lru_entry("new", 3, None).unwrap();
+        let evicted = metadata_db
+            .eviction_candidates(4, "newer", 10, None)
+            .unwrap()
+            .evicted;
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].key, "old");
+        assert_eq!(evicted[0].size, 5);
+        let age = util::now() - evicted[0].atime;
+        assert!((1..10).contains(&age), "age: {}", age);
+    }
+
+    #[test]
+    fn redis_evict_reports_entries() {
+        test_evicted_entries(&RedisMetadataDb::new(new_redis_client(), "evict_reports").unwrap());
+    }
+
+    #[test]
+    fn sled_evict_reports_entries() {
+        test_evicted_entries(&SledMetadataDb::new_lru(
+            &format!("{}/evict_reports", TEST_CACHE_DIR),
+            "evict_reports",
+        ));
+    }
+
+    fn test_eviction_preview(metadata_db: &dyn LruMetadataStore) {
+        let entries = [("a", 4), ("b", 3), ("c", 5), ("d", 2)];
+        for (key, size) in &entries {
+            metadata_db.remove_lru_entry(key).unwrap();
+            metadata_db.set_lru_entry(key, *size, None).unwrap();
+            util::sleep_ms(10);
+        }
+        // `a` becomes the most recently used entry
+        assert!(matches!(metadata_db.get_lru_entry("a"), CacheHitMiss::Hit));
+        assert!(metadata_db.preview_eviction(0, 14).unwrap().is_empty());
+        let preview = metadata_db.preview_eviction(0, 6).unwrap();
+        let keys: Vec<&str> = preview.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, vec!["b", "c"]);
+        // nothing is removed by the preview
+        assert_eq!(metadata_db.get_total_size(), 14);
+        assert_eq!(metadata_db.preview_eviction(0, 6).unwrap(), preview);
+        let keys: Vec<String> = preview.into_iter().map(|entry| entry.key).collect();
+        assert_eq!(
+            metadata_db.remove_lru_entries(&keys).unwrap(),
+            vec![true, true]
+        );
+        assert_eq!(metadata_db.get_total_size(), 6);
+    }
+
+    #[test]
+    fn redis_eviction_preview() {
+        test_eviction_preview(
+            &RedisMetadataDb::new(new_redis_client(), "eviction_preview").unwrap(),
+        );
+    }
+
+    #[test]
+    fn sled_eviction_preview() {
+        test_eviction_preview(&SledMetadataDb::new_lru(
+            &format!("{}/eviction_preview", TEST_CACHE_DIR),
+            "eviction_preview",
+        ));
+    }
+
+    fn test_lru_entry_stats(metadata_db: &dyn LruMetadataStore) {
+        for key in &["a", "b", "c"] {
+            metadata_db.remove_lru_entry(key).unwrap();
+            metadata_db.set_lru_entry(key, 1, None).unwrap();
+            util::sleep_ms(10);
+        }
+        for _ in 0..3 {
+            assert!(matches!(metadata_db.get_lru_entry("a"), CacheHitMiss::Hit));
+        }
+        // a protected entry moves to the end of the queue without a hit
+        assert!(metadata_db.touch_lru_entry("b").unwrap());
+        assert!(!metadata_db.touch_lru_entry("missing").unwrap());
+        let stats = metadata_db.lru_entry_stats(0, 10).unwrap();
+        let hits: Vec<(&str, u64)> = stats
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.hits))
+            .collect();
+        assert_eq!(hits, vec![("c", 0), ("a", 3), ("b", 0)]);
+        assert_eq!(metadata_db.lru_len().unwrap(), 3);
+        assert_eq!(metadata_db.lru_entry_stats(1, 1).unwrap(), stats[1..2]);
+        // a replaced entry keeps its hit count
+        metadata_db.set_lru_entry("a", 2, None).unwrap();
+        let stats = metadata_db.lru_entry_stats(2, 1).unwrap();
+        assert_eq!(
+            (stats[0].key.as_str(), stats[0].size, stats[0].hits),
+            ("a", 2, 3)
+        );
+    }
+
+    #[test]
+    fn redis_lru_entry_stats() {
+        test_lru_entry_stats(&RedisMetadataDb::new(new_redis_client(), "lru_entry_stats").unwrap());
+    }
+
+    #[test]
+    fn sled_lru_entry_stats() {
+        test_lru_entry_stats(&SledMetadataDb::new_lru(
+            &format!("{}/lru_entry_stats", TEST_CACHE_DIR),
+            "lru_entry_stats",
+        ));
+    }
+
+    fn shard_ids(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("volume{}", i)).collect()
+    }
+
+    #[test]
+    fn hash_ring_distribution() {
+        let ring = HashRing::new(&shard_ids(4));
+        let mut counts = vec![0; 4];
+        for i in 0..10000 {
+            counts[ring.get(&format!("pypi/packages/pkg{}.whl", i))] += 1;
+        }
+        for count in counts {
+            assert!((1500..3500).contains(&count), "unbalanced shard: {}", count);
+        }
+    }
+
+    #[test]
+    fn hash_ring_add_shard() {
+        let ring = HashRing::new(&shard_ids(4));
+        let new_ring = HashRing::new(&shard_ids(5));
+        let mut moved = 0;
+        for i in 0..10000 {
+            let key = format!("pypi/packages/pkg{}.whl", i);
+            let (old, new) = (ring.get(&key), new_ring.get(&key));
+            if old != new {
+                // keys only move to the new shard
+                assert_eq!(new, 4);
+                moved += 1;
+            }
+        }
+        assert!((1000..3000).contains(&moved), "moved keys: {}", moved);
+    }
+
+    #[tokio::test]
+    async fn sharded_cache_enforces_shard_limits() {
+        let shards = shard_ids(3)
+            .into_iter()
+            .map(|id| {
+                let shard = new_lru_redis_cache!(
+                    format!("{}/sharded/{}", TEST_CACHE_DIR, id),
+                    100,
+                    new_redis_client(),
+                    &format!("sharded_{}", id)
+                );
+                (id, shard)
+            })
+            .collect();
+        let mut cache = ShardedCache::new(shards);
+        for i in 0..30 {
+            cache_put!(cache, &format!("key{}", i), vec![i as u8; 10].into());
+        }
+        for shard in &cache.shards {
+            assert!(shard.get_total_size() <= 100);
+        }
+        let key = "key29";
+        assert_eq!(cache_get!(cache, key).unwrap().to_vec().await, vec![29; 10]);
+        let shard = &cache.shards[cache.ring.get(key)];
+        assert_eq!(cache_get!(shard, key).unwrap().to_vec().await, vec![29; 10]);
+    }
+
     #[tokio::test]
     async fn test_ttl_redis_cache_expire_key() {
         setup();
@@ -1323,6 +5059,150 @@ mod tests {
         assert!(cache_get!(cache, "key").is_none());
     }
 
+    #[tokio::test]
+    async fn ttl_redis_cache_staleness() {
+        setup();
+        let redis_client = new_redis_client();
+        let id = "ttl_staleness";
+        let mut cache =
+            new_ttl_redis_cache!(TEST_CACHE_DIR, 1, redis_client.clone(), id).with_stale_window(2);
+        cache_put!(cache, "stale_key", vec![1].into());
+        assert_eq!(
+            cache.staleness("stale_key").await,
+            Some(Duration::from_secs(0))
+        );
+        let mut con = redis_client.get_connection().unwrap();
+        let meta_key = RedisMetadataDb::get_ttl_meta_key(&CacheId::new(id).unwrap(), "stale_key");
+        let (created_at, expires_at): (i64, i64) = redis::cmd("HMGET")
+            .arg(&meta_key)
+            .arg("created_at")
+            .arg("expires_at")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(expires_at, created_at + 1);
+        assert!(cache.age("stale_key").await.unwrap() <= Duration::from_secs(1));
+        util::sleep_ms(2500);
+        // expired, but still in the grace period
+        assert!(cache_get!(cache, "stale_key").is_none());
+        assert!(cache.age("stale_key").await.unwrap() >= Duration::from_secs(2));
+        assert!(cache.staleness("stale_key").await.unwrap() >= Duration::from_secs(1));
+        assert!(cache.get_stale("stale_key").await.is_some());
+        util::sleep_ms(2000);
+        // the file is removed along with its metadata
+        assert!(cache.get_stale("stale_key").await.is_none());
+        assert_eq!(cache.staleness("stale_key").await, None);
+        assert_eq!(cache.age("stale_key").await, None);
+        assert!(!models::cache_entry_exists(&mut con, &meta_key).unwrap());
+    }
+
+    #[test]
+    fn sliding_extension() {
+        let sliding = Sliding { max_lifetime: None };
+        // more than half of the ttl remains
+        assert_eq!(sliding.extended(60, 1000, 1060, 1020), None);
+        assert_eq!(sliding.extended(60, 1000, 1060, 1030), Some(1090));
+        assert_eq!(sliding.extended(60, 1000, 1060, 1059), Some(1119));
+        let capped = Sliding {
+            max_lifetime: Some(100),
+        };
+        assert_eq!(capped.extended(60, 1000, 1060, 1059), Some(1100));
+        // at its max lifetime
+        assert_eq!(capped.extended(60, 1000, 1100, 1090), None);
+    }
+
+    #[tokio::test]
+    async fn ttl_redis_cache_sliding() {
+        setup();
+        let redis_client = new_redis_client();
+        let new_cache = |id: &str, sliding: Option<Sliding>| {
+            new_ttl_redis_cache!(TEST_CACHE_DIR, 4, redis_client.clone(), id).with_sliding(sliding)
+        };
+        let mut sliding = new_cache("ttl_sliding", Some(Sliding { max_lifetime: None }));
+        let capped = Sliding {
+            max_lifetime: Some(5),
+        };
+        let mut capped = new_cache("ttl_sliding_capped", Some(capped));
+        let mut fixed = new_cache("ttl_not_sliding", None);
+        cache_put!(sliding, "key", vec![1].into());
+        cache_put!(capped, "key", vec![1].into());
+        cache_put!(fixed, "key", vec![1].into());
+        let mut con = redis_client.get_connection().unwrap();
+        let times = |id: &str| {
+            let meta_key = RedisMetadataDb::get_ttl_meta_key(&CacheId::new(id).unwrap(), "key");
+            let mut con = redis_client.get_connection().unwrap();
+            models::get_ttl_cache_times(&mut con, &meta_key)
+                .unwrap()
+                .unwrap()
+        };
+        let set = times("ttl_sliding");
+        assert_eq!(set.ttl, Some(4));
+        // hits are not written while more than half of the ttl remains
+        assert!(cache_get!(sliding, "key").is_some());
+        assert_eq!(times("ttl_sliding"), set);
+
+        util::sleep_ms(2500);
+        assert!(cache_get!(sliding, "key").is_some());
+        assert!(cache_get!(capped, "key").is_some());
+        assert!(cache_get!(fixed, "key").is_some());
+        assert!(times("ttl_sliding").expires_at > set.expires_at);
+        let capped_times = times("ttl_sliding_capped");
+        assert_eq!(
+            capped_times.expires_at,
+            capped_times.created_at.unwrap() + 5
+        );
+        assert_eq!(times("ttl_not_sliding").expires_at, set.expires_at);
+        let id = CacheId::new("ttl_sliding").unwrap();
+        let redis_key = RedisMetadataDb::get_redis_key(&id, "key");
+        let remove_in: i64 = redis::cmd("TTL").arg(&redis_key).query(&mut con).unwrap();
+        assert!(remove_in > 2, "{}", remove_in);
+
+        util::sleep_ms(2000);
+        assert!(cache_get!(sliding, "key").is_some());
+        util::sleep_ms(2000);
+        assert!(cache_get!(sliding, "key").is_some());
+        assert!(cache_get!(capped, "key").is_none());
+        assert!(cache_get!(fixed, "key").is_none());
+        // expired, though kept for the grace period
+        assert_eq!(
+            fixed.contains_many(&["key".to_string()]).unwrap(),
+            vec![false]
+        );
+    }
+
+    #[tokio::test]
+    async fn ttl_cache_close_many() {
+        setup();
+        let redis_client = new_redis_client();
+        let started = std::time::Instant::now();
+        let mut caches: Vec<TtlCache> = (0..32)
+            .map(|i| {
+                let id = format!("ttl_close_{}", i);
+                new_ttl_redis_cache!(TEST_CACHE_DIR, 1, redis_client.clone(), &id)
+            })
+            .collect();
+        future::join_all(caches.iter_mut().map(|cache| cache.close())).await;
+        assert!(caches
+            .iter()
+            .all(|cache| cache.expiration_thread_handler.is_none()));
+        // closing again, or dropping closed caches, is a no-op
+        caches[0].close().await;
+        drop(caches);
+        // dropping caches that are not closed does not block the runtime
+        for i in 0..8 {
+            let id = format!("ttl_drop_{}", i);
+            let cache = new_ttl_redis_cache!(TEST_CACHE_DIR, 1, redis_client.clone(), &id);
+            drop(cache);
+        }
+        assert!(started.elapsed() < CLOSE_TIMEOUT * 2);
+    }
+
+    #[test]
+    fn join_panicked_expiration_thread() {
+        let thread_handler = std::thread::spawn(|| panic!("malformed pubsub message"));
+        // logged instead of propagated
+        join_expiration_thread(thread_handler);
+    }
+
     #[tokio::test]
     async fn ttl_sled_cache_expire_key() {
         setup();
@@ -1337,4 +5217,274 @@ mod tests {
         util::sleep_ms(1000);
         assert!(cache_get!(cache, "key").is_none());
     }
+
+    #[tokio::test]
+    async fn ttl_cache_put_with_ttl() {
+        setup();
+        let mut cache = new_ttl_sled_cache!(
+            &format!("{}/sled_put_with_ttl", TEST_CACHE_DIR),
+            1,
+            "ttl_sled_put_with_ttl",
+            0
+        );
+        cache_put!(cache, "short", vec![1].into());
+        cache.put_with_ttl("long", vec![2].into(), 3600).await;
+        util::sleep_ms(1500);
+        assert!(cache_get!(cache, "short").is_none());
+        assert_eq!(cache_get!(cache, "long").unwrap().to_vec().await, vec![2]);
+        let keys = vec!["short".to_string(), "long".to_string(), "none".to_string()];
+        assert_eq!(
+            cache.contains_many(&keys).unwrap(),
+            vec![false, true, false]
+        );
+    }
+
+    /// All keys of a cache, scanned in batches of `count`
+    fn scan_all(cache: &dyn Cache, count: usize) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let (batch, next) = cache.scan_keys(&cursor, count).unwrap();
+            keys.extend(batch);
+            if next.is_empty() {
+                break;
+            }
+            cursor = next;
+        }
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    fn scan_keys_test_entries() -> Vec<String> {
+        (0..5).map(|i| format!("scan/pkg{}.whl", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn lru_redis_cache_scan_and_remove() {
+        let dir = format!("{}/lru_redis_scan", TEST_CACHE_DIR);
+        let id = "lru_redis_scan";
+        let redis_client = new_redis_client();
+        let mut cache = new_lru_redis_cache!(&dir, 1024, redis_client, id);
+        for key in &scan_keys_test_entries() {
+            cache_put!(cache, key, vec![1; 10].into());
+        }
+        assert_eq!(scan_all(&cache, 2), scan_keys_test_entries());
+        assert!(cache.remove("scan/pkg0.whl").await.unwrap());
+        assert!(!cache.remove("scan/pkg0.whl").await.unwrap());
+        assert!(file_not_exist(&format!("{}/scan/pkg0.whl", dir)));
+        assert!(cache_get!(cache, "scan/pkg0.whl").is_none());
+        assert_eq!(cache.get_total_size(), 40);
+        assert_eq!(scan_all(&cache, 2), scan_keys_test_entries()[1..]);
+        assert_eq!(
+            cache.entry_sizes(&scan_keys_test_entries()).unwrap(),
+            vec![0, 10, 10, 10, 10]
+        );
+        assert_eq!(
+            cache.contains_many(&scan_keys_test_entries()).unwrap(),
+            vec![false, true, true, true, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn lru_redis_cache_contains_many() {
+        let dir = format!("{}/lru_redis_contains", TEST_CACHE_DIR);
+        let id = "lru_redis_contains";
+        let redis_client = new_redis_client();
+        let mut cache = new_lru_redis_cache!(&dir, 1024, redis_client, id);
+        cache_put!(cache, "a", vec![1].into());
+        util::sleep_ms(5);
+        cache_put!(cache, "b", vec![1].into());
+        let keys = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(cache.contains_many(&keys).unwrap(), vec![true, true, false]);
+        assert!(cache.contains_many(&[]).unwrap().is_empty());
+        // the least recently used entry is still the first one put
+        assert_eq!(cache.metadata_db.lru_keys(0, 10), vec!["a", "b"]);
+    }
+
+    /// Compares the pipelined lookup with one round trip per key, run with
+    /// `cargo test contains_many_benchmark -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn contains_many_benchmark() {
+        let dir = format!("{}/lru_redis_contains_bench", TEST_CACHE_DIR);
+        let id = "lru_redis_contains_bench";
+        let redis_client = new_redis_client();
+        let mut cache = new_lru_redis_cache!(&dir, 1 << 20, redis_client, id);
+        let keys: Vec<String> = (0..1000).map(|i| format!("bench/{}.whl", i)).collect();
+        for key in keys.iter().step_by(2) {
+            cache_put!(cache, key, vec![1].into());
+        }
+        let started = std::time::Instant::now();
+        let pipelined = cache.metadata_db.lru_entries_exist(&keys).unwrap();
+        let pipelined_time = started.elapsed();
+        let started = std::time::Instant::now();
+        let looped: Vec<bool> = keys
+            .iter()
+            .map(|key| cache.metadata_db.lru_entry_exists(key).unwrap())
+            .collect();
+        let looped_time = started.elapsed();
+        assert_eq!(pipelined, looped);
+        println!(
+            "{} keys: pipelined {:?}, looped {:?}",
+            keys.len(),
+            pipelined_time,
+            looped_time
+        );
+    }
+
+    /// p99 latency of gets of a skewed workload, with and without hot keys.
+    /// `cargo test hot_keys_benchmark -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn hot_keys_benchmark() {
+        let dir = format!("{}/lru_sled_hot_keys_bench", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let keys: Vec<String> = (0..100).map(|i| format!("simple/pkg{}/", i)).collect();
+        // 4 of 5 gets are of the first 5 keys, like the index pages of
+        // popular packages
+        let workload: Vec<&String> = (0..5000)
+            .map(|i| match i % 5 {
+                4 => &keys[(i * 7) % keys.len()],
+                _ => &keys[i % 5],
+            })
+            .collect();
+        for (id, hot_keys) in &[("cold", None), ("hot", Some(16))] {
+            let hot_keys = hot_keys.map(|top| HotKeys::new(top, Duration::from_secs(60), 1 << 20));
+            let mut cache = new_lru_sled_cache!(&format!("{}/{}", dir, id), 1 << 30, *id)
+                .with_hot_keys(hot_keys);
+            for key in &keys {
+                cache_put!(cache, key, vec![1; 64 * 1024].into());
+            }
+            let mut latencies = Vec::with_capacity(workload.len());
+            for key in &workload {
+                let started = std::time::Instant::now();
+                let content = cache_get!(cache, key).unwrap().into_vec_u8().await;
+                latencies.push(started.elapsed());
+                assert_eq!(content.len(), 64 * 1024);
+            }
+            latencies.sort();
+            println!(
+                "{} keys: p50 {:?}, p99 {:?}",
+                id,
+                latencies[latencies.len() / 2],
+                latencies[latencies.len() * 99 / 100]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_scan_and_remove() {
+        let dir = format!("{}/lru_sled_scan", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let mut cache = new_lru_sled_cache!(&dir, 1024, "lru_sled_scan");
+        for key in &scan_keys_test_entries() {
+            cache_put!(cache, key, vec![1; 10].into());
+        }
+        assert_eq!(scan_all(&cache, 2), scan_keys_test_entries());
+        assert!(cache.remove("scan/pkg3.whl").await.unwrap());
+        assert!(!cache.remove("scan/pkg3.whl").await.unwrap());
+        assert!(file_not_exist(&format!("{}/scan/pkg3.whl", dir)));
+        assert_eq!(cache.get_total_size(), 40);
+        assert_eq!(
+            cache.entry_sizes(&scan_keys_test_entries()).unwrap(),
+            vec![10, 10, 10, 0, 10]
+        );
+        assert_eq!(
+            cache.contains_many(&scan_keys_test_entries()).unwrap(),
+            vec![true, true, true, false, true]
+        );
+        // the atime entry is removed too, so it is not evicted again
+        assert_eq!(cache.metadata_db.lru_keys(0, 10).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn ttl_redis_cache_scan_and_remove() {
+        setup();
+        let redis_client = new_redis_client();
+        let id = "ttl_redis_scan";
+        let mut cache = new_ttl_redis_cache!(TEST_CACHE_DIR, 60, redis_client.clone(), id);
+        for key in &scan_keys_test_entries() {
+            cache_put!(cache, key, vec![1].into());
+        }
+        assert_eq!(scan_all(&cache, 2), scan_keys_test_entries());
+        assert!(cache.remove("scan/pkg1.whl").await.unwrap());
+        assert!(cache_get!(cache, "scan/pkg1.whl").is_none());
+        assert_eq!(cache.staleness("scan/pkg1.whl").await, None);
+        assert!(file_not_exist(&format!("{}/scan/pkg1.whl", TEST_CACHE_DIR)));
+        assert!(cache_get!(cache, "scan/pkg2.whl").is_some());
+        assert_eq!(
+            cache.contains_many(&scan_keys_test_entries()).unwrap(),
+            vec![true, false, true, true, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn sharded_cache_scan_all_shards() {
+        let shards = (0..3)
+            .map(|i| {
+                let id = format!("volume{}", i);
+                let cache = new_lru_sled_cache!(
+                    format!("{}/sharded_scan/{}", TEST_CACHE_DIR, id),
+                    1024,
+                    &format!("sharded_scan_{}", id)
+                );
+                (id, cache)
+            })
+            .collect();
+        let mut cache = ShardedCache::new(shards);
+        for key in &scan_keys_test_entries() {
+            cache_put!(cache, key, vec![1].into());
+        }
+        assert_eq!(scan_all(&cache, 1), scan_keys_test_entries());
+        assert!(cache.remove("scan/pkg4.whl").await.unwrap());
+        assert_eq!(scan_all(&cache, 1), scan_keys_test_entries()[..4]);
+        assert_eq!(
+            cache.entry_sizes(&scan_keys_test_entries()).unwrap(),
+            vec![1, 1, 1, 1, 0]
+        );
+        assert_eq!(
+            cache.contains_many(&scan_keys_test_entries()).unwrap(),
+            vec![true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn micro_cache_bounded() {
+        let micro_cache = MicroCache::new(Duration::from_millis(200), 2);
+        micro_cache.put("a", 1);
+        std::thread::sleep(Duration::from_millis(10));
+        micro_cache.put("b", 2);
+        micro_cache.put("b", 3);
+        assert_eq!(micro_cache.get("b"), Some(3));
+        // the oldest entry makes room
+        micro_cache.put("c", 4);
+        assert_eq!(micro_cache.get("a"), None);
+        assert_eq!(micro_cache.get("b"), Some(3));
+        assert_eq!(micro_cache.entries.lock().unwrap().len(), 2);
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(micro_cache.get("c"), None);
+        assert!(micro_cache.entries.lock().unwrap().len() <= 1);
+    }
+
+    #[tokio::test]
+    async fn no_cache_micro_cache() {
+        let mut cache = NoCache::default();
+        cache_put!(cache, "pkg", vec![1; 4].into());
+        assert!(cache_get!(cache, "pkg").is_none());
+
+        let mut cache = NoCache::with_micro_cache(MicroCache::new(Duration::from_secs(60), 8), 4);
+        cache_put!(cache, "pkg", vec![1; 4].into());
+        assert_eq!(cache_get!(cache, "pkg").unwrap().to_vec().await, vec![1; 4]);
+        // too large, read to the end but not kept
+        let read = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = read.clone();
+        let chunks = stream::iter(vec![vec![2; 3], vec![2; 3], vec![2; 3]]).map(move |chunk| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Bytes::from(chunk))
+        });
+        cache_put!(cache, "big", CacheData::ByteStream(Box::new(chunks), None));
+        assert_eq!(read.load(Ordering::SeqCst), 3);
+        assert!(cache_get!(cache, "big").is_none());
+    }
 }
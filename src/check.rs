@@ -0,0 +1,493 @@
+//! Self-tests of a configuration: redis, keyspace notifications of TTL caches,
+//! the storages of caches and the upstreams of rules are probed. Run by the
+//! `check` subcommand before a config is deployed, and in part by the
+//! readiness endpoint, which the `health` subcommand asks.
+
+use crate::cache::CacheData;
+use crate::error::{Error, Result};
+use crate::listener;
+use crate::models;
+use crate::settings::{MetadataDb, PolicyType, Settings};
+use crate::storage::{Storage, StorageBackend};
+use crate::task::{Task, TaskManager};
+use crate::upstreams;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest time a probe waits for redis or an upstream
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Content of the files written by storage probes
+const PROBE_CONTENT: &[u8] = b"mirror-cache storage probe";
+
+/// The outcome of a probe
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    /// What is probed, e.g. `redis` or `storage`
+    pub check: String,
+    /// e.g. the id of a cache or the origin of an upstream
+    pub target: String,
+    /// Why the probe failed, `None` if it passed
+    pub error: Option<String>,
+}
+
+impl ProbeResult {
+    fn new(check: &str, target: impl Into<String>, result: Result<()>) -> Self {
+        Self {
+            check: check.to_string(),
+            target: target.into(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Connect to redis and send a `PING`
+pub fn probe_redis(client: &redis::Client) -> Result<()> {
+    let mut con = client
+        .get_connection_with_timeout(PROBE_TIMEOUT)
+        .map_err(Error::RedisUnavailable)?;
+    models::ping(&mut con)
+}
+
+/// Check that redis notifies expired keys, which TTL caches with redis
+/// metadata rely on to remove the files of expired entries
+pub fn probe_keyspace_notifications(client: &redis::Client) -> Result<()> {
+    let mut con = client
+        .get_connection_with_timeout(PROBE_TIMEOUT)
+        .map_err(Error::RedisUnavailable)?;
+    let events = models::notify_keyspace_events(&mut con)?;
+    if notifies_expired_keys(&events) {
+        Ok(())
+    } else {
+        Err(Error::OtherError(format!(
+            "notify-keyspace-events is \"{}\", at least \"Kx\" is expected",
+            events
+        )))
+    }
+}
+
+/// `K` enables keyspace events, and `x` or `A` (all classes) expired ones
+fn notifies_expired_keys(events: &str) -> bool {
+    events.contains('K') && (events.contains('x') || events.contains('A'))
+}
+
+/// Write a small file to a storage, read it back and remove it
+pub async fn probe_storage(storage: &dyn StorageBackend) -> Result<()> {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        ".mirror-cache-probe-{}-{}",
+        std::process::id(),
+        SEQ.fetch_add(1, Ordering::SeqCst)
+    );
+    let data = CacheData::BytesData(Bytes::from_static(PROBE_CONTENT));
+    storage.persist(&name, data).await?;
+    let content = match storage.read(&name).await {
+        Ok(data) => read_all(data).await,
+        Err(e) => Err(e),
+    };
+    let removed = storage.remove(&name).await;
+    if content? != PROBE_CONTENT {
+        return Err(Error::OtherError(
+            "the probe file is read back with another content".to_string(),
+        ));
+    }
+    removed
+}
+
+async fn read_all(data: CacheData) -> Result<Vec<u8>> {
+    let mut stream = data.into_byte_stream();
+    let mut content = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        content.extend_from_slice(&chunk?);
+    }
+    Ok(content)
+}
+
+/// Send a `HEAD` request to the origin of an upstream. Any response but a
+/// server error shows that it is reachable.
+pub async fn probe_upstream(client: &reqwest::Client, origin: &str) -> Result<()> {
+    let resp = tokio::time::timeout(PROBE_TIMEOUT, client.head(origin).send())
+        .await
+        .map_err(|_| {
+            Error::UpstreamUnavailable(format!("no response in {} secs", PROBE_TIMEOUT.as_secs()))
+        })?
+        .map_err(Error::RequestError)?;
+    if resp.status().is_server_error() {
+        return Err(Error::UpstreamUnavailable(format!(
+            "answered {}",
+            resp.status()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether caches or quotas keep their data in redis
+fn uses_redis(settings: &Settings) -> bool {
+    settings.quotas.is_some()
+        || settings
+            .policies
+            .iter()
+            .any(|p| p.typ != PolicyType::NoCache && matches!(p.metadata_db, MetadataDb::Redis))
+}
+
+/// Whether a TTL cache relies on keyspace notifications to remove files
+fn uses_keyspace_notifications(settings: &Settings) -> bool {
+    settings
+        .policies
+        .iter()
+        .any(|p| p.typ == PolicyType::Ttl && matches!(p.metadata_db, MetadataDb::Redis))
+}
+
+/// The redis url without its password
+fn redacted_redis_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// The storages of the caches of the settings, by cache id. Each cache has
+/// its own directory in a filesystem storage.
+fn cache_storages(settings: &Settings) -> Vec<(String, Arc<dyn StorageBackend>)> {
+    let storages: HashMap<&str, Storage> = settings
+        .storages
+        .iter()
+        .map(|s| (s.name.as_str(), TaskManager::create_storage(s)))
+        .collect();
+    let mut caches = Vec::new();
+    for policy in &settings.policies {
+        if policy.typ == PolicyType::NoCache {
+            continue;
+        }
+        match (policy.typ, &policy.shards) {
+            (PolicyType::Lru, Some(shards)) => {
+                for shard in shards {
+                    let id = format!("{}_{}", policy.name, shard.storage);
+                    if let Some(storage) = storages.get(shard.storage.as_str()) {
+                        caches.push((id.clone(), storage.for_cache(&id, None)));
+                    }
+                }
+            }
+            _ => {
+                if let Some(storage) = storages.get(policy.storage.as_str()) {
+                    let storage = storage.for_cache(&policy.name, policy.root_dir.as_deref());
+                    caches.push((policy.name.clone(), storage));
+                }
+            }
+        }
+    }
+    caches
+}
+
+/// The origins of the upstreams of rules not in offline mode, e.g.
+/// `https://pypi.org/`, and of their mirrors, each with the first rule
+/// fetching from it
+pub(crate) fn upstream_origins(tm: &TaskManager) -> Vec<(String, usize)> {
+    let mut seen = HashSet::new();
+    let mut origins = Vec::new();
+    for (rule_id, rule) in tm.config.rules.iter().enumerate() {
+        if tm.is_offline(rule_id) {
+            continue;
+        }
+        let channels = rule
+            .options
+            .as_ref()
+            .and_then(|options| options.conda_channels.as_ref());
+        let mirrors = rule.upstreams().into_iter().skip(1);
+        let upstreams = std::iter::once(rule.upstream_template().to_string())
+            .chain(channels.into_iter().flat_map(|c| c.values().cloned()))
+            .chain(mirrors);
+        for upstream in upstreams {
+            if let Some(origin) = upstreams::origin(&upstream) {
+                if seen.insert(origin.clone()) {
+                    origins.push((origin, rule_id));
+                }
+            }
+        }
+    }
+    origins
+}
+
+/// Probe what requests are served with: redis if it is used, and the
+/// storages of caches. Upstreams are left out, since cached files are served
+/// without them.
+pub async fn probe_dependencies(settings: &Settings) -> Vec<ProbeResult> {
+    let mut results = Vec::new();
+    if uses_redis(settings) {
+        let url = settings.get_redis_url();
+        let result = match redis::Client::open(url.as_str()) {
+            Ok(client) => tokio::task::spawn_blocking(move || probe_redis(&client))
+                .await
+                .unwrap_or_else(|e| Err(Error::OtherError(e.to_string()))),
+            Err(e) => Err(Error::RedisUnavailable(e)),
+        };
+        results.push(ProbeResult::new("redis", redacted_redis_url(&url), result));
+    }
+    for (id, storage) in cache_storages(settings) {
+        results.push(ProbeResult::new(
+            "storage",
+            id,
+            probe_storage(&*storage).await,
+        ));
+    }
+    results
+}
+
+/// Load and validate the config of `filename`, and probe everything it
+/// depends on. Nothing else is probed if the config is invalid.
+pub async fn check_config(filename: &str) -> Vec<ProbeResult> {
+    let settings = match Settings::new(filename) {
+        Ok(settings) => settings,
+        Err(e) => return vec![ProbeResult::new("settings", filename, Err(e))],
+    };
+    let mut results = vec![ProbeResult::new("settings", filename, Ok(()))];
+    results.extend(probe_dependencies(&settings).await);
+    if uses_keyspace_notifications(&settings) {
+        let url = settings.get_redis_url();
+        let result = match redis::Client::open(url.as_str()) {
+            Ok(client) => {
+                tokio::task::spawn_blocking(move || probe_keyspace_notifications(&client))
+                    .await
+                    .unwrap_or_else(|e| Err(Error::OtherError(e.to_string())))
+            }
+            Err(e) => Err(Error::RedisUnavailable(e)),
+        };
+        results.push(ProbeResult::new(
+            "keyspace_notifications",
+            redacted_redis_url(&url),
+            result,
+        ));
+    }
+    let tm = TaskManager::new(settings);
+    for (origin, rule_id) in upstream_origins(&tm) {
+        let task = Task {
+            rule_id,
+            url: origin.clone(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let result = probe_upstream(&tm.upstream_client(&task), &origin).await;
+        results.push(ProbeResult::new("upstream", origin, result));
+    }
+    results
+}
+
+/// Ask a running instance whether it is ready, over its unix socket if it
+/// listens on one, otherwise on its TCP port. Run by the `health` subcommand,
+/// e.g. as the health check of a container.
+pub async fn probe_instance(settings: &Settings) -> Result<()> {
+    let timed_out = |_: tokio::time::error::Elapsed| {
+        Error::OtherError(format!("no response in {} secs", PROBE_TIMEOUT.as_secs()))
+    };
+    let (status, body) = match settings.unix_socket() {
+        Some(socket) => {
+            let path = std::path::Path::new(&socket.path);
+            tokio::time::timeout(PROBE_TIMEOUT, listener::get(path, "/ready"))
+                .await
+                .map_err(timed_out)??
+        }
+        None => {
+            let url = format!("http://127.0.0.1:{}/ready", settings.port);
+            // the instance is local, whatever the proxy of upstreams
+            let client = reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .map_err(Error::RequestError)?;
+            let resp = tokio::time::timeout(PROBE_TIMEOUT, client.get(&url).send())
+                .await
+                .map_err(timed_out)?
+                .map_err(Error::RequestError)?;
+            let status = resp.status().as_u16();
+            (
+                status,
+                resp.bytes().await.map_err(Error::RequestError)?.to_vec(),
+            )
+        }
+    };
+    if status != 200 {
+        return Err(Error::OtherError(format!(
+            "not ready, answered {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )));
+    }
+    Ok(())
+}
+
+/// A human readable table of the results, one line per probe
+pub fn format_table(results: &[ProbeResult]) -> String {
+    let check_width = results
+        .iter()
+        .map(|r| r.check.len())
+        .chain(std::iter::once("CHECK".len()))
+        .max()
+        .unwrap_or_default();
+    let target_width = results
+        .iter()
+        .map(|r| r.target.len())
+        .chain(std::iter::once("TARGET".len()))
+        .max()
+        .unwrap_or_default();
+    let mut table = format!(
+        "{:cw$}  {:tw$}  RESULT\n",
+        "CHECK",
+        "TARGET",
+        cw = check_width,
+        tw = target_width
+    );
+    for result in results {
+        let outcome = match &result.error {
+            None => "PASS".to_string(),
+            Some(e) => format!("FAIL {}", e),
+        };
+        table.push_str(&format!(
+            "{:cw$}  {:tw$}  {}\n",
+            result.check,
+            result.target,
+            outcome,
+            cw = check_width,
+            tw = target_width
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn keyspace_events() {
+        assert!(notifies_expired_keys("Kx"));
+        assert!(notifies_expired_keys("AKE"));
+        assert!(notifies_expired_keys("xKE"));
+        assert!(!notifies_expired_keys(""));
+        assert!(!notifies_expired_keys("Ex"));
+        assert!(!notifies_expired_keys("Kg"));
+    }
+
+    #[test]
+    fn redis_password_redacted() {
+        assert_eq!(
+            redacted_redis_url("redis://:secret@redis.corp:6379/0"),
+            "redis://:***@redis.corp:6379/0"
+        );
+        assert_eq!(
+            redacted_redis_url("redis://localhost:3001/"),
+            "redis://localhost:3001/"
+        );
+    }
+
+    fn failures(results: &[ProbeResult]) -> Vec<(&str, &str)> {
+        results
+            .iter()
+            .filter(|r| !r.passed())
+            .map(|r| (r.check.as_str(), r.target.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn check_broken_config() {
+        let dir = TempDir::new("check_broken");
+        // a file where the storage expects a directory
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let config = format!(
+            r#"
+port: 9000
+metrics_port: 9001
+log_level: info
+redis:
+  url: "redis://127.0.0.1:1/"
+sled:
+  metadata_path: "{dir}/sled"
+rules:
+  - path: "pypi/"
+    upstream: "http://127.0.0.1:1/pypi/"
+    policy: "ttl"
+  - path: "local/"
+    upstream: "http://127.0.0.1:1/local/"
+    policy: "lru"
+policies:
+  - name: "ttl"
+    type: TTL
+    metadata_db: redis
+    timeout: 60
+    storage: blocked
+  - name: "lru"
+    type: LRU
+    metadata_db: sled
+    size: 1024
+    storage: fs
+storages:
+  - name: blocked
+    config:
+      Fs:
+        path: "{dir}/file/storage"
+  - name: fs
+    config:
+      Fs:
+        path: "{dir}/storage"
+"#,
+            dir = dir.path().display()
+        );
+        let config_path = dir.path().join("config.yml");
+        std::fs::write(&config_path, config).unwrap();
+        let filename = config_path.to_str().unwrap();
+        let results = check_config(filename).await;
+        assert_eq!(
+            failures(&results),
+            vec![
+                ("redis", "redis://127.0.0.1:1/"),
+                ("storage", "ttl"),
+                ("keyspace_notifications", "redis://127.0.0.1:1/"),
+                // both rules fetch from the same origin
+                ("upstream", "http://127.0.0.1:1/"),
+            ]
+        );
+        assert!(results
+            .iter()
+            .any(|r| r.check == "storage" && r.target == "lru" && r.passed()));
+        // the storage probe leaves nothing behind
+        let lru_dir = dir.path().join("storage/lru");
+        assert_eq!(std::fs::read_dir(lru_dir).unwrap().count(), 0);
+        let table = format_table(&results);
+        assert!(table.starts_with("CHECK"));
+        assert!(table.contains(&format!("settings                {}  PASS", filename)));
+        assert_eq!(table.matches("FAIL").count(), 4);
+    }
+
+    #[tokio::test]
+    async fn check_invalid_config() {
+        let dir = TempDir::new("check_invalid");
+        let config_path = dir.path().join("config.yml");
+        std::fs::write(
+            &config_path,
+            "port: 9000\nmetrics_port: 9001\nlog_level: info\nredis:\n  url: redis://localhost\n\
+             sled:\n  metadata_path: sled\noffline_miss_status: 500\nrules: []\npolicies: []\n\
+             storages: []\n",
+        )
+        .unwrap();
+        let results = check_config(config_path.to_str().unwrap()).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].check, "settings");
+        assert!(results[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("offline_miss_status 500"));
+    }
+}
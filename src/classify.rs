@@ -0,0 +1,68 @@
+//! Classes of the keys of a rule: within one rule, e.g. `v1.2.3/tool.tar.gz`
+//! never changes while `latest/tool.tar.gz` does. Immutable keys are cached
+//! by the rule's LRU policy, mutable ones by a TTL policy or revalidated.
+
+use crate::settings::{KeyClass, KeyClasses};
+use regex::RegexSet;
+
+pub struct KeyClassifier {
+    immutable: RegexSet,
+    mutable: RegexSet,
+    default: KeyClass,
+}
+
+impl KeyClassifier {
+    /// The regexes are checked when settings are loaded
+    pub fn from_settings(classes: &KeyClasses) -> Self {
+        let set = |patterns: &Option<Vec<String>>| {
+            RegexSet::new(patterns.iter().flatten()).expect("invalid key_classes regex")
+        };
+        Self {
+            immutable: set(&classes.immutable),
+            mutable: set(&classes.mutable),
+            default: classes.default.unwrap_or(KeyClass::Immutable),
+        }
+    }
+
+    /// The class of `key`, immutable regexes are matched first
+    pub fn classify(&self, key: &str) -> KeyClass {
+        if self.immutable.is_match(key) {
+            KeyClass::Immutable
+        } else if self.mutable.is_match(key) {
+            KeyClass::Mutable
+        } else {
+            self.default
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn classes(default: Option<KeyClass>) -> KeyClasses {
+        KeyClasses {
+            immutable: Some(vec![r"/v\d+(\.\d+)*/".to_string()]),
+            mutable: Some(vec!["/latest/".to_string(), r"\.json$".to_string()]),
+            default,
+            mutable_policy: None,
+        }
+    }
+
+    #[test]
+    fn classify_keys() {
+        let classifier = KeyClassifier::from_settings(&classes(None));
+        let class = |key| classifier.classify(key);
+        assert_eq!(class("releases/v1.2.3/tool.tar.gz"), KeyClass::Immutable);
+        assert_eq!(class("releases/latest/tool.tar.gz"), KeyClass::Mutable);
+        assert_eq!(class("releases/index.json"), KeyClass::Mutable);
+        // immutable regexes win
+        assert_eq!(class("releases/v2/index.json"), KeyClass::Immutable);
+        assert_eq!(class("releases/tool.tar.gz"), KeyClass::Immutable);
+        let classifier = KeyClassifier::from_settings(&classes(Some(KeyClass::Mutable)));
+        assert_eq!(
+            classifier.classify("releases/tool.tar.gz"),
+            KeyClass::Mutable
+        );
+    }
+}
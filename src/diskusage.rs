@@ -0,0 +1,236 @@
+//! Disk usage of the filesystem storages of caches, see
+//! `Settings::disk_usage`. The bytes an LRU cache records in its metadata
+//! drift from those of its files after crashes, files removed by hand or
+//! writes of other instances; the files are walked periodically to report
+//! both.
+//!
+//! A storage may hold millions of files, so a walk visits `BATCH_SIZE`
+//! entries at a time on the blocking pool, and pauses for `BATCH_PAUSE`
+//! between batches to leave the disk to requests.
+
+use crate::storage;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Entries of directories visited per batch of a walk
+pub const BATCH_SIZE: usize = 4096;
+
+/// Pause between the batches of a walk
+pub const BATCH_PAUSE: Duration = Duration::from_millis(50);
+
+/// Files of a storage, temporary files of interrupted or ongoing writes
+/// counted apart
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DiskUsage {
+    pub bytes: u64,
+    pub files: u64,
+    pub temp_bytes: u64,
+    pub temp_files: u64,
+}
+
+impl DiskUsage {
+    pub fn add(&mut self, other: &DiskUsage) {
+        self.bytes += other.bytes;
+        self.files += other.files;
+        self.temp_bytes += other.temp_bytes;
+        self.temp_files += other.temp_files;
+    }
+}
+
+/// A walk of a directory tree, a batch of entries at a time. Symlinks are
+/// not followed.
+pub struct Walk {
+    dirs: Vec<PathBuf>,
+    current: Option<fs::ReadDir>,
+    usage: DiskUsage,
+}
+
+impl Walk {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            dirs: vec![root.to_path_buf()],
+            current: None,
+            usage: DiskUsage::default(),
+        }
+    }
+
+    /// Visit at most `batch` entries. False once the walk is complete.
+    /// Directories that cannot be read, e.g. removed meanwhile, are skipped.
+    pub fn step(&mut self, batch: usize) -> bool {
+        let mut visited = 0;
+        while visited < batch {
+            let entries = match &mut self.current {
+                Some(entries) => entries,
+                None => match self.dirs.pop() {
+                    Some(dir) => {
+                        self.current = fs::read_dir(dir).ok();
+                        continue;
+                    }
+                    None => return false,
+                },
+            };
+            let entry = match entries.next() {
+                Some(entry) => entry,
+                None => {
+                    self.current = None;
+                    continue;
+                }
+            };
+            visited += 1;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let path = entry.path();
+            if metadata.is_dir() {
+                self.dirs.push(path);
+            } else if storage::is_fs_temp_path(&path) {
+                self.usage.temp_files += 1;
+                self.usage.temp_bytes += metadata.len();
+            } else {
+                self.usage.files += 1;
+                self.usage.bytes += metadata.len();
+            }
+        }
+        true
+    }
+
+    pub fn usage(&self) -> DiskUsage {
+        self.usage
+    }
+}
+
+/// Walk `dirs` in batches on the blocking pool
+pub async fn measure(dirs: &[String]) -> DiskUsage {
+    let mut usage = DiskUsage::default();
+    for dir in dirs {
+        let mut walk = Walk::new(Path::new(dir));
+        loop {
+            let step = tokio::task::spawn_blocking(move || {
+                let more = walk.step(BATCH_SIZE);
+                (walk, more)
+            });
+            let more = match step.await {
+                Ok((stepped, more)) => {
+                    walk = stepped;
+                    more
+                }
+                // the walk is lost, the usage of the directory with it
+                Err(e) => {
+                    warn!("failed to walk {}: {}", dir, e);
+                    break;
+                }
+            };
+            if !more {
+                usage.add(&walk.usage());
+                break;
+            }
+            tokio::time::sleep(BATCH_PAUSE).await;
+        }
+    }
+    usage
+}
+
+/// Last walk of the storage of a cache
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskUsageReport {
+    pub usage: DiskUsage,
+    /// Bytes recorded by the metadata of the cache when the walk completed,
+    /// `None` if it does not keep count, e.g. of a TTL cache
+    pub accounted_bytes: Option<u64>,
+    /// Unix timestamp in seconds
+    pub measured_at: i64,
+}
+
+impl DiskUsageReport {
+    /// Bytes of files on disk over those recorded, negative if files are
+    /// missing
+    pub fn drift(&self) -> Option<i64> {
+        let accounted = self.accounted_bytes?;
+        Some(self.usage.bytes as i64 - accounted as i64)
+    }
+}
+
+/// Last walks of the storages of caches, by policy
+pub struct DiskUsageReports {
+    reports: Mutex<HashMap<String, DiskUsageReport>>,
+}
+
+impl DiskUsageReports {
+    pub fn new() -> Self {
+        Self {
+            reports: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, policy: &str) -> Option<DiskUsageReport> {
+        self.reports.lock().unwrap().get(policy).copied()
+    }
+
+    pub fn insert(&self, policy: &str, report: DiskUsageReport) {
+        let mut reports = self.reports.lock().unwrap();
+        reports.insert(policy.to_string(), report);
+    }
+
+    /// Whether the storage of `policy` is due for a walk every `interval`
+    /// secs, counted from the end of the last one
+    pub fn is_due(&self, policy: &str, interval: u64, now: i64) -> bool {
+        self.get(policy)
+            .map_or(true, |report| now - report.measured_at >= interval as i64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn measure_files() {
+        let root = Path::new("cache/disk_usage_test");
+        let _ = fs::remove_dir_all(root);
+        let files = [
+            (root.join("a.whl"), "wheel"),
+            (root.join("ab/cd/b.tar.gz"), "sdist"),
+            (root.join("ab/.c.whl.part"), "part"),
+        ];
+        for (path, content) in &files {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        // batches smaller than the tree
+        let mut walk = Walk::new(root);
+        let mut steps = 1;
+        while walk.step(2) {
+            steps += 1;
+        }
+        assert!(steps > 2);
+        let expected = DiskUsage {
+            bytes: 10,
+            files: 2,
+            temp_bytes: 4,
+            temp_files: 1,
+        };
+        assert_eq!(walk.usage(), expected);
+        let missing = "cache/disk_usage_test/missing".to_string();
+        let dirs = [root.to_string_lossy().to_string(), missing];
+        assert_eq!(measure(&dirs).await, expected);
+        let report = DiskUsageReport {
+            usage: expected,
+            accounted_bytes: Some(16),
+            measured_at: 100,
+        };
+        assert_eq!(report.drift(), Some(-6));
+        let reports = DiskUsageReports::new();
+        assert!(reports.is_due("pypi", 60, 100));
+        reports.insert("pypi", report);
+        assert!(!reports.is_due("pypi", 60, 159));
+        assert!(reports.is_due("pypi", 60, 160));
+    }
+}
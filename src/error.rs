@@ -1,9 +1,11 @@
 use config::ConfigError;
+use mirror_cache::api::ApiError;
 use redis::RedisError;
 use rusoto_core::RusotoError;
-use rusoto_s3::{CreateBucketError, DeleteObjectError, GetObjectError};
+use rusoto_s3::{CreateBucketError, DeleteObjectError, GetObjectError, PutObjectError};
 use std::convert::From;
 use thiserror::Error;
+use warp::http::StatusCode;
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[allow(clippy::enum_variant_names)]
@@ -13,8 +15,8 @@ pub enum Error {
     RedisTypeError(redis::RedisError),
     #[error("error executing redis command: {0}")]
     RedisCMDError(redis::RedisError),
-    #[error("error creating Redis client: {0}")]
-    RedisClientError(redis::RedisError),
+    #[error("redis is unavailable: {0}")]
+    RedisUnavailable(redis::RedisError),
     #[error("sled error: {0}")]
     SledError(sled::Error),
     #[error("sled transaction error: {0}")]
@@ -23,16 +25,54 @@ pub enum Error {
     RequestError(reqwest::Error),
     #[error("upstream request is not successful: {0:?}")]
     UpstreamRequestError(reqwest::Response),
+    #[error("upstream request timed out: {0}")]
+    UpstreamTimeout(reqwest::Error),
+    #[error("upstream responded with status {0}")]
+    UpstreamStatus(u16),
+    #[error("invalid cache key: {0}")]
+    InvalidKey(String),
+    #[error("cache metadata is inconsistent: {0}")]
+    CacheMetadataInconsistent(String),
     #[error("{0}")]
     ConfigDeserializeError(config::ConfigError),
+    #[error("declared size is {0} bytes, but {1} bytes are received")]
+    SizeMismatch(u64, u64),
     #[error("invalid configuration: {0}")]
     ConfigInvalid(String),
     #[error("{0}")]
     IoError(std::io::Error),
     #[error("{0}")]
     OtherError(String),
+    #[error("too many in-flight upstream requests")]
+    Overloaded,
+    #[error("quota exceeded, retry after {0} secs")]
+    QuotaExceeded(u64),
+    #[error("missing or invalid admin token")]
+    Unauthorized,
+    #[error("audit log is disabled")]
+    AuditDisabled,
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("request body is larger than {0} bytes")]
+    BodyTooLarge(u64),
+    #[error("request path is longer than {0} bytes")]
+    UriTooLong(usize),
+    #[error("a request header is larger than {0} bytes")]
+    HeaderTooLarge(usize),
+    #[error("upstream is unavailable: {0}")]
+    UpstreamUnavailable(String),
+    #[error("upstream is rate limiting, retry after {0} secs")]
+    UpstreamThrottled(u64),
+    #[error("not cached, and the upstream is not contacted in offline mode")]
+    OfflineMiss(StatusCode),
+    #[error("the upstream does not serve the representation asked for")]
+    NotAcceptable,
     #[error("failed to get rusoto object: {0}")]
     RusotoGetObjectError(RusotoError<GetObjectError>),
+    #[error("failed to put rusoto object: {0}")]
+    RusotoPutObjectError(RusotoError<PutObjectError>),
     #[error("failed to delete rusoto object: {0}")]
     RusotoDeleteObjectError(RusotoError<DeleteObjectError>),
     #[error("faield to crate bucket: {0}")]
@@ -41,6 +81,88 @@ pub enum Error {
 
 impl warp::reject::Reject for Error {}
 
+impl Error {
+    /// The HTTP status code of a response to a request failed with this error
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::UpstreamRequestError(_)
+            | Error::UpstreamStatus(_)
+            | Error::UpstreamUnavailable(_)
+            | Error::RequestError(_) => StatusCode::BAD_GATEWAY,
+            Error::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::InvalidKey(_) | Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::AuditDisabled | Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Overloaded | Error::RedisUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::QuotaExceeded(_) | Error::UpstreamThrottled(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::OfflineMiss(status) => *status,
+            Error::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UriTooLong(_) => StatusCode::URI_TOO_LONG,
+            Error::HeaderTooLarge(_) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Error::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The body of a failed request: `{"error": "...", "detail": "..."}`.
+    /// Messages are fixed per variant, so that internal details like file
+    /// paths, redis urls and tokens are never exposed.
+    pub fn to_api_error(&self) -> ApiError {
+        let (error, detail) = match self {
+            Error::UpstreamRequestError(res) => (
+                "upstream request failed",
+                Some(format!(
+                    "upstream responded with status {}",
+                    res.status().as_u16()
+                )),
+            ),
+            Error::UpstreamStatus(status) => (
+                "upstream request failed",
+                Some(format!("upstream responded with status {}", status)),
+            ),
+            Error::UpstreamUnavailable(_) | Error::RequestError(_) => {
+                ("upstream request failed", None)
+            }
+            Error::UpstreamTimeout(_) => ("upstream request timed out", None),
+            Error::UpstreamThrottled(secs) => (
+                "upstream rate limited",
+                Some(format!("retry after {} secs", secs)),
+            ),
+            Error::InvalidKey(_) => ("invalid request path", None),
+            Error::Overloaded => ("too many in-flight upstream requests", None),
+            Error::QuotaExceeded(secs) => {
+                ("quota exceeded", Some(format!("retry after {} secs", secs)))
+            }
+            Error::OfflineMiss(_) => (
+                "not cached",
+                Some("the mirror is offline, only cached files are served".to_string()),
+            ),
+            Error::Unauthorized => ("missing or invalid admin token", None),
+            Error::AuditDisabled => ("audit log is disabled", None),
+            Error::NotFound(what) => ("not found", Some(what.clone())),
+            Error::BadRequest(reason) => ("bad request", Some(reason.clone())),
+            Error::BodyTooLarge(limit) => (
+                "request body too large",
+                Some(format!("at most {} bytes are passed through", limit)),
+            ),
+            Error::UriTooLong(limit) => (
+                "request path too long",
+                Some(format!("at most {} bytes are accepted", limit)),
+            ),
+            Error::HeaderTooLarge(limit) => (
+                "request header too large",
+                Some(format!("at most {} bytes per header are accepted", limit)),
+            ),
+            Error::RedisUnavailable(_) => ("cache metadata database is unavailable", None),
+            _ => ("internal error", None),
+        };
+        ApiError {
+            error: error.to_string(),
+            detail,
+        }
+    }
+}
+
 impl From<RedisError> for Error {
     fn from(e: RedisError) -> Error {
         Error::RedisTypeError(e)
@@ -77,6 +199,12 @@ impl From<RusotoError<GetObjectError>> for Error {
     }
 }
 
+impl From<RusotoError<PutObjectError>> for Error {
+    fn from(e: RusotoError<PutObjectError>) -> Error {
+        Error::RusotoPutObjectError(e)
+    }
+}
+
 impl From<RusotoError<DeleteObjectError>> for Error {
     fn from(e: RusotoError<DeleteObjectError>) -> Error {
         Error::RusotoDeleteObjectError(e)
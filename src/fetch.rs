@@ -0,0 +1,340 @@
+//! Fetching files into the caches on demand, see `mirror-cache fetch` and
+//! `POST /admin/fetch`. A file is named by its upstream url, matched against
+//! the rules like the path of the mirror serving it, or by a shorthand:
+//! `pypi:<project>` for the index of a PyPI project, `pypi:<project>==<version>`
+//! for its files of a version, and `anaconda:<channel>/<subdir>/<file>` for a
+//! file of a conda channel.
+
+use crate::error::{Error, Result};
+use crate::listener;
+use crate::listing::HREF;
+use crate::settings::Settings;
+use crate::util;
+use reqwest::Url;
+use std::str::FromStr;
+
+/// Channels of the `defaults` of conda, served by `repo.anaconda.com`. Others
+/// are served by `conda.anaconda.org`.
+const ANACONDA_DEFAULT_CHANNELS: &[&str] = &["main", "free", "r", "msys2"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchSpec {
+    /// An upstream url, e.g. `https://files.pythonhosted.org/packages/...`
+    Url(String),
+    /// The index of a PyPI project, and its files of `version` if set
+    Pypi {
+        project: String,
+        version: Option<String>,
+    },
+    /// A file of a conda channel, e.g. `main` and `linux-64/repodata.json`
+    Anaconda { channel: String, path: String },
+}
+
+impl FromStr for FetchSpec {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let invalid = || {
+            Error::BadRequest(format!(
+                "{} is neither a url, pypi:<project>[==<version>] nor \
+                 anaconda:<channel>/<subdir>/<file>",
+                spec
+            ))
+        };
+        if let Some(requirement) = spec.strip_prefix("pypi:") {
+            let (project, version) = match requirement.split_once("==") {
+                Some((project, version)) => (project.trim(), Some(version.trim())),
+                None => (requirement.trim(), None),
+            };
+            let valid = |s: &str| {
+                !s.is_empty()
+                    && s.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.!+".contains(c))
+            };
+            if !valid(project) || !version.map_or(true, valid) {
+                return Err(invalid());
+            }
+            return Ok(FetchSpec::Pypi {
+                project: util::pep503_normalize(project),
+                version: version.map(String::from),
+            });
+        }
+        if let Some(file) = spec.strip_prefix("anaconda:") {
+            let (channel, path) = file.split_once('/').ok_or_else(invalid)?;
+            let segments: Vec<&str> = path.split('/').collect();
+            let relative = |s: &&str| s.is_empty() || *s == "." || *s == "..";
+            if segments.len() < 2 || relative(&channel) || segments.iter().any(relative) {
+                return Err(invalid());
+            }
+            return Ok(FetchSpec::Anaconda {
+                channel: channel.to_string(),
+                path: path.to_string(),
+            });
+        }
+        match Url::parse(spec) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                Ok(FetchSpec::Url(spec.to_string()))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl FetchSpec {
+    /// The upstream url of a file of a conda channel on anaconda.org, for
+    /// channels not served by a `conda_channels` rule
+    pub fn anaconda_url(channel: &str, path: &str) -> String {
+        if ANACONDA_DEFAULT_CHANNELS.contains(&channel) {
+            format!("https://repo.anaconda.com/pkgs/{}/{}", channel, path)
+        } else {
+            format!("https://conda.anaconda.org/{}/{}", channel, path)
+        }
+    }
+}
+
+/// A file fetched for a `FetchRequest`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchedFile {
+    pub url: String,
+    /// Key of the file, `None` if no rule serves its url
+    pub key: Option<String>,
+    /// Size of the cached file, `None` if it is not cached
+    pub size: Option<u64>,
+    #[serde(flatten)]
+    pub outcome: FetchOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "outcome", content = "error")]
+pub enum FetchOutcome {
+    /// Cached before the request, left untouched
+    Cached,
+    Fetched,
+    Failed(String),
+}
+
+/// Body of `POST /admin/fetch`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchRequest {
+    pub spec: String,
+}
+
+/// Answer of `POST /admin/fetch`, the files of the spec in the order they
+/// are fetched
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchReport {
+    pub spec: String,
+    pub files: Vec<FetchedFile>,
+}
+
+impl FetchReport {
+    pub fn failed(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|file| matches!(file.outcome, FetchOutcome::Failed(_)))
+            .count()
+    }
+}
+
+/// Whether `filename` is a distribution of `version` of the PyPI project
+/// normalized as `project`, e.g. `numpy-1.26.4-cp312-cp312-win_amd64.whl` or
+/// `numpy-1.26.4.tar.gz`
+pub fn is_distribution_of(filename: &str, project: &str, version: &str) -> bool {
+    filename.match_indices('-').any(|(idx, _)| {
+        let tail = match filename[idx + 1..].strip_prefix(version) {
+            Some(tail) => tail,
+            None => return false,
+        };
+        (tail.starts_with('-') || tail.starts_with(".tar.") || tail == ".zip")
+            && util::pep503_normalize(&filename[..idx]) == project
+    })
+}
+
+/// The urls of the distributions of `version` of `project` linked by its
+/// index page served at `page`, without their fragments, in order of
+/// appearance
+pub fn distribution_links(content: &str, page: &Url, project: &str, version: &str) -> Vec<Url> {
+    let mut links: Vec<Url> = Vec::new();
+    let hrefs = HREF
+        .captures_iter(content)
+        .filter_map(|caps| caps.get(2).or_else(|| caps.get(3)));
+    for href in hrefs {
+        let mut url = match page.join(href.as_str()) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        url.set_fragment(None);
+        let filename = url.path_segments().and_then(|s| s.last()).unwrap_or("");
+        if is_distribution_of(filename, project, version) && !links.contains(&url) {
+            links.push(url);
+        }
+    }
+    links
+}
+
+/// Ask a running instance to fetch `spec`, over its unix socket if it listens
+/// on one, otherwise on its TCP port. Run by the `fetch` subcommand. The
+/// request is authenticated with `token`, or the first of `admin_tokens`.
+pub async fn fetch_on_instance(
+    settings: &Settings,
+    spec: &str,
+    token: Option<&str>,
+) -> Result<FetchReport> {
+    let token = match token {
+        Some(token) => token.to_string(),
+        None => settings
+            .admin_tokens
+            .iter()
+            .flatten()
+            .next()
+            .map(|admin| admin.token.expose().clone())
+            .ok_or_else(|| {
+                Error::OtherError("no admin token, set admin_tokens or --token".to_string())
+            })?,
+    };
+    let body = serde_json::to_vec(&FetchRequest {
+        spec: spec.to_string(),
+    })
+    .unwrap();
+    let authorization = format!("Bearer {}", token);
+    let (status, body) = match settings.unix_socket() {
+        Some(socket) => {
+            let headers = [
+                ("Authorization", authorization.as_str()),
+                ("Content-Type", "application/json"),
+            ];
+            let path = std::path::Path::new(&socket.path);
+            listener::request(path, "POST", "/admin/fetch", &headers, &body).await?
+        }
+        None => {
+            let url = format!("http://127.0.0.1:{}/admin/fetch", settings.port);
+            // the instance is local, whatever the proxy of upstreams
+            let client = reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .map_err(Error::RequestError)?;
+            let resp = client
+                .post(&url)
+                .header("Authorization", &authorization)
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+                .await
+                .map_err(Error::RequestError)?;
+            let status = resp.status().as_u16();
+            (
+                status,
+                resp.bytes().await.map_err(Error::RequestError)?.to_vec(),
+            )
+        }
+    };
+    if status != 200 {
+        return Err(Error::OtherError(format!(
+            "failed to fetch {}, answered {}: {}",
+            spec,
+            status,
+            String::from_utf8_lossy(&body)
+        )));
+    }
+    serde_json::from_slice(&body)
+        .map_err(|e| Error::OtherError(format!("invalid answer to fetch {}: {}", spec, e)))
+}
+
+/// A human readable report, one line per file
+pub fn format_report(report: &FetchReport) -> String {
+    let mut text = String::new();
+    for file in &report.files {
+        let size = file
+            .size
+            .map_or_else(|| "-".to_string(), |size| size.to_string());
+        let outcome = match &file.outcome {
+            FetchOutcome::Cached => "cached".to_string(),
+            FetchOutcome::Fetched => "fetched".to_string(),
+            FetchOutcome::Failed(e) => format!("failed: {}", e),
+        };
+        let name = file.key.as_deref().unwrap_or(&file.url);
+        text.push_str(&format!("{}  {} bytes  {}\n", name, size, outcome));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(
+            "pypi:NumPy==1.26.4".parse::<FetchSpec>().unwrap(),
+            FetchSpec::Pypi {
+                project: "numpy".to_string(),
+                version: Some("1.26.4".to_string()),
+            }
+        );
+        assert_eq!(
+            "pypi:typing_extensions".parse::<FetchSpec>().unwrap(),
+            FetchSpec::Pypi {
+                project: "typing-extensions".to_string(),
+                version: None,
+            }
+        );
+        assert_eq!(
+            "anaconda:conda-forge/noarch/tqdm-4.66.1-pyhd8ed1ab_0.conda"
+                .parse::<FetchSpec>()
+                .unwrap(),
+            FetchSpec::Anaconda {
+                channel: "conda-forge".to_string(),
+                path: "noarch/tqdm-4.66.1-pyhd8ed1ab_0.conda".to_string(),
+            }
+        );
+        let url = "https://files.pythonhosted.org/packages/ab/cd/flask-2.0.whl";
+        assert_eq!(
+            url.parse::<FetchSpec>().unwrap(),
+            FetchSpec::Url(url.to_string())
+        );
+        for invalid in &[
+            "pypi:",
+            "pypi:numpy==",
+            "pypi:../numpy",
+            "anaconda:main",
+            "anaconda:main/../x.conda",
+            "ftp://mirror.corp/a.tgz",
+            "numpy",
+        ] {
+            assert!(invalid.parse::<FetchSpec>().is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            FetchSpec::anaconda_url("main", "linux-64/repodata.json"),
+            "https://repo.anaconda.com/pkgs/main/linux-64/repodata.json"
+        );
+        assert_eq!(
+            FetchSpec::anaconda_url("conda-forge", "noarch/repodata.json"),
+            "https://conda.anaconda.org/conda-forge/noarch/repodata.json"
+        );
+    }
+
+    #[test]
+    fn distributions_of_a_version() {
+        let page = Url::parse("https://pypi.org/simple/typing-extensions/").unwrap();
+        let content = "\
+            <a href=\"https://files.corp/a/typing_extensions-4.8.0-py3-none-any.whl#x\">w</a>\n\
+            <a href=\"../../packages/b/typing_extensions-4.8.0.tar.gz\">s</a>\n\
+            <a href=\"../../packages/c/typing_extensions-4.8.0rc1.tar.gz\">rc</a>\n\
+            <a href=\"../../packages/d/typing_extensions-4.8.01-py3-none-any.whl\">x</a>\n\
+            <a href=\"../../packages/e/typing_extensions_more-4.8.0.tar.gz\">other</a>\n";
+        let links: Vec<String> = distribution_links(content, &page, "typing-extensions", "4.8.0")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                "https://files.corp/a/typing_extensions-4.8.0-py3-none-any.whl",
+                "https://pypi.org/packages/b/typing_extensions-4.8.0.tar.gz",
+            ]
+        );
+        assert!(is_distribution_of("numpy-1.26.4.zip", "numpy", "1.26.4"));
+        assert!(!is_distribution_of("numpy-1.26.4.exe", "numpy", "1.26.4"));
+    }
+}
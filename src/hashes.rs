@@ -0,0 +1,103 @@
+//! Hash fragments of the links of PyPI simple index pages. pip checks a file
+//! against the `#sha256=<hash>` fragment of its link, which internal indexes
+//! often leave out; the hashes of the files already cached are appended to
+//! such links.
+
+use crate::listing::HREF;
+use regex::Captures;
+use reqwest::Url;
+use std::collections::HashMap;
+
+/// The urls of the links of a page served at `page` lacking a fragment,
+/// resolved against the page, in order of appearance
+pub fn unhashed_links(content: &str, page: &Url) -> Vec<Url> {
+    HREF.captures_iter(content)
+        .filter_map(|caps| caps.get(2).or_else(|| caps.get(3)))
+        .filter_map(|href| link_target(href.as_str(), page))
+        .collect()
+}
+
+/// Append `#sha256=<hash>` to the links of a page served at `page` lacking a
+/// fragment, `hashes` maps the resolved urls of the links to the hex encoded
+/// SHA-256 of their files. Other links are kept as is.
+pub fn append_hashes(content: &str, page: &Url, hashes: &HashMap<String, String>) -> String {
+    HREF.replace_all(content, |caps: &Captures| {
+        let (href, quote) = match (caps.get(2), caps.get(3)) {
+            (Some(href), _) => (href.as_str(), '"'),
+            (None, Some(href)) => (href.as_str(), '\''),
+            (None, None) => return caps[0].to_string(),
+        };
+        match link_target(href, page).and_then(|target| hashes.get(target.as_str())) {
+            Some(hash) => format!("{}{}{}#sha256={}{}", &caps[1], quote, href, hash, quote),
+            None => caps[0].to_string(),
+        }
+    })
+    .into_owned()
+}
+
+/// The url `href` links to, if it has no fragment and is a http(s) url
+fn link_target(href: &str, page: &Url) -> Option<Url> {
+    if href.is_empty() || href.contains('#') {
+        return None;
+    }
+    let target = page.join(href).ok()?;
+    match target.scheme() {
+        "http" | "https" => Some(target),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    const INDEX: &str = "<!DOCTYPE html>\n<html>\n<body>\n\
+        <h1>Links for tool</h1>\n\
+        <a href=\"../../packages/tool-1.0.tar.gz\">tool-1.0.tar.gz</a><br/>\n\
+        <a href='https://files.corp/packages/tool-1.1.tar.gz'>tool-1.1.tar.gz</a><br/>\n\
+        <a href=\"../../packages/tool-1.2.tar.gz#sha256=abc\">tool-1.2.tar.gz</a><br/>\n\
+        <a href=\"mailto:pypi@corp\">contact</a>\n\
+        </body>\n</html>\n";
+
+    fn page() -> Url {
+        Url::parse("http://pypi.corp/simple/tool/").unwrap()
+    }
+
+    #[test]
+    fn unhashed_links_test() {
+        let links: Vec<String> = unhashed_links(INDEX, &page())
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                "http://pypi.corp/packages/tool-1.0.tar.gz",
+                "https://files.corp/packages/tool-1.1.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn append_hashes_test() {
+        let hashes: HashMap<String, String> = [
+            "http://pypi.corp/packages/tool-1.0.tar.gz",
+            "http://pypi.corp/packages/tool-1.2.tar.gz",
+        ]
+        .iter()
+        .map(|url| (url.to_string(), HASH.to_string()))
+        .collect();
+        let content = append_hashes(INDEX, &page(), &hashes);
+        assert!(content.contains(&format!(
+            "<a href=\"../../packages/tool-1.0.tar.gz#sha256={}\">",
+            HASH
+        )));
+        // links of uncached files, links with a fragment and other links are kept
+        assert!(content.contains("<a href='https://files.corp/packages/tool-1.1.tar.gz'>"));
+        assert!(content.contains("<a href=\"../../packages/tool-1.2.tar.gz#sha256=abc\">"));
+        assert!(content.contains("<a href=\"mailto:pypi@corp\">"));
+        assert_eq!(append_hashes(INDEX, &page(), &HashMap::new()), INDEX);
+    }
+}
@@ -0,0 +1,341 @@
+//! Hot keys of an LRU cache, whose content is kept in memory, see
+//! `Policy::hot_keys`. A few keys, e.g. the index pages of popular packages,
+//! take a large share of the hits, and each hit of an LRU cache costs a
+//! lookup of the metadata of its entry and a read of its file.
+//!
+//! Hits are counted by space-saving sketches of `SKETCH_FACTOR` times `top`
+//! counters, one per half of the window: the hits of a key are those of the
+//! current half and of the previous one, so that a key cooling down leaves
+//! the hot set within a window. The content of a hot key of at most
+//! `max_entry_size` bytes is kept in memory for at most a window, and served
+//! without looking up its entry. Its atime is updated in a batch at most once
+//! per `TOUCH_INTERVAL` instead.
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counters of a sketch per hot key
+const SKETCH_FACTOR: usize = 4;
+
+/// Fewest hits in a window for a key to be hot, so that a quiet cache does
+/// not keep the keys it happens to serve in memory
+pub const MIN_HOT_HITS: u64 = 3;
+
+/// How often the atimes of the entries of hot keys served from memory are
+/// updated
+pub const TOUCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A hot key, as listed by `GET /admin/cache/<policy>/hot-keys`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HotKey {
+    pub key: String,
+    /// Hits in the last window to a half more, overestimated by at most the
+    /// hits of the keys whose counter it took
+    pub hits: u64,
+    /// Bytes of its content kept in memory, `None` if it is not
+    pub cached_bytes: Option<u64>,
+}
+
+/// Space-saving sketch of at most `capacity` counters. A key without a
+/// counter takes the one of the least counted key, with its count plus one,
+/// so that the counts of frequent keys are never underestimated.
+struct SpaceSaving {
+    capacity: usize,
+    counts: HashMap<String, u64>,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn record(&mut self, key: &str) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        let mut count = 1;
+        if self.counts.len() >= self.capacity {
+            // the sketch is small, a scan is cheaper than keeping it ordered
+            let least = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count));
+            if let Some((least, least_count)) = least {
+                self.counts.remove(&least);
+                count += least_count;
+            }
+        }
+        self.counts.insert(key.to_string(), count);
+    }
+
+    fn count(&self, key: &str) -> u64 {
+        self.counts.get(key).copied().unwrap_or(0)
+    }
+}
+
+struct State {
+    current: SpaceSaving,
+    previous: SpaceSaving,
+    current_started: Instant,
+    /// The hot keys and their hits
+    hot: HashMap<String, u64>,
+    /// Content of hot keys, and when it was read
+    entries: HashMap<String, (Instant, Bytes)>,
+    /// Incremented by invalidations, so that content read before one is not
+    /// kept after it
+    generation: u64,
+    /// Hot keys served from memory since their atimes were last updated
+    touched: HashSet<String>,
+    last_touch: Option<Instant>,
+}
+
+impl State {
+    fn hits(&self, key: &str) -> u64 {
+        self.current.count(key) + self.previous.count(key)
+    }
+
+    /// Start a new half of the window, and pick the hot keys again
+    fn rotate(&mut self, top: usize) {
+        let capacity = self.current.capacity;
+        self.previous = std::mem::replace(&mut self.current, SpaceSaving::new(capacity));
+        self.current_started = Instant::now();
+        let mut hits: Vec<(String, u64)> = self
+            .previous
+            .counts
+            .keys()
+            .map(|key| (key.clone(), self.hits(key)))
+            .filter(|(_, hits)| *hits >= MIN_HOT_HITS)
+            .collect();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hits.truncate(top);
+        self.hot = hits.into_iter().collect();
+        let hot = &self.hot;
+        self.entries.retain(|key, _| hot.contains_key(key));
+    }
+}
+
+/// Hot keys of a cache, shared by its shards
+pub struct HotKeys {
+    top: usize,
+    window: Duration,
+    max_entry_size: u64,
+    state: Mutex<State>,
+}
+
+impl HotKeys {
+    pub fn new(top: usize, window: Duration, max_entry_size: u64) -> Self {
+        let capacity = top * SKETCH_FACTOR;
+        Self {
+            top,
+            window,
+            max_entry_size,
+            state: Mutex::new(State {
+                current: SpaceSaving::new(capacity),
+                previous: SpaceSaving::new(capacity),
+                current_started: Instant::now(),
+                hot: HashMap::new(),
+                entries: HashMap::new(),
+                generation: 0,
+                touched: HashSet::new(),
+                last_touch: None,
+            }),
+        }
+    }
+
+    /// Count a hit of `key`. It becomes hot if it has more hits than the
+    /// least hot key, or if there are fewer than `top` of them.
+    pub fn record(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.current_started.elapsed() >= self.window / 2 {
+            state.rotate(self.top);
+        }
+        state.current.record(key);
+        let hits = state.hits(key);
+        if let Some(hot_hits) = state.hot.get_mut(key) {
+            *hot_hits = hits;
+            return;
+        }
+        if hits < MIN_HOT_HITS {
+            return;
+        }
+        if state.hot.len() >= self.top {
+            let least = state
+                .hot
+                .iter()
+                .min_by_key(|(_, hits)| **hits)
+                .map(|(key, hits)| (key.clone(), *hits));
+            match least {
+                Some((least, least_hits)) if least_hits < hits => {
+                    state.hot.remove(&least);
+                    state.entries.remove(&least);
+                }
+                _ => return,
+            }
+        }
+        state.hot.insert(key.to_string(), hits);
+    }
+
+    /// The content of `key` if it is hot and in memory. Its atime is to be
+    /// updated by the next `take_touched`.
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let mut state = self.state.lock().unwrap();
+        let bytes = match state.entries.get(key) {
+            Some((read_at, bytes)) if read_at.elapsed() < self.window => bytes.clone(),
+            Some(_) => {
+                state.entries.remove(key);
+                return None;
+            }
+            None => return None,
+        };
+        state.touched.insert(key.to_string());
+        Some(bytes)
+    }
+
+    /// Whether the content of `key` is to be kept in memory, if it is at most
+    /// `max_entry_size` bytes. If so, the generation to pass to `keep`, taken
+    /// before its content is read.
+    pub fn wants(&self, key: &str) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        let wanted = state.hot.contains_key(key) && !state.entries.contains_key(key);
+        Some(state.generation).filter(|_| wanted)
+    }
+
+    pub fn max_entry_size(&self) -> u64 {
+        self.max_entry_size
+    }
+
+    /// Keep the content of `key` in memory, unless an invalidation happened
+    /// since `generation`, the key is no longer hot or the content is larger
+    /// than `max_entry_size`
+    pub fn keep(&self, key: &str, bytes: Bytes, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        let fits = bytes.len() as u64 <= self.max_entry_size;
+        if fits && state.generation == generation && state.hot.contains_key(key) {
+            state
+                .entries
+                .insert(key.to_string(), (Instant::now(), bytes));
+        }
+    }
+
+    /// Drop the content of `key`, e.g. when its entry is put or removed
+    pub fn invalidate(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.entries.remove(key);
+    }
+
+    /// Keys served from memory whose atimes are due to be updated, at most
+    /// once per `TOUCH_INTERVAL`
+    pub fn take_touched(&self) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let recently = |last_touch: Instant| last_touch.elapsed() < TOUCH_INTERVAL;
+        if state.touched.is_empty() || state.last_touch.map_or(false, recently) {
+            return vec![];
+        }
+        state.last_touch = Some(Instant::now());
+        state.touched.drain().collect()
+    }
+
+    /// The hot keys, the hottest first
+    pub fn report(&self) -> Vec<HotKey> {
+        let state = self.state.lock().unwrap();
+        let mut report: Vec<HotKey> = state
+            .hot
+            .iter()
+            .map(|(key, hits)| HotKey {
+                key: key.clone(),
+                hits: *hits,
+                cached_bytes: state.entries.get(key).map(|(_, bytes)| bytes.len() as u64),
+            })
+            .collect();
+        report.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.key.cmp(&b.key)));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(hot_keys: &HotKeys, key: &str, times: usize) {
+        for _ in 0..times {
+            hot_keys.record(key);
+        }
+    }
+
+    fn hot(hot_keys: &HotKeys) -> Vec<String> {
+        hot_keys.report().into_iter().map(|hot| hot.key).collect()
+    }
+
+    #[test]
+    fn space_saving_overestimates() {
+        let mut sketch = SpaceSaving::new(2);
+        for key in &["a", "a", "a", "b", "c"] {
+            sketch.record(key);
+        }
+        assert_eq!(sketch.count("a"), 3);
+        // `c` took the counter of `b`
+        assert_eq!(sketch.count("b"), 0);
+        assert_eq!(sketch.count("c"), 2);
+    }
+
+    #[test]
+    fn hottest_keys() {
+        let hot_keys = HotKeys::new(2, Duration::from_secs(60), 4);
+        hit(&hot_keys, "numpy", 10);
+        hit(&hot_keys, "boto3", 5);
+        hit(&hot_keys, "rare", MIN_HOT_HITS as usize - 1);
+        assert_eq!(hot(&hot_keys), vec!["numpy", "boto3"]);
+        // a hotter key takes the place of the least hot one
+        hit(&hot_keys, "flask", 6);
+        assert_eq!(hot(&hot_keys), vec!["numpy", "flask"]);
+        assert_eq!(hot_keys.report()[0].hits, 10);
+    }
+
+    #[test]
+    fn content_of_hot_keys() {
+        let hot_keys = HotKeys::new(2, Duration::from_secs(60), 4);
+        hit(&hot_keys, "numpy", 3);
+        assert_eq!(hot_keys.wants("cold"), None);
+        let generation = hot_keys.wants("numpy").unwrap();
+        hot_keys.keep("numpy", Bytes::from("index"), generation);
+        assert_eq!(hot_keys.get("numpy"), None);
+        hot_keys.keep("numpy", Bytes::from("idx"), generation);
+        assert_eq!(hot_keys.get("numpy"), Some(Bytes::from("idx")));
+        assert_eq!(hot_keys.report()[0].cached_bytes, Some(3));
+        // served from memory, its atime is updated once
+        assert_eq!(hot_keys.take_touched(), vec!["numpy"]);
+        assert!(hot_keys.take_touched().is_empty());
+        hot_keys.invalidate("numpy");
+        assert_eq!(hot_keys.get("numpy"), None);
+        // content read before an invalidation is not kept
+        let generation = hot_keys.wants("numpy").unwrap();
+        hot_keys.invalidate("numpy");
+        hot_keys.keep("numpy", Bytes::from("old"), generation);
+        assert_eq!(hot_keys.get("numpy"), None);
+    }
+
+    #[test]
+    fn hot_keys_cool_down() {
+        let hot_keys = HotKeys::new(2, Duration::from_millis(100), 4);
+        hit(&hot_keys, "numpy", 3);
+        let generation = hot_keys.wants("numpy").unwrap();
+        hot_keys.keep("numpy", Bytes::from("i"), generation);
+        // still hot in the next half of the window
+        std::thread::sleep(Duration::from_millis(60));
+        hit(&hot_keys, "boto3", 1);
+        assert_eq!(hot(&hot_keys), vec!["numpy"]);
+        // the content expires after a window
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(hot_keys.get("numpy"), None);
+        hit(&hot_keys, "boto3", 1);
+        assert!(hot(&hot_keys).is_empty());
+    }
+}
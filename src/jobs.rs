@@ -0,0 +1,149 @@
+use crate::error::Error;
+use crate::error::Result;
+use crate::util;
+pub use mirror_cache::api::{JobId, JobState, JobStatus};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Number of finished jobs kept to be queried
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// Admin jobs running in the background, and the most recently finished ones.
+pub struct JobRegistry {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<JobId, JobStatus>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register a running job, returns its id.
+    pub fn start(&self, principal: &str, operation: &str, targets: Vec<String>) -> JobId {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let status = JobStatus {
+            id,
+            principal: principal.to_string(),
+            operation: operation.to_string(),
+            targets,
+            started_at: util::now(),
+            finished_at: None,
+            state: JobState::Running,
+            scanned: 0,
+            removed: 0,
+            queued: 0,
+        };
+        self.jobs.lock().unwrap().insert(id, status);
+        id
+    }
+
+    /// Add the progress of a batch
+    pub fn report(&self, id: JobId, scanned: u64, removed: u64) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.scanned += scanned;
+            job.removed += removed;
+        }
+    }
+
+    /// Add files queued to be fetched, e.g. by a warmup
+    pub fn report_queued(&self, id: JobId, queued: u64) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.queued += queued;
+        }
+    }
+
+    /// Mark a job as finished, and forget the oldest finished jobs.
+    /// Returns the final status of the job.
+    pub fn finish(&self, id: JobId, result: &Result<()>) -> Option<JobStatus> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(&id)?;
+        job.finished_at = Some(util::now());
+        job.state = match result {
+            Ok(_) => JobState::Completed,
+            Err(e) => JobState::Failed(e.to_string()),
+        };
+        let status = job.clone();
+        let mut finished: Vec<JobId> = jobs
+            .values()
+            .filter(|job| job.state != JobState::Running)
+            .map(|job| job.id)
+            .collect();
+        if finished.len() > MAX_FINISHED_JOBS {
+            finished.sort_unstable();
+            for id in &finished[..finished.len() - MAX_FINISHED_JOBS] {
+                jobs.remove(id);
+            }
+        }
+        Some(status)
+    }
+
+    /// Running and recently finished jobs, by id
+    pub fn list(&self) -> Vec<JobStatus> {
+        let mut jobs: Vec<JobStatus> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_unstable_by_key(|job| job.id);
+        jobs
+    }
+
+    pub fn get(&self, id: JobId) -> Result<JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("job {}", id)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn job_progress_and_state() {
+        let registry = JobRegistry::new();
+        let id = registry.start("ops", "purge", vec!["flask-*".to_string()]);
+        registry.report(id, 100, 3);
+        registry.report(id, 20, 1);
+        registry.report_queued(id, 2);
+        let job = registry.get(id).unwrap();
+        assert_eq!((job.scanned, job.removed, job.queued), (120, 4, 2));
+        assert_eq!(job.state, JobState::Running);
+        let json = serde_json::to_value(&job).unwrap();
+        assert_eq!(json["state"], "running");
+        assert!(json["finished_at"].is_null());
+
+        let job = registry
+            .finish(id, &Err(Error::OtherError("redis is gone".to_string())))
+            .unwrap();
+        assert!(job.finished_at.is_some());
+        let json = serde_json::to_value(&job).unwrap();
+        assert_eq!(json["state"], "failed");
+        assert_eq!(json["error"], "redis is gone");
+        assert!(registry.get(id + 1).is_err());
+    }
+
+    #[test]
+    fn forget_oldest_finished_jobs() {
+        let registry = JobRegistry::new();
+        let running = registry.start("ops", "purge", vec![]);
+        let ids: Vec<JobId> = (0..MAX_FINISHED_JOBS + 2)
+            .map(|_| registry.start("ops", "purge", vec![]))
+            .collect();
+        for id in &ids {
+            registry.finish(*id, &Ok(()));
+        }
+        assert!(registry.get(running).is_ok());
+        assert!(registry.get(ids[0]).is_err());
+        assert!(registry.get(ids[1]).is_err());
+        assert_eq!(registry.get(ids[2]).unwrap().state, JobState::Completed);
+        let jobs = registry.list();
+        assert_eq!(jobs.len(), MAX_FINISHED_JOBS + 1);
+        assert_eq!((jobs[0].id, jobs[1].id), (running, ids[2]));
+    }
+}
@@ -0,0 +1,242 @@
+//! Keys of cache entries in redis. A key is made of the id of its cache, a
+//! separator and the key of the entry. LRU entries and expiring TTL entries
+//! use different separators, and their keys are different types so that a key
+//! of one kind is never parsed as the other.
+
+use crate::error::{Error, Result};
+
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Characters separating ids from keys in redis keys and in the channels of
+/// keyspace notifications
+const RESERVED_ID_CHARS: &[char] = &['/', ':'];
+
+/// Prefix of the keys of TTL metadata, see `RedisMetadataDb::get_ttl_meta_key`
+const TTL_META_ID: &str = "ttl_meta";
+
+/// Prefix of the keys of the usage of quotas, see `QuotaTracker`
+pub const QUOTA_ID: &str = "quota";
+
+/// Key of the set of the cache bypass switches kept in redis, see
+/// `BypassSwitch`
+pub const BYPASS_ID: &str = "bypass";
+
+/// Prefix of the keys of the manifests of snapshots, see `SnapshotStore`.
+/// Also the directory of their pages in the `snapshot_storage`.
+pub const SNAPSHOT_ID: &str = "snapshot";
+
+/// Prefix of the keys telling that caches are in use by an instance, see
+/// `migrate::write_heartbeats`
+pub const HEARTBEAT_ID: &str = "heartbeat";
+
+/// Prefix of the keys of the progress of renames of cache ids, see
+/// `migrate::migrate_cache_id`
+pub const MIGRATION_ID: &str = "migration";
+
+/// Ids of redis keys that are not caches
+const RESERVED_IDS: &[&str] = &[
+    TTL_META_ID,
+    QUOTA_ID,
+    BYPASS_ID,
+    SNAPSHOT_ID,
+    HEARTBEAT_ID,
+    MIGRATION_ID,
+];
+
+/// Identifier of a cache, e.g. the name of its policy.
+///
+/// `_` is allowed for compatibility with existing keys, although it separates
+/// ids and keys of LRU entries: those are only listed through the sorted set
+/// of their cache, never by prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheId(String);
+
+impl CacheId {
+    pub fn new(id: &str) -> Result<Self> {
+        if id.is_empty() || id.contains(RESERVED_ID_CHARS) || RESERVED_IDS.contains(&id) {
+            return Err(Error::ConfigInvalid(format!(
+                "invalid cache id {:?}: it must not be empty, be in {:?}, or contain any of {:?}",
+                id, RESERVED_IDS, RESERVED_ID_CHARS
+            )));
+        }
+        Ok(Self(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CacheId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Key of an entry of a cache, without the cache id
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl From<&str> for CacheKey {
+    fn from(key: &str) -> Self {
+        Self(key.to_string())
+    }
+}
+
+/// A kind of keys in redis, separated from cache ids by `SEPARATOR`
+pub trait KeyDomain {
+    const SEPARATOR: char;
+}
+
+/// Hashes of LRU entries, `<id>_<key>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lru {}
+
+impl KeyDomain for Lru {
+    const SEPARATOR: char = '_';
+}
+
+/// Expiring keys of TTL entries, `<id>/<key>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ttl {}
+
+impl KeyDomain for Ttl {
+    const SEPARATOR: char = '/';
+}
+
+/// A redis key of a cache entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixedKey<D> {
+    key: String,
+    domain: PhantomData<D>,
+}
+
+impl<D: KeyDomain> PrefixedKey<D> {
+    pub fn new(id: &CacheId, key: &CacheKey) -> Self {
+        Self::from_redis(format!("{}{}", Self::prefix(id), key.as_str()))
+    }
+
+    /// A key read from redis, e.g. a member of a sorted set. It may not be a
+    /// key of the expected cache, see `try_strip_prefix`.
+    pub fn from_redis(key: String) -> Self {
+        Self {
+            key,
+            domain: PhantomData,
+        }
+    }
+
+    /// The prefix of all keys of the cache
+    pub fn prefix(id: &CacheId) -> String {
+        format!("{}{}", id, D::SEPARATOR)
+    }
+
+    /// The key of the entry, if this is a key of the cache `id`
+    pub fn try_strip_prefix(&self, id: &CacheId) -> Result<CacheKey> {
+        self.key
+            .strip_prefix(&Self::prefix(id))
+            .map(CacheKey::from)
+            .ok_or_else(|| Error::InvalidKey(format!("{} is not a key of cache {}", self.key, id)))
+    }
+
+    pub fn into_string(self) -> String {
+        self.key
+    }
+}
+
+/// The key of the channel of a keyspace notification, e.g. `policy/a.whl` of
+/// `__keyspace@0__:policy/a.whl`. Keys may contain `:` as well.
+pub fn keyspace_channel_key(channel: &str) -> Result<&str> {
+    channel
+        .strip_prefix("__keyspace@")
+        .and_then(|channel| channel.split_once("__:"))
+        .map(|(_db, key)| key)
+        .ok_or_else(|| Error::InvalidKey(format!("not a keyspace channel: {}", channel)))
+}
+
+/// The pattern of the channels of keyspace notifications of keys starting
+/// with `prefix`, with glob characters of the prefix escaped
+pub fn keyspace_pattern(prefix: &str) -> String {
+    let mut pattern = String::from("__keyspace@*__:");
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_cache_ids() {
+        assert!(CacheId::new("policy_lru").is_ok());
+        for id in &["", "a/b", "a:b", "ttl_meta", "quota", "bypass", "snapshot"] {
+            assert!(CacheId::new(id).is_err(), "{}", id);
+        }
+        assert!(CacheId::new(HEARTBEAT_ID).is_err());
+        assert!(CacheId::new(MIGRATION_ID).is_err());
+    }
+
+    #[test]
+    fn strip_prefix_of_keys() {
+        let id = CacheId::new("policy_ttl").unwrap();
+        let key = CacheKey::from("pypi/simple/flask:1.0/");
+        let ttl_key = PrefixedKey::<Ttl>::new(&id, &key);
+        assert_eq!(ttl_key.try_strip_prefix(&id).unwrap(), key);
+        assert_eq!(ttl_key.into_string(), "policy_ttl/pypi/simple/flask:1.0/");
+        let lru_key = PrefixedKey::<Lru>::new(&id, &key);
+        assert_eq!(lru_key.try_strip_prefix(&id).unwrap(), key);
+        let lru_key = lru_key.into_string();
+        assert_eq!(lru_key, "policy_ttl_pypi/simple/flask:1.0/");
+
+        let key = CacheKey::from("中文/🦀 1%.whl");
+        let ttl_key = PrefixedKey::<Ttl>::new(&id, &key);
+        assert_eq!(ttl_key.try_strip_prefix(&id).unwrap(), key);
+
+        // keys of another cache, and of another kind
+        let other = CacheId::new("policy_ttl2").unwrap();
+        assert!(PrefixedKey::<Ttl>::new(&other, &key)
+            .try_strip_prefix(&id)
+            .is_err());
+        assert!(PrefixedKey::<Ttl>::from_redis(lru_key)
+            .try_strip_prefix(&id)
+            .is_err());
+        assert!(PrefixedKey::<Lru>::from_redis("p".to_string())
+            .try_strip_prefix(&id)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_keyspace_channels() {
+        assert_eq!(
+            keyspace_channel_key("__keyspace@0__:policy/a:b.whl").unwrap(),
+            "policy/a:b.whl"
+        );
+        assert_eq!(
+            keyspace_channel_key("__keyspace@12__:policy/a").unwrap(),
+            "policy/a"
+        );
+        assert_eq!(
+            keyspace_channel_key("__keyspace@0__:policy/中文/🦀 1%.whl").unwrap(),
+            "policy/中文/🦀 1%.whl"
+        );
+        assert!(keyspace_channel_key("__keyevent@0__:expired").is_err());
+        assert!(keyspace_channel_key("policy/a").is_err());
+        assert_eq!(keyspace_pattern("p[1]/"), r"__keyspace@*__:p\[1\]/*");
+    }
+}
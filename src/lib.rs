@@ -0,0 +1,7 @@
+//! Types of the management API of mirror-cache, for clients written in Rust.
+//! The server itself is the `mirror-cache` binary.
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod api;
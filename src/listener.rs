@@ -0,0 +1,204 @@
+//! Serving requests on a unix domain socket, e.g. for a front proxy on the
+//! same host. Warp serves the accepted connections like those of its TCP
+//! listener.
+
+use crate::error::{Error, Result};
+use crate::settings::{parse_mode, UnixSocket};
+
+use futures::Stream;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Wait after a failed accept, e.g. when the process is out of file
+/// descriptors, instead of spinning
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Bind the socket of `config`, replacing a stale socket file, and set its
+/// mode and ownership
+pub fn bind(config: &UnixSocket) -> Result<UnixListener> {
+    let path = Path::new(&config.path);
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = &config.mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(parse_mode(mode)?))?;
+    }
+    if config.uid.is_some() || config.gid.is_some() {
+        std::os::unix::fs::chown(path, config.uid, config.gid)?;
+    }
+    info!("listening on unix socket {}", path.display());
+    Ok(listener)
+}
+
+/// Remove a socket file no process listens on, e.g. left by a crash. Other
+/// files, and sockets in use, are kept and fail the bind.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::OtherError(format!(
+            "{} exists and is not a socket",
+            path.display()
+        )));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(Error::OtherError(format!(
+            "{} is in use by another process",
+            path.display()
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            info!("removing stale socket {}", path.display());
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remove the socket file on shutdown
+pub fn remove(config: &UnixSocket) {
+    if let Err(e) = fs::remove_file(&config.path) {
+        warn!("failed to remove unix socket {}: {}", config.path, e);
+    }
+}
+
+/// Connections accepted by `listener`. Failed accepts are logged and
+/// retried, since an error would stop the server.
+pub fn incoming(
+    listener: UnixListener,
+) -> impl Stream<Item = io::Result<UnixStream>> + Send + Unpin {
+    Box::pin(futures::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => {
+                    warn!("failed to accept a connection on the unix socket: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                }
+            }
+        }
+    }))
+}
+
+/// `GET` `uri` of the server listening on the socket at `path`, e.g. to
+/// probe its readiness. Returns the status and the body.
+pub async fn get(path: &Path, uri: &str) -> Result<(u16, Vec<u8>)> {
+    request(path, "GET", uri, &[], &[]).await
+}
+
+/// Send a request to the server listening on the socket at `path`. Returns
+/// the status and the body. HTTP/1.0 is spoken, so that the body is neither
+/// chunked nor followed by another response.
+pub async fn request(
+    path: &Path,
+    method: &str,
+    uri: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(u16, Vec<u8>)> {
+    let mut stream = UnixStream::connect(path).await?;
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: localhost\r\n", method, uri);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let invalid = || Error::OtherError(format!("invalid response from {}", path.display()));
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let status = std::str::from_utf8(&response[..header_end])
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, response[header_end + 4..].to_vec()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+    use warp::Filter;
+
+    fn socket(dir: &TempDir, mode: Option<&str>) -> UnixSocket {
+        UnixSocket {
+            path: dir.path().join("mirror-cache.sock").display().to_string(),
+            mode: mode.map(String::from),
+            uid: None,
+            gid: None,
+        }
+    }
+
+    /// Serve `hello` on the socket until the returned sender is dropped
+    fn serve(listener: UnixListener) -> tokio::sync::oneshot::Sender<()> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let routes = warp::path!("hello").map(|| "hello over a unix socket");
+        let server = warp::serve(routes).serve_incoming_with_graceful_shutdown(
+            incoming(listener),
+            async move {
+                let _ = rx.await;
+            },
+        );
+        tokio::spawn(server);
+        tx
+    }
+
+    #[tokio::test]
+    async fn serve_on_unix_socket() {
+        let dir = TempDir::new("unix_socket");
+        let config = socket(&dir, Some("0660"));
+        let listener = bind(&config).unwrap();
+        let _server = serve(listener);
+        let path = Path::new(&config.path);
+        let (status, body) = get(path, "/hello").await.unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"hello over a unix socket");
+        let (status, _) = get(path, "/missing").await.unwrap();
+        assert_eq!(status, 404);
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o660);
+    }
+
+    #[tokio::test]
+    async fn replace_stale_socket() {
+        let dir = TempDir::new("unix_socket_stale");
+        let config = socket(&dir, None);
+        // a socket file left by a process that is gone
+        drop(std::os::unix::net::UnixListener::bind(&config.path).unwrap());
+        assert!(Path::new(&config.path).exists());
+        let listener = bind(&config).unwrap();
+        // a socket in use is kept
+        assert!(bind(&config).is_err());
+        let _server = serve(listener);
+        let (status, _) = get(Path::new(&config.path), "/hello").await.unwrap();
+        assert_eq!(status, 200);
+        remove(&config);
+        assert!(!Path::new(&config.path).exists());
+    }
+
+    #[tokio::test]
+    async fn keep_other_files() {
+        let dir = TempDir::new("unix_socket_file");
+        let config = socket(&dir, None);
+        fs::write(&config.path, "not a socket").unwrap();
+        assert!(bind(&config).is_err());
+        assert_eq!(fs::read(&config.path).unwrap(), b"not a socket");
+    }
+}
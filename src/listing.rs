@@ -0,0 +1,235 @@
+//! Directory listings of plain autoindex upstreams, e.g. nginx `autoindex` or
+//! Apache `mod_autoindex`. Links of a listing are made relative to the page,
+//! so that users browse the mirror and download cached files wherever the
+//! mirror serves the rule.
+
+use regex::{Captures, Regex};
+use reqwest::Url;
+
+/// Bytes at the start of a page searched for the title of a listing
+const HEAD_LEN: usize = 4096;
+
+lazy_static::lazy_static! {
+    /// The title or heading of nginx, Apache and lighttpd listings
+    static ref LISTING_TITLE: Regex = Regex::new(r"(?i)<(title|h1|h2)>\s*index of ").unwrap();
+    /// An `href` attribute quoted by `"` or `'`
+    pub(crate) static ref HREF: Regex = Regex::new(r#"(?i)(\bhref\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap();
+}
+
+/// Whether an html page looks like a directory listing, i.e. it is titled
+/// `Index of <path>`
+pub fn is_dir_listing(content: &str) -> bool {
+    let mut len = content.len().min(HEAD_LEN);
+    while !content.is_char_boundary(len) {
+        len -= 1;
+    }
+    LISTING_TITLE.is_match(&content[..len])
+}
+
+/// Whether the response of `url` redirects to the same path with a trailing
+/// slash, i.e. a directory requested without it. The redirect is either the
+/// response itself, or was followed by the client.
+pub fn is_trailing_slash_redirect(url: &str, res: &reqwest::Response) -> bool {
+    let requested = match Url::parse(url) {
+        Ok(requested) => requested,
+        Err(_) => return false,
+    };
+    if requested.path().ends_with('/') {
+        return false;
+    }
+    let target = if res.status().is_redirection() {
+        res.headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| requested.join(location).ok())
+    } else {
+        Some(res.url().clone())
+    };
+    target.map_or(false, |target| {
+        same_host(&requested, &target) && target.path() == format!("{}/", requested.path())
+    })
+}
+
+/// `Location` of the redirect of a directory requested without a trailing
+/// slash, relative to the request so that it holds wherever the mirror
+/// serves the rule, e.g. `pub/` for `http://files.corp/pub`
+pub fn trailing_slash_location(url: &str) -> String {
+    let path = Url::parse(url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_default();
+    format!("{}/", path.rsplit('/').next().unwrap_or_default())
+}
+
+/// Rewrite the absolute links of a listing served at `page` to the same
+/// host, e.g. `http://files.corp/pub/a.tar.gz` or `/pub/a.tar.gz`, to links
+/// relative to the page, e.g. `a.tar.gz`. Relative links and links to other
+/// hosts are kept.
+pub fn rewrite_hrefs(content: &str, page: &Url) -> String {
+    HREF.replace_all(content, |caps: &Captures| {
+        let (href, quote) = match (caps.get(2), caps.get(3)) {
+            (Some(href), _) => (href.as_str(), '"'),
+            (None, Some(href)) => (href.as_str(), '\''),
+            (None, None) => return caps[0].to_string(),
+        };
+        match relative_href(href, page) {
+            Some(relative) => format!("{}{}{}{}", &caps[1], quote, relative, quote),
+            None => caps[0].to_string(),
+        }
+    })
+    .into_owned()
+}
+
+/// `href` relative to `page`, if it is an absolute link to the same host
+fn relative_href(href: &str, page: &Url) -> Option<String> {
+    let absolute = Url::parse(href).is_ok() || href.starts_with('/');
+    if !absolute {
+        return None;
+    }
+    let target = page.join(href).ok()?;
+    if !matches!(target.scheme(), "http" | "https") || !same_host(page, &target) {
+        return None;
+    }
+    let base = directory_segments(page.path());
+    let target_segments = directory_segments(target.path());
+    let file = target.path().rsplit('/').next().unwrap_or_default();
+    let common = base
+        .iter()
+        .zip(&target_segments)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = "../".repeat(base.len() - common);
+    for segment in &target_segments[common..] {
+        relative.push_str(segment);
+        relative.push('/');
+    }
+    relative.push_str(file);
+    // e.g. `a:b.txt` would be read as a url of the scheme `a`
+    if relative.is_empty() || relative.split('/').next().unwrap_or_default().contains(':') {
+        relative.insert_str(0, "./");
+    }
+    if let Some(query) = target.query() {
+        relative.push('?');
+        relative.push_str(query);
+    }
+    if let Some(fragment) = target.fragment() {
+        relative.push('#');
+        relative.push_str(fragment);
+    }
+    Some(relative)
+}
+
+/// Segments of the directory of `path`, e.g. `["pub", "dir"]` for
+/// `/pub/dir/a.tar.gz`
+fn directory_segments(path: &str) -> Vec<&str> {
+    let dir = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+    dir.split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+/// Whether two urls are of the same host and port, e.g. an `https` link on an
+/// `http` page of the host
+fn same_host(a: &Url, b: &Url) -> bool {
+    a.host_str() == b.host_str() && a.port() == b.port()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NGINX_LISTING: &str = "<html>\r\n\
+        <head><title>Index of /pub/releases/</title></head>\r\n\
+        <body>\r\n\
+        <h1>Index of /pub/releases/</h1><hr><pre><a href=\"../\">../</a>\r\n\
+        <a href=\"v1.0/\">v1.0/</a>                                              01-Oct-2026 10:00       -\r\n\
+        <a href=\"tool-1.0.tar.gz\">tool-1.0.tar.gz</a>                                    01-Oct-2026 10:00    1048576\r\n\
+        <a href=\"http://files.corp/pub/releases/tool-1.1.tar.gz\">tool-1.1.tar.gz</a>      02-Oct-2026 10:00    1048576\r\n\
+        </pre><hr></body>\r\n\
+        </html>\r\n";
+
+    const APACHE_LISTING: &str = "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 3.2 Final//EN\">\n\
+        <html>\n <head>\n  <title>Index of /pub/releases</title>\n </head>\n <body>\n\
+        <h1>Index of /pub/releases</h1>\n  <table>\n\
+        <tr><th valign=\"top\"><img src=\"/icons/blank.gif\" alt=\"[ICO]\"></th>\
+        <th><a href=\"?C=N;O=D\">Name</a></th><th><a href=\"?C=M;O=A\">Last modified</a></th></tr>\n\
+        <tr><td valign=\"top\"><img src=\"/icons/back.gif\" alt=\"[PARENTDIR]\"></td>\
+        <td><a href=\"/pub/\">Parent Directory</a></td><td>&nbsp;</td></tr>\n\
+        <tr><td><a href=\"v1.0/\">v1.0/</a></td></tr>\n\
+        <tr><td><a href=\"/pub/releases/v1.1/tool-1.1.tar.gz\">tool-1.1.tar.gz</a></td></tr>\n\
+        <tr><td><a href='https://github.com/corp/tool'>source</a></td></tr>\n\
+        </table>\n<address>Apache/2.4.57 (Debian) Server at files.corp Port 80</address>\n\
+        </body></html>\n";
+
+    fn page() -> Url {
+        Url::parse("http://files.corp/pub/releases/").unwrap()
+    }
+
+    #[test]
+    fn detect_listings() {
+        assert!(is_dir_listing(NGINX_LISTING));
+        assert!(is_dir_listing(APACHE_LISTING));
+        assert!(!is_dir_listing(
+            "<html><head><title>Tool</title></head><body>Index of /pub</body></html>"
+        ));
+        // only the head of the page is searched, even if it ends in a character
+        let late = format!("a{}<title>Index of /pub/</title>", "é".repeat(HEAD_LEN));
+        assert!(!is_dir_listing(&late));
+    }
+
+    #[test]
+    fn rewrite_nginx_listing() {
+        let rewritten = rewrite_hrefs(NGINX_LISTING, &page());
+        assert_eq!(
+            rewritten,
+            NGINX_LISTING.replace(
+                "http://files.corp/pub/releases/tool-1.1.tar.gz",
+                "tool-1.1.tar.gz"
+            )
+        );
+    }
+
+    #[test]
+    fn rewrite_apache_listing() {
+        let rewritten = rewrite_hrefs(APACHE_LISTING, &page());
+        assert!(rewritten.contains("<a href=\"../\">Parent Directory</a>"));
+        assert!(rewritten.contains("<a href=\"v1.1/tool-1.1.tar.gz\">"));
+        // relative links, links to other hosts, and sources of images are kept
+        assert!(rewritten.contains("<a href=\"?C=N;O=D\">"));
+        assert!(rewritten.contains("<a href=\"v1.0/\">"));
+        assert!(rewritten.contains("<a href='https://github.com/corp/tool'>"));
+        assert!(rewritten.contains("<img src=\"/icons/back.gif\""));
+    }
+
+    #[test]
+    fn relative_hrefs() {
+        let page = Url::parse("http://files.corp/pub/releases/index.html").unwrap();
+        let relative = |href: &str| relative_href(href, &page);
+        assert_eq!(relative("/pub/releases/"), Some("./".to_string()));
+        assert_eq!(relative("/"), Some("../../".to_string()));
+        assert_eq!(
+            relative("/other/a.txt"),
+            Some("../../other/a.txt".to_string())
+        );
+        assert_eq!(
+            relative("https://files.corp/pub/releases/a.txt?v=1#top"),
+            Some("a.txt?v=1#top".to_string())
+        );
+        assert_eq!(
+            relative("/pub/releases/a:b.txt"),
+            Some("./a:b.txt".to_string())
+        );
+        assert_eq!(relative("http://files.corp:8080/pub/a.txt"), None);
+        assert_eq!(relative("//cdn.corp/pub/a.txt"), None);
+        assert_eq!(relative("mailto:ops@corp"), None);
+        assert_eq!(relative("a.txt"), None);
+    }
+
+    #[test]
+    fn trailing_slash_locations() {
+        assert_eq!(trailing_slash_location("http://files.corp/pub"), "pub/");
+        assert_eq!(
+            trailing_slash_location("http://files.corp/pub/v1.0?x=1"),
+            "v1.0/"
+        );
+    }
+}
@@ -1,42 +1,74 @@
+mod admission;
+mod audit;
+mod backup;
+mod bypass;
 mod cache;
+mod check;
+mod classify;
+mod diskusage;
 mod error;
+mod fetch;
+mod hashes;
+mod hotkeys;
+mod jobs;
+mod keys;
+mod listener;
+mod listing;
 mod metric;
+mod migrate;
 mod models;
+mod offline;
+mod pagination;
+mod pep658;
+mod pep691;
+mod protect;
+mod quota;
+mod redirects;
+mod rewrite;
+mod routes;
+mod rules;
+mod scheduler;
+mod secret;
 mod settings;
+mod slowlog;
+mod snapshot;
 mod storage;
 mod task;
+mod telemetry;
+mod tenant;
+#[cfg(any(test, feature = "test-util"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod test_util;
+mod upstreams;
+mod usage;
 mod util;
 
-use cache::CacheHitMiss;
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, SubCommand};
 use metrics::{increment_counter, register_counter};
 use metrics_exporter_prometheus::PrometheusBuilder;
 use metrics_util::MetricKindMask;
+use mirror_cache::api;
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use regex::{Regex, RegexSet};
+use rules::RuleMatcher;
 use settings::{rule_label, Rule};
+use std::future::Future;
 use std::path::Path;
+use std::pin::Pin;
 use task::TaskManager;
 use tokio::sync::RwLock;
 
 #[macro_use]
 extern crate serde_derive;
 
-extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
 pub type LockedSharedTaskManager = RwLock<TaskManager>;
 
 lazy_static::lazy_static! {
-    /// A regular expression set of all specified rule paths and a list of Regex
-    /// As suggest in regex documentation of `RegexSet`:
-    /// Other features like finding the location of successive matches or their
-    /// sub-captures aren’t supported. If you need this functionality, the
-    /// recommended approach is to compile each regex in the set independently
-    /// and selectively match them based on which regexes in the set matched.
-    static ref RE_SET_LIST: RwLock<(RegexSet, Vec<Regex>)> = {
-        RwLock::new((RegexSet::empty(), vec![]))
+    /// Matcher of all specified rule paths
+    static ref RULE_MATCHER: RwLock<RuleMatcher> = {
+        RwLock::new(RuleMatcher::empty())
     };
     /// Global task manager.
     static ref TASK_MANAGER: LockedSharedTaskManager = {
@@ -54,7 +86,110 @@ async fn main() {
                 .long("config")
                 .value_name("FILE")
                 .help("Sets a custom config file. Default config.yml")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Prints the settings in effect as JSON, secrets redacted, then exits"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks the config, redis, storages and upstreams, then exits"),
+        )
+        .subcommand(
+            SubCommand::with_name("health")
+                .about("Checks that a running instance is ready, over its unix socket if set"),
+        )
+        .subcommand(
+            SubCommand::with_name("fetch")
+                .about("Fetches files into the caches of a running instance, and waits for them")
+                .arg(
+                    Arg::with_name("spec")
+                        .value_name("SPEC")
+                        .help(
+                            "An upstream url, pypi:<project>[==<version>] or \
+                             anaconda:<channel>/<subdir>/<file>",
+                        )
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .long("token")
+                        .value_name("TOKEN")
+                        .help("An admin token. Default the first of admin_tokens")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup-metadata")
+                .about("Writes the redis metadata of an LRU cache to a file")
+                .arg(
+                    Arg::with_name("cache")
+                        .long("cache")
+                        .value_name("ID")
+                        .help("The cache id, the policy name or <policy>_<storage> of a shard")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("The file written, compressed with zstd if it ends with .zst")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore-metadata")
+                .about("Restores the redis metadata of an LRU cache from a backup")
+                .arg(
+                    Arg::with_name("cache")
+                        .long("cache")
+                        .value_name("ID")
+                        .help("The cache id, the policy name or <policy>_<storage> of a shard")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("in")
+                        .long("in")
+                        .value_name("FILE")
+                        .help("A file written by backup-metadata")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Replaces the entries of a cache that is not empty"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-cache-id")
+                .about("Renames the id of an LRU cache with redis metadata, keeping its entries")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("ID")
+                        .help("The old cache id")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("ID")
+                        .help("The new cache id, the policy name or <policy>_<storage> of a shard")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("move-storage")
+                        .long("move-storage")
+                        .help("Renames the directories of the files named after the old id"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Renames a cache that a running instance uses"),
+                ),
         )
         .get_matches();
     debug!("CLI args: {:?}", matches);
@@ -63,30 +198,144 @@ async fn main() {
         .unwrap_or("config.yml")
         .to_string();
 
+    if matches.is_present("print-config") {
+        let effective = settings::Settings::new(&config_filename).and_then(|s| s.effective());
+        match effective {
+            Ok(effective) => println!("{}", serde_json::to_string_pretty(&effective).unwrap()),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("check").is_some() {
+        let results = check::check_config(&config_filename).await;
+        print!("{}", check::format_table(&results));
+        let failed = results.iter().filter(|r| !r.passed()).count();
+        if failed > 0 {
+            eprintln!("{} of {} checks failed", failed, results.len());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("health").is_some() {
+        let result = match settings::Settings::new(&config_filename) {
+            Ok(settings) => check::probe_instance(&settings).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => println!("ready"),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("fetch") {
+        let spec = args.value_of("spec").unwrap();
+        let result = match settings::Settings::new(&config_filename) {
+            Ok(settings) => fetch::fetch_on_instance(&settings, spec, args.value_of("token")).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(report) => {
+                print!("{}", fetch::format_report(&report));
+                let failed = report.failed();
+                if failed > 0 {
+                    eprintln!("{} of {} files failed", failed, report.files.len());
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(args) = matches.subcommand_matches("backup-metadata") {
+        let id = args.value_of("cache").unwrap();
+        let out = Path::new(args.value_of("out").unwrap());
+        let result = settings::Settings::new(&config_filename)
+            .and_then(|settings| backup::redis_lru_cache(&settings, id))
+            .and_then(|(db, _)| backup::dump(&db, id))
+            .and_then(|dump| backup::write_dump(out, &dump).map(|_| dump));
+        match result {
+            Ok(dump) => println!(
+                "wrote {} entries of {}, {} bytes, to {}",
+                dump.entries.len(),
+                id,
+                dump.total_size,
+                out.display()
+            ),
+            Err(e) => {
+                eprintln!("failed to back up the metadata of {}: {}", id, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(args) = matches.subcommand_matches("restore-metadata") {
+        let id = args.value_of("cache").unwrap();
+        let input = Path::new(args.value_of("in").unwrap());
+        let loaded = settings::Settings::new(&config_filename)
+            .and_then(|settings| backup::redis_lru_cache(&settings, id))
+            .and_then(|cache| backup::read_dump(input).map(|dump| (cache, dump)));
+        let result = match loaded {
+            Ok(((db, storage), dump)) => {
+                backup::restore(&db, &*storage, &dump, args.is_present("force")).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(report) => print!("{}", backup::format_report(&report)),
+            Err(e) => {
+                eprintln!("failed to restore the metadata of {}: {}", id, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(args) = matches.subcommand_matches("migrate-cache-id") {
+        let from = args.value_of("from").unwrap();
+        let to = args.value_of("to").unwrap();
+        let force = args.is_present("force");
+        let move_storage = args.is_present("move-storage");
+        let result = settings::Settings::new(&config_filename)
+            .and_then(|settings| migrate::rename_cache(&settings, from, to, force, move_storage));
+        match result {
+            Ok(report) => print!("{}", migrate::format_report(&report)),
+            Err(e) => {
+                eprintln!("failed to rename cache {} to {}: {}", from, to, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let app_settings = settings::Settings::new(&config_filename).unwrap();
     let port = app_settings.port;
     let metrics_port = app_settings.metrics_port;
     let hot_reload = app_settings.hot_reload.unwrap_or(false);
-    let api = filters::root();
+    let api = routes::build_routes();
 
     // initialize the logger
-    let mut log_builder = pretty_env_logger::formatted_builder();
-    log_builder
-        .filter_module("hyper::proto", log::LevelFilter::Error) // hide excessive logs
-        .filter_module("tracing::span", log::LevelFilter::Error)
-        .filter_module("tokio_util::codec", log::LevelFilter::Error)
-        .filter_module("sled::pagecache", log::LevelFilter::Error)
-        .filter_level(app_settings.get_log_level())
-        .init();
-
-    // initialize global static TASK_MANAGER and RE_SET_LIST
+    telemetry::init(&app_settings);
+
+    // initialize global static TASK_MANAGER and RULE_MATCHER
     let mut tm = TaskManager::new(app_settings.clone());
     tm.refresh_config(&app_settings);
     {
         let mut global_tm = TASK_MANAGER.write().await;
         *global_tm = tm;
-        let mut global_re_set_list = RE_SET_LIST.write().await;
-        *global_re_set_list = create_re_set_list(&app_settings.rules);
+        let mut global_rule_matcher = RULE_MATCHER.write().await;
+        *global_rule_matcher = RuleMatcher::new(&app_settings.rules).unwrap();
     }
 
     // init metrics
@@ -102,6 +351,69 @@ async fn main() {
     metric::register_counters();
     register_rules_metrics(&app_settings.rules);
 
+    // storages may be large, so they are cleaned up while serving requests
+    let tm = TASK_MANAGER.read().await.clone();
+    tokio::task::spawn_blocking(move || tm.clean_temp_files());
+
+    // forget background tasks that never complete, so their keys can be retried
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            TASK_MANAGER.read().await.remove_stuck_tasks().await;
+        }
+    });
+
+    // keep frequently hit files that are about to be evicted
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let tm = TASK_MANAGER.read().await.clone();
+            tm.run_protective_refreshes().await;
+        }
+    });
+
+    // compare the files of caches with their metadata, walks taking a while
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let tm = TASK_MANAGER.read().await.clone();
+            tm.run_disk_usage_walks().await;
+        }
+    });
+
+    // pick up the cache bypass switched by other instances
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(bypass::SYNC_INTERVAL);
+        loop {
+            interval.tick().await;
+            let tm = TASK_MANAGER.read().await.clone();
+            tokio::task::spawn_blocking(move || tm.sync_bypass());
+        }
+    });
+
+    // keep other processes, e.g. `migrate-cache-id`, from renaming caches in use
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(migrate::HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let tm = TASK_MANAGER.read().await.clone();
+            tokio::task::spawn_blocking(move || tm.write_heartbeats());
+        }
+    });
+
+    // keep the status of upstreams without traffic current
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(upstreams::IDLE_AFTER);
+        loop {
+            interval.tick().await;
+            let tm = TASK_MANAGER.read().await.clone();
+            tm.probe_idle_upstreams().await;
+        }
+    });
+
     let config_filename_clone = config_filename.clone();
     // make watcher live long enough
     let mut watcher =
@@ -125,7 +437,52 @@ async fn main() {
         );
     }
 
-    warp::serve(api).run(([127, 0, 0, 1], port)).await;
+    // all listeners shut down on the same signal
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(());
+    });
+    let shutdown = move || {
+        let mut rx = shutdown_rx.clone();
+        async move {
+            let _ = rx.changed().await;
+        }
+    };
+    let mut servers: Vec<Pin<Box<dyn Future<Output = ()> + Send>>> = Vec::new();
+    if app_settings.serves_tcp() {
+        let (_, server) = warp::serve(api.clone())
+            .bind_with_graceful_shutdown(([127, 0, 0, 1], port), shutdown());
+        servers.push(Box::pin(server));
+    }
+    let unix_socket = app_settings.unix_socket().cloned();
+    if let Some(socket) = &unix_socket {
+        let listener = listener::bind(socket)
+            .unwrap_or_else(|e| panic!("failed to listen on unix socket {}: {}", socket.path, e));
+        servers.push(Box::pin(
+            warp::serve(api)
+                .serve_incoming_with_graceful_shutdown(listener::incoming(listener), shutdown()),
+        ));
+    }
+    futures::future::join_all(servers).await;
+    if let Some(socket) = &unix_socket {
+        listener::remove(socket);
+    }
+    // stop background threads of caches before the runtime goes away
+    TASK_MANAGER.read().await.close_caches().await;
+    telemetry::shutdown();
+    info!("shut down");
+}
+
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    info!("shutting down gracefully");
 }
 
 fn file_watch_handler(config_filename: &str, result: std::result::Result<Event, notify::Error>) {
@@ -138,8 +495,8 @@ fn file_watch_handler(config_filename: &str, result: std::result::Result<Event,
             match settings::Settings::new(config_filename) {
                 Ok(settings) => {
                     TASK_MANAGER.write().await.refresh_config(&settings);
-                    let mut re_set_list = RE_SET_LIST.write().await;
-                    *re_set_list = create_re_set_list(&settings.rules);
+                    let mut rule_matcher = RULE_MATCHER.write().await;
+                    *rule_matcher = RuleMatcher::new(&settings.rules).unwrap();
                     register_rules_metrics(&settings.rules);
                     info!("config updated");
                 }
@@ -151,22 +508,20 @@ fn file_watch_handler(config_filename: &str, result: std::result::Result<Event,
     }
 }
 
-fn create_re_set_list(rules: &[Rule]) -> (RegexSet, Vec<Regex>) {
-    let rules_strings: Vec<String> = rules.iter().map(|rule| rule.path.clone()).collect();
-    let set = RegexSet::new(&rules_strings).unwrap();
-    let list = rules
-        .iter()
-        .map(|rule| Regex::new(&rule.path).unwrap())
-        .collect();
-    (set, list)
-}
-
 /// Register metrics for each rule.
 /// - counter - cache hit
 /// - counter - cache miss
 /// - counter - incoming requests
 /// - counter - successful requests
 /// - counter - failed requests
+/// - counter - shed requests
+/// - counter - upstream failures
+/// - counter - stale entries served on upstream failures
+/// - counter - uncacheable upstream responses
+/// - counter - cache misses in offline mode
+/// - counter - requests served from upstream while the caches are bypassed
+/// - counter - cache misses admitted to the cache
+/// - counter - cache misses rejected by the admission filter
 fn register_rules_metrics(rules: &[Rule]) {
     for rule in rules {
         register_counter!(metric::COUNTER_CACHE_HIT, "Cache hit count", "rule" => rule_label(rule));
@@ -174,209 +529,1365 @@ fn register_rules_metrics(rules: &[Rule]) {
         register_counter!(metric::COUNTER_REQ, "Incoming requests count", "rule" => rule_label(rule));
         register_counter!(metric::COUNTER_REQ_SUCCESS, "Incoming requests count (success)", "rule" => rule_label(rule));
         register_counter!(metric::COUNTER_REQ_FAILURE, "Incoming requests count (failure)", "rule" => rule_label(rule));
+        register_counter!(metric::CNT_REQ_SHED, "Requests rejected because of too many in-flight upstream requests", "rule" => rule_label(rule));
+        register_counter!(metric::CNT_UPSTREAM_FAILURE, "Cache misses failed to be fetched from upstream", "rule" => rule_label(rule));
+        register_counter!(metric::CNT_STALE_SERVED, "Expired cache entries served because the upstream failed", "rule" => rule_label(rule));
+        register_counter!(metric::CNT_UNCACHEABLE, "Upstream responses not cached because they may be personalized", "rule" => rule_label(rule));
+        register_counter!(metric::CNT_OFFLINE_MISS, "Cache misses not fetched from upstream in offline mode", "rule" => rule_label(rule));
+        register_counter!(metric::CNT_BYPASSED, "Requests served from upstream while the caches are bypassed", "rule" => rule_label(rule));
+        if rule.admission.is_some() {
+            register_counter!(metric::CNT_ADMISSION_ADMITTED, "Cache misses admitted to the cache by the admission filter", "rule" => rule_label(rule));
+            register_counter!(metric::CNT_ADMISSION_REJECTED, "Cache misses served without being cached by the admission filter", "rule" => rule_label(rule));
+        }
     }
 }
 
-mod filters {
+mod handlers {
     use super::*;
-    use warp::Filter;
+    use crate::error::Error;
+    use crate::quota::ByteCounter;
+    use crate::settings::Settings;
+    use crate::slowlog::{Category, Timer};
+    use crate::task::{CacheStatus, Task, TaskResponse};
+    use futures::StreamExt;
+    use std::result::Result;
+    use warp::Rejection;
+    use warp::Reply;
 
-    pub fn root() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        let log = warp::log::custom(|info| {
-            info!(
-                "🌐 {} {} Response: {}",
-                info.method(),
-                info.path(),
-                info.status(),
-            );
-        });
+    /// `Retry-After` in seconds of responses rejected by load shedding
+    const RETRY_AFTER_SECS: u64 = 5;
+
+    /// Number of audit entries returned in a page by default
+    const AUDIT_PAGE_SIZE: usize = 100;
 
-        fallback_head().or(fallback().with(log))
+    /// Number of evicted entries returned in a page of a preview by default
+    const EVICTION_PREVIEW_PAGE_SIZE: usize = 100;
+
+    /// Maximum number of evicted entries in a page of a preview
+    const MAX_EVICTION_PREVIEW_PAGE_SIZE: usize = 1000;
+
+    /// Maximum number of keys looked up by a request to
+    /// `POST /admin/cache/<policy>/contains`
+    const MAX_CONTAINS_KEYS: usize = 1000;
+
+    /// Number of entries returned in a page of a listing by default
+    const ENTRY_PAGE_SIZE: usize = 100;
+
+    /// Maximum number of entries in a page of a listing
+    const MAX_ENTRY_PAGE_SIZE: usize = 1000;
+
+    /// Maximum number of files fetched by a warmup
+    const MAX_WARMUP_PATHS: usize = 10_000;
+
+    #[derive(Debug, Deserialize)]
+    pub struct AuditQuery {
+        since: Option<i64>,
+        offset: Option<usize>,
+        limit: Option<usize>,
     }
 
-    fn fallback_head() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::head()
-            .and(
-                warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()),
-            )
-            .and_then(handlers::head_fallback_handler)
+    #[derive(Debug, Deserialize)]
+    pub struct UsageQuery {
+        /// Group keys by their first N segments
+        group_by_prefix: Option<usize>,
+        group_regex: Option<String>,
+        /// Number of the largest groups reported
+        top: Option<usize>,
     }
 
-    /// fallback handler, matches all paths
-    fn fallback() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::get()
-            .and(
-                warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()),
-            )
-            .and_then(handlers::fallback_handler)
+    #[derive(Debug, Deserialize)]
+    pub struct PurgeAllQuery {
+        /// The cache again, to confirm the purge
+        confirm: Option<String>,
     }
-}
 
-mod handlers {
-    use super::*;
-    use crate::error::Error;
-    use crate::task::Task;
-    use std::result::Result;
-    use warp::Rejection;
-    use warp::Reply;
+    #[derive(Debug, Deserialize)]
+    pub struct EvictionPreviewQuery {
+        /// Size the cache should fit in, in bytes or e.g. `10 GB`
+        target_size: Option<String>,
+        offset: Option<usize>,
+        limit: Option<usize>,
+    }
 
-    pub async fn head_fallback_handler(path: String) -> Result<impl warp::Reply, Rejection> {
-        // resolve path to upstream url
-        let resolve_result = resolve_upstream(&path).await;
-        if resolve_result.is_none() {
-            return Err(warp::reject::not_found());
+    #[derive(Debug, Deserialize)]
+    pub struct OfflineQuery {
+        /// Name of the rule, all rules if absent
+        rule: Option<String>,
+        enabled: Option<bool>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct BypassQuery {
+        /// Name of the rule, all rules if absent or `all`
+        rule: Option<String>,
+        enabled: Option<bool>,
+    }
+
+    /// Find the label of the admin token in an `Authorization: Bearer` header.
+    pub async fn authorize_admin(authorization: Option<String>) -> Result<String, Rejection> {
+        let tm = TASK_MANAGER.read().await;
+        admin_label(&tm.config, authorization.as_deref())
+            .ok_or_else(|| warp::reject::custom(Error::Unauthorized))
+    }
+
+    /// Reject a request whose path and query are longer than
+    /// `max_path_length`, or with a header larger than `max_header_bytes`,
+    /// before it is routed, so that it never makes a cache key
+    pub async fn check_request_limits(
+        path: warp::filters::path::FullPath,
+        query: Option<String>,
+        headers: warp::http::HeaderMap,
+    ) -> Result<(), Rejection> {
+        let tm = TASK_MANAGER.read().await;
+        let max_path = tm.config.max_path_length();
+        let len = path.as_str().len() + query.map_or(0, |query| query.len() + 1);
+        if len > max_path {
+            return Err(warp::reject::custom(Error::UriTooLong(max_path)));
         }
-        let upstream = resolve_result.unwrap().0;
-        match util::make_request(&upstream, true).await {
-            Ok(up_resp) => {
-                // create a response and copy headers
-                let resp_builder = up_resp
-                    .headers()
-                    .iter()
-                    .fold(warp::http::Response::builder(), |prev, (key, value)| {
-                        prev.header(key, value)
-                    });
-                Ok(resp_builder.body("").unwrap())
-            }
-            Err(e) => match e {
-                Error::UpstreamRequestError(res) => {
-                    let resp = warp::http::Response::builder()
-                        .status(res.status())
-                        .body("");
-                    Ok(resp.unwrap())
-                }
-                _ => Err(warp::reject::custom(e)),
-            },
+        let max_header = tm.config.max_header_bytes();
+        let too_large = headers
+            .iter()
+            .any(|(name, value)| name.as_str().len() + value.len() > max_header);
+        if too_large {
+            return Err(warp::reject::custom(Error::HeaderTooLarge(max_header)));
         }
+        Ok(())
     }
 
-    pub async fn fallback_handler(path: String) -> Result<impl warp::Reply, Rejection> {
-        let upstream = resolve_upstream(&path).await;
-        if upstream.is_none() {
-            return Err(warp::reject());
+    /// Find the label of an admin token allowed to manage `cache` in an
+    /// `Authorization: Bearer` header: a global one, or one of the tenant of
+    /// the cache.
+    pub async fn authorize_cache_admin(
+        cache: String,
+        authorization: Option<String>,
+    ) -> Result<(String, String), Rejection> {
+        let tm = TASK_MANAGER.read().await;
+        if let Some(label) = admin_label(&tm.config, authorization.as_deref()) {
+            return Ok((cache, label));
         }
-        let (upstream, idx, rule) = upstream.unwrap();
-        trace!("matched by rule #{}: {}", idx, &rule.path);
-        increment_counter!(metric::COUNTER_REQ, "rule" => rule_label(&rule));
-        let task = Task {
-            rule_id: idx,
-            url: upstream,
-        };
-        let tm = TASK_MANAGER.read().await.clone();
-        let tm_resp = tm.resolve_task(&task).await;
-        match tm_resp.1 {
-            CacheHitMiss::Hit => {
-                increment_counter!(metric::COUNTER_CACHE_HIT, "rule" => rule_label(&rule))
+        let tenants = tm.config.tenants();
+        let holder = tenant::admin_label(tenants, authorization.as_deref());
+        let owner = tenant::split_cache_id(tenants, &cache).map(|(owner, _)| owner);
+        match (holder, owner) {
+            (Some((holder, label)), Some(owner)) if holder.name == owner.name => Ok((cache, label)),
+            _ => Err(warp::reject::custom(Error::Unauthorized)),
+        }
+    }
+
+    /// The label of the admin token sent as `Authorization: Bearer <token>`
+    fn admin_label(config: &Settings, authorization: Option<&str>) -> Option<String> {
+        let token = authorization.and_then(|value| value.strip_prefix("Bearer "));
+        config
+            .admin_tokens
+            .iter()
+            .flatten()
+            .find(|admin| token == Some(admin.token.expose().as_str()))
+            .map(|admin| admin.label.clone())
+    }
+
+    /// Whether a request asking for a forced refresh is allowed one, see
+    /// `ForceRefresh`
+    pub fn may_refresh(config: &Settings, authorization: Option<&str>) -> bool {
+        match &config.force_refresh {
+            Some(force_refresh) if force_refresh.require_admin_token() => {
+                admin_label(config, authorization).is_some()
             }
-            CacheHitMiss::Miss => {
-                increment_counter!(metric::COUNTER_CACHE_MISS, "rule" => rule_label(&rule))
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    pub async fn audit_handler(
+        principal: String,
+        query: AuditQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let audit = TASK_MANAGER.read().await.audit.clone();
+        let audit = audit.ok_or_else(|| warp::reject::custom(Error::AuditDisabled))?;
+        debug!("audit log read by {}: {:?}", principal, query);
+        let since = query.since.unwrap_or(0);
+        let offset = query.offset.unwrap_or(0);
+        let limit = std::cmp::min(query.limit.unwrap_or(AUDIT_PAGE_SIZE), audit::MAX_PAGE_SIZE);
+        let (entries, next_offset) =
+            tokio::task::spawn_blocking(move || audit.read(since, offset, limit))
+                .await
+                .map_err(|e| warp::reject::custom(Error::OtherError(e.to_string())))?
+                .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "entries": entries,
+            "next_offset": next_offset,
+        })))
+    }
+
+    /// Start a job purging the entries of a cache matching a glob or a regex,
+    /// answered with `202 Accepted` and the location of the job.
+    pub async fn purge_handler(
+        policy: String,
+        principal: String,
+        query: api::PurgeQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let (regex, target) = match (&query.pattern, &query.regex) {
+            (Some(pattern), None) => (util::glob_to_regex(pattern), pattern.clone()),
+            (None, Some(regex)) => (regex.clone(), format!("~{}", regex)),
+            _ => {
+                return Err(warp::reject::custom(Error::BadRequest(
+                    "either pattern or regex is required".to_string(),
+                )))
             }
         };
-        match tm_resp.0 {
-            Ok(data) => {
-                let mut resp = data.into_response();
-                if let Some(options) = &rule.options {
-                    if let Some(content_type) = &options.content_type {
-                        resp = warp::reply::with_header(resp, "content-type", content_type)
-                            .into_response();
-                    }
-                }
-                increment_counter!(metric::COUNTER_REQ_FAILURE, "rule" => rule_label(&rule));
-                Ok(resp)
+        let matcher = regex::Regex::new(&regex)
+            .map_err(|e| warp::reject::custom(Error::BadRequest(e.to_string())))?;
+        let tm = TASK_MANAGER.read().await.clone();
+        let id = tm
+            .spawn_purge(&principal, &policy, matcher, &target)
+            .map_err(warp::reject::custom)?;
+        Ok(job_accepted(id))
+    }
+
+    /// Remove all the entries of a cache, answered once they are removed
+    pub async fn purge_all_handler(
+        policy: String,
+        principal: String,
+        query: PurgeAllQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        if query.confirm.as_deref() != Some(policy.as_str()) {
+            return Err(warp::reject::custom(Error::BadRequest(format!(
+                "confirm={} is required to purge all the entries of {}",
+                policy, policy
+            ))));
+        }
+        let tm = TASK_MANAGER.read().await.clone();
+        let report = tm
+            .purge_all(&principal, &policy)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "cache": policy,
+            "entries": report.entries,
+            "bytes": report.bytes,
+        })))
+    }
+
+    /// `202 Accepted` with the location of the status of a job
+    fn job_accepted(id: jobs::JobId) -> impl warp::Reply {
+        let location = format!("{}/jobs/{}", api::BASE_PATH, id);
+        warp::reply::with_status(
+            warp::reply::with_header(
+                warp::reply::json(&api::JobAccepted {
+                    job: id,
+                    location: location.clone(),
+                }),
+                "Location",
+                location,
+            ),
+            warp::http::StatusCode::ACCEPTED,
+        )
+    }
+
+    /// Entries and bytes of a cache by groups of keys
+    pub async fn usage_handler(
+        policy: String,
+        principal: String,
+        query: UsageQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let grouping = match (query.group_by_prefix, &query.group_regex) {
+            (Some(segments), None) if segments > 0 => usage::Grouping::Prefix(segments),
+            (None, Some(regex)) => usage::Grouping::Regex(
+                regex::Regex::new(regex)
+                    .map_err(|e| warp::reject::custom(Error::BadRequest(e.to_string())))?,
+            ),
+            _ => {
+                return Err(warp::reject::custom(Error::BadRequest(
+                    "either a positive group_by_prefix or group_regex is required".to_string(),
+                )))
             }
-            Err(e) => {
-                increment_counter!(metric::COUNTER_REQ_FAILURE, "rule" => rule_label(&rule));
-                match e {
-                    Error::UpstreamRequestError(res) => {
-                        let resp = warp::http::Response::builder()
-                            .status(res.status())
-                            .body(res.bytes().await.unwrap().into())
-                            .unwrap();
-                        Ok(resp)
-                    }
-                    _ => Err(warp::reject::custom(e)),
-                }
+        };
+        let top = std::cmp::min(
+            query.top.unwrap_or(usage::DEFAULT_TOP_GROUPS),
+            usage::MAX_TOP_GROUPS,
+        );
+        debug!("usage of {} read by {}: {:?}", policy, principal, query);
+        let tm = TASK_MANAGER.read().await.clone();
+        let report = tm
+            .usage_report(&policy, &grouping, top)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&report))
+    }
+
+    /// Entries that would be evicted if the size limit of a cache was
+    /// `target_size`, least recently used first. Nothing is removed.
+    pub async fn eviction_preview_handler(
+        policy: String,
+        principal: String,
+        query: EvictionPreviewQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let target_size = query
+            .target_size
+            .as_deref()
+            .and_then(|size| size.parse().ok().or_else(|| bytefmt::parse(size).ok()))
+            .ok_or_else(|| {
+                warp::reject::custom(Error::BadRequest(
+                    "a target_size, e.g. 10 GB, is required".to_string(),
+                ))
+            })?;
+        let offset = query.offset.unwrap_or(0);
+        let limit = std::cmp::min(
+            query.limit.unwrap_or(EVICTION_PREVIEW_PAGE_SIZE),
+            MAX_EVICTION_PREVIEW_PAGE_SIZE,
+        );
+        debug!(
+            "eviction of {} to {} bytes previewed by {}",
+            policy, target_size, principal
+        );
+        let tm = TASK_MANAGER.read().await.clone();
+        let entries = tm
+            .preview_eviction(&policy, target_size)
+            .await
+            .map_err(warp::reject::custom)?;
+        let bytes: cache::CacheSizeType = entries.iter().map(|entry| entry.size).sum();
+        let next_offset = Some(offset + limit).filter(|next| *next < entries.len());
+        Ok(warp::reply::json(&serde_json::json!({
+            "cache": policy,
+            "target_size": target_size,
+            "count": entries.len(),
+            "bytes": bytes,
+            "entries": entries.iter().skip(offset).take(limit).collect::<Vec<_>>(),
+            "next_offset": next_offset,
+        })))
+    }
+
+    /// Whether each of `keys` is cached, in the same order. Entries are not
+    /// touched, so that the lookups do not delay their eviction.
+    pub async fn contains_handler(
+        policy: String,
+        principal: String,
+        keys: Vec<String>,
+    ) -> Result<impl warp::Reply, Rejection> {
+        if keys.len() > MAX_CONTAINS_KEYS {
+            return Err(warp::reject::custom(Error::BadRequest(format!(
+                "at most {} keys are looked up at once, got {}",
+                MAX_CONTAINS_KEYS,
+                keys.len()
+            ))));
+        }
+        trace!(
+            "{} keys of {} looked up by {}",
+            keys.len(),
+            policy,
+            principal
+        );
+        let tm = TASK_MANAGER.read().await.clone();
+        let found = tm
+            .contains_many(&policy, &keys)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&found))
+    }
+
+    /// The hot keys of an LRU cache, see `Policy::hot_keys`
+    pub async fn hot_keys_handler(
+        policy: String,
+        _principal: String,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let hot_keys = tm.hot_keys(&policy).await.map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "cache": policy,
+            "hot_keys": hot_keys,
+        })))
+    }
+
+    pub async fn job_handler(
+        id: jobs::JobId,
+        _principal: String,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let job = TASK_MANAGER
+            .read()
+            .await
+            .jobs
+            .get(id)
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&job))
+    }
+
+    pub async fn jobs_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
+        let jobs = TASK_MANAGER.read().await.jobs.list();
+        Ok(warp::reply::json(&api::JobList { jobs }))
+    }
+
+    pub async fn api_spec_handler() -> Result<impl warp::Reply, Rejection> {
+        Ok(warp::reply::json(&api::spec()))
+    }
+
+    /// Only the names of failed checks are in the response, their errors are
+    /// logged
+    pub async fn ready_handler() -> Result<impl warp::Reply, Rejection> {
+        let settings = TASK_MANAGER.read().await.config.clone();
+        let results = crate::check::probe_dependencies(&settings).await;
+        let failed: Vec<&str> = results
+            .iter()
+            .filter(|r| !r.passed())
+            .map(|r| {
+                warn!(
+                    "readiness check {} of {} failed: {}",
+                    r.check,
+                    r.target,
+                    r.error.as_deref().unwrap_or_default()
+                );
+                r.check.as_str()
+            })
+            .collect();
+        let status = if failed.is_empty() {
+            warp::http::StatusCode::OK
+        } else {
+            warp::http::StatusCode::SERVICE_UNAVAILABLE
+        };
+        Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "ready": failed.is_empty(),
+                "failed": failed,
+            })),
+            status,
+        ))
+    }
+
+    pub async fn stats_handler(
+        policy: String,
+        _principal: String,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let stats = tm
+            .cache_stats(&policy)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&stats))
+    }
+
+    pub async fn entries_handler(
+        policy: String,
+        _principal: String,
+        query: api::EntryQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let limit = std::cmp::min(query.limit.unwrap_or(ENTRY_PAGE_SIZE), MAX_ENTRY_PAGE_SIZE);
+        let cursor = query.cursor.unwrap_or_default();
+        let tm = TASK_MANAGER.read().await.clone();
+        let page = tm
+            .list_entries(&policy, &cursor, limit)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&page))
+    }
+
+    pub async fn pin_handler(
+        policy: String,
+        principal: String,
+        request: api::PinRequest,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let pinned = tm
+            .pin(&principal, &policy, &request.key)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&pinned))
+    }
+
+    /// Start a job fetching files by their paths on the mirror, answered with
+    /// `202 Accepted` and the location of the job. Paths matched by no rule
+    /// are rejected before anything is fetched.
+    pub async fn warmup_handler(
+        principal: String,
+        request: api::WarmupRequest,
+    ) -> Result<impl warp::Reply, Rejection> {
+        if request.paths.is_empty() || request.paths.len() > MAX_WARMUP_PATHS {
+            return Err(warp::reject::custom(Error::BadRequest(format!(
+                "1 to {} paths are expected",
+                MAX_WARMUP_PATHS
+            ))));
+        }
+        let mut tasks = Vec::with_capacity(request.paths.len());
+        {
+            let rule_matcher = RULE_MATCHER.read().await;
+            for path in &request.paths {
+                let (path_only, query) = match path.split_once('?') {
+                    Some((path_only, query)) => (path_only, Some(query)),
+                    None => (path.as_str(), None),
+                };
+                let (task, _) = rule_matcher
+                    .resolve("GET", path_only.trim_start_matches('/'), query)
+                    .ok_or_else(|| {
+                        warp::reject::custom(Error::BadRequest(format!("no rule matches {}", path)))
+                    })?;
+                tasks.push(task);
             }
         }
+        let tm = TASK_MANAGER.read().await.clone();
+        Ok(job_accepted(tm.spawn_warmup(&principal, tasks)))
     }
 
-    /// Dynamically resolve upstream url as defined in config file
-    async fn resolve_upstream(path: &str) -> Option<(String, usize, Rule)> {
+    pub async fn tasks_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
         let tm = TASK_MANAGER.read().await.clone();
-        let config = &tm.config;
-        let rules_regex_set_list = RE_SET_LIST.read().await;
-        let matched_indices: Vec<usize> =
-            rules_regex_set_list.0.matches(path).into_iter().collect();
-        if matched_indices.is_empty() {
-            // No matching rule
-            return None;
-        }
-        let idx = *matched_indices.first().unwrap();
-        let re = &rules_regex_set_list.1[idx];
-        let rule = config.rules.get(idx).unwrap();
-        let upstream = rule.upstream.clone();
-        trace!("matched by rule #{}: {}", idx, &rule.path);
-        increment_counter!(metric::COUNTER_REQ, "rule" => rule_label(rule));
-        let replaced = re.replace_all(path, &upstream);
-        Some((String::from(replaced), idx, rule.clone()))
+        let tasks = tm.running_tasks().await;
+        Ok(warp::reply::json(&api::TaskList { tasks }))
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::settings::Settings;
-    use lazy_static::lazy_static;
-    use warp::http::StatusCode;
-    use warp::test::request;
-    use warp::Filter;
+    pub async fn offline_status_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        Ok(warp::reply::json(&tm.offline_status()))
+    }
 
-    async fn setup() {
-        lazy_static! {
-            /// Initialize logger only once.
-            static ref LOGGER: () = {
-                let mut log_builder = pretty_env_logger::formatted_builder();
-                log_builder
-                    .filter_module("sled", log::LevelFilter::Info)
-                    .filter_level(log::LevelFilter::Trace)
-                    .target(pretty_env_logger::env_logger::Target::Stdout)
-                    .init();
-            };
+    /// Override the offline mode set by the settings
+    pub async fn set_offline_handler(
+        principal: String,
+        query: OfflineQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let enabled = query.enabled.ok_or_else(|| {
+            warp::reject::custom(Error::BadRequest("enabled is required".to_string()))
+        })?;
+        let tm = TASK_MANAGER.read().await.clone();
+        let status = tm
+            .set_offline(&principal, query.rule.as_deref(), Some(enabled))
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&status))
+    }
+
+    /// Fall back to the offline mode set by the settings
+    pub async fn clear_offline_handler(
+        principal: String,
+        query: OfflineQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let status = tm
+            .set_offline(&principal, query.rule.as_deref(), None)
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&status))
+    }
+
+    pub async fn bypass_status_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        Ok(warp::reply::json(&tm.bypass_status()))
+    }
+
+    /// Switch the cache bypass of a rule, or of all rules
+    pub async fn set_bypass_handler(
+        principal: String,
+        query: BypassQuery,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let enabled = query.enabled.ok_or_else(|| {
+            warp::reject::custom(Error::BadRequest("enabled is required".to_string()))
+        })?;
+        let rule = match query.rule.as_deref() {
+            None | Some("all") => None,
+            Some(rule) => Some(rule),
         };
+        let tm = TASK_MANAGER.read().await.clone();
+        let status = tm
+            .set_bypass(&principal, rule, enabled)
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&status))
+    }
 
-        let _ = &LOGGER;
-        let settings = get_settings();
-        TASK_MANAGER.write().await.refresh_config(&settings);
-        let mut global_re_set_list = RE_SET_LIST.write().await;
-        *global_re_set_list = create_re_set_list(&settings.rules);
+    /// Usage of the quotas of clients in the current window
+    pub async fn quotas_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
+        let quotas = TASK_MANAGER.read().await.quotas.clone();
+        let quotas = quotas.ok_or_else(|| {
+            warp::reject::custom(Error::NotFound("quotas are not enabled".to_string()))
+        })?;
+        let usage = tokio::task::spawn_blocking(move || quotas.usage())
+            .await
+            .map_err(|e| warp::reject::custom(Error::OtherError(e.to_string())))?
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&serde_json::json!({ "clients": usage })))
     }
 
-    fn get_settings() -> Settings {
-        settings::Settings::new_from("config-test", "app_test").unwrap()
+    /// Status, latency and error rate of each upstream over the last minutes
+    pub async fn upstreams_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        Ok(warp::reply::json(
+            &serde_json::json!({ "upstreams": tm.upstream_report() }),
+        ))
     }
 
-    fn get_filter_root() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
-    {
-        filters::root()
+    /// Fetch the files of a spec into the caches, see `TaskManager::fetch`
+    pub async fn fetch_handler(
+        principal: String,
+        request: fetch::FetchRequest,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let report = tm
+            .fetch(&principal, &request.spec)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&report))
     }
 
-    #[tokio::test]
-    async fn get_pypi_index() {
-        setup().await;
-        let pkg_name = "hello-world";
-        let api = get_filter_root();
-        let resp = request()
-            .method("GET")
-            .path(&format!("/pypi/simple/{}", pkg_name))
-            .reply(&api)
-            .await;
-        let resp_bytes = resp.body().to_vec();
-        let resp_text = std::str::from_utf8(&resp_bytes).unwrap();
-        assert_eq!(resp.status(), StatusCode::OK);
-        assert!(resp
-            .headers()
-            .get("content-type")
-            .unwrap()
+    pub async fn list_snapshots_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let snapshots = tm.list_snapshots().map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&snapshots))
+    }
+
+    pub async fn create_snapshot_handler(
+        principal: String,
+        request: snapshot::SnapshotRequest,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let info = tm
+            .create_snapshot(&principal, &request)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::with_status(
+            warp::reply::json(&info),
+            warp::http::StatusCode::CREATED,
+        ))
+    }
+
+    pub async fn snapshot_manifest_handler(
+        id: String,
+        _principal: String,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let manifest = tm.snapshot_manifest(&id).map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&manifest))
+    }
+
+    pub async fn delete_snapshot_handler(
+        id: String,
+        principal: String,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let info = tm
+            .delete_snapshot(&principal, &id)
+            .await
+            .map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&info))
+    }
+
+    /// A page of a snapshot, see `TaskManager::snapshot_page`. Files of rules
+    /// that are not snapshotted are redirected to their usual path.
+    pub async fn snapshot_handler(
+        id: String,
+        path: String,
+        query: Option<String>,
+        accept: Option<String>,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let (mut task, rule) = resolve_task("GET", &path, query.as_deref())
+            .await
+            .ok_or_else(warp::reject::not_found)?;
+        let pep503 = rule
+            .options
+            .as_ref()
+            .and_then(|o| o.pep503)
+            .unwrap_or(false);
+        let index_page = pep503 && path.ends_with('/');
+        task.simple_json = index_page && pep691::prefers_json(accept.as_deref());
+        let page = tm
+            .snapshot_page(&id, &task)
+            .await
+            .map_err(warp::reject::custom)?;
+        let data = match page {
+            Some(data) => data,
+            None => {
+                let location = match &query {
+                    Some(query) => format!("/{}?{}", path, query),
+                    None => format!("/{}", path),
+                };
+                return Ok(warp::http::Response::builder()
+                    .status(warp::http::StatusCode::FOUND)
+                    .header("Location", location)
+                    .body("".into())
+                    .unwrap());
+            }
+        };
+        let mut resp = TaskResponse::from(data).into_response();
+        let content_type = if task.simple_json {
+            Some(pep691::JSON_MEDIA_TYPE)
+        } else {
+            rule.options
+                .as_ref()
+                .and_then(|o| o.content_type.as_deref())
+        };
+        if let Some(content_type) = content_type {
+            resp = warp::reply::with_header(resp, "content-type", content_type).into_response();
+        }
+        if index_page {
+            let vary = warp::http::HeaderValue::from_static("Accept");
+            resp.headers_mut().append("Vary", vary);
+        }
+        Ok(resp)
+    }
+
+    /// The settings after defaults and environment variables, with the rules
+    /// and caches derived from them
+    pub async fn config_handler(_principal: String) -> Result<impl warp::Reply, Rejection> {
+        let settings = TASK_MANAGER.read().await.config.clone();
+        let effective = settings.effective().map_err(warp::reject::custom)?;
+        Ok(warp::reply::json(&effective))
+    }
+
+    /// Turn errors of handlers, and malformed queries and bodies of the
+    /// management API, into responses with a JSON problem body. Requests no
+    /// route serves are answered by `unmatched_reply`.
+    pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Rejection> {
+        let malformed;
+        let e = if let Some(e) = err.find::<Error>() {
+            e
+        } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+            malformed = Error::BadRequest(e.to_string());
+            &malformed
+        } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+            malformed = Error::BadRequest("invalid query string".to_string());
+            &malformed
+        } else if let Some((status, reason)) = malformed_request(&err) {
+            return Ok(warp::reply::with_status(reason, status).into_response());
+        } else if let Some(unmatched) = err.find::<Unmatched>() {
+            return Ok(unmatched_reply(unmatched).await);
+        } else {
+            return Err(err);
+        };
+        error!("request failed: {}", e);
+        Ok(
+            warp::reply::with_status(warp::reply::json(&e.to_api_error()), e.status_code())
+                .into_response(),
+        )
+    }
+
+    /// The status and reason of the rejection of a request by a route
+    /// serving its method and path, for what the request holds
+    fn malformed_request(err: &Rejection) -> Option<(warp::http::StatusCode, String)> {
+        use warp::http::StatusCode;
+        if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+            Some((StatusCode::PAYLOAD_TOO_LARGE, e.to_string()))
+        } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+            Some((StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()))
+        } else if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+            Some((StatusCode::LENGTH_REQUIRED, e.to_string()))
+        } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+            Some((StatusCode::BAD_REQUEST, e.to_string()))
+        } else {
+            err.find::<warp::reject::InvalidHeader>()
+                .map(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+        }
+    }
+
+    /// A request no route serves, see `unmatched_handler`
+    #[derive(Debug)]
+    pub struct Unmatched {
+        method: warp::http::Method,
+        path: String,
+        accept: Option<String>,
+    }
+
+    impl warp::reject::Reject for Unmatched {}
+
+    /// Redirect a request of a path that is not canonical to its canonical
+    /// form, see `routes::canonical_path`: `301 Moved Permanently` for GET
+    /// and HEAD, `308 Permanent Redirect` for other methods, so that clients
+    /// send the same request again. CORS preflights are not redirected.
+    pub async fn canonical_redirect_handler(
+        method: warp::http::Method,
+        path: warp::filters::path::FullPath,
+        query: Option<String>,
+    ) -> Result<warp::reply::Response, Rejection> {
+        use warp::http::StatusCode;
+        if method == warp::http::Method::OPTIONS {
+            return Err(warp::reject());
+        }
+        let canonical = routes::canonical_path(path.as_str()).ok_or_else(warp::reject)?;
+        debug!("[Request] {} redirected to {}", path.as_str(), canonical);
+        let status = match method {
+            warp::http::Method::GET | warp::http::Method::HEAD => StatusCode::MOVED_PERMANENTLY,
+            _ => StatusCode::PERMANENT_REDIRECT,
+        };
+        let location = match query {
+            Some(query) => format!("{}?{}", canonical, query),
+            None => canonical,
+        };
+        Ok(warp::http::Response::builder()
+            .status(status)
+            .header("Location", location)
+            .body("".into())
+            .unwrap())
+    }
+
+    /// Reject every request with what `handle_rejection` needs to answer it
+    /// if no other route serves it
+    pub async fn unmatched_handler(
+        method: warp::http::Method,
+        path: warp::filters::path::FullPath,
+        accept: Option<String>,
+    ) -> Result<warp::reply::Response, Rejection> {
+        Err(warp::reject::custom(Unmatched {
+            method,
+            path: path.as_str().to_string(),
+            accept,
+        }))
+    }
+
+    /// Body of `404 Not Found` and `405 Method Not Allowed` answers to
+    /// requests no route serves
+    #[derive(Debug, Serialize)]
+    struct RouteError {
+        error: String,
+        path: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        allow: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        routes: Option<Vec<String>>,
+    }
+
+    /// `405 Method Not Allowed` with an `Allow` header if a builtin route or
+    /// a rule serves the path with other methods only, `404 Not Found` listing
+    /// the builtin paths and the prefixes of the rules otherwise, unless
+    /// `list_routes` is off. In plain text if the client prefers it to JSON.
+    async fn unmatched_reply(unmatched: &Unmatched) -> warp::reply::Response {
+        use warp::http::{HeaderValue, StatusCode};
+        let tm = TASK_MANAGER.read().await.clone();
+        let full_path = unmatched.path.trim_start_matches('/');
+        let (_, path) = tenant::select(tm.config.tenants(), full_path, None);
+        let mut allow: Vec<String> = routes::builtin_methods(full_path)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        if allow.is_empty() {
+            allow = RULE_MATCHER.read().await.methods_of(path);
+            if !allow.is_empty() && tm.config.cors.is_some() {
+                allow.push("OPTIONS".to_string());
+            }
+        }
+        // served with the method, but not with these parameters
+        if allow
+            .iter()
+            .any(|method| method == unmatched.method.as_str())
+        {
+            allow.clear();
+        }
+        let (status, error) = if allow.is_empty() {
+            (
+                StatusCode::NOT_FOUND,
+                "no route serves the path".to_string(),
+            )
+        } else {
+            let error = format!("method {} is not allowed for the path", unmatched.method);
+            (StatusCode::METHOD_NOT_ALLOWED, error)
+        };
+        let routes = if allow.is_empty() && tm.config.list_routes() {
+            let mut routes: Vec<String> = settings::BUILTIN_PATHS
+                .iter()
+                .map(|p| p.to_string())
+                .collect();
+            routes.extend(RULE_MATCHER.read().await.prefixes());
+            Some(routes)
+        } else {
+            None
+        };
+        let body = RouteError {
+            error,
+            path: unmatched.path.clone(),
+            allow,
+            routes,
+        };
+        let accept = unmatched.accept.as_deref().unwrap_or_default();
+        let mut resp = if pep691::quality(accept, &["text/plain"])
+            > pep691::quality(accept, &["application/json"])
+        {
+            let mut text = format!("{}: {}\n", body.error, body.path);
+            if !body.allow.is_empty() {
+                text.push_str(&format!("allowed methods: {}\n", body.allow.join(", ")));
+            }
+            if let Some(routes) = &body.routes {
+                text.push_str(&format!("routes: {}\n", routes.join(", ")));
+            }
+            text.into_response()
+        } else {
+            warp::reply::json(&body).into_response()
+        };
+        *resp.status_mut() = status;
+        if !body.allow.is_empty() {
+            if let Ok(allow) = HeaderValue::from_str(&body.allow.join(", ")) {
+                resp.headers_mut().insert("Allow", allow);
+            }
+        }
+        resp
+    }
+
+    /// Headers exposed to scripts of other origins
+    const CORS_EXPOSED_HEADERS: &str =
+        "Content-Disposition, Content-Length, X-Cache, X-Cache-Id, X-Cache-Age";
+
+    pub async fn preflight_handler(
+        origin: Option<String>,
+        method: Option<String>,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let cors = match TASK_MANAGER.read().await.config.cors.clone() {
+            Some(cors) => cors,
+            None => return Err(warp::reject()),
+        };
+        let methods = cors.methods();
+        let allowed_origin = origin.as_deref().and_then(|o| cors.allow_origin(o));
+        let allowed_method = method
+            .as_deref()
+            .map_or(false, |m| methods.iter().any(|allowed| allowed == m));
+        let resp = match allowed_origin {
+            Some(allowed_origin) if allowed_method => warp::http::Response::builder()
+                .status(warp::http::StatusCode::NO_CONTENT)
+                .header("Access-Control-Allow-Origin", allowed_origin)
+                .header("Access-Control-Allow-Methods", methods.join(", "))
+                .header("Access-Control-Allow-Headers", "Authorization, Range")
+                .header("Access-Control-Max-Age", cors.max_age())
+                .header("Vary", "Origin"),
+            _ => warp::http::Response::builder()
+                .status(warp::http::StatusCode::FORBIDDEN)
+                .header("Vary", "Origin"),
+        };
+        Ok(resp.body("".into()).unwrap())
+    }
+
+    pub async fn cors_handler<T: warp::Reply>(
+        origin: Option<String>,
+        reply: T,
+    ) -> Result<warp::reply::Response, Rejection> {
+        use warp::http::HeaderValue;
+        let mut resp = reply.into_response();
+        let cors = match TASK_MANAGER.read().await.config.cors.clone() {
+            Some(cors) => cors,
+            None => return Ok(resp),
+        };
+        let headers = resp.headers_mut();
+        headers.append("Vary", HeaderValue::from_static("Origin"));
+        let allowed_origin = origin.as_deref().and_then(|o| cors.allow_origin(o));
+        if let Some(Ok(allowed_origin)) = allowed_origin.map(HeaderValue::from_str) {
+            headers.insert("Access-Control-Allow-Origin", allowed_origin);
+            headers.insert(
+                "Access-Control-Expose-Headers",
+                HeaderValue::from_static(CORS_EXPOSED_HEADERS),
+            );
+        }
+        Ok(resp)
+    }
+
+    pub async fn head_fallback_handler(
+        path: String,
+        query: Option<String>,
+        authorization: Option<String>,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let (tenant, path) = tenant::select(tm.config.tenants(), &path, authorization.as_deref());
+        // resolve path to upstream url
+        let resolve_result = resolve_task("HEAD", path, query.as_deref()).await;
+        if resolve_result.is_none() {
+            return Err(warp::reject::not_found());
+        }
+        let mut task = resolve_result.unwrap().0;
+        task.tenant = tenant.map(|tenant| tenant.name.clone());
+        let cached_size = if tm.is_bypassed(task.rule_id) {
+            None
+        } else {
+            tm.cached_size(&task).await
+        };
+        if let Some(size) = cached_size {
+            // the same Content-Length as a GET served from the cache
+            return Ok(warp::http::Response::builder()
+                .header("Content-Length", size)
+                .body("")
+                .unwrap());
+        }
+        if tm.is_offline(task.rule_id) {
+            // answer from the cache without contacting the upstream
+            let (result, _) = tm.resolve_task(&task, None).await;
+            return match result {
+                Ok(_) => Ok(warp::http::Response::builder().body("").unwrap()),
+                Err(Error::OfflineMiss(status)) => Ok(warp::http::Response::builder()
+                    .status(status)
+                    .body("")
+                    .unwrap()),
+                Err(e) => Err(warp::reject::custom(e)),
+            };
+        }
+        let client = tm.upstream_client(&task);
+        let timer = Timer::start(Category::Upstream, "head");
+        let resp = util::make_request(&client, &task.url, true).await;
+        timer.finish(&task.to_key(), None);
+        match resp {
+            Ok(up_resp) => {
+                // create a response and copy headers
+                let resp_builder = up_resp
+                    .headers()
+                    .iter()
+                    .fold(warp::http::Response::builder(), |prev, (key, value)| {
+                        prev.header(key, value)
+                    });
+                Ok(resp_builder.body("").unwrap())
+            }
+            Err(e) => match e {
+                Error::UpstreamRequestError(res) => {
+                    let resp = warp::http::Response::builder()
+                        .status(res.status())
+                        .body("");
+                    Ok(resp.unwrap())
+                }
+                _ => Err(warp::reject::custom(e)),
+            },
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn fallback_handler(
+        full_path: String,
+        query: Option<String>,
+        range: Option<String>,
+        authorization: Option<String>,
+        cache_control: Option<String>,
+        pragma: Option<String>,
+        accept: Option<String>,
+        remote: Option<std::net::SocketAddr>,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let tm = TASK_MANAGER.read().await.clone();
+        let tenants = tm.config.tenants();
+        let (tenant, path) = tenant::select(tenants, &full_path, authorization.as_deref());
+        // `t/<tenant>/` if the tenant is selected by the path
+        let tenant_prefix = &full_path[..full_path.len() - path.len()];
+        let resolved = resolve_task("GET", path, query.as_deref()).await;
+        if resolved.is_none() {
+            return Err(warp::reject());
+        }
+        let (mut task, rule) = resolved.unwrap();
+        let mut refresh = util::requests_no_cache(cache_control.as_deref(), pragma.as_deref());
+        if let (Some(param), Some(query)) = (rule.refresh_param(), query.as_deref()) {
+            let (rest, set) = util::take_query_param(query, param);
+            if rest.as_deref() != Some(query) {
+                // the parameter is neither sent to the upstream nor part of the key
+                let rule_matcher = RULE_MATCHER.read().await;
+                let resolved = rule_matcher.resolve("GET", path, rest.as_deref());
+                task = resolved.ok_or_else(warp::reject)?.0;
+            }
+            refresh |= set;
+        }
+        if let Some(tenant) = tenant {
+            task.tenant = Some(tenant.name.clone());
+            increment_counter!(metric::CNT_TENANT_REQUESTS, "tenant" => tenant.name.clone());
+        }
+        trace!("matched by rule #{}: {}", task.rule_id, rule.pattern());
+        increment_counter!(metric::COUNTER_REQ, "rule" => rule_label(&rule));
+        let pep503 = rule
+            .options
+            .as_ref()
+            .and_then(|o| o.pep503)
+            .unwrap_or(false);
+        // pages of the simple index, served in the representation asked for
+        let index_page = pep503 && path.ends_with('/');
+        if pep503 {
+            // let clients converge on one cache key per project
+            if let Some(canonical) = util::pep503_canonical_path(path) {
+                let location = match &query {
+                    Some(query) => format!("/{}{}?{}", tenant_prefix, canonical, query),
+                    None => format!("/{}{}", tenant_prefix, canonical),
+                };
+                let resp = warp::http::Response::builder()
+                    .status(warp::http::StatusCode::MOVED_PERMANENTLY)
+                    .header("Location", location)
+                    .body("".into())
+                    .unwrap();
+                return Ok(resp);
+            }
+        }
+        task.simple_json = index_page && pep691::prefers_json(accept.as_deref());
+        let quota_client = match &tm.quotas {
+            Some(quotas) => {
+                let client = quotas.client(authorization.as_deref(), remote);
+                match quotas.acquire(&client) {
+                    Ok(None) => Some(client),
+                    Ok(Some(retry_after)) => {
+                        info!("[Request] {} rejected: quota exceeded", client.id);
+                        let e = Error::QuotaExceeded(retry_after);
+                        return Ok(warp::http::Response::builder()
+                            .status(e.status_code())
+                            .header("Retry-After", retry_after)
+                            .header("Content-Type", "application/json")
+                            .body(serde_json::to_string(&e.to_api_error()).unwrap().into())
+                            .unwrap());
+                    }
+                    Err(e) => {
+                        // quotas are soft, requests are served without redis
+                        warn!("failed to check the quotas of {}: {}", client.id, e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        if refresh && !may_refresh(&tm.config, authorization.as_deref()) {
+            debug!(
+                "[Request] {:?}: refresh not allowed, served as usual",
+                &task
+            );
+            refresh = false;
+        }
+        let bypassed = tm.is_bypassed(task.rule_id);
+        let (result, outcome) = if refresh {
+            tm.refresh_task(&task).await
+        } else {
+            tm.resolve_task(&task, range.as_deref()).await
+        };
+        match outcome.status {
+            CacheStatus::Hit | CacheStatus::Stale => {
+                increment_counter!(metric::COUNTER_CACHE_HIT, "rule" => rule_label(&rule))
+            }
+            CacheStatus::Offline => {
+                increment_counter!(metric::CNT_OFFLINE_MISS, "rule" => rule_label(&rule))
+            }
+            _ if bypassed => {
+                increment_counter!(metric::CNT_BYPASSED, "rule" => rule_label(&rule))
+            }
+            _ => increment_counter!(metric::COUNTER_CACHE_MISS, "rule" => rule_label(&rule)),
+        };
+        let mut resp = match result {
+            Ok(data) => {
+                if let TaskResponse::StaleResponse(_) = data {
+                    increment_counter!(metric::CNT_UPSTREAM_FAILURE, "rule" => rule_label(&rule));
+                    increment_counter!(metric::CNT_STALE_SERVED, "rule" => rule_label(&rule));
+                }
+                let mut resp = data.into_response();
+                let content_type = match outcome.content_type {
+                    Some(content_type) => Some(content_type),
+                    None => rule
+                        .options
+                        .as_ref()
+                        .and_then(|o| o.content_type.as_deref()),
+                };
+                if let Some(content_type) = content_type {
+                    resp = warp::reply::with_header(resp, "content-type", content_type)
+                        .into_response();
+                }
+                if tm.config.content_disposition.unwrap_or(true) && tm.is_binary_package(&task) {
+                    let value = util::content_disposition(&task.to_key())
+                        .and_then(|value| warp::http::HeaderValue::from_str(&value).ok());
+                    if let Some(value) = value {
+                        resp.headers_mut().insert("Content-Disposition", value);
+                    }
+                }
+                increment_counter!(metric::COUNTER_REQ_FAILURE, "rule" => rule_label(&rule));
+                resp
+            }
+            Err(e) => {
+                increment_counter!(metric::COUNTER_REQ_FAILURE, "rule" => rule_label(&rule));
+                match e {
+                    Error::UpstreamRequestError(res) => warp::http::Response::builder()
+                        .status(res.status())
+                        .body(res.bytes().await.unwrap().into())
+                        .unwrap(),
+                    // an upstream error remembered by a micro-cache
+                    Error::UpstreamStatus(status) => warp::http::Response::builder()
+                        .status(status)
+                        .body("".into())
+                        .unwrap(),
+                    Error::Overloaded => {
+                        increment_counter!(metric::CNT_REQ_SHED, "rule" => rule_label(&rule));
+                        warp::http::Response::builder()
+                            .status(warp::http::StatusCode::SERVICE_UNAVAILABLE)
+                            .header("Retry-After", RETRY_AFTER_SECS)
+                            .body("".into())
+                            .unwrap()
+                    }
+                    Error::UpstreamUnavailable(_) => {
+                        increment_counter!(metric::CNT_UPSTREAM_FAILURE, "rule" => rule_label(&rule));
+                        let body = serde_json::json!({
+                            "error": "failed to fetch from upstream",
+                            "upstream": &task.url,
+                        });
+                        warp::http::Response::builder()
+                            .status(warp::http::StatusCode::BAD_GATEWAY)
+                            .header("Content-Type", "application/json")
+                            .body(body.to_string().into())
+                            .unwrap()
+                    }
+                    // so that clients back off along with the mirror
+                    Error::UpstreamThrottled(retry_after) => warp::http::Response::builder()
+                        .status(e.status_code())
+                        .header("Retry-After", retry_after)
+                        .header("Content-Type", "application/json")
+                        .body(serde_json::to_string(&e.to_api_error()).unwrap().into())
+                        .unwrap(),
+                    Error::OfflineMiss(status) => warp::http::Response::builder()
+                        .status(status)
+                        .header("Content-Type", "application/json")
+                        .body(serde_json::to_string(&e.to_api_error()).unwrap().into())
+                        .unwrap(),
+                    _ => return Err(warp::reject::custom(e)),
+                }
+            }
+        };
+        if index_page {
+            let vary = warp::http::HeaderValue::from_static("Accept");
+            resp.headers_mut().append("Vary", vary);
+        }
+        if tm.config.cache_status_headers.unwrap_or(true) {
+            outcome.set_headers(resp.headers_mut());
+        }
+        if let (Some(quotas), Some(client)) = (&tm.quotas, quota_client) {
+            let hit = matches!(outcome.status, CacheStatus::Hit | CacheStatus::Stale);
+            if !(hit && quotas.exempt_hits()) {
+                // counted as the body is streamed, until the client disconnects
+                let mut counter = ByteCounter::new(quotas.clone(), client);
+                resp = resp.map(|body| {
+                    warp::hyper::Body::wrap_stream(body.map(move |chunk| {
+                        if let Ok(bytes) = &chunk {
+                            counter.add(bytes.len() as u64);
+                        }
+                        chunk
+                    }))
+                });
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Hop-by-hop headers of upstream responses, not passed through
+    const HOP_BY_HOP_HEADERS: &[&str] = &[
+        "connection",
+        "keep-alive",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "te",
+        "trailer",
+        "transfer-encoding",
+        "upgrade",
+    ];
+
+    pub async fn pass_through_handler(
+        method: warp::http::Method,
+        full_path: String,
+        query: Option<String>,
+        headers: warp::http::HeaderMap,
+        body: impl futures::Stream<Item = Result<impl bytes::Buf, warp::Error>> + Send,
+    ) -> Result<warp::reply::Response, Rejection> {
+        use bytes::BufMut;
+        let method = method.as_str();
+        if !settings::PASS_THROUGH_METHODS.contains(&method) {
+            return Err(warp::reject::not_found());
+        }
+        let tm = TASK_MANAGER.read().await.clone();
+        let authorization = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        let (tenant, path) = tenant::select(tm.config.tenants(), &full_path, authorization);
+        let (mut task, rule) = resolve_task(method, path, query.as_deref())
+            .await
+            .ok_or_else(warp::reject::not_found)?;
+        task.tenant = tenant.map(|tenant| tenant.name.clone());
+        // read the body whole, up to the limit of the rule
+        let limit = rule.max_body_size();
+        let declared = headers
+            .get("content-length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if declared.map_or(false, |len| len > limit) {
+            return Err(warp::reject::custom(Error::BodyTooLarge(limit)));
+        }
+        futures::pin_mut!(body);
+        let mut buf = bytes::BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            let chunk =
+                chunk.map_err(|e| warp::reject::custom(Error::BadRequest(e.to_string())))?;
+            if (buf.len() + chunk.remaining()) as u64 > limit {
+                return Err(warp::reject::custom(Error::BodyTooLarge(limit)));
+            }
+            buf.put(chunk);
+        }
+        increment_counter!(
+            metric::CNT_FORWARDED_REQUESTS,
+            "rule" => rule_label(&rule),
+            "method" => method.to_string()
+        );
+        let res = tm
+            .pass_through(&task, method, &headers, buf.freeze())
+            .await
+            .map_err(warp::reject::custom)?;
+        let builder = res
+            .headers()
+            .iter()
+            .filter(|(name, _)| !HOP_BY_HOP_HEADERS.contains(&name.as_str()))
+            .fold(
+                warp::http::Response::builder().status(res.status()),
+                |builder, (name, value)| builder.header(name, value),
+            );
+        let body = warp::hyper::Body::wrap_stream(res.bytes_stream());
+        Ok(builder.body(body).unwrap())
+    }
+
+    /// Dynamically resolve the task of a request as defined in config file
+    async fn resolve_task(method: &str, path: &str, query: Option<&str>) -> Option<(Task, Rule)> {
+        let rule_matcher = RULE_MATCHER.read().await;
+        // None if no rule matches
+        let (task, rule) = rule_matcher.resolve(method, path, query)?;
+        trace!("matched by rule #{}: {}", task.rule_id, rule.pattern());
+        increment_counter!(metric::COUNTER_REQ, "rule" => rule_label(rule));
+        Some((task, rule.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::Settings;
+    use lazy_static::lazy_static;
+    use warp::http::StatusCode;
+    use warp::test::request;
+    use warp::Filter;
+
+    lazy_static! {
+        /// Held for reading by tests relying on the settings of `TASK_MANAGER`,
+        /// and for writing while they are replaced, as the tests share them
+        static ref SETTINGS_LOCK: RwLock<()> = RwLock::new(());
+    }
+
+    async fn setup() {
+        lazy_static! {
+            /// Initialize logger only once.
+            static ref LOGGER: () = {
+                let mut log_builder = pretty_env_logger::formatted_builder();
+                log_builder
+                    .filter_module("sled", log::LevelFilter::Info)
+                    .filter_level(log::LevelFilter::Trace)
+                    .target(pretty_env_logger::env_logger::Target::Stdout)
+                    .init();
+            };
+        };
+
+        let _ = &LOGGER;
+        let settings = get_settings();
+        let _settings = SETTINGS_LOCK.write().await;
+        TASK_MANAGER.write().await.refresh_config(&settings);
+        let mut global_rule_matcher = RULE_MATCHER.write().await;
+        *global_rule_matcher = RuleMatcher::new(&settings.rules).unwrap();
+    }
+
+    fn get_settings() -> Settings {
+        settings::Settings::new_from("config-test", "app_test").unwrap()
+    }
+
+    fn get_filter_root() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    {
+        routes::build_routes()
+    }
+
+    #[tokio::test]
+    async fn get_pypi_index() {
+        setup().await;
+        let pkg_name = "hello-world";
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path(&format!("/pypi/simple/{}/", pkg_name))
+            .reply(&api)
+            .await;
+        let resp_bytes = resp.body().to_vec();
+        let resp_text = std::str::from_utf8(&resp_bytes).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp
+            .headers()
+            .get("content-type")
+            .unwrap()
             .to_str()
             .unwrap()
             .contains("text/html"));
@@ -385,4 +1896,1447 @@ mod test {
         // target link is replaced successfully
         assert!(resp_text.contains("http://localhost:9001/pypi"));
     }
+
+    #[tokio::test]
+    async fn request_id_header() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path("/api/v1/spec")
+            .header("X-Request-Id", "slow-download-42")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["X-Request-Id"], "slow-download-42");
+        // rejected requests get a new id as well
+        let resp = request()
+            .method("GET")
+            .path("/no/rule/matches")
+            .header("X-Request-Id", "not a valid id")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let id = resp.headers()["X-Request-Id"].to_str().unwrap();
+        assert_eq!(id.len(), 16);
+        assert_ne!(id, "not a valid id");
+    }
+
+    static FAKE_PROVIDER_ZIP: &[u8] = b"PK\x03\x04 fake terraform provider archive";
+
+    /// A fake Terraform provider network mirror listening on port 3002
+    fn fake_terraform_mirror(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get().and(warp::path::tail()).map(|tail: warp::filters::path::Tail| {
+            let body: Vec<u8> = match tail.as_str() {
+                "registry.example.com/hashicorp/random/index.json" => {
+                    br#"{"versions":{"2.0.0":{}}}"#.to_vec()
+                }
+                "registry.example.com/hashicorp/random/2.0.0.json" => {
+                    br#"{"archives":{"linux_amd64":{"url":"http://localhost:3002/registry.example.com/hashicorp/random/terraform-provider-random_2.0.0_linux_amd64.zip","hashes":["h1:fake"]}}}"#.to_vec()
+                }
+                "registry.example.com/hashicorp/random/terraform-provider-random_2.0.0_linux_amd64.zip" => {
+                    FAKE_PROVIDER_ZIP.to_vec()
+                }
+                _ => {
+                    return warp::http::Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(vec![])
+                        .unwrap()
+                }
+            };
+            warp::http::Response::builder().body(body).unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn pypi_index_non_canonical_name_redirect() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path("/pypi/simple/Hello_World/")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get("Location").unwrap(),
+            "/pypi/simple/hello-world/"
+        );
+        // the query is kept
+        let resp = request()
+            .method("GET")
+            .path("/pypi/simple/Hello_World/?format=json")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get("Location").unwrap(),
+            "/pypi/simple/hello-world/?format=json"
+        );
+    }
+
+    #[tokio::test]
+    async fn canonical_paths() {
+        setup().await;
+        let api = get_filter_root();
+        // canonical paths and their keys
+        let flask = ("/pypi/simple/flask/", "https/pypi.org/simple/flask");
+        let wheel = (
+            "/pypi/packages/ab/~user-1.0.whl",
+            "https/files.pythonhosted.org/packages/ab/~user-1.0.whl",
+        );
+        // each variant is redirected to its canonical path, which makes the key
+        for (variant, (canonical, key)) in &[
+            ("/pypi/simple/flask/", flask),
+            ("/pypi/simple/flask", flask),
+            ("/pypi/simple//flask/", flask),
+            ("//pypi/simple/flask//", flask),
+            ("/pypi/simple/%66lask/", flask),
+            ("/pypi/simple//Flask", flask),
+            ("/pypi/packages/ab/~user-1.0.whl", wheel),
+            ("/pypi/packages/ab/%7Euser-1.0.whl", wheel),
+            ("/pypi/packages/ab/%7euser%2D1.0.whl", wheel),
+            ("/pypi/packages//ab/~user-1.0.whl", wheel),
+        ] {
+            let mut path = variant.to_string();
+            for _ in 0..3 {
+                if path == *canonical {
+                    break;
+                }
+                let resp = request().method("GET").path(&path).reply(&api).await;
+                assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY, "{}", path);
+                path = resp.headers()["Location"].to_str().unwrap().to_string();
+            }
+            assert_eq!(path, *canonical, "{}", variant);
+            let rule_matcher = RULE_MATCHER.read().await;
+            let (task, _) = rule_matcher.resolve("GET", &path[1..], None).unwrap();
+            assert_eq!(task.to_key(), *key, "{}", variant);
+        }
+        // keys of tasks made of other paths are canonical too
+        let rule_matcher = RULE_MATCHER.read().await;
+        let (task, _) = rule_matcher
+            .resolve("GET", "pypi/packages/ab//%7euser-1.0.whl", None)
+            .unwrap();
+        assert_eq!(task.to_key(), wheel.1);
+        drop(rule_matcher);
+
+        // builtin routes are canonical without a trailing slash, the query
+        // is kept, and other methods are redirected with theirs
+        for (method, variant, location) in &[
+            ("GET", "/ready/", "/ready"),
+            ("GET", "//api/v1/spec", "/api/v1/spec"),
+            ("GET", "/admin/audit/?limit=1", "/admin/audit?limit=1"),
+            ("POST", "/admin/fetch/", "/admin/fetch"),
+        ] {
+            let status = match *method {
+                "GET" => StatusCode::MOVED_PERMANENTLY,
+                _ => StatusCode::PERMANENT_REDIRECT,
+            };
+            let resp = request().method(method).path(variant).reply(&api).await;
+            assert_eq!(resp.status(), status, "{}", variant);
+            assert_eq!(resp.headers()["Location"], *location, "{}", variant);
+        }
+        // the rest of a snapshot path is the path of a rule
+        let snapshot = "/snapshot/s1/pypi/simple/flask/";
+        assert_eq!(routes::canonical_path(snapshot), None);
+        assert_eq!(routes::canonical_path("/ready"), None);
+    }
+
+    #[tokio::test]
+    async fn terraform_provider_mirror_protocol() {
+        setup().await;
+        let _settings = SETTINGS_LOCK.read().await;
+        tokio::spawn(warp::serve(fake_terraform_mirror()).run(([127, 0, 0, 1], 3002)));
+        let api = get_filter_root();
+
+        let resp = request()
+            .method("GET")
+            .path("/terraform/registry.example.com/hashicorp/random/index.json")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let index: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(index["versions"]["2.0.0"].is_object());
+
+        let resp = request()
+            .method("GET")
+            .path("/terraform/registry.example.com/hashicorp/random/2.0.0.json")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let archive_url = version["archives"]["linux_amd64"]["url"].as_str().unwrap();
+        // archive url points back to the mirror, hashes are untouched
+        assert!(archive_url.starts_with("http://localhost:9001/terraform/"));
+        assert_eq!(version["archives"]["linux_amd64"]["hashes"][0], "h1:fake");
+
+        let archive_path = &archive_url["http://localhost:9001".len()..];
+        let resp = request().method("GET").path(archive_path).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // archive bytes must not be modified
+        assert_eq!(resp.body().as_ref(), FAKE_PROVIDER_ZIP);
+        assert_eq!(resp.headers().get("X-Cache-Id").unwrap(), "policy_lru");
+        // served from the cache once the background download is done
+        let mut x_cache = String::new();
+        for _ in 0..20 {
+            let resp = request().method("GET").path(archive_path).reply(&api).await;
+            x_cache = resp.headers()["X-Cache"].to_str().unwrap().to_string();
+            if x_cache == "HIT" {
+                assert_eq!(resp.body().as_ref(), FAKE_PROVIDER_ZIP);
+                break;
+            }
+            assert_eq!(x_cache, "MISS");
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(x_cache, "HIT");
+    }
+
+    /// A fake upstream of the `inflight-test` rule listening on port 3015,
+    /// answering after a second so that a request holds its in-flight slot
+    fn fake_slow_upstream(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path::param().and_then(|_: String| async {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            Ok::<_, warp::Rejection>(FAKE_PROVIDER_ZIP.to_vec())
+        })
+    }
+
+    /// Requests of the fake upstream of the `cors-test` rule
+    static CORS_UPSTREAM_HITS: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+
+    /// A fake upstream of the `cors-test` rule listening on port 3010
+    fn fake_cors_upstream(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("pkg-1.0-py3-none-any.whl").map(|| {
+            CORS_UPSTREAM_HITS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            FAKE_PROVIDER_ZIP.to_vec()
+        })
+    }
+
+    #[tokio::test]
+    async fn max_inflight_sheds_misses() {
+        setup().await;
+        // bound before the requests, as the test runtime runs one task at a time
+        let (_, server) = warp::serve(fake_slow_upstream()).bind_ephemeral(([127, 0, 0, 1], 3015));
+        tokio::spawn(server);
+        let api = get_filter_root();
+        let paths = ["/inflight-test/slow.bin", "/inflight-test/shed.bin"];
+        // cached by an earlier run
+        for path in &paths {
+            let task = RULE_MATCHER
+                .read()
+                .await
+                .resolve("GET", &path[1..], None)
+                .unwrap()
+                .0;
+            let cache = TASK_MANAGER
+                .read()
+                .await
+                .get_cache_for_cache_rule(task.rule_id)
+                .unwrap();
+            let _ = cache.read().await.remove(&task.to_key()).await;
+        }
+        // takes the only slot of the rule until the upstream answers
+        let slow = tokio::spawn({
+            let api = api.clone();
+            async move { request().method("GET").path(paths[0]).reply(&api).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let resp = request().method("GET").path(paths[1]).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()["Retry-After"], "5");
+        let resp = slow.await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body().as_ref(), FAKE_PROVIDER_ZIP);
+        // the slot is released
+        let resp = request().method("GET").path(paths[1]).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn cache_status_headers_off() {
+        setup().await;
+        let _settings = SETTINGS_LOCK.write().await;
+        let api = get_filter_root();
+        // nothing listens on the upstream of the rule, which fails the request
+        let get = || request().method("GET").path("/offline-test/status.bin");
+        let resp = get().reply(&api).await;
+        assert_eq!(resp.headers()["X-Cache-Id"], "policy_lru");
+        assert!(resp.headers().get("X-Cache").is_some());
+
+        TASK_MANAGER.write().await.config.cache_status_headers = Some(false);
+        let resp = get().reply(&api).await;
+        TASK_MANAGER.write().await.config.cache_status_headers = None;
+        for name in &["X-Cache", "X-Cache-Id", "X-Cache-Age"] {
+            assert!(resp.headers().get(*name).is_none(), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn cors_and_content_disposition() {
+        use std::sync::atomic::Ordering;
+        setup().await;
+        let _settings = SETTINGS_LOCK.read().await;
+        // bound before the requests, as the test runtime runs one task at a time
+        let (_, server) = warp::serve(fake_cors_upstream()).bind_ephemeral(([127, 0, 0, 1], 3010));
+        tokio::spawn(server);
+        let api = get_filter_root();
+        let path = "/cors-test/pkg-1.0-py3-none-any.whl";
+        let origin = "https://notebook.example";
+        // cached by an earlier run
+        let task = RULE_MATCHER
+            .read()
+            .await
+            .resolve("GET", &path[1..], None)
+            .unwrap()
+            .0;
+        let cache = TASK_MANAGER
+            .read()
+            .await
+            .get_cache_for_cache_rule(task.rule_id)
+            .unwrap();
+        let _ = cache.read().await.remove(&task.to_key()).await;
+
+        // preflights are answered without the cache or the upstream
+        let resp = request()
+            .method("OPTIONS")
+            .path(path)
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "GET")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()["Access-Control-Allow-Origin"], origin);
+        assert_eq!(resp.headers()["Access-Control-Allow-Methods"], "GET, HEAD");
+        assert_eq!(resp.headers()["Access-Control-Max-Age"], "600");
+        assert!(resp.headers().get("X-Cache").is_none());
+        for (origin, method) in &[("https://evil.example", "GET"), (origin, "DELETE")] {
+            let resp = request()
+                .method("OPTIONS")
+                .path(path)
+                .header("Origin", *origin)
+                .header("Access-Control-Request-Method", *method)
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            assert!(resp.headers().get("Access-Control-Allow-Origin").is_none());
+        }
+        assert_eq!(CORS_UPSTREAM_HITS.load(Ordering::SeqCst), 0);
+        assert_eq!(TASK_MANAGER.read().await.cached_size(&task).await, None);
+
+        let disposition = "attachment; filename=\"pkg-1.0-py3-none-any.whl\"";
+        let resp = request()
+            .method("GET")
+            .path(path)
+            .header("Origin", origin)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["X-Cache"], "MISS");
+        assert_eq!(resp.headers()["Access-Control-Allow-Origin"], origin);
+        assert_eq!(resp.headers()["Vary"], "Origin");
+        assert_eq!(resp.headers()["Content-Disposition"], disposition);
+        let mut x_cache = String::new();
+        for _ in 0..20 {
+            let resp = request()
+                .method("GET")
+                .path(path)
+                .header("Origin", origin)
+                .reply(&api)
+                .await;
+            x_cache = resp.headers()["X-Cache"].to_str().unwrap().to_string();
+            if x_cache == "HIT" {
+                assert_eq!(resp.headers()["Access-Control-Allow-Origin"], origin);
+                assert_eq!(resp.headers()["Content-Disposition"], disposition);
+                assert_eq!(resp.body().as_ref(), FAKE_PROVIDER_ZIP);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(x_cache, "HIT");
+
+        // other origins get no CORS headers
+        let resp = request()
+            .method("GET")
+            .path(path)
+            .header("Origin", "https://evil.example")
+            .reply(&api)
+            .await;
+        assert!(resp.headers().get("Access-Control-Allow-Origin").is_none());
+        assert_eq!(resp.headers()["Content-Disposition"], disposition);
+    }
+
+    /// A fake upstream of the `post-test` rule listening on port 3014, echoing
+    /// the bodies of POST requests
+    fn fake_echo_upstream(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::bytes())
+            .map(
+                |content_type: Option<String>,
+                 authorization: Option<String>,
+                 body: bytes::Bytes| {
+                    warp::http::Response::builder()
+                        .status(StatusCode::CREATED)
+                        .header("Content-Type", content_type.unwrap_or_default())
+                        .header("X-Authorization", authorization.unwrap_or_default())
+                        .body(body)
+                        .unwrap()
+                },
+            )
+    }
+
+    #[tokio::test]
+    async fn pass_through_post() {
+        setup().await;
+        // bound before the requests, as the test runtime runs one task at a time
+        let (_, server) = warp::serve(fake_echo_upstream()).bind_ephemeral(([127, 0, 0, 1], 3014));
+        tokio::spawn(server);
+        let api = get_filter_root();
+        let path = "/post-test/-/npm/v1/security/advisories/bulk";
+        let body = r#"{"lodash":["4.17.20"]}"#;
+        let resp = request()
+            .method("POST")
+            .path(path)
+            .header("Content-Type", "application/json")
+            .header("Authorization", "Bearer mirror-token")
+            .body(body)
+            .reply(&api)
+            .await;
+        // status, headers and body of the upstream are kept
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers()["Content-Type"], "application/json");
+        assert_eq!(resp.body(), body);
+        // the authorization of the mirror is not forwarded
+        assert_eq!(resp.headers()["X-Authorization"], "");
+        assert!(resp.headers().get("X-Cache").is_none());
+        // never cached
+        let task = RULE_MATCHER
+            .read()
+            .await
+            .resolve("POST", &path[1..], None)
+            .unwrap()
+            .0;
+        let cache = TASK_MANAGER
+            .read()
+            .await
+            .get_cache_for_cache_rule(task.rule_id)
+            .unwrap();
+        assert!(cache.read().await.get(&task.to_key()).await.is_none());
+
+        // bodies over `max_body_size`
+        let resp = request()
+            .method("POST")
+            .path(path)
+            .body(vec![b'x'; 2048])
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // methods not allowed by the rule, or by any rule
+        let resp = request().method("PUT").path(path).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let resp = request()
+            .method("POST")
+            .path("/cors-test/pkg-1.0-py3-none-any.whl")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn unmatched_requests() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path("/no/rule/matches")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["error"], "no route serves the path");
+        assert_eq!(body["path"], "/no/rule/matches");
+        assert!(body.get("allow").is_none());
+        let routes = body["routes"].as_array().unwrap();
+        assert_eq!(routes[0], "admin/");
+        assert!(routes.iter().any(|route| route == "cors-test/"));
+        // builtin paths with other methods
+        let resp = request().method("POST").path("/ready").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()["Allow"], "GET");
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["allow"], serde_json::json!(["GET"]));
+        assert!(body.get("routes").is_none());
+        let resp = request()
+            .method("PATCH")
+            .path("/admin/offline")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()["Allow"], "GET, PUT, DELETE");
+        // rule paths with other methods, OPTIONS included with `cors`
+        let resp = request()
+            .method("PUT")
+            .path("/cors-test/pkg-1.0-py3-none-any.whl")
+            .header("Accept", "text/plain, application/json;q=0.5")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()["Allow"], "GET, HEAD, OPTIONS");
+        assert_eq!(
+            resp.body(),
+            "method PUT is not allowed for the path: /cors-test/pkg-1.0-py3-none-any.whl\n\
+             allowed methods: GET, HEAD, OPTIONS\n"
+        );
+        // served with the method, but not with these parameters
+        let resp = request()
+            .method("GET")
+            .path("/admin/jobs/abc")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(resp.headers().get("Allow").is_none());
+    }
+
+    #[tokio::test]
+    async fn builtin_routes_registry() {
+        setup().await;
+        let api = get_filter_root();
+        for (method, route) in routes::BUILTIN_ROUTES {
+            let path = route
+                .replace("{}", "1")
+                .replace("**", "cors-test/index.html");
+            let resp = request()
+                .method(method)
+                .path(&format!("/{}", path))
+                .reply(&api)
+                .await;
+            let body = String::from_utf8_lossy(resp.body());
+            assert_ne!(
+                resp.status(),
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{} {}",
+                method,
+                path
+            );
+            assert!(!body.contains("no route serves"), "{} {}", method, path);
+        }
+        assert!(routes::is_builtin_path("ready"));
+        assert!(routes::is_builtin_path("snapshot/1/"));
+        assert!(!routes::is_builtin_path("ready-to-go"));
+        assert!(!routes::is_builtin_path("admins/"));
+    }
+
+    #[tokio::test]
+    async fn request_limits() {
+        setup().await;
+        let api = get_filter_root();
+        let long = format!("/pypi/simple/{}", "a".repeat(4096));
+        let resp = request().method("GET").path(&long).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::URI_TOO_LONG);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["error"], "request path too long");
+        // the query counts as well, for builtin routes too
+        let query = format!("/ready?{}", "a".repeat(4096));
+        let resp = request().method("GET").path(&query).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::URI_TOO_LONG);
+        let resp = request()
+            .method("GET")
+            .path("/ready")
+            .header("x-padding", "a".repeat(8192))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        let resp = request().method("GET").path("/ready").reply(&api).await;
+        assert_ne!(resp.status(), StatusCode::URI_TOO_LONG);
+        assert_ne!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn error_problem_body_hides_internal_details() {
+        use crate::error::Error;
+        use warp::Reply;
+        let err = Error::RedisUnavailable(redis::RedisError::from((
+            redis::ErrorKind::IoError,
+            "failed to connect",
+            "redis://:secret@localhost:3001".to_string(),
+        )));
+        let resp = handlers::handle_rejection(warp::reject::custom(err))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = warp::hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "cache metadata database is unavailable");
+        assert!(!body.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn admin_audit_requires_token() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path("/admin/audit")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = request()
+            .method("GET")
+            .path("/admin/audit")
+            .header("Authorization", "Bearer wrong-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn admin_audit_read_entries() {
+        use crate::audit::{AuditEntry, Outcome};
+        let _ = std::fs::remove_dir_all("cache/test/audit");
+        setup().await;
+        let audit = TASK_MANAGER.read().await.audit.clone().unwrap();
+        let timestamp = util::now();
+        for key in &["a.whl", "b.whl"] {
+            audit
+                .record(&AuditEntry {
+                    timestamp,
+                    principal: "ops".to_string(),
+                    operation: "purge".to_string(),
+                    targets: vec![key.to_string()],
+                    outcome: Outcome::Success,
+                })
+                .unwrap();
+        }
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path(&format!("/admin/audit?since={}&limit=1", timestamp))
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(body["entries"][0]["principal"], "ops");
+        let next_offset = body["next_offset"].as_u64().unwrap();
+        let resp = request()
+            .method("GET")
+            .path(&format!(
+                "/admin/audit?since={}&offset={}",
+                timestamp, next_offset
+            ))
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(!body["entries"].as_array().unwrap().is_empty());
+        assert!(body["next_offset"].is_null());
+    }
+
+    #[tokio::test]
+    async fn admin_purge_bad_requests() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("DELETE")
+            .path("/admin/cache/policy_lru/entries?pattern=flask-*")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        for (path, status) in &[
+            (
+                "/admin/cache/policy_lru/entries?pattern=a&regex=b",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/admin/cache/policy_lru/entries?regex=flask-(",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/admin/cache/no_such_policy/entries?pattern=flask-*",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = request()
+                .method("DELETE")
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), *status, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_purge_all_bad_requests() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("DELETE")
+            .path("/admin/cache/policy_lru?confirm=policy_lru")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        for (path, status) in &[
+            ("/admin/cache/policy_lru", StatusCode::BAD_REQUEST),
+            (
+                "/admin/cache/policy_lru?confirm=policy_ttl",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/admin/cache/no_such_policy?confirm=no_such_policy",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = request()
+                .method("DELETE")
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), *status, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_usage_by_group() {
+        setup().await;
+        let cache = TASK_MANAGER
+            .read()
+            .await
+            .get_cache_for_policy("policy_lru")
+            .unwrap();
+        for (key, size) in &[
+            ("usage_test/torch-1.0.whl", 30),
+            ("usage_test/torch-2.0.whl", 20),
+            ("usage_test/flask-2.0.whl", 5),
+        ] {
+            cache.write().await.put(key, vec![1; *size].into()).await;
+        }
+        let api = get_filter_root();
+        for (path, status) in &[
+            ("/admin/cache/policy_lru/usage", StatusCode::BAD_REQUEST),
+            (
+                "/admin/cache/policy_lru/usage?group_by_prefix=0",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/admin/cache/no_such_policy/usage?group_by_prefix=1",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = request()
+                .method("GET")
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), *status, "{}", path);
+        }
+        // ^usage_test/([a-z]+)-
+        let path = "/admin/cache/policy_lru/usage?group_regex=%5Eusage_test%2F%28%5Ba-z%5D%2B%29-&top=1000";
+        let resp = request().method("GET").path(path).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = request()
+            .method("GET")
+            .path(path)
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(report["cache"], "policy_lru");
+        let groups = report["groups"].as_array().unwrap();
+        assert_eq!(
+            groups[0],
+            serde_json::json!({"group": "torch", "count": 2, "bytes": 50})
+        );
+        assert_eq!(
+            groups[1],
+            serde_json::json!({"group": "flask", "count": 1, "bytes": 5})
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_offline() {
+        setup().await;
+        let _settings = SETTINGS_LOCK.read().await;
+        let api = get_filter_root();
+        let admin = |method: &str, path: &str| {
+            request()
+                .method(method)
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+        };
+        let resp = request()
+            .method("PUT")
+            .path("/admin/offline?enabled=true&rule=offline-test")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        for (method, path, status) in &[
+            (
+                "PUT",
+                "/admin/offline?rule=offline-test",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "PUT",
+                "/admin/offline?enabled=true&rule=no-such-rule",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "DELETE",
+                "/admin/offline?rule=no-such-rule",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = admin(method, path).reply(&api).await;
+            assert_eq!(resp.status(), *status, "{} {}", method, path);
+        }
+
+        let resp = admin("PUT", "/admin/offline?enabled=true&rule=offline-test")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(status["rules"]["offline-test"], true);
+        assert_eq!(status["overrides"]["rules"]["offline-test"], true);
+
+        // misses are not fetched from the unavailable upstream
+        let resp = request()
+            .method("GET")
+            .path("/offline-test/missing.bin")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()["X-Cache"], "OFFLINE");
+        let problem: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(problem["error"], "not cached");
+        let resp = request()
+            .method("HEAD")
+            .path("/offline-test/missing.bin")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = admin("DELETE", "/admin/offline?rule=offline-test")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = admin("GET", "/admin/offline").reply(&api).await;
+        let status: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(status["rules"]["offline-test"], false);
+        assert!(status["overrides"]["rules"]["offline-test"].is_null());
+    }
+
+    #[tokio::test]
+    async fn admin_bypass() {
+        setup().await;
+        let api = get_filter_root();
+        let admin = |method: &str, path: &str| {
+            request()
+                .method(method)
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+        };
+        let resp = request()
+            .method("POST")
+            .path("/admin/bypass?enabled=true&rule=offline-test")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        for (path, status) in &[
+            ("/admin/bypass?rule=offline-test", StatusCode::BAD_REQUEST),
+            (
+                "/admin/bypass?enabled=true&rule=no-such-rule",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = admin("POST", path).reply(&api).await;
+            assert_eq!(resp.status(), *status, "{}", path);
+        }
+
+        let resp = admin("POST", "/admin/bypass?enabled=true&rule=offline-test")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(status["rules"]["offline-test"], true);
+        assert_eq!(status["global"], false);
+        assert_eq!(status["persisted"], false);
+
+        let resp = admin("POST", "/admin/bypass?enabled=false&rule=offline-test")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = admin("GET", "/admin/bypass").reply(&api).await;
+        let status: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(status["rules"]["offline-test"], false);
+    }
+
+    #[tokio::test]
+    async fn quota_exceeded_then_recovered() {
+        setup().await;
+        let api = get_filter_root();
+        let get = || {
+            request()
+                .method("GET")
+                .path("/offline-test/quota.bin")
+                .header("Authorization", "Bearer quota-test-token")
+        };
+        // the token is limited to 2 requests in a window of 2 secs
+        for _ in 0..2 {
+            let resp = get().reply(&api).await;
+            assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        let resp = get().reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = resp.headers()["Retry-After"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after >= 1 && retry_after <= 2);
+        let problem: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(problem["error"], "quota exceeded");
+
+        let resp = request()
+            .method("GET")
+            .path("/admin/quotas")
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let usage = report["clients"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|u| u["client"] == "token:quota-test")
+            .unwrap();
+        assert_eq!(usage["limits"]["requests"], 2);
+
+        tokio::time::sleep(std::time::Duration::from_secs(retry_after)).await;
+        let resp = get().reply(&api).await;
+        assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn admin_upstreams() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path("/admin/upstreams")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = request()
+            .method("GET")
+            .path("/admin/upstreams")
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(body["upstreams"].is_array());
+    }
+
+    #[tokio::test]
+    async fn admin_fetch() {
+        setup().await;
+        let api = get_filter_root();
+        let post = |spec: &str| {
+            request()
+                .method("POST")
+                .path("/admin/fetch")
+                .header("Authorization", "Bearer test-admin-token")
+                .body(
+                    serde_json::to_vec(&fetch::FetchRequest {
+                        spec: spec.to_string(),
+                    })
+                    .unwrap(),
+                )
+        };
+        for spec in &["numpy", "https://no-such-rule.corp/a.tar.gz"] {
+            let resp = post(spec).reply(&api).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", spec);
+        }
+        // nothing listens on the upstream of the rule
+        let resp = post("http://127.0.0.1:3009/fetch.bin").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: fetch::FetchReport = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(
+            report.files[0].key.as_deref(),
+            Some("http/127.0.0.1:3009/fetch.bin")
+        );
+        assert_eq!(report.failed(), 1);
+    }
+
+    #[tokio::test]
+    async fn admin_config_redacts_secrets() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request()
+            .method("GET")
+            .path("/admin/config")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = request()
+            .method("GET")
+            .path("/admin/config")
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(!body.contains("test-admin-token"));
+        assert!(!body.contains("quota-test-token"));
+        let config: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(config["settings"]["admin_tokens"][0]["token"], "***");
+        assert_eq!(config["settings"]["redis"]["url"], "***");
+        let settings = get_settings();
+        assert_eq!(
+            config["rules"].as_array().unwrap().len(),
+            settings.rules.len()
+        );
+        assert_eq!(config["rules"][0]["policy"], settings.rules[0].policy);
+    }
+
+    #[test]
+    fn force_refresh_authorization() {
+        use crate::secret::Secret;
+        use crate::settings::{AdminToken, ForceRefresh};
+        let mut config = Settings::default();
+        let admin = Some("Bearer s3cr3t");
+        // off by default
+        assert!(!handlers::may_refresh(&config, admin));
+        config.force_refresh = Some(ForceRefresh::default());
+        assert!(handlers::may_refresh(&config, None));
+        config.force_refresh = Some(ForceRefresh {
+            require_admin_token: Some(true),
+        });
+        config.admin_tokens = Some(vec![AdminToken {
+            label: "ci".to_string(),
+            token: Secret::new("s3cr3t".to_string()),
+        }]);
+        assert!(handlers::may_refresh(&config, admin));
+        assert!(!handlers::may_refresh(&config, None));
+        assert!(!handlers::may_refresh(&config, Some("Bearer guess")));
+    }
+
+    #[tokio::test]
+    async fn admin_eviction_preview() {
+        setup().await;
+        let cache = TASK_MANAGER
+            .read()
+            .await
+            .get_cache_for_policy("policy_lru")
+            .unwrap();
+        let keys = [
+            "eviction_preview_test/a.whl",
+            "eviction_preview_test/b.whl",
+            "eviction_preview_test/c.whl",
+        ];
+        for key in &keys {
+            cache.write().await.put(key, vec![1; 7].into()).await;
+        }
+        let api = get_filter_root();
+        for (path, status) in &[
+            (
+                "/admin/cache/policy_lru/eviction-preview",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/admin/cache/policy_ttl/eviction-preview?target_size=0",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/admin/cache/no_such_policy/eviction-preview?target_size=0",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = request()
+                .method("GET")
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), *status, "{}", path);
+        }
+        let path = "/admin/cache/policy_lru/eviction-preview?target_size=0&limit=1000";
+        let resp = request().method("GET").path(path).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = request()
+            .method("GET")
+            .path(path)
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let preview: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        // everything would be evicted, other tests may use the cache as well
+        let entries = preview["entries"].as_array().unwrap();
+        assert_eq!(preview["count"].as_u64().unwrap(), entries.len() as u64);
+        let previewed: Vec<&str> = entries
+            .iter()
+            .map(|entry| entry["key"].as_str().unwrap())
+            .filter(|key| key.starts_with("eviction_preview_test/"))
+            .collect();
+        assert_eq!(previewed, keys);
+        assert!(entries.iter().all(|entry| entry["size"].is_u64()));
+
+        let path = "/admin/cache/policy_lru/eviction-preview?target_size=0&limit=1";
+        let resp = request()
+            .method("GET")
+            .path(path)
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        let page: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(page["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(page["next_offset"], 1);
+        // nothing is removed
+        for key in &keys {
+            assert!(cache.read().await.get(key).await.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_contains() {
+        setup().await;
+        let cache = TASK_MANAGER
+            .read()
+            .await
+            .get_cache_for_policy("policy_lru")
+            .unwrap();
+        cache
+            .write()
+            .await
+            .put("contains_test/a.whl", vec![1].into())
+            .await;
+        let api = get_filter_root();
+        let contains = |policy: &str, body: String| {
+            request()
+                .method("POST")
+                .path(&format!("/admin/cache/{}/contains", policy))
+                .header("Authorization", "Bearer test-admin-token")
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+        let body = r#"["contains_test/a.whl", "contains_test/b.whl"]"#.to_string();
+        let resp = contains("policy_lru", body.clone()).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let found: Vec<bool> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(found, vec![true, false]);
+        let resp = contains("no_such_policy", body).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let too_many = serde_json::to_string(&vec!["a.whl"; 1001]).unwrap();
+        let resp = contains("policy_lru", too_many).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = request()
+            .method("POST")
+            .path("/admin/cache/policy_lru/contains")
+            .body("[]")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn admin_snapshots() {
+        setup().await;
+        let api = get_filter_root();
+        let admin = |method: &str, path: &str| {
+            request()
+                .method(method)
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+        };
+        let resp = admin("GET", "/admin/snapshots").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let _: Vec<snapshot::SnapshotInfo> = serde_json::from_slice(resp.body()).unwrap();
+        for body in &[
+            // of an LRU policy
+            r#"{"rules": ["offline-test"]}"#,
+            r#"{"id": "../snapshot", "rules": ["offline-test"]}"#,
+            r#"{"rules": []}"#,
+        ] {
+            let resp = admin("POST", "/admin/snapshots")
+                .header("Content-Type", "application/json")
+                .body(*body)
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        for method in &["GET", "DELETE"] {
+            let resp = admin(method, "/admin/snapshots/no-such-snapshot")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+        let resp = request()
+            .path("/snapshot/no-such-snapshot/pypi/simple/flask/")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = request().path("/admin/snapshots").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn admin_purge_by_pattern() {
+        setup().await;
+        let cache = TASK_MANAGER
+            .read()
+            .await
+            .get_cache_for_policy("policy_lru")
+            .unwrap();
+        let keys = [
+            "purge_test/flask-1.0.whl",
+            "purge_test/flask-2.0.whl",
+            "purge_test/django-3.0.whl",
+        ];
+        for key in &keys {
+            cache.write().await.put(key, vec![1; 4].into()).await;
+        }
+        let api = get_filter_root();
+        let resp = request()
+            .method("DELETE")
+            .path("/admin/cache/policy_lru/entries?pattern=purge_test/flask-*")
+            .header("Authorization", "Bearer test-admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        let mut job = serde_json::Value::Null;
+        for _ in 0..50 {
+            let resp = request()
+                .method("GET")
+                .path(location)
+                .header("Authorization", "Bearer test-admin-token")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            job = serde_json::from_slice(resp.body()).unwrap();
+            if job["state"] != "running" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(job["state"], "completed");
+        assert_eq!(job["removed"], 2);
+        assert_eq!(job["targets"][0], "policy_lru/purge_test/flask-*");
+        let cache = cache.read().await;
+        assert!(cache.get(keys[0]).await.is_none());
+        assert!(cache.get(keys[1]).await.is_none());
+        assert!(cache.get(keys[2]).await.is_some());
+    }
+
+    /// Follow the location of a job until it is finished
+    async fn wait_for_job(location: &str) -> serde_json::Value {
+        let api = get_filter_root();
+        let mut job = serde_json::Value::Null;
+        for _ in 0..50 {
+            let resp = request()
+                .method("GET")
+                .path(location)
+                .header("Authorization", "Bearer test-admin-token")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            job = serde_json::from_slice(resp.body()).unwrap();
+            if job["state"] != "running" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        job
+    }
+
+    #[tokio::test]
+    async fn api_v1_management() {
+        setup().await;
+        let cache = TASK_MANAGER
+            .read()
+            .await
+            .get_cache_for_policy("policy_lru")
+            .unwrap();
+        for (key, size) in &[("api_test/a.whl", 3), ("api_test/b.whl", 4)] {
+            cache.write().await.put(key, vec![1; *size].into()).await;
+        }
+        let api = get_filter_root();
+        let admin = |method: &str, path: &str| {
+            request()
+                .method(method)
+                .path(path)
+                .header("Authorization", "Bearer test-admin-token")
+        };
+
+        // the spec is public
+        let resp = request()
+            .method("GET")
+            .path("/api/v1/spec")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let spec: api::ApiSpec = serde_json::from_slice(resp.body()).unwrap();
+        assert!(spec
+            .endpoints
+            .iter()
+            .any(|e| e.method == "DELETE" && e.path == "/caches/{cache}/entries"));
+
+        let resp = request()
+            .method("GET")
+            .path("/api/v1/caches/policy_lru/stats")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let error: api::ApiError = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(error.error, "missing or invalid admin token");
+        let resp = admin("GET", "/api/v1/caches/policy_lru/stats")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stats: api::CacheStats = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(stats.policy_type, "LRU");
+        assert_eq!(stats.size_limit, Some(1073741824));
+        assert!(stats.current_entries.is_some());
+        assert_eq!(stats.max_entries, None);
+        // sled metadata
+        assert_eq!(stats.redis_memory, None);
+        assert_eq!(stats.max_redis_memory, None);
+
+        // all pages of the listing
+        let mut listed = Vec::new();
+        let mut path = "/api/v1/caches/policy_lru/entries?limit=1000".to_string();
+        loop {
+            let resp = admin("GET", &path).reply(&api).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let page: api::EntryPage = serde_json::from_slice(resp.body()).unwrap();
+            listed.extend(
+                page.entries
+                    .into_iter()
+                    .filter(|entry| entry.key.starts_with("api_test/")),
+            );
+            match page.next_cursor {
+                Some(cursor) => {
+                    path = format!(
+                        "/api/v1/caches/policy_lru/entries?limit=1000&cursor={}",
+                        cursor
+                    )
+                }
+                None => break,
+            }
+        }
+        listed.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            listed,
+            vec![
+                api::EntryInfo {
+                    key: "api_test/a.whl".to_string(),
+                    size: 3,
+                    url: None,
+                },
+                api::EntryInfo {
+                    key: "api_test/b.whl".to_string(),
+                    size: 4,
+                    url: None,
+                },
+            ]
+        );
+
+        for (policy, body, status) in &[
+            ("policy_lru", r#"{"key": "api_test/a.whl"}"#, StatusCode::OK),
+            (
+                "policy_lru",
+                r#"{"key": "api_test/c.whl"}"#,
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "policy_ttl",
+                r#"{"key": "api_test/a.whl"}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "policy_lru",
+                r#"{"path": "api_test/a.whl"}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            let resp = admin("PUT", &format!("/api/v1/caches/{}/pins", policy))
+                .body(body)
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), *status, "{} {}", policy, body);
+            if *status != StatusCode::OK {
+                // errors of the API share a body
+                let _: api::ApiError = serde_json::from_slice(resp.body()).unwrap();
+            }
+        }
+
+        let resp = admin(
+            "DELETE",
+            "/api/v1/caches/policy_lru/entries?pattern=api_test/b.*",
+        )
+        .reply(&api)
+        .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let accepted: api::JobAccepted = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(accepted.location, format!("/api/v1/jobs/{}", accepted.job));
+        let job = wait_for_job(&accepted.location).await;
+        assert_eq!(job["state"], "completed");
+        assert_eq!(job["removed"], 1);
+        assert!(cache.read().await.get("api_test/b.whl").await.is_none());
+        let resp = admin("GET", "/api/v1/jobs").reply(&api).await;
+        let jobs: api::JobList = serde_json::from_slice(resp.body()).unwrap();
+        assert!(jobs.jobs.iter().any(|job| job.id == accepted.job));
+
+        let resp = admin("POST", "/api/v1/warmup")
+            .body(r#"{"paths": ["offline-test/warm.bin", "no-such-rule/warm.bin"]}"#)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = admin("POST", "/api/v1/warmup")
+            .body(r#"{"paths": ["/offline-test/warm.bin"]}"#)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let accepted: api::JobAccepted = serde_json::from_slice(resp.body()).unwrap();
+        let job = wait_for_job(&accepted.location).await;
+        assert_eq!(job["operation"], "warmup");
+        assert_eq!(job["scanned"], 1);
+        assert_eq!(job["targets"][0], "http://127.0.0.1:3009/warm.bin");
+
+        let resp = admin("GET", "/api/v1/tasks").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let _: api::TaskList = serde_json::from_slice(resp.body()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ready_with_redis_and_storages() {
+        setup().await;
+        let api = get_filter_root();
+        let resp = request().method("GET").path("/ready").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["failed"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn unmatched_path_is_not_recovered() {
+        assert!(handlers::handle_rejection(warp::reject::not_found())
+            .await
+            .is_err());
+    }
 }
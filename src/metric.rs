@@ -13,7 +13,56 @@ pub static CNT_OUT_REQUESTS_SUCCESS: &str = "outbound_requests_success";
 pub static CNT_OUT_REQUESTS_FAILURE: &str = "outbound_requests_failure";
 pub static HG_TASKS_LEN: &str = "current_download_tasks";
 pub static HG_CACHE_SIZE_PREFIX: &str = "cache_size";
+pub static HG_CACHE_ENTRIES_PREFIX: &str = "cache_entries";
 pub static CNT_RM_FILES: &str = "files_removed";
+pub static CNT_EVICTED_ENTRIES: &str = "evicted_entries";
+pub static CNT_EVICTED_BYTES: &str = "evicted_bytes";
+pub static HG_EVICTED_ENTRY_AGE: &str = "evicted_entry_age";
+pub static CNT_REQ_SHED: &str = "requests_shed";
+pub static GAUGE_INFLIGHT_REQ: &str = "inflight_upstream_requests";
+pub static GAUGE_BG_TASKS_ACTIVE: &str = "background_tasks_active";
+pub static GAUGE_BG_TASKS_QUEUED: &str = "background_tasks_queued";
+pub static GAUGE_REDIS_MEMORY: &str = "redis_memory_bytes";
+pub static CNT_UPSTREAM_FAILURE: &str = "upstream_failures";
+pub static CNT_STALE_SERVED: &str = "stale_on_error_served";
+pub static CNT_UNCACHEABLE: &str = "uncacheable_responses";
+pub static GAUGE_USAGE_GROUP_BYTES: &str = "usage_group_bytes";
+pub static GAUGE_USAGE_GROUP_ENTRIES: &str = "usage_group_entries";
+pub static CNT_TEMP_FILES_REMOVED: &str = "temp_files_removed";
+pub static CNT_TEMP_BYTES_RECLAIMED: &str = "temp_bytes_reclaimed";
+pub static CNT_OFFLINE_MISS: &str = "offline_misses";
+pub static CNT_BYPASSED: &str = "bypassed_requests";
+pub static CNT_PROTECTED_ENTRIES: &str = "protected_entries";
+pub static CNT_PROTECTED_BYTES: &str = "protected_bytes";
+pub static CNT_UNPROTECTED_ENTRIES: &str = "unprotected_cold_entries";
+pub static CNT_CORRUPT_ENTRIES: &str = "corrupt_cache_entries";
+pub static CNT_UNCHANGED_PUTS: &str = "unchanged_puts";
+pub static CNT_ADMISSION_ADMITTED: &str = "admission_admitted";
+pub static CNT_ADMISSION_REJECTED: &str = "admission_rejected";
+pub static CNT_PASSTHROUGH_GETS: &str = "passthrough_gets";
+pub static CNT_PASSTHROUGH_PUTS: &str = "passthrough_puts";
+pub static CNT_FORWARDED_REQUESTS: &str = "forwarded_requests";
+pub static CNT_MICRO_CACHE_HITS: &str = "micro_cache_hits";
+pub static CNT_MICRO_CACHE_NEGATIVE_HITS: &str = "micro_cache_negative_hits";
+pub static CNT_HOT_KEY_HITS: &str = "hot_key_hits";
+pub static CNT_REVALIDATIONS: &str = "lru_revalidations";
+pub static CNT_FORCED_REFRESHES: &str = "forced_refreshes";
+pub static CNT_REWRITES_SKIPPED: &str = "rewrites_skipped";
+pub static CNT_METADATA_PREFETCHES: &str = "metadata_prefetches";
+pub static CNT_DOWNLOAD_RESUMES: &str = "download_resumes";
+pub static CNT_TENANT_REQUESTS: &str = "tenant_requests";
+pub static HG_REMOVAL_LATENCY: &str = "eviction_removal_latency";
+pub static GAUGE_ORPHAN_FILES: &str = "orphaned_files";
+pub static GAUGE_DISK_BYTES_ACTUAL: &str = "cache_disk_bytes_actual";
+pub static GAUGE_ACCOUNTING_DRIFT: &str = "cache_accounting_drift_bytes";
+pub static HG_REDIS_LATENCY: &str = "redis_latency";
+pub static HG_STORAGE_LATENCY: &str = "storage_latency";
+pub static HG_FSYNC_LATENCY: &str = "fsync_latency";
+pub static HG_UPSTREAM_LATENCY: &str = "upstream_latency";
+pub static GAUGE_UPSTREAM_STATUS: &str = "upstream_status";
+pub static GAUGE_UPSTREAM_ERROR_RATE: &str = "upstream_error_rate";
+pub static GAUGE_UPSTREAM_P95_LATENCY: &str = "upstream_p95_latency";
+pub static CNT_UPSTREAM_COOLDOWNS: &str = "upstream_cooldowns";
 
 pub fn register_counters() {
     register_counter!(
@@ -43,8 +92,177 @@ pub fn register_counters() {
         "The current size of background download task set.",
     );
     register_counter!(CNT_RM_FILES, "The number of removed files.");
+    register_counter!(
+        CNT_EVICTED_ENTRIES,
+        "The number of entries evicted from LRU caches."
+    );
+    register_counter!(
+        CNT_EVICTED_BYTES,
+        "The number of bytes evicted from LRU caches."
+    );
+    register_counter!(
+        CNT_TEMP_FILES_REMOVED,
+        "The number of stale temporary files removed at startup."
+    );
+    register_counter!(
+        CNT_TEMP_BYTES_RECLAIMED,
+        "The number of bytes of stale temporary files removed at startup."
+    );
+    register_counter!(
+        CNT_PROTECTED_ENTRIES,
+        "The number of cold LRU entries kept by protective refreshes."
+    );
+    register_counter!(
+        CNT_PROTECTED_BYTES,
+        "The number of bytes of cold LRU entries kept by protective refreshes."
+    );
+    register_counter!(
+        CNT_UNPROTECTED_ENTRIES,
+        "The number of cold LRU entries left to be evicted by protective refreshes."
+    );
+    register_counter!(
+        CNT_CORRUPT_ENTRIES,
+        "The number of cache entries removed because their size does not match the recorded one."
+    );
+    register_counter!(
+        CNT_UNCHANGED_PUTS,
+        "The number of LRU entries put again with the same content, whose files are kept."
+    );
+    register_counter!(
+        CNT_PASSTHROUGH_GETS,
+        "The number of lookups of rules with a NONE policy."
+    );
+    register_counter!(
+        CNT_PASSTHROUGH_PUTS,
+        "The number of responses of rules with a NONE policy put in its micro-cache."
+    );
+    register_counter!(
+        CNT_FORWARDED_REQUESTS,
+        "The number of requests of other methods than GET and HEAD passed through to upstreams."
+    );
+    register_counter!(
+        CNT_MICRO_CACHE_HITS,
+        "The number of responses served from the micro-cache of a NONE policy."
+    );
+    register_counter!(
+        CNT_HOT_KEY_HITS,
+        "The number of LRU hits of hot keys served from memory."
+    );
+    register_counter!(
+        CNT_MICRO_CACHE_NEGATIVE_HITS,
+        "The number of upstream errors served from the micro-cache of a NONE policy."
+    );
+    register_counter!(
+        CNT_REVALIDATIONS,
+        "The number of LRU entries older than the revalidate_after of their rule revalidated in the background."
+    );
+    register_counter!(
+        CNT_FORCED_REFRESHES,
+        "The number of requests bypassing the cache to refetch a file, e.g. with Cache-Control: no-cache."
+    );
+    register_counter!(
+        CNT_REWRITES_SKIPPED,
+        "The number of responses served or cached unrewritten, since they are over max_rewrite_body_bytes."
+    );
+    register_counter!(
+        CNT_METADATA_PREFETCHES,
+        "The number of PEP 658 metadata files advertised by cached index pages queued for fetching."
+    );
+    register_counter!(
+        CNT_DOWNLOAD_RESUMES,
+        "The number of upstream response bodies interrupted midway and resumed from the last byte received."
+    );
+    register_counter!(
+        CNT_UPSTREAM_COOLDOWNS,
+        "The number of cooldowns of an upstream answering 429 Too Many Requests."
+    );
+    register_counter!(
+        CNT_TENANT_REQUESTS,
+        "The number of requests of a tenant, served from the caches of the tenant."
+    );
+    register_histogram!(
+        HG_REDIS_LATENCY,
+        metrics::Unit::Seconds,
+        "The duration of redis commands of cache metadata.",
+    );
+    register_histogram!(
+        HG_STORAGE_LATENCY,
+        metrics::Unit::Seconds,
+        "The duration of reads and writes of cached files.",
+    );
+    register_histogram!(
+        HG_FSYNC_LATENCY,
+        metrics::Unit::Seconds,
+        "The duration of syncs of written files, and of their directory, to disk.",
+    );
+    register_histogram!(
+        HG_REMOVAL_LATENCY,
+        metrics::Unit::Seconds,
+        "The duration of removals of the files of evicted LRU entries.",
+    );
+    register_histogram!(
+        HG_UPSTREAM_LATENCY,
+        metrics::Unit::Seconds,
+        "The duration of upstream fetches until the response headers are received.",
+    );
+    register_gauge!(
+        GAUGE_REDIS_MEMORY,
+        metrics::Unit::Bytes,
+        "The estimated redis memory used by the metadata of an LRU cache."
+    );
+    register_gauge!(
+        GAUGE_ORPHAN_FILES,
+        "The number of files of evicted LRU entries queued to be removed again."
+    );
+    register_gauge!(
+        GAUGE_DISK_BYTES_ACTUAL,
+        metrics::Unit::Bytes,
+        "The bytes of the files of a cache on disk, as of the last walk of its storage."
+    );
+    register_gauge!(
+        GAUGE_ACCOUNTING_DRIFT,
+        metrics::Unit::Bytes,
+        "The bytes of the files of an LRU cache on disk less those recorded by its metadata."
+    );
+    register_gauge!(
+        GAUGE_UPSTREAM_STATUS,
+        "The status of an upstream: -1 unknown, 0 up, 1 degraded, 2 down."
+    );
+    register_gauge!(
+        GAUGE_UPSTREAM_ERROR_RATE,
+        "The share of the recent fetches of an upstream that failed."
+    );
+    register_gauge!(
+        GAUGE_UPSTREAM_P95_LATENCY,
+        metrics::Unit::Seconds,
+        "The 95th percentile of the latency of the recent fetches of an upstream."
+    );
+    register_gauge!(
+        GAUGE_INFLIGHT_REQ,
+        "The number of in-flight upstream requests for cache misses."
+    );
+    for priority in &["high", "low"] {
+        register_gauge!(
+            GAUGE_BG_TASKS_ACTIVE,
+            "The number of running background download tasks.",
+            "priority" => *priority
+        );
+        register_gauge!(
+            GAUGE_BG_TASKS_QUEUED,
+            "The number of background download tasks waiting to run.",
+            "priority" => *priority
+        );
+    }
 }
 
 pub fn get_cache_size_metrics_key(id: &str) -> String {
     format!("{}_{}", HG_CACHE_SIZE_PREFIX, id)
 }
+
+pub fn get_cache_entries_metrics_key(id: &str) -> String {
+    format!("{}_{}", HG_CACHE_ENTRIES_PREFIX, id)
+}
+
+pub fn get_evicted_entry_age_metrics_key(id: &str) -> String {
+    format!("{}_{}", HG_EVICTED_ENTRY_AGE, id)
+}
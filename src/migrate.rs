@@ -0,0 +1,383 @@
+//! Renames of the ids of LRU caches kept in redis, e.g. after their policy
+//! is renamed. The id prefixes the redis keys of the total size, of the
+//! sorted set of the entries and of the hash of each entry, and names the
+//! directory of the files in filesystem storages without `root_dir`. Run by
+//! the `migrate-cache-id` subcommand.
+//!
+//! Entries are moved in batches, each in a single transaction, from the
+//! least recently used one. Their hashes are found through the sorted set of
+//! the cache rather than by prefix, as `_` may be part of ids, see `CacheId`.
+//! The progress is kept in redis under `migration/<old id>`, so that an
+//! interrupted rename is resumed by running it again.
+//!
+//! Instances write `heartbeat/<id>` for each of their LRU caches with redis
+//! metadata every `HEARTBEAT_INTERVAL`, expiring after `HEARTBEAT_TTL`, so
+//! that a cache is not renamed while it is in use.
+
+use crate::backup;
+use crate::cache::{CacheSizeType, LruMetadataStore, RedisMetadataDb};
+use crate::error::{Error, Result};
+use crate::keys::{HEARTBEAT_ID, MIGRATION_ID};
+use crate::models;
+use crate::settings::Settings;
+use crate::util;
+
+use redis::Connection;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Entries moved per transaction
+const BATCH: usize = 1000;
+
+/// How often instances tell that their caches are in use
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A cache is in use until this long after the last heartbeat of an instance
+pub const HEARTBEAT_TTL: Duration = Duration::from_secs(90);
+
+/// The outcome of a rename
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub from: String,
+    pub to: String,
+    /// Entries of the cache under its new id
+    pub entries: usize,
+    /// Entries left out because their hash is missing
+    pub dropped: usize,
+    pub total_size: CacheSizeType,
+    /// Whether an interrupted rename is completed
+    pub resumed: bool,
+    /// (old, new) directories of the files of the cache
+    pub moved_dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+fn heartbeat_key(id: &str) -> String {
+    format!("{}/{}", HEARTBEAT_ID, id)
+}
+
+fn progress_key(id: &str) -> String {
+    format!("{}/{}", MIGRATION_ID, id)
+}
+
+/// Tell that the LRU caches `ids` are in use, until `HEARTBEAT_TTL` from now
+pub fn write_heartbeats(client: &redis::Client, ids: &[String]) -> Result<()> {
+    let mut con = models::get_sync_con(client)?;
+    let keys: Vec<String> = ids.iter().map(|id| heartbeat_key(id)).collect();
+    let now = util::now().to_string();
+    models::set_expiring(&mut con, &keys, &now, HEARTBEAT_TTL.as_secs() as usize)
+}
+
+/// A number kept in the hash of the progress of a rename
+fn progress_field(con: &mut Connection, key: &str, field: &str) -> Result<Option<u64>> {
+    let value = models::get_hash_field(con, key, field)?;
+    Ok(value.and_then(|value| value.parse().ok()))
+}
+
+/// Record the start of the rename of `from` to `to`, or check that it is
+/// the rename in progress. Returns whether it is in progress. Fails if `to`
+/// has entries, or if `from` has no metadata.
+fn begin(
+    con: &mut Connection,
+    from: &RedisMetadataDb,
+    to: &RedisMetadataDb,
+    from_id: &str,
+    to_id: &str,
+) -> Result<bool> {
+    let key = progress_key(from_id);
+    match models::get_hash_field(con, &key, "to")? {
+        Some(target) if target == to_id => return Ok(true),
+        Some(target) => {
+            return Err(Error::OtherError(format!(
+                "the rename of cache {} to {} is in progress, complete it first",
+                from_id, target
+            )))
+        }
+        None => {}
+    }
+    let existing = to.lru_len()?;
+    if existing > 0 {
+        return Err(Error::OtherError(format!(
+            "cache {} already has {} entries",
+            to_id, existing
+        )));
+    }
+    let entries = from.lru_len()?;
+    let total_size = from.recorded_total_size()?;
+    if entries == 0 && total_size.is_none() {
+        return Err(Error::NotFound(format!("metadata of cache {}", from_id)));
+    }
+    let mut fields = vec![
+        ("to".to_string(), to_id.to_string()),
+        ("entries".to_string(), entries.to_string()),
+        ("started_at".to_string(), util::now().to_string()),
+    ];
+    if let Some(total_size) = total_size {
+        fields.push(("total_size".to_string(), total_size.to_string()));
+    }
+    models::set_hash_fields(con, &key, &fields)?;
+    Ok(false)
+}
+
+/// Rename the LRU cache `from` to `to`: move its entries, its total size
+/// and its queued orphaned files, then check that all entries recorded when
+/// the rename started are under `to`, and none are left under `from`.
+/// Fails while an instance uses either id, unless `force` is set. The
+/// directories of its files are not moved, see `storage_moves`.
+pub fn migrate_cache_id(
+    client: &redis::Client,
+    from: &str,
+    to: &str,
+    force: bool,
+) -> Result<MigrationReport> {
+    if from == to {
+        return Err(Error::OtherError("the ids are the same".to_string()));
+    }
+    let from_db = RedisMetadataDb::new(client.clone(), from)?;
+    let to_db = RedisMetadataDb::new(client.clone(), to)?;
+    let mut con = models::get_sync_con(client)?;
+    let in_use = models::keys_exist(&mut con, &[heartbeat_key(from), heartbeat_key(to)])?;
+    for (id, in_use) in [from, to].iter().zip(in_use) {
+        if in_use && !force {
+            return Err(Error::OtherError(format!(
+                "cache {} is in use by a running instance, stop it or use --force",
+                id
+            )));
+        }
+    }
+    let resumed = begin(&mut con, &from_db, &to_db, from, to)?;
+    let key = progress_key(from);
+    while from_db.move_lru_entries(&to_db, BATCH, &key)? > 0 {}
+    from_db.move_lru_totals(&to_db)?;
+
+    let recorded = progress_field(&mut con, &key, "entries")?.unwrap_or(0) as usize;
+    let dropped = progress_field(&mut con, &key, "dropped")?.unwrap_or(0) as usize;
+    let total_size = progress_field(&mut con, &key, "total_size")?;
+    let entries = to_db.lru_len()?;
+    let missing = to_db.missing_lru_hashes()?;
+    let left = from_db.lru_len()?;
+    let verified = entries + dropped == recorded
+        && missing == 0
+        && left == 0
+        && from_db.recorded_total_size()?.is_none()
+        && to_db.recorded_total_size()? == total_size;
+    if !verified {
+        return Err(Error::OtherError(format!(
+            "{} has {} entries, {} of them without metadata, instead of {}, and {} has {} \
+             left: run the rename again to resume it",
+            to,
+            entries,
+            missing,
+            recorded.saturating_sub(dropped),
+            from,
+            left
+        )));
+    }
+    models::del(&mut con, &key)?;
+    Ok(MigrationReport {
+        from: from.to_string(),
+        to: to.to_string(),
+        entries,
+        dropped,
+        total_size: total_size.unwrap_or(0),
+        resumed,
+        moved_dirs: vec![],
+    })
+}
+
+/// (old, new) directories of the files of the cache `to` of the settings,
+/// renamed from `from`, in the filesystem storages where the directory is
+/// derived from the id, `<storage path>/<id>`, i.e. without `root_dir`
+pub fn storage_moves(settings: &Settings, from: &str, to: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let tenant_policies = settings.tenant_policies();
+    let root_dir = settings
+        .policies
+        .iter()
+        .chain(tenant_policies.iter())
+        .find(|policy| policy.name == to && policy.shards.is_none())
+        .and_then(|policy| policy.root_dir.clone());
+    let moves = settings
+        .cache_dirs()?
+        .into_iter()
+        .filter(|(cache, dir)| cache == to && Some(dir) != root_dir.as_ref())
+        .map(|(_, dir)| {
+            let new = PathBuf::from(dir);
+            (new.with_file_name(from), new)
+        })
+        .collect();
+    Ok(moves)
+}
+
+/// Rename the directories of `moves`, skipping the missing ones, e.g.
+/// renamed by an earlier run. An empty new directory is replaced. Returns
+/// the ones renamed.
+pub fn move_dirs(moves: &[(PathBuf, PathBuf)]) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut moved = Vec::new();
+    for (old, new) in moves {
+        if !old.exists() {
+            continue;
+        }
+        if new.exists() {
+            if fs::read_dir(new)?.next().is_some() {
+                return Err(Error::OtherError(format!(
+                    "both {} and {} have files",
+                    old.display(),
+                    new.display()
+                )));
+            }
+            fs::remove_dir(new)?;
+        }
+        fs::rename(old, new)?;
+        moved.push((old.clone(), new.clone()));
+    }
+    Ok(moved)
+}
+
+/// Rename the cache `from` to `to`, which must be an LRU cache with redis
+/// metadata of the settings, and the directories of its files if
+/// `move_storage`
+pub fn rename_cache(
+    settings: &Settings,
+    from: &str,
+    to: &str,
+    force: bool,
+    move_storage: bool,
+) -> Result<MigrationReport> {
+    backup::redis_lru_cache(settings, to)?;
+    let client = redis::Client::open(settings.get_redis_url().as_str())?;
+    let mut report = migrate_cache_id(&client, from, to, force)?;
+    if move_storage {
+        report.moved_dirs = move_dirs(&storage_moves(settings, from, to)?)?;
+    }
+    Ok(report)
+}
+
+/// A human readable summary of a rename
+pub fn format_report(report: &MigrationReport) -> String {
+    let action = if report.resumed {
+        "resumed renaming"
+    } else {
+        "renamed"
+    };
+    let mut text = format!(
+        "{} cache {} to {}: {} entries, {} bytes\n",
+        action, report.from, report.to, report.entries, report.total_size
+    );
+    if report.dropped > 0 {
+        text.push_str(&format!(
+            "dropped {} entries without metadata\n",
+            report.dropped
+        ));
+    }
+    for (old, new) in &report.moved_dirs {
+        text.push_str(&format!("moved {} to {}\n", old.display(), new.display()));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{Cache, LruCache};
+    use crate::storage::FsBackend;
+    use std::sync::Arc;
+
+    fn lru_cache(client: &redis::Client, id: &str, dir: &str) -> LruCache {
+        let storage = Arc::new(FsBackend {
+            root_dir: dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: Default::default(),
+        });
+        let db = RedisMetadataDb::new(client.clone(), id).unwrap();
+        LruCache::new(1024, Arc::new(db), storage, id)
+    }
+
+    /// The redis keys of the LRU cache `id`
+    fn keys_of(con: &mut Connection, id: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) =
+                models::scan_prefixed_keys(con, &format!("{}_", id), cursor, 100).unwrap();
+            keys.extend(batch);
+            if next == 0 {
+                return keys;
+            }
+            cursor = next;
+        }
+    }
+
+    #[tokio::test]
+    async fn rename_cache_id() {
+        let client = redis::Client::open("redis://localhost:3001/").unwrap();
+        let mut con = models::get_sync_con(&client).unwrap();
+        let (from, to) = ("migrate_test_old", "migrate_test_new");
+        for id in &[from, to] {
+            let mut keys = keys_of(&mut con, id);
+            keys.extend(vec![heartbeat_key(id), progress_key(id)]);
+            for key in keys {
+                models::del(&mut con, &key).unwrap();
+            }
+        }
+        let dir = "cache/migrate_test";
+        let _ = fs::remove_dir_all(dir);
+        let mut cache = lru_cache(&client, from, dir);
+        cache.put("a.whl", vec![1; 3].into()).await;
+        cache.put("b/c.whl", vec![2; 5].into()).await;
+        cache.put("d.whl", vec![3; 7].into()).await;
+
+        // an instance uses the cache
+        write_heartbeats(&client, &[from.to_string()]).unwrap();
+        assert!(migrate_cache_id(&client, from, to, false).is_err());
+        models::del(&mut con, &heartbeat_key(from)).unwrap();
+
+        // interrupted after the first entry
+        let from_db = RedisMetadataDb::new(client.clone(), from).unwrap();
+        let to_db = RedisMetadataDb::new(client.clone(), to).unwrap();
+        assert!(!begin(&mut con, &from_db, &to_db, from, to).unwrap());
+        from_db
+            .move_lru_entries(&to_db, 1, &progress_key(from))
+            .unwrap();
+        assert!(migrate_cache_id(&client, from, "migrate_test_other", false).is_err());
+        let report = migrate_cache_id(&client, from, to, false).unwrap();
+        assert!(report.resumed);
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.dropped, 0);
+        assert_eq!(report.total_size, 15);
+
+        // nothing is left under the old id
+        assert!(keys_of(&mut con, from).is_empty());
+        let progress = models::keys_exist(&mut con, &[progress_key(from)]).unwrap();
+        assert_eq!(progress, vec![false]);
+        let (entries, total_size) = to_db.dump_lru_entries().unwrap();
+        let keys: Vec<&str> = entries.iter().map(|(key, _, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["a.whl", "b/c.whl", "d.whl"]);
+        assert_eq!(total_size, 15);
+        let renamed = lru_cache(&client, to, dir);
+        for key in keys {
+            assert!(renamed.get(key).await.is_some(), "{}", key);
+        }
+        // already renamed
+        assert!(migrate_cache_id(&client, from, to, false).is_err());
+    }
+
+    #[test]
+    fn move_cache_dirs() {
+        let root = PathBuf::from("cache/migrate_dirs_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("old")).unwrap();
+        fs::write(root.join("old/a.whl"), "wheel").unwrap();
+        // created empty, e.g. by an instance started with the new id
+        fs::create_dir_all(root.join("new")).unwrap();
+        let moves = vec![(root.join("old"), root.join("new"))];
+        assert_eq!(move_dirs(&moves).unwrap(), moves);
+        assert!(root.join("new/a.whl").exists());
+        // already moved
+        assert!(move_dirs(&moves).unwrap().is_empty());
+        fs::create_dir_all(root.join("old")).unwrap();
+        fs::write(root.join("old/b.whl"), "wheel").unwrap();
+        assert!(move_dirs(&moves).is_err());
+    }
+}
@@ -1,11 +1,11 @@
 use crate::cache::CacheEntry;
-use crate::cache::LruCacheMetadata;
 use crate::error::Error::*;
 use crate::error::Result;
+use crate::storage::Tier;
 use crate::util;
 use redis::{aio::Connection, Commands, Connection as SyncConnection};
 use sled::transaction::TransactionalTree;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::convert::{From, TryInto};
 
 #[allow(dead_code)]
@@ -13,89 +13,485 @@ pub async fn get_con(client: &redis::Client) -> Result<Connection> {
     client
         .get_async_connection()
         .await
-        .map_err(RedisClientError)
+        .map_err(RedisUnavailable)
 }
 
 pub fn get_sync_con(client: &redis::Client) -> Result<SyncConnection> {
-    client.get_connection().map_err(RedisClientError)
+    client.get_connection().map_err(RedisUnavailable)
 }
 
-/// get cache entry
-pub fn get_cache_entry(
+/// Metadata of an LRU entry, kept in redis as a hash with a field per field
+/// of the struct, see `to_redis_fields` and `from_redis_fields`. Numbers are
+/// kept as their decimal strings.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    /// Path of the file relative to the cache root, for tools reading redis
+    pub path: String,
+    #[serde(with = "redis_number")]
+    pub size: u64,
+    /// Last access time in millisecs
+    #[serde(with = "redis_number")]
+    pub atime: i64,
+    /// Hex encoded SHA-256 of the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Time the file is cached or last confirmed to be current, in secs.
+    /// Entries of earlier versions have none.
+    #[serde(
+        default,
+        with = "redis_optional_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_at: Option<i64>,
+    /// Number of hits, counted in redis. Entries never hit have none.
+    #[serde(
+        default,
+        with = "redis_optional_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub hits: Option<u64>,
+    /// Url of an entry whose key is a digest of it, see `Task::to_key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Tier of a tiered storage the file is in, see `storage::Tier`. Files
+    /// never demoted have none, as those of storages without tiers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tier: Option<Tier>,
+    /// Fields unknown to this version, e.g. added by a newer one sharing the
+    /// redis, kept as is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, String>,
+}
+
+impl EntryMetadata {
+    /// The fields of the hash of the entry, unknown ones included
+    pub fn to_redis_fields(&self) -> Vec<(String, String)> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields
+                .into_iter()
+                .filter_map(|(field, value)| match value {
+                    serde_json::Value::String(value) => Some((field, value)),
+                    _ => None,
+                })
+                .collect(),
+            _ => unreachable!("entry metadata is a map of strings"),
+        }
+    }
+
+    /// The metadata of the fields of a hash, `None` if the hash does not
+    /// exist, i.e. it has no fields
+    pub fn from_redis_fields(fields: Vec<(String, String)>) -> Result<Option<Self>> {
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let fields = fields
+            .into_iter()
+            .map(|(field, value)| (field, serde_json::Value::String(value)))
+            .collect();
+        serde_json::from_value(serde_json::Value::Object(fields))
+            .map(Some)
+            .map_err(|e| CacheMetadataInconsistent(format!("invalid LRU entry: {}", e)))
+    }
+
+    /// Fields of the file left unset, removed when the entry is written so
+    /// that they do not outlive the file they describe. `hits` is kept.
+    fn unset_fields(&self) -> Vec<&'static str> {
+        let mut unset = Vec::new();
+        if self.sha256.is_none() {
+            unset.push("sha256");
+        }
+        if self.created_at.is_none() {
+            unset.push("created_at");
+        }
+        if self.tier.is_none() {
+            unset.push("tier");
+        }
+        unset
+    }
+}
+
+/// A number kept as its decimal string in a redis hash
+mod redis_number {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+/// An optional number kept as its decimal string in a redis hash
+mod redis_optional_number {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T, S>(value: &Option<T>, s: S) -> Result<S::Ok, S::Error>
+    where
+        T: Display,
+        S: Serializer,
+    {
+        match value {
+            Some(value) => s.collect_str(value),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(d)?
+            .map(|value| value.parse().map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Replace an LRU entry and update the total size in a single round trip.
+    /// Fields of the hash not set nor removed are kept. An entry of the same
+    /// size and SHA-256 describes the same file, so only its atime and
+    /// created_at are updated.
+    /// KEYS: entry, total size, zlist
+    /// ARGV: size, atime, sha256 and created_at (empty if unknown), the
+    /// number n of fields to remove, these n fields, then the fields to set
+    /// followed by their values
+    /// Returns the new total size.
+    static ref SET_LRU_ENTRY_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        local old = redis.call('HMGET', KEYS[1], 'size', 'sha256')
+        if ARGV[3] ~= '' and old[1] == ARGV[1] and old[2] == ARGV[3] then
+            redis.call('HSET', KEYS[1], 'atime', ARGV[2])
+            if ARGV[4] ~= '' then
+                redis.call('HSET', KEYS[1], 'created_at', ARGV[4])
+            end
+            redis.call('ZADD', KEYS[3], ARGV[2], KEYS[1])
+            return tonumber(redis.call('GET', KEYS[2]) or '0')
+        end
+        if old[1] then
+            redis.call('DECRBY', KEYS[2], old[1])
+        end
+        local total_size = redis.call('INCRBY', KEYS[2], ARGV[1])
+        local unset = tonumber(ARGV[5])
+        for i = 6, 5 + unset do
+            redis.call('HDEL', KEYS[1], ARGV[i])
+        end
+        redis.call('HSET', KEYS[1], unpack(ARGV, 6 + unset))
+        redis.call('ZADD', KEYS[3], ARGV[2], KEYS[1])
+        return total_size
+        ",
+    );
+    /// Remove LRU entries and their sizes from the total size, which is
+    /// reset to 0 once there are no entries, so that any drift is dropped.
+    /// KEYS: total size, zlist, then the entries
+    /// Returns 1 for each entry that existed, otherwise 0.
+    static ref REMOVE_LRU_ENTRIES_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        local existed = {}
+        for i = 3, #KEYS do
+            redis.call('ZREM', KEYS[2], KEYS[i])
+            local size = redis.call('HGET', KEYS[i], 'size')
+            if size then
+                redis.call('DECRBY', KEYS[1], size)
+                redis.call('DEL', KEYS[i])
+                table.insert(existed, 1)
+            else
+                table.insert(existed, 0)
+            end
+        end
+        if redis.call('ZCARD', KEYS[2]) == 0 then
+            redis.call('SET', KEYS[1], 0)
+        end
+        return existed
+        ",
+    );
+    /// Update the atime of an LRU entry if it exists, and add to its hit
+    /// count, in a single round trip.
+    /// KEYS: entry, zlist
+    /// ARGV: atime, hits, created_at (empty to keep it)
+    /// Returns 1 if the entry exists, otherwise 0.
+    static ref TOUCH_LRU_ENTRY_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], 'atime', ARGV[1])
+        if ARGV[2] ~= '0' then
+            redis.call('HINCRBY', KEYS[1], 'hits', ARGV[2])
+        end
+        if ARGV[3] ~= '' then
+            redis.call('HSET', KEYS[1], 'created_at', ARGV[3])
+        end
+        redis.call('ZADD', KEYS[2], ARGV[1], KEYS[1])
+        return 1
+        ",
+    );
+    /// Set a field of a hash only if the hash exists, so that an entry
+    /// removed meanwhile is not brought back partially.
+    /// KEYS: hash
+    /// ARGV: field, value
+    /// Returns 1 if the field is set, otherwise 0.
+    static ref SET_EXISTING_HASH_FIELD_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+        return 1
+        ",
+    );
+    /// Like TOUCH_LRU_ENTRY_SCRIPT counting a hit, for a batch of entries.
+    /// KEYS: zlist, then the entries
+    /// ARGV: the atimes of the entries
+    static ref TOUCH_LRU_ENTRIES_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        for i = 2, #KEYS do
+            if redis.call('EXISTS', KEYS[i]) == 1 then
+                redis.call('HSET', KEYS[i], 'atime', ARGV[i - 1])
+                redis.call('HINCRBY', KEYS[i], 'hits', 1)
+                redis.call('ZADD', KEYS[1], ARGV[i - 1], KEYS[i])
+            end
+        end
+        ",
+    );
+    /// Remove an LRU entry and update the total size in a single round trip.
+    /// KEYS: entry, total size, zlist
+    /// Returns 1 if the entry existed, otherwise 0.
+    static ref REMOVE_LRU_ENTRY_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        redis.call('ZREM', KEYS[3], KEYS[1])
+        local size = redis.call('HGET', KEYS[1], 'size')
+        if not size then
+            return 0
+        end
+        redis.call('DECRBY', KEYS[2], size)
+        redis.call('DEL', KEYS[1])
+        return 1
+        ",
+    );
+    /// Push back the expiration of a TTL entry, unless it was set again or
+    /// extended meanwhile, i.e. its expiration is no longer ARGV[1].
+    /// KEYS: expiring key, hash
+    /// ARGV: expiration read, new expiration, secs until the entry is removed
+    /// Returns 1 if the entry is extended, otherwise 0.
+    static ref EXTEND_TTL_ENTRY_SCRIPT: redis::Script = redis::Script::new(
+        r"
+        if redis.call('HGET', KEYS[2], 'expires_at') ~= ARGV[1] then
+            return 0
+        end
+        redis.call('HSET', KEYS[2], 'expires_at', ARGV[2])
+        redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+        return 1
+        ",
+    );
+}
+
+/// Check whether an LRU entry exists, and update its atime on hit. The hit
+/// is counted if `count_hit`, and `created_at` is replaced if set.
+pub fn touch_lru_cache_entry(
     con: &mut SyncConnection,
     key: &str,
-) -> Result<Option<CacheEntry<LruCacheMetadata, String, ()>>> {
-    let map: HashMap<String, String> = con.hgetall(key)?;
-    if map.is_empty() {
-        // not exist in cache
-        return Ok(None);
-    }
-    let cache_entry = CacheEntry {
-        metadata: LruCacheMetadata {
-            atime: String::from(map.get("atime").unwrap_or(&String::from("0")))
-                .parse::<i64>()
-                .unwrap_or(0),
-            size: String::from(map.get("size").unwrap_or(&String::from("0")))
-                .parse::<u64>()
-                .unwrap_or(0),
-        },
-        key: String::from(map.get("path").unwrap_or(&String::from(""))),
-        value: (),
-    };
-    Ok(Some(cache_entry))
+    atime: i64,
+    zlist_key: &str,
+    count_hit: bool,
+    created_at: Option<i64>,
+) -> Result<bool> {
+    TOUCH_LRU_ENTRY_SCRIPT
+        .key(key)
+        .key(zlist_key)
+        .arg(atime)
+        .arg(count_hit as i32)
+        .arg(created_at.map(|t| t.to_string()).unwrap_or_default())
+        .invoke::<i32>(con)
+        .map(|exists| exists == 1)
+        .map_err(RedisCMDError)
 }
 
-/// set an lru cache entry
+/// Like `touch_lru_cache_entry` counting the hit, for a batch of entries and
+/// their atimes in a single round trip. Entries removed meanwhile are skipped.
+pub fn touch_lru_cache_entries(
+    con: &mut SyncConnection,
+    touches: &[(String, i64)],
+    zlist_key: &str,
+) -> Result<()> {
+    let mut invocation = TOUCH_LRU_ENTRIES_SCRIPT.key(zlist_key);
+    for (key, atime) in touches {
+        invocation.key(key).arg(*atime);
+    }
+    invocation.invoke::<()>(con).map_err(RedisCMDError)
+}
+
+/// Remove an LRU entry, returns whether it existed.
+pub fn remove_lru_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    total_size_key: &str,
+    zlist_key: &str,
+) -> Result<bool> {
+    REMOVE_LRU_ENTRY_SCRIPT
+        .key(key)
+        .key(total_size_key)
+        .key(zlist_key)
+        .invoke::<i32>(con)
+        .map(|existed| existed == 1)
+        .map_err(RedisCMDError)
+}
+
+/// Check whether a cache entry exists, without touching it.
+pub fn cache_entry_exists(con: &mut SyncConnection, key: &str) -> Result<bool> {
+    con.exists(key).map_err(RedisCMDError)
+}
+
+/// Whether each of `keys` exists, in a single round trip
+pub fn keys_exist(con: &mut SyncConnection, keys: &[String]) -> Result<Vec<bool>> {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.exists(key);
+    }
+    pipe.query(con).map_err(RedisCMDError)
+}
+
+/// set an lru cache entry, returns the new total size. Fields of the hash
+/// unknown to `EntryMetadata` and the hit count are kept, and an entry of
+/// the same size and SHA-256 is only touched, see `SET_LRU_ENTRY_SCRIPT`.
 pub fn set_lru_cache_entry(
     con: &mut SyncConnection,
     key: &str,
-    entry: &CacheEntry<LruCacheMetadata, String, ()>,
+    entry: &CacheEntry<EntryMetadata, String, ()>,
     total_size_key: &str,
     zlist_key: &str,
-) -> Result<()> {
-    let kv_array = entry.to_redis_multiple_fields();
-    let tx_result = redis::transaction(con, &[key, total_size_key, zlist_key], |con, pipe| {
-        let pkg_size: Option<u64> = con.hget(&key, "size").unwrap();
-        pipe.decr(
-            total_size_key,
-            if let Some(old_size) = pkg_size {
-                old_size
-            } else {
-                0
-            },
+) -> Result<u64> {
+    let metadata = &entry.metadata;
+    let unset = metadata.unset_fields();
+    let mut script = SET_LRU_ENTRY_SCRIPT.key(key);
+    script
+        .key(total_size_key)
+        .key(zlist_key)
+        .arg(metadata.size)
+        .arg(metadata.atime)
+        .arg(metadata.sha256.as_deref().unwrap_or_default())
+        .arg(
+            metadata
+                .created_at
+                .map(|t| t.to_string())
+                .unwrap_or_default(),
         )
-        .incr(total_size_key, entry.metadata.size)
-        .hset_multiple::<&str, &str, String>(key, &kv_array)
-        .ignore()
-        .zadd(zlist_key, key, entry.metadata.atime)
-        .query(con)?;
-        Ok(Some(()))
-    });
-    tx_result.map_err(RedisCMDError)
+        .arg(unset.len())
+        .arg(unset);
+    for (field, value) in metadata.to_redis_fields() {
+        script.arg(field).arg(value);
+    }
+    script.invoke::<u64>(con).map_err(RedisCMDError)
 }
 
-pub fn update_cache_entry_atime(
+/// The metadata of an LRU entry, `None` if there is no such entry
+pub fn get_lru_cache_entry(con: &mut SyncConnection, key: &str) -> Result<Option<EntryMetadata>> {
+    let fields: Vec<(String, String)> = con.hgetall(key).map_err(RedisCMDError)?;
+    EntryMetadata::from_redis_fields(fields)
+}
+
+/// The metadata of LRU entries in a single round trip, `None` for entries
+/// that do not exist
+pub fn get_lru_cache_entries(
     con: &mut SyncConnection,
-    key: &str,
-    atime: i64,
+    keys: &[String],
+) -> Result<Vec<Option<EntryMetadata>>> {
+    get_hashes(con, keys)?
+        .into_iter()
+        .map(EntryMetadata::from_redis_fields)
+        .collect()
+}
+
+/// Remove LRU entries, returns whether each of them existed.
+pub fn remove_lru_cache_entries(
+    con: &mut SyncConnection,
+    keys: &[String],
+    total_size_key: &str,
     zlist_key: &str,
-) -> Result<i64> {
-    match redis::pipe()
-        .atomic()
-        .hset(key, "atime", atime)
-        .zadd(zlist_key, key, atime)
-        .query::<(i64, i64)>(con)
-    {
-        Ok(_) => Ok(atime),
-        Err(e) => Err(RedisCMDError(e)),
+) -> Result<Vec<bool>> {
+    let mut invocation = REMOVE_LRU_ENTRIES_SCRIPT.key(total_size_key);
+    invocation.key(zlist_key);
+    for key in keys {
+        invocation.key(key);
     }
+    let existed: Vec<i64> = invocation.invoke(con).map_err(RedisCMDError)?;
+    Ok(existed.into_iter().map(|existed| existed == 1).collect())
 }
 
-pub fn set(con: &mut SyncConnection, key: &str, value: &str) -> Result<String> {
-    match con.set(key, value) {
-        Ok(res) => Ok(res),
-        Err(e) => Err(RedisCMDError(e)),
+/// The SHA-256 of the file of an LRU entry, if recorded.
+pub fn lru_entry_sha256(con: &mut SyncConnection, key: &str) -> Result<Option<String>> {
+    Ok(get_lru_cache_entry(con, key)?.and_then(|entry| entry.sha256))
+}
+
+/// The SHA-256 of the files of LRU entries in a single round trip, `None`
+/// for entries that do not exist or have no recorded hash.
+pub fn lru_entry_sha256s(con: &mut SyncConnection, keys: &[String]) -> Result<Vec<Option<String>>> {
+    let entries = get_lru_cache_entries(con, keys)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| entry.and_then(|entry| entry.sha256))
+        .collect())
+}
+
+/// `created_at` of an LRU entry, `None` if there is no such entry or it was
+/// cached before the time was recorded.
+pub fn lru_entry_created_at(con: &mut SyncConnection, key: &str) -> Result<Option<i64>> {
+    Ok(get_lru_cache_entry(con, key)?.and_then(|entry| entry.created_at))
+}
+
+/// Set a field of a hash if the hash exists, returns whether it exists.
+pub fn set_existing_hash_field(
+    con: &mut SyncConnection,
+    key: &str,
+    field: &str,
+    value: &str,
+) -> Result<bool> {
+    SET_EXISTING_HASH_FIELD_SCRIPT
+        .key(key)
+        .arg(field)
+        .arg(value)
+        .invoke::<i32>(con)
+        .map(|set| set == 1)
+        .map_err(RedisCMDError)
+}
+
+/// Atimes below this are in secs: it is year 5138 in secs, and 1973 in millisecs.
+const ATIME_SECS_THRESHOLD: i64 = 100_000_000_000;
+
+/// Rescale LRU atimes in secs to millisecs, in the zlist and the entries.
+/// Returns the number of entries rescaled.
+pub fn rescale_lru_atime_secs(con: &mut SyncConnection, zlist_key: &str) -> Result<usize> {
+    let entries: Vec<(String, i64)> = con
+        .zrangebyscore_withscores(zlist_key, "-inf", format!("({}", ATIME_SECS_THRESHOLD))
+        .map_err(RedisCMDError)?;
+    if entries.is_empty() {
+        return Ok(0);
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (key, atime) in &entries {
+        let atime = atime * 1000;
+        pipe.zadd(zlist_key, key, atime)
+            .ignore()
+            .hset(key, "atime", atime)
+            .ignore();
     }
+    pipe.query::<()>(con).map_err(RedisCMDError)?;
+    Ok(entries.len())
 }
 
 pub fn get(con: &mut SyncConnection, key: &str) -> Result<Option<String>> {
@@ -105,45 +501,558 @@ pub fn get(con: &mut SyncConnection, key: &str) -> Result<Option<String>> {
     }
 }
 
-/**
- * Set the TTL of given key
- */
+/// Set the TTL of given key
+#[cfg(test)]
 pub fn expire(con: &mut SyncConnection, key: &str, ttl: usize) -> Result<i32> {
-    match con.expire(key, ttl) {
+    con.expire(key, ttl).map_err(RedisCMDError)
+}
+
+/// Set a TTL cache entry. The hash `meta_key` keeps `created_at`, `expires_at`
+/// (secs), `grace` and `ttl`, and `key` expires when the entry is due to be
+/// removed, which notifies the cleaner.
+pub fn set_ttl_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    meta_key: &str,
+    created_at: i64,
+    ttl: u64,
+    grace: u64,
+) -> Result<()> {
+    let expires_at = created_at + ttl as i64;
+    redis::pipe()
+        .atomic()
+        .hset_multiple(
+            meta_key,
+            &[
+                ("created_at", created_at),
+                ("expires_at", expires_at),
+                ("grace", grace as i64),
+                ("ttl", ttl as i64),
+            ],
+        )
+        .ignore()
+        .set_ex(key, expires_at, (ttl + grace) as usize)
+        .ignore()
+        .query::<()>(con)
+        .map_err(RedisCMDError)
+}
+
+/// The `expires_at` kept as the value of the keys of TTL cache entries, in a
+/// single round trip. `None` for entries that are removed.
+pub fn get_ttl_expirations(con: &mut SyncConnection, keys: &[String]) -> Result<Vec<Option<i64>>> {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.get(key);
+    }
+    let values: Vec<Option<String>> = pipe.query(con).map_err(RedisCMDError)?;
+    Ok(values
+        .into_iter()
+        .map(|value| value.map(|value| value.parse().unwrap_or(i64::MAX)))
+        .collect())
+}
+
+/// Get `expires_at` and `grace` of a TTL cache entry, `None` if there is no
+/// hash `meta_key`.
+pub fn get_ttl_cache_entry(con: &mut SyncConnection, meta_key: &str) -> Result<Option<(i64, i64)>> {
+    let (expires_at, grace): (Option<i64>, Option<i64>) = redis::cmd("HMGET")
+        .arg(meta_key)
+        .arg("expires_at")
+        .arg("grace")
+        .query(con)
+        .map_err(RedisCMDError)?;
+    Ok(expires_at.map(|expires_at| (expires_at, grace.unwrap_or(0))))
+}
+
+/// The times of a TTL cache entry kept in its hash, in secs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TtlTimes {
+    /// `None` if the entry was set by an earlier version
+    pub created_at: Option<i64>,
+    pub expires_at: i64,
+    pub grace: i64,
+    /// The ttl the entry was set with, `None` if set by an earlier version
+    pub ttl: Option<u64>,
+}
+
+/// Get the times of a TTL cache entry, `None` if there is no hash `meta_key`.
+pub fn get_ttl_cache_times(con: &mut SyncConnection, meta_key: &str) -> Result<Option<TtlTimes>> {
+    let (created_at, expires_at, grace, ttl): (Option<i64>, Option<i64>, Option<i64>, Option<u64>) =
+        redis::cmd("HMGET")
+            .arg(meta_key)
+            .arg("created_at")
+            .arg("expires_at")
+            .arg("grace")
+            .arg("ttl")
+            .query(con)
+            .map_err(RedisCMDError)?;
+    Ok(expires_at.map(|expires_at| TtlTimes {
+        created_at,
+        expires_at,
+        grace: grace.unwrap_or(0),
+        ttl,
+    }))
+}
+
+/// Push back the expiration of a TTL cache entry read as `times` to
+/// `expires_at`, at `now` (secs). Returns whether it is extended, i.e. it was
+/// not set again meanwhile.
+pub fn extend_ttl_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    meta_key: &str,
+    times: &TtlTimes,
+    expires_at: i64,
+    now: i64,
+) -> Result<bool> {
+    let remove_in = (expires_at - now + times.grace).max(1);
+    EXTEND_TTL_ENTRY_SCRIPT
+        .key(key)
+        .key(meta_key)
+        .arg(times.expires_at)
+        .arg(expires_at)
+        .arg(remove_in)
+        .invoke::<i32>(con)
+        .map(|extended| extended == 1)
+        .map_err(RedisCMDError)
+}
+
+/// Get `created_at` of a TTL cache entry, `None` if there is no hash `meta_key`.
+pub fn get_ttl_created_at(con: &mut SyncConnection, meta_key: &str) -> Result<Option<i64>> {
+    con.hget(meta_key, "created_at").map_err(RedisCMDError)
+}
+
+pub fn set(con: &mut SyncConnection, key: &str, value: &str) -> Result<()> {
+    con.set(key, value).map_err(RedisCMDError)
+}
+
+/// Set `key` to `value` unless it exists, returns whether it is set
+pub fn set_if_absent(con: &mut SyncConnection, key: &str, value: &str) -> Result<bool> {
+    con.set_nx(key, value).map_err(RedisCMDError)
+}
+
+/// Set the fields of a hash
+pub fn set_hash_fields(
+    con: &mut SyncConnection,
+    key: &str,
+    fields: &[(String, String)],
+) -> Result<()> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    con.hset_multiple(key, fields).map_err(RedisCMDError)
+}
+
+/// A field of a hash, `None` if the hash or the field does not exist
+pub fn get_hash_field(con: &mut SyncConnection, key: &str, field: &str) -> Result<Option<String>> {
+    con.hget(key, field).map_err(RedisCMDError)
+}
+
+/// The same field of the hashes `keys` in a single round trip, `None` where
+/// the hash or the field does not exist
+pub fn get_hash_field_of_keys(
+    con: &mut SyncConnection,
+    keys: &[String],
+    field: &str,
+) -> Result<Vec<Option<String>>> {
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.hget(key, field);
+    }
+    pipe.query(con).map_err(RedisCMDError)
+}
+
+pub fn del(con: &mut SyncConnection, key: &str) -> Result<i32> {
+    match con.del(key) {
         Ok(res) => Ok(res),
         Err(e) => Err(RedisCMDError(e)),
     }
 }
 
+/// Remove a TTL cache entry and its hash, returns whether it existed.
+pub fn remove_ttl_cache_entry(con: &mut SyncConnection, key: &str, meta_key: &str) -> Result<bool> {
+    let (deleted, meta_deleted): (i32, i32) = redis::pipe()
+        .atomic()
+        .del(key)
+        .del(meta_key)
+        .query(con)
+        .map_err(RedisCMDError)?;
+    Ok(deleted + meta_deleted > 0)
+}
+
+/// Get the sizes of LRU cache entries in a single round trip, `None` for
+/// entries that do not exist.
+pub fn get_lru_entry_sizes(con: &mut SyncConnection, keys: &[String]) -> Result<Vec<Option<u64>>> {
+    let entries = get_lru_cache_entries(con, keys)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| entry.map(|entry| entry.size))
+        .collect())
+}
+
+/// Get the (size, hits) of LRU cache entries in a single round trip, `None`
+/// for entries that do not exist.
+pub fn get_lru_entry_stats(
+    con: &mut SyncConnection,
+    keys: &[String],
+) -> Result<Vec<Option<(u64, u64)>>> {
+    let entries = get_lru_cache_entries(con, keys)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| entry.map(|entry| (entry.size, entry.hits.unwrap_or(0))))
+        .collect())
+}
+
+/// Add to the `requests` and `bytes` of a bucket of quota usage, and let it
+/// expire after `expire` secs
+pub fn incr_quota_usage(
+    con: &mut SyncConnection,
+    key: &str,
+    requests: u64,
+    bytes: u64,
+    expire: u64,
+) -> Result<()> {
+    redis::pipe()
+        .atomic()
+        .hincr(key, "requests", requests)
+        .ignore()
+        .hincr(key, "bytes", bytes)
+        .ignore()
+        .expire(key, expire as usize)
+        .ignore()
+        .query(con)
+        .map_err(RedisCMDError)
+}
+
+/// Get the (requests, bytes) of buckets of quota usage in a single round
+/// trip, 0 for buckets that do not exist
+pub fn get_quota_usage(con: &mut SyncConnection, keys: &[String]) -> Result<Vec<(u64, u64)>> {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.hget(key, &["requests", "bytes"]);
+    }
+    let usage: Vec<(Option<u64>, Option<u64>)> = pipe.query(con).map_err(RedisCMDError)?;
+    Ok(usage
+        .into_iter()
+        .map(|(requests, bytes)| (requests.unwrap_or(0), bytes.unwrap_or(0)))
+        .collect())
+}
+
+/// The number of members of a sorted set
+pub fn ping(con: &mut SyncConnection) -> Result<()> {
+    redis::cmd("PING")
+        .query::<String>(con)
+        .map(|_| ())
+        .map_err(RedisCMDError)
+}
+
+/// The `notify-keyspace-events` config of the server, e.g. `Kx`
+pub fn notify_keyspace_events(con: &mut SyncConnection) -> Result<String> {
+    let config: Vec<String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query(con)
+        .map_err(RedisCMDError)?;
+    Ok(config.get(1).cloned().unwrap_or_default())
+}
+
+/// Add `members` to a set, returns the number of members of the set.
+pub fn add_to_set(con: &mut SyncConnection, key: &str, members: &[String]) -> Result<usize> {
+    let (len,): (usize,) = redis::pipe()
+        .sadd(key, members)
+        .ignore()
+        .scard(key)
+        .query(con)
+        .map_err(RedisCMDError)?;
+    Ok(len)
+}
+
+/// Remove up to `count` random members of a set, returns them with the
+/// number of members left.
+pub fn pop_from_set(
+    con: &mut SyncConnection,
+    key: &str,
+    count: usize,
+) -> Result<(Vec<String>, usize)> {
+    redis::pipe()
+        .cmd("SPOP")
+        .arg(key)
+        .arg(count)
+        .scard(key)
+        .query(con)
+        .map_err(RedisCMDError)
+}
+
+/// Remove `members` from a set
+pub fn remove_from_set(con: &mut SyncConnection, key: &str, members: &[String]) -> Result<()> {
+    con.srem(key, members).map_err(RedisCMDError)
+}
+
+/// All members of a set, empty if it does not exist
+pub fn set_members(con: &mut SyncConnection, key: &str) -> Result<Vec<String>> {
+    con.smembers(key).map_err(RedisCMDError)
+}
+
+pub fn zcard(con: &mut SyncConnection, zlist_key: &str) -> Result<usize> {
+    con.zcard(zlist_key).map_err(RedisCMDError)
+}
+
+/// One iteration of `ZSCAN`, returns the next cursor and the members.
+/// The scan is complete when the cursor is 0.
+pub fn zscan_members(
+    con: &mut SyncConnection,
+    zlist_key: &str,
+    cursor: u64,
+    count: usize,
+) -> Result<(u64, Vec<String>)> {
+    let (cursor, entries): (u64, Vec<(String, String)>) = redis::cmd("ZSCAN")
+        .arg(zlist_key)
+        .arg(cursor)
+        .arg("COUNT")
+        .arg(count)
+        .query(con)
+        .map_err(RedisCMDError)?;
+    let members = entries.into_iter().map(|(member, _)| member).collect();
+    Ok((cursor, members))
+}
+
+/// Up to `count` members of a sorted set with their scores, from the lowest
+/// score, skipping the first `offset` ones
+pub fn zrange_with_scores(
+    con: &mut SyncConnection,
+    zlist_key: &str,
+    offset: usize,
+    count: usize,
+) -> Result<Vec<(String, i64)>> {
+    con.zrange_withscores(zlist_key, offset as isize, (offset + count) as isize - 1)
+        .map_err(RedisCMDError)
+}
+
+/// All fields of the hashes `keys` in a single round trip, empty for hashes
+/// that do not exist
+pub fn get_hashes(con: &mut SyncConnection, keys: &[String]) -> Result<Vec<Vec<(String, String)>>> {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.hgetall(key);
+    }
+    pipe.query(con).map_err(RedisCMDError)
+}
+
+/// Members of a sorted set of `len` members at up to `count` evenly spaced
+/// ranks, in a single round trip
+pub fn sample_zset_members(
+    con: &mut SyncConnection,
+    zlist_key: &str,
+    len: usize,
+    count: usize,
+) -> Result<Vec<String>> {
+    let count = count.min(len);
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    let mut pipe = redis::pipe();
+    for i in 0..count {
+        let rank = (i * len / count) as isize;
+        pipe.zrange(zlist_key, rank, rank);
+    }
+    let members: Vec<Vec<String>> = pipe.query(con).map_err(RedisCMDError)?;
+    Ok(members.into_iter().flatten().collect())
+}
+
+/// `MEMORY USAGE` of `keys` in bytes in a single round trip, `None` for keys
+/// that do not exist
+pub fn memory_usage(con: &mut SyncConnection, keys: &[String]) -> Result<Vec<Option<u64>>> {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.cmd("MEMORY").arg("USAGE").arg(key);
+    }
+    pipe.query(con).map_err(RedisCMDError)
+}
+
+/// Replace all LRU entries of a cache in a single transaction: the entries
+/// of the zlist are removed, then `entries` are set with their fields and
+/// zlist scores, and the total size is set to `total_size`.
+pub fn replace_lru_entries(
+    con: &mut SyncConnection,
+    total_size_key: &str,
+    zlist_key: &str,
+    entries: &[(String, Vec<(String, String)>, i64)],
+    total_size: u64,
+) -> Result<()> {
+    let existing: Vec<String> = con.zrange(zlist_key, 0, -1).map_err(RedisCMDError)?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in &existing {
+        pipe.del(key).ignore();
+    }
+    pipe.del(zlist_key).ignore();
+    for (key, fields, score) in entries {
+        pipe.del(key).ignore();
+        if !fields.is_empty() {
+            pipe.hset_multiple(key, fields).ignore();
+        }
+        pipe.zadd(zlist_key, key, *score).ignore();
+    }
+    pipe.set(total_size_key, total_size).ignore();
+    pipe.query::<()>(con).map_err(RedisCMDError)
+}
+
+/// Move LRU entries from the zlist `from_zlist` to `to_zlist` in a single
+/// transaction. Each `(member, target, score)` is removed from `from_zlist`,
+/// and unless `target` is `None`, its hash is renamed to `target`, which is
+/// added to `to_zlist` with `score`. The entries moved and dropped are added
+/// to the fields `moved` and `dropped` of the hash `progress_key`.
+pub fn move_lru_entries(
+    con: &mut SyncConnection,
+    from_zlist: &str,
+    to_zlist: &str,
+    moves: &[(String, Option<String>, i64)],
+    progress_key: &str,
+) -> Result<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    let mut moved = 0;
+    for (member, target, score) in moves {
+        pipe.zrem(from_zlist, member).ignore();
+        if let Some(target) = target {
+            pipe.rename(member, target).ignore();
+            pipe.zadd(to_zlist, target, *score).ignore();
+            moved += 1;
+        }
+    }
+    pipe.hincr(progress_key, "moved", moved).ignore();
+    let dropped = moves.len() - moved;
+    pipe.hincr(progress_key, "dropped", dropped).ignore();
+    pipe.query::<()>(con).map_err(RedisCMDError)
+}
+
+/// Rename the keys of `renames` that exist in a single transaction, the
+/// others are left out
+pub fn rename_existing(con: &mut SyncConnection, renames: &[(String, String)]) -> Result<()> {
+    let keys: Vec<String> = renames.iter().map(|(key, _)| key.clone()).collect();
+    let exist = keys_exist(con, &keys)?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for ((key, new_key), exists) in renames.iter().zip(exist) {
+        if exists {
+            pipe.rename(key, new_key).ignore();
+        }
+    }
+    pipe.query::<()>(con).map_err(RedisCMDError)
+}
+
+/// Set `keys` to `value`, expiring after `ttl` secs, in a single round trip
+pub fn set_expiring(
+    con: &mut SyncConnection,
+    keys: &[String],
+    value: &str,
+    ttl: usize,
+) -> Result<()> {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.set_ex(key, value, ttl).ignore();
+    }
+    pipe.query::<()>(con).map_err(RedisCMDError)
+}
+
+/// One iteration of `SCAN` over keys starting with `prefix`, returns the next
+/// cursor and the keys. The scan is complete when the cursor is 0.
+pub fn scan_prefixed_keys(
+    con: &mut SyncConnection,
+    prefix: &str,
+    cursor: u64,
+    count: usize,
+) -> Result<(u64, Vec<String>)> {
+    // escape glob characters of the prefix
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(count)
+        .query(con)
+        .map_err(RedisCMDError)
+}
+
 pub struct SledMetadata {
     pub atime: i64,
     pub size: u64,
+    pub hits: u64,
+    /// Hex encoded SHA-256 of the file
+    pub sha256: Option<String>,
+    /// Time the file is cached or last confirmed to be current, in secs
+    pub created_at: Option<i64>,
 }
 
+/// Length of a hex encoded SHA-256
+const SHA256_HEX_LEN: usize = 64;
+
 impl From<sled::IVec> for SledMetadata {
     fn from(vec: sled::IVec) -> Self {
+        // `created_at` follows the hit count, and the hash is last
+        let has_created_at = vec.len() > 24 && (vec.len() - 24) % SHA256_HEX_LEN == 8;
+        let sha256_start = if has_created_at { 32 } else { 24 };
         Self {
             atime: i64::from_be_bytes(vec.subslice(0, 8).as_ref().try_into().unwrap()),
             size: util::ivec_to_u64(&vec.subslice(8, 8)),
+            // entries of earlier versions have no hit count
+            hits: match vec.len() {
+                16 => 0,
+                _ => util::ivec_to_u64(&vec.subslice(16, 8)),
+            },
+            // nor hash
+            sha256: match vec.len() {
+                len if len <= sha256_start => None,
+                len => String::from_utf8(vec[sha256_start..len].to_vec()).ok(),
+            },
+            // nor creation time
+            created_at: if has_created_at {
+                Some(i64::from_be_bytes(vec[24..32].try_into().unwrap()))
+            } else {
+                None
+            },
         }
     }
 }
 
 impl From<SledMetadata> for sled::IVec {
     fn from(metadata: SledMetadata) -> Self {
-        [metadata.atime.to_be_bytes(), metadata.size.to_be_bytes()]
-            .concat()
-            .into()
+        let mut vec = [
+            metadata.atime.to_be_bytes(),
+            metadata.size.to_be_bytes(),
+            metadata.hits.to_be_bytes(),
+        ]
+        .concat();
+        if let Some(created_at) = metadata.created_at {
+            vec.extend_from_slice(&created_at.to_be_bytes());
+        }
+        if let Some(sha256) = metadata.sha256 {
+            vec.extend_from_slice(sha256.as_bytes());
+        }
+        vec.into()
     }
 }
 
-/// Update the atime for the given cache key.
+/// Update the atime for the given cache key, count a hit if `count_hit`, and
+/// replace `created_at` if set.
 /// This should be called within a transaction context to ensure atomicity.
 pub fn sled_update_cache_entry_atime(
     metadata_tree: &TransactionalTree,
     atime_tree: &TransactionalTree,
     key: &str,
     atime: i64,
+    count_hit: bool,
+    created_at: Option<i64>,
 ) {
     let old_entry: SledMetadata = metadata_tree.get(key).unwrap().unwrap().into();
     let old_atime = old_entry.atime;
@@ -151,25 +1060,50 @@ pub fn sled_update_cache_entry_atime(
     let new_metadata = SledMetadata {
         atime,
         size: old_entry.size,
+        hits: old_entry.hits + count_hit as u64,
+        sha256: old_entry.sha256,
+        created_at: created_at.or(old_entry.created_at),
     };
     metadata_tree.insert(key, new_metadata).unwrap();
     atime_tree.insert(&atime.to_be_bytes(), key).unwrap();
 }
 
+/// Insert or replace the entry of `key`. A replaced entry keeps its hit count.
 pub fn sled_insert_cache_entry(
     db: &TransactionalTree,
     prefix: &str,
     metadata_tree: &TransactionalTree,
     atime_tree: &TransactionalTree,
     key: &str,
-    size: u64,
-    atime: i64,
+    entry: SledMetadata,
 ) {
-    match metadata_tree.insert(key, SledMetadata { atime, size }) {
+    let (atime, size, sha256, created_at) = (
+        entry.atime,
+        entry.size,
+        entry.sha256.clone(),
+        entry.created_at,
+    );
+    match metadata_tree.insert(key, entry) {
         Ok(Some(old_entry)) => {
             // remove old entry in atime_tree
             let old_entry: SledMetadata = old_entry.into();
             atime_tree.remove(&old_entry.atime.to_be_bytes()).unwrap();
+            // a replaced file keeps the hit count of the key
+            if old_entry.hits > 0 {
+                let hits = old_entry.hits;
+                metadata_tree
+                    .insert(
+                        key,
+                        SledMetadata {
+                            atime,
+                            size,
+                            hits,
+                            sha256,
+                            created_at,
+                        },
+                    )
+                    .unwrap();
+            }
             sled_lru_set_current_size(
                 db,
                 prefix,
@@ -241,6 +1175,7 @@ pub fn sled_try_init_current_size(db: &TransactionalTree, prefix: &str) -> Resul
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cache::{CacheHitMiss, LruMetadataStore, RedisMetadataDb};
     use sled::IVec;
     use std::thread;
 
@@ -281,17 +1216,389 @@ mod tests {
         assert_eq!(val_actual, None);
     }
 
+    #[test]
+    fn set_lru_entry_script() {
+        let client = new_redis_client();
+        let mut con = client.get_connection().unwrap();
+        let (key, total_size_key, zlist_key) = (
+            "set_lru_entry/a.whl",
+            "set_lru_entry_total_size",
+            "set_lru_entry_cache_keys",
+        );
+        let _: () = redis::cmd("DEL")
+            .arg(&[key, total_size_key, zlist_key])
+            .query(&mut con)
+            .unwrap();
+        // the total size drifted, e.g. an entry of another instance
+        let _: () = con.set(total_size_key, 10).unwrap();
+        let mut entry = CacheEntry::new("a.whl", 5, Some("abc"));
+        entry.metadata.atime = 1000;
+        let total_size = set_lru_cache_entry(&mut con, key, &entry, total_size_key, zlist_key);
+        assert_eq!(total_size.unwrap(), 15);
+        let score: Option<i64> = con.zscore(zlist_key, key).unwrap();
+        assert_eq!(score, Some(1000));
+        // replaced, only the difference of the sizes is added
+        entry.metadata.size = 3;
+        entry.metadata.atime = 2000;
+        entry.metadata.tier = Some(Tier::Slow);
+        let total_size = set_lru_cache_entry(&mut con, key, &entry, total_size_key, zlist_key);
+        assert_eq!(total_size.unwrap(), 13);
+        let score: Option<i64> = con.zscore(zlist_key, key).unwrap();
+        assert_eq!(score, Some(2000));
+        let zcard: usize = con.zcard(zlist_key).unwrap();
+        assert_eq!(zcard, 1);
+        // the same file again, only its atime is updated
+        entry.metadata.atime = 2500;
+        entry.metadata.tier = None;
+        let total_size = set_lru_cache_entry(&mut con, key, &entry, total_size_key, zlist_key);
+        assert_eq!(total_size.unwrap(), 13);
+        let score: Option<i64> = con.zscore(zlist_key, key).unwrap();
+        assert_eq!(score, Some(2500));
+        let tier: Option<String> = con.hget(key, "tier").unwrap();
+        assert_eq!(tier.as_deref(), Some("slow"));
+        // unset fields are removed
+        entry.metadata.sha256 = None;
+        set_lru_cache_entry(&mut con, key, &entry, total_size_key, zlist_key).unwrap();
+        let sha256: Option<String> = con.hget(key, "sha256").unwrap();
+        let tier: Option<String> = con.hget(key, "tier").unwrap();
+        assert_eq!((sha256, tier), (None, None));
+        assert_eq!(
+            get_lru_cache_entry(&mut con, key).unwrap(),
+            Some(entry.metadata)
+        );
+    }
+
+    #[test]
+    fn touch_lru_entry_script() {
+        let client = new_redis_client();
+        let mut con = client.get_connection().unwrap();
+        let (key, zlist_key) = ("touch_lru_entry/a.whl", "touch_lru_entry_cache_keys");
+        let _: () = redis::cmd("DEL")
+            .arg(&[key, zlist_key])
+            .query(&mut con)
+            .unwrap();
+        // a missing entry is not brought back
+        assert!(!touch_lru_cache_entry(&mut con, key, 1000, zlist_key, true, None).unwrap());
+        assert!(!cache_entry_exists(&mut con, key).unwrap());
+        let zcard: usize = con.zcard(zlist_key).unwrap();
+        assert_eq!(zcard, 0);
+        let fields = fields(&[
+            ("path", "a.whl"),
+            ("size", "5"),
+            ("atime", "0"),
+            ("created_at", "7"),
+        ]);
+        let _: () = con.hset_multiple(key, &fields).unwrap();
+        // touched without a hit
+        assert!(touch_lru_cache_entry(&mut con, key, 1000, zlist_key, false, None).unwrap());
+        let entry = get_lru_cache_entry(&mut con, key).unwrap().unwrap();
+        assert_eq!(
+            (entry.atime, entry.hits, entry.created_at),
+            (1000, None, Some(7))
+        );
+        // a hit, confirming the file is current
+        assert!(touch_lru_cache_entry(&mut con, key, 2000, zlist_key, true, Some(9)).unwrap());
+        assert!(touch_lru_cache_entry(&mut con, key, 3000, zlist_key, true, None).unwrap());
+        let entry = get_lru_cache_entry(&mut con, key).unwrap().unwrap();
+        assert_eq!(
+            (entry.atime, entry.hits, entry.created_at),
+            (3000, Some(2), Some(9))
+        );
+        let score: Option<i64> = con.zscore(zlist_key, key).unwrap();
+        assert_eq!(score, Some(3000));
+        // a batch of lazy touches, skipping a missing entry
+        let missing = "touch_lru_entry/missing.whl";
+        let touches = [
+            (key.to_string(), 4000),
+            (missing.to_string(), 4000),
+            (key.to_string(), 5000),
+        ];
+        touch_lru_cache_entries(&mut con, &touches, zlist_key).unwrap();
+        let entry = get_lru_cache_entry(&mut con, key).unwrap().unwrap();
+        assert_eq!((entry.atime, entry.hits), (5000, Some(4)));
+        assert!(!cache_entry_exists(&mut con, missing).unwrap());
+        let zcard: usize = con.zcard(zlist_key).unwrap();
+        assert_eq!(zcard, 1);
+    }
+
+    #[tokio::test]
+    async fn lazy_atime_hit() {
+        let client = new_redis_client();
+        let db = RedisMetadataDb::new(client.clone(), "lazy_atime_hit")
+            .unwrap()
+            .with_lazy_atime(true);
+        db.replace_lru_entries(&[], 0).unwrap();
+        assert!(matches!(db.get_lru_entry("a.whl"), CacheHitMiss::Miss));
+        assert!(!db.lru_entry_exists("a.whl").unwrap());
+        db.set_lru_entry("a.whl", 5, None).unwrap();
+        db.set_lru_entry("b.whl", 5, None).unwrap();
+        // the atime and the hit count are updated in the background, within
+        // the flush interval
+        assert!(matches!(db.get_lru_entry("a.whl"), CacheHitMiss::Hit));
+        let mut stats = vec![];
+        for _ in 0..300 {
+            stats = db.lru_entry_stats(0, 10).unwrap();
+            if stats[1].hits == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let keys: Vec<&str> = stats.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, vec!["b.whl", "a.whl"]);
+        assert_eq!((stats[0].hits, stats[1].hits), (0, 1));
+        assert!(stats[1].atime > stats[0].atime);
+    }
+
+    /// Compares the scripted put and hit of an LRU entry with the same commands
+    /// sent one round trip at a time, and the lazy hit with the scripted one.
+    /// `cargo test lru_scripts_benchmark -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn lru_scripts_benchmark() {
+        let client = new_redis_client();
+        let mut con = client.get_connection().unwrap();
+        let (total_size_key, zlist_key) = ("lru_bench_total_size", "lru_bench_cache_keys");
+        let keys: Vec<String> = (0..1000).map(|i| format!("lru_bench/{}.whl", i)).collect();
+        let started = std::time::Instant::now();
+        for key in &keys {
+            let entry = CacheEntry::new(key, 5, Some("abc"));
+            set_lru_cache_entry(&mut con, key, &entry, total_size_key, zlist_key).unwrap();
+            touch_lru_cache_entry(&mut con, key, util::atime_millis(), zlist_key, true, None)
+                .unwrap();
+        }
+        let scripted_time = started.elapsed();
+        let started = std::time::Instant::now();
+        for key in &keys {
+            let entry = CacheEntry::new(key, 5, Some("abc"));
+            let old_size: Option<u64> = con.hget(key, "size").unwrap();
+            let _: i64 = con.decr(total_size_key, old_size.unwrap_or(0)).unwrap();
+            let _: i64 = con.incr(total_size_key, 5).unwrap();
+            let _: () = con
+                .hset_multiple(key, &entry.metadata.to_redis_fields())
+                .unwrap();
+            let _: i64 = con.zadd(zlist_key, key, entry.metadata.atime).unwrap();
+            assert!(cache_entry_exists(&mut con, key).unwrap());
+            let atime = util::atime_millis();
+            let _: i64 = con.hset(key, "atime", atime).unwrap();
+            let _: i64 = con.zadd(zlist_key, key, atime).unwrap();
+            let _: i64 = con.hincr(key, "hits", 1).unwrap();
+        }
+        let looped_time = started.elapsed();
+        let eager = RedisMetadataDb::new(client.clone(), "lru_bench").unwrap();
+        let lazy = RedisMetadataDb::new(client, "lru_bench")
+            .unwrap()
+            .with_lazy_atime(true);
+        for key in &keys {
+            eager.set_lru_entry(key, 5, None).unwrap();
+        }
+        let mut hit_times = vec![];
+        for db in &[eager, lazy] {
+            let started = std::time::Instant::now();
+            for key in &keys {
+                assert!(matches!(db.get_lru_entry(key), CacheHitMiss::Hit));
+            }
+            hit_times.push(started.elapsed());
+        }
+        println!(
+            "{} puts and hits: scripted {:?}, one command per round trip {:?}; {} hits: scripted {:?}, lazy_atime {:?}",
+            keys.len(),
+            scripted_time,
+            looped_time,
+            keys.len(),
+            hit_times[0],
+            hit_times[1]
+        );
+    }
+
+    #[test]
+    fn rescale_atime_secs_to_millis() {
+        let client = new_redis_client();
+        let mut con = client.get_connection().unwrap();
+        let zlist_key = "rescale_atime_cache_keys";
+        let _: () = redis::cmd("DEL").arg(zlist_key).query(&mut con).unwrap();
+        // an entry recorded in secs by an earlier version, and a newer one in millisecs
+        let old_atime = util::now() - 10;
+        let new_atime = util::atime_millis();
+        for (key, atime) in &[
+            ("rescale_atime/old", old_atime),
+            ("rescale_atime/new", new_atime),
+        ] {
+            let _: i64 = con.hset(key, "atime", *atime).unwrap();
+            let _: i64 = con.zadd(zlist_key, key, *atime).unwrap();
+        }
+        assert_eq!(rescale_lru_atime_secs(&mut con, zlist_key).unwrap(), 1);
+        let keys: Vec<String> = con.zrange(zlist_key, 0, -1).unwrap();
+        assert_eq!(keys, vec!["rescale_atime/old", "rescale_atime/new"]);
+        let atime: i64 = con.hget("rescale_atime/old", "atime").unwrap();
+        assert_eq!(atime, old_atime * 1000);
+        // nothing left to migrate
+        assert_eq!(rescale_lru_atime_secs(&mut con, zlist_key).unwrap(), 0);
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn entry_metadata_redis_fields() {
+        let metadata = EntryMetadata {
+            path: "pypi/packages/tool-1.0.tar.gz".into(),
+            size: 1024,
+            atime: 1_700_000_000_123,
+            sha256: Some("abc".into()),
+            created_at: Some(1_700_000_000),
+            hits: None,
+            url: None,
+            tier: None,
+            extra: BTreeMap::new(),
+        };
+        let mut redis_fields = metadata.to_redis_fields();
+        redis_fields.sort();
+        assert_eq!(
+            redis_fields,
+            fields(&[
+                ("atime", "1700000000123"),
+                ("created_at", "1700000000"),
+                ("path", "pypi/packages/tool-1.0.tar.gz"),
+                ("sha256", "abc"),
+                ("size", "1024"),
+            ])
+        );
+        assert_eq!(
+            EntryMetadata::from_redis_fields(redis_fields).unwrap(),
+            Some(metadata)
+        );
+        // recorded by an earlier version
+        let old = fields(&[("path", "a.whl"), ("size", "5"), ("atime", "233")]);
+        let metadata = EntryMetadata::from_redis_fields(old).unwrap().unwrap();
+        assert_eq!(
+            (metadata.size, metadata.created_at, metadata.hits),
+            (5, None, None)
+        );
+        let demoted = fields(&[
+            ("path", "a.whl"),
+            ("size", "5"),
+            ("atime", "233"),
+            ("tier", "slow"),
+        ]);
+        let metadata = EntryMetadata::from_redis_fields(demoted).unwrap().unwrap();
+        assert_eq!(metadata.tier, Some(Tier::Slow));
+        // a field of a newer version is kept through a round trip
+        let newer = fields(&[
+            ("path", "a.whl"),
+            ("size", "5"),
+            ("atime", "233"),
+            ("hits", "3"),
+            ("region", "eu"),
+        ]);
+        let metadata = EntryMetadata::from_redis_fields(newer.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.hits, Some(3));
+        assert_eq!(metadata.extra.get("region").map(String::as_str), Some("eu"));
+        let mut round_trip = metadata.to_redis_fields();
+        round_trip.sort();
+        let mut newer = newer;
+        newer.sort();
+        assert_eq!(round_trip, newer);
+
+        assert_eq!(EntryMetadata::from_redis_fields(vec![]).unwrap(), None);
+        let invalid = fields(&[("path", "a.whl"), ("size", "big"), ("atime", "233")]);
+        assert!(EntryMetadata::from_redis_fields(invalid).is_err());
+        assert!(EntryMetadata::from_redis_fields(fields(&[("path", "a.whl")])).is_err());
+    }
+
+    #[test]
+    fn set_lru_entry_keeps_unknown_fields() {
+        let client = new_redis_client();
+        let mut con = client.get_connection().unwrap();
+        let (key, total_size_key, zlist_key) = (
+            "entry_fields/a.whl",
+            "entry_fields_total_size",
+            "entry_fields_cache_keys",
+        );
+        let _: () = redis::cmd("DEL")
+            .arg(&[key, total_size_key, zlist_key])
+            .query(&mut con)
+            .unwrap();
+        let entry = CacheEntry::new("a.whl", 5, Some("abc"));
+        let total_size = set_lru_cache_entry(&mut con, key, &entry, total_size_key, zlist_key);
+        assert_eq!(total_size.unwrap(), 5);
+        assert_eq!(
+            get_lru_cache_entry(&mut con, key).unwrap(),
+            Some(entry.metadata)
+        );
+        // written by a newer version, and hit
+        let _: () = con.hset(key, "region", "eu").unwrap();
+        let touches = [(key.to_string(), util::atime_millis())];
+        touch_lru_cache_entries(&mut con, &touches, zlist_key).unwrap();
+        // a new file of the key, without a hash
+        let entry = CacheEntry::new("a.whl", 7, None);
+        let total_size = set_lru_cache_entry(&mut con, key, &entry, total_size_key, zlist_key);
+        assert_eq!(total_size.unwrap(), 7);
+        let metadata = get_lru_cache_entry(&mut con, key).unwrap().unwrap();
+        assert_eq!(metadata.size, 7);
+        assert_eq!(metadata.sha256, None);
+        assert_eq!(metadata.hits, Some(1));
+        assert_eq!(metadata.extra.get("region").map(String::as_str), Some("eu"));
+        assert_eq!(
+            get_lru_cache_entry(&mut con, "entry_fields/none").unwrap(),
+            None
+        );
+    }
+
     #[test]
     fn sled_metadata_to_ivec() {
         let metadata = SledMetadata {
             atime: 233,
             size: 0xaabbccdddeadbeef,
+            hits: 7,
+            sha256: None,
+            created_at: None,
         };
         let ivec: IVec = metadata.into();
         assert_eq!(
             ivec,
-            vec![0, 0, 0, 0, 0, 0, 0, 233, 0xaa, 0xbb, 0xcc, 0xdd, 0xde, 0xad, 0xbe, 0xef]
+            vec![
+                0, 0, 0, 0, 0, 0, 0, 233, 0xaa, 0xbb, 0xcc, 0xdd, 0xde, 0xad, 0xbe, 0xef, 0, 0, 0,
+                0, 0, 0, 0, 7
+            ]
         );
+        let metadata: SledMetadata = ivec.into();
+        assert_eq!(metadata.hits, 7);
+        assert_eq!(metadata.sha256, None);
+        let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let ivec: IVec = SledMetadata {
+            atime: 233,
+            size: 5,
+            hits: 7,
+            sha256: Some(sha256.to_string()),
+            created_at: None,
+        }
+        .into();
+        assert_eq!(ivec.len(), 24 + 64);
+        let metadata: SledMetadata = ivec.into();
+        assert_eq!(metadata.hits, 7);
+        assert_eq!(metadata.sha256.as_deref(), Some(sha256));
+        assert_eq!(metadata.created_at, None);
+        // with and without a hash
+        for sha256 in [None, Some(sha256.to_string())] {
+            let ivec: IVec = SledMetadata {
+                atime: 233,
+                size: 5,
+                hits: 7,
+                sha256: sha256.clone(),
+                created_at: Some(1_700_000_000),
+            }
+            .into();
+            let metadata: SledMetadata = ivec.into();
+            assert_eq!(metadata.hits, 7);
+            assert_eq!(metadata.sha256, sha256);
+            assert_eq!(metadata.created_at, Some(1_700_000_000));
+        }
     }
 
     #[test]
@@ -303,5 +1610,7 @@ mod tests {
         let metadata: SledMetadata = ivec.into();
         assert_eq!(metadata.atime, 233);
         assert_eq!(metadata.size, 0xaabbccdddeadbeef);
+        // recorded before hits were counted
+        assert_eq!(metadata.hits, 0);
     }
 }
@@ -0,0 +1,106 @@
+//! Offline mode: cache hits are served, and upstreams are never contacted.
+//! It is enabled globally or per rule by the settings, and can be switched at
+//! runtime through the admin API.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// Offline mode set at runtime. Overrides take precedence over the settings.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct OfflineOverrides {
+    /// Applies to all rules without an override of their own
+    pub global: Option<bool>,
+    /// By rule name
+    pub rules: BTreeMap<String, bool>,
+}
+
+impl OfflineOverrides {
+    /// Whether a rule is offline. The override of the rule wins over the
+    /// global override, which wins over the `offline` option of the rule and
+    /// then the global setting.
+    pub fn is_offline(
+        &self,
+        rule: &str,
+        rule_setting: Option<bool>,
+        global_setting: Option<bool>,
+    ) -> bool {
+        self.rules
+            .get(rule)
+            .copied()
+            .or(self.global)
+            .or(rule_setting)
+            .or(global_setting)
+            .unwrap_or(false)
+    }
+}
+
+/// Offline mode of rules, and the overrides it is computed with
+#[derive(Debug, Clone, Serialize)]
+pub struct OfflineStatus {
+    /// Whether each rule is offline, by rule name
+    pub rules: BTreeMap<String, bool>,
+    pub overrides: OfflineOverrides,
+}
+
+/// Overrides set through the admin API, kept across config reloads
+pub struct OfflineSwitch {
+    overrides: RwLock<OfflineOverrides>,
+}
+
+impl OfflineSwitch {
+    pub fn new() -> Self {
+        Self {
+            overrides: RwLock::new(OfflineOverrides::default()),
+        }
+    }
+
+    pub fn overrides(&self) -> OfflineOverrides {
+        self.overrides.read().unwrap().clone()
+    }
+
+    /// Override the offline mode of `rule`, or of all rules if `None`.
+    /// `enabled` is `None` to fall back to the settings.
+    pub fn set(&self, rule: Option<&str>, enabled: Option<bool>) {
+        let mut overrides = self.overrides.write().unwrap();
+        match (rule, enabled) {
+            (Some(rule), Some(enabled)) => {
+                overrides.rules.insert(rule.to_string(), enabled);
+            }
+            (Some(rule), None) => {
+                overrides.rules.remove(rule);
+            }
+            (None, enabled) => overrides.global = enabled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offline_precedence() {
+        let switch = OfflineSwitch::new();
+        let is_offline = |rule_setting, global_setting| {
+            switch
+                .overrides()
+                .is_offline("pypi", rule_setting, global_setting)
+        };
+        assert!(!is_offline(None, None));
+        assert!(is_offline(None, Some(true)));
+        assert!(!is_offline(Some(false), Some(true)));
+
+        // cut the cord of all rules
+        switch.set(None, Some(true));
+        assert!(is_offline(Some(false), None));
+        switch.set(Some("pypi"), Some(false));
+        assert!(!is_offline(None, Some(true)));
+        assert!(switch.overrides().is_offline("conda", None, None));
+
+        // back to the settings
+        switch.set(Some("pypi"), None);
+        switch.set(None, None);
+        assert_eq!(switch.overrides(), OfflineOverrides::default());
+        assert!(!is_offline(Some(false), Some(true)));
+    }
+}
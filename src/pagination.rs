@@ -0,0 +1,211 @@
+//! Upstream documents served page by page, e.g. the tag lists of Docker
+//! registries, each page linking to the next one with a
+//! `Link: <url>; rel="next"` header. Rules with `paginate` follow the links
+//! and cache the pages merged as one document under the key of the first
+//! page, see `Options::paginate`.
+
+use crate::error::{Error, Result};
+use crate::settings::PageMerge;
+use crate::util::{self, TextBody};
+use regex::Regex;
+use reqwest::Url;
+use serde_json::Value;
+
+/// Default most pages of a document, the first one included
+pub const DEFAULT_MAX_PAGES: usize = 100;
+
+/// Default most pages fetched before a request is answered
+pub const DEFAULT_FOREGROUND_PAGES: usize = 3;
+
+lazy_static::lazy_static! {
+    /// A link of a `Link` header and its parameters
+    static ref LINK: Regex = Regex::new(r"<([^>]*)>((?:\s*;[^;,]*)*)").unwrap();
+    /// The `rel` parameter of a link, a space separated list of relations
+    static ref REL: Regex = Regex::new(r#"(?i)^\s*rel\s*=\s*"?([^"]*)"?\s*$"#).unwrap();
+}
+
+/// The url of the page following a response, resolved against its url
+pub fn next_link(res: &reqwest::Response) -> Option<Url> {
+    res.headers()
+        .get_all(reqwest::header::LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find_map(|value| parse_next_link(value, res.url()))
+}
+
+/// The link of a `Link` header value with the relation `next`
+fn parse_next_link(value: &str, base: &Url) -> Option<Url> {
+    LINK.captures_iter(value).find_map(|link| {
+        let next = link[2]
+            .split(';')
+            .filter_map(|param| REL.captures(param))
+            .any(|rel| {
+                rel[1]
+                    .split_whitespace()
+                    .any(|r| r.eq_ignore_ascii_case("next"))
+            });
+        if next {
+            base.join(link[1].trim()).ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// The pages of a document, from the first one
+#[derive(Debug)]
+pub struct Pages {
+    pub pages: Vec<String>,
+    /// Whether the last page is the last one of the document, i.e. it does
+    /// not link to a next page
+    pub complete: bool,
+}
+
+/// Read `first` and the pages following it, up to `max_pages` pages in all,
+/// requested with `accept` like the first one. Fails if a page is over
+/// `max_body` bytes, is not answered with `200 OK`, or is linked on another
+/// origin than the first one.
+pub async fn follow(
+    client: &reqwest::Client,
+    first: reqwest::Response,
+    accept: Option<&str>,
+    max_pages: usize,
+    max_body: u64,
+) -> Result<Pages> {
+    let origin = first.url().origin();
+    let mut next = next_link(&first);
+    let mut pages = vec![read_page(first, max_body).await?];
+    while let Some(url) = next {
+        if pages.len() >= max_pages {
+            return Ok(Pages {
+                pages,
+                complete: false,
+            });
+        }
+        if url.origin() != origin {
+            return Err(Error::UpstreamUnavailable(format!(
+                "the next page {} is on another origin",
+                url
+            )));
+        }
+        let res = util::make_negotiated_request(client, url.as_str(), accept).await?;
+        if res.status() != reqwest::StatusCode::OK {
+            return Err(Error::UpstreamUnavailable(format!(
+                "page {} answered {}",
+                url,
+                res.status()
+            )));
+        }
+        next = next_link(&res);
+        pages.push(read_page(res, max_body).await?);
+    }
+    Ok(Pages {
+        pages,
+        complete: true,
+    })
+}
+
+async fn read_page(res: reqwest::Response, max_body: u64) -> Result<String> {
+    let url = res.url().clone();
+    match util::response_text(res, max_body).await? {
+        TextBody::Text(text) => Ok(text),
+        TextBody::TooLarge(_, _) => Err(Error::UpstreamUnavailable(format!(
+            "page {} is over max_rewrite_body_bytes",
+            url
+        ))),
+    }
+}
+
+/// Merge the pages of a document. With `PageMerge::Json`, pages that are
+/// arrays are concatenated, and so are the array fields of pages that are
+/// objects, whose other fields are the ones of the first page.
+pub fn merge(pages: Vec<String>, merge: PageMerge) -> Result<String> {
+    if merge == PageMerge::Concat {
+        return Ok(pages.concat());
+    }
+    let mut merged: Option<Value> = None;
+    for page in &pages {
+        let page: Value = serde_json::from_str(page)
+            .map_err(|e| Error::OtherError(format!("invalid JSON page: {}", e)))?;
+        merged = Some(match merged {
+            Some(merged) => merge_json(merged, page)?,
+            None => page,
+        });
+    }
+    Ok(merged.map_or_else(String::new, |merged| merged.to_string()))
+}
+
+fn merge_json(merged: Value, page: Value) -> Result<Value> {
+    match (merged, page) {
+        (Value::Array(mut items), Value::Array(more)) => {
+            items.extend(more);
+            Ok(Value::Array(items))
+        }
+        (Value::Object(mut fields), Value::Object(more)) => {
+            for (name, value) in more {
+                if !fields.contains_key(&name) {
+                    fields.insert(name, value);
+                    continue;
+                }
+                if let (Some(Value::Array(items)), Value::Array(more)) =
+                    (fields.get_mut(&name), value)
+                {
+                    items.extend(more);
+                }
+            }
+            Ok(Value::Object(fields))
+        }
+        _ => Err(Error::OtherError(
+            "JSON pages are neither all arrays nor all objects".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn next_links() {
+        let base = Url::parse("https://registry.corp/v2/tool/tags/list?n=2").unwrap();
+        let next = |value: &str| parse_next_link(value, &base).map(String::from);
+        assert_eq!(
+            next(r#"</v2/tool/tags/list?n=2&last=b>; rel="next""#).as_deref(),
+            Some("https://registry.corp/v2/tool/tags/list?n=2&last=b")
+        );
+        assert_eq!(
+            next(r#"<https://registry.corp/a?page=1>; rel="prev", <?page=3>; rel=next"#).as_deref(),
+            Some("https://registry.corp/v2/tool/tags/list?page=3")
+        );
+        assert_eq!(
+            next(r#"<?page=3>; title="more"; rel="next last""#).as_deref(),
+            Some("https://registry.corp/v2/tool/tags/list?page=3")
+        );
+        assert_eq!(next(r#"<?page=1>; rel="prev""#), None);
+        assert_eq!(next("garbage"), None);
+    }
+
+    #[test]
+    fn merge_pages() {
+        let pages =
+            |pages: &[&str]| -> Vec<String> { pages.iter().map(|page| page.to_string()).collect() };
+        assert_eq!(
+            merge(pages(&["[1, 2]", "[3]", "[]"]), PageMerge::Json).unwrap(),
+            "[1,2,3]"
+        );
+        let tags = pages(&[
+            r#"{"name": "tool", "tags": ["1.0", "1.1"]}"#,
+            r#"{"name": "tool", "tags": ["2.0"]}"#,
+        ]);
+        assert_eq!(
+            merge(tags, PageMerge::Json).unwrap(),
+            r#"{"name":"tool","tags":["1.0","1.1","2.0"]}"#
+        );
+        assert!(merge(pages(&["[1]", "{}"]), PageMerge::Json).is_err());
+        assert!(merge(pages(&["[1]", "<html>"]), PageMerge::Json).is_err());
+        assert_eq!(
+            merge(pages(&["a\n", "b\n"]), PageMerge::Concat).unwrap(),
+            "a\nb\n"
+        );
+    }
+}
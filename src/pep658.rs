@@ -0,0 +1,166 @@
+//! Metadata files of distributions, see PEP 658 and PEP 714. An index page
+//! advertises the core metadata of a file with a `data-core-metadata` (or the
+//! older `data-dist-info-metadata`) attribute of its link, or a
+//! `core-metadata` (`dist-info-metadata`) key of the file in the PEP 691 JSON
+//! representation. The metadata is served at the url of the file followed
+//! by `.metadata`, so rewrites of the links carry it over to the mirror.
+//! Rules with `prefetch_metadata` fetch the advertised metadata files when
+//! an index page is cached, since pip asks for them in bursts.
+
+use crate::listing::HREF;
+use regex::Regex;
+use reqwest::Url;
+
+/// Suffix of the url of the metadata file of a distribution
+pub const METADATA_SUFFIX: &str = ".metadata";
+
+lazy_static::lazy_static! {
+    /// The start tag of a link
+    static ref ANCHOR: Regex = Regex::new(r"(?i)<a\s[^>]*>").unwrap();
+    /// The attribute advertising the metadata of the file of a link, and its
+    /// value if any, e.g. `sha256=<hash>` or `true`
+    static ref METADATA_ATTR: Regex = Regex::new(
+        r#"(?i)\sdata-(?:core|dist-info)-metadata(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+)))?"#
+    )
+    .unwrap();
+}
+
+/// The urls of the metadata files advertised by an index page served at
+/// `page`, in order of appearance, `json` if it is the PEP 691 JSON
+/// representation
+pub fn metadata_urls(content: &str, page: &Url, json: bool) -> Vec<Url> {
+    let files = if json {
+        json_files(content)
+    } else {
+        html_files(content)
+    };
+    let mut urls: Vec<Url> = Vec::new();
+    for href in files {
+        let mut url = match page.join(&href) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+            _ => continue,
+        };
+        url.set_fragment(None);
+        let path = format!("{}{}", url.path(), METADATA_SUFFIX);
+        url.set_path(&path);
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// The hrefs of the links of an html page advertising their metadata
+fn html_files(content: &str) -> Vec<String> {
+    ANCHOR
+        .find_iter(content)
+        .filter_map(|anchor| {
+            let anchor = anchor.as_str();
+            let attr = METADATA_ATTR.captures(anchor)?;
+            let value = attr.get(1).or_else(|| attr.get(2)).or_else(|| attr.get(3));
+            // a bare attribute advertises the metadata too
+            if value.map_or(false, |value| value.as_str().eq_ignore_ascii_case("false")) {
+                return None;
+            }
+            let href = HREF.captures(anchor)?;
+            let href = href.get(2).or_else(|| href.get(3))?.as_str();
+            Some(href.replace("&amp;", "&"))
+        })
+        .collect()
+}
+
+/// The urls of the files of a JSON page advertising their metadata, with
+/// `true` or the hashes of the metadata file
+fn json_files(content: &str) -> Vec<String> {
+    let page: serde_json::Value = match serde_json::from_str(content) {
+        Ok(page) => page,
+        Err(_) => return vec![],
+    };
+    let files = match page.get("files").and_then(|files| files.as_array()) {
+        Some(files) => files,
+        None => return vec![],
+    };
+    files
+        .iter()
+        .filter(|file| {
+            ["core-metadata", "dist-info-metadata"]
+                .iter()
+                .filter_map(|key| file.get(key))
+                .any(|value| value.as_bool() == Some(true) || value.is_object())
+        })
+        .filter_map(|file| Some(file.get("url")?.as_str()?.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn page() -> Url {
+        Url::parse("https://pypi.org/simple/tool/").unwrap()
+    }
+
+    fn strings(urls: Vec<Url>) -> Vec<String> {
+        urls.into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn metadata_of_html_pages() {
+        let content = "<!DOCTYPE html>\n<html><body>\n\
+            <a href=\"https://files.corp/packages/a/tool-1.0-py3-none-any.whl#sha256=ab\" \
+            data-requires-python=\"&gt;=3.8\" data-dist-info-metadata=\"sha256=cd\" \
+            data-core-metadata=\"sha256=cd\">tool-1.0-py3-none-any.whl</a><br/>\n\
+            <a data-core-metadata='true' href='../../packages/b/tool-1.1-py3-none-any.whl'>\
+            w</a>\n\
+            <a href=\"../../packages/c/tool-1.2-py3-none-any.whl\" data-core-metadata>w</a>\n\
+            <a href=\"../../packages/d/tool-1.3.tar.gz\">sdist</a>\n\
+            <a href=\"../../packages/e/tool-1.4.tar.gz\" data-core-metadata=\"false\">s</a>\n\
+            </body></html>\n";
+        assert_eq!(
+            strings(metadata_urls(content, &page(), false)),
+            vec![
+                "https://files.corp/packages/a/tool-1.0-py3-none-any.whl.metadata",
+                "https://pypi.org/packages/b/tool-1.1-py3-none-any.whl.metadata",
+                "https://pypi.org/packages/c/tool-1.2-py3-none-any.whl.metadata",
+            ]
+        );
+        assert!(metadata_urls("<a href=\"a.whl\">a</a>", &page(), false).is_empty());
+    }
+
+    #[test]
+    fn metadata_of_json_pages() {
+        let content = r#"{
+            "meta": {"api-version": "1.1"},
+            "name": "tool",
+            "files": [
+                {
+                    "filename": "tool-1.0-py3-none-any.whl",
+                    "url": "https://files.pythonhosted.org/packages/a/tool-1.0-py3-none-any.whl",
+                    "hashes": {"sha256": "ab"},
+                    "core-metadata": {"sha256": "cd"},
+                    "dist-info-metadata": {"sha256": "cd"}
+                },
+                {
+                    "filename": "tool-1.1-py3-none-any.whl",
+                    "url": "../../packages/b/tool-1.1-py3-none-any.whl",
+                    "hashes": {},
+                    "dist-info-metadata": true
+                },
+                {
+                    "filename": "tool-1.2.tar.gz",
+                    "url": "../../packages/c/tool-1.2.tar.gz",
+                    "hashes": {},
+                    "core-metadata": false
+                }
+            ]
+        }"#;
+        assert_eq!(
+            strings(metadata_urls(content, &page(), true)),
+            vec![
+                "https://files.pythonhosted.org/packages/a/tool-1.0-py3-none-any.whl.metadata",
+                "https://pypi.org/packages/b/tool-1.1-py3-none-any.whl.metadata",
+            ]
+        );
+        assert!(metadata_urls("<html></html>", &page(), true).is_empty());
+    }
+}
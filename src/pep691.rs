@@ -0,0 +1,125 @@
+//! Content negotiation of PyPI simple index pages, see PEP 691. Clients like
+//! recent pip ask for the JSON representation of the pages with an `Accept`
+//! header listing `application/vnd.pypi.simple.v1+json` first. Pages of
+//! `pep503` rules are cached once per representation: the HTML one under the
+//! key of the page, and the JSON one under the key followed by
+//! `JSON_KEY_SUFFIX`, see `Task::simple_json`.
+
+use std::time::Duration;
+
+/// Media type of the JSON representation, sent upstream and served back
+pub const JSON_MEDIA_TYPE: &str = "application/vnd.pypi.simple.v1+json";
+
+/// Media types of the JSON representation a client may accept
+const JSON_MEDIA_TYPES: &[&str] = &[JSON_MEDIA_TYPE, "application/vnd.pypi.simple.latest+json"];
+
+/// Media types of the HTML representation a client may accept, wildcards
+/// included: the HTML representation is served by default
+const HTML_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.pypi.simple.v1+html",
+    "application/vnd.pypi.simple.latest+html",
+    "text/html",
+    "text/*",
+    "*/*",
+];
+
+/// Suffix of the keys of the JSON representation of index pages
+pub const JSON_KEY_SUFFIX: &str = ".v1+json";
+
+/// How long an upstream is assumed not to serve the JSON representation of
+/// a page after it answered it with HTML or `406 Not Acceptable`
+pub const JSON_UNSUPPORTED_TTL: Duration = Duration::from_secs(300);
+
+/// Most pages remembered as not served in JSON
+pub const JSON_UNSUPPORTED_MAX_ENTRIES: usize = 10000;
+
+/// Whether a client sending `accept` prefers the JSON representation, i.e.
+/// it accepts it with a quality at least as high as the HTML one
+pub fn prefers_json(accept: Option<&str>) -> bool {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let json = quality(accept, JSON_MEDIA_TYPES);
+    json > 0.0 && json >= quality(accept, HTML_MEDIA_TYPES)
+}
+
+/// The highest quality of `media_types` in an `Accept` header, 0 if none
+/// is accepted
+pub fn quality(accept: &str, media_types: &[&str]) -> f32 {
+    let mut best: f32 = 0.0;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_type = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if !media_types.contains(&media_type.as_str()) {
+            continue;
+        }
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        best = best.max(q);
+    }
+    best
+}
+
+/// Whether an upstream response is the JSON representation of a page
+pub fn is_json_response(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            let media_type = value.split(';').next().unwrap_or_default().trim();
+            JSON_MEDIA_TYPES.contains(&media_type.to_ascii_lowercase().as_str())
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiate_representation() {
+        // sent by pip
+        let pip = "application/vnd.pypi.simple.v1+json, \
+                   application/vnd.pypi.simple.v1+html; q=0.1, text/html; q=0.01";
+        assert!(prefers_json(Some(pip)));
+        assert!(prefers_json(Some(
+            "application/vnd.pypi.simple.latest+json"
+        )));
+        assert!(prefers_json(Some(
+            "*/*, application/vnd.pypi.simple.v1+json"
+        )));
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(Some("text/html")));
+        assert!(!prefers_json(Some("application/json")));
+        assert!(!prefers_json(Some(
+            "text/html, application/vnd.pypi.simple.v1+json; q=0.5"
+        )));
+        assert!(!prefers_json(Some(
+            "application/vnd.pypi.simple.v1+json; q=0"
+        )));
+    }
+
+    #[test]
+    fn json_responses() {
+        let response = |content_type: &str| {
+            reqwest::Response::from(
+                warp::http::Response::builder()
+                    .header("Content-Type", content_type)
+                    .body("")
+                    .unwrap(),
+            )
+        };
+        assert!(is_json_response(&response(JSON_MEDIA_TYPE)));
+        assert!(is_json_response(&response(
+            "application/vnd.pypi.simple.v1+json; charset=utf-8"
+        )));
+        assert!(!is_json_response(&response("text/html")));
+    }
+}
@@ -0,0 +1,154 @@
+//! Protective refresh of LRU caches: entries about to be evicted, but hit
+//! often, are marked as used so that they survive the next evictions.
+//! Keeping a large and popular file is cheaper than downloading it again.
+
+use crate::cache::{CacheSizeType, LruEntryStats};
+use crate::settings::ProtectiveRefresh;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_INTERVAL: u64 = 3600;
+pub const DEFAULT_COLD_PERCENT: u64 = 10;
+pub const DEFAULT_MIN_HITS: u64 = 10;
+pub const DEFAULT_MAX_BYTES_PER_RUN: &str = "1 GB";
+
+/// Thresholds of a run, with the defaults of unset options
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thresholds {
+    pub cold_percent: u64,
+    pub min_hits: u64,
+    pub min_size: CacheSizeType,
+    pub max_bytes: CacheSizeType,
+}
+
+impl From<&ProtectiveRefresh> for Thresholds {
+    /// Sizes are checked when the settings are loaded
+    fn from(options: &ProtectiveRefresh) -> Self {
+        let parse = |size: Option<&String>, default: &str| {
+            bytefmt::parse(size.map_or(default, String::as_str)).unwrap_or(0)
+        };
+        Self {
+            cold_percent: options.cold_percent.unwrap_or(DEFAULT_COLD_PERCENT),
+            min_hits: options.min_hits.unwrap_or(DEFAULT_MIN_HITS),
+            min_size: parse(options.min_size.as_ref(), "0"),
+            max_bytes: parse(
+                options.max_bytes_per_run.as_ref(),
+                DEFAULT_MAX_BYTES_PER_RUN,
+            ),
+        }
+    }
+}
+
+/// Split cold entries into the ones to protect, most hit first, and the ones
+/// left to be evicted. Protected entries add up to at most `max_bytes`.
+pub fn select(
+    entries: Vec<LruEntryStats>,
+    thresholds: &Thresholds,
+) -> (Vec<LruEntryStats>, Vec<LruEntryStats>) {
+    let (mut candidates, mut unprotected): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|entry| entry.hits >= thresholds.min_hits && entry.size >= thresholds.min_size);
+    // the sort is stable, so the colder of entries hit as often comes first
+    candidates.sort_by(|a, b| b.hits.cmp(&a.hits));
+    let mut protected = Vec::new();
+    let mut protected_bytes: CacheSizeType = 0;
+    for entry in candidates {
+        if protected_bytes + entry.size <= thresholds.max_bytes {
+            protected_bytes += entry.size;
+            protected.push(entry);
+        } else {
+            unprotected.push(entry);
+        }
+    }
+    (protected, unprotected)
+}
+
+/// Outcome of a protective refresh of a cache
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ProtectionReport {
+    /// Number of cold entries inspected
+    pub inspected: usize,
+    pub protected: usize,
+    pub protected_bytes: CacheSizeType,
+    /// Cold entries left to be evicted
+    pub unprotected: usize,
+}
+
+/// When the cache of each policy was last refreshed, kept across config
+/// reloads
+pub struct RefreshSchedule {
+    last_runs: Mutex<HashMap<String, Instant>>,
+}
+
+impl RefreshSchedule {
+    pub fn new() -> Self {
+        Self {
+            last_runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the cache of `policy` is due for a refresh every `interval`.
+    /// A due refresh is recorded as run.
+    pub fn is_due(&self, policy: &str, interval: Duration) -> bool {
+        let mut last_runs = self.last_runs.lock().unwrap();
+        match last_runs.get(policy) {
+            Some(last_run) if last_run.elapsed() < interval => false,
+            _ => {
+                last_runs.insert(policy.to_string(), Instant::now());
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(key: &str, size: CacheSizeType, hits: u64) -> LruEntryStats {
+        LruEntryStats {
+            key: key.to_string(),
+            size,
+            atime: 0,
+            hits,
+        }
+    }
+
+    #[test]
+    fn select_protected_entries() {
+        let thresholds = Thresholds {
+            cold_percent: 10,
+            min_hits: 5,
+            min_size: 100,
+            max_bytes: 1000,
+        };
+        let entries = vec![
+            entry("small.whl", 10, 50),
+            entry("cold.whl", 400, 5),
+            entry("rare.whl", 500, 1),
+            entry("hot.whl", 500, 20),
+            entry("warm.whl", 400, 8),
+        ];
+        let (protected, unprotected) = select(entries, &thresholds);
+        let keys = |entries: &[LruEntryStats]| -> Vec<String> {
+            entries.iter().map(|entry| entry.key.clone()).collect()
+        };
+        // the most hit entries until the budget is spent
+        assert_eq!(keys(&protected), vec!["hot.whl", "warm.whl"]);
+        assert_eq!(
+            keys(&unprotected),
+            vec!["small.whl", "rare.whl", "cold.whl"]
+        );
+    }
+
+    #[test]
+    fn refresh_schedule() {
+        let schedule = RefreshSchedule::new();
+        let interval = Duration::from_secs(3600);
+        assert!(schedule.is_due("policy_lru", interval));
+        assert!(!schedule.is_due("policy_lru", interval));
+        assert!(schedule.is_due("policy_lru", Duration::from_secs(0)));
+        assert!(schedule.is_due("policy_ubuntu", interval));
+    }
+}
@@ -0,0 +1,418 @@
+//! Soft quotas of requests and bytes served to each client over a rolling
+//! window. Usage is counted in redis in buckets of a tenth of the window,
+//! which expire once they leave the window, so that instances sharing redis
+//! enforce the same quotas. A request is rejected once a quota is reached, and
+//! the bytes of a response are counted after it is served, so a client may
+//! exceed its byte quota by one response.
+
+use crate::error::Result;
+use crate::keys::QUOTA_ID;
+use crate::models;
+use crate::settings::{QuotaLimits, Quotas};
+use crate::util;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::warn;
+
+pub const DEFAULT_WINDOW: u64 = 3600;
+
+/// Number of buckets of a window
+const BUCKETS: u64 = 10;
+
+/// Number of keys asked for in each `SCAN` of the usage
+const SCAN_COUNT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Limits {
+    pub requests: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+impl Limits {
+    /// Byte limits are checked when settings are loaded
+    fn new(requests: Option<u64>, bytes: Option<&String>) -> Self {
+        Self {
+            requests,
+            bytes: bytes.map(|bytes| bytefmt::parse(bytes).unwrap()),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.requests.is_none() && self.bytes.is_none()
+    }
+}
+
+/// A client of quotas, identified by `token:<label>` or `ip:<address>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub id: String,
+    pub limits: Limits,
+}
+
+/// Usage of the quotas of a client in the current window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuotaUsage {
+    pub client: String,
+    pub requests: u64,
+    pub bytes: u64,
+    pub limits: Limits,
+}
+
+pub struct QuotaTracker {
+    redis_client: redis::Client,
+    /// Secs of a bucket
+    bucket_len: u64,
+    /// Number of buckets in the window
+    buckets: u64,
+    exempt_hits: bool,
+    default: Limits,
+    /// token -> (label, limits)
+    tokens: HashMap<String, (String, Limits)>,
+}
+
+impl QuotaTracker {
+    pub fn from_settings(settings: &Quotas, redis_client: redis::Client) -> Self {
+        let window = settings.window.unwrap_or(DEFAULT_WINDOW);
+        let bucket_len = (window + BUCKETS - 1) / BUCKETS;
+        let default = settings
+            .default
+            .as_ref()
+            .map_or_else(Limits::default, |limits: &QuotaLimits| {
+                Limits::new(limits.requests, limits.bytes.as_ref())
+            });
+        let tokens = settings
+            .tokens
+            .iter()
+            .flatten()
+            .map(|token| {
+                let limits = Limits::new(token.requests, token.bytes.as_ref());
+                (token.token.expose().clone(), (token.label.clone(), limits))
+            })
+            .collect();
+        Self {
+            redis_client,
+            bucket_len,
+            buckets: (window + bucket_len - 1) / bucket_len,
+            exempt_hits: settings.exempt_hits.unwrap_or(false),
+            default,
+            tokens,
+        }
+    }
+
+    /// Whether bytes served from the cache are left out of byte quotas
+    pub fn exempt_hits(&self) -> bool {
+        self.exempt_hits
+    }
+
+    /// The client of a request: the label of its `Authorization: Bearer`
+    /// token if it is one of the quota tokens, or else its IP address
+    pub fn client(&self, authorization: Option<&str>, addr: Option<SocketAddr>) -> Client {
+        let token = authorization.and_then(|value| value.strip_prefix("Bearer "));
+        if let Some((label, limits)) = token.and_then(|token| self.tokens.get(token)) {
+            return Client {
+                id: format!("token:{}", label),
+                limits: *limits,
+            };
+        }
+        Client {
+            id: format!(
+                "ip:{}",
+                addr.map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
+            ),
+            limits: self.default,
+        }
+    }
+
+    /// Count a request of `client`. If one of its quotas is reached, the
+    /// request is not counted, and the secs after which it may be retried
+    /// are returned.
+    pub fn acquire(&self, client: &Client) -> Result<Option<u64>> {
+        self.acquire_at(client, util::now() as u64)
+    }
+
+    fn acquire_at(&self, client: &Client, now: u64) -> Result<Option<u64>> {
+        if client.limits.is_unlimited() {
+            return Ok(None);
+        }
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let current = now / self.bucket_len;
+        let first = (current + 1).saturating_sub(self.buckets);
+        let keys: Vec<String> = (first..=current)
+            .map(|bucket| self.bucket_key(&client.id, bucket))
+            .collect();
+        let usage = models::get_quota_usage(&mut con, &keys)?;
+        let requests: Vec<(u64, u64)> = (first..=current)
+            .zip(usage.iter().map(|(requests, _)| *requests))
+            .collect();
+        let bytes: Vec<(u64, u64)> = (first..=current)
+            .zip(usage.iter().map(|(_, bytes)| *bytes))
+            .collect();
+        let retry_after = [
+            (client.limits.requests, requests),
+            (client.limits.bytes, bytes),
+        ]
+        .iter()
+        .filter_map(|(limit, usage)| limit.and_then(|limit| self.retry_after(usage, limit, now)))
+        .max();
+        if retry_after.is_some() {
+            return Ok(retry_after);
+        }
+        models::incr_quota_usage(&mut con, &keys[keys.len() - 1], 1, 0, self.expire())?;
+        Ok(None)
+    }
+
+    /// Count the bytes of a response served to `client`
+    pub fn record_bytes(&self, client: &Client, bytes: u64) -> Result<()> {
+        self.record_bytes_at(client, bytes, util::now() as u64)
+    }
+
+    fn record_bytes_at(&self, client: &Client, bytes: u64, now: u64) -> Result<()> {
+        if client.limits.bytes.is_none() {
+            return Ok(());
+        }
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let key = self.bucket_key(&client.id, now / self.bucket_len);
+        models::incr_quota_usage(&mut con, &key, 0, bytes, self.expire())
+    }
+
+    /// Usage of all clients with a quota and requests in the current window,
+    /// by client
+    pub fn usage(&self) -> Result<Vec<QuotaUsage>> {
+        self.usage_at(util::now() as u64)
+    }
+
+    fn usage_at(&self, now: u64) -> Result<Vec<QuotaUsage>> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let prefix = format!("{}/", QUOTA_ID);
+        let first = (now / self.bucket_len + 1).saturating_sub(self.buckets);
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) = models::scan_prefixed_keys(&mut con, &prefix, cursor, SCAN_COUNT)?;
+            keys.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        // the bucket is the last segment, labels may contain `/`
+        let keys: Vec<(String, String)> = keys
+            .into_iter()
+            .filter_map(|key| {
+                let (client, bucket) = key.strip_prefix(&prefix)?.rsplit_once('/')?;
+                if bucket.parse::<u64>().ok()? < first {
+                    return None;
+                }
+                Some((client.to_string(), key.clone()))
+            })
+            .collect();
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let redis_keys: Vec<String> = keys.iter().map(|(_, key)| key.clone()).collect();
+        let usage = models::get_quota_usage(&mut con, &redis_keys)?;
+        let mut by_client: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for ((client, _), (requests, bytes)) in keys.into_iter().zip(usage) {
+            let total = by_client.entry(client).or_default();
+            total.0 += requests;
+            total.1 += bytes;
+        }
+        Ok(by_client
+            .into_iter()
+            .map(|(client, (requests, bytes))| QuotaUsage {
+                limits: self.limits_of(&client),
+                client,
+                requests,
+                bytes,
+            })
+            .collect())
+    }
+
+    fn limits_of(&self, client: &str) -> Limits {
+        self.tokens
+            .values()
+            .find(|(label, _)| client.strip_prefix("token:") == Some(label.as_str()))
+            .map_or(self.default, |(_, limits)| *limits)
+    }
+
+    fn bucket_key(&self, client: &str, bucket: u64) -> String {
+        format!("{}/{}/{}", QUOTA_ID, client, bucket)
+    }
+
+    /// Buckets expire once they leave the window
+    fn expire(&self) -> u64 {
+        (self.buckets + 1) * self.bucket_len
+    }
+
+    /// Secs until the usage of the buckets, from the oldest, drops below
+    /// `limit` as they leave the window, `None` if it is below already
+    fn retry_after(&self, usage: &[(u64, u64)], limit: u64, now: u64) -> Option<u64> {
+        let mut total: u64 = usage.iter().map(|(_, used)| used).sum();
+        if total < limit {
+            return None;
+        }
+        for (bucket, used) in usage {
+            total -= used;
+            if total < limit {
+                let leaves_window = (bucket + self.buckets) * self.bucket_len;
+                return Some(leaves_window.saturating_sub(now).max(1));
+            }
+        }
+        None
+    }
+}
+
+/// Counts the bytes served to a client, and records them in its usage when
+/// it is dropped, e.g. at the end of a streamed response
+pub struct ByteCounter {
+    quotas: Arc<QuotaTracker>,
+    client: Client,
+    bytes: u64,
+}
+
+impl ByteCounter {
+    pub fn new(quotas: Arc<QuotaTracker>, client: Client) -> Self {
+        Self {
+            quotas,
+            client,
+            bytes: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for ByteCounter {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+        if let Err(e) = self.quotas.record_bytes(&self.client, self.bytes) {
+            warn!(
+                "failed to count the bytes served to {}: {}",
+                self.client.id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secret::Secret;
+    use crate::settings::QuotaToken;
+
+    fn tracker(window: u64, exempt_hits: bool) -> QuotaTracker {
+        let settings = Quotas {
+            window: Some(window),
+            exempt_hits: Some(exempt_hits),
+            default: Some(QuotaLimits {
+                requests: Some(100),
+                bytes: None,
+            }),
+            tokens: Some(vec![QuotaToken {
+                label: "ci".to_string(),
+                token: Secret::new("ci-token".to_string()),
+                requests: Some(2),
+                bytes: Some("100 B".to_string()),
+            }]),
+        };
+        let redis_client = redis::Client::open("redis://localhost:3001/").unwrap();
+        QuotaTracker::from_settings(&settings, redis_client)
+    }
+
+    /// A client with the limits of the `ci` token, and a unique id
+    fn ci_client(tracker: &QuotaTracker, name: &str) -> Client {
+        let client = tracker.client(Some("Bearer ci-token"), None);
+        Client {
+            id: format!("{}-{}-{}", client.id, name, util::now_nanos()),
+            ..client
+        }
+    }
+
+    #[test]
+    fn clients() {
+        let tracker = tracker(3600, false);
+        let addr: SocketAddr = "10.0.0.1:4321".parse().unwrap();
+        let ci = tracker.client(Some("Bearer ci-token"), Some(addr));
+        assert_eq!(ci.id, "token:ci");
+        assert_eq!(ci.limits.requests, Some(2));
+        assert_eq!(ci.limits.bytes, Some(100));
+        let anonymous = tracker.client(Some("Bearer unknown"), Some(addr));
+        assert_eq!(anonymous.id, "ip:10.0.0.1");
+        assert_eq!(anonymous.limits.requests, Some(100));
+        assert_eq!(tracker.client(None, None).id, "ip:unknown");
+        assert_eq!((tracker.bucket_len, tracker.buckets), (360, 10));
+        let tracker = super::test::tracker(25, false);
+        assert_eq!((tracker.bucket_len, tracker.buckets), (3, 9));
+    }
+
+    #[test]
+    fn retry_after_oldest_buckets_leave_the_window() {
+        // buckets of 1 sec, 10 in the window
+        let tracker = tracker(10, false);
+        let usage = [(100, 1), (105, 3), (109, 1)];
+        assert_eq!(tracker.retry_after(&usage, 6, 109), None);
+        // bucket 100 leaves the window at 110
+        assert_eq!(tracker.retry_after(&usage, 5, 109), Some(1));
+        // bucket 105 leaves the window at 115
+        assert_eq!(tracker.retry_after(&usage, 2, 109), Some(6));
+        assert_eq!(tracker.retry_after(&usage, 1, 109), Some(10));
+    }
+
+    #[test]
+    fn request_quota_then_recovery() {
+        let tracker = tracker(10, false);
+        let client = ci_client(&tracker, "requests");
+        let now = util::now() as u64;
+        assert_eq!(tracker.acquire_at(&client, now).unwrap(), None);
+        assert_eq!(tracker.acquire_at(&client, now + 1).unwrap(), None);
+        // the third request in the window is rejected, and not counted
+        let retry_after = tracker.acquire_at(&client, now + 2).unwrap().unwrap();
+        assert_eq!(retry_after, 8);
+        assert!(tracker.acquire_at(&client, now + 9).unwrap().is_some());
+        // the first request leaves the window
+        assert_eq!(tracker.acquire_at(&client, now + 10).unwrap(), None);
+        assert!(tracker.acquire_at(&client, now + 10).unwrap().is_some());
+    }
+
+    #[test]
+    fn byte_quota() {
+        let tracker = tracker(10, true);
+        assert!(tracker.exempt_hits());
+        let client = ci_client(&tracker, "bytes");
+        let now = util::now() as u64;
+        assert_eq!(tracker.acquire_at(&client, now).unwrap(), None);
+        tracker.record_bytes_at(&client, 100, now).unwrap();
+        assert_eq!(tracker.acquire_at(&client, now + 3).unwrap(), Some(7));
+        let usage = tracker.usage_at(now + 3).unwrap();
+        let usage = usage.iter().find(|u| u.client == client.id).unwrap();
+        assert_eq!((usage.requests, usage.bytes), (1, 100));
+        assert_eq!(tracker.acquire_at(&client, now + 10).unwrap(), None);
+        // unlimited clients are not counted
+        let unlimited = Client {
+            id: client.id.clone(),
+            limits: Limits::default(),
+        };
+        assert_eq!(tracker.acquire_at(&unlimited, now + 10).unwrap(), None);
+        let usage = tracker.usage_at(now + 10).unwrap();
+        let usage = usage.iter().find(|u| u.client == client.id).unwrap();
+        assert_eq!((usage.requests, usage.bytes), (1, 0));
+    }
+
+    #[test]
+    fn byte_counter_records_on_drop() {
+        let tracker = Arc::new(tracker(10, false));
+        let client = ci_client(&tracker, "counter");
+        let mut counter = ByteCounter::new(tracker.clone(), client.clone());
+        counter.add(10);
+        counter.add(32);
+        drop(counter);
+        let usage = tracker.usage().unwrap();
+        let usage = usage.iter().find(|u| u.client == client.id).unwrap();
+        assert_eq!((usage.requests, usage.bytes), (0, 42));
+    }
+}
@@ -0,0 +1,108 @@
+//! Redirects of upstreams cached as entries of their own, see
+//! `Options::cache_redirects`. An entry holds the status and the `Location`
+//! of a redirect, and is kept under the key of the task followed by
+//! `REDIRECT_KEY_SUFFIX`, next to where the file would be cached.
+
+use bytes::Bytes;
+use reqwest::Url;
+
+/// Suffix of the keys of cached redirects
+pub const REDIRECT_KEY_SUFFIX: &str = ".redirect";
+
+/// Statuses of the redirects that are cached. `300 Multiple Choices` and
+/// `304 Not Modified` are not redirects to a single location.
+const REDIRECT_STATUSES: &[u16] = &[301, 302, 303, 307, 308];
+
+/// The key of the cached redirect of the task of `key`
+pub fn redirect_key(key: &str) -> String {
+    format!("{}{}", key, REDIRECT_KEY_SUFFIX)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedRedirect {
+    pub status: u16,
+    pub location: String,
+}
+
+impl CachedRedirect {
+    /// The redirect answered for `url`, its `Location` resolved against
+    /// `url`. `None` if the response is not a redirect to a valid location.
+    pub fn from_response(url: &str, res: &reqwest::Response) -> Option<Self> {
+        let status = res.status().as_u16();
+        if !REDIRECT_STATUSES.contains(&status) {
+            return None;
+        }
+        let location = res
+            .headers()
+            .get(reqwest::header::LOCATION)?
+            .to_str()
+            .ok()?;
+        let location = Url::parse(url).ok()?.join(location).ok()?;
+        Some(Self {
+            status,
+            location: location.to_string(),
+        })
+    }
+
+    /// The content of the cache entry
+    pub fn to_bytes(&self) -> Bytes {
+        Bytes::from(serde_json::to_vec(self).unwrap())
+    }
+
+    /// The redirect of a cache entry, `None` if the entry is not one
+    pub fn parse(content: &[u8]) -> Option<Self> {
+        let redirect: Self = serde_json::from_slice(content).ok()?;
+        if REDIRECT_STATUSES.contains(&redirect.status) {
+            Some(redirect)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(status: u16, location: Option<&str>) -> reqwest::Response {
+        let mut builder = warp::http::Response::builder().status(status);
+        if let Some(location) = location {
+            builder = builder.header("Location", location);
+        }
+        reqwest::Response::from(builder.body("").unwrap())
+    }
+
+    #[test]
+    fn redirect_from_response() {
+        let url = "https://releases.corp/tool/latest";
+        let redirect = CachedRedirect::from_response(url, &response(302, Some("v1.2/tool.tgz")));
+        assert_eq!(
+            redirect,
+            Some(CachedRedirect {
+                status: 302,
+                location: "https://releases.corp/tool/v1.2/tool.tgz".to_string(),
+            })
+        );
+        let cdn = "https://cdn.corp/tool.tgz?sig=abc";
+        let redirect = CachedRedirect::from_response(url, &response(307, Some(cdn))).unwrap();
+        assert_eq!(redirect.location, cdn);
+        assert!(CachedRedirect::from_response(url, &response(302, None)).is_none());
+        assert!(CachedRedirect::from_response(url, &response(304, Some(cdn))).is_none());
+        assert!(CachedRedirect::from_response(url, &response(200, Some(cdn))).is_none());
+    }
+
+    #[test]
+    fn redirect_entries() {
+        let redirect = CachedRedirect {
+            status: 301,
+            location: "http://mirror.corp/tool/v1.2/tool.tgz".to_string(),
+        };
+        assert_eq!(CachedRedirect::parse(&redirect.to_bytes()), Some(redirect));
+        assert!(CachedRedirect::parse(b"\x1f\x8b").is_none());
+        assert!(CachedRedirect::parse(br#"{"status": 200, "location": "/"}"#).is_none());
+        assert_eq!(
+            redirect_key("releases/tool/latest"),
+            "releases/tool/latest.redirect"
+        );
+    }
+}
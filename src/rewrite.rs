@@ -0,0 +1,361 @@
+//! Streaming rewrites of upstream responses. Documents like the root PyPI
+//! simple index are tens of MB, so they are rewritten chunk by chunk instead
+//! of being read whole: only the bytes that may start a match split across
+//! two chunks are held back.
+
+use crate::error::Result;
+use crate::settings::Rewrite;
+
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use regex::bytes::Regex;
+
+/// Replaces a literal in a stream of chunks, like `str::replace` on the whole
+/// document
+struct Replacer {
+    from: Regex,
+    from_len: usize,
+    to: Vec<u8>,
+    /// The tail of the previous chunks, which may start a match
+    carry: Vec<u8>,
+}
+
+impl Replacer {
+    fn push(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        self.carry.extend_from_slice(chunk);
+        let buf = std::mem::take(&mut self.carry);
+        let mut pos = 0;
+        while let Some(m) = self.from.find_at(&buf, pos) {
+            out.extend_from_slice(&buf[pos..m.start()]);
+            out.extend_from_slice(&self.to);
+            pos = m.end();
+        }
+        // a match may start in the last `from_len - 1` bytes
+        let keep = (self.from_len - 1).min(buf.len() - pos);
+        out.extend_from_slice(&buf[pos..buf.len() - keep]);
+        self.carry = buf[buf.len() - keep..].to_vec();
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.carry);
+    }
+}
+
+/// Applies the rewrites of a rule in order to a document received in chunks.
+/// The output is the same as `TaskManager::rewrite_upstream` on the whole
+/// document.
+pub struct StreamRewriter {
+    replacers: Vec<Replacer>,
+}
+
+impl StreamRewriter {
+    /// `None` if a rewrite needs the whole document, i.e. it has a
+    /// `json_field` or nothing to replace
+    pub fn new(rewrites: &[Rewrite]) -> Option<Self> {
+        let mut replacers = Vec::with_capacity(rewrites.len());
+        for rewrite in rewrites {
+            if rewrite.json_field.is_some() || rewrite.from.is_empty() {
+                return None;
+            }
+            replacers.push(Replacer {
+                from: Regex::new(&regex::escape(&rewrite.from)).ok()?,
+                from_len: rewrite.from.len(),
+                to: rewrite.to.as_bytes().to_vec(),
+                carry: Vec::new(),
+            });
+        }
+        Some(Self { replacers })
+    }
+
+    /// Like `new`, but also `None` if the response is compressed, since it is
+    /// decoded whole
+    pub fn for_response(res: &reqwest::Response, rewrites: &[Rewrite]) -> Option<Self> {
+        let encoding = res
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if !encoding.is_empty() && encoding != "identity" {
+            return None;
+        }
+        Self::new(rewrites)
+    }
+
+    /// Rewrite the next chunk. Bytes that may start a match are held back
+    /// until the next chunk or `finish`.
+    pub fn push(&mut self, chunk: &[u8]) -> Bytes {
+        let mut input = chunk.to_vec();
+        for replacer in self.replacers.iter_mut() {
+            let mut out = Vec::with_capacity(input.len());
+            replacer.push(&input, &mut out);
+            input = out;
+        }
+        input.into()
+    }
+
+    /// Rewrite the bytes held back at the end of the document
+    pub fn finish(&mut self) -> Bytes {
+        let mut input: Vec<u8> = Vec::new();
+        for replacer in self.replacers.iter_mut() {
+            let mut out = Vec::with_capacity(input.len());
+            replacer.push(&input, &mut out);
+            replacer.finish(&mut out);
+            input = out;
+        }
+        input.into()
+    }
+}
+
+/// Rewrite a stream of chunks with `rewriter`. The stream ends at the first
+/// error.
+pub fn rewrite_stream<S>(
+    chunks: S,
+    rewriter: StreamRewriter,
+) -> impl Stream<Item = Result<Bytes>> + Send
+where
+    S: Stream<Item = Result<Bytes>> + Send + Unpin,
+{
+    stream::unfold(Some((chunks, rewriter)), |state| async move {
+        let (mut chunks, mut rewriter) = state?;
+        loop {
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    let rewritten = rewriter.push(&chunk);
+                    if !rewritten.is_empty() {
+                        return Some((Ok(rewritten), Some((chunks, rewriter))));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => {
+                    let rest = rewriter.finish();
+                    return if rest.is_empty() {
+                        None
+                    } else {
+                        Some((Ok(rest), None))
+                    };
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::TaskManager;
+
+    fn rewrite(from: &str, to: &str) -> Rewrite {
+        Rewrite {
+            from: from.to_string(),
+            to: to.to_string(),
+            json_field: None,
+        }
+    }
+
+    fn rewrite_in_chunks(rewrites: &[Rewrite], content: &str, chunk_len: usize) -> String {
+        let mut rewriter = StreamRewriter::new(rewrites).unwrap();
+        let mut out = Vec::new();
+        for chunk in content.as_bytes().chunks(chunk_len) {
+            out.extend_from_slice(&rewriter.push(chunk));
+        }
+        out.extend_from_slice(&rewriter.finish());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn same_as_whole_document() {
+        let rewrites = vec![
+            rewrite("cat", "dog"),
+            rewrite("dog", "bird"),
+            rewrite("aa", "a"),
+            rewrite("https://files.pythonhosted.org/", "/pypi/"),
+        ];
+        let content = "cat dog caat aaaa ca t <a href=\"https://files.pythonhosted.org/x\">x</a> c";
+        let expected = TaskManager::rewrite_upstream(content.to_string(), &rewrites);
+        for chunk_len in 1..=content.len() {
+            assert_eq!(
+                rewrite_in_chunks(&rewrites, content, chunk_len),
+                expected,
+                "chunks of {} bytes",
+                chunk_len
+            );
+        }
+    }
+
+    /// Rewrite `content` split in two at every offset, including in the middle
+    /// of multi-byte characters
+    fn assert_every_split(rewrites: &[Rewrite], content: &str) {
+        let expected = TaskManager::rewrite_upstream(content.to_string(), rewrites);
+        let bytes = content.as_bytes();
+        for split in 0..=bytes.len() {
+            let mut rewriter = StreamRewriter::new(rewrites).unwrap();
+            let mut out = rewriter.push(&bytes[..split]).to_vec();
+            out.extend_from_slice(&rewriter.push(&bytes[split..]));
+            out.extend_from_slice(&rewriter.finish());
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                expected,
+                "split at byte {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn url_straddling_chunks() {
+        let rewrites = vec![rewrite("https://files.pythonhosted.org/", "/pypi/")];
+        assert_every_split(
+            &rewrites,
+            "<a href=\"https://files.pythonhosted.org/packages/a.whl\">a</a>\n\
+             <a href=\"https://files.pythonhosted.org/packages/b.whl\">b</a>",
+        );
+        // a partial match right before a full one
+        assert_every_split(
+            &rewrites,
+            "https://files.pythonhttps://files.pythonhosted.org/x",
+        );
+    }
+
+    #[test]
+    fn multi_byte_characters_at_split() {
+        let rewrites = vec![
+            rewrite("https://files.pythonhosted.org/", "/pypi/"),
+            rewrite("ünïcødé/", "ascii/"),
+            rewrite("→", "->"),
+        ];
+        assert_every_split(
+            &rewrites,
+            "<a href=\"https://files.pythonhosted.org/ünïcødé/日本語.whl\">日本語 → 🦀</a>\n\
+             <a href=\"https://files.pythonhosted.org/€/ünïcødé/\">ünïcødé→</a>",
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_stream_at_every_offset() {
+        let rewrites = vec![rewrite("https://files.pythonhosted.org/", "/pypi/")];
+        let content = "é<a href=\"https://files.pythonhosted.org/日本/x.whl\">x</a>é";
+        let expected = TaskManager::rewrite_upstream(content.to_string(), &rewrites);
+        let bytes = Bytes::from_static(content.as_bytes());
+        for chunk_len in 1..=bytes.len() {
+            let chunks: Vec<Result<Bytes>> = (0..bytes.len())
+                .step_by(chunk_len)
+                .map(|start| Ok(bytes.slice(start..(start + chunk_len).min(bytes.len()))))
+                .collect();
+            let rewriter = StreamRewriter::new(&rewrites).unwrap();
+            let out: Vec<Bytes> = rewrite_stream(stream::iter(chunks), rewriter)
+                .map(|chunk| chunk.unwrap())
+                .collect()
+                .await;
+            assert_eq!(
+                String::from_utf8(out.concat()).unwrap(),
+                expected,
+                "chunks of {} bytes",
+                chunk_len
+            );
+        }
+    }
+
+    #[test]
+    fn hash_fragments_kept() {
+        let rewrites = vec![rewrite("https://files.pythonhosted.org/", "/pypi/")];
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let content = format!(
+            "<a href=\"https://files.pythonhosted.org/packages/a.whl#sha256={}\" \
+             data-requires-python=\"&gt;=3.8\">a.whl</a>",
+            hash
+        );
+        let expected = format!(
+            "<a href=\"/pypi/packages/a.whl#sha256={}\" \
+             data-requires-python=\"&gt;=3.8\">a.whl</a>",
+            hash
+        );
+        assert_eq!(
+            TaskManager::rewrite_upstream(content.clone(), &rewrites),
+            expected
+        );
+        for chunk_len in 1..=content.len() {
+            assert_eq!(rewrite_in_chunks(&rewrites, &content, chunk_len), expected);
+        }
+    }
+
+    #[test]
+    fn whole_document_rewrites() {
+        assert!(StreamRewriter::new(&[rewrite("", "x")]).is_none());
+        let mut json = rewrite("a", "b");
+        json.json_field = Some("@id".to_string());
+        assert!(StreamRewriter::new(&[rewrite("a", "b"), json]).is_none());
+        assert!(StreamRewriter::new(&[]).is_some());
+    }
+
+    /// The root index of a PyPI mirror, ~20 MB
+    fn root_index_line(i: usize) -> String {
+        format!("    <a href=\"/simple/project-{}/\">project-{}</a>\n", i, i)
+    }
+
+    #[test]
+    fn memory_ceiling_of_large_document() {
+        const CHUNK_LEN: usize = 16 * 1024;
+        const PROJECTS: usize = 400_000;
+        let rewrites = vec![
+            rewrite("href=\"/simple/", "href=\""),
+            rewrite("project-", "mirrored-project-"),
+        ];
+        let max_held_back = rewrites.iter().map(|r| r.from.len()).sum::<usize>();
+        let mut rewriter = StreamRewriter::new(&rewrites).unwrap();
+        let mut input = Vec::with_capacity(CHUNK_LEN * 2);
+        // rewritten output not compared yet, at most a line
+        let mut pending = Vec::new();
+        let mut line = 0;
+        let mut total = 0;
+        let mut check = |out: &[u8], pending: &mut Vec<u8>| {
+            pending.extend_from_slice(out);
+            while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                let expected = format!(
+                    "    <a href=\"mirrored-project-{}/\">mirrored-project-{}</a>\n",
+                    line, line
+                );
+                assert_eq!(&pending[..=end], expected.as_bytes());
+                pending.drain(..=end);
+                line += 1;
+            }
+        };
+        for i in 0..PROJECTS {
+            input.extend_from_slice(root_index_line(i).as_bytes());
+            if input.len() < CHUNK_LEN {
+                continue;
+            }
+            total += input.len();
+            let out = rewriter.push(&input);
+            // nothing more than a partial match is held back
+            let held_back: usize = rewriter.replacers.iter().map(|r| r.carry.len()).sum();
+            assert!(held_back < max_held_back);
+            assert!(out.len() <= input.len() * 2);
+            check(&out, &mut pending);
+            assert!(pending.len() < CHUNK_LEN);
+            input.clear();
+        }
+        total += input.len();
+        let out = rewriter.push(&input);
+        check(&out, &mut pending);
+        check(&rewriter.finish(), &mut pending);
+        assert!(pending.is_empty());
+        assert_eq!(line, PROJECTS);
+        assert!(total > 20 * 1000 * 1000);
+    }
+
+    #[tokio::test]
+    async fn rewrite_chunk_stream() {
+        let chunks: Vec<Result<Bytes>> = vec![
+            Ok(Bytes::from_static(b"<a href=\"/sim")),
+            Ok(Bytes::from_static(b"ple/flask/\">flask</a>")),
+        ];
+        let rewriter = StreamRewriter::new(&[rewrite("href=\"/simple/", "href=\"")]).unwrap();
+        let out: Vec<Bytes> = rewrite_stream(stream::iter(chunks), rewriter)
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(out.concat(), b"<a href=\"flask/\">flask</a>".to_vec());
+    }
+}
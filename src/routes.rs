@@ -0,0 +1,594 @@
+//! The HTTP routes of the server. Requests of paths that are not canonical
+//! are redirected first. Builtin routes are tried before the routes of the
+//! rules, each group is a boxed filter of its own, see `build_routes`.
+//! Requests are answered by the `handlers` of `main`, and requests no route
+//! serves by `handlers::handle_rejection`.
+
+use super::*;
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+/// The routes of the server: redirects to canonical paths first, then the
+/// builtin routes of the management API, then the routes of the rules. Rules
+/// are resolved by `RULE_MATCHER` when a request comes, so that reloaded
+/// settings apply without rebuilding routes.
+pub fn build_routes() -> BoxedFilter<(Response,)> {
+    let routes = canonical()
+        .or(builtin_routes())
+        .unify()
+        .or(rule_routes())
+        .unify()
+        .or(unmatched())
+        .unify();
+    let routes = request_limits()
+        .and(routes)
+        .recover(handlers::handle_rejection);
+    request_id()
+        .and(routes)
+        .map(|id: String, reply| warp::reply::with_header(reply, telemetry::REQUEST_ID_HEADER, id))
+        .with(warp::trace(|info| {
+            tracing::info_span!(
+                "request",
+                method = %info.method(),
+                path = %info.path(),
+                request_id = tracing::field::Empty,
+            )
+        }))
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// The builtin routes by method and path, without the leading `/`. `{}`
+/// stands for a segment and `**` for the rest of the path. Requests of
+/// these paths with other methods are answered with `405 Method Not
+/// Allowed`, see `handlers::handle_rejection`, and with a trailing slash
+/// are redirected without it, see `canonical_path`. Kept in sync with
+/// `builtin_routes` by the `builtin_routes_registry` test.
+pub const BUILTIN_ROUTES: &[(&str, &str)] = &[
+    ("GET", "admin/audit"),
+    ("DELETE", "admin/cache/{}/entries"),
+    ("DELETE", "admin/cache/{}"),
+    ("GET", "admin/cache/{}/usage"),
+    ("GET", "admin/cache/{}/eviction-preview"),
+    ("POST", "admin/cache/{}/contains"),
+    ("GET", "admin/cache/{}/hot-keys"),
+    ("GET", "admin/jobs/{}"),
+    ("GET", "admin/offline"),
+    ("PUT", "admin/offline"),
+    ("DELETE", "admin/offline"),
+    ("GET", "admin/bypass"),
+    ("POST", "admin/bypass"),
+    ("GET", "admin/quotas"),
+    ("GET", "admin/upstreams"),
+    ("POST", "admin/fetch"),
+    ("GET", "admin/snapshots"),
+    ("POST", "admin/snapshots"),
+    ("GET", "admin/snapshots/{}"),
+    ("DELETE", "admin/snapshots/{}"),
+    ("GET", "admin/config"),
+    ("GET", "api/v1/spec"),
+    ("GET", "api/v1/caches/{}/stats"),
+    ("GET", "api/v1/caches/{}/entries"),
+    ("DELETE", "api/v1/caches/{}/entries"),
+    ("PUT", "api/v1/caches/{}/pins"),
+    ("POST", "api/v1/warmup"),
+    ("GET", "api/v1/jobs"),
+    ("GET", "api/v1/jobs/{}"),
+    ("GET", "api/v1/tasks"),
+    ("GET", "ready"),
+    ("GET", "snapshot/{}/**"),
+];
+
+/// The methods of the builtin routes of `path`, without its leading `/`
+pub fn builtin_methods(path: &str) -> Vec<&'static str> {
+    BUILTIN_ROUTES
+        .iter()
+        .filter(|(_, route)| matches_route(route, path))
+        .map(|(method, _)| *method)
+        .collect()
+}
+
+/// Whether `path` is the path of `route`, see `BUILTIN_ROUTES`
+fn matches_route(route: &str, path: &str) -> bool {
+    let mut segments = path.split('/');
+    for part in route.split('/') {
+        match (part, segments.next()) {
+            ("**", _) => return true,
+            ("{}", Some(segment)) if !segment.is_empty() => {}
+            (part, Some(segment)) if part == segment => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+/// The canonical form of `path`, a request path, if it is not: normalized
+/// by `util::normalize_path`, and without a trailing slash for the builtin
+/// routes, but for the rest of a snapshot path, which is the path of a
+/// rule. Requests are redirected to it, so that each resource has one url
+/// and rules resolve canonical paths to tasks, see `Task::to_key`.
+pub fn canonical_path(path: &str) -> Option<String> {
+    let mut canonical = util::normalize_path(path);
+    let route_path = canonical.trim_start_matches('/');
+    if route_path.ends_with('/') && is_builtin_route(route_path.trim_end_matches('/')) {
+        canonical.pop();
+    }
+    Some(canonical).filter(|canonical| canonical != path)
+}
+
+/// Whether `path` is the path of a builtin route, other than the rest of a
+/// snapshot path
+fn is_builtin_route(path: &str) -> bool {
+    BUILTIN_ROUTES
+        .iter()
+        .any(|(_, route)| !route.ends_with("**") && matches_route(route, path))
+}
+
+/// Whether `path`, without its leading `/`, is under one of
+/// `settings::BUILTIN_PATHS`
+pub fn is_builtin_path(path: &str) -> bool {
+    settings::BUILTIN_PATHS.iter().any(|builtin| {
+        path.starts_with(builtin) && (builtin.ends_with('/') || path.len() == builtin.len())
+    })
+}
+
+/// `/admin/...`, `/api/v1/...`, `/ready` and `/snapshot/...`, see
+/// `settings::BUILTIN_PATHS`. Other paths are left to the rules.
+/// Rules claiming their paths are refused when settings are loaded.
+fn builtin_routes() -> BoxedFilter<(Response,)> {
+    let builtin_path = warp::path::full()
+        .and_then(|path: warp::filters::path::FullPath| async move {
+            if is_builtin_path(path.as_str().trim_start_matches('/')) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
+    let routes = admin_audit()
+        .or(admin_purge())
+        .or(admin_purge_all())
+        .or(admin_usage())
+        .or(admin_eviction_preview())
+        .or(admin_contains())
+        .or(admin_hot_keys())
+        .or(admin_job())
+        .or(admin_offline())
+        .or(admin_bypass())
+        .or(admin_quotas())
+        .or(admin_upstreams())
+        .or(admin_fetch())
+        .or(admin_snapshots())
+        .or(admin_config())
+        .or(api_spec())
+        .or(api_stats())
+        .or(api_entries())
+        .or(api_pin())
+        .or(api_warmup())
+        .or(api_jobs())
+        .or(api_tasks())
+        .or(ready())
+        .or(snapshot());
+    builtin_path.and(routes).map(Reply::into_response).boxed()
+}
+
+/// Requests of files resolved by the rules: CORS preflights, `GET` and
+/// `HEAD` served through the caches, and other methods passed through
+fn rule_routes() -> BoxedFilter<(Response,)> {
+    let log = warp::log::custom(|info| {
+        info!(
+            "🌐 {} {} Response: {}",
+            info.method(),
+            info.path(),
+            info.status(),
+        );
+    });
+    preflight()
+        .or(with_cors(fallback_head().or(fallback().with(log))))
+        .or(pass_through())
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// The id of a request, also recorded in its span. Events of the
+/// request, including those of the background download it spawns, are
+/// in this span.
+fn request_id() -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: warp::http::HeaderMap| {
+        let incoming = headers
+            .get(telemetry::REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let id = telemetry::request_id(incoming);
+        tracing::Span::current().record("request_id", &id.as_str());
+        id
+    })
+}
+
+/// Requests of paths that are not canonical, redirected to their canonical
+/// form, see `canonical_path`
+fn canonical() -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(raw_query())
+        .and_then(handlers::canonical_redirect_handler)
+}
+
+/// Requests no other route serves, rejected with what
+/// `handlers::handle_rejection` answers them with, unless another route
+/// rejected them for another reason
+fn unmatched() -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(handlers::unmatched_handler)
+}
+
+/// Reject requests over `max_path_length` or `max_header_bytes`, see
+/// `handlers::check_request_limits`
+fn request_limits() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::path::full()
+        .and(raw_query())
+        .and(warp::header::headers_cloned())
+        .and_then(handlers::check_request_limits)
+        .untuple_one()
+}
+
+/// Authenticate an admin request, extracting the label of its token
+fn admin() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(handlers::authorize_admin)
+}
+
+/// Authenticate an admin request of the cache in `path`, extracting the
+/// cache and the label of the token. Admin tokens of a tenant are
+/// accepted for the caches of the tenant.
+fn cache_admin<P>(
+    path: P,
+) -> impl Filter<Extract = (String, String), Error = warp::Rejection> + Clone
+where
+    P: Filter<Extract = (String,), Error = warp::Rejection> + Clone,
+{
+    path.and(warp::header::optional::<String>("authorization"))
+        .and_then(handlers::authorize_cache_admin)
+        .untuple_one()
+}
+
+/// `GET /admin/audit?since=<unix timestamp>&offset=<n>&limit=<n>`
+fn admin_audit() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "audit"))
+        .and(admin())
+        .and(warp::query::<handlers::AuditQuery>())
+        .and_then(handlers::audit_handler)
+}
+
+/// `DELETE /admin/cache/<policy>/entries?pattern=<glob>` or `?regex=<regex>`,
+/// alias of `DELETE /api/v1/caches/<policy>/entries`
+fn admin_purge() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(cache_admin(warp::path!(
+            "admin" / "cache" / String / "entries"
+        )))
+        .and(warp::query::<api::PurgeQuery>())
+        .and_then(handlers::purge_handler)
+}
+
+/// `DELETE /admin/cache/<policy>?confirm=<policy>`, the policy is repeated
+/// so that a whole cache is not purged by accident
+fn admin_purge_all() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::delete()
+        .and(cache_admin(warp::path!("admin" / "cache" / String)))
+        .and(warp::query::<handlers::PurgeAllQuery>())
+        .and_then(handlers::purge_all_handler)
+}
+
+/// `GET /admin/cache/<policy>/usage?group_by_prefix=<n>` or `?group_regex=<regex>`,
+/// and `&top=<k>`
+fn admin_usage() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(cache_admin(warp::path!(
+            "admin" / "cache" / String / "usage"
+        )))
+        .and(warp::query::<handlers::UsageQuery>())
+        .and_then(handlers::usage_handler)
+}
+
+/// `GET /admin/cache/<policy>/eviction-preview?target_size=<size>`, and
+/// `&offset=<n>&limit=<n>`
+fn admin_eviction_preview(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(cache_admin(warp::path!(
+            "admin" / "cache" / String / "eviction-preview"
+        )))
+        .and(warp::query::<handlers::EvictionPreviewQuery>())
+        .and_then(handlers::eviction_preview_handler)
+}
+
+/// `POST /admin/cache/<policy>/contains` with a JSON array of keys, answered
+/// with whether each one is cached
+fn admin_contains() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(cache_admin(warp::path!(
+            "admin" / "cache" / String / "contains"
+        )))
+        .and(json_body::<Vec<String>>())
+        .and_then(handlers::contains_handler)
+}
+
+/// `GET /admin/cache/<policy>/hot-keys`, the keys whose content is kept in
+/// memory, the hottest first
+fn admin_hot_keys() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(cache_admin(warp::path!(
+            "admin" / "cache" / String / "hot-keys"
+        )))
+        .and_then(handlers::hot_keys_handler)
+}
+
+/// `GET /admin/jobs/<id>`, alias of `GET /api/v1/jobs/<id>`
+fn admin_job() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "jobs" / u64))
+        .and(admin())
+        .and_then(handlers::job_handler)
+}
+
+/// `GET /admin/offline`, `PUT /admin/offline?enabled=<bool>&rule=<name>`
+/// and `DELETE /admin/offline?rule=<name>`, all rules if `rule` is absent
+fn admin_offline() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let status = warp::get()
+        .and(warp::path!("admin" / "offline"))
+        .and(admin())
+        .and_then(handlers::offline_status_handler);
+    let set = warp::put()
+        .and(warp::path!("admin" / "offline"))
+        .and(admin())
+        .and(warp::query::<handlers::OfflineQuery>())
+        .and_then(handlers::set_offline_handler);
+    let clear = warp::delete()
+        .and(warp::path!("admin" / "offline"))
+        .and(admin())
+        .and(warp::query::<handlers::OfflineQuery>())
+        .and_then(handlers::clear_offline_handler);
+    status.or(set).or(clear)
+}
+
+/// `GET /admin/bypass` and `POST /admin/bypass?rule=<name|all>&enabled=<bool>`,
+/// all rules if `rule` is absent
+fn admin_bypass() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let status = warp::get()
+        .and(warp::path!("admin" / "bypass"))
+        .and(admin())
+        .and_then(handlers::bypass_status_handler);
+    let set = warp::post()
+        .and(warp::path!("admin" / "bypass"))
+        .and(admin())
+        .and(warp::query::<handlers::BypassQuery>())
+        .and_then(handlers::set_bypass_handler);
+    status.or(set)
+}
+
+/// `GET /admin/quotas`, the usage of the quotas of clients
+fn admin_quotas() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "quotas"))
+        .and(admin())
+        .and_then(handlers::quotas_handler)
+}
+
+/// `GET /admin/upstreams`, the status of the upstreams fetched from
+fn admin_upstreams() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "upstreams"))
+        .and(admin())
+        .and_then(handlers::upstreams_handler)
+}
+
+/// `POST /admin/fetch` with a `FetchRequest` body, answered once the files
+/// are fetched
+fn admin_fetch() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("admin" / "fetch"))
+        .and(admin())
+        .and(json_body::<fetch::FetchRequest>())
+        .and_then(handlers::fetch_handler)
+}
+
+/// `GET /admin/snapshots`, `POST /admin/snapshots` taking a snapshot, and
+/// `GET /admin/snapshots/<id>` or `DELETE /admin/snapshots/<id>`
+fn admin_snapshots() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("admin" / "snapshots"))
+        .and(admin())
+        .and_then(handlers::list_snapshots_handler);
+    let create = warp::post()
+        .and(warp::path!("admin" / "snapshots"))
+        .and(admin())
+        .and(json_body::<snapshot::SnapshotRequest>())
+        .and_then(handlers::create_snapshot_handler);
+    let manifest = warp::get()
+        .and(warp::path!("admin" / "snapshots" / String))
+        .and(admin())
+        .and_then(handlers::snapshot_manifest_handler);
+    let delete = warp::delete()
+        .and(warp::path!("admin" / "snapshots" / String))
+        .and(admin())
+        .and_then(handlers::delete_snapshot_handler);
+    list.or(create).or(manifest).or(delete)
+}
+
+/// `GET /admin/config`, the settings in effect with secrets redacted
+fn admin_config() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("admin" / "config"))
+        .and(admin())
+        .and_then(handlers::config_handler)
+}
+
+/// `GET /api/v1/spec`, the self-description of the management API. It is
+/// public, unlike the other endpoints.
+fn api_spec() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "spec"))
+        .and_then(handlers::api_spec_handler)
+}
+
+/// `GET /ready`, public: whether redis and the storages of caches are
+/// usable
+fn ready() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("ready"))
+        .and_then(handlers::ready_handler)
+}
+
+/// `GET /snapshot/<id>/<path>`, the page of `<path>` recorded by a snapshot.
+/// It is public, like the files of the rules.
+fn snapshot() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("snapshot"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()))
+        .and(raw_query())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(handlers::snapshot_handler)
+}
+
+/// `GET /api/v1/caches/<policy>/stats`
+fn api_stats() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(cache_admin(warp::path!(
+            "api" / "v1" / "caches" / String / "stats"
+        )))
+        .and_then(handlers::stats_handler)
+}
+
+/// `GET /api/v1/caches/<policy>/entries?cursor=<cursor>&limit=<n>`, and
+/// `DELETE /api/v1/caches/<policy>/entries?pattern=<glob>` or `?regex=<regex>`
+fn api_entries() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::get()
+        .and(cache_admin(warp::path!(
+            "api" / "v1" / "caches" / String / "entries"
+        )))
+        .and(warp::query::<api::EntryQuery>())
+        .and_then(handlers::entries_handler);
+    let purge = warp::delete()
+        .and(cache_admin(warp::path!(
+            "api" / "v1" / "caches" / String / "entries"
+        )))
+        .and(warp::query::<api::PurgeQuery>())
+        .and_then(handlers::purge_handler);
+    list.or(purge)
+}
+
+/// `PUT /api/v1/caches/<policy>/pins` with a `PinRequest` body
+fn api_pin() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::put()
+        .and(cache_admin(warp::path!(
+            "api" / "v1" / "caches" / String / "pins"
+        )))
+        .and(json_body::<api::PinRequest>())
+        .and_then(handlers::pin_handler)
+}
+
+/// `POST /api/v1/warmup` with a `WarmupRequest` body
+fn api_warmup() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "warmup"))
+        .and(admin())
+        .and(json_body::<api::WarmupRequest>())
+        .and_then(handlers::warmup_handler)
+}
+
+/// `GET /api/v1/jobs` and `GET /api/v1/jobs/<id>`
+fn api_jobs() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("api" / "v1" / "jobs"))
+        .and(admin())
+        .and_then(handlers::jobs_handler);
+    let job = warp::get()
+        .and(warp::path!("api" / "v1" / "jobs" / u64))
+        .and(admin())
+        .and_then(handlers::job_handler);
+    list.or(job)
+}
+
+/// `GET /api/v1/tasks`
+fn api_tasks() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "tasks"))
+        .and(admin())
+        .and_then(handlers::tasks_handler)
+}
+
+/// A JSON body of at most 1 MB
+fn json_body<T: serde::de::DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 1024).and(warp::body::json())
+}
+
+/// The raw query string of a request, if any
+fn raw_query() -> impl Filter<Extract = (Option<String>,), Error = std::convert::Infallible> + Clone
+{
+    warp::query::raw()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+fn fallback_head() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::head()
+        .and(warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()))
+        .and(raw_query())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(handlers::head_fallback_handler)
+}
+
+/// CORS preflight of files, `OPTIONS /<path>`. Answered from the `cors`
+/// settings alone, the cache and upstreams are left untouched.
+fn preflight() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::options()
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>(
+            "access-control-request-method",
+        ))
+        .and_then(handlers::preflight_handler)
+}
+
+/// Add the CORS headers of the `cors` settings to replies of `filter`
+fn with_cors<F, T>(
+    filter: F,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    T: warp::Reply + Send,
+{
+    warp::header::optional::<String>("origin")
+        .and(filter)
+        .and_then(handlers::cors_handler)
+}
+
+/// fallback handler, matches all paths
+fn fallback() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()))
+        .and(raw_query())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("cache-control"))
+        .and(warp::header::optional::<String>("pragma"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::addr::remote())
+        .and_then(handlers::fallback_handler)
+}
+
+/// Requests of other methods than GET and HEAD, passed through to the
+/// upstream of rules allowing them
+fn pass_through() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()))
+        .and(raw_query())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::stream())
+        .and_then(handlers::pass_through_handler)
+}
@@ -0,0 +1,635 @@
+use crate::error::Error;
+use crate::error::Result;
+use crate::settings::{Rule, UnknownCondaChannel};
+use crate::task::{RuleId, Task};
+use crate::util;
+
+use regex::{Regex, RegexSet};
+
+/// Matches request paths against the rules of the settings. Rules are tried
+/// from the longest literal prefix of their path, see `Rule::prefix`, so that
+/// `pypi/simple/` is matched before `pypi/` wherever they are listed. Rules
+/// of the same prefix are tried in the order of the settings.
+///
+/// As suggested in the regex documentation of `RegexSet`, finding
+/// sub-captures isn't supported by a set, so each regex is also compiled
+/// independently and only the ones that matched are run again.
+pub struct RuleMatcher {
+    set: RegexSet,
+    list: Vec<Regex>,
+    rules: Vec<Rule>,
+    /// Ids of the rules, in the order they are tried
+    order: Vec<RuleId>,
+}
+
+impl RuleMatcher {
+    pub fn empty() -> Self {
+        Self {
+            set: RegexSet::empty(),
+            list: vec![],
+            rules: vec![],
+            order: vec![],
+        }
+    }
+
+    pub fn new(rules: &[Rule]) -> Result<Self> {
+        let patterns: Vec<&str> = rules.iter().map(|rule| rule.pattern()).collect();
+        let set = RegexSet::new(&patterns).map_err(|e| Error::ConfigInvalid(e.to_string()))?;
+        let list = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern).map_err(|e| Error::ConfigInvalid(e.to_string())))
+            .collect::<Result<Vec<Regex>>>()?;
+        let mut order: Vec<RuleId> = (0..rules.len()).collect();
+        // stable, rules of the same prefix keep their order
+        order.sort_by_key(|idx| std::cmp::Reverse(rules[*idx].prefix().len()));
+        Ok(Self {
+            set,
+            list,
+            rules: rules.to_vec(),
+            order,
+        })
+    }
+
+    /// Find the first rule matching `path` that allows `method`, and create
+    /// the task of the request. Ids of tasks are the indexes of the rules in
+    /// the settings, whatever the order they are tried in.
+    ///
+    /// Tasks of `path_pattern` rules are keyed by the rule name and the
+    /// captured groups, so cached entries are kept if the upstream changes.
+    ///
+    /// The keys of a `nuget` rule are lower-cased after `v3-flatcontainer`,
+    /// see `nuget_key`.
+    ///
+    /// Tasks of `conda_channels` rules are fetched from the upstream of the
+    /// channel in the path, and keyed by the path. `None` if the channel is
+    /// unknown and not fetched from the rule's upstream.
+    ///
+    /// The root index of a `pep503` rule is keyed by `PYPI_ROOT_INDEX_KEY`
+    /// under the key of `simple`, which is the directory of the project
+    /// indexes, e.g. `https/pypi.org/simple/index.html`.
+    ///
+    /// Directories of a `rewrite_dir_listing` rule, i.e. paths ending with
+    /// `/`, are keyed by `DIR_LISTING_KEY` under the key of the directory, so
+    /// that their listing does not take the place of the directory of the
+    /// files, e.g. `http/files.corp/pub/index.html`.
+    ///
+    /// The query string is forwarded to the upstream if the rule has a
+    /// `query` mode, and the part of it kept by the mode is hashed into the
+    /// key, e.g. `pypi/simple/flask.q-<hash>`.
+    pub fn resolve(&self, method: &str, path: &str, query: Option<&str>) -> Option<(Task, &Rule)> {
+        let matches = self.set.matches(path);
+        let rule_id: RuleId = self
+            .order
+            .iter()
+            .copied()
+            .find(|idx| matches.matched(*idx) && self.rules[*idx].allows(method))?;
+        let rule = &self.rules[rule_id];
+        let re = &self.list[rule_id];
+        let mut url = re.replace_all(path, rule.upstream_template()).into_owned();
+        let mut key = match rule.path_pattern {
+            Some(_) => {
+                let captures = re.captures(path)?;
+                let mut segments = vec![rule.name.clone().unwrap_or_default()];
+                segments.extend(
+                    captures
+                        .iter()
+                        .skip(1)
+                        .flatten()
+                        .map(|m| m.as_str().to_string()),
+                );
+                Some(segments.join("/"))
+            }
+            None => None,
+        };
+        if let Some(options) = rule.options.as_ref().filter(|o| o.conda_channels.is_some()) {
+            let channels = options.conda_channels.as_ref()?;
+            let rest = &path[re.find(path)?.end()..];
+            let (channel, file) = rest.split_once('/').unwrap_or((rest, ""));
+            match channels.get(channel) {
+                Some(upstream) => {
+                    url = format!("{}/{}", upstream.trim_end_matches('/'), file);
+                    // channels may share an upstream
+                    key = Some(path.to_string());
+                }
+                None if options.unknown_conda_channel == Some(UnknownCondaChannel::Upstream) => {}
+                None => return None,
+            }
+        }
+        let mut task = Task {
+            rule_id,
+            url,
+            key,
+            tenant: None,
+            simple_json: false,
+        };
+        if rule.options.as_ref().and_then(|o| o.nuget).unwrap_or(false) {
+            task.key = Some(nuget_key(&task.to_key()));
+        }
+        let pep503 = rule
+            .options
+            .as_ref()
+            .and_then(|o| o.pep503)
+            .unwrap_or(false);
+        let dir_listing = rule
+            .options
+            .as_ref()
+            .and_then(|o| o.rewrite_dir_listing)
+            .unwrap_or(false);
+        if pep503 && util::is_pypi_root_index(path) {
+            task.key = Some(format!("{}/{}", task.to_key(), util::PYPI_ROOT_INDEX_KEY));
+        } else if dir_listing && path.ends_with('/') {
+            task.key = Some(format!("{}/{}", task.to_key(), util::DIR_LISTING_KEY));
+        }
+        if let (Some(mode), Some(query)) = (&rule.query, query.filter(|q| !q.is_empty())) {
+            let key = task.to_key();
+            task.key = Some(match util::query_key(query, mode) {
+                Some(query_key) => format!("{}.{}", key, query_key),
+                None => key,
+            });
+            task.url = format!("{}?{}", task.url, query);
+        }
+        Some((task, rule))
+    }
+
+    /// The methods allowed by the rules matching `path`, whatever the
+    /// method of the request. Empty if no rule matches it.
+    pub fn methods_of(&self, path: &str) -> Vec<String> {
+        let mut methods: Vec<String> = Vec::new();
+        for idx in self.set.matches(path).iter() {
+            for method in self.rules[idx].methods() {
+                if !methods.contains(&method) {
+                    methods.push(method);
+                }
+            }
+        }
+        methods
+    }
+
+    /// The literal prefixes of the paths of the rules, see `Rule::prefix`,
+    /// or their patterns if they have none, in the order they are tried
+    pub fn prefixes(&self) -> Vec<String> {
+        let mut prefixes: Vec<String> = Vec::new();
+        for idx in &self.order {
+            let rule = &self.rules[*idx];
+            let prefix = rule.prefix();
+            let prefix = if prefix.is_empty() {
+                rule.pattern().to_string()
+            } else {
+                prefix
+            };
+            if !prefixes.contains(&prefix) {
+                prefixes.push(prefix);
+            }
+        }
+        prefixes
+    }
+
+    /// Find the task of a `GET` of the mirror path serving the upstream
+    /// `url`, e.g. for `mirror-cache fetch`. Paths are guessed from the
+    /// upstream of each rule, and of each of its `conda_channels`, prefixing
+    /// the url; a path is taken if it resolves to `url` itself. Rules whose
+    /// upstream template reorders the captured groups are not found.
+    pub fn task_for_url(&self, url: &str) -> Option<(Task, &Rule)> {
+        let (base, query) = match url.split_once('?') {
+            Some((base, query)) => (base, Some(query)),
+            None => (url, None),
+        };
+        self.order.iter().find_map(|idx| {
+            mirror_paths(&self.rules[*idx], base)
+                .iter()
+                .filter_map(|path| self.resolve("GET", path, query))
+                .find(|(task, _)| task.url == url)
+        })
+    }
+
+    /// Find the task of the index of the PyPI `project`, normalized, e.g. for
+    /// `mirror-cache fetch pypi:<project>`: of a rule mirroring pypi.org if
+    /// any, otherwise of the first `pep503` rule.
+    pub fn pypi_index_task(&self, project: &str) -> Option<(Task, &Rule)> {
+        let url = format!("https://pypi.org/simple/{}/", project);
+        if let Some(found) = self.task_for_url(&url) {
+            return Some(found);
+        }
+        self.order.iter().find_map(|idx| {
+            let rule = &self.rules[*idx];
+            if !rule
+                .options
+                .as_ref()
+                .and_then(|o| o.pep503)
+                .unwrap_or(false)
+            {
+                return None;
+            }
+            let mut simple = rule.prefix();
+            if !util::is_pypi_root_index(&simple) {
+                simple.push_str("simple/");
+            }
+            self.resolve("GET", &format!("{}{}/", simple, project), None)
+                .filter(|(task, _)| task.rule_id == *idx)
+        })
+    }
+
+    /// Find the task of `path` of the conda `channel` of a `conda_channels`
+    /// rule listing the channel, e.g. `linux-64/repodata.json`
+    pub fn conda_task(&self, channel: &str, path: &str) -> Option<(Task, &Rule)> {
+        self.order.iter().find_map(|idx| {
+            let rule = &self.rules[*idx];
+            let channels = rule.options.as_ref()?.conda_channels.as_ref()?;
+            if !channels.contains_key(channel) {
+                return None;
+            }
+            self.resolve(
+                "GET",
+                &format!("{}{}/{}", rule.prefix(), channel, path),
+                None,
+            )
+            .filter(|(task, _)| task.rule_id == *idx)
+        })
+    }
+}
+
+/// Paths of `rule` that may serve the upstream url `base`, without a query
+fn mirror_paths(rule: &Rule, base: &str) -> Vec<String> {
+    let prefix = rule.prefix();
+    let template = rule.upstream_template();
+    let literal = &template[..template.find('$').unwrap_or(template.len())];
+    let mut paths = Vec::new();
+    if let Some(rest) = base.strip_prefix(literal) {
+        paths.push(format!("{}{}", prefix, rest));
+    }
+    let channels = rule
+        .options
+        .as_ref()
+        .and_then(|o| o.conda_channels.as_ref());
+    for (channel, upstream) in channels.into_iter().flatten() {
+        let upstream = format!("{}/", upstream.trim_end_matches('/'));
+        if let Some(rest) = base.strip_prefix(&upstream) {
+            paths.push(format!("{}{}/{}", prefix, channel, rest));
+        }
+    }
+    paths
+}
+
+/// NuGet package ids and versions are case-insensitive, so the segments of
+/// `key` after `v3-flatcontainer` are lower-cased.
+fn nuget_key(key: &str) -> String {
+    match key.find("/v3-flatcontainer/") {
+        Some(idx) => {
+            let (head, tail) = key.split_at(idx);
+            format!("{}{}", head, tail.to_lowercase())
+        }
+        None => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::{Options, QueryMode};
+
+    fn rule(name: &str, path: &str, upstream: &str) -> Rule {
+        Rule {
+            name: Some(name.to_string()),
+            path: path.to_string(),
+            path_pattern: None,
+            methods: None,
+            max_body_size: None,
+            policy: "".into(),
+            upstream: upstream.to_string(),
+            upstream_template: None,
+            size_limit: None,
+            max_inflight: None,
+            rewrite: None,
+            rewrite_from: None,
+            options: None,
+            cache_mode: None,
+            query: None,
+            admission: None,
+            revalidate_after: None,
+            key_classes: None,
+        }
+    }
+
+    fn gh_rule(upstream_template: &str) -> Rule {
+        let mut gh = rule("gh", "", "");
+        gh.path_pattern = Some("^gh/(?P<org>[^/]+)/(?P<repo>[^/]+)/(?P<path>.+)$".to_string());
+        gh.upstream_template = Some(upstream_template.to_string());
+        gh
+    }
+
+    #[test]
+    fn resolve_path_pattern() {
+        let matcher = RuleMatcher::new(&[gh_rule(
+            "https://raw.githubusercontent.com/$org/$repo/$path",
+        )])
+        .unwrap();
+        let (task, rule) = matcher
+            .resolve("GET", "gh/rust-lang/rust/master/README.md", None)
+            .unwrap();
+        assert_eq!(rule.name.as_deref(), Some("gh"));
+        assert_eq!(
+            task.url,
+            "https://raw.githubusercontent.com/rust-lang/rust/master/README.md"
+        );
+        assert_eq!(task.to_key(), "gh/rust-lang/rust/master/README.md");
+        assert!(matcher.resolve("GET", "gh/rust-lang", None).is_none());
+    }
+
+    #[test]
+    fn key_stable_across_upstreams() {
+        let path = "gh/rust-lang/rust/master/README.md";
+        let origin = RuleMatcher::new(&[gh_rule(
+            "https://raw.githubusercontent.com/$org/$repo/$path",
+        )])
+        .unwrap();
+        let mirror =
+            RuleMatcher::new(&[gh_rule("https://mirror.example.com/gh/$org/$repo/$path")]).unwrap();
+        let (origin_task, _) = origin.resolve("GET", path, None).unwrap();
+        let (mirror_task, _) = mirror.resolve("GET", path, None).unwrap();
+        assert_ne!(origin_task.url, mirror_task.url);
+        assert_eq!(origin_task.to_key(), mirror_task.to_key());
+    }
+
+    #[test]
+    fn resolve_nuget() {
+        let path = "nuget/v3-flatcontainer/Newtonsoft.Json/13.0.1/Newtonsoft.Json.13.0.1.nupkg";
+        let mut nuget = rule("nuget", "^nuget/(.*)$", "https://api.nuget.org/$1");
+        let matcher = RuleMatcher::new(&[nuget.clone()]).unwrap();
+        let (task, _) = matcher.resolve("GET", path, None).unwrap();
+        assert_eq!(
+            task.to_key(),
+            "https/api.nuget.org/v3-flatcontainer/Newtonsoft.Json/13.0.1/Newtonsoft.Json.13.0.1.nupkg"
+        );
+        nuget.options = Some(Options {
+            nuget: Some(true),
+            ..Default::default()
+        });
+        let matcher = RuleMatcher::new(&[nuget]).unwrap();
+        let (task, _) = matcher.resolve("GET", path, None).unwrap();
+        // the upstream gets the path as requested
+        assert_eq!(
+            task.url,
+            "https://api.nuget.org/v3-flatcontainer/Newtonsoft.Json/13.0.1/Newtonsoft.Json.13.0.1.nupkg"
+        );
+        assert_eq!(
+            task.to_key(),
+            "https/api.nuget.org/v3-flatcontainer/newtonsoft.json/13.0.1/newtonsoft.json.13.0.1.nupkg"
+        );
+        let (task, _) = matcher.resolve("GET", "nuget/v3/index.json", None).unwrap();
+        assert_eq!(task.to_key(), "https/api.nuget.org/v3/index.json");
+    }
+
+    #[test]
+    fn resolve_by_method() {
+        let mut get_only = rule("get_only", "^pypi/(.*)$", "https://pypi.org/$1");
+        get_only.methods = Some(vec!["get".to_string()]);
+        let fallback = rule("fallback", "^pypi/(.*)$", "https://mirror.example.com/$1");
+        let matcher = RuleMatcher::new(&[get_only, fallback]).unwrap();
+        let (task, rule) = matcher.resolve("GET", "pypi/simple/", None).unwrap();
+        assert_eq!(rule.name.as_deref(), Some("get_only"));
+        assert_eq!(task.url, "https://pypi.org/simple/");
+        assert_eq!(task.key, None);
+        let (task, rule) = matcher.resolve("HEAD", "pypi/simple/", None).unwrap();
+        assert_eq!(rule.name.as_deref(), Some("fallback"));
+        assert_eq!(task.rule_id, 1);
+        assert_eq!(matcher.methods_of("pypi/simple/"), vec!["GET", "HEAD"]);
+        assert!(matcher.methods_of("npm/").is_empty());
+    }
+
+    #[test]
+    fn resolve_longest_prefix_first() {
+        let pypi = rule("pypi", "pypi/", "https://mirror.example.com/pypi/");
+        let simple = rule("simple", "pypi/simple/", "https://pypi.org/simple/");
+        let whl = rule("whl", "(.*\\.whl)$", "https://files.example.com/$1");
+        let matcher = RuleMatcher::new(&[pypi, whl, simple]).unwrap();
+        let (task, rule) = matcher.resolve("GET", "pypi/simple/flask/", None).unwrap();
+        assert_eq!(rule.name.as_deref(), Some("simple"));
+        // ids are the indexes in the settings
+        assert_eq!(task.rule_id, 2);
+        assert_eq!(task.url, "https://pypi.org/simple/flask/");
+        let (task, rule) = matcher.resolve("GET", "pypi/packages/a.whl", None).unwrap();
+        assert_eq!(rule.name.as_deref(), Some("pypi"));
+        assert_eq!(task.url, "https://mirror.example.com/pypi/packages/a.whl");
+        let (task, _) = matcher.resolve("GET", "wheels/a.whl", None).unwrap();
+        assert_eq!(task.rule_id, 1);
+        assert_eq!(task.url, "https://files.example.com/wheels/a.whl");
+        assert_eq!(
+            matcher.prefixes(),
+            vec!["pypi/simple/", "pypi/", "(.*\\.whl)$"]
+        );
+    }
+
+    #[test]
+    fn resolve_pypi_root_index() {
+        let mut pypi = rule("pypi", "^pypi/(.*)$", "https://pypi.org/$1");
+        pypi.options = Some(Options {
+            pep503: Some(true),
+            ..Default::default()
+        });
+        let matcher = RuleMatcher::new(&[pypi]).unwrap();
+        let (task, _) = matcher.resolve("GET", "pypi/simple/", None).unwrap();
+        assert_eq!(task.url, "https://pypi.org/simple/");
+        assert_eq!(task.to_key(), "https/pypi.org/simple/index.html");
+        let (task, _) = matcher.resolve("GET", "pypi/simple/flask/", None).unwrap();
+        assert_eq!(task.to_key(), "https/pypi.org/simple/flask");
+    }
+
+    #[test]
+    fn resolve_dir_listing() {
+        let mut files = rule("files", "^files/(.*)$", "http://files.corp/$1");
+        files.options = Some(Options {
+            rewrite_dir_listing: Some(true),
+            ..Default::default()
+        });
+        let matcher = RuleMatcher::new(&[files]).unwrap();
+        let (task, _) = matcher.resolve("GET", "files/pub/", None).unwrap();
+        assert_eq!(task.url, "http://files.corp/pub/");
+        assert_eq!(task.to_key(), "http/files.corp/pub/index.html");
+        let (task, _) = matcher.resolve("GET", "files/pub/a.tar.gz", None).unwrap();
+        assert_eq!(task.to_key(), "http/files.corp/pub/a.tar.gz");
+        let (task, _) = matcher.resolve("GET", "files/pub", None).unwrap();
+        assert_eq!(task.to_key(), "http/files.corp/pub");
+    }
+
+    #[test]
+    fn resolve_query_modes() {
+        let resolve = |mode: Option<QueryMode>, query: Option<&str>| {
+            let mut pypi = rule("pypi", "^pypi/(.*)$", "https://pypi.org/$1");
+            pypi.query = mode;
+            let matcher = RuleMatcher::new(&[pypi]).unwrap();
+            let (task, _) = matcher.resolve("GET", "pypi/simple/flask/", query).unwrap();
+            (task.url.clone(), task.to_key())
+        };
+        let query = Some("token=abc&v=1");
+        let base_key = "https/pypi.org/simple/flask";
+        // the query string is dropped by default
+        assert_eq!(
+            resolve(None, query),
+            (
+                "https://pypi.org/simple/flask/".to_string(),
+                base_key.to_string()
+            )
+        );
+        let (url, key) = resolve(Some(QueryMode::IgnoreQuery), query);
+        assert_eq!(url, "https://pypi.org/simple/flask/?token=abc&v=1");
+        assert_eq!(key, base_key);
+        let (url, key) = resolve(Some(QueryMode::IncludeQuery), query);
+        assert_eq!(url, "https://pypi.org/simple/flask/?token=abc&v=1");
+        assert!(key.starts_with("https/pypi.org/simple/flask.q-"));
+        assert!(!key.contains("abc"));
+        let whitelist = QueryMode::Whitelist(vec!["v".to_string()]);
+        let (_, key) = resolve(Some(whitelist.clone()), query);
+        assert_eq!(
+            resolve(Some(whitelist.clone()), Some("v=1&token=xyz")).1,
+            key
+        );
+        assert_ne!(resolve(Some(whitelist.clone()), Some("v=2")).1, key);
+        assert_eq!(resolve(Some(whitelist), Some("token=abc")).1, base_key);
+        assert_eq!(resolve(Some(QueryMode::IncludeQuery), Some("")).1, base_key);
+    }
+
+    #[test]
+    fn resolve_conda_channels() {
+        let mut conda = rule(
+            "conda",
+            "^anaconda/channels/",
+            "https://conda.anaconda.org/",
+        );
+        let channels = [
+            ("main", "https://repo.anaconda.com/pkgs/main"),
+            ("internal", "http://conda.corp/internal/"),
+        ];
+        conda.options = Some(Options {
+            conda_channels: Some(
+                channels
+                    .iter()
+                    .map(|(name, url)| (name.to_string(), url.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        });
+        let matcher = RuleMatcher::new(&[conda.clone()]).unwrap();
+        let resolve = |matcher: &RuleMatcher, channel: &str| {
+            let path = format!("anaconda/channels/{}/noarch/repodata.json", channel);
+            matcher
+                .resolve("GET", &path, None)
+                .map(|(task, _)| (task.url.clone(), task.to_key()))
+        };
+        let (url, main_key) = resolve(&matcher, "main").unwrap();
+        assert_eq!(
+            url,
+            "https://repo.anaconda.com/pkgs/main/noarch/repodata.json"
+        );
+        assert_eq!(main_key, "anaconda/channels/main/noarch/repodata.json");
+        let (url, internal_key) = resolve(&matcher, "internal").unwrap();
+        assert_eq!(url, "http://conda.corp/internal/noarch/repodata.json");
+        assert_ne!(main_key, internal_key);
+        assert_eq!(resolve(&matcher, "bioconda"), None);
+
+        conda.options.as_mut().unwrap().unknown_conda_channel = Some(UnknownCondaChannel::Upstream);
+        let matcher = RuleMatcher::new(&[conda]).unwrap();
+        let (url, _) = resolve(&matcher, "bioconda").unwrap();
+        assert_eq!(
+            url,
+            "https://conda.anaconda.org/bioconda/noarch/repodata.json"
+        );
+    }
+
+    #[test]
+    fn task_for_upstream_url() {
+        let mut conda = rule(
+            "conda",
+            "^anaconda/channels/",
+            "https://conda.anaconda.org/",
+        );
+        conda.options = Some(Options {
+            conda_channels: Some(
+                [(
+                    "main".to_string(),
+                    "https://repo.anaconda.com/pkgs/main".to_string(),
+                )]
+                .iter()
+                .cloned()
+                .collect(),
+            ),
+            ..Default::default()
+        });
+        let mut pypi = rule("pypi", "^pypi/web/", "https://pypi.org/");
+        pypi.query = Some(QueryMode::IncludeQuery);
+        let matcher = RuleMatcher::new(&[
+            conda,
+            pypi,
+            rule(
+                "files",
+                "^pypi/packages/",
+                "https://files.pythonhosted.org/packages/",
+            ),
+            gh_rule("https://github.com/$org/$repo/raw/$path"),
+        ])
+        .unwrap();
+        let name = |url: &str| {
+            matcher
+                .task_for_url(url)
+                .and_then(|(_, rule)| rule.name.clone())
+        };
+        assert_eq!(
+            name("https://files.pythonhosted.org/packages/ab/cd/flask-2.0.whl").as_deref(),
+            Some("files")
+        );
+        let (task, _) = matcher
+            .task_for_url("https://repo.anaconda.com/pkgs/main/noarch/repodata.json")
+            .unwrap();
+        assert_eq!(task.to_key(), "anaconda/channels/main/noarch/repodata.json");
+        let (task, _) = matcher
+            .task_for_url("https://pypi.org/simple/flask/?a=1")
+            .unwrap();
+        assert_eq!(task.url, "https://pypi.org/simple/flask/?a=1");
+        // reordered by the template
+        assert_eq!(name("https://github.com/org/repo/raw/README.md"), None);
+        assert_eq!(name("https://mirrors.corp/flask-2.0.whl"), None);
+    }
+
+    #[test]
+    fn tasks_of_shorthands() {
+        let mut pypi = rule("pypi", "^pypi/", "http://pypi.corp/");
+        pypi.options = Some(Options {
+            pep503: Some(true),
+            ..Default::default()
+        });
+        let mut conda = rule("conda", "^conda/", "https://conda.anaconda.org/");
+        conda.options = Some(Options {
+            conda_channels: Some(
+                [(
+                    "internal".to_string(),
+                    "http://conda.corp/internal".to_string(),
+                )]
+                .iter()
+                .cloned()
+                .collect(),
+            ),
+            ..Default::default()
+        });
+        let matcher = RuleMatcher::new(&[pypi.clone(), conda]).unwrap();
+        let (task, _) = matcher.pypi_index_task("flask").unwrap();
+        assert_eq!(task.url, "http://pypi.corp/simple/flask/");
+        let (task, _) = matcher
+            .conda_task("internal", "noarch/repodata.json")
+            .unwrap();
+        assert_eq!(task.url, "http://conda.corp/internal/noarch/repodata.json");
+        assert!(matcher
+            .conda_task("bioconda", "noarch/repodata.json")
+            .is_none());
+
+        // a rule mirroring pypi.org is preferred
+        let matcher =
+            RuleMatcher::new(&[pypi, rule("pypi.org", "^pypi-org/", "https://pypi.org/")]).unwrap();
+        let (task, _) = matcher.pypi_index_task("flask").unwrap();
+        assert_eq!(task.url, "https://pypi.org/simple/flask/");
+        assert_eq!(task.rule_id, 1);
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        assert!(RuleMatcher::new(&[rule("broken", "^pypi/(", "")]).is_err());
+    }
+}
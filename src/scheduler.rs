@@ -0,0 +1,178 @@
+use crate::metric;
+
+use metrics::gauge;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Priority of a background task
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Fills the cache for a live client miss
+    High = 0,
+    /// Scheduled work, e.g. prefetches, retries and warmups
+    Low = 1,
+}
+
+impl Priority {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Low => "low",
+        }
+    }
+}
+
+const PRIORITIES: [Priority; 2] = [Priority::High, Priority::Low];
+
+#[derive(Default)]
+struct State {
+    /// Number of running tasks of each priority
+    active: [usize; 2],
+    /// Waiting tasks of each priority
+    queues: [VecDeque<oneshot::Sender<SchedulerPermit>>; 2],
+}
+
+/// Admits background tasks in priority order. Queued high priority tasks
+/// always run before low priority ones, and low priority tasks only run while
+/// fewer than `idle_threshold` high priority tasks are running.
+pub struct Scheduler {
+    max_concurrent: usize,
+    idle_threshold: usize,
+    state: Mutex<State>,
+}
+
+/// Admission of a background task, the next queued task is admitted on drop.
+pub struct SchedulerPermit {
+    /// `None` if the permit is not handed out
+    scheduler: Option<Arc<Scheduler>>,
+    priority: Priority,
+}
+
+impl Drop for SchedulerPermit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release(self.priority);
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent: usize, idle_threshold: usize) -> Self {
+        Self {
+            max_concurrent,
+            idle_threshold,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Wait until a task of `priority` is admitted.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> SchedulerPermit {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.queues[priority as usize].push_back(tx);
+            self.dispatch(&mut state);
+        }
+        // the sender is kept in the queue until a permit is sent
+        rx.await.unwrap()
+    }
+
+    fn release(self: &Arc<Self>, priority: Priority) {
+        let mut state = self.state.lock().unwrap();
+        state.active[priority as usize] -= 1;
+        self.dispatch(&mut state);
+    }
+
+    /// Admit queued tasks while there is capacity.
+    fn dispatch(self: &Arc<Self>, state: &mut State) {
+        while state.active.iter().sum::<usize>() < self.max_concurrent {
+            let priority = if !state.queues[Priority::High as usize].is_empty() {
+                Priority::High
+            } else if !state.queues[Priority::Low as usize].is_empty()
+                && state.active[Priority::High as usize] < self.idle_threshold
+            {
+                Priority::Low
+            } else {
+                break;
+            };
+            let tx = state.queues[priority as usize].pop_front().unwrap();
+            state.active[priority as usize] += 1;
+            let permit = SchedulerPermit {
+                scheduler: Some(self.clone()),
+                priority,
+            };
+            if let Err(mut permit) = tx.send(permit) {
+                // the waiting task is gone
+                permit.scheduler = None;
+                state.active[priority as usize] -= 1;
+            }
+        }
+        for priority in PRIORITIES.iter() {
+            let idx = *priority as usize;
+            gauge!(metric::GAUGE_BG_TASKS_ACTIVE, state.active[idx] as f64, "priority" => priority.label());
+            gauge!(metric::GAUGE_BG_TASKS_QUEUED, state.queues[idx].len() as f64, "priority" => priority.label());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    /// Run a task of `priority` that records its label once admitted
+    fn spawn_recorder(
+        scheduler: &Arc<Scheduler>,
+        priority: Priority,
+        log: &Arc<Mutex<Vec<&'static str>>>,
+    ) -> tokio::task::JoinHandle<()> {
+        let scheduler = scheduler.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let _permit = scheduler.acquire(priority).await;
+            log.lock().unwrap().push(priority.label());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        })
+    }
+
+    #[tokio::test]
+    async fn queued_prefetches_yield_to_live_misses() {
+        let scheduler = Arc::new(Scheduler::new(1, 1));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let running = scheduler.acquire(Priority::High).await;
+        let mut handles = Vec::new();
+        // prefetches are queued first
+        for _ in 0..3 {
+            handles.push(spawn_recorder(&scheduler, Priority::Low, &log));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // then a burst of live misses arrives
+        for _ in 0..3 {
+            handles.push(spawn_recorder(&scheduler, Priority::High, &log));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(running);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["high", "high", "high", "low", "low", "low"]
+        );
+    }
+
+    #[tokio::test]
+    async fn low_priority_waits_for_idle() {
+        let scheduler = Arc::new(Scheduler::new(4, 1));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let running = scheduler.acquire(Priority::High).await;
+        let low = spawn_recorder(&scheduler, Priority::Low, &log);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // there is capacity, but a high priority task is running
+        assert!(log.lock().unwrap().is_empty());
+        drop(running);
+        low.await.unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["low"]);
+    }
+}
@@ -0,0 +1,63 @@
+//! Secrets of the settings, e.g. tokens and the redis url with its password.
+//! They are redacted wherever the settings are serialized or logged, so a
+//! field of this type cannot leak, e.g. through `GET /admin/config`.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// What secrets are serialized and logged as
+pub const REDACTED: &str = "***";
+
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    /// The secret itself, e.g. to authenticate a request. Never log it
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Secret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Credentials {
+        user: String,
+        token: Secret<String>,
+    }
+
+    #[test]
+    fn redact_secrets() {
+        let credentials: Credentials =
+            serde_json::from_str(r#"{"user": "ci", "token": "s3cr3t-token"}"#).unwrap();
+        assert_eq!(credentials.token.expose(), "s3cr3t-token");
+        let json = serde_json::to_string(&credentials).unwrap();
+        assert_eq!(json, r#"{"user":"ci","token":"***"}"#);
+        let debug = format!("{:?}", credentials);
+        assert!(!debug.contains("s3cr3t"), "{}", debug);
+    }
+}
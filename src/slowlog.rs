@@ -0,0 +1,137 @@
+//! Timing of operations that may stall requests: redis commands, reads and
+//! writes of storages, and upstream fetches. Durations are recorded in a
+//! histogram per category, and operations slower than the threshold of their
+//! category in the `slow_log` settings are logged as warnings.
+
+use crate::metric;
+use crate::settings::SlowLog;
+use metrics::histogram;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Thresholds in millisecs, `u64::MAX` if slow operations are not logged
+static REDIS_THRESHOLD: AtomicU64 = AtomicU64::new(u64::MAX);
+static STORAGE_THRESHOLD: AtomicU64 = AtomicU64::new(u64::MAX);
+static UPSTREAM_THRESHOLD: AtomicU64 = AtomicU64::new(u64::MAX);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Redis,
+    Storage,
+    Upstream,
+}
+
+impl Category {
+    fn label(&self) -> &'static str {
+        match self {
+            Category::Redis => "redis",
+            Category::Storage => "storage",
+            Category::Upstream => "upstream",
+        }
+    }
+
+    fn histogram(&self) -> &'static str {
+        match self {
+            Category::Redis => metric::HG_REDIS_LATENCY,
+            Category::Storage => metric::HG_STORAGE_LATENCY,
+            Category::Upstream => metric::HG_UPSTREAM_LATENCY,
+        }
+    }
+
+    fn threshold(&self) -> &'static AtomicU64 {
+        match self {
+            Category::Redis => &REDIS_THRESHOLD,
+            Category::Storage => &STORAGE_THRESHOLD,
+            Category::Upstream => &UPSTREAM_THRESHOLD,
+        }
+    }
+}
+
+/// Apply the thresholds of the settings. Categories without a threshold are
+/// not logged.
+pub fn configure(settings: Option<&SlowLog>) {
+    let thresholds = [
+        (Category::Redis, settings.and_then(|s| s.redis)),
+        (Category::Storage, settings.and_then(|s| s.storage)),
+        (Category::Upstream, settings.and_then(|s| s.upstream)),
+    ];
+    for (category, threshold) in &thresholds {
+        category
+            .threshold()
+            .store(threshold.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+}
+
+fn is_slow(elapsed: Duration, threshold_millis: u64) -> bool {
+    threshold_millis != u64::MAX && elapsed.as_millis() >= threshold_millis as u128
+}
+
+/// Measures an operation from its start until `finish`
+pub struct Timer {
+    category: Category,
+    op: &'static str,
+    started: Instant,
+}
+
+impl Timer {
+    pub fn start(category: Category, op: &'static str) -> Self {
+        Self {
+            category,
+            op,
+            started: Instant::now(),
+        }
+    }
+
+    /// Record the duration of the operation on `key`, and log it if it is
+    /// slow. `size` is the number of bytes involved, if known.
+    pub fn finish(self, key: &str, size: Option<u64>) -> Duration {
+        let elapsed = self.started.elapsed();
+        histogram!(self.category.histogram(), elapsed.as_secs_f64(), "op" => self.op);
+        let threshold = self.category.threshold().load(Ordering::Relaxed);
+        if is_slow(elapsed, threshold) {
+            warn!(
+                category = self.category.label(),
+                op = self.op,
+                %key,
+                duration_ms = elapsed.as_millis() as u64,
+                ?size,
+                "slow {} operation {} on {}: {:?}",
+                self.category.label(),
+                self.op,
+                key,
+                elapsed
+            );
+        }
+        elapsed
+    }
+}
+
+/// Run the operation `op` on `key`, timed by a `Timer`
+pub fn time<T>(category: Category, op: &'static str, key: &str, f: impl FnOnce() -> T) -> T {
+    let timer = Timer::start(category, op);
+    let result = f();
+    timer.finish(key, None);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slow_operations() {
+        assert!(!is_slow(Duration::from_secs(3600), u64::MAX));
+        assert!(!is_slow(Duration::from_millis(99), 100));
+        assert!(is_slow(Duration::from_millis(100), 100));
+        assert!(is_slow(Duration::from_millis(1), 0));
+    }
+
+    #[test]
+    fn timed_operations() {
+        let timer = Timer::start(Category::Storage, "read");
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(timer.finish("a/b", Some(10)) >= Duration::from_millis(5));
+        assert_eq!(time(Category::Redis, "get", "a/b", || 42), 42);
+    }
+}
@@ -0,0 +1,336 @@
+//! Snapshots of index pages, for reproducible builds. A snapshot records the
+//! TTL-cached pages of some rules at some point in time, e.g. the PyPI simple
+//! index a release is built against, and serves them at
+//! `/snapshot/<id>/<path>` for as long as it is kept, whatever the upstream
+//! and the caches serve since. Files linked by the pages are served by their
+//! own rules as usual: they are immutable.
+//!
+//! The pages are copied into the `snapshot_storage`, under
+//! `<id>/<sha256>`, so that they outlive the expiration of the cached ones.
+//! The manifest of a snapshot is kept in redis: its summary under
+//! `snapshot/<id>`, and the content hash of each key in the hash
+//! `snapshot/<id>/entries`.
+
+use crate::cache::CacheData;
+use crate::error::{Error, Result};
+use crate::keys::SNAPSHOT_ID;
+use crate::models;
+use crate::storage::StorageBackend;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Longest id of a snapshot
+const MAX_ID_LEN: usize = 64;
+
+/// Keys of redis listed by `SCAN` iteration
+const SCAN_COUNT: usize = 1000;
+
+/// A snapshot, as listed by `GET /admin/snapshots`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub id: String,
+    /// Secs since the epoch
+    pub created_at: i64,
+    /// Admin who took the snapshot
+    pub principal: String,
+    /// Names of the rules whose pages are recorded
+    pub rules: Vec<String>,
+    /// Number of pages, 0 while the snapshot is taken
+    pub entries: usize,
+    /// Total size of the pages
+    pub bytes: u64,
+}
+
+/// A page of a snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    /// Hex encoded SHA-256 of the content
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// A snapshot and its pages, by key, as served by `GET /admin/snapshots/<id>`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    #[serde(flatten)]
+    pub info: SnapshotInfo,
+    pub entries: BTreeMap<String, SnapshotEntry>,
+}
+
+/// Body of `POST /admin/snapshots`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    /// Id of the snapshot, e.g. the name of a release. The time it is taken,
+    /// e.g. `20240301T120000Z`, if not set
+    pub id: Option<String>,
+    /// Names of the rules whose pages are recorded, of TTL policies
+    pub rules: Vec<String>,
+}
+
+/// Check the id of a snapshot: up to `MAX_ID_LEN` ASCII letters, digits and
+/// `-._`, not starting with a dot
+pub fn validate_id(id: &str) -> Result<()> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._".contains(c));
+    if !valid {
+        return Err(Error::BadRequest(format!(
+            "invalid snapshot id {:?}: up to {} letters, digits, `-`, `.` or `_` are expected",
+            id, MAX_ID_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// The id of a snapshot taken at `secs` since the epoch, in UTC
+pub fn default_id(secs: i64) -> String {
+    chrono::NaiveDateTime::from_timestamp(secs, 0)
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+fn info_key(id: &str) -> String {
+    format!("{}/{}", SNAPSHOT_ID, id)
+}
+
+fn entries_key(id: &str) -> String {
+    format!("{}/{}/entries", SNAPSHOT_ID, id)
+}
+
+fn page_name(id: &str, sha256: &str) -> String {
+    format!("{}/{}", id, sha256)
+}
+
+/// The manifests of snapshots in redis, and their pages in a storage
+pub struct SnapshotStore {
+    redis_client: redis::Client,
+    /// The `snapshot` directory of the `snapshot_storage`
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl SnapshotStore {
+    pub fn new(redis_client: redis::Client, storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            redis_client,
+            storage,
+        }
+    }
+
+    /// Record a snapshot being taken, without pages. Fails if its id is
+    /// taken.
+    pub fn reserve(&self, info: &SnapshotInfo) -> Result<()> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let value = serde_json::to_string(info).unwrap();
+        if !models::set_if_absent(&mut con, &info_key(&info.id), &value)? {
+            return Err(Error::BadRequest(format!("snapshot {} exists", info.id)));
+        }
+        Ok(())
+    }
+
+    /// Copy a page into the snapshot `id`. Pages with the same content share
+    /// their file.
+    pub async fn put_page(&self, id: &str, data: CacheData) -> Result<SnapshotEntry> {
+        let mut stream = data.into_byte_stream();
+        let mut content = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+        let sha256 = format!("{:x}", Sha256::digest(&content));
+        let size = content.len() as u64;
+        let data = CacheData::BytesData(Bytes::from(content));
+        self.storage.persist(&page_name(id, &sha256), data).await?;
+        Ok(SnapshotEntry { sha256, size })
+    }
+
+    /// Record the pages of a reserved snapshot, and its final summary
+    pub fn save(
+        &self,
+        info: &SnapshotInfo,
+        entries: &BTreeMap<String, SnapshotEntry>,
+    ) -> Result<()> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let fields: Vec<(String, String)> = entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), serde_json::to_string(entry).unwrap()))
+            .collect();
+        for chunk in fields.chunks(SCAN_COUNT) {
+            models::set_hash_fields(&mut con, &entries_key(&info.id), chunk)?;
+        }
+        let value = serde_json::to_string(info).unwrap();
+        models::set(&mut con, &info_key(&info.id), &value)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<SnapshotInfo>> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let value = models::get(&mut con, &info_key(id))?;
+        value.map(|value| parse(&value)).transpose()
+    }
+
+    pub fn manifest(&self, id: &str) -> Result<Option<SnapshotManifest>> {
+        let info = match self.get(id)? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        Ok(Some(SnapshotManifest {
+            info,
+            entries: self.entries(id)?,
+        }))
+    }
+
+    fn entries(&self, id: &str) -> Result<BTreeMap<String, SnapshotEntry>> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let hashes = models::get_hashes(&mut con, &[entries_key(id)])?;
+        hashes
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| Ok((key, parse(&value)?)))
+            .collect()
+    }
+
+    /// All snapshots, from the oldest
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let prefix = info_key("");
+        let mut ids = BTreeSet::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = models::scan_prefixed_keys(&mut con, &prefix, cursor, SCAN_COUNT)?;
+            for key in keys {
+                let id = &key[prefix.len()..];
+                // not the hashes of entries
+                if !id.contains('/') {
+                    ids.insert(id.to_string());
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        let mut snapshots = Vec::new();
+        for id in ids {
+            // deleted in the meantime
+            if let Some(value) = models::get(&mut con, &info_key(&id))? {
+                snapshots.push(parse::<SnapshotInfo>(&value)?);
+            }
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.created_at);
+        Ok(snapshots)
+    }
+
+    /// The content of the page of `key` in the snapshot `id`, `None` if the
+    /// snapshot does not record it
+    pub async fn read_page(&self, id: &str, key: &str) -> Result<Option<CacheData>> {
+        let value = {
+            let mut con = models::get_sync_con(&self.redis_client)?;
+            models::get_hash_field(&mut con, &entries_key(id), key)?
+        };
+        let entry: SnapshotEntry = match value {
+            Some(value) => parse(&value)?,
+            None => return Ok(None),
+        };
+        let data = self.storage.read(&page_name(id, &entry.sha256)).await?;
+        Ok(Some(data))
+    }
+
+    /// Remove the pages of `entries` from the snapshot `id`, e.g. of a
+    /// snapshot that failed to be taken
+    pub async fn remove_pages(&self, id: &str, entries: &BTreeMap<String, SnapshotEntry>) {
+        let hashes: BTreeSet<&str> = entries.values().map(|e| e.sha256.as_str()).collect();
+        for sha256 in hashes {
+            if let Err(e) = self.storage.remove(&page_name(id, sha256)).await {
+                warn!("failed to remove page {} of snapshot {}: {}", sha256, id, e);
+            }
+        }
+    }
+
+    /// Remove a snapshot and its pages. Returns it, `None` if it does not
+    /// exist.
+    pub async fn delete(&self, id: &str) -> Result<Option<SnapshotInfo>> {
+        let info = match self.get(id)? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let entries = self.entries(id)?;
+        // no longer served once its manifest is gone
+        {
+            let mut con = models::get_sync_con(&self.redis_client)?;
+            models::del(&mut con, &info_key(id))?;
+            models::del(&mut con, &entries_key(id))?;
+        }
+        self.remove_pages(id, &entries).await;
+        Ok(Some(info))
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(value: &str) -> Result<T> {
+    serde_json::from_str(value)
+        .map_err(|e| Error::OtherError(format!("invalid snapshot manifest: {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemBackend;
+
+    #[test]
+    fn snapshot_ids() {
+        assert!(validate_id("release-2024.03_1").is_ok());
+        assert_eq!(default_id(1709294400), "20240301T120000Z");
+        assert!(validate_id(&default_id(1709294400)).is_ok());
+        let long = "a".repeat(MAX_ID_LEN + 1);
+        for invalid in &["", ".hidden", "a/b", "a b", "..", long.as_str()] {
+            assert!(validate_id(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_store() {
+        let client = redis::Client::open("redis://localhost:3001/").unwrap();
+        let store = SnapshotStore::new(client, Arc::new(MemBackend::new()));
+        let id = "snapshot_store_test";
+        store.delete(id).await.unwrap();
+        let mut info = SnapshotInfo {
+            id: id.to_string(),
+            created_at: 1709294400,
+            principal: "admin".to_string(),
+            rules: vec!["pypi_index".to_string()],
+            entries: 0,
+            bytes: 0,
+        };
+        store.reserve(&info).unwrap();
+        assert!(store.reserve(&info).is_err());
+        let mut entries = BTreeMap::new();
+        for (key, content) in &[("simple/a", "a"), ("simple/b", "b"), ("simple/c", "a")] {
+            let data = CacheData::TextData(content.to_string());
+            entries.insert(key.to_string(), store.put_page(id, data).await.unwrap());
+        }
+        info.entries = entries.len();
+        info.bytes = entries.values().map(|e| e.size).sum();
+        store.save(&info, &entries).unwrap();
+
+        assert!(store.list().unwrap().contains(&info));
+        let manifest = store.manifest(id).unwrap().unwrap();
+        assert_eq!(manifest.entries, entries);
+        assert_eq!(
+            manifest.entries["simple/c"].sha256,
+            manifest.entries["simple/a"].sha256
+        );
+        let page = store.read_page(id, "simple/c").await.unwrap().unwrap();
+        assert_eq!(page.into_vec_u8().await, b"a");
+        assert!(store.read_page(id, "simple/d").await.unwrap().is_none());
+
+        assert_eq!(store.delete(id).await.unwrap(), Some(info));
+        assert!(store.get(id).unwrap().is_none());
+        assert!(store.read_page(id, "simple/a").await.unwrap().is_none());
+        assert!(store.delete(id).await.unwrap().is_none());
+    }
+}
@@ -1,193 +1,1806 @@
 use crate::cache::{CacheData, CacheSizeType};
 use crate::error::{Error, Result};
+use crate::metric;
+use crate::settings::{cache_root_dir, Fsync};
+use crate::slowlog::{Category, Timer};
+use crate::util;
 
+use async_trait::async_trait;
 use bytes::Bytes;
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use metrics::histogram;
 use rusoto_core::{Region, RusotoError};
 use rusoto_s3::{S3Client, S3};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::Vec;
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
 use tokio::{fs::OpenOptions, io::BufReader, sync::RwLock};
 use tokio_util::codec;
 
-/// Storage is an abstraction over a persistent storage.
-/// - FileSystem: local filesystem
+/// The tier of `TieredFsBackend` a file is stored in, recorded in the LRU
+/// entry of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Fast,
+    Slow,
+}
+
+impl Tier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tier::Fast => "fast",
+            Tier::Slow => "slow",
+        }
+    }
+}
+
+/// Progress of a file being written by a background download
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownloadProgress {
+    /// Number of bytes received so far
+    Downloading(u64),
+    Done,
+    Failed,
+}
+
+/// Number of bytes read at a time when following a growing file
+const FOLLOW_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Permissions and ownership of files and directories created by the
+/// filesystem storage. `None` keeps the process default.
+#[derive(Clone, Debug, Default)]
+pub struct FsPermissions {
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FsPermissions {
+    #[cfg(unix)]
+    fn apply(&self, path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _path: &Path, _mode: Option<u32>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Size and modification time of a stored object
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StorageMeta {
+    pub size: CacheSizeType,
+    /// `None` if the backend does not record it
+    pub modified: Option<SystemTime>,
+}
+
+/// Whether an object of `stored` bytes is not the one recorded with
+/// `recorded` bytes in the metadata of its entry, e.g. an empty or short file
+/// left by a power cut with `fsync: never`. An unknown recorded size, `None`,
+/// matches any size.
+pub fn size_mismatch(recorded: Option<CacheSizeType>, stored: CacheSizeType) -> bool {
+    recorded.map_or(false, |recorded| recorded != stored)
+}
+
+/// A persistent storage of the files of a cache. The builtin backends are
+/// `FsBackend`, `TieredFsBackend`, `MemBackend` and `S3Backend`, possibly
+/// replicated by `ReplicatedBackend`. Others can be plugged in by implementing
+/// this trait.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn read(&self, name: &str) -> Result<CacheData>;
+
+    /// Read an object from the `tier` it is recorded in, see
+    /// `TieredFsBackend::read_tier`. Returns the tier it is read from.
+    async fn read_tier(&self, name: &str, tier: Tier) -> Result<(CacheData, Tier)> {
+        self.read(name).await.map(|data| (data, tier))
+    }
+
+    /// Like `persist`, but the stored object is left untouched if its SHA-256
+    /// is `known_sha256` and the new one has the same, see
+    /// `PersistReport::unchanged`. Backends unable to tell may always replace
+    /// the object.
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<PersistReport>;
+
+    /// Write an object, and report the number of bytes written and their
+    /// SHA-256. A stream that ends before or runs past its declared size is
+    /// not stored, and an error is returned.
+    async fn persist(&self, name: &str, data: CacheData) -> Result<PersistReport> {
+        self.persist_changed(name, data, None).await
+    }
+
+    /// Write an object like `persist_changed`, but where reads do not find it
+    /// until it is published, see `publish`, so that it can be written
+    /// without holding the lock of its entry. Backends unable to stage
+    /// objects write them when they are published.
+    async fn stage(
+        &self,
+        _name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<Staged> {
+        Ok(Staged::Pending(data, known_sha256.map(String::from)))
+    }
+
+    /// Replace the stored object with the one written by `stage`
+    async fn publish(&self, name: &str, staged: Staged) -> Result<PersistReport> {
+        match staged {
+            Staged::Written(report) => Ok(report),
+            Staged::Pending(data, known_sha256) => {
+                self.persist_changed(name, data, known_sha256.as_deref())
+                    .await
+            }
+        }
+    }
+
+    async fn remove(&self, name: &str) -> Result<()>;
+
+    /// The size and modification time of an object, without reading it
+    async fn metadata(&self, name: &str) -> Result<StorageMeta>;
+
+    /// The path relative to the storage root and the size of a file stored in
+    /// the local filesystem. `None` if the file does not exist or the storage
+    /// is not a single local filesystem root.
+    fn local_path(&self, _name: &str) -> Option<(String, CacheSizeType)> {
+        None
+    }
+
+    /// The temporary path a file is written to, if it is stored in a single
+    /// file in the local filesystem
+    fn write_path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Whether the files are stored in tiers, see `read_tier`
+    fn is_tiered(&self) -> bool {
+        false
+    }
+
+    /// Whether the fast tier of a tiered storage is over its budget
+    fn needs_demotion(&self) -> bool {
+        false
+    }
+
+    /// Move files to the slow tier of a tiered storage, see
+    /// `TieredFsBackend::demote`. Returns the moved files.
+    fn demote(&self, _names: &[String]) -> Vec<String> {
+        vec![]
+    }
+
+    /// Number of objects missing from some backends of a replicated storage,
+    /// see `ReplicatedBackend`. `None` if the storage is not replicated.
+    fn degraded_entries(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A storage of the settings, shared by the caches using it. Each cache gets
+/// a backend of its own, see `for_cache`.
 #[derive(Clone)]
 pub enum Storage {
-    FileSystem {
-        root_dir: String,
-    },
-    Memory {
-        map: Arc<RwLock<HashMap<String, Vec<u8>>>>,
-    },
-    S3 {
-        endpoint: String,
-        bucket: String,
-    },
+    FileSystem(FsBackend),
+    TieredFs(TieredFsBackend),
+    Memory(MemBackend),
+    S3(S3Backend),
+}
+
+impl Storage {
+    /// The backend of the cache `cache_id`. Files of a filesystem storage are
+    /// kept in `root_dir` if set, otherwise in `<storage root>/<cache_id>`, so
+    /// caches sharing a storage never touch each other's files.
+    pub fn for_cache(&self, cache_id: &str, root_dir: Option<&str>) -> Arc<dyn StorageBackend> {
+        let backend: Arc<dyn StorageBackend> = match self {
+            Storage::FileSystem(fs) => Arc::new(FsBackend {
+                root_dir: root_dir
+                    .map_or_else(|| cache_root_dir(&fs.root_dir, cache_id), String::from),
+                ..fs.clone()
+            }),
+            Storage::TieredFs(tiered) => Arc::new(TieredFsBackend {
+                fast_root: cache_root_dir(&tiered.fast_root, cache_id),
+                slow_root: cache_root_dir(&tiered.slow_root, cache_id),
+                // the fast tier budget is shared by all caches of the storage
+                ..tiered.clone()
+            }),
+            Storage::Memory(mem) => Arc::new(mem.clone()),
+            Storage::S3(s3) => Arc::new(s3.clone()),
+        };
+        Arc::new(TimedBackend(backend))
+    }
+}
+
+/// Logs slow reads and writes of a backend, see `slowlog`
+struct TimedBackend(Arc<dyn StorageBackend>);
+
+#[async_trait]
+impl StorageBackend for TimedBackend {
+    async fn read(&self, name: &str) -> Result<CacheData> {
+        let timer = Timer::start(Category::Storage, "read");
+        let result = self.0.read(name).await;
+        let size = result.as_ref().ok().and_then(|data| match data {
+            CacheData::ByteStream(_, size) => *size,
+            data => Some(data.len()),
+        });
+        timer.finish(name, size);
+        result
+    }
+
+    async fn read_tier(&self, name: &str, tier: Tier) -> Result<(CacheData, Tier)> {
+        let timer = Timer::start(Category::Storage, "read");
+        let result = self.0.read_tier(name, tier).await;
+        let size = result.as_ref().ok().and_then(|(data, _)| match data {
+            CacheData::ByteStream(_, size) => *size,
+            data => Some(data.len()),
+        });
+        timer.finish(name, size);
+        result
+    }
+
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<PersistReport> {
+        let timer = Timer::start(Category::Storage, "persist");
+        let result = self.0.persist_changed(name, data, known_sha256).await;
+        timer.finish(
+            name,
+            result.as_ref().ok().map(|report| report.bytes_written),
+        );
+        result
+    }
+
+    async fn stage(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<Staged> {
+        let timer = Timer::start(Category::Storage, "persist");
+        let result = self.0.stage(name, data, known_sha256).await;
+        let size = match &result {
+            Ok(Staged::Written(report)) => Some(report.bytes_written),
+            _ => None,
+        };
+        timer.finish(name, size);
+        result
+    }
+
+    async fn publish(&self, name: &str, staged: Staged) -> Result<PersistReport> {
+        self.0.publish(name, staged).await
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        self.0.remove(name).await
+    }
+
+    async fn metadata(&self, name: &str) -> Result<StorageMeta> {
+        self.0.metadata(name).await
+    }
+
+    fn local_path(&self, name: &str) -> Option<(String, CacheSizeType)> {
+        self.0.local_path(name)
+    }
+
+    fn write_path(&self, name: &str) -> Option<PathBuf> {
+        self.0.write_path(name)
+    }
+
+    fn is_tiered(&self) -> bool {
+        self.0.is_tiered()
+    }
+
+    fn needs_demotion(&self) -> bool {
+        self.0.needs_demotion()
+    }
+
+    fn demote(&self, names: &[String]) -> Vec<String> {
+        self.0.demote(names)
+    }
+
+    fn degraded_entries(&self) -> Option<u64> {
+        self.0.degraded_entries()
+    }
+}
+
+/// Files in a local filesystem root
+#[derive(Clone)]
+pub struct FsBackend {
+    pub root_dir: String,
+    /// Spread files into two levels of hash-prefixed directories
+    /// (`ab/cd/<name>`) to keep directories small.
+    pub sharded: bool,
+    /// Split objects into chunk files of at most this many bytes, see
+    /// `fs_persist_chunked`. Objects stored either way are readable.
+    pub chunk_size: Option<u64>,
+    pub fsync: Fsync,
+    pub permissions: FsPermissions,
+}
+
+#[async_trait]
+impl StorageBackend for FsBackend {
+    async fn read(&self, name: &str) -> Result<CacheData> {
+        let path = fs_path(&self.root_dir, name, self.sharded)?;
+        match read_manifest(&path)? {
+            Some(manifest) => fs_read_chunked(&path, &manifest),
+            None => fs_read(&path).await,
+        }
+    }
+
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<PersistReport> {
+        let path = fs_path(&self.root_dir, name, self.sharded)?;
+        let permissions = &self.permissions;
+        let fsync = self.fsync;
+        match self.chunk_size {
+            Some(chunk_size) => {
+                fs_persist_chunked(&path, data, permissions, fsync, known_sha256, chunk_size).await
+            }
+            None => {
+                let report = fs_persist(&path, data, permissions, fsync, known_sha256).await?;
+                // the object may have been stored in chunks before
+                if !report.unchanged {
+                    remove_chunks(&path)?;
+                }
+                Ok(report)
+            }
+        }
+    }
+
+    /// Objects stored in a single file are written to its temporary file,
+    /// and moved in place when published. Chunked objects are written when
+    /// published.
+    async fn stage(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<Staged> {
+        if self.chunk_size.is_some() {
+            return Ok(Staged::Pending(data, known_sha256.map(String::from)));
+        }
+        let path = fs_path(&self.root_dir, name, self.sharded)?;
+        let report = fs_stage(&path, data, &self.permissions, self.fsync, known_sha256).await?;
+        Ok(Staged::Written(report))
+    }
+
+    async fn publish(&self, name: &str, staged: Staged) -> Result<PersistReport> {
+        let report = match staged {
+            Staged::Written(report) => report,
+            Staged::Pending(data, known_sha256) => {
+                return self
+                    .persist_changed(name, data, known_sha256.as_deref())
+                    .await
+            }
+        };
+        if !report.unchanged {
+            let path = fs_path(&self.root_dir, name, self.sharded)?;
+            fs_publish(&path, self.fsync).await?;
+            remove_chunks(&path)?;
+        }
+        Ok(report)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let path = fs_path(&self.root_dir, name, self.sharded)?;
+        if remove_chunks(&path)? {
+            return Ok(());
+        }
+        fs::remove_file(path).map_err(|e| e.into())
+    }
+
+    async fn metadata(&self, name: &str) -> Result<StorageMeta> {
+        let path = fs_path(&self.root_dir, name, self.sharded)?;
+        // an object stored in chunks is complete once its manifest is written
+        let (size, path) = match read_manifest(&path)? {
+            Some(manifest) => (Some(manifest.size), manifest_path(&path)),
+            None => (None, path),
+        };
+        fs_metadata(&path, size)
+    }
+
+    fn local_path(&self, name: &str) -> Option<(String, CacheSizeType)> {
+        let path = fs_path(&self.root_dir, name, self.sharded).ok()?;
+        let size = fs::metadata(&path).ok()?.len();
+        let relative = path.strip_prefix(&self.root_dir).ok()?;
+        Some((relative.to_string_lossy().to_string(), size))
+    }
+
+    fn write_path(&self, name: &str) -> Option<PathBuf> {
+        if self.chunk_size.is_some() {
+            return None;
+        }
+        Some(fs_temp_path(
+            &fs_path(&self.root_dir, name, self.sharded).ok()?,
+        ))
+    }
+}
+
+/// Local filesystem split into a small fast tier and a large slow tier
+#[derive(Clone)]
+pub struct TieredFsBackend {
+    fast_root: String,
+    slow_root: String,
+    /// Files are demoted to the slow tier once the fast tier exceeds this size
+    fast_budget: CacheSizeType,
+    /// Move a file back to the fast tier when it is read from the slow tier
+    promote: bool,
+    /// Of files written to the fast tier. Files moved to the slow tier are
+    /// not synced
+    fsync: Fsync,
+    /// Bytes currently stored in the fast tier
+    fast_usage: Arc<AtomicU64>,
+}
+
+impl TieredFsBackend {
+    pub fn new(
+        fast_root: &str,
+        slow_root: &str,
+        fast_budget: CacheSizeType,
+        promote: bool,
+        fsync: Fsync,
+    ) -> Self {
+        TieredFsBackend {
+            fast_root: fast_root.to_string(),
+            slow_root: slow_root.to_string(),
+            fast_budget,
+            promote,
+            fsync,
+            fast_usage: Arc::new(AtomicU64::new(dir_size(Path::new(fast_root)))),
+        }
+    }
+
+    /// Read a file from the slow tier, promoting it if configured to
+    async fn read_slow(&self, name: &str) -> Result<(CacheData, Tier)> {
+        let fast_path = fs_path(&self.fast_root, name, false)?;
+        let slow_path = fs_path(&self.slow_root, name, false)?;
+        if self.promote {
+            if let Ok(size) = move_file(&slow_path, &fast_path) {
+                self.fast_usage.fetch_add(size, Ordering::SeqCst);
+                trace!("promoted {} to the fast tier", name);
+                return Ok((fs_read(&fast_path).await?, Tier::Fast));
+            }
+        }
+        match fs_read(&slow_path).await {
+            Ok(data) => Ok((data, Tier::Slow)),
+            // promoted or written again meanwhile
+            Err(e) => fs_read(&fast_path)
+                .await
+                .map(|data| (data, Tier::Fast))
+                .map_err(|_| e),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for TieredFsBackend {
+    async fn read(&self, name: &str) -> Result<CacheData> {
+        self.read_tier(name, Tier::Fast).await.map(|(data, _)| data)
+    }
+
+    /// Read a file from the recorded `tier`, falling back to the other one if
+    /// it is not there, e.g. demoted since it is recorded, so that reads do
+    /// not probe the tiers.
+    async fn read_tier(&self, name: &str, tier: Tier) -> Result<(CacheData, Tier)> {
+        if tier == Tier::Slow {
+            return self.read_slow(name).await;
+        }
+        match fs_read(&fs_path(&self.fast_root, name, false)?).await {
+            Ok(data) => Ok((data, Tier::Fast)),
+            Err(_) => self.read_slow(name).await,
+        }
+    }
+
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<PersistReport> {
+        let fast_path = fs_path(&self.fast_root, name, false)?;
+        let slow_path = fs_path(&self.slow_root, name, false)?;
+        let old_size = fs::metadata(&fast_path).map_or(0, |metadata| metadata.len());
+        let permissions = FsPermissions::default();
+        let report = fs_persist(&fast_path, data, &permissions, self.fsync, known_sha256).await?;
+        if report.unchanged {
+            // possibly in the slow tier, where it stays
+            return Ok(report);
+        }
+        self.fast_usage.fetch_sub(old_size, Ordering::SeqCst);
+        self.fast_usage
+            .fetch_add(report.bytes_written, Ordering::SeqCst);
+        // drop a stale copy in the slow tier
+        let _ = fs::remove_file(slow_path);
+        Ok(report)
+    }
+
+    async fn stage(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<Staged> {
+        let fast_path = fs_path(&self.fast_root, name, false)?;
+        let permissions = FsPermissions::default();
+        let report = fs_stage(&fast_path, data, &permissions, self.fsync, known_sha256).await?;
+        Ok(Staged::Written(report))
+    }
+
+    async fn publish(&self, name: &str, staged: Staged) -> Result<PersistReport> {
+        let report = match staged {
+            Staged::Written(report) => report,
+            Staged::Pending(data, known_sha256) => {
+                return self
+                    .persist_changed(name, data, known_sha256.as_deref())
+                    .await
+            }
+        };
+        if report.unchanged {
+            return Ok(report);
+        }
+        let fast_path = fs_path(&self.fast_root, name, false)?;
+        let old_size = fs::metadata(&fast_path).map_or(0, |metadata| metadata.len());
+        fs_publish(&fast_path, self.fsync).await?;
+        self.fast_usage.fetch_sub(old_size, Ordering::SeqCst);
+        self.fast_usage
+            .fetch_add(report.bytes_written, Ordering::SeqCst);
+        let _ = fs::remove_file(fs_path(&self.slow_root, name, false)?);
+        Ok(report)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let fast_path = fs_path(&self.fast_root, name, false)?;
+        match fs::metadata(&fast_path) {
+            Ok(metadata) => {
+                fs::remove_file(fast_path)?;
+                self.fast_usage.fetch_sub(metadata.len(), Ordering::SeqCst);
+                Ok(())
+            }
+            Err(_) => fs::remove_file(fs_path(&self.slow_root, name, false)?).map_err(|e| e.into()),
+        }
+    }
+
+    async fn metadata(&self, name: &str) -> Result<StorageMeta> {
+        let fast_path = fs_path(&self.fast_root, name, false)?;
+        match fs_metadata(&fast_path, None) {
+            Ok(meta) => Ok(meta),
+            Err(_) => fs_metadata(&fs_path(&self.slow_root, name, false)?, None),
+        }
+    }
+
+    fn write_path(&self, name: &str) -> Option<PathBuf> {
+        Some(fs_temp_path(&fs_path(&self.fast_root, name, false).ok()?))
+    }
+
+    fn is_tiered(&self) -> bool {
+        true
+    }
+
+    fn needs_demotion(&self) -> bool {
+        self.fast_usage.load(Ordering::SeqCst) > self.fast_budget
+    }
+
+    /// Move files to the slow tier, in the given order, until the fast tier fits
+    /// in its budget. `names` should be ordered from least to most recently used.
+    fn demote(&self, names: &[String]) -> Vec<String> {
+        let mut demoted = Vec::new();
+        for name in names {
+            if !self.needs_demotion() {
+                break;
+            }
+            let (fast_path, slow_path) = match (
+                fs_path(&self.fast_root, name, false),
+                fs_path(&self.slow_root, name, false),
+            ) {
+                (Ok(fast_path), Ok(slow_path)) => (fast_path, slow_path),
+                _ => continue,
+            };
+            if !fast_path.exists() {
+                continue;
+            }
+            match move_file(&fast_path, &slow_path) {
+                Ok(size) => {
+                    self.fast_usage.fetch_sub(size, Ordering::SeqCst);
+                    debug!("demoted {} to the slow tier", name);
+                    demoted.push(name.clone());
+                }
+                Err(e) => {
+                    warn!("failed to demote {}: {}", name, e);
+                }
+            }
+        }
+        demoted
+    }
+}
+
+/// Objects kept in memory, shared by the caches of the storage
+#[derive(Clone, Default)]
+pub struct MemBackend {
+    map: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemBackend {
+    async fn read(&self, name: &str) -> Result<CacheData> {
+        self.map.read().await.get(name).map_or(
+            Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No such key.",
+            ))),
+            |x| Ok(x.clone().into()),
+        )
+    }
+
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<PersistReport> {
+        let mut buf = Vec::new();
+        let mut report = write_counted(data, |bytes| {
+            buf.extend_from_slice(bytes);
+            Ok(())
+        })
+        .await?;
+        let mut map = self.map.write().await;
+        if known_sha256 == Some(report.sha256.as_str())
+            && map.get(name).map(|old| old.len() as u64) == Some(report.bytes_written)
+        {
+            report.unchanged = true;
+            return Ok(report);
+        }
+        map.insert(name.to_string(), buf);
+        Ok(report)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        self.map.write().await.remove(name);
+        Ok(())
+    }
+
+    async fn metadata(&self, name: &str) -> Result<StorageMeta> {
+        match self.map.read().await.get(name) {
+            Some(object) => Ok(StorageMeta {
+                size: object.len() as CacheSizeType,
+                modified: None,
+            }),
+            None => Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No such key.",
+            ))),
+        }
+    }
+}
+
+/// Objects in a bucket of an S3-compatible object storage
+#[derive(Clone)]
+pub struct S3Backend {
+    endpoint: String,
+    bucket: String,
+}
+
+impl S3Backend {
+    pub fn new(endpoint: &str, bucket: &str) -> Self {
+        S3Backend {
+            endpoint: endpoint.to_string(),
+            bucket: bucket.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn read(&self, name: &str) -> Result<CacheData> {
+        let client = new_s3_client(&self.endpoint);
+        let output = client
+            .get_object(rusoto_s3::GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: name.to_string(),
+                ..Default::default()
+            })
+            .await?;
+        let rusoto_stream = output.body.unwrap();
+        Ok(CacheData::ByteStream(
+            Box::new(rusoto_stream.map_err(Error::IoError)),
+            output.content_length.map(|x| x as CacheSizeType),
+        ))
+    }
+
+    /// Objects are always replaced
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        _known_sha256: Option<&str>,
+    ) -> Result<PersistReport> {
+        let bucket = &self.bucket;
+        let client = new_s3_client(&self.endpoint);
+        let len = data.len();
+        let declared = declared_size(&data);
+        match client
+            .head_bucket(rusoto_s3::HeadBucketRequest {
+                bucket: bucket.clone(),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(_) => {}
+            Err(e) => match e {
+                RusotoError::Unknown(resp) => {
+                    if resp.status.as_u16() == 404 {
+                        client
+                            .create_bucket(rusoto_s3::CreateBucketRequest {
+                                bucket: bucket.clone(),
+                                ..Default::default()
+                            })
+                            .await
+                            .unwrap();
+                        debug!("created bucket {}", bucket)
+                    }
+                }
+                _ => {
+                    error!("{:?}", e);
+                }
+            },
+        };
+
+        // count and hash the bytes while they are uploaded
+        let digest = Arc::new(std::sync::Mutex::new(PersistDigest::default()));
+        let digest_clone = digest.clone();
+        let body = data.into_byte_stream().map(move |bytes| {
+            if let Ok(bytes) = &bytes {
+                digest_clone.lock().unwrap().update(bytes);
+            }
+            bytes.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        });
+        client
+            .put_object(rusoto_s3::PutObjectRequest {
+                bucket: bucket.clone(),
+                key: name.to_string(),
+                content_length: Some(len as i64),
+                body: Some(rusoto_s3::StreamingBody::new(body)),
+                ..Default::default()
+            })
+            .await?;
+        let digest = std::mem::take(&mut *digest.lock().unwrap());
+        match digest.finish(declared) {
+            Ok(report) => Ok(report),
+            Err(e) => {
+                self.remove(name).await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let client = new_s3_client(&self.endpoint);
+        client
+            .delete_object(rusoto_s3::DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: name.to_string(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn metadata(&self, name: &str) -> Result<StorageMeta> {
+        let client = new_s3_client(&self.endpoint);
+        let output = client
+            .head_object(rusoto_s3::HeadObjectRequest {
+                bucket: self.bucket.clone(),
+                key: name.to_string(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| Error::OtherError(format!("failed to head rusoto object: {}", e)))?;
+        // e.g. `Wed, 21 Oct 2015 07:28:00 GMT`
+        let modified = output
+            .last_modified
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(&date).ok())
+            .map(SystemTime::from);
+        Ok(StorageMeta {
+            size: output.content_length.unwrap_or(0) as CacheSizeType,
+            modified,
+        })
+    }
+}
+
+/// Objects written to an ordered list of backends, e.g. a local filesystem
+/// and an S3 bucket, see `Policy::replicas`. Writes go to all of them and
+/// reads are served by the first that has the object. A write fails if the
+/// first backend fails it; an object missing from others is degraded, see
+/// `degraded_entries`, until it is written again or repaired.
+#[derive(Clone)]
+pub struct ReplicatedBackend {
+    replicas: Vec<Arc<dyn StorageBackend>>,
+    /// Copy an object to the backends it is missing from when it is read
+    repair: bool,
+    /// name -> indexes of the backends missing the object. Kept in memory
+    /// only, so objects degraded before a restart are repaired on reads
+    /// falling back to another backend only.
+    degraded: Arc<std::sync::Mutex<HashMap<String, Vec<usize>>>>,
+    /// Names of the objects being repaired
+    repairing: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+impl ReplicatedBackend {
+    /// `replicas` in the order they are read, the first one being the
+    /// primary
+    pub fn new(replicas: Vec<Arc<dyn StorageBackend>>, repair: bool) -> Self {
+        assert!(!replicas.is_empty(), "no replicas");
+        Self {
+            replicas,
+            repair,
+            degraded: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            repairing: Arc::new(std::sync::Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn degraded_replicas(&self, name: &str) -> Vec<usize> {
+        let degraded = self.degraded.lock().unwrap();
+        degraded.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Copy `name` from the backend `source` to the backends `targets` in
+    /// the background
+    fn spawn_repair(&self, name: &str, source: usize, targets: Vec<usize>) {
+        if !self.repairing.lock().unwrap().insert(name.to_string()) {
+            return;
+        }
+        let backend = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let mut failed = Vec::new();
+            for target in targets {
+                let result = match backend.replicas[source].read(&name).await {
+                    Ok(data) => backend.replicas[target].persist(&name, data).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(_) => info!("repaired {} in replica #{}", name, target),
+                    Err(e) => {
+                        warn!("failed to repair {} in replica #{}: {}", name, target, e);
+                        failed.push(target);
+                    }
+                }
+            }
+            mark_degraded(&backend.degraded, &name, failed);
+            backend.repairing.lock().unwrap().remove(&name);
+        });
+    }
+}
+
+/// Record the backends missing `name`, none if `missing` is empty
+fn mark_degraded(
+    degraded: &std::sync::Mutex<HashMap<String, Vec<usize>>>,
+    name: &str,
+    missing: Vec<usize>,
+) {
+    let mut degraded = degraded.lock().unwrap();
+    if missing.is_empty() {
+        degraded.remove(name);
+    } else {
+        degraded.insert(name.to_string(), missing);
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ReplicatedBackend {
+    /// The object of the first backend reading it. If repair is on, it is
+    /// then copied to the backends failing to read it, and to the ones it is
+    /// known to be missing from.
+    async fn read(&self, name: &str) -> Result<CacheData> {
+        let mut missing = Vec::new();
+        let mut last_error = None;
+        for (idx, replica) in self.replicas.iter().enumerate() {
+            match replica.read(name).await {
+                Ok(data) => {
+                    if idx > 0 {
+                        debug!("{} read from replica #{}", name, idx);
+                    }
+                    for degraded in self.degraded_replicas(name) {
+                        if degraded != idx && !missing.contains(&degraded) {
+                            missing.push(degraded);
+                        }
+                    }
+                    if self.repair && !missing.is_empty() {
+                        self.spawn_repair(name, idx, missing);
+                    }
+                    return Ok(data);
+                }
+                Err(e) => {
+                    missing.push(idx);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// Written to the first backend, then copied from it to the others. The
+    /// report is the one of the first backend. Failures of the others are
+    /// logged, and the object is degraded.
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<PersistReport> {
+        let primary = &self.replicas[0];
+        let report = primary.persist_changed(name, data, known_sha256).await?;
+        let degraded = self.degraded_replicas(name);
+        let mut failed = Vec::new();
+        for (idx, replica) in self.replicas.iter().enumerate().skip(1) {
+            // the others are assumed to hold the same object
+            if report.unchanged && !degraded.contains(&idx) {
+                continue;
+            }
+            let result = match primary.read(name).await {
+                Ok(data) => replica.persist_changed(name, data, known_sha256).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("failed to write {} to replica #{}: {}", name, idx, e);
+                failed.push(idx);
+            }
+        }
+        mark_degraded(&self.degraded, name, failed);
+        Ok(report)
+    }
+
+    /// Removed from all backends. Fails if the first backend fails to remove
+    /// it.
+    async fn remove(&self, name: &str) -> Result<()> {
+        let mut result = Ok(());
+        for (idx, replica) in self.replicas.iter().enumerate() {
+            match replica.remove(name).await {
+                Ok(()) => {}
+                Err(e) if idx == 0 => result = Err(e),
+                Err(e) => debug!("failed to remove {} from replica #{}: {}", name, idx, e),
+            }
+        }
+        mark_degraded(&self.degraded, name, vec![]);
+        result
+    }
+
+    async fn metadata(&self, name: &str) -> Result<StorageMeta> {
+        let mut last_error = None;
+        for replica in &self.replicas {
+            match replica.metadata(name).await {
+                Ok(meta) => return Ok(meta),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    fn local_path(&self, name: &str) -> Option<(String, CacheSizeType)> {
+        self.replicas[0].local_path(name)
+    }
+
+    fn write_path(&self, name: &str) -> Option<PathBuf> {
+        self.replicas[0].write_path(name)
+    }
+
+    fn needs_demotion(&self) -> bool {
+        self.replicas.iter().any(|replica| replica.needs_demotion())
+    }
+
+    fn demote(&self, names: &[String]) -> Vec<String> {
+        let mut demoted = Vec::new();
+        for replica in &self.replicas {
+            demoted.extend(replica.demote(names));
+        }
+        demoted.sort();
+        demoted.dedup();
+        demoted
+    }
+
+    fn degraded_entries(&self) -> Option<u64> {
+        Some(self.degraded.lock().unwrap().len() as u64)
+    }
+}
+
+/// Longest encoded file name, longer ones are shortened with a hash. Leaves
+/// room for the `.<name>.part0000.part` temporary files of chunks within the
+/// usual 255 bytes.
+const MAX_FILE_NAME_LEN: usize = 200;
+
+/// Length of the encoded name kept in front of the hash of a shortened name
+const SHORTENED_NAME_PREFIX_LEN: usize = 128;
+
+/// Whether file names must also be valid on NTFS, see `encode_file_name`
+const NTFS_NAMES: bool = cfg!(windows);
+
+/// Names reserved by Windows, with or without an extension
+const WINDOWS_DEVICE_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The path of the file of `name` in a filesystem storage, relative to its
+/// root, with `/` separators. Bytes of path segments other than ASCII
+/// letters, digits and `-._~+=,@` are percent-encoded, e.g. `a b%.whl` is
+/// `a%20b%25.whl`, so that names are valid on any filesystem and different
+/// names never share a file. A segment encoded to more than
+/// `MAX_FILE_NAME_LEN` bytes keeps a prefix, followed by `~` and the SHA-256
+/// of the segment.
+pub fn encode_file_path(name: &str) -> String {
+    encode_file_path_with(name, NTFS_NAMES)
+}
+
+fn encode_file_path_with(name: &str, ntfs: bool) -> String {
+    name.split('/')
+        .map(|segment| encode_file_name(segment, ntfs))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// With `ntfs`, names Windows would not keep as is are encoded as well: the
+/// first letter of device names like `con.txt` (`%63on.txt`), and trailing
+/// dots, which NTFS strips. Letters and dots are never encoded otherwise, so
+/// encoded names stay distinct.
+fn encode_file_name(segment: &str, ntfs: bool) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~+=,@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if ntfs && segment != "." && segment != ".." {
+        if is_windows_device_name(segment) {
+            encoded.replace_range(..1, &format!("%{:02X}", segment.as_bytes()[0]));
+        }
+        let dots = encoded.len() - encoded.trim_end_matches('.').len();
+        if dots > 0 {
+            encoded.truncate(encoded.len() - dots);
+            encoded.push_str(&"%2E".repeat(dots));
+        }
+    }
+    if encoded.len() > MAX_FILE_NAME_LEN {
+        // the encoding is ASCII, so any length is a char boundary
+        encoded.truncate(SHORTENED_NAME_PREFIX_LEN);
+        encoded.push_str(&format!("~{:x}", Sha256::digest(segment.as_bytes())));
+    }
+    encoded
+}
+
+/// Whether Windows reserves a file name, e.g. `nul` or `COM1.tar.gz`
+fn is_windows_device_name(segment: &str) -> bool {
+    let stem = segment.split('.').next().unwrap_or_default();
+    WINDOWS_DEVICE_NAMES
+        .iter()
+        .any(|device| device.eq_ignore_ascii_case(stem))
+}
+
+/// Resolve the path of a cached file in the filesystem storage, with the name
+/// encoded by `encode_file_path`.
+///
+/// Keys are sanitized before they get here, but names that could escape the
+/// root directory (absolute paths, `..`) are rejected anyway, so a cache never
+/// touches files outside of its root.
+fn fs_path(root_dir: &str, name: &str, sharded: bool) -> Result<PathBuf> {
+    // split on `/` only: other separators and drive prefixes of Windows are
+    // encoded, e.g. `\` is `%5C` and `c:` is `c%3A`
+    let contained = !name.starts_with('/') && name.split('/').all(|segment| segment != "..");
+    if name.is_empty() || !contained {
+        return Err(Error::InvalidKey(format!(
+            "path outside of the storage root: {}",
+            name
+        )));
+    }
+    let mut path = PathBuf::from(root_dir);
+    if sharded {
+        let hash = format!("{:016x}", util::fnv1a_64(name.as_bytes()));
+        path.push(&hash[0..2]);
+        path.push(&hash[2..4]);
+    }
+    for segment in encode_file_path(name).split('/') {
+        if !segment.is_empty() && segment != "." {
+            path.push(segment);
+        }
+    }
+    Ok(path)
+}
+
+/// The metadata of the file at `path`. `size` overrides the size of the
+/// file, e.g. of the manifest of an object stored in chunks
+fn fs_metadata(path: &Path, size: Option<u64>) -> Result<StorageMeta> {
+    let metadata = fs::metadata(path)?;
+    Ok(StorageMeta {
+        size: size.unwrap_or_else(|| metadata.len()),
+        modified: metadata.modified().ok(),
+    })
+}
+
+async fn fs_read(path: &Path) -> Result<CacheData> {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            // sent as Content-Length of cache hits
+            let len = metadata.len();
+            match get_file_stream(path).await {
+                Ok(stream) => Ok(CacheData::ByteStream(Box::new(stream), Some(len))),
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(Error::IoError(e)),
+    }
+}
+
+/// Move a file, creating parent directories of the destination.
+/// Falls back to copy and remove if `from` and `to` are on different filesystems.
+/// Returns the size of the file.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<u64> {
+    let size = fs::metadata(from)?.len();
+    fs::create_dir_all(to.parent().unwrap())?;
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(size)
+}
+
+/// Attempts of `replace_file` while the destination is in use
+const REPLACE_ATTEMPTS: u32 = 10;
+
+/// Move a written file to `to`, replacing the file there. On Windows the
+/// destination can not be replaced while another process, e.g. an antivirus
+/// or a reader that did not allow it, has it open, so sharing violations are
+/// retried with a growing delay.
+async fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match tokio::fs::rename(from, to).await {
+            Err(e) if is_sharing_violation(&e) && attempt < REPLACE_ATTEMPTS => {
+                debug!("{} is in use, retrying: {}", to.display(), e);
+                tokio::time::sleep(Duration::from_millis(20 * attempt as u64)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(windows)]
+fn is_sharing_violation(e: &std::io::Error) -> bool {
+    // ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    matches!(e.raw_os_error(), Some(5) | Some(32) | Some(33))
+}
+
+#[cfg(not(windows))]
+fn is_sharing_violation(_e: &std::io::Error) -> bool {
+    false
+}
+
+/// Total size of files under `path`, 0 if it does not exist.
+fn dir_size(path: &Path) -> u64 {
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            })
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+/// Create a directory and all of its missing parents, applying `permissions`
+/// to the directories created.
+fn create_dirs(dir: &Path, permissions: &FsPermissions) -> std::io::Result<()> {
+    if dir.as_os_str().is_empty() || dir.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dirs(parent, permissions)?;
+    }
+    match fs::create_dir(dir) {
+        Ok(_) => permissions.apply(dir, permissions.dir_mode),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e),
+    }
 }
 
-impl Storage {
-    pub async fn read(&self, name: &str) -> Result<CacheData> {
-        match &self {
-            Storage::FileSystem { root_dir, .. } => {
-                let mut path = PathBuf::from(root_dir);
-                path.push(name);
-                match fs::metadata(&path) {
-                    Ok(metadata) => {
-                        let len = metadata.len(); // actually we don't need to know the size here
-                        match get_file_stream(&path).await {
-                            Ok(stream) => Ok(CacheData::ByteStream(Box::new(stream), Some(len))),
-                            Err(e) => Err(e),
-                        }
-                    }
-                    Err(e) => Err(Error::IoError(e)),
-                }
-            }
-            Storage::Memory { map, .. } => map.read().await.get(name).map_or(
-                Err(Error::IoError(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "No such key.",
-                ))),
-                |x| Ok(x.clone().into()),
-            ),
-            Storage::S3 { endpoint, bucket } => {
-                let client = new_s3_client(endpoint);
-                let output = client
-                    .get_object(rusoto_s3::GetObjectRequest {
-                        bucket: bucket.clone(),
-                        key: name.to_string(),
-                        ..Default::default()
-                    })
-                    .await?;
-                let rusoto_stream = output.body.unwrap();
-                Ok(CacheData::ByteStream(
-                    Box::new(rusoto_stream.map_err(Error::IoError)),
-                    output.content_length.map(|x| x as CacheSizeType),
-                ))
-            }
-        }
-    }
-
-    pub async fn persist(&self, name: &str, mut data: CacheData) {
-        match self {
-            Storage::FileSystem { root_dir, .. } => fs_persist(root_dir, name, &mut data).await,
-            Storage::Memory { ref map, .. } => {
-                map.write()
-                    .await
-                    .insert(name.to_string(), data.into_vec_u8().await);
-            }
-            Storage::S3 {
-                endpoint, bucket, ..
-            } => {
-                let client = new_s3_client(endpoint);
-                let len = data.len();
-                match client
-                    .head_bucket(rusoto_s3::HeadBucketRequest {
-                        bucket: bucket.clone(),
-                        ..Default::default()
-                    })
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(e) => match e {
-                        RusotoError::Unknown(resp) => {
-                            if resp.status.as_u16() == 404 {
-                                client
-                                    .create_bucket(rusoto_s3::CreateBucketRequest {
-                                        bucket: bucket.clone(),
-                                        ..Default::default()
-                                    })
-                                    .await
-                                    .unwrap();
-                                debug!("created bucket {}", bucket)
-                            }
-                        }
-                        _ => {
-                            error!("{:?}", e);
-                        }
-                    },
-                };
+/// The file an object is written to before it is moved to `path`
+fn fs_temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.part", name))
+}
 
-                match client
-                    .put_object(rusoto_s3::PutObjectRequest {
-                        bucket: bucket.clone(),
-                        key: name.to_string(),
-                        content_length: Some(len as i64),
-                        body: Some(rusoto_s3::StreamingBody::new(
-                            data.into_byte_stream()
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
-                        )),
-                        ..Default::default()
-                    })
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Failed to persist object in S3: {:?}", e)
-                    }
-                }
+/// Whether a file is a temporary file written by `fs_persist`
+pub fn is_fs_temp_path(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.len() > ".part".len() + 1 && name.starts_with('.') && name.ends_with(".part")
+}
+
+/// Temporary files of interrupted writes found in a filesystem storage
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TempFilesReport {
+    /// Number of stale files removed
+    pub removed: u64,
+    /// Bytes of the removed files
+    pub reclaimed_bytes: u64,
+    /// Number of files kept, that may be of writes in progress
+    pub kept: u64,
+}
+
+impl TempFilesReport {
+    pub fn add(&mut self, other: &TempFilesReport) {
+        self.removed += other.removed;
+        self.reclaimed_bytes += other.reclaimed_bytes;
+        self.kept += other.kept;
+    }
+}
+
+/// Remove temporary files under `dir` not modified for `max_age`, e.g. left
+/// behind by a crash while an object was written. They are not part of the
+/// size of caches, so they would never be evicted.
+pub fn clean_temp_files(dir: &Path, max_age: Duration) -> TempFilesReport {
+    let mut report = TempFilesReport::default();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return report,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            report.add(&clean_temp_files(&path, max_age));
+            continue;
+        }
+        if !is_fs_temp_path(&path) {
+            continue;
+        }
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or_default();
+        if age < max_age {
+            report.kept += 1;
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                debug!("removed stale temporary file {}", path.display());
+                report.removed += 1;
+                report.reclaimed_bytes += metadata.len();
             }
+            Err(e) => warn!("failed to remove {}: {}", path.display(), e),
         }
     }
+    report
+}
+
+/// Sync a written file to disk, unless `fsync` is `never`. The time taken
+/// is recorded in `fsync_latency`.
+fn sync_file(file: &mut fs::File, fsync: Fsync) -> std::io::Result<()> {
+    file.flush()?;
+    if fsync == Fsync::Never {
+        return Ok(());
+    }
+    let start = Instant::now();
+    let synced = file.sync_data();
+    histogram!(metric::HG_FSYNC_LATENCY, start.elapsed().as_secs_f64(), "target" => "data");
+    synced
+}
 
-    pub async fn remove(&self, name: &str) -> Result<()> {
-        match self {
-            Storage::FileSystem { ref root_dir, .. } => {
-                let mut path = PathBuf::from(root_dir);
-                path.push(name);
-                fs::remove_file(path).map_err(|e| e.into())
+/// Sync the directory of `path`, with `fsync: data+dir`, so that a file
+/// moved there is still there after a power cut. Directories can not be
+/// opened to be synced on Windows.
+fn sync_parent_dir(path: &Path, fsync: Fsync) -> std::io::Result<()> {
+    if fsync != Fsync::DataDir || cfg!(windows) {
+        return Ok(());
+    }
+    let start = Instant::now();
+    let synced = match path.parent() {
+        Some(dir) => fs::File::open(dir).and_then(|dir| dir.sync_all()),
+        None => Ok(()),
+    };
+    histogram!(metric::HG_FSYNC_LATENCY, start.elapsed().as_secs_f64(), "target" => "dir");
+    synced
+}
+
+/// Write an object to a temporary file, and move it to `path` once all bytes
+/// are written and synced as `fsync` asks, so that a partial file is never
+/// served. See `StorageBackend::persist_changed`. The file is only kept if it
+/// has the same size as the new one, in case it was modified behind the cache.
+async fn fs_persist(
+    path: &Path,
+    data: CacheData,
+    permissions: &FsPermissions,
+    fsync: Fsync,
+    known_sha256: Option<&str>,
+) -> Result<PersistReport> {
+    let report = fs_stage(path, data, permissions, fsync, known_sha256).await?;
+    if !report.unchanged {
+        fs_publish(path, fsync).await?;
+    }
+    Ok(report)
+}
+
+/// Write an object to the temporary file of `path`, see `fs_temp_path`,
+/// which `fs_publish` moves in place. Nothing is left to publish if the
+/// report is `unchanged`.
+async fn fs_stage(
+    path: &Path,
+    data: CacheData,
+    permissions: &FsPermissions,
+    fsync: Fsync,
+    known_sha256: Option<&str>,
+) -> Result<PersistReport> {
+    let parent_dirs = path.parent().unwrap();
+    create_dirs(parent_dirs, permissions)?;
+    let temp_path = fs_temp_path(path);
+    let mut f = fs::File::create(&temp_path)?;
+    if let Err(e) = permissions.apply(&temp_path, permissions.file_mode) {
+        warn!(
+            "failed to set permissions of {}: {}",
+            temp_path.display(),
+            e
+        );
+    }
+    match write_counted(data, |bytes| f.write_all(bytes)).await {
+        Ok(mut report) => {
+            if known_sha256 == Some(report.sha256.as_str())
+                && fs::metadata(path).map_or(false, |m| m.len() == report.bytes_written)
+            {
+                drop(f);
+                let _ = fs::remove_file(&temp_path);
+                report.unchanged = true;
+                return Ok(report);
             }
-            Storage::Memory { map, .. } => {
-                map.write().await.remove(name);
-                Ok(())
+            // the bytes are on disk before the file is in place, so that a
+            // crash never leaves a partial file under the name of the object.
+            // An open file can not be moved on Windows.
+            let synced = sync_file(&mut f, fsync);
+            drop(f);
+            if let Err(e) = synced {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e.into());
             }
-            Storage::S3 {
-                endpoint, bucket, ..
-            } => {
-                let client = new_s3_client(endpoint);
-                client
-                    .delete_object(rusoto_s3::DeleteObjectRequest {
-                        bucket: bucket.clone(),
-                        key: name.to_string(),
-                        ..Default::default()
-                    })
-                    .await?;
-                Ok(())
+            Ok(report)
+        }
+        Err(e) => {
+            drop(f);
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Move the temporary file written by `fs_stage` to `path`
+async fn fs_publish(path: &Path, fsync: Fsync) -> Result<()> {
+    let temp_path = fs_temp_path(path);
+    if let Err(e) = replace_file(&temp_path, path).await {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+    // the entry is committed once the file is in place
+    sync_parent_dir(path, fsync)?;
+    Ok(())
+}
+
+/// The manifest of an object stored in chunks, `<name>.manifest` next to the
+/// chunk files `<name>.part0000`, `<name>.part0001`, etc. Its presence tells
+/// chunked objects from objects stored in a single file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ChunkManifest {
+    /// Total size of the object
+    size: u64,
+    chunk_size: u64,
+    /// Number of chunk files
+    chunks: usize,
+    /// SHA-256 of the object, hex encoded
+    sha256: String,
+}
+
+fn manifest_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.manifest", name))
+}
+
+fn chunk_path(path: &Path, index: usize) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.part{:04}", name, index))
+}
+
+/// The manifest of the object stored at `path`, `None` if it is not stored
+/// in chunks
+fn read_manifest(path: &Path) -> Result<Option<ChunkManifest>> {
+    let manifest_path = manifest_path(path);
+    let content = match fs::read(&manifest_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_slice(&content).map(Some).map_err(|e| {
+        Error::CacheMetadataInconsistent(format!("{}: {}", manifest_path.display(), e))
+    })
+}
+
+/// Remove the manifest and the chunk files of the object stored at `path`.
+/// Returns whether the object is stored in chunks.
+fn remove_chunks(path: &Path) -> Result<bool> {
+    let manifest = match read_manifest(path)? {
+        Some(manifest) => manifest,
+        None => return Ok(false),
+    };
+    // the object is gone once its manifest is
+    fs::remove_file(manifest_path(path))?;
+    for index in 0..manifest.chunks {
+        if let Err(e) = fs::remove_file(chunk_path(path, index)) {
+            let path = path.display();
+            warn!("failed to remove chunk {} of {}: {}", index, path, e);
+        }
+    }
+    Ok(true)
+}
+
+/// Read an object stored in chunks, concatenating its chunk files
+fn fs_read_chunked(path: &Path, manifest: &ChunkManifest) -> Result<CacheData> {
+    let paths: Vec<PathBuf> = (0..manifest.chunks)
+        .map(|index| chunk_path(path, index))
+        .collect();
+    // a missing chunk is a miss rather than a truncated response
+    for chunk in &paths {
+        fs::metadata(chunk)?;
+    }
+    let stream = stream::iter(paths)
+        .then(|chunk| async move { get_file_stream(&chunk).await })
+        .try_flatten();
+    Ok(CacheData::ByteStream(
+        Box::new(Box::pin(stream)),
+        Some(manifest.size),
+    ))
+}
+
+/// Writes an object to the temporary files of its chunks, starting a new
+/// chunk every `chunk_size` bytes
+struct ChunkWriter<'a> {
+    path: &'a Path,
+    chunk_size: u64,
+    permissions: &'a FsPermissions,
+    fsync: Fsync,
+    file: Option<fs::File>,
+    /// Bytes written to the current chunk
+    written: u64,
+    temp_paths: Vec<PathBuf>,
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn write(&mut self, mut bytes: &[u8]) -> std::io::Result<()> {
+        while !bytes.is_empty() {
+            if self.file.is_none() || self.written == self.chunk_size {
+                self.close()?;
+                let temp_path = fs_temp_path(&chunk_path(self.path, self.temp_paths.len()));
+                let file = fs::File::create(&temp_path)?;
+                let file_mode = self.permissions.file_mode;
+                if let Err(e) = self.permissions.apply(&temp_path, file_mode) {
+                    warn!(
+                        "failed to set permissions of {}: {}",
+                        temp_path.display(),
+                        e
+                    );
+                }
+                self.temp_paths.push(temp_path);
+                self.file = Some(file);
+                self.written = 0;
             }
+            let len = std::cmp::min(bytes.len() as u64, self.chunk_size - self.written) as usize;
+            if let Some(file) = self.file.as_mut() {
+                file.write_all(&bytes[..len])?;
+            }
+            self.written += len as u64;
+            bytes = &bytes[len..];
         }
+        Ok(())
     }
-    pub fn new_mem() -> Self {
-        Storage::Memory {
-            map: Arc::new(RwLock::new(HashMap::new())),
+
+    /// Sync and close the current chunk
+    fn close(&mut self) -> std::io::Result<()> {
+        match self.file.take() {
+            Some(mut file) => sync_file(&mut file, self.fsync),
+            None => Ok(()),
         }
     }
 
-    pub fn new_s3(endpoint: &str, bucket: &str) -> Self {
-        Storage::S3 {
-            endpoint: endpoint.to_string(),
-            bucket: bucket.to_string(),
+    fn remove_temp_files(&self) {
+        for temp_path in &self.temp_paths {
+            let _ = fs::remove_file(temp_path);
         }
     }
 }
 
-async fn fs_persist(root_dir: &str, name: &str, data: &mut CacheData) {
-    let mut path = PathBuf::from(root_dir);
-    path.push(name);
-    let parent_dirs = path.as_path().parent().unwrap();
-    fs::create_dir_all(parent_dirs).unwrap();
-    let mut f = fs::File::create(path).unwrap();
+/// Like `fs_persist`, but the object is split into chunk files of at most
+/// `chunk_size` bytes, for filesystems limiting the size of files. The chunks
+/// are written to temporary files, which are removed if the write fails.
+/// Once all bytes are written and synced, the manifest of the previous object
+/// is removed, the chunks are moved in place, and the new manifest is written
+/// last, so that a partial object is never served.
+async fn fs_persist_chunked(
+    path: &Path,
+    data: CacheData,
+    permissions: &FsPermissions,
+    fsync: Fsync,
+    known_sha256: Option<&str>,
+    chunk_size: u64,
+) -> Result<PersistReport> {
+    create_dirs(path.parent().unwrap(), permissions)?;
+    let mut writer = ChunkWriter {
+        path,
+        chunk_size,
+        permissions,
+        fsync,
+        file: None,
+        written: 0,
+        temp_paths: Vec::new(),
+    };
+    let result = write_counted(data, |bytes| writer.write(bytes)).await;
+    let closed = writer.close();
+    let mut report = match result.and_then(|report| closed.map(|_| report).map_err(Error::from)) {
+        Ok(report) => report,
+        Err(e) => {
+            writer.remove_temp_files();
+            return Err(e);
+        }
+    };
+    let previous = read_manifest(path).unwrap_or(None);
+    if let Some(previous) = &previous {
+        if known_sha256 == Some(report.sha256.as_str())
+            && previous.sha256 == report.sha256
+            && previous.size == report.bytes_written
+        {
+            writer.remove_temp_files();
+            report.unchanged = true;
+            return Ok(report);
+        }
+    }
+    let manifest = ChunkManifest {
+        size: report.bytes_written,
+        chunk_size,
+        chunks: writer.temp_paths.len(),
+        sha256: report.sha256.clone(),
+    };
+    let moved = move_chunks_in_place(path, &writer.temp_paths, &manifest, permissions, fsync).await;
+    if let Err(e) = moved {
+        writer.remove_temp_files();
+        return Err(e.into());
+    }
+    // chunks of a larger previous object, and a previous single file
+    let previous_chunks = previous.map_or(0, |previous| previous.chunks);
+    for index in manifest.chunks..previous_chunks {
+        let _ = fs::remove_file(chunk_path(path, index));
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            warn!("failed to remove {}: {}", path.display(), e)
+        }
+        _ => {}
+    }
+    Ok(report)
+}
+
+/// Replace the chunks of the object at `path` with the written ones, and
+/// write their manifest
+async fn move_chunks_in_place(
+    path: &Path,
+    temp_paths: &[PathBuf],
+    manifest: &ChunkManifest,
+    permissions: &FsPermissions,
+    fsync: Fsync,
+) -> std::io::Result<()> {
+    match fs::remove_file(manifest_path(path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for (index, temp_path) in temp_paths.iter().enumerate() {
+        replace_file(temp_path, &chunk_path(path, index)).await?;
+    }
+    let manifest_path = manifest_path(path);
+    let temp_path = fs_temp_path(&manifest_path);
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(&serde_json::to_vec(manifest)?)?;
+    sync_file(&mut file, fsync)?;
+    drop(file);
+    if let Err(e) = permissions.apply(&temp_path, permissions.file_mode) {
+        warn!(
+            "failed to set permissions of {}: {}",
+            temp_path.display(),
+            e
+        );
+    }
+    replace_file(&temp_path, &manifest_path).await?;
+    sync_parent_dir(&manifest_path, fsync)
+}
+
+/// Number of bytes and SHA-256 of a persisted object
+#[derive(Debug, Clone, PartialEq)]
+pub struct PersistReport {
+    pub bytes_written: u64,
+    /// Hex encoded
+    pub sha256: String,
+    /// The stored object had the same content, and is not replaced
+    pub unchanged: bool,
+}
+
+/// An object written by `StorageBackend::stage`, that reads do not find
+/// until it is published
+pub enum Staged {
+    /// Written where the backend moves it from when it is published, or not
+    /// at all if `PersistReport::unchanged`
+    Written(PersistReport),
+    /// Not written yet, by backends unable to stage objects, with the known
+    /// SHA-256 of the stored object
+    Pending(CacheData, Option<String>),
+}
+
+/// Counts and hashes the bytes of an object in a single pass
+#[derive(Default)]
+struct PersistDigest {
+    bytes_written: u64,
+    hasher: Sha256,
+}
+
+impl PersistDigest {
+    fn update(&mut self, bytes: &[u8]) {
+        self.bytes_written += bytes.len() as u64;
+        self.hasher.update(bytes);
+    }
+
+    /// Check the number of bytes against the declared size, if any
+    fn finish(self, declared: Option<u64>) -> Result<PersistReport> {
+        match declared {
+            Some(declared) if declared != self.bytes_written => {
+                Err(Error::SizeMismatch(declared, self.bytes_written))
+            }
+            _ => Ok(PersistReport {
+                bytes_written: self.bytes_written,
+                sha256: format!("{:x}", self.hasher.finalize()),
+                unchanged: false,
+            }),
+        }
+    }
+}
+
+/// The size of a stream, declared by e.g. the `Content-Length` of the upstream
+fn declared_size(data: &CacheData) -> Option<u64> {
     match data {
-        CacheData::ByteStream(stream, ..) => {
-            while let Some(v) = stream.next().await {
-                f.write_all(v.unwrap().as_ref()).unwrap()
+        CacheData::ByteStream(_, size) => *size,
+        _ => None,
+    }
+}
+
+/// Pass all bytes of `data` to `write`, counting and hashing them. Fails as
+/// soon as a stream runs past its declared size, or if it ends before it.
+async fn write_counted(
+    data: CacheData,
+    mut write: impl FnMut(&[u8]) -> std::io::Result<()>,
+) -> Result<PersistReport> {
+    let declared = declared_size(&data);
+    let mut stream = data.into_byte_stream();
+    let mut digest = PersistDigest::default();
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes?;
+        digest.update(&bytes);
+        if let Some(declared) = declared {
+            if digest.bytes_written > declared {
+                return Err(Error::SizeMismatch(declared, digest.bytes_written));
             }
         }
-        _ => f.write_all(data.as_ref()).unwrap(),
+        write(&bytes)?;
     }
+    digest.finish(declared)
+}
+
+/// Stream a file that is still being written, reading what is on disk and then
+/// following the writer, until the writer reports `Done` or `expected_size`
+/// bytes are read. Fails if the writer fails, makes no progress in `timeout`,
+/// or reports `Done` before `expected_size` bytes are written.
+pub fn follow_file(
+    path: PathBuf,
+    progress: watch::Receiver<DownloadProgress>,
+    expected_size: Option<u64>,
+    timeout: Duration,
+) -> impl Stream<Item = Result<Bytes>> {
+    let state: Option<(
+        Option<tokio::fs::File>,
+        watch::Receiver<DownloadProgress>,
+        u64,
+    )> = Some((None, progress, 0));
+    stream::unfold(state, move |state| {
+        let path = path.clone();
+        async move {
+            let (mut file, mut progress, read) = state?;
+            loop {
+                if expected_size.map_or(false, |size| read >= size) {
+                    return None;
+                }
+                // take the status before reading, so that an empty read after
+                // `Done` means the end of the file
+                let status = *progress.borrow();
+                if status == DownloadProgress::Failed {
+                    return Some((Err(Error::OtherError("download failed".into())), None));
+                }
+                if file.is_none() && status != DownloadProgress::Downloading(0) {
+                    match tokio::fs::File::open(&path).await {
+                        Ok(f) => file = Some(f),
+                        Err(e) => return Some((Err(e.into()), None)),
+                    }
+                }
+                if let Some(f) = file.as_mut() {
+                    let mut buf = vec![0; FOLLOW_CHUNK_SIZE];
+                    match f.read(&mut buf).await {
+                        Ok(0) => {}
+                        Ok(n) => {
+                            buf.truncate(n);
+                            let read = read + n as u64;
+                            return Some((Ok(Bytes::from(buf)), Some((file, progress, read))));
+                        }
+                        Err(e) => return Some((Err(e.into()), None)),
+                    }
+                }
+                if status == DownloadProgress::Done {
+                    if expected_size.map_or(false, |size| read < size) {
+                        return Some((Err(Error::OtherError("download incomplete".into())), None));
+                    }
+                    return None;
+                }
+                match tokio::time::timeout(timeout, progress.changed()).await {
+                    Ok(Ok(_)) => {}
+                    // the writer is gone without reporting `Done`
+                    Ok(Err(_)) => {
+                        return Some((Err(Error::OtherError("download aborted".into())), None))
+                    }
+                    Err(_) => {
+                        return Some((Err(Error::OtherError("download stalled".into())), None))
+                    }
+                }
+            }
+        }
+    })
 }
 
 pub async fn get_file_stream(path: &Path) -> Result<impl Stream<Item = Result<Bytes>>> {
@@ -209,47 +1822,852 @@ fn new_s3_client(endpoint: &str) -> S3Client {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::{MockBackend, StorageOp};
 
-    async fn write_read(storage: &mut Storage) {
+    async fn write_read(storage: &dyn StorageBackend) {
         let name = "write_read_test";
         let data = "Metaphysics includes cosmosology and ontology.";
-        storage.persist(name, String::from(data).into()).await;
+        storage
+            .persist(name, String::from(data).into())
+            .await
+            .unwrap();
         let data_read: Vec<u8> = storage.read(name).await.unwrap().into_vec_u8().await;
         assert_eq!(data.as_bytes().to_vec(), data_read);
     }
 
-    async fn remove(storage: &mut Storage) {
+    async fn remove(storage: &dyn StorageBackend) {
         let name = "remove_test";
-        storage.persist(name, String::from("wow").into()).await;
+        storage
+            .persist(name, String::from("wow").into())
+            .await
+            .unwrap();
         storage.remove(name).await.unwrap();
         assert!(storage.read(name).await.is_err());
     }
 
     #[tokio::test]
     async fn test_fs_write_read() {
-        let mut storage = Storage::FileSystem {
+        let storage = FsBackend {
             root_dir: "cache/storage_test".to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        write_read(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn test_fs_sharded_write_read() {
+        let storage = FsBackend {
+            root_dir: "cache/storage_sharded_test".to_string(),
+            sharded: true,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
         };
-        write_read(&mut storage).await;
+        write_read(&storage).await;
+        remove(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn test_tiered_fs_write_read() {
+        let storage = TieredFsBackend::new(
+            "cache/tiered_test/fast",
+            "cache/tiered_test/slow",
+            1024,
+            false,
+            Fsync::Data,
+        );
+        write_read(&storage).await;
+        remove(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn test_tiered_fs_demote_promote() {
+        let fast_root = "cache/tiered_demote_test/fast";
+        let slow_root = "cache/tiered_demote_test/slow";
+        let _ = fs::remove_dir_all("cache/tiered_demote_test");
+        let storage = TieredFsBackend::new(fast_root, slow_root, 4, true, Fsync::Never);
+        storage.persist("old", vec![1; 3].into()).await.unwrap();
+        storage.persist("new", vec![2; 3].into()).await.unwrap();
+        assert!(storage.needs_demotion());
+        assert_eq!(
+            storage.demote(&["old".to_string(), "new".to_string()]),
+            vec!["old".to_string()]
+        );
+        assert!(!storage.needs_demotion());
+        assert!(!Path::new(fast_root).join("old").exists());
+        assert!(Path::new(slow_root).join("old").exists());
+        assert!(Path::new(fast_root).join("new").exists());
+        // read from the slow tier, and promote it
+        assert_eq!(
+            storage.read("old").await.unwrap().into_vec_u8().await,
+            vec![1; 3]
+        );
+        assert!(Path::new(fast_root).join("old").exists());
+        assert!(!Path::new(slow_root).join("old").exists());
+    }
+
+    #[tokio::test]
+    async fn test_tiered_fs_read_tier() {
+        let fast_root = "cache/tiered_read_tier_test/fast";
+        let slow_root = "cache/tiered_read_tier_test/slow";
+        let _ = fs::remove_dir_all("cache/tiered_read_tier_test");
+        let storage = TieredFsBackend::new(fast_root, slow_root, 4, false, Fsync::Never);
+        storage.persist("old", vec![1; 3].into()).await.unwrap();
+        storage.persist("new", vec![2; 3].into()).await.unwrap();
+        storage.demote(&["old".to_string()]);
+        let (data, tier) = storage.read_tier("old", Tier::Slow).await.unwrap();
+        assert_eq!((data.into_vec_u8().await, tier), (vec![1; 3], Tier::Slow));
+        // demoted since it is recorded
+        let (data, tier) = storage.read_tier("old", Tier::Fast).await.unwrap();
+        assert_eq!((data.into_vec_u8().await, tier), (vec![1; 3], Tier::Slow));
+        // written again since it is recorded
+        let (data, tier) = storage.read_tier("new", Tier::Slow).await.unwrap();
+        assert_eq!((data.into_vec_u8().await, tier), (vec![2; 3], Tier::Fast));
+        assert!(storage.read_tier("none", Tier::Fast).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fs_fsync() {
+        for (fsync, dir) in &[
+            (Fsync::Never, "never"),
+            (Fsync::Data, "data"),
+            (Fsync::DataDir, "data_dir"),
+        ] {
+            for chunk_size in &[None, Some(4)] {
+                let storage = FsBackend {
+                    root_dir: format!("cache/fs_fsync_test/{}", dir),
+                    sharded: false,
+                    chunk_size: *chunk_size,
+                    fsync: *fsync,
+                    permissions: FsPermissions::default(),
+                };
+                storage.persist("a/pkg", vec![1; 10].into()).await.unwrap();
+                let data = storage.read("a/pkg").await.unwrap().into_vec_u8().await;
+                assert_eq!(data, vec![1; 10]);
+            }
+        }
+        assert!(!size_mismatch(Some(10), 10));
+        assert!(!size_mismatch(None, 0));
+        assert!(size_mismatch(Some(10), 0));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_fs_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let root_dir = "cache/fs_permissions_test";
+        let _ = fs::remove_dir_all(root_dir);
+        let storage = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions {
+                file_mode: Some(0o640),
+                dir_mode: Some(0o750),
+                uid: None,
+                gid: None,
+            },
+        };
+        storage
+            .persist("private/pkg.tar.gz", vec![0].into())
+            .await
+            .unwrap();
+        let mode = |path: &str| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("cache/fs_permissions_test/private/pkg.tar.gz"), 0o640);
+        assert_eq!(mode("cache/fs_permissions_test/private"), 0o750);
+        assert_eq!(mode("cache/fs_permissions_test"), 0o750);
+    }
+
+    fn byte_stream(chunks: Vec<&'static [u8]>, declared: Option<u64>) -> CacheData {
+        let stream = stream::iter(chunks.into_iter().map(|chunk| Ok(Bytes::from(chunk))));
+        CacheData::ByteStream(Box::new(stream), declared)
+    }
+
+    #[tokio::test]
+    async fn test_persist_report() {
+        let root_dir = "cache/persist_report_test";
+        let storage = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        let report = storage
+            .persist("hello", byte_stream(vec![b"hel", b"lo"], Some(5)))
+            .await
+            .unwrap();
+        assert_eq!(report.bytes_written, 5);
+        assert_eq!(
+            report.sha256,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        let report = MemBackend::new()
+            .persist("hello", String::from("hello").into())
+            .await
+            .unwrap();
+        assert_eq!(report.bytes_written, 5);
+        assert!(report.sha256.starts_with("2cf24dba"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_persist_unchanged() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root_dir = "cache/persist_unchanged_test";
+        let storage = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        let path = format!("{}/hello", root_dir);
+        let hello = storage
+            .persist("hello", String::from("hello").into())
+            .await
+            .unwrap();
+        assert!(!hello.unchanged);
+        let inode = fs::metadata(&path).unwrap().ino();
+        // the file is not replaced
+        let report = storage
+            .persist_changed("hello", String::from("hello").into(), Some(&hello.sha256))
+            .await
+            .unwrap();
+        assert!(report.unchanged);
+        assert_eq!(fs::metadata(&path).unwrap().ino(), inode);
+        let report = storage
+            .persist_changed("hello", String::from("world").into(), Some(&hello.sha256))
+            .await
+            .unwrap();
+        assert!(!report.unchanged);
+        assert_eq!(fs::read(&path).unwrap(), b"world");
+
+        let storage = MemBackend::new();
+        storage
+            .persist("hello", String::from("hello").into())
+            .await
+            .unwrap();
+        let report = storage
+            .persist_changed("hello", String::from("hello").into(), Some(&hello.sha256))
+            .await
+            .unwrap();
+        assert!(report.unchanged);
+    }
+
+    async fn stage_publish(storage: &dyn StorageBackend) {
+        let name = "stage_publish_test";
+        storage
+            .persist(name, String::from("old").into())
+            .await
+            .unwrap();
+        let staged = storage
+            .stage(name, String::from("new").into(), None)
+            .await
+            .unwrap();
+        // the stored object is read until the staged one is published
+        let read = storage.read(name).await.unwrap().into_vec_u8().await;
+        assert_eq!(read, b"old");
+        let report = storage.publish(name, staged).await.unwrap();
+        assert_eq!(report.bytes_written, 3);
+        let read = storage.read(name).await.unwrap().into_vec_u8().await;
+        assert_eq!(read, b"new");
+    }
+
+    #[tokio::test]
+    async fn test_stage_publish() {
+        let root_dir = "cache/stage_publish_test";
+        let _ = fs::remove_dir_all(root_dir);
+        let mut storage = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        stage_publish(&storage).await;
+        storage.chunk_size = Some(2);
+        stage_publish(&storage).await;
+        let storage = TieredFsBackend::new(
+            "cache/stage_publish_test/fast",
+            "cache/stage_publish_test/slow",
+            1024,
+            false,
+            Fsync::Data,
+        );
+        stage_publish(&storage).await;
+        stage_publish(&MemBackend::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_persist_size_mismatch() {
+        let root_dir = "cache/persist_mismatch_test";
+        let _ = fs::remove_dir_all(root_dir);
+        let storage = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        // truncated
+        let result = storage
+            .persist("short", byte_stream(vec![b"hel", b"lo"], Some(6)))
+            .await;
+        assert!(matches!(result, Err(Error::SizeMismatch(6, 5))));
+        // over-read
+        let result = storage
+            .persist("long", byte_stream(vec![b"hel", b"lo"], Some(4)))
+            .await;
+        assert!(matches!(result, Err(Error::SizeMismatch(4, 5))));
+        // neither the object nor its temporary file is left
+        assert_eq!(fs::read_dir(root_dir).unwrap().count(), 0);
+    }
+
+    fn chunked_storage(root_dir: &str, chunk_size: u64) -> FsBackend {
+        let _ = fs::remove_dir_all(root_dir);
+        FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: Some(chunk_size),
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        }
+    }
+
+    fn dir_entries(root_dir: &str) -> Vec<String> {
+        let mut entries: Vec<String> = fs::read_dir(root_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[tokio::test]
+    async fn test_chunked_exact_multiple() {
+        let root_dir = "cache/chunked_multiple_test";
+        let storage = chunked_storage(root_dir, 4);
+        let report = storage
+            .persist("pkg", byte_stream(vec![b"abc", b"defgh"], Some(8)))
+            .await
+            .unwrap();
+        assert_eq!(report.bytes_written, 8);
+        // no empty trailing chunk
+        assert_eq!(
+            dir_entries(root_dir),
+            vec!["pkg.manifest", "pkg.part0000", "pkg.part0001"]
+        );
+        let second = fs::read(format!("{}/pkg.part0001", root_dir)).unwrap();
+        assert_eq!(second, b"efgh");
+        let data = storage.read("pkg").await.unwrap();
+        assert_eq!(data.len(), 8);
+        assert_eq!(data.into_vec_u8().await, b"abcdefgh");
+        assert_eq!(storage.local_path("pkg"), None);
+        assert_eq!(storage.write_path("pkg"), None);
+
+        // a smaller object replaces all chunks
+        storage.persist("pkg", vec![1; 5].into()).await.unwrap();
+        assert_eq!(
+            dir_entries(root_dir),
+            vec!["pkg.manifest", "pkg.part0000", "pkg.part0001"]
+        );
+        storage.persist("pkg", vec![2; 3].into()).await.unwrap();
+        assert_eq!(dir_entries(root_dir), vec!["pkg.manifest", "pkg.part0000"]);
+        let data = storage.read("pkg").await.unwrap().into_vec_u8().await;
+        assert_eq!(data, vec![2; 3]);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_sub_chunk_and_remove() {
+        let root_dir = "cache/chunked_remove_test";
+        let storage = chunked_storage(root_dir, 1024);
+        write_read(&storage).await;
+        storage.persist("small", vec![7; 10].into()).await.unwrap();
+        assert!(dir_entries(root_dir).contains(&"small.part0000".to_string()));
+        let data = storage.read("small").await.unwrap();
+        assert_eq!(data.len(), 10);
+        assert_eq!(data.into_vec_u8().await, vec![7; 10]);
+
+        let storage = chunked_storage(root_dir, 2);
+        storage.persist("big", vec![3; 7].into()).await.unwrap();
+        assert_eq!(dir_entries(root_dir).len(), 5);
+        storage.remove("big").await.unwrap();
+        assert!(dir_entries(root_dir).is_empty());
+        assert!(storage.read("big").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_reads_single_files() {
+        let root_dir = "cache/chunked_single_test";
+        let plain = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        let _ = fs::remove_dir_all(root_dir);
+        plain.persist("old", vec![5; 9].into()).await.unwrap();
+        let storage = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: false,
+            chunk_size: Some(4),
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        let data = storage.read("old").await.unwrap().into_vec_u8().await;
+        assert_eq!(data, vec![5; 9]);
+        storage.remove("old").await.unwrap();
+        assert!(dir_entries(root_dir).is_empty());
+
+        // and the other way around, the single file replaces the chunks
+        storage.persist("new", vec![6; 9].into()).await.unwrap();
+        plain.persist("new", vec![6; 2].into()).await.unwrap();
+        assert_eq!(dir_entries(root_dir), vec!["new"]);
+        let data = plain.read("new").await.unwrap().into_vec_u8().await;
+        assert_eq!(data, vec![6; 2]);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_failure() {
+        let root_dir = "cache/chunked_failure_test";
+        let storage = chunked_storage(root_dir, 2);
+        storage.persist("pkg", vec![1; 3].into()).await.unwrap();
+        let entries = dir_entries(root_dir);
+        // the stream fails after several chunks are written
+        let chunks: Vec<Result<Bytes>> = vec![
+            Ok(Bytes::from_static(b"abcde")),
+            Err(Error::OtherError("connection reset".to_string())),
+        ];
+        let data = CacheData::ByteStream(Box::new(stream::iter(chunks)), None);
+        assert!(storage.persist("pkg", data).await.is_err());
+        let result = storage
+            .persist("pkg", byte_stream(vec![b"abcde"], Some(6)))
+            .await;
+        assert!(matches!(result, Err(Error::SizeMismatch(6, 5))));
+        // neither the written chunks nor their temporary files are left, and
+        // the previous object is intact
+        assert_eq!(dir_entries(root_dir), entries);
+        let data = storage.read("pkg").await.unwrap().into_vec_u8().await;
+        assert_eq!(data, vec![1; 3]);
+    }
+
+    #[tokio::test]
+    async fn test_fs_local_path() {
+        let storage = FsBackend {
+            root_dir: "cache/local_path_test".to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        storage.persist("a/b", vec![0; 3].into()).await.unwrap();
+        assert_eq!(storage.local_path("a/b"), Some(("a/b".to_string(), 3)));
+        assert_eq!(storage.local_path("a/missing"), None);
+        assert_eq!(MemBackend::new().local_path("a/b"), None);
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let root_dir = "cache/metadata_test";
+        let _ = fs::remove_dir_all(root_dir);
+        let plain = FsBackend {
+            root_dir: root_dir.to_string(),
+            sharded: true,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        };
+        let before = SystemTime::now() - Duration::from_secs(1);
+        plain.persist("plain", vec![1; 5].into()).await.unwrap();
+        let meta = plain.metadata("plain").await.unwrap();
+        assert_eq!(meta.size, 5);
+        assert!(meta.modified.unwrap() >= before);
+        // the size of an object stored in chunks is the one of the object
+        let chunked = chunked_storage(root_dir, 2);
+        chunked.persist("chunked", vec![2; 5].into()).await.unwrap();
+        assert_eq!(chunked.metadata("chunked").await.unwrap().size, 5);
+        assert!(plain.metadata("missing").await.is_err());
+
+        let mem = MemBackend::new();
+        mem.persist("a", vec![3; 7].into()).await.unwrap();
+        let meta = mem.metadata("a").await.unwrap();
+        assert_eq!((meta.size, meta.modified), (7, None));
+        assert!(mem.metadata("missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_follow_file() {
+        let path = PathBuf::from("cache/follow_file_test");
+        fs::create_dir_all("cache").unwrap();
+        fs::write(&path, vec![1; 3]).unwrap();
+        let (tx, rx) = watch::channel(DownloadProgress::Downloading(3));
+        let follower = tokio::spawn(async move {
+            follow_file(
+                PathBuf::from("cache/follow_file_test"),
+                rx,
+                None,
+                Duration::from_secs(5),
+            )
+            .map(|bytes| bytes.unwrap().to_vec())
+            .concat()
+            .await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[2; 2])
+            .unwrap();
+        tx.send(DownloadProgress::Done).unwrap();
+        assert_eq!(follower.await.unwrap(), vec![1, 1, 1, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_follow_file_writer_failed() {
+        let (tx, rx) = watch::channel(DownloadProgress::Downloading(0));
+        tx.send(DownloadProgress::Failed).unwrap();
+        let mut follower = Box::pin(follow_file(
+            PathBuf::from("cache/follow_file_failed_test"),
+            rx,
+            None,
+            Duration::from_secs(5),
+        ));
+        assert!(follower.next().await.unwrap().is_err());
+        assert!(follower.next().await.is_none());
+    }
+
+    #[test]
+    fn fs_sharded_path() {
+        let path = fs_path("cache", "a/b.tar.xz", true).unwrap();
+        let hash = format!("{:016x}", util::fnv1a_64(b"a/b.tar.xz"));
+        assert_eq!(
+            path,
+            PathBuf::from(format!("cache/{}/{}/a/b.tar.xz", &hash[0..2], &hash[2..4]))
+        );
+        assert_eq!(
+            fs_path("cache", "a/b.tar.xz", false).unwrap(),
+            PathBuf::from("cache/a/b.tar.xz")
+        );
+    }
+
+    #[test]
+    fn encode_file_paths() {
+        let ascii = "pypi/packages/ab/flask-2.0+cu118.tar.gz";
+        assert_eq!(encode_file_path(ascii), ascii);
+        assert_eq!(encode_file_path("a b/100%.txt"), "a%20b/100%25.txt");
+        assert_eq!(encode_file_path("a%20b"), "a%2520b");
+        assert_eq!(
+            encode_file_path("中文/🦀.whl"),
+            "%E4%B8%AD%E6%96%87/%F0%9F%A6%80.whl"
+        );
+        let long = "x".repeat(300);
+        let encoded = encode_file_path(&format!("a/{}", long));
+        let name = encoded.strip_prefix("a/").unwrap();
+        assert_eq!(name.len(), SHORTENED_NAME_PREFIX_LEN + 1 + 64);
+        assert!(name.starts_with(&"x".repeat(SHORTENED_NAME_PREFIX_LEN)));
+        assert_ne!(encode_file_path(&"x".repeat(301)), name);
+        // long encodings of short names are shortened as well
+        assert!(encode_file_path(&"中".repeat(100)).len() <= MAX_FILE_NAME_LEN);
+    }
+
+    #[test]
+    fn encode_ntfs_file_names() {
+        let ntfs = |name| encode_file_path_with(name, true);
+        assert_eq!(ntfs("con"), "%63on");
+        assert_eq!(ntfs("a/CON.tar.gz"), "a/%43ON.tar.gz");
+        assert_eq!(ntfs("Lpt9.whl"), "%4Cpt9.whl");
+        for name in &["console.txt", "com10", "nul-1.0.zip", "xcon"] {
+            assert_eq!(ntfs(name), *name);
+        }
+        assert_eq!(ntfs("a./b.."), "a%2E/b%2E%2E");
+        assert_eq!(ntfs("a.b/./c"), "a.b/./c");
+        assert_eq!(ntfs("c:/x<y>|\"?*\\z"), "c%3A/x%3Cy%3E%7C%22%3F%2A%5Cz");
+        // encoded names stay distinct
+        assert_ne!(ntfs("a."), ntfs("a%2E"));
+        assert_ne!(ntfs("con"), ntfs("%63on"));
+        for name in &["con", "a.", "aux.txt"] {
+            assert_eq!(encode_file_path_with(name, false), *name);
+            let encoded = ntfs(name);
+            assert!(!encoded.ends_with('.'));
+            assert!(!is_windows_device_name(&encoded));
+        }
+    }
+
+    #[test]
+    fn fs_path_separators() {
+        let root = Path::new("cache");
+        // backslashes and drive letters are part of names, not paths
+        for name in &["..\\victim", "c:\\victim", "c:/victim", "a\\..\\..\\b"] {
+            let path = fs_path("cache", name, false).unwrap();
+            assert!(path.starts_with(root), "{}", name);
+            assert!(path
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_))));
+        }
+        assert_eq!(
+            fs_path("cache", "a//./b", false).unwrap(),
+            root.join("a").join("b")
+        );
+        for name in &["..", "a/../../b", "/etc/passwd", ""] {
+            assert!(fs_path("cache", name, false).is_err(), "{}", name);
+        }
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_replace_file_in_use() {
+        use std::os::windows::fs::OpenOptionsExt;
+        let dir = Path::new("cache/replace_file_test");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let (from, to) = (dir.join(".a.part"), dir.join("a"));
+        fs::write(&from, "new").unwrap();
+        fs::write(&to, "old").unwrap();
+        // a reader not sharing the file, for a while
+        let reader = fs::OpenOptions::new()
+            .read(true)
+            .share_mode(0)
+            .open(&to)
+            .unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(reader);
+        });
+        replace_file(&from, &to).await.unwrap();
+        release.await.unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "new");
+        assert!(!from.exists());
+    }
+
+    #[tokio::test]
+    async fn test_fs_non_ascii_names() {
+        let root_dir = "cache/fs_non_ascii_test";
+        let _ = fs::remove_dir_all(root_dir);
+        let long = "长".repeat(300);
+        let names = [
+            "emoji/🦀🦀.whl",
+            "cjk/中文包-1.0.tar.gz",
+            "spaces/my package 1.0.zip",
+            "percent/100%.txt",
+            "percent/100%25.txt",
+            long.as_str(),
+        ];
+        for sharded in &[false, true] {
+            let storage = FsBackend {
+                root_dir: root_dir.to_string(),
+                sharded: *sharded,
+                chunk_size: None,
+                fsync: Default::default(),
+                permissions: FsPermissions::default(),
+            };
+            for name in &names {
+                storage
+                    .persist(name, name.to_string().into())
+                    .await
+                    .unwrap();
+                let path = fs_path(root_dir, name, *sharded).unwrap();
+                assert!(path.exists(), "{}", name);
+                assert!(path.to_str().unwrap().is_ascii());
+            }
+            // names never share a file
+            for name in &names {
+                let data = storage.read(name).await.unwrap().into_vec_u8().await;
+                assert_eq!(data, name.as_bytes());
+            }
+            for name in &names {
+                storage.remove(name).await.unwrap();
+                assert!(storage.read(name).await.is_err(), "{}", name);
+            }
+        }
     }
 
     #[tokio::test]
     async fn test_fs_remove() {
-        let mut storage = Storage::FileSystem {
+        let storage = FsBackend {
             root_dir: "cache/test_fs_remove".to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
         };
-        remove(&mut storage).await;
+        remove(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn test_fs_cache_root() {
+        let storage_root = "cache/fs_cache_root_test";
+        let _ = fs::remove_dir_all(storage_root);
+        let storage = Storage::FileSystem(FsBackend {
+            root_dir: storage_root.to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: FsPermissions::default(),
+        })
+        .for_cache("policy_a", None);
+        storage
+            .persist("a/file", String::from("a").into())
+            .await
+            .unwrap();
+        assert!(Path::new(storage_root).join("policy_a/a/file").exists());
+        // a file of another cache sharing the storage
+        fs::write(Path::new(storage_root).join("victim"), "b").unwrap();
+        for name in &["../victim", "a/../../victim", "/etc/passwd", ""] {
+            assert!(storage.remove(name).await.is_err(), "{}", name);
+            assert!(storage.read(name).await.is_err(), "{}", name);
+        }
+        assert!(Path::new(storage_root).join("victim").exists());
+    }
+
+    #[test]
+    fn clean_stale_temp_files() {
+        let root = Path::new("cache/clean_temp_files_test");
+        let _ = fs::remove_dir_all(root);
+        let stale = [root.join(".a.whl.part"), root.join("ab/cd/.b.whl.part")];
+        let fresh = root.join(".c.whl.part");
+        let object = root.join("d.whl");
+        for path in stale.iter().chain([&fresh, &object]) {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "partial").unwrap();
+        }
+        let two_hours_ago = std::time::SystemTime::now() - Duration::from_secs(7200);
+        for path in stale.iter().chain([&object]) {
+            let f = fs::File::options().write(true).open(path).unwrap();
+            f.set_modified(two_hours_ago).unwrap();
+        }
+        let report = clean_temp_files(root, Duration::from_secs(3600));
+        assert_eq!(
+            report,
+            TempFilesReport {
+                removed: 2,
+                reclaimed_bytes: 14,
+                kept: 1,
+            }
+        );
+        assert!(stale.iter().all(|path| !path.exists()));
+        // a write in progress, and a complete object
+        assert!(fresh.exists() && object.exists());
     }
 
     #[tokio::test]
     async fn test_mem_write_read() {
-        let mut storage = Storage::new_mem();
-        write_read(&mut storage).await;
+        let storage = MemBackend::new();
+        write_read(&storage).await;
     }
 
     #[tokio::test]
     async fn test_mem_remove() {
-        let mut storage = Storage::new_mem();
-        remove(&mut storage).await;
+        let storage = MemBackend::new();
+        remove(&storage).await;
+    }
+
+    async fn read_bytes(storage: &dyn StorageBackend, name: &str) -> Option<Vec<u8>> {
+        Some(storage.read(name).await.ok()?.into_vec_u8().await)
+    }
+
+    /// Wait for a background repair to write `name` to `storage`
+    async fn wait_until_stored(storage: &dyn StorageBackend, name: &str) -> bool {
+        for _ in 0..100 {
+            if storage.read(name).await.is_ok() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_replicated_write_read() {
+        let replica = MemBackend::new();
+        let replicas: Vec<Arc<dyn StorageBackend>> =
+            vec![Arc::new(MemBackend::new()), Arc::new(replica.clone())];
+        let storage = ReplicatedBackend::new(replicas, false);
+        write_read(&storage).await;
+        remove(&storage).await;
+        assert!(read_bytes(&replica, "write_read_test").await.is_some());
+        assert!(read_bytes(&replica, "remove_test").await.is_none());
+        assert_eq!(storage.degraded_entries(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_replicated_fallback() {
+        let (primary, replica) = (MockBackend::new(), MemBackend::new());
+        let replicas: Vec<Arc<dyn StorageBackend>> =
+            vec![Arc::new(primary.clone()), Arc::new(replica.clone())];
+        let storage = ReplicatedBackend::new(replicas, true);
+        storage
+            .persist("tool.tgz", Bytes::from("v1").into())
+            .await
+            .unwrap();
+        primary.fail(StorageOp::Read, true);
+        assert_eq!(read_bytes(&storage, "tool.tgz").await.unwrap(), b"v1");
+        assert!(storage.metadata("tool.tgz").await.is_ok());
+        primary.fail(StorageOp::Read, false);
+
+        // a file lost by the primary is copied back from the replica
+        storage
+            .persist("lost.tgz", Bytes::from("v1").into())
+            .await
+            .unwrap();
+        primary.remove("lost.tgz").await.unwrap();
+        assert_eq!(read_bytes(&storage, "lost.tgz").await.unwrap(), b"v1");
+        assert!(wait_until_stored(&primary, "lost.tgz").await);
+        assert_eq!(read_bytes(&primary, "lost.tgz").await.unwrap(), b"v1");
+
+        // the primary fails the write
+        primary.fail(StorageOp::Persist, true);
+        assert!(storage
+            .persist("tool.tgz", Bytes::from("v2").into())
+            .await
+            .is_err());
+        assert_eq!(read_bytes(&replica, "tool.tgz").await.unwrap(), b"v1");
+    }
+
+    #[tokio::test]
+    async fn test_replicated_partial_write() {
+        let (primary, replica) = (MemBackend::new(), MockBackend::new());
+        let replicas: Vec<Arc<dyn StorageBackend>> =
+            vec![Arc::new(primary.clone()), Arc::new(replica.clone())];
+        let storage = ReplicatedBackend::new(replicas.clone(), false);
+        replica.fail(StorageOp::Persist, true);
+        // usable, but degraded
+        let report = storage
+            .persist("tool.tgz", Bytes::from("v1").into())
+            .await
+            .unwrap();
+        assert_eq!(read_bytes(&storage, "tool.tgz").await.unwrap(), b"v1");
+        assert_eq!(storage.degraded_entries(), Some(1));
+        assert!(read_bytes(&replica, "tool.tgz").await.is_none());
+        replica.fail(StorageOp::Persist, false);
+        // an unchanged write still goes to the replica missing the file
+        let report = storage
+            .persist_changed("tool.tgz", Bytes::from("v1").into(), Some(&report.sha256))
+            .await
+            .unwrap();
+        assert!(report.unchanged);
+        assert_eq!(storage.degraded_entries(), Some(0));
+        assert_eq!(read_bytes(&replica, "tool.tgz").await.unwrap(), b"v1");
+
+        // repaired when it is read
+        let storage = ReplicatedBackend::new(replicas, true);
+        replica.fail(StorageOp::Persist, true);
+        storage
+            .persist("other.tgz", Bytes::from("v1").into())
+            .await
+            .unwrap();
+        assert_eq!(storage.degraded_entries(), Some(1));
+        replica.fail(StorageOp::Persist, false);
+        assert_eq!(read_bytes(&storage, "other.tgz").await.unwrap(), b"v1");
+        assert!(wait_until_stored(&replica, "other.tgz").await);
+        storage.remove("other.tgz").await.unwrap();
+        assert_eq!(storage.degraded_entries(), Some(0));
     }
 }
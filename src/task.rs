@@ -1,36 +1,195 @@
+use crate::admission::AdmissionFilter;
+use crate::audit::{AuditEntry, AuditLog, Outcome};
+use crate::bypass::{BypassStatus, BypassSwitch};
 use crate::cache::{
-    Cache, CacheData, CacheHitMiss, LruCache, RedisMetadataDb, SledMetadataDb, TtlCache,
+    Cache, CacheData, CacheSizeType, EvictedEntry, LruCache, LruEntryStats, LruMetadataStore,
+    MicroCache, NoCache, PurgeReport, RedisMetadataDb, ShardedCache, SledMetadataDb, Sliding,
+    TtlCache,
 };
+use crate::check;
+use crate::classify::KeyClassifier;
+use crate::diskusage::{self, DiskUsageReport, DiskUsageReports};
 use crate::error::Error;
 use crate::error::Result;
+use crate::fetch::{self, FetchOutcome, FetchReport, FetchSpec, FetchedFile};
+use crate::hashes;
+use crate::hotkeys::{HotKey, HotKeys};
+use crate::jobs::{JobId, JobRegistry};
+use crate::keys::SNAPSHOT_ID;
+use crate::listing;
 use crate::metric;
-use crate::settings::Settings;
-use crate::settings::{MetadataDb, Policy, PolicyType, Rewrite};
-use crate::storage::Storage;
-use crate::util;
+use crate::migrate;
+use crate::offline::{OfflineStatus, OfflineSwitch};
+use crate::pagination;
+use crate::pep658;
+use crate::pep691;
+use crate::protect::{self, ProtectionReport, RefreshSchedule, Thresholds};
+use crate::quota::QuotaTracker;
+use crate::redirects::{self, CachedRedirect};
+use crate::rewrite::{self, StreamRewriter};
+use crate::rules::RuleMatcher;
+use crate::scheduler::{Priority, Scheduler};
+use crate::settings::Pagination;
+use crate::settings::{parse_mode, CacheMode, KeyClass, Settings, DEFAULT_BINARY_SUFFIXES};
+use crate::settings::{rule_label, MetadataDb, Policy, PolicyType, ProtectiveRefresh, Rewrite};
+use crate::slowlog::{self, Category, Timer};
+use crate::snapshot::{self, SnapshotInfo, SnapshotManifest, SnapshotRequest, SnapshotStore};
+use crate::storage::{
+    self, DownloadProgress, FsBackend, FsPermissions, MemBackend, ReplicatedBackend, S3Backend,
+    Storage, StorageBackend, TempFilesReport, TieredFsBackend,
+};
+use crate::tenant;
+use crate::upstreams::{self, UpstreamHealth, UpstreamReport};
+use crate::usage::{self, Grouping, UsageReport, UsageReports};
+use crate::util::{self, TextBody};
+use mirror_cache::api::{CacheStats, EntryInfo, EntryPage, PinResult, TaskInfo};
 
 use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::future;
+use futures::SinkExt;
 use futures::Stream;
 use futures::StreamExt;
-use metrics::{histogram, increment_counter};
+use metrics::{counter, decrement_gauge, gauge, histogram, increment_counter, increment_gauge};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument};
 use warp::http::Response;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Task {
     pub rule_id: RuleId,
     pub url: String,
+    /// Cache key of the task, derived from the url if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Tenant whose caches the task is cached in, the shared caches if not
+    /// set, see `Tenant`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// The PEP 691 JSON representation of a PyPI simple index page is
+    /// fetched and cached under its own key, see `pep691`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub simple_json: bool,
 }
 
 pub enum TaskResponse {
     StringResponse(String),
     BytesResponse(Bytes),
     StreamResponse(Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>),
+    /// A cached object sent with its size as `Content-Length`. The stream
+    /// fails if it delivers a different number of bytes.
+    SizedResponse(Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>, u64),
+    /// A single byte range of a cached object: stream, (start, end, total)
+    PartialResponse(
+        Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        (u64, u64, u64),
+    ),
     Redirect(warp::reply::WithHeader<warp::http::StatusCode>),
+    /// Hand off a cached file to a front proxy, e.g. nginx `X-Accel-Redirect`
+    SendfileRedirect {
+        header: String,
+        internal_path: String,
+        size: u64,
+        content_type: Option<String>,
+    },
+    /// An expired cached object, served because the upstream is unavailable
+    StaleResponse(Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>),
+}
+
+/// How the cache is involved in serving a task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheStatus {
+    /// Served from the cache, or from a download in progress
+    Hit,
+    /// Fetched from the upstream to be cached
+    Miss,
+    /// An expired entry is served because the upstream is unavailable
+    Stale,
+    /// The cache is not used, i.e. the `NONE` policy, a `read-only` rule, a
+    /// response over the size limit or an object not admitted to the cache yet
+    Bypass,
+    /// The upstream response is not cached, e.g. it is not `200 OK` or it
+    /// may be personalized
+    Uncacheable,
+    /// Not cached, and the upstream is not contacted in offline mode
+    Offline,
+}
+
+impl CacheStatus {
+    /// Value of the `X-Cache` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Bypass => "BYPASS",
+            CacheStatus::Uncacheable => "UNCACHEABLE",
+            CacheStatus::Offline => "OFFLINE",
+        }
+    }
+}
+
+/// The cache outcome of resolving a task, returned alongside its response
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolveOutcome {
+    pub status: CacheStatus,
+    /// Id of the cache of the task, i.e. the name of the policy of its rule,
+    /// or `<tenant>_<policy>` for a tenant. Empty if the rule is unknown
+    pub cache_id: String,
+    /// Time since the served entry is cached, if known
+    pub age: Option<Duration>,
+    /// How the upstream served the response, if it is another mirror-cache
+    /// instance: its `X-Cache-Hierarchy`, or else its `X-Cache`
+    pub upstream_cache: Option<String>,
+    /// Content-Type of the representation served, overriding the
+    /// `content-type` of the rule, e.g. of the JSON of a PyPI index page
+    pub content_type: Option<&'static str>,
+}
+
+impl ResolveOutcome {
+    /// Tell the client how the cache is involved in serving the request.
+    /// Responses fetched from another mirror-cache instance also carry an
+    /// `X-Cache-Hierarchy` breadcrumb, e.g. `MISS, HIT` for a miss filled by
+    /// a hit of the instance.
+    pub fn set_headers(&self, headers: &mut warp::http::HeaderMap) {
+        use warp::http::HeaderValue;
+        headers.insert("X-Cache", HeaderValue::from_static(self.status.as_str()));
+        if let Ok(cache_id) = HeaderValue::from_str(&self.cache_id) {
+            if !self.cache_id.is_empty() {
+                headers.insert("X-Cache-Id", cache_id);
+            }
+        }
+        if let Some(age) = self.age {
+            headers.insert("X-Cache-Age", HeaderValue::from(age.as_secs()));
+        }
+        if let Some(upstream) = &self.upstream_cache {
+            let hierarchy = format!("{}, {}", self.status.as_str(), upstream);
+            if let Ok(hierarchy) = HeaderValue::from_str(&hierarchy) {
+                headers.insert("X-Cache-Hierarchy", hierarchy);
+            }
+        }
+    }
+}
+
+/// How another mirror-cache instance served an upstream response, see
+/// `ResolveOutcome::upstream_cache`
+fn upstream_cache_status(res: &reqwest::Response) -> Option<String> {
+    let headers = res.headers();
+    headers
+        .get("X-Cache-Hierarchy")
+        .or_else(|| headers.get("X-Cache"))
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
 }
 
 impl From<String> for TaskResponse {
@@ -44,11 +203,26 @@ impl From<CacheData> for TaskResponse {
         match cache_data {
             CacheData::TextData(text) => text.into(),
             CacheData::BytesData(bytes) => TaskResponse::BytesResponse(bytes),
-            CacheData::ByteStream(stream, ..) => TaskResponse::StreamResponse(Box::pin(stream)),
+            CacheData::ByteStream(stream, Some(size)) => {
+                TaskResponse::SizedResponse(Box::pin(exact_size_stream(stream, size, |_| {})), size)
+            }
+            CacheData::ByteStream(stream, None) => TaskResponse::StreamResponse(Box::pin(stream)),
         }
     }
 }
 
+impl From<CachedRedirect> for TaskResponse {
+    fn from(redirect: CachedRedirect) -> TaskResponse {
+        let status = warp::http::StatusCode::from_u16(redirect.status)
+            .unwrap_or(warp::http::StatusCode::FOUND);
+        TaskResponse::Redirect(warp::reply::with_header(
+            status,
+            "Location",
+            redirect.location,
+        ))
+    }
+}
+
 impl warp::Reply for TaskResponse {
     fn into_response(self) -> warp::reply::Response {
         match self {
@@ -60,24 +234,403 @@ impl warp::Reply for TaskResponse {
             TaskResponse::StreamResponse(stream) => {
                 warp::reply::Response::new(warp::hyper::Body::wrap_stream(stream))
             }
+            TaskResponse::SizedResponse(stream, size) => Response::builder()
+                .header("Content-Length", size)
+                .body(warp::hyper::Body::wrap_stream(stream))
+                .unwrap(),
+            TaskResponse::PartialResponse(stream, (start, end, total)) => Response::builder()
+                .status(warp::http::StatusCode::PARTIAL_CONTENT)
+                .header("Accept-Ranges", "bytes")
+                .header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end, total),
+                )
+                .header("Content-Length", end - start + 1)
+                .body(warp::hyper::Body::wrap_stream(stream))
+                .unwrap(),
             TaskResponse::Redirect(r) => r.into_response(),
+            TaskResponse::SendfileRedirect {
+                header,
+                internal_path,
+                size,
+                content_type,
+            } => {
+                trace!("{}: {} ({} bytes)", header, internal_path, size);
+                let mut builder = Response::builder().header(header.as_str(), internal_path);
+                if let Some(content_type) = content_type {
+                    builder = builder.header("Content-Type", content_type);
+                }
+                builder.body(warp::hyper::Body::empty()).unwrap()
+            }
+            TaskResponse::StaleResponse(stream) => Response::builder()
+                .header("Warning", "111 - \"Revalidation Failed\"")
+                .body(warp::hyper::Body::wrap_stream(stream))
+                .unwrap(),
+        }
+    }
+}
+
+impl TaskResponse {
+    /// Serve the inclusive byte range `[start, end]` of a cached object.
+    fn from_range(data: CacheData, start: u64, end: u64, total: u64) -> TaskResponse {
+        let stream = slice_stream(data.into_byte_stream(), start, end);
+        TaskResponse::PartialResponse(Box::pin(stream), (start, end, total))
+    }
+}
+
+/// Fail a byte stream that does not deliver exactly `size` bytes, so that a
+/// body of another size is never sent with a `Content-Length` of `size`.
+/// `on_mismatch` is called with the number of bytes delivered before failing.
+fn exact_size_stream(
+    stream: impl Stream<Item = Result<Bytes>> + Unpin,
+    size: u64,
+    on_mismatch: impl FnOnce(u64),
+) -> impl Stream<Item = Result<Bytes>> {
+    let state = (stream, 0, Some(on_mismatch));
+    futures::stream::unfold(
+        state,
+        move |(mut stream, mut received, mut on_mismatch)| async move {
+            // the stream ends after an error
+            on_mismatch.as_ref()?;
+            match stream.next().await {
+                Some(Ok(bytes)) if received + bytes.len() as u64 <= size => {
+                    received += bytes.len() as u64;
+                    return Some((Ok(bytes), (stream, received, on_mismatch)));
+                }
+                Some(Ok(bytes)) => received += bytes.len() as u64,
+                Some(Err(e)) => return Some((Err(e), (stream, received, None))),
+                None if received == size => return None,
+                None => {}
+            }
+            if let Some(on_mismatch) = on_mismatch.take() {
+                on_mismatch(received);
+            }
+            Some((
+                Err(Error::SizeMismatch(size, received)),
+                (stream, received, None),
+            ))
+        },
+    )
+}
+
+/// Keep only the inclusive byte range `[start, end]` of a byte stream.
+fn slice_stream(
+    stream: impl Stream<Item = Result<Bytes>>,
+    start: u64,
+    end: u64,
+) -> impl Stream<Item = Result<Bytes>> {
+    let mut offset: u64 = 0;
+    stream.filter_map(move |chunk| {
+        let sliced = match chunk {
+            Ok(bytes) => {
+                let chunk_start = offset;
+                let chunk_end = offset + bytes.len() as u64;
+                offset = chunk_end;
+                if chunk_end <= start || chunk_start > end {
+                    None
+                } else {
+                    let from = start.saturating_sub(chunk_start) as usize;
+                    let to =
+                        (std::cmp::min(end.saturating_add(1), chunk_end) - chunk_start) as usize;
+                    Some(Ok(bytes.slice(from..to)))
+                }
+            }
+            Err(e) => Some(Err(e)),
+        };
+        future::ready(sliced)
+    })
+}
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// Stream `follower` until it fails, then fetch the rest from `upstream_url`
+/// with `client`, skipping the bytes already sent.
+fn follow_or_fallback(
+    follower: ByteStream,
+    client: reqwest::Client,
+    upstream_url: String,
+) -> ByteStream {
+    Box::pin(futures::stream::unfold(
+        (follower, 0u64, Some((client, upstream_url))),
+        |(mut stream, sent, fallback)| async move {
+            match stream.next().await {
+                Some(Ok(bytes)) => {
+                    let sent = sent + bytes.len() as u64;
+                    Some((Ok(bytes), (stream, sent, fallback)))
+                }
+                Some(Err(e)) => {
+                    let (client, url) = match fallback {
+                        Some(fallback) => fallback,
+                        None => return Some((Err(e), (stream, sent, None))),
+                    };
+                    warn!(
+                        "[Request] following download failed: {}, fetching from upstream",
+                        e
+                    );
+                    match util::make_request(&client, &url, false).await {
+                        Ok(res) if res.status().is_success() => {
+                            let mut upstream: ByteStream = Box::pin(slice_stream(
+                                res.bytes_stream().map(|x| x.map_err(Error::RequestError)),
+                                sent,
+                                u64::MAX,
+                            ));
+                            let first = upstream.next().await?;
+                            let sent = sent + first.as_ref().map_or(0, |b| b.len() as u64);
+                            Some((first, (upstream, sent, None)))
+                        }
+                        Ok(res) => Some((
+                            Err(Error::UpstreamStatus(res.status().as_u16())),
+                            (stream, sent, None),
+                        )),
+                        Err(e) => Some((Err(e), (stream, sent, None))),
+                    }
+                }
+                None => None,
+            }
+        },
+    ))
+}
+
+/// The body of `res`, resumed up to `max_resumes` times if it is interrupted
+/// by a request of the rest of it, from the last byte received. Only bodies
+/// of responses with a strong `ETag` are resumed, with `If-Range`: the rest
+/// is taken if the upstream answers `206 Partial Content` from that byte, so
+/// that a body is never assembled from two versions of an object. Otherwise,
+/// the body fails, and the object is fetched again from scratch next time.
+fn resumable_body(res: reqwest::Response, resume: Option<Resume>) -> ByteStream {
+    let etag = res
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .map(String::from);
+    let url = res.url().to_string();
+    let body: ByteStream = Box::pin(res.bytes_stream().map(|x| x.map_err(Error::RequestError)));
+    let source = match (resume, etag) {
+        (Some(resume), Some(etag)) => (resume, url, etag),
+        _ => return body,
+    };
+    Box::pin(futures::stream::unfold(
+        (body, 0u64, 0u32, Some(source)),
+        |(mut stream, received, mut resumed, mut source)| async move {
+            loop {
+                match stream.next().await {
+                    Some(Ok(bytes)) => {
+                        let received = received + bytes.len() as u64;
+                        return Some((Ok(bytes), (stream, received, resumed, source)));
+                    }
+                    Some(Err(e)) => {
+                        let (resume, url, etag) = match source.take() {
+                            Some(source) if resumed < source.0.max_resumes => source,
+                            _ => return Some((Err(e), (stream, received, resumed, None))),
+                        };
+                        warn!(
+                            "[TASK] response body interrupted at byte {}: {}",
+                            received, e
+                        );
+                        match fetch_rest(&resume.client, &url, received, &etag).await {
+                            Ok(rest) => {
+                                increment_counter!(metric::CNT_DOWNLOAD_RESUMES);
+                                stream = rest;
+                                resumed += 1;
+                                source = Some((resume, url, etag));
+                            }
+                            Err(e) => return Some((Err(e), (stream, received, resumed, None))),
+                        }
+                    }
+                    None => return None,
+                }
+            }
+        },
+    ))
+}
+
+/// The body of the object at `url` from byte `offset`, if it is still the
+/// one of `etag`
+async fn fetch_rest(
+    client: &reqwest::Client,
+    url: &str,
+    offset: u64,
+    etag: &str,
+) -> Result<ByteStream> {
+    let res = util::make_range_request(client, url, offset, etag).await?;
+    let start = res
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(content_range_start);
+    if res.status() != reqwest::StatusCode::PARTIAL_CONTENT || start != Some(offset) {
+        // the url may hold the token of the rule
+        return Err(Error::UpstreamUnavailable(format!(
+            "response body not resumed from byte {}, the upstream answered {}",
+            offset,
+            res.status()
+        )));
+    }
+    Ok(Box::pin(
+        res.bytes_stream().map(|x| x.map_err(Error::RequestError)),
+    ))
+}
+
+/// The first byte of a `Content-Range` header, e.g. 100 of
+/// `bytes 100-199/200`
+fn content_range_start(range: &str) -> Option<u64> {
+    range
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Remove the entries of `cache` whose keys match `matcher`, scanning the keys
+/// in batches. `report` is called with the number of keys scanned and removed
+/// after each batch.
+async fn purge_matching(
+    cache: &Arc<RwLock<dyn Cache>>,
+    matcher: &Regex,
+    report: impl Fn(u64, u64),
+) -> Result<()> {
+    let mut cursor = String::new();
+    loop {
+        let (keys, next) = cache.read().await.scan_keys(&cursor, PURGE_BATCH_SIZE)?;
+        let mut removed = 0;
+        for key in keys.iter().filter(|key| matcher.is_match(key)) {
+            if cache.read().await.remove(key).await? {
+                removed += 1;
+            }
+        }
+        report(keys.len() as u64, removed);
+        if next.is_empty() {
+            return Ok(());
         }
+        cursor = next;
+        // let requests for the cache in between batches
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Remove all the entries of `cache` in batches, see `Cache::purge_batch`.
+/// The cache is locked for a batch at a time, so that it keeps serving.
+async fn purge_all(cache: &Arc<RwLock<dyn Cache>>) -> Result<PurgeReport> {
+    let mut report = PurgeReport::default();
+    let mut cursor = String::new();
+    loop {
+        let (batch, next) = cache
+            .read()
+            .await
+            .purge_batch(&cursor, PURGE_BATCH_SIZE)
+            .await?;
+        report += batch;
+        if next.is_empty() {
+            return Ok(report);
+        }
+        cursor = next;
+        tokio::task::yield_now().await;
     }
 }
 
 impl Task {
+    /// The media type asked from the upstream, if not the default one
+    pub fn accept(&self) -> Option<&'static str> {
+        if self.simple_json {
+            Some(pep691::JSON_MEDIA_TYPE)
+        } else {
+            None
+        }
+    }
+
     /// create a unique key for the current task
+    ///
+    /// The key is used as a relative storage path, so empty, `.` and `..`
+    /// segments are dropped. Escapes are normalized like request paths, see
+    /// `util::normalize_path`, so `%7E` and `~` make the same key. Deep paths like OSTree objects
+    /// (`objects/ab/cdef....filez`) keep their directory structure.
+    ///
+    /// The key of a task matched by a `path_pattern` rule is made of the rule
+    /// name and the captured groups instead of the url, and the key of a
+    /// task of a conda channel is the request path.
+    ///
+    /// The JSON representation of a PyPI index page is keyed by the key of
+    /// the page followed by `pep691::JSON_KEY_SUFFIX`.
+    ///
+    /// Keys longer than `MAX_KEY_LEN` bytes, or with a segment longer than
+    /// `MAX_KEY_SEGMENT_LEN`, are replaced by a digest of them, see
+    /// `digest_key`, so that clients cannot fill redis with huge keys.
     pub fn to_key(&self) -> String {
-        self.url
-            .replace("http://", "http/")
-            .replace("https://", "https/")
-            .trim_end_matches('/')
-            .to_string()
+        let source = match &self.key {
+            Some(key) => util::normalize_path(key),
+            None => util::normalize_path(
+                &self
+                    .url
+                    .replace("http://", "http/")
+                    .replace("https://", "https/"),
+            ),
+        };
+        let segments: Vec<&str> = source
+            .split('/')
+            .filter(|segment| !segment.is_empty() && *segment != "." && *segment != "..")
+            .collect();
+        let mut key = segments.join("/");
+        if key.len() > MAX_KEY_LEN || segments.iter().any(|s| s.len() > MAX_KEY_SEGMENT_LEN) {
+            key = digest_key(&key);
+        }
+        if self.simple_json {
+            format!("{}{}", key, pep691::JSON_KEY_SUFFIX)
+        } else {
+            key
+        }
+    }
+}
+
+/// The upstream url of a cache key made of a url by `Task::to_key`, e.g.
+/// `https/pypi.org/simple/flask` -> `https://pypi.org/simple/flask`. `None`
+/// for a digest key, whose url is lost.
+fn upstream_url_of_key(key: &str) -> Option<String> {
+    if is_digest_key(key) {
+        return None;
+    }
+    let (scheme, rest) = key.split_once('/')?;
+    match scheme {
+        "http" | "https" => Some(format!("{}://{}", scheme, rest)),
+        _ => None,
+    }
+}
+
+/// The key replacing a key too long: its first `DIGEST_KEY_PREFIX_SEGMENTS`
+/// segments, so that it is still listed and purged with its neighbours, and
+/// the SHA-256 of the whole key, e.g. `https/pypi.org/~sha256-<hex>`. It is at
+/// most a few hundred bytes long, whatever the length of the key.
+fn digest_key(key: &str) -> String {
+    let mut segments: Vec<String> = key
+        .split('/')
+        .take(DIGEST_KEY_PREFIX_SEGMENTS)
+        .take_while(|segment| segment.len() <= MAX_KEY_SEGMENT_LEN)
+        .map(String::from)
+        .collect();
+    segments.push(format!(
+        "{}{:x}",
+        DIGEST_SEGMENT_PREFIX,
+        Sha256::digest(key.as_bytes())
+    ));
+    segments.join("/")
+}
+
+/// Whether `key` is made by `digest_key`, possibly followed by
+/// `pep691::JSON_KEY_SUFFIX`
+fn is_digest_key(key: &str) -> bool {
+    let key = key.strip_suffix(pep691::JSON_KEY_SUFFIX).unwrap_or(key);
+    let last = key.rsplit('/').next().unwrap_or_default();
+    match last.strip_prefix(DIGEST_SEGMENT_PREFIX) {
+        Some(hex) => hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()),
+        None => false,
     }
 }
 
 pub type RuleId = usize;
 
+type TaskSet = Arc<RwLock<HashMap<Task, Instant>>>;
+
 #[derive(Clone)]
 pub struct TaskManager {
     pub config: Settings,
@@ -86,7 +639,176 @@ pub struct TaskManager {
     /// Specifies how to do the upstream rewrite for RuleId.
     /// RuleId -> Vec<Rewrite>
     pub rewrite_map: HashMap<RuleId, Vec<Rewrite>>,
-    task_set: Arc<RwLock<HashSet<Task>>>,
+    /// Limits of concurrent upstream fetches for cache misses.
+    /// RuleId -> Semaphore
+    inflight_map: HashMap<RuleId, Arc<Semaphore>>,
+    inflight_global: Option<Arc<Semaphore>>,
+    /// Tokens of private conda channels.
+    /// RuleId -> token
+    token_map: HashMap<RuleId, String>,
+    /// Clients of upstreams, shared by rules with the same client options.
+    /// RuleId -> client
+    client_map: HashMap<RuleId, reqwest::Client>,
+    /// Admission filters of rules caching objects requested often enough.
+    /// RuleId -> AdmissionFilter
+    admission_map: HashMap<RuleId, Arc<AdmissionFilter>>,
+    /// Classes of the keys of rules with `key_classes`, and the caches of
+    /// their mutable keys.
+    /// RuleId -> KeyClassRoute
+    class_map: HashMap<RuleId, KeyClassRoute>,
+    /// Upstream errors recently returned for rules of NONE policies with a
+    /// micro-cache `negative_ttl`.
+    /// RuleId -> key -> status
+    negative_map: HashMap<RuleId, Arc<MicroCache<u16>>>,
+    /// Usage of the quotas of clients, `None` if quotas are not enabled
+    pub quotas: Option<Arc<QuotaTracker>>,
+    /// Background tasks in progress, and when they were spawned
+    task_set: TaskSet,
+    /// Downloads in progress that concurrent readers can follow.
+    /// cache key -> Download
+    downloads: Arc<RwLock<HashMap<String, Download>>>,
+    /// Audit log of admin operations, `None` if auditing is disabled
+    pub audit: Option<Arc<AuditLog>>,
+    /// Admits background tasks by priority
+    scheduler: Arc<Scheduler>,
+    /// Long-running admin operations, kept across config reloads
+    pub jobs: Arc<JobRegistry>,
+    /// Recently computed usage reports of caches
+    usage_reports: Arc<UsageReports>,
+    /// Offline mode set through the admin API, kept across config reloads
+    offline: Arc<OfflineSwitch>,
+    /// Cache bypass set through the admin API, kept across config reloads
+    bypass: Arc<BypassSwitch>,
+    /// Redis keeping the cache bypass, `None` unless `persist_bypass`
+    bypass_store: Option<redis::Client>,
+    /// Redis of the heartbeats of the LRU caches with redis metadata, `None`
+    /// if there are none
+    heartbeats: Option<redis::Client>,
+    /// Snapshots of index pages, `None` unless `snapshot_storage`
+    snapshots: Option<Arc<SnapshotStore>>,
+    /// Last protective refreshes of caches, kept across config reloads
+    refresh_schedule: Arc<RefreshSchedule>,
+    /// Last walks of the filesystem storages of caches
+    disk_usage: Arc<DiskUsageReports>,
+    /// Caches of tenants, `None` if there are no tenants
+    tenant_caches: Option<Arc<TenantCaches>>,
+    /// Recent fetches of upstreams, kept across config reloads
+    upstream_health: Arc<UpstreamHealth>,
+    /// Rules of the settings, to find the tasks of upstream urls
+    rule_matcher: Arc<RuleMatcher>,
+    /// Keys of the JSON representations of PyPI index pages recently served
+    /// in HTML by their upstream, see `resolve_negotiated`
+    json_unsupported: Arc<MicroCache<()>>,
+}
+
+/// Maximum length of a file name on common filesystems (`NAME_MAX`)
+const MAX_KEY_SEGMENT_LEN: usize = 255;
+
+/// Most bytes of a cache key, longer ones are replaced by a digest of them
+const MAX_KEY_LEN: usize = 1024;
+
+/// Leading segments of a key kept by its digest, e.g. the scheme and host
+const DIGEST_KEY_PREFIX_SEGMENTS: usize = 2;
+
+/// Starts the last segment of a digest key, followed by the hex encoded
+/// SHA-256 of the key it replaces
+const DIGEST_SEGMENT_PREFIX: &str = "~sha256-";
+
+/// Low priority background tasks run only if no high priority task is running
+const DEFAULT_IDLE_THRESHOLD: usize = 1;
+
+/// Number of chunks buffered for a client of a write-through response
+const TEE_BUFFER: usize = 16;
+
+/// Number of keys scanned at a time by a purge job
+const PURGE_BATCH_SIZE: usize = 256;
+
+/// Number of keys scanned at a time while a snapshot is taken
+const SNAPSHOT_BATCH_SIZE: usize = 256;
+
+/// Secs after which a background task still running is assumed to be stuck
+const DEFAULT_MAX_TASK_DURATION: u64 = 3600;
+
+/// Times an interrupted response body is resumed, see
+/// `BackgroundTasks::max_resumes`
+const DEFAULT_MAX_RESUMES: u32 = 3;
+
+/// Secs after which a temporary file of an interrupted write is stale
+const DEFAULT_TEMP_FILE_MAX_AGE: u64 = 3600;
+
+/// How long a reader following a download waits for more bytes
+const FOLLOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a fetch of `POST /admin/fetch` checks whether a task it did not
+/// spawn is done
+const FETCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Secs the root PyPI simple index is cached by a TTL policy. It lists all
+/// projects, and is too large to be fetched as often as project indexes
+const DEFAULT_ROOT_INDEX_TTL: u64 = 86400;
+
+/// Secs a directory listing of a `rewrite_dir_listing` rule is cached by a TTL
+/// policy, so that new files show up soon
+const DEFAULT_DIR_LISTING_TTL: u64 = 300;
+
+/// Most bytes of a response read whole to be rewritten
+const DEFAULT_MAX_REWRITE_BODY_BYTES: u64 = 8 << 20;
+
+/// Headers of a request passed through to the upstream, see
+/// `TaskManager::pass_through`. Others, e.g. `Authorization` of the mirror,
+/// are dropped.
+const PASS_THROUGH_HEADERS: &[&str] = &[
+    "accept",
+    "accept-encoding",
+    "content-encoding",
+    "content-type",
+    "npm-command",
+    "npm-session",
+    "user-agent",
+];
+
+/// A background download being written to the local filesystem
+#[derive(Clone)]
+struct Download {
+    path: PathBuf,
+    expected_size: Option<u64>,
+    progress: watch::Receiver<DownloadProgress>,
+}
+
+/// Classes of the keys of a rule, and the cache of its mutable keys if they
+/// are not cached by the rule's policy
+#[derive(Clone)]
+struct KeyClassRoute {
+    classifier: Arc<KeyClassifier>,
+    mutable_cache: Option<Arc<RwLock<dyn Cache>>>,
+}
+
+/// Caches of tenants, created on the first request of a tenant for them from
+/// the policies, see `Policy::for_tenant`
+struct TenantCaches {
+    storages: HashMap<String, Arc<Storage>>,
+    redis_client: redis::Client,
+    sled_metadata_path: String,
+    /// cache id -> cache
+    caches: Mutex<HashMap<String, Arc<RwLock<dyn Cache>>>>,
+}
+
+/// Permits of an in-flight upstream fetch for a cache miss, released on drop.
+struct InflightPermit {
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+impl InflightPermit {
+    fn new(permits: Vec<OwnedSemaphorePermit>) -> Self {
+        increment_gauge!(metric::GAUGE_INFLIGHT_REQ, 1.0);
+        Self { _permits: permits }
+    }
+}
+
+impl Drop for InflightPermit {
+    fn drop(&mut self) {
+        decrement_gauge!(metric::GAUGE_INFLIGHT_REQ, 1.0);
+    }
 }
 
 impl TaskManager {
@@ -94,8 +816,35 @@ impl TaskManager {
         TaskManager {
             config,
             rule_map: HashMap::new(),
-            task_set: Arc::new(RwLock::new(HashSet::new())),
+            task_set: Arc::new(RwLock::new(HashMap::new())),
             rewrite_map: HashMap::new(),
+            inflight_map: HashMap::new(),
+            inflight_global: None,
+            token_map: HashMap::new(),
+            client_map: HashMap::new(),
+            admission_map: HashMap::new(),
+            class_map: HashMap::new(),
+            negative_map: HashMap::new(),
+            quotas: None,
+            downloads: Arc::new(RwLock::new(HashMap::new())),
+            audit: None,
+            scheduler: Arc::new(Scheduler::new(usize::MAX, DEFAULT_IDLE_THRESHOLD)),
+            jobs: Arc::new(JobRegistry::new()),
+            usage_reports: Arc::new(UsageReports::new()),
+            offline: Arc::new(OfflineSwitch::new()),
+            bypass: Arc::new(BypassSwitch::new()),
+            bypass_store: None,
+            heartbeats: None,
+            snapshots: None,
+            refresh_schedule: Arc::new(RefreshSchedule::new()),
+            disk_usage: Arc::new(DiskUsageReports::new()),
+            tenant_caches: None,
+            upstream_health: Arc::new(UpstreamHealth::new()),
+            rule_matcher: Arc::new(RuleMatcher::empty()),
+            json_unsupported: Arc::new(MicroCache::new(
+                pep691::JSON_UNSUPPORTED_TTL,
+                pep691::JSON_UNSUPPORTED_MAX_ENTRIES,
+            )),
         }
     }
 
@@ -103,88 +852,568 @@ impl TaskManager {
         Self {
             config: Settings::default(),
             rule_map: HashMap::new(),
-            task_set: Arc::new(RwLock::new(HashSet::new())),
+            task_set: Arc::new(RwLock::new(HashMap::new())),
             rewrite_map: HashMap::new(),
+            inflight_map: HashMap::new(),
+            inflight_global: None,
+            token_map: HashMap::new(),
+            client_map: HashMap::new(),
+            admission_map: HashMap::new(),
+            class_map: HashMap::new(),
+            negative_map: HashMap::new(),
+            quotas: None,
+            downloads: Arc::new(RwLock::new(HashMap::new())),
+            audit: None,
+            scheduler: Arc::new(Scheduler::new(usize::MAX, DEFAULT_IDLE_THRESHOLD)),
+            jobs: Arc::new(JobRegistry::new()),
+            usage_reports: Arc::new(UsageReports::new()),
+            offline: Arc::new(OfflineSwitch::new()),
+            bypass: Arc::new(BypassSwitch::new()),
+            bypass_store: None,
+            heartbeats: None,
+            snapshots: None,
+            refresh_schedule: Arc::new(RefreshSchedule::new()),
+            disk_usage: Arc::new(DiskUsageReports::new()),
+            tenant_caches: None,
+            upstream_health: Arc::new(UpstreamHealth::new()),
+            rule_matcher: Arc::new(RuleMatcher::empty()),
+            json_unsupported: Arc::new(MicroCache::new(
+                pep691::JSON_UNSUPPORTED_TTL,
+                pep691::JSON_UNSUPPORTED_MAX_ENTRIES,
+            )),
         }
     }
 
-    pub async fn resolve_task(&self, task: &Task) -> (Result<TaskResponse>, CacheHitMiss) {
-        // try get from cache
-        let mut cache_result = None;
+    pub async fn resolve_task(
+        &self,
+        task: &Task,
+        range: Option<&str>,
+    ) -> (Result<TaskResponse>, ResolveOutcome) {
+        self.resolve_negotiated(task, range, false).await
+    }
+
+    /// Like `resolve_task`, but the cache is not read: the file is fetched
+    /// from the upstream and replaces the cached one, see `ForceRefresh`. A
+    /// download in progress is followed, as it is fresh.
+    pub async fn refresh_task(&self, task: &Task) -> (Result<TaskResponse>, ResolveOutcome) {
+        increment_counter!(metric::CNT_FORCED_REFRESHES, "rule" => self.rule_label(task));
+        self.resolve_negotiated(task, None, true).await
+    }
+
+    /// Resolve a task, or the HTML representation of a PyPI index page in
+    /// place of the JSON one if its upstream does not serve JSON. That is
+    /// remembered for `JSON_UNSUPPORTED_TTL`, during which the HTML one is
+    /// served without asking the upstream for JSON again.
+    async fn resolve_negotiated(
+        &self,
+        task: &Task,
+        range: Option<&str>,
+        refresh: bool,
+    ) -> (Result<TaskResponse>, ResolveOutcome) {
+        if !task.simple_json {
+            return self.resolve(task, range, refresh).await;
+        }
+        if self.json_unsupported.get(&task.to_key()).is_none() {
+            match self.resolve(task, range, refresh).await {
+                (Err(Error::NotAcceptable), _) => {}
+                resolved => return resolved,
+            }
+        }
+        let html = Task {
+            simple_json: false,
+            ..task.clone()
+        };
+        self.resolve(&html, range, refresh).await
+    }
+
+    /// Pass a request of one of `PASS_THROUGH_METHODS` through to the upstream
+    /// of its rule, with its headers among `PASS_THROUGH_HEADERS`. The cache
+    /// is left untouched, the response of the upstream is returned as is.
+    pub async fn pass_through(
+        &self,
+        task: &Task,
+        method: &str,
+        headers: &warp::http::HeaderMap,
+        body: Bytes,
+    ) -> Result<reqwest::Response> {
+        if self.is_offline(task.rule_id) {
+            return Err(Error::OfflineMiss(self.offline_miss_status()));
+        }
+        let method = reqwest::Method::from_bytes(method.as_bytes())
+            .map_err(|e| Error::BadRequest(e.to_string()))?;
+        info!("[Request] [PASS] {} {:?}", method, task);
+        let url = self.resolve_task_upstream(task);
+        if let Some(rest) = self.upstream_health.cooldown(&url) {
+            info!("[Request] {:?} deferred: upstream rate limiting", task);
+            return Err(Error::UpstreamThrottled(upstreams::retry_after_secs(rest)));
+        }
+        let mut req = self.upstream_client(task).request(method, &url).body(body);
+        for name in PASS_THROUGH_HEADERS {
+            for value in headers.get_all(*name) {
+                req = req.header(*name, value.clone());
+            }
+        }
+        let timer = Timer::start(Category::Upstream, "pass_through");
+        let resp = util::send_request(req).await;
+        let latency = timer.finish(&task.to_key(), None);
+        self.upstream_health.record_fetch(&url, latency, &resp);
+        resp
+    }
+
+    #[tracing::instrument(
+        name = "resolve",
+        skip_all,
+        fields(key = %task.to_key(), cache_id = %self.cache_id(task))
+    )]
+    async fn resolve(
+        &self,
+        task: &Task,
+        range: Option<&str>,
+        refresh: bool,
+    ) -> (Result<TaskResponse>, ResolveOutcome) {
         let key = task.to_key();
+        // nothing is read from the caches while they are bypassed
+        let bypassed = self.is_bypassed(task.rule_id);
+        let refresh = refresh || bypassed;
+
+        // follow a download in progress instead of fetching upstream again
+        let download = if bypassed {
+            None
+        } else {
+            self.downloads.read().await.get(&key).cloned()
+        };
+        if let Some(download) = download {
+            info!("[Request] [FOLLOW] {:?}", &task);
+            let follower = storage::follow_file(
+                download.path,
+                download.progress,
+                download.expected_size,
+                FOLLOW_TIMEOUT,
+            );
+            return (
+                Ok(TaskResponse::StreamResponse(follow_or_fallback(
+                    Box::pin(follower),
+                    self.upstream_client(task),
+                    self.resolve_task_upstream(task),
+                ))),
+                self.outcome(task, CacheStatus::Hit),
+            );
+        }
 
-        if let Some(bytes) = self.get(task, &key).await {
-            cache_result = Some(bytes);
+        if let Some(sendfile) = self.config.sendfile.as_ref().filter(|_| !refresh) {
+            if let Some((path, size)) = self.get_local_path(task, &key).await {
+                info!("[Request] [HIT] {:?} ({})", &task, &sendfile.header);
+                let outcome = self.hit_outcome(task, &key, CacheStatus::Hit).await;
+                self.revalidate_if_old(task, outcome.age).await;
+                return (
+                    Ok(TaskResponse::SendfileRedirect {
+                        header: sendfile.header.clone(),
+                        internal_path: format!(
+                            "{}/{}",
+                            sendfile.location.trim_end_matches('/'),
+                            path
+                        ),
+                        size,
+                        content_type: self
+                            .config
+                            .rules
+                            .get(task.rule_id)
+                            .and_then(|rule| rule.options.as_ref())
+                            .and_then(|options| options.content_type.clone()),
+                    }),
+                    outcome,
+                );
+            }
         }
+
+        // try get from cache
+        let cache_result = if refresh {
+            None
+        } else {
+            self.get(task, &key).await
+        };
         if let Some(data) = cache_result {
             info!("[Request] [HIT] {:?}", &task);
-            return (Ok(data.into()), CacheHitMiss::Hit);
+            let outcome = self.hit_outcome(task, &key, CacheStatus::Hit).await;
+            self.revalidate_if_old(task, outcome.age).await;
+            let total = match &data {
+                CacheData::ByteStream(_, Some(total)) => Some(*total),
+                _ => None,
+            };
+            if let (Some(range), Some(total)) = (range, total) {
+                if let Some((start, end)) = util::parse_range(range, total) {
+                    return (
+                        Ok(TaskResponse::from_range(data, start, end, total)),
+                        outcome,
+                    );
+                }
+            }
+            return (Ok(self.hit_response(task, &key, data)), outcome);
+        }
+        let redirect = if refresh {
+            None
+        } else {
+            self.cached_redirect(task, &key).await
+        };
+        if let Some(redirect) = redirect {
+            info!(
+                "[Request] [HIT] {:?}: redirect to {}",
+                &task, &redirect.location
+            );
+            let redirect_key = redirects::redirect_key(&key);
+            let outcome = self
+                .hit_outcome(task, &redirect_key, CacheStatus::Hit)
+                .await;
+            return (Ok(redirect.into()), outcome);
+        }
+        if self.is_offline(task.rule_id) {
+            // expired entries are better than nothing without an upstream
+            if let Some(data) = self.get_stale(task, &key).await {
+                info!("[Request] [OFFLINE] [STALE] {:?}", &task);
+                return (
+                    Ok(data.into()),
+                    self.hit_outcome(task, &key, CacheStatus::Stale).await,
+                );
+            }
+            info!("[Request] [OFFLINE] {:?}", &task);
+            return (
+                Err(Error::OfflineMiss(self.offline_miss_status())),
+                self.outcome(task, CacheStatus::Offline),
+            );
+        }
+        let negative = if refresh {
+            None
+        } else {
+            self.negative_hit(task, &key)
+        };
+        if let Some(status) = negative {
+            info!("[Request] [HIT] {:?}: upstream error {}", &task, status);
+            return (
+                Err(Error::UpstreamStatus(status)),
+                self.outcome(task, CacheStatus::Hit),
+            );
         }
         increment_counter!(metric::COUNTER_CACHE_MISS);
         // cache miss
+        // shed the request if there are too many in-flight upstream fetches
+        let permit = match self.try_acquire_inflight(task) {
+            Some(permit) => permit,
+            None => {
+                warn!(
+                    "[Request] {:?} rejected: too many in-flight requests",
+                    &task
+                );
+                return (
+                    Err(Error::Overloaded),
+                    self.outcome(task, CacheStatus::Miss),
+                );
+            }
+        };
         // fetch from upstream
         let remote_url = self.resolve_task_upstream(task);
+        if let Some(rest) = self.upstream_health.cooldown(&remote_url) {
+            return self.throttled(task, &key, rest, bypassed).await;
+        }
+        let upstream = self.redact(task, &remote_url);
         info!(
-            "[Request] [MISS] {:?}, fetching from upstream: {}",
-            &task, &remote_url
+            %upstream,
+            "[Request] [{}] {:?}, fetching from upstream: {}",
+            if bypassed {
+                "BYPASS"
+            } else if refresh {
+                "REFRESH"
+            } else {
+                "MISS"
+            },
+            &task,
+            upstream
         );
-        let resp = util::make_request(&remote_url, false).await;
+        let timer = Timer::start(Category::Upstream, "fetch");
+        let client = self.upstream_client(task);
+        let resp = util::make_negotiated_request(&client, &remote_url, task.accept())
+            .instrument(info_span!("upstream", %upstream))
+            .await;
+        let latency = timer.finish(
+            &key,
+            resp.as_ref().ok().and_then(|res| res.content_length()),
+        );
+        self.upstream_health
+            .record_fetch(&remote_url, latency, &resp);
         match resp {
             Ok(res) => {
+                let upstream_cache = upstream_cache_status(&res);
+                let upstream_outcome = |status| ResolveOutcome {
+                    upstream_cache: upstream_cache.clone(),
+                    ..self.outcome(task, status)
+                };
+                if task.simple_json
+                    && (res.status() == reqwest::StatusCode::NOT_ACCEPTABLE
+                        || (res.status().is_success() && !pep691::is_json_response(&res)))
+                {
+                    info!("[Request] {:?}: the upstream does not serve JSON", &task);
+                    self.json_unsupported.put(&key, ());
+                    return (
+                        Err(Error::NotAcceptable),
+                        upstream_outcome(CacheStatus::Uncacheable),
+                    );
+                }
+                let dir_listing = self.dir_listing_ttl(task).is_some();
+                if dir_listing && listing::is_trailing_slash_redirect(&remote_url, &res) {
+                    // the listing is cached and served where its relative links hold
+                    return (
+                        Ok(TaskResponse::Redirect(warp::reply::with_header(
+                            warp::http::StatusCode::MOVED_PERMANENTLY,
+                            "Location",
+                            listing::trailing_slash_location(&task.url),
+                        ))),
+                        upstream_outcome(CacheStatus::Bypass),
+                    );
+                }
+                if let Some(redirect) = self.upstream_redirect(task, &remote_url, &res) {
+                    let status = if self.cache_mode(task) == CacheMode::ReadOnly
+                        || self.is_no_cache(task)
+                        || bypassed
+                    {
+                        CacheStatus::Bypass
+                    } else {
+                        self.cache_redirect(task, &key, &redirect).await;
+                        CacheStatus::Miss
+                    };
+                    return (Ok(redirect.into()), upstream_outcome(status));
+                }
+                if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    // the cooldown started when the fetch was recorded
+                    let rest = self.upstream_health.cooldown(&remote_url);
+                    return self
+                        .throttled(task, &key, rest.unwrap_or_default(), bypassed)
+                        .await;
+                }
                 if !res.status().is_success() {
-                    return (Err(Error::UpstreamRequestError(res)), CacheHitMiss::Miss);
+                    let negative = self.negative_map.get(&task.rule_id).filter(|_| !bypassed);
+                    if let Some(negative) = negative {
+                        let status = res.status();
+                        if status.is_client_error() || status.is_server_error() {
+                            negative.put(&key, status.as_u16());
+                        }
+                    }
+                    return (
+                        Err(Error::UpstreamRequestError(res)),
+                        upstream_outcome(CacheStatus::Uncacheable),
+                    );
                 }
                 // if the response is too large, respond users with a redirect to upstream
                 if let Some(content_length) = res.content_length() {
                     let size_limit = self.get_task_size_limit(task);
                     if size_limit != 0 && size_limit < content_length as usize {
+                        if self.token_map.contains_key(&task.rule_id) {
+                            // never redirect clients to an upstream url with a token
+                            return (
+                                Ok(TaskResponse::StreamResponse(Box::pin(
+                                    res.bytes_stream()
+                                        .map(move |x| x.map_err(Error::RequestError)),
+                                ))),
+                                upstream_outcome(CacheStatus::Bypass),
+                            );
+                        }
                         return (
                             Ok(TaskResponse::Redirect(warp::reply::with_header(
                                 warp::http::StatusCode::FOUND,
                                 "Location",
                                 remote_url,
                             ))),
-                            CacheHitMiss::Miss,
+                            upstream_outcome(CacheStatus::Bypass),
                         );
                     }
                 }
-                // dispatch async cache task
-                let _ = self.spawn_task(task.clone()).await;
-                let rule_id = task.rule_id;
-                if let Some(rewrite_rules) = self.rewrite_map.get(&rule_id) {
-                    let text = res.text().await.unwrap();
-                    let content = Self::rewrite_upstream(text, rewrite_rules);
-                    (Ok(content.into()), CacheHitMiss::Miss)
+                let cache_mode = self.cache_mode(task);
+                let cacheable = res.status() == warp::http::StatusCode::OK
+                    && cache_mode != CacheMode::ReadOnly
+                    && self.is_cacheable(task, &res);
+                let mut status =
+                    if cache_mode == CacheMode::ReadOnly || self.is_no_cache(task) || bypassed {
+                        CacheStatus::Bypass
+                    } else if cacheable && !(self.is_binary_package(task) && is_html_response(&res))
+                    {
+                        CacheStatus::Miss
+                    } else {
+                        CacheStatus::Uncacheable
+                    };
+                let admitted = !cacheable || bypassed || self.admit(task, &key);
+                if !admitted && status == CacheStatus::Miss {
+                    // served pass-through until the object is requested often enough
+                    status = CacheStatus::Bypass;
+                }
+                let outcome = upstream_outcome(status);
+                let no_cache = self.is_no_cache(task);
+                let paginated = self
+                    .pagination(task)
+                    .filter(|_| pagination::next_link(&res).is_some());
+                if no_cache
+                    && cacheable
+                    && !bypassed
+                    && self.has_micro_cache(task)
+                    && paginated.is_none()
+                {
+                    // kept in memory while it is streamed to the client
+                    let rule = self.rule_label(task);
+                    increment_counter!(metric::CNT_PASSTHROUGH_PUTS, "rule" => rule);
+                    return (self.write_through(task, &key, res, permit).await, outcome);
+                }
+                if cache_mode == CacheMode::WriteThrough
+                    && status == CacheStatus::Miss
+                    && paginated.is_none()
+                {
+                    return (self.write_through(task, &key, res, permit).await, outcome);
+                }
+                // dispatch async cache task, only complete responses are cached.
+                // Paginated documents are merged in the background whatever the mode.
+                if (cache_mode == CacheMode::WriteBack || paginated.is_some())
+                    && cacheable
+                    && admitted
+                    && !no_cache
+                    && !bypassed
+                {
+                    self.spawn_task(task.clone(), Priority::High).await;
+                }
+                if let Some(paginate) = paginated {
+                    return (self.serve_pages(task, res, &paginate).await, outcome);
+                }
+                let hash_source = self.hash_source(task);
+                if (dir_listing || hash_source.is_some()) && is_html_response(&res) {
+                    let page = res.url().clone();
+                    let rewrites = self.rewrites(task);
+                    return match self.response_text(task, res).await {
+                        Ok(TextBody::Text(text)) => {
+                            let text = match &hash_source {
+                                Some(files) => append_file_hashes(text, &page, files).await,
+                                None => text,
+                            };
+                            let (content, _) =
+                                rewrite_page(text, &page, rewrites.as_deref(), dir_listing);
+                            (Ok(content.into()), outcome)
+                        }
+                        Ok(TextBody::TooLarge(body, _)) => {
+                            (Ok(pass_through(body, permit)), outcome)
+                        }
+                        Err(e) => (Err(e), outcome),
+                    };
+                }
+                if let Some(rewrites) = self.rewrites(task) {
+                    if let Some(rewriter) = StreamRewriter::for_response(&res, &rewrites) {
+                        let chunks = Box::pin(res.bytes_stream().map(move |x| {
+                            let _ = &permit;
+                            x.map_err(Error::RequestError)
+                        }));
+                        return (
+                            Ok(TaskResponse::StreamResponse(Box::pin(
+                                rewrite::rewrite_stream(chunks, rewriter),
+                            ))),
+                            outcome,
+                        );
+                    }
+                    match self.response_text(task, res).await {
+                        Ok(TextBody::Text(text)) => {
+                            let content = Self::rewrite_upstream(text, &rewrites);
+                            (Ok(content.into()), outcome)
+                        }
+                        Ok(TextBody::TooLarge(body, _)) => {
+                            (Ok(pass_through(body, permit)), outcome)
+                        }
+                        Err(e) => (Err(e), outcome),
+                    }
                 } else {
+                    // hold the permit until the response is streamed to the client
                     (
                         Ok(TaskResponse::StreamResponse(Box::pin(
-                            res.bytes_stream()
-                                .map(move |x| x.map_err(Error::RequestError)),
+                            res.bytes_stream().map(move |x| {
+                                let _ = &permit;
+                                x.map_err(Error::RequestError)
+                            }),
                         ))),
-                        CacheHitMiss::Miss,
+                        outcome,
                     )
                 }
             }
             Err(e) => {
-                error!("[Request] {:?} failed to fetch upstream: {}", &task, e);
-                (Err(e), CacheHitMiss::Miss)
+                // the error message may contain the url with the token
+                let message = self.redact(task, &e.to_string());
+                error!(
+                    "[Request] {:?} failed to fetch upstream: {}",
+                    &task, message
+                );
+                let stale = if bypassed {
+                    None
+                } else {
+                    self.get_stale(task, &key).await
+                };
+                if let Some(data) = stale {
+                    warn!("[Request] [STALE] {:?}", &task);
+                    // retry when live cache fills are done
+                    self.spawn_task(task.clone(), Priority::Low).await;
+                    return (
+                        Ok(TaskResponse::StaleResponse(Box::pin(
+                            data.into_byte_stream(),
+                        ))),
+                        self.hit_outcome(task, &key, CacheStatus::Stale).await,
+                    );
+                }
+                let outcome = self.outcome(task, CacheStatus::Miss);
+                match e {
+                    Error::UpstreamTimeout(_) => (Err(e), outcome),
+                    _ => (Err(Error::UpstreamUnavailable(message)), outcome),
+                }
             }
         }
     }
 
+    /// Serve a stale entry of `key` while the upstream of `task` cools down
+    /// for `rest`, or else tell the client to retry once it is over, see
+    /// `UpstreamHealth::cooldown`
+    async fn throttled(
+        &self,
+        task: &Task,
+        key: &str,
+        rest: Duration,
+        bypassed: bool,
+    ) -> (Result<TaskResponse>, ResolveOutcome) {
+        let stale = if bypassed {
+            None
+        } else {
+            self.get_stale(task, key).await
+        };
+        if let Some(data) = stale {
+            warn!("[Request] [STALE] {:?}: upstream rate limiting", &task);
+            let stream = Box::pin(data.into_byte_stream());
+            return (
+                Ok(TaskResponse::StaleResponse(stream)),
+                self.hit_outcome(task, key, CacheStatus::Stale).await,
+            );
+        }
+        info!("[Request] {:?} deferred: upstream rate limiting", &task);
+        let retry_after = upstreams::retry_after_secs(rest).max(1);
+        (
+            Err(Error::UpstreamThrottled(retry_after)),
+            self.outcome(task, CacheStatus::Miss),
+        )
+    }
+
     /// for each rule, create associated cache if the policy has not been created
     pub fn refresh_config(&mut self, settings: &Settings) {
         let app_settings = settings;
         let redis_url = app_settings.get_redis_url();
         let policies = app_settings.policies.clone();
 
-        let mut tm = self;
+        let tm = self;
         tm.config = app_settings.clone();
 
         let mut policy_map: HashSet<String> = HashSet::new(); // used to avoid create duplicated cache if some rules share the same policy
                                                               // get active policy set
         for rule in &app_settings.rules {
             policy_map.insert(rule.policy.clone());
+            let classes = rule.key_classes.as_ref();
+            if let Some(policy) = classes.and_then(|c| c.mutable_policy.as_ref()) {
+                policy_map.insert(policy.clone());
+            }
         }
 
         // Create storages
@@ -193,12 +1422,65 @@ impl TaskManager {
             let storage = Self::create_storage(storage_config);
             storage_map.insert(storage_config.name.clone(), Arc::new(storage));
         }
+        let snapshot_storage = app_settings
+            .snapshot_storage
+            .as_ref()
+            .and_then(|name| storage_map.get(name))
+            .map(|storage| storage.for_cache(SNAPSHOT_ID, None));
 
         // Clear cache here, so that previous cache objects can be dropped
         tm.rule_map.clear();
         tm.rewrite_map.clear();
+        tm.inflight_map.clear();
+        tm.token_map.clear();
+        tm.client_map.clear();
+        tm.admission_map.clear();
+        tm.class_map.clear();
+        tm.negative_map.clear();
+        tm.inflight_global = app_settings
+            .max_inflight_requests
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        tm.audit = AuditLog::from_settings(app_settings.audit.as_ref()).map(Arc::new);
+        // patterns are checked when settings are loaded
+        tm.rule_matcher = Arc::new(RuleMatcher::new(&app_settings.rules).unwrap());
+        slowlog::configure(app_settings.slow_log.as_ref());
+        let background_tasks = app_settings.background_tasks.as_ref();
+        tm.scheduler = Arc::new(Scheduler::new(
+            background_tasks
+                .and_then(|b| b.max_concurrent)
+                .unwrap_or(usize::MAX),
+            background_tasks
+                .and_then(|b| b.idle_threshold)
+                .unwrap_or(DEFAULT_IDLE_THRESHOLD),
+        ));
         let mut cache_map: HashMap<String, _> = HashMap::new();
         let redis_client = redis::Client::open(redis_url).expect("failed to connect to redis");
+        tm.quotas = app_settings
+            .quotas
+            .as_ref()
+            .map(|quotas| Arc::new(QuotaTracker::from_settings(quotas, redis_client.clone())));
+        tm.bypass_store = if app_settings.persist_bypass.unwrap_or(false) {
+            Some(redis_client.clone())
+        } else {
+            None
+        };
+        tm.heartbeats = if app_settings.redis_lru_cache_ids().is_empty() {
+            None
+        } else {
+            Some(redis_client.clone())
+        };
+        tm.snapshots = snapshot_storage
+            .map(|storage| Arc::new(SnapshotStore::new(redis_client.clone(), storage)));
+        for policy in &policies {
+            let ignored = policy.ignored_options();
+            if !ignored.is_empty() {
+                warn!(
+                    "policy {} is NONE and caches nothing, ignoring {}",
+                    policy.name,
+                    ignored.join(", ")
+                );
+            }
+        }
         // create cache for each policy
         for policy in &policy_map {
             let cache = Self::create_cache_from_rule(
@@ -210,7 +1492,19 @@ impl TaskManager {
             );
             cache_map.insert(policy.to_string(), cache.unwrap());
         }
+        // the caches of tenants are created again on their next use
+        tm.tenant_caches = if app_settings.tenants().is_empty() {
+            None
+        } else {
+            Some(Arc::new(TenantCaches {
+                storages: storage_map.clone(),
+                redis_client: redis_client.clone(),
+                sled_metadata_path: app_settings.sled.metadata_path.clone(),
+                caches: Mutex::new(HashMap::new()),
+            }))
+        };
 
+        let mut clients: HashMap<util::ClientConfig, reqwest::Client> = HashMap::new();
         for (idx, rule) in app_settings.rules.iter().enumerate() {
             debug!("creating rule #{}: {:?}", idx, rule);
             let cache = cache_map.get(&rule.policy).unwrap().clone();
@@ -223,22 +1517,159 @@ impl TaskManager {
                         .map_or(0, |x| bytefmt::parse(x).unwrap() as usize),
                 ),
             );
-            if let Some(rewrite) = rule.rewrite.clone() {
+            if let Some(rewrite) = rule.rewrites() {
                 tm.rewrite_map.insert(idx, rewrite);
             }
+            if let Some(limit) = rule.max_inflight {
+                tm.inflight_map.insert(idx, Arc::new(Semaphore::new(limit)));
+            }
+            if let Some(token) = rule.options.as_ref().and_then(|o| o.conda_token.as_ref()) {
+                tm.token_map.insert(idx, token.expose().clone());
+            }
+            if let Some(admission) = &rule.admission {
+                tm.admission_map
+                    .insert(idx, Arc::new(AdmissionFilter::from_settings(admission)));
+            }
+            if let Some(classes) = &rule.key_classes {
+                let route = KeyClassRoute {
+                    classifier: Arc::new(KeyClassifier::from_settings(classes)),
+                    mutable_cache: classes
+                        .mutable_policy
+                        .as_ref()
+                        .map(|policy| cache_map.get(policy).unwrap().clone()),
+                };
+                tm.class_map.insert(idx, route);
+            }
+            let micro_cache = policies
+                .iter()
+                .find(|p| p.name == rule.policy)
+                .and_then(|p| p.micro_cache.as_ref());
+            if let Some((ttl, micro_cache)) =
+                micro_cache.and_then(|m| m.negative_ttl.map(|ttl| (ttl, m)))
+            {
+                let negative = MicroCache::new(Duration::from_secs(ttl), micro_cache.max_entries());
+                tm.negative_map.insert(idx, Arc::new(negative));
+            }
+            let client_config = tm.client_config(idx);
+            let client = match clients.get(&client_config) {
+                Some(client) => client.clone(),
+                None => {
+                    // client options are checked when settings are loaded
+                    let client = util::build_client(&client_config).unwrap();
+                    clients.insert(client_config, client.clone());
+                    client
+                }
+            };
+            tm.client_map.insert(idx, client);
+        }
+    }
+
+    /// Remove temporary files of writes interrupted e.g. by a crash from the
+    /// filesystem storages of all caches. Files modified within
+    /// `temp_file_max_age` may be written by another instance sharing the
+    /// storage, and are kept.
+    pub fn clean_temp_files(&self) -> TempFilesReport {
+        let max_age = Duration::from_secs(
+            self.config
+                .temp_file_max_age
+                .unwrap_or(DEFAULT_TEMP_FILE_MAX_AGE),
+        );
+        let mut report = TempFilesReport::default();
+        // the directories are checked when settings are loaded
+        for (cache, dir) in self.config.cache_dirs().unwrap_or_default() {
+            let dir_report = storage::clean_temp_files(Path::new(&dir), max_age);
+            if dir_report.removed > 0 {
+                info!(
+                    "removed {} stale temporary files of cache {} in {}, {} bytes reclaimed",
+                    dir_report.removed, cache, dir, dir_report.reclaimed_bytes
+                );
+            }
+            report.add(&dir_report);
         }
+        counter!(metric::CNT_TEMP_FILES_REMOVED, report.removed);
+        counter!(metric::CNT_TEMP_BYTES_RECLAIMED, report.reclaimed_bytes);
+        info!(
+            "temporary files cleaned up: {} removed, {} bytes reclaimed, {} recent ones kept",
+            report.removed, report.reclaimed_bytes, report.kept
+        );
+        report
     }
 
-    fn create_storage(storage: &crate::settings::Storage) -> crate::storage::Storage {
+    pub fn create_storage(storage: &crate::settings::Storage) -> crate::storage::Storage {
         match &storage.config {
-            crate::settings::StorageConfig::Fs { path } => Storage::FileSystem {
+            crate::settings::StorageConfig::Fs {
+                path,
+                sharded,
+                chunk_size,
+                file_mode,
+                dir_mode,
+                uid,
+                gid,
+                fsync,
+            } => Storage::FileSystem(FsBackend {
                 root_dir: path.clone(),
-            },
-            crate::settings::StorageConfig::Mem => Storage::new_mem(),
+                sharded: sharded.unwrap_or(false),
+                chunk_size: chunk_size
+                    .as_ref()
+                    .map(|size| bytefmt::parse(size).unwrap()),
+                // modes are checked when settings are loaded
+                permissions: FsPermissions {
+                    file_mode: file_mode.as_ref().map(|m| parse_mode(m).unwrap()),
+                    dir_mode: dir_mode.as_ref().map(|m| parse_mode(m).unwrap()),
+                    uid: *uid,
+                    gid: *gid,
+                },
+                fsync: fsync.unwrap_or_default(),
+            }),
+            crate::settings::StorageConfig::TieredFs {
+                fast_path,
+                slow_path,
+                fast_size,
+                promote,
+                fsync,
+            } => Storage::TieredFs(TieredFsBackend::new(
+                fast_path,
+                slow_path,
+                bytefmt::parse(fast_size).unwrap(),
+                promote.unwrap_or(false),
+                fsync.unwrap_or_default(),
+            )),
+            crate::settings::StorageConfig::Mem => Storage::Memory(MemBackend::new()),
             crate::settings::StorageConfig::S3 {
                 endpoint, bucket, ..
-            } => Storage::new_s3(endpoint, bucket),
+            } => Storage::S3(S3Backend::new(endpoint, bucket)),
+        }
+    }
+
+    /// The hot keys of an LRU cache of `p`, if they are enabled. Shards have
+    /// hot keys of their own.
+    fn create_hot_keys(p: &Policy) -> Option<HotKeys> {
+        let hot_keys = p.hot_keys.as_ref()?;
+        Some(HotKeys::new(
+            hot_keys.top(),
+            hot_keys.window(),
+            hot_keys.max_entry_size(),
+        ))
+    }
+
+    /// Create the redis metadata of an LRU cache, and rescale atimes recorded
+    /// in secs by earlier versions.
+    fn create_lru_redis_db(
+        redis_client: redis::Client,
+        id: &str,
+        p: &Policy,
+    ) -> Result<RedisMetadataDb> {
+        let db =
+            RedisMetadataDb::new(redis_client, id)?.with_lazy_atime(p.lazy_atime.unwrap_or(false));
+        match db.migrate_atime_to_millis() {
+            Ok(0) => {}
+            Ok(n) => info!(
+                "LRU cache {}: rescaled atime of {} entries to millisecs",
+                id, n
+            ),
+            Err(e) => error!("LRU cache {}: failed to rescale atime: {}", id, e),
         }
+        Ok(db)
     }
 
     fn create_cache_from_rule(
@@ -253,43 +1684,133 @@ impl TaskManager {
             if p.name == policy_ident {
                 let policy_type = p.typ;
                 let metadata_db = p.metadata_db;
+                if let (PolicyType::Lru, Some(shards)) = (policy_type, &p.shards) {
+                    let shards = shards
+                        .iter()
+                        .map(|shard| {
+                            // the storage name identifies the shard on the hash ring
+                            let id = format!("{}_{}", policy_ident, shard.storage);
+                            let shard_db: Arc<dyn LruMetadataStore> = match metadata_db {
+                                MetadataDb::Redis => Arc::new(Self::create_lru_redis_db(
+                                    redis_client.clone().unwrap(),
+                                    &id,
+                                    p,
+                                )?),
+                                MetadataDb::Sled => Arc::new(SledMetadataDb::new_lru(
+                                    &format!("{}/{}", sled_metadata_path, id),
+                                    &id,
+                                )),
+                            };
+                            let storage = storage_map.get(&shard.storage).unwrap();
+                            let cache = LruCache::new(
+                                bytefmt::parse(&shard.size).unwrap(),
+                                shard_db,
+                                storage.for_cache(&id, None),
+                                &id,
+                            )
+                            .with_max_entries(p.max_entries)
+                            .with_max_redis_memory(p.max_redis_memory())
+                            .with_hot_keys(Self::create_hot_keys(p));
+                            Ok((shard.storage.clone(), cache))
+                        })
+                        .collect::<Result<_>>()?;
+                    return Ok(Arc::new(RwLock::new(ShardedCache::new(shards))));
+                }
+                // each cache gets its own directory in the storage, see `Settings::validate`
+                let storage = || {
+                    let primary = storage_map
+                        .get(&p.storage)
+                        .unwrap()
+                        .for_cache(policy_ident, p.root_dir.as_deref());
+                    let replicas = match &p.replicas {
+                        Some(replicas) => replicas,
+                        None => return primary,
+                    };
+                    let mut backends = vec![primary];
+                    backends.extend(replicas.storages.iter().map(|storage| {
+                        storage_map
+                            .get(storage)
+                            .unwrap()
+                            .for_cache(policy_ident, None)
+                    }));
+                    let repair = replicas.repair.unwrap_or(false);
+                    Arc::new(ReplicatedBackend::new(backends, repair)) as Arc<dyn StorageBackend>
+                };
                 match (policy_type, metadata_db) {
                     (PolicyType::Lru, MetadataDb::Redis) => {
-                        return Ok(Arc::new(RwLock::new(LruCache::new(
-                            p.size.as_ref().map_or(0, |x| bytefmt::parse(x).unwrap()),
-                            Arc::new(RedisMetadataDb::new(redis_client.unwrap(), policy_ident)),
-                            storage_map.get(&p.storage).unwrap().clone(),
-                            policy_ident,
-                        ))));
+                        return Ok(Arc::new(RwLock::new(
+                            LruCache::new(
+                                p.size.as_ref().map_or(0, |x| bytefmt::parse(x).unwrap()),
+                                Arc::new(Self::create_lru_redis_db(
+                                    redis_client.unwrap(),
+                                    policy_ident,
+                                    p,
+                                )?),
+                                storage(),
+                                policy_ident,
+                            )
+                            .with_max_entries(p.max_entries)
+                            .with_max_redis_memory(p.max_redis_memory())
+                            .with_hot_keys(Self::create_hot_keys(p)),
+                        )));
                     }
                     (PolicyType::Lru, MetadataDb::Sled) => {
-                        return Ok(Arc::new(RwLock::new(LruCache::new(
-                            p.size.as_ref().map_or(0, |x| bytefmt::parse(x).unwrap()),
-                            Arc::new(SledMetadataDb::new_lru(
-                                &format!("{}/{}", sled_metadata_path, policy_ident),
+                        return Ok(Arc::new(RwLock::new(
+                            LruCache::new(
+                                p.size.as_ref().map_or(0, |x| bytefmt::parse(x).unwrap()),
+                                Arc::new(SledMetadataDb::new_lru(
+                                    &format!("{}/{}", sled_metadata_path, policy_ident),
+                                    policy_ident,
+                                )),
+                                storage(),
                                 policy_ident,
-                            )),
-                            storage_map.get(&p.storage).unwrap().clone(),
-                            policy_ident,
-                        ))));
+                            )
+                            .with_max_entries(p.max_entries)
+                            .with_max_redis_memory(p.max_redis_memory())
+                            .with_hot_keys(Self::create_hot_keys(p)),
+                        )));
                     }
                     (PolicyType::Ttl, MetadataDb::Redis) => {
-                        return Ok(Arc::new(RwLock::new(TtlCache::new(
-                            p.timeout.unwrap_or(0),
-                            Arc::new(RedisMetadataDb::new(redis_client.unwrap(), policy_ident)),
-                            storage_map.get(&p.storage).unwrap().clone(),
-                        ))));
+                        return Ok(Arc::new(RwLock::new(
+                            TtlCache::new(
+                                p.timeout.unwrap_or(0),
+                                Arc::new(RedisMetadataDb::new(
+                                    redis_client.unwrap(),
+                                    policy_ident,
+                                )?),
+                                storage(),
+                            )
+                            .with_stale_window(p.serve_stale_on_error.unwrap_or(0))
+                            .with_sliding(
+                                p.sliding.unwrap_or(false).then(|| Sliding {
+                                    max_lifetime: p.max_lifetime,
+                                }),
+                            ),
+                        )));
                     }
                     (PolicyType::Ttl, MetadataDb::Sled) => {
-                        return Ok(Arc::new(RwLock::new(TtlCache::new(
-                            p.timeout.unwrap_or(0),
-                            Arc::new(SledMetadataDb::new_ttl(
-                                &format!("{}/{}", sled_metadata_path, &policy_ident),
-                                policy_ident,
-                                p.clean_interval.unwrap_or(3),
-                            )),
-                            storage_map.get(&p.storage).unwrap().clone(),
-                        ))));
+                        return Ok(Arc::new(RwLock::new(
+                            TtlCache::new(
+                                p.timeout.unwrap_or(0),
+                                Arc::new(SledMetadataDb::new_ttl(
+                                    &format!("{}/{}", sled_metadata_path, &policy_ident),
+                                    policy_ident,
+                                    p.clean_interval.unwrap_or(3),
+                                )),
+                                storage(),
+                            )
+                            .with_stale_window(p.serve_stale_on_error.unwrap_or(0)),
+                        )));
+                    }
+                    (PolicyType::NoCache, _) => {
+                        let cache = match &p.micro_cache {
+                            Some(m) => NoCache::with_micro_cache(
+                                MicroCache::new(Duration::from_secs(m.ttl()), m.max_entries()),
+                                m.max_entry_size(),
+                            ),
+                            None => NoCache::default(),
+                        };
+                        return Ok(Arc::new(RwLock::new(cache)));
                     }
                 };
             }
@@ -300,132 +1821,2571 @@ impl TaskManager {
         )))
     }
 
+    /// Try to reserve a slot for an upstream fetch of a cache miss, within both
+    /// the global limit and the limit of the task's rule.
+    /// Returns `None` if either limit is reached.
+    fn try_acquire_inflight(&self, task: &Task) -> Option<InflightPermit> {
+        let mut permits = Vec::new();
+        for semaphore in self
+            .inflight_global
+            .iter()
+            .chain(self.inflight_map.get(&task.rule_id))
+        {
+            permits.push(semaphore.clone().try_acquire_owned().ok()?);
+        }
+        Some(InflightPermit::new(permits))
+    }
+
     async fn taskset_contains(&self, t: &Task) -> bool {
-        self.task_set.read().await.contains(t)
+        self.task_set.read().await.contains_key(t)
     }
 
-    async fn taskset_add(&self, t: Task) {
-        self.task_set.write().await.insert(t);
+    async fn taskset_add(&self, t: Task) -> Instant {
+        let spawned_at = Instant::now();
+        self.task_set.write().await.insert(t, spawned_at);
+        spawned_at
     }
 
-    async fn taskset_remove(task_set: Arc<RwLock<HashSet<Task>>>, t: &Task) {
-        task_set.write().await.remove(t);
+    /// Remove a task, unless it was removed as stuck and spawned again since
+    async fn taskset_remove(task_set: TaskSet, t: &Task, spawned_at: Instant) {
+        let mut task_set = task_set.write().await;
+        if task_set.get(t) == Some(&spawned_at) {
+            task_set.remove(t);
+        }
     }
 
-    async fn taskset_len(task_set: Arc<RwLock<HashSet<Task>>>) -> usize {
+    async fn taskset_len(task_set: TaskSet) -> usize {
         let len = task_set.read().await.len();
         histogram!(metric::HG_TASKS_LEN, len as f64);
         len
     }
 
-    /// Spawn an async task
-    async fn spawn_task(&self, task: Task) {
-        increment_counter!(metric::COUNTER_TASKS_BG);
-        if self.taskset_contains(&task).await {
-            info!("[TASK] ignored existing task: {:?}", task);
-            return;
-        }
-        self.taskset_add(task.clone()).await;
-        let task_set_len = Self::taskset_len(self.task_set.clone()).await;
-        info!("[TASK] [len={}] + {:?}", task_set_len, task);
-        let c = self.get_cache_for_cache_rule(task.rule_id).unwrap();
-        let rewrites = self.rewrite_map.get(&task.rule_id).cloned();
-        let task_clone = task.clone();
-        let upstream_url = self.resolve_task_upstream(&task_clone);
-        let task_list_ptr = self.task_set.clone();
-        // spawn an async download task
-        tokio::spawn(async move {
-            let resp = util::make_request(&upstream_url, false).await;
-            match resp {
-                Ok(res) => {
-                    if res.status().is_success() {
-                        if let Some(rewrites) = rewrites {
-                            let content = res.text().await.ok();
-                            if content.is_none() {
-                                increment_counter!(metric::CNT_TASKS_BG_FAILURE);
-                                return;
-                            }
-                            let mut content = content.unwrap();
-                            content = Self::rewrite_upstream(content, &rewrites);
-                            c.write()
-                                .await
-                                .put(&task_clone.to_key(), content.into())
-                                .await;
-                        } else {
-                            let len = res.content_length();
-                            let bytestream = res.bytes_stream();
-                            c.write()
-                                .await
-                                .put(
-                                    &task_clone.to_key(),
-                                    CacheData::ByteStream(
-                                        Box::new(
-                                            bytestream.map(move |x| x.map_err(Error::RequestError)),
-                                        ),
-                                        len,
-                                    ),
-                                )
-                                .await;
-                        }
-                        increment_counter!(metric::CNT_TASKS_BG_SUCCESS);
-                    } else {
-                        warn!(
-                            "[TASK] ❌ failed to fetch upstream: {}, Task {:?}",
-                            res.status().canonical_reason().unwrap_or("unknown"),
-                            &task_clone
-                        );
-                        increment_counter!(metric::CNT_TASKS_BG_FAILURE);
-                    }
+    fn max_task_duration(&self) -> Duration {
+        Duration::from_secs(
+            self.config
+                .background_tasks
+                .as_ref()
+                .and_then(|b| b.max_task_duration)
+                .unwrap_or(DEFAULT_MAX_TASK_DURATION),
+        )
+    }
+
+    fn max_resumes(&self) -> u32 {
+        self.config
+            .background_tasks
+            .as_ref()
+            .and_then(|b| b.max_resumes)
+            .unwrap_or(DEFAULT_MAX_RESUMES)
+    }
+
+    /// Forget background tasks running longer than `max_task_duration`, so
+    /// that a stuck task does not prevent its key from being fetched again.
+    /// Returns the number of removed tasks.
+    pub async fn remove_stuck_tasks(&self) -> usize {
+        let max_duration = self.max_task_duration();
+        let mut task_set = self.task_set.write().await;
+        let len = task_set.len();
+        task_set.retain(|task, spawned_at| {
+            let elapsed = spawned_at.elapsed();
+            if elapsed < max_duration {
+                return true;
+            }
+            warn!(
+                "[TASK] removed task running for {}s: {:?}",
+                elapsed.as_secs(),
+                task
+            );
+            false
+        });
+        len - task_set.len()
+    }
+
+    /// Background tasks in progress, the longest running first
+    pub async fn running_tasks(&self) -> Vec<TaskInfo> {
+        let mut tasks: Vec<TaskInfo> = self
+            .task_set
+            .read()
+            .await
+            .iter()
+            .map(|(task, spawned_at)| TaskInfo {
+                rule: self.rule_label(task),
+                url: task.url.clone(),
+                key: task.to_key(),
+                running_secs: spawned_at.elapsed().as_secs(),
+            })
+            .collect();
+        tasks.sort_by(|a, b| b.running_secs.cmp(&a.running_secs));
+        tasks
+    }
+
+    /// Write the response of a cache miss to the cache while streaming it to
+    /// the client, so the upstream is requested only once.
+    async fn write_through(
+        &self,
+        task: &Task,
+        key: &str,
+        res: reqwest::Response,
+        permit: InflightPermit,
+    ) -> Result<TaskResponse> {
+        let c = match self.get_cache_for_task(task) {
+            Some(c) => c,
+            None => {
+                error!("[TASK] no cache for rule #{}: {:?}", task.rule_id, task);
+                return Ok(TaskResponse::StreamResponse(Box::pin(
+                    res.bytes_stream().map(|x| x.map_err(Error::RequestError)),
+                )));
+            }
+        };
+        let entry = self.entry_options(task);
+        let downloads = self.downloads.clone();
+        let key = key.to_string();
+        let (tx, rx) = mpsc::channel(TEE_BUFFER);
+        // the client may disconnect early, so the cache is written in another task
+        tokio::spawn(
+            async move {
+                // hold the permit until the response is written to the cache
+                let _permit = permit;
+                if cache_response(c, &key, res, entry, downloads, Some(tx)).await {
+                    increment_counter!(metric::CNT_TASKS_BG_SUCCESS);
+                } else {
+                    increment_counter!(metric::CNT_TASKS_BG_FAILURE);
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(TaskResponse::StreamResponse(Box::pin(rx)))
+    }
+
+    /// Spawn an async task, it waits for tasks of higher priority to run first.
+    /// Returns a handle completing with the task, `None` if it is not spawned.
+    async fn spawn_task(&self, task: Task, priority: Priority) -> Option<JoinHandle<()>> {
+        self.spawn_fetch(task, priority, None).await
+    }
+
+    /// Revalidate a cached entry of an LRU cache in the background, if it is
+    /// older than the `revalidate_after` of its rule. It is served meanwhile.
+    async fn revalidate_if_old(&self, task: &Task, age: Option<Duration>) {
+        // immutable keys never change, and mutable keys of a TTL cache expire
+        if let Some(route) = self.class_map.get(&task.rule_id) {
+            if route.mutable_cache.is_some() || self.key_class(task) == Some(KeyClass::Immutable) {
+                return;
+            }
+        }
+        let revalidate_after = self
+            .config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| rule.revalidate_after);
+        let age = match (revalidate_after, age) {
+            (Some(threshold), Some(age)) if age.as_secs() >= threshold => age,
+            _ => return,
+        };
+        info!(
+            "[Request] [REVALIDATE] {:?} cached {}s ago",
+            task,
+            age.as_secs()
+        );
+        increment_counter!(metric::CNT_REVALIDATIONS, "rule" => self.rule_label(task));
+        let cached_at = util::now() - age.as_secs() as i64;
+        self.spawn_fetch(task.clone(), Priority::Low, Some(cached_at))
+            .await;
+    }
+
+    /// Fetch the upstream of a task in a background task and cache the
+    /// response. If `cached_at` is set, the fetch is conditional, and an
+    /// entry the upstream did not modify since then is kept. Returns a
+    /// handle completing once the task is removed from the running tasks,
+    /// `None` if it is not spawned, e.g. if it is already running.
+    async fn spawn_fetch(
+        &self,
+        task: Task,
+        priority: Priority,
+        cached_at: Option<i64>,
+    ) -> Option<JoinHandle<()>> {
+        if self.cache_mode(&task) == CacheMode::ReadOnly {
+            return None;
+        }
+        if self.is_offline(task.rule_id) {
+            debug!("[TASK] ignored in offline mode: {:?}", task);
+            return None;
+        }
+        if self.is_bypassed(task.rule_id) {
+            debug!("[TASK] ignored while the caches are bypassed: {:?}", task);
+            return None;
+        }
+        increment_counter!(metric::COUNTER_TASKS_BG, "priority" => priority.label());
+        let c = match self.get_cache_for_task(&task) {
+            Some(c) => c,
+            None => {
+                error!("[TASK] no cache for rule #{}: {:?}", task.rule_id, task);
+                return None;
+            }
+        };
+        self.remove_stuck_tasks().await;
+        if self.taskset_contains(&task).await {
+            info!("[TASK] ignored existing task: {:?}", task);
+            return None;
+        }
+        let spawned_at = self.taskset_add(task.clone()).await;
+        let task_set_len = Self::taskset_len(self.task_set.clone()).await;
+        info!("[TASK] [len={}] + {:?}", task_set_len, task);
+        let entry = self.entry_options(&task);
+        let dir_listing = entry.dir_listing_ttl.is_some();
+        let task_clone = task.clone();
+        let upstream_url = self.resolve_task_upstream(&task_clone);
+        let token = self.token_map.get(&task.rule_id).cloned();
+        let task_list_ptr = self.task_set.clone();
+        let downloads = self.downloads.clone();
+        let client = self.upstream_client(&task);
+        let is_binary = self.is_binary_package(&task);
+        let uncacheable_headers = self.uncacheable_headers(&task);
+        let accept = task.accept();
+        let label = self.rule_label(&task);
+        let paginate = self.pagination(&task);
+        let scheduler = self.scheduler.clone();
+        let upstream_health = self.upstream_health.clone();
+        // a child of the span of the request the task is spawned for, if any
+        let span = info_span!(
+            "background",
+            key = %task.to_key(),
+            cache_id = %self.cache_id(&task),
+            upstream = %redact_token(&upstream_url, token.as_deref()),
+            priority = priority.label(),
+        );
+        // spawn an async download task
+        let download = tokio::spawn(
+            async move {
+                // wait for the cooldown of a rate limiting upstream, which a
+                // 429 answer of another fetch may extend meanwhile
+                while let Some(rest) = upstream_health.cooldown(&upstream_url) {
+                    debug!("[TASK] waiting {:?} for the upstream to cool down", rest);
+                    tokio::time::sleep(rest).await;
                 }
-                Err(e) => {
+                let _permit = scheduler.acquire(priority).await;
+                let timer = Timer::start(Category::Upstream, "background_fetch");
+                let resp = match cached_at {
+                    Some(since) => {
+                        util::make_conditional_request(&client, &upstream_url, since, accept).await
+                    }
+                    None => util::make_negotiated_request(&client, &upstream_url, accept).await,
+                };
+                let latency = timer.finish(
+                    &task_clone.to_key(),
+                    resp.as_ref().ok().and_then(|res| res.content_length()),
+                );
+                upstream_health.record_fetch(&upstream_url, latency, &resp);
+                match resp {
+                    Ok(res)
+                        if cached_at.is_some()
+                            && res.status() == reqwest::StatusCode::NOT_MODIFIED =>
+                    {
+                        let key = task_clone.to_key();
+                        match c.read().await.confirm_current(&key) {
+                            Ok(cached) => {
+                                debug!("[TASK] {} not modified, cached: {}", key, cached);
+                                increment_counter!(metric::CNT_TASKS_BG_SUCCESS);
+                            }
+                            Err(e) => {
+                                error!("[TASK] ❌ failed to keep {}: {}", key, e);
+                                increment_counter!(metric::CNT_TASKS_BG_FAILURE);
+                            }
+                        }
+                    }
+                    Ok(res) => {
+                        let is_html = is_html_response(&res);
+                        // e.g. a redirect that is not followed, or an html error page
+                        // served for a package is not cached
+                        if res.status() == reqwest::StatusCode::OK
+                            && !is_uncacheable(
+                                &res,
+                                uncacheable_headers.as_deref(),
+                                &label,
+                                &task_clone,
+                            )
+                            && !(is_binary && is_html)
+                            && !(accept.is_some() && !pep691::is_json_response(&res))
+                            && !(dir_listing
+                                && listing::is_trailing_slash_redirect(&upstream_url, &res))
+                        {
+                            let key = task_clone.to_key();
+                            let cached = match &paginate {
+                                Some(paginate) if pagination::next_link(&res).is_some() => {
+                                    cache_pages(c, &key, res, &client, accept, paginate, entry)
+                                        .await
+                                }
+                                _ => cache_response(c, &key, res, entry, downloads, None).await,
+                            };
+                            if cached {
+                                increment_counter!(metric::CNT_TASKS_BG_SUCCESS);
+                            } else {
+                                increment_counter!(metric::CNT_TASKS_BG_FAILURE);
+                            }
+                        } else {
+                            warn!(
+                                "[TASK] ❌ refused to cache upstream response: {}{}, Task {:?}",
+                                res.status(),
+                                if is_html { " (text/html)" } else { "" },
+                                &task_clone
+                            );
+                            increment_counter!(metric::CNT_TASKS_BG_FAILURE);
+                        }
+                    }
+                    Err(e) => {
+                        increment_counter!(metric::CNT_TASKS_BG_FAILURE);
+                        error!(
+                            "[TASK] ❌ failed to fetch upstream: {}, Task {:?}",
+                            redact_token(&e.to_string(), token.as_deref()),
+                            &task_clone
+                        );
+                    }
+                };
+            }
+            .instrument(span.clone()),
+        );
+        // the task is removed even if the download panics, so it can be retried
+        let watcher = tokio::spawn(
+            async move {
+                if let Err(e) = download.await {
                     increment_counter!(metric::CNT_TASKS_BG_FAILURE);
-                    error!(
-                        "[TASK] ❌ failed to fetch upstream: {}, Task {:?}",
-                        e, &task_clone
-                    );
+                    let message = if e.is_panic() {
+                        format!("panicked: {}", util::panic_message(&*e.into_panic()))
+                    } else {
+                        e.to_string()
+                    };
+                    error!("[TASK] ❌ {}, Task {:?}", message, &task);
+                }
+                Self::taskset_remove(task_list_ptr.clone(), &task, spawned_at).await;
+                Self::taskset_len(task_list_ptr).await;
+            }
+            .instrument(span),
+        );
+        Some(watcher)
+    }
+
+    /// get task result from cache, including entries that expired recently
+    async fn get_stale(&self, task: &Task, key: &str) -> Option<CacheData> {
+        let cache = self.get_cache_for_task(task)?;
+        let cache = cache.read().await;
+        let data = cache.get_stale(key).await?;
+        if let Some(staleness) = cache.staleness(key).await {
+            info!(
+                "[Request] [STALE] {} expired {}s ago",
+                key,
+                staleness.as_secs()
+            );
+        }
+        Some(data)
+    }
+
+    /// The outcome of a task not served from the cache
+    fn outcome(&self, task: &Task, status: CacheStatus) -> ResolveOutcome {
+        ResolveOutcome {
+            status,
+            cache_id: self.cache_id(task),
+            age: None,
+            upstream_cache: None,
+            content_type: task.accept(),
+        }
+    }
+
+    /// Count a request of a cache miss by the admission filter of its rule,
+    /// and return whether the response may be cached. Always true for rules
+    /// without an admission filter.
+    fn admit(&self, task: &Task, key: &str) -> bool {
+        let filter = match self.admission_map.get(&task.rule_id) {
+            Some(filter) => filter,
+            None => return true,
+        };
+        let admitted = filter.admit(key);
+        let rule = self
+            .config
+            .rules
+            .get(task.rule_id)
+            .map(rule_label)
+            .unwrap_or_default();
+        if admitted {
+            increment_counter!(metric::CNT_ADMISSION_ADMITTED, "rule" => rule);
+        } else {
+            debug!("{} is not admitted to the cache yet", key);
+            increment_counter!(metric::CNT_ADMISSION_REJECTED, "rule" => rule);
+        }
+        admitted
+    }
+
+    /// Whether the redirects of the upstream of a task are cached, see
+    /// `Options::cache_redirects`
+    fn caches_redirects(&self, task: &Task) -> bool {
+        self.config
+            .rules
+            .get(task.rule_id)
+            .map_or(false, |rule| rule.cache_redirects())
+    }
+
+    /// The redirect cached for the key of a task, if its rule caches them
+    async fn cached_redirect(&self, task: &Task, key: &str) -> Option<CachedRedirect> {
+        if !self.caches_redirects(task) {
+            return None;
+        }
+        let data = self.get(task, &redirects::redirect_key(key)).await?;
+        CachedRedirect::parse(&data.into_vec_u8().await)
+    }
+
+    /// The redirect answered by the upstream of a task whose rule caches
+    /// them, with its `Location` rewritten by the rewrites of the rule
+    fn upstream_redirect(
+        &self,
+        task: &Task,
+        url: &str,
+        res: &reqwest::Response,
+    ) -> Option<CachedRedirect> {
+        if !self.caches_redirects(task) {
+            return None;
+        }
+        let mut redirect = CachedRedirect::from_response(url, res)?;
+        let rewrites = self.rewrites(task).unwrap_or_default();
+        // rewrites of JSON fields are for bodies
+        for rewrite in rewrites
+            .iter()
+            .filter(|rewrite| rewrite.json_field.is_none())
+        {
+            redirect.location = redirect.location.replace(&rewrite.from, &rewrite.to);
+        }
+        Some(redirect)
+    }
+
+    /// Cache a redirect of the upstream of a task, expiring with the TTL of
+    /// its policy
+    async fn cache_redirect(&self, task: &Task, key: &str, redirect: &CachedRedirect) {
+        let cache = match self.get_cache_for_task(task) {
+            Some(cache) => cache,
+            None => return,
+        };
+        debug!("caching redirect of {} to {}", key, redirect.location);
+        let entry = CacheData::BytesData(redirect.to_bytes());
+        put_entry(&cache, &redirects::redirect_key(key), entry, None, None).await;
+    }
+
+    /// The status of an upstream error recently returned for the key of a
+    /// task, if its rule remembers them
+    fn negative_hit(&self, task: &Task, key: &str) -> Option<u16> {
+        let status = self.negative_map.get(&task.rule_id)?.get(key)?;
+        let rule = self.rule_label(task);
+        increment_counter!(metric::CNT_MICRO_CACHE_NEGATIVE_HITS, "rule" => rule);
+        Some(status)
+    }
+
+    /// The name of the policy caching a task, see `get_cache_for_task`,
+    /// empty if unknown
+    fn policy_name(&self, task: &Task) -> String {
+        let rule = match self.config.rules.get(task.rule_id) {
+            Some(rule) => rule,
+            None => return String::new(),
+        };
+        match rule
+            .key_classes
+            .as_ref()
+            .and_then(|c| c.mutable_policy.as_ref())
+        {
+            Some(policy) if self.key_class(task) == Some(KeyClass::Mutable) => policy.clone(),
+            _ => rule.policy.clone(),
+        }
+    }
+
+    /// The class of the key of a task, if its rule has `key_classes`
+    fn key_class(&self, task: &Task) -> Option<KeyClass> {
+        let route = self.class_map.get(&task.rule_id)?;
+        Some(route.classifier.classify(&task.to_key()))
+    }
+
+    /// The outcome of a task served from the cache, with the age of the entry
+    async fn hit_outcome(&self, task: &Task, key: &str, status: CacheStatus) -> ResolveOutcome {
+        let age = match self.get_cache_for_task(task) {
+            Some(cache) => cache.read().await.age(key).await,
+            None => None,
+        };
+        ResolveOutcome {
+            age,
+            ..self.outcome(task, status)
+        }
+    }
+
+    /// Serve a cache hit with the size of the entry as `Content-Length`. If
+    /// the stored object turns out shorter or longer, the response is
+    /// aborted and the entry is removed, so that the next request fetches it
+    /// again.
+    fn hit_response(&self, task: &Task, key: &str, data: CacheData) -> TaskResponse {
+        let (stream, size) = match data {
+            CacheData::ByteStream(stream, Some(size)) => (stream, size),
+            data => return data.into(),
+        };
+        let cache = self.get_cache_for_task(task);
+        let key = key.to_string();
+        let stream = exact_size_stream(stream, size, move |received| {
+            warn!(
+                "cache entry {} is corrupted: {} bytes expected, {} read",
+                key, size, received
+            );
+            increment_counter!(metric::CNT_CORRUPT_ENTRIES);
+            if let Some(cache) = cache {
+                tokio::spawn(async move {
+                    if let Err(e) = cache.read().await.remove(&key).await {
+                        error!("failed to remove corrupted entry {}: {}", key, e);
+                    }
+                });
+            }
+        });
+        TaskResponse::SizedResponse(Box::pin(stream), size)
+    }
+
+    /// The size a cache hit of a task is served with, if the entry is cached
+    /// and its size is recorded. Neither the entry is read nor a hit counted.
+    pub async fn cached_size(&self, task: &Task) -> Option<u64> {
+        let cache = self.get_cache_for_task(task)?;
+        let sizes = cache.read().await.entry_sizes(&[task.to_key()]).ok()?;
+        sizes.first().copied().filter(|size| *size != 0)
+    }
+
+    /// Whether the files of `tasks` are cached, looked up in a batch per
+    /// cache. Files of caches failing to be read are taken as not cached.
+    async fn cached_tasks(&self, tasks: &[Task]) -> Vec<bool> {
+        let mut cached = vec![false; tasks.len()];
+        let mut by_cache: HashMap<String, Vec<usize>> = HashMap::new();
+        for (idx, task) in tasks.iter().enumerate() {
+            by_cache.entry(self.cache_id(task)).or_default().push(idx);
+        }
+        for (cache_id, positions) in by_cache {
+            let cache = match self.get_cache_for_task(&tasks[positions[0]]) {
+                Some(cache) => cache,
+                None => continue,
+            };
+            let keys: Vec<String> = positions.iter().map(|idx| tasks[*idx].to_key()).collect();
+            let found = cache.read().await.contains_many(&keys);
+            match found {
+                Ok(found) => {
+                    for (idx, found) in positions.into_iter().zip(found) {
+                        cached[idx] = found;
+                    }
+                }
+                Err(e) => warn!(
+                    "failed to look up {} files in {}: {}",
+                    keys.len(),
+                    cache_id,
+                    e
+                ),
+            }
+        }
+        cached
+    }
+
+    /// Whether the entries of `keys` are cached in the cache `cache_id`,
+    /// see `Cache::contains_many`
+    pub async fn contains_many(&self, cache_id: &str, keys: &[String]) -> Result<Vec<bool>> {
+        let cache = self
+            .get_cache_for_policy(cache_id)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", cache_id)))?;
+        let found = cache.read().await.contains_many(keys);
+        found
+    }
+
+    /// get task result from cache
+    pub async fn get(&self, task: &Task, key: &str) -> Option<CacheData> {
+        let rule_id = task.rule_id;
+        match self.get_cache_for_task(task) {
+            Some(cache) => {
+                let span = debug_span!("cache_get", key, cache_id = %self.cache_id(task));
+                let data = cache.read().await.get(key).instrument(span).await;
+                if self.is_no_cache(task) {
+                    let rule = self.rule_label(task);
+                    increment_counter!(metric::CNT_PASSTHROUGH_GETS, "rule" => rule.clone());
+                    if data.is_some() {
+                        increment_counter!(metric::CNT_MICRO_CACHE_HITS, "rule" => rule);
+                    }
+                }
+                data
+            }
+            None => {
+                error!("Failed to get cache for rule #{} from cache map", rule_id);
+                None
+            }
+        }
+    }
+
+    /// get the local path of a cached file, if it is stored in the filesystem.
+    /// The path is relative to the storage root, or to the `root_dir` of the
+    /// policy if it is set.
+    async fn get_local_path(&self, task: &Task, key: &str) -> Option<(String, u64)> {
+        let cache = self.get_cache_for_task(task)?;
+        let (path, size) = cache.read().await.get_local_path(key).await?;
+        match self.cache_policy(&self.cache_id(task)) {
+            Some(policy) if policy.root_dir.is_none() => {
+                Some((format!("{}/{}", policy.name, path), size))
+            }
+            _ => Some((path, size)),
+        }
+    }
+
+    /// Rewrites of the response of a task: the ones of its rule, and for the
+    /// root PyPI simple index, links to projects made relative to the index,
+    /// e.g. `/simple/flask/` -> `flask/`, wherever the mirror serves it
+    fn rewrites(&self, task: &Task) -> Option<Vec<Rewrite>> {
+        let mut rewrites = self.rewrite_map.get(&task.rule_id).cloned();
+        if let Some(path) = self.root_index_path(task) {
+            rewrites.get_or_insert_with(Vec::new).push(Rewrite {
+                from: format!("href=\"{}", path),
+                to: "href=\"".to_string(),
+                json_field: None,
+            });
+        }
+        rewrites
+    }
+
+    /// The upstream path of a task of the root PyPI simple index of a `pep503`
+    /// rule, e.g. `/simple/`
+    fn root_index_path(&self, task: &Task) -> Option<String> {
+        let options = self.config.rules.get(task.rule_id)?.options.as_ref()?;
+        if !options.pep503.unwrap_or(false) {
+            return None;
+        }
+        let url = reqwest::Url::parse(&task.url).ok()?;
+        if !util::is_pypi_root_index(url.path()) {
+            return None;
+        }
+        Some(format!("{}/", url.path().trim_end_matches('/')))
+    }
+
+    /// TTL of the entry of a task overriding the one of its policy, if any
+    fn entry_ttl(&self, task: &Task) -> Option<u64> {
+        self.root_index_path(task)?;
+        let options = self.config.rules.get(task.rule_id)?.options.as_ref()?;
+        Some(options.root_index_ttl.unwrap_or(DEFAULT_ROOT_INDEX_TTL))
+    }
+
+    /// TTL of the directory listings of a task, if its rule rewrites them
+    fn dir_listing_ttl(&self, task: &Task) -> Option<u64> {
+        let options = self.config.rules.get(task.rule_id)?.options.as_ref()?;
+        if !options.rewrite_dir_listing.unwrap_or(false) {
+            return None;
+        }
+        Some(options.dir_listing_ttl.unwrap_or(DEFAULT_DIR_LISTING_TTL))
+    }
+
+    /// How the response of a task is written to the cache
+    fn entry_options(&self, task: &Task) -> EntryOptions {
+        EntryOptions {
+            rewrites: self.rewrites(task),
+            ttl: self.entry_ttl(task),
+            dir_listing_ttl: self.dir_listing_ttl(task),
+            max_rewrite_body: self.max_rewrite_body_bytes(),
+            rule: self.rule_label(task),
+            hash_source: self.hash_source(task),
+            metadata_prefetch: self.metadata_prefetch(task),
+            url: Some(task.url.clone()).filter(|_| is_digest_key(&task.to_key())),
+            resume: Some(Resume {
+                client: self.upstream_client(task),
+                max_resumes: self.max_resumes(),
+            })
+            .filter(|resume| resume.max_resumes > 0),
+        }
+    }
+
+    /// Fetch the metadata files advertised by the index pages of a task in
+    /// the background, if its rule sets `prefetch_metadata`
+    fn metadata_prefetch(&self, task: &Task) -> Option<MetadataPrefetch> {
+        let options = self.config.rules.get(task.rule_id)?.options.as_ref()?;
+        if options.prefetch_metadata != Some(true) {
+            return None;
+        }
+        let tm = self.clone();
+        let tenant = task.tenant.clone();
+        Some(Box::new(move |urls: Vec<reqwest::Url>| {
+            if !urls.is_empty() {
+                let prefetch = async move { tm.prefetch_metadata(urls, tenant).await };
+                tokio::spawn(prefetch.in_current_span());
+            }
+        }))
+    }
+
+    /// Fetch the metadata files of `urls` that are not cached yet as low
+    /// priority background tasks of the rules serving them, in the caches
+    /// of `tenant` if set. Files no rule serves are skipped.
+    async fn prefetch_metadata(&self, urls: Vec<reqwest::Url>, tenant: Option<String>) {
+        let mut tasks: Vec<Task> = Vec::new();
+        for url in urls {
+            let mut task = match self.task_for_url(url.as_str()) {
+                Some(task) => task,
+                None => {
+                    trace!("[TASK] no rule serves the metadata file {}", url);
+                    continue;
+                }
+            };
+            task.tenant = tenant.clone();
+            tasks.push(task);
+        }
+        let cached = self.cached_tasks(&tasks).await;
+        let mut queued: u64 = 0;
+        for (task, cached) in tasks.into_iter().zip(cached) {
+            if cached {
+                continue;
+            }
+            if self.spawn_task(task, Priority::Low).await.is_some() {
+                queued += 1;
+            }
+        }
+        if queued > 0 {
+            debug!("[TASK] prefetching {} metadata files", queued);
+            counter!(metric::CNT_METADATA_PREFETCHES, queued);
+        }
+    }
+
+    /// The cache of the files linked by the index pages of a task whose
+    /// hashes are appended to the links, see `Options::hash_links_from`.
+    /// A task of a tenant looks the files up in the cache of the tenant.
+    fn hash_source(&self, task: &Task) -> Option<Arc<RwLock<dyn Cache>>> {
+        let policy = self.config.rules.get(task.rule_id)?.hash_links_from()?;
+        match &task.tenant {
+            Some(tenant) => self.get_cache_for_policy(&format!("{}_{}", tenant, policy)),
+            None => self.get_cache_for_policy(policy),
+        }
+    }
+
+    /// See `Options::paginate`
+    fn pagination(&self, task: &Task) -> Option<Pagination> {
+        self.config.rules.get(task.rule_id)?.pagination().cloned()
+    }
+
+    /// A paginated document served to a client: its pages merged if it has
+    /// at most `foreground_pages`, otherwise its first page, so that the
+    /// client is not kept waiting while the document is merged in the
+    /// background
+    async fn serve_pages(
+        &self,
+        task: &Task,
+        res: reqwest::Response,
+        paginate: &Pagination,
+    ) -> Result<TaskResponse> {
+        let client = self.upstream_client(task);
+        let pages = pagination::follow(
+            &client,
+            res,
+            task.accept(),
+            paginate.foreground_pages(),
+            self.max_rewrite_body_bytes(),
+        )
+        .await?;
+        let first = pages.pages[0].clone();
+        let content = if pages.complete {
+            let count = pages.pages.len();
+            pagination::merge(pages.pages, paginate.merge()).unwrap_or_else(|e| {
+                warn!(
+                    "[Request] failed to merge the {} pages of {:?}: {}",
+                    count, task, e
+                );
+                first
+            })
+        } else {
+            debug!("[Request] {:?} has more pages, serving the first one", task);
+            first
+        };
+        Ok(match self.rewrites(task) {
+            Some(rewrites) => Self::rewrite_upstream(content, &rewrites),
+            None => content,
+        }
+        .into())
+    }
+
+    fn max_rewrite_body_bytes(&self) -> u64 {
+        self.config
+            .max_rewrite_body_bytes
+            .unwrap_or(DEFAULT_MAX_REWRITE_BODY_BYTES)
+    }
+
+    /// The body of a response to rewrite whole, see `util::response_text`
+    async fn response_text(&self, task: &Task, res: reqwest::Response) -> Result<TextBody> {
+        let body = util::response_text(res, self.max_rewrite_body_bytes()).await?;
+        if let TextBody::TooLarge(_, len) = &body {
+            skip_rewrite(&task.to_key(), &self.rule_label(task), *len);
+        }
+        Ok(body)
+    }
+
+    pub fn rewrite_upstream(content: String, rewrites: &[Rewrite]) -> String {
+        let mut content = content;
+        for rewrite in rewrites {
+            content = match &rewrite.json_field {
+                Some(field) => Self::rewrite_json_field(content, field, rewrite),
+                None => content.replace(&rewrite.from, &rewrite.to),
+            };
+        }
+        content
+    }
+
+    /// Rewrite string values of the JSON fields named `field`.
+    /// The content is returned untouched if it is not valid JSON.
+    fn rewrite_json_field(content: String, field: &str, rewrite: &Rewrite) -> String {
+        match serde_json::from_str::<serde_json::Value>(&content) {
+            Ok(mut value) => {
+                rewrite_json_value(&mut value, field, &rewrite.from, &rewrite.to);
+                serde_json::to_string(&value).unwrap_or(content)
+            }
+            Err(e) => {
+                warn!("failed to parse JSON for rewriting `{}`: {}", field, e);
+                content
+            }
+        }
+    }
+
+    pub fn resolve_task_upstream(&self, task_type: &Task) -> String {
+        if let Some(url) = self.select_mirror(task_type) {
+            return url;
+        }
+        match self.token_map.get(&task_type.rule_id) {
+            Some(token) => inject_conda_token(&task_type.url, token),
+            None => task_type.url.clone(),
+        }
+    }
+
+    /// The url of the task on the mirror picked by `UpstreamHealth::select`,
+    /// if its rule has `mirrors` and the rule's upstream was not picked. The
+    /// token of the rule is only sent to its upstream.
+    fn select_mirror(&self, task: &Task) -> Option<String> {
+        let rule = self.config.rules.get(task.rule_id)?;
+        let upstreams = rule.upstreams();
+        if upstreams.len() < 2 {
+            return None;
+        }
+        let rest = task.url.strip_prefix(upstreams[0].as_str())?;
+        match self
+            .upstream_health
+            .select(&upstreams, rule.upstream_selection())
+        {
+            0 => None,
+            picked => Some(format!("{}{}", upstreams[picked], rest)),
+        }
+    }
+
+    /// Hide the token of the task's rule in a message to be logged or returned.
+    fn redact(&self, task: &Task, message: &str) -> String {
+        redact_token(
+            message,
+            self.token_map.get(&task.rule_id).map(|t| t.as_str()),
+        )
+    }
+
+    pub fn get_cache_for_cache_rule(&self, rule_id: RuleId) -> Option<Arc<RwLock<dyn Cache>>> {
+        self.rule_map.get(&rule_id).map(|tuple| tuple.0.clone())
+    }
+
+    /// The cache of a task: the cache of the `mutable_policy` of its rule
+    /// for mutable keys, otherwise the cache of its rule
+    pub fn get_cache_for_task(&self, task: &Task) -> Option<Arc<RwLock<dyn Cache>>> {
+        if let Some(tenant) = &task.tenant {
+            return self.tenant_cache(tenant, &self.policy_name(task));
+        }
+        if let Some(cache) = self
+            .class_map
+            .get(&task.rule_id)
+            .and_then(|route| route.mutable_cache.as_ref())
+        {
+            if self.key_class(task) == Some(KeyClass::Mutable) {
+                return Some(cache.clone());
+            }
+        }
+        self.get_cache_for_cache_rule(task.rule_id)
+    }
+
+    /// The cache of a policy, if any rule uses it, or the cache
+    /// `<tenant>_<policy>` of a tenant
+    pub fn get_cache_for_policy(&self, policy: &str) -> Option<Arc<RwLock<dyn Cache>>> {
+        if let Some(cache) = self.shared_cache(policy) {
+            return Some(cache);
+        }
+        let (tenant, policy) = tenant::split_cache_id(self.config.tenants(), policy)?;
+        // tenants only have caches of the policies of rules
+        self.shared_cache(policy)?;
+        self.tenant_cache(&tenant.name, policy)
+    }
+
+    /// The shared cache of a policy, if any rule uses it
+    fn shared_cache(&self, policy: &str) -> Option<Arc<RwLock<dyn Cache>>> {
+        let rules = &self.config.rules;
+        if let Some(rule_id) = rules.iter().position(|rule| rule.policy == policy) {
+            return self.get_cache_for_cache_rule(rule_id);
+        }
+        // a policy may only cache the mutable keys of rules
+        let rule_id = rules.iter().position(|rule| {
+            rule.key_classes
+                .as_ref()
+                .and_then(|c| c.mutable_policy.as_deref())
+                == Some(policy)
+        })?;
+        self.class_map.get(&rule_id)?.mutable_cache.clone()
+    }
+
+    /// The cache of `policy` for `tenant`, created on first use
+    fn tenant_cache(&self, tenant: &str, policy: &str) -> Option<Arc<RwLock<dyn Cache>>> {
+        let tenant_caches = self.tenant_caches.as_ref()?;
+        let tenant = self.config.tenants().iter().find(|t| t.name == tenant)?;
+        let id = tenant.cache_id(policy);
+        let mut caches = tenant_caches.caches.lock().unwrap();
+        if let Some(cache) = caches.get(&id) {
+            return Some(cache.clone());
+        }
+        let policy = self
+            .config
+            .policies
+            .iter()
+            .find(|p| p.name == policy)?
+            .for_tenant(tenant);
+        let cache = Self::create_cache_from_rule(
+            &id,
+            std::slice::from_ref(&policy),
+            Some(tenant_caches.redis_client.clone()),
+            &tenant_caches.sled_metadata_path,
+            &tenant_caches.storages,
+        );
+        match cache {
+            Ok(cache) => {
+                info!("created cache {} of tenant {}", id, tenant.name);
+                caches.insert(id, cache.clone());
+                Some(cache)
+            }
+            Err(e) => {
+                error!(
+                    "failed to create cache {} of tenant {}: {}",
+                    id, tenant.name, e
+                );
+                None
+            }
+        }
+    }
+
+    /// The id of the cache of a task, see `get_cache_for_task`, empty if
+    /// unknown
+    fn cache_id(&self, task: &Task) -> String {
+        let policy = self.policy_name(task);
+        match &task.tenant {
+            Some(tenant) => format!("{}_{}", tenant, policy),
+            None => policy,
+        }
+    }
+
+    /// The settings of the cache `cache_id`: its policy, or the policy a
+    /// cache of a tenant is created from, see `Policy::for_tenant`
+    fn cache_policy(&self, cache_id: &str) -> Option<Policy> {
+        let find = |name: &str| self.config.policies.iter().find(|p| p.name == name);
+        if let Some(policy) = find(cache_id) {
+            return Some(policy.clone());
+        }
+        let (tenant, policy) = tenant::split_cache_id(self.config.tenants(), cache_id)?;
+        Some(find(policy)?.for_tenant(tenant))
+    }
+
+    /// Close the caches of all rules and tenants, e.g. on shutdown
+    pub async fn close_caches(&self) {
+        let mutable_caches = self
+            .class_map
+            .values()
+            .filter_map(|route| route.mutable_cache.as_ref());
+        let tenant_caches: Vec<_> = match &self.tenant_caches {
+            Some(tenants) => tenants.caches.lock().unwrap().values().cloned().collect(),
+            None => vec![],
+        };
+        let caches = self.rule_map.values().map(|(cache, _)| cache);
+        for cache in caches.chain(mutable_caches).chain(tenant_caches.iter()) {
+            // rules of the same policy share a cache, closing it again is a no-op
+            cache.write().await.close().await;
+        }
+    }
+
+    /// Entries and bytes of the cache of `policy` by groups of keys. A report
+    /// computed within `usage_report_ttl` secs is reused.
+    pub async fn usage_report(
+        &self,
+        policy: &str,
+        grouping: &Grouping,
+        top: usize,
+    ) -> Result<UsageReport> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let max_age = self
+            .config
+            .usage_report_ttl
+            .unwrap_or(usage::DEFAULT_REPORT_TTL);
+        if let Some(report) = self.usage_reports.get(policy, grouping, top, max_age) {
+            return Ok(report);
+        }
+        info!(
+            "[Admin] scanning {} for usage by {}",
+            policy,
+            grouping.describe()
+        );
+        let report = usage::compute_usage(&cache, policy, grouping, top).await?;
+        self.usage_reports.insert(report.clone(), top);
+        Ok(report)
+    }
+
+    /// Entries an eviction would remove from the cache of `policy` for it to
+    /// fit in `target_size` bytes, from the least recently used one
+    pub async fn preview_eviction(
+        &self,
+        policy: &str,
+        target_size: CacheSizeType,
+    ) -> Result<Vec<EvictedEntry>> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let candidates = cache.read().await.preview_eviction(target_size);
+        candidates
+    }
+
+    /// The hot keys of the cache of `policy`, the hottest first
+    pub async fn hot_keys(&self, policy: &str) -> Result<Vec<HotKey>> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let hot_keys = cache.read().await.hot_keys();
+        hot_keys.ok_or_else(|| {
+            Error::BadRequest(format!("cache {} does not keep hot keys in memory", policy))
+        })
+    }
+
+    /// Entries and bytes of the cache of `policy`, counted by a usage report
+    pub async fn cache_stats(&self, policy: &str) -> Result<CacheStats> {
+        let settings = self
+            .cache_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let report = self.usage_report(policy, &Grouping::Prefix(1), 0).await?;
+        let size_limit = match (settings.typ, &settings.shards) {
+            (PolicyType::Lru, Some(shards)) => Some(
+                shards
+                    .iter()
+                    .filter_map(|shard| bytefmt::parse(&shard.size).ok())
+                    .sum(),
+            ),
+            (PolicyType::Lru, None) => settings
+                .size
+                .as_deref()
+                .and_then(|size| bytefmt::parse(size).ok()),
+            _ => None,
+        };
+        let disk_usage = self.disk_usage.get(policy);
+        let (current_entries, redis_memory, degraded_entries) =
+            match self.get_cache_for_policy(policy) {
+                Some(cache) => {
+                    let cache = cache.read().await;
+                    (
+                        cache.entry_count()?,
+                        cache.redis_memory()?,
+                        cache.degraded_entries(),
+                    )
+                }
+                None => (None, None, None),
+            };
+        Ok(CacheStats {
+            cache: policy.to_string(),
+            policy_type: settings.typ.as_str().to_string(),
+            entries: report.count,
+            bytes: report.bytes,
+            size_limit,
+            current_entries,
+            max_entries: settings.max_entries,
+            redis_memory,
+            max_redis_memory: settings.max_redis_memory(),
+            degraded_entries,
+            disk_bytes_actual: disk_usage.map(|report| report.usage.bytes),
+            disk_temp_bytes: disk_usage.map(|report| report.usage.temp_bytes),
+            accounting_drift_bytes: disk_usage.and_then(|report| report.drift()),
+            generated_at: report.generated_at,
+        })
+    }
+
+    /// A page of about `limit` entries of the cache of `policy`, scanned from
+    /// `cursor`, empty for the first page
+    pub async fn list_entries(
+        &self,
+        policy: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<EntryPage> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let cache = cache.read().await;
+        let (keys, next) = cache.scan_keys(cursor, limit)?;
+        let sizes = cache.entry_sizes(&keys)?;
+        // only digest keys have their url recorded
+        let digests: Vec<String> = keys.iter().filter(|k| is_digest_key(k)).cloned().collect();
+        let mut urls: HashMap<String, String> = digests
+            .iter()
+            .cloned()
+            .zip(cache.entry_urls(&digests)?)
+            .filter_map(|(key, url)| Some((key, url?)))
+            .collect();
+        let entries = keys
+            .into_iter()
+            .zip(sizes)
+            .map(|(key, size)| EntryInfo {
+                url: urls.remove(&key),
+                key,
+                size,
+            })
+            .collect();
+        Ok(EntryPage {
+            cache: policy.to_string(),
+            entries,
+            next_cursor: Some(next).filter(|next| !next.is_empty()),
+        })
+    }
+
+    /// Mark the entry of `key` in the LRU cache of `policy` as just used,
+    /// without counting a hit, so that it is evicted last
+    pub async fn pin(&self, principal: &str, policy: &str, key: &str) -> Result<PinResult> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let is_lru = self
+            .cache_policy(policy)
+            .map_or(false, |p| p.typ == PolicyType::Lru);
+        if !is_lru {
+            return Err(Error::BadRequest(
+                "only entries of LRU caches can be pinned".to_string(),
+            ));
+        }
+        if !cache.read().await.protect(key)? {
+            return Err(Error::NotFound(format!(
+                "entry {} of cache {}",
+                key, policy
+            )));
+        }
+        let target = format!("{}/{}", policy, key);
+        info!("[Admin] {} pinned by {}", target, principal);
+        if let Some(audit) = &self.audit {
+            let entry = AuditEntry {
+                timestamp: util::now(),
+                principal: principal.to_string(),
+                operation: "pin".to_string(),
+                targets: vec![target],
+                outcome: Outcome::Success,
+            };
+            if let Err(e) = audit.record(&entry) {
+                error!("failed to record pin of {}/{}: {}", policy, key, e);
+            }
+        }
+        Ok(PinResult {
+            cache: policy.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    /// Status of the upstreams fetched from recently, see `UpstreamHealth`
+    pub fn upstream_report(&self) -> Vec<UpstreamReport> {
+        self.upstream_health.report()
+    }
+
+    /// Probe the upstreams of rules that no request has fetched from lately,
+    /// so that their status stays current, then export the status of all
+    /// upstreams as gauges
+    pub async fn probe_idle_upstreams(&self) {
+        for (origin, rule_id) in check::upstream_origins(self) {
+            if !self.upstream_health.is_idle(&origin) {
+                continue;
+            }
+            let task = Task {
+                rule_id,
+                url: origin.clone(),
+                key: None,
+                tenant: None,
+                simple_json: false,
+            };
+            let started = Instant::now();
+            let result = check::probe_upstream(&self.upstream_client(&task), &origin).await;
+            if let Err(e) = &result {
+                warn!("[UPSTREAM] probe of idle upstream {} failed: {}", origin, e);
+            }
+            self.upstream_health
+                .record_probe(&origin, started.elapsed(), &result);
+        }
+        self.upstream_health.export_gauges();
+    }
+
+    /// Run the protective refreshes of caches that are due
+    pub async fn run_protective_refreshes(&self) {
+        for policy in &self.config.policies {
+            let options = match &policy.protective_refresh {
+                Some(options) => options,
+                None => continue,
+            };
+            let interval = options.interval.unwrap_or(protect::DEFAULT_INTERVAL);
+            if !self
+                .refresh_schedule
+                .is_due(&policy.name, Duration::from_secs(interval))
+            {
+                continue;
+            }
+            if let Err(e) = self.protective_refresh(&policy.name, options).await {
+                error!("[PROTECT] cache {} failed: {}", policy.name, e);
+            }
+        }
+    }
+
+    /// Walk the filesystem storages of the caches due for it, and compare
+    /// their bytes with those recorded by their metadata, see `diskusage`
+    pub async fn run_disk_usage_walks(&self) {
+        let options = match &self.config.disk_usage {
+            Some(options) => options,
+            None => return,
+        };
+        let mut policy_dirs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        // the directories are checked when settings are loaded
+        for (policy, dir) in self.config.policy_dirs().unwrap_or_default() {
+            policy_dirs.entry(policy).or_default().push(dir);
+        }
+        let interval = options.interval();
+        for (policy, dirs) in policy_dirs {
+            if !self.disk_usage.is_due(&policy, interval, util::now()) {
+                continue;
+            }
+            let usage = diskusage::measure(&dirs).await;
+            // entries put during the walk are drift until the next one
+            let accounted_bytes = match self.get_cache_for_policy(&policy) {
+                Some(cache) => cache.read().await.total_size(),
+                None => None,
+            };
+            let report = DiskUsageReport {
+                usage,
+                accounted_bytes,
+                measured_at: util::now(),
+            };
+            self.disk_usage.insert(&policy, report);
+            info!(
+                "[DISK USAGE] cache {}: {} files of {} bytes, {} temporary files of {} bytes",
+                policy, usage.files, usage.bytes, usage.temp_files, usage.temp_bytes
+            );
+            let label = policy.clone();
+            gauge!(metric::GAUGE_DISK_BYTES_ACTUAL, usage.bytes as f64, "cache" => label);
+            let drift = match report.drift() {
+                Some(drift) => drift,
+                None => continue,
+            };
+            let label = policy.clone();
+            gauge!(metric::GAUGE_ACCOUNTING_DRIFT, drift as f64, "cache" => label);
+            if drift.unsigned_abs() > options.drift_threshold() {
+                warn!(
+                    "[DISK USAGE] cache {} has {} bytes on disk but {} recorded, a drift of {} \
+                     bytes: a metadata backup restored with --force drops the entries of missing \
+                     files and recounts the size",
+                    policy,
+                    usage.bytes,
+                    accounted_bytes.unwrap_or(0),
+                    drift
+                );
+            }
+        }
+    }
+
+    /// Mark the frequently hit entries among the least recently used ones of
+    /// the cache of `policy` as used, so that they survive the next evictions
+    pub async fn protective_refresh(
+        &self,
+        policy: &str,
+        options: &ProtectiveRefresh,
+    ) -> Result<ProtectionReport> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let thresholds = Thresholds::from(options);
+        let entries = cache
+            .read()
+            .await
+            .coldest_entries(thresholds.cold_percent)?;
+        let mut report = ProtectionReport {
+            inspected: entries.len(),
+            ..Default::default()
+        };
+        let (candidates, unprotected) = protect::select(entries, &thresholds);
+        report.unprotected = unprotected.len();
+        for entry in candidates {
+            if options.revalidate.unwrap_or(false) && !self.revalidate(policy, &entry).await {
+                report.unprotected += 1;
+                continue;
+            }
+            // the entry may be evicted in the meantime
+            if cache.read().await.protect(&entry.key)? {
+                trace!("[PROTECT] {} ({} hits)", entry.key, entry.hits);
+                report.protected += 1;
+                report.protected_bytes += entry.size;
+            }
+        }
+        counter!(metric::CNT_PROTECTED_ENTRIES, report.protected as u64, "cache" => policy.to_string());
+        counter!(metric::CNT_PROTECTED_BYTES, report.protected_bytes, "cache" => policy.to_string());
+        counter!(metric::CNT_UNPROTECTED_ENTRIES, report.unprotected as u64, "cache" => policy.to_string());
+        info!(
+            "[PROTECT] cache {}: {} of {} cold entries protected ({} bytes), {} left to be evicted",
+            policy, report.protected, report.inspected, report.protected_bytes, report.unprotected
+        );
+        Ok(report)
+    }
+
+    /// Ask the upstream whether a cached entry has changed since it was last
+    /// served, with a conditional GET. Returns whether the entry is worth
+    /// keeping: entries that changed or are gone are fetched again if needed.
+    /// Entries whose upstream cannot tell are kept.
+    async fn revalidate(&self, policy: &str, entry: &LruEntryStats) -> bool {
+        let rule_id = self
+            .config
+            .rules
+            .iter()
+            .position(|rule| rule.policy == policy);
+        let (rule_id, url) = match (rule_id, upstream_url_of_key(&entry.key)) {
+            (Some(rule_id), Some(url)) => (rule_id, url),
+            // e.g. keys of `path_pattern` rules are not urls
+            _ => return true,
+        };
+        let task = Task {
+            rule_id,
+            url,
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let resp = self
+            .upstream_client(&task)
+            .get(&task.url)
+            .header(
+                reqwest::header::IF_MODIFIED_SINCE,
+                util::http_date(entry.atime),
+            )
+            .send()
+            .await;
+        match resp.map(|resp| resp.status()) {
+            Ok(reqwest::StatusCode::NOT_MODIFIED) => true,
+            Ok(status)
+                if status == reqwest::StatusCode::OK
+                    || status == reqwest::StatusCode::NOT_FOUND
+                    || status == reqwest::StatusCode::GONE =>
+            {
+                debug!("[PROTECT] {} not protected: {}", entry.key, status);
+                false
+            }
+            Ok(status) => {
+                debug!(
+                    "[PROTECT] {} kept, upstream responded {}",
+                    entry.key, status
+                );
+                true
+            }
+            Err(e) => {
+                warn!("[PROTECT] {} kept, failed to revalidate: {}", entry.key, e);
+                true
+            }
+        }
+    }
+
+    /// Start a job removing the entries of the cache of `policy` whose keys
+    /// match `matcher`. `target` describes the matched keys in the job status
+    /// and the audit log. Returns the id of the job.
+    pub fn spawn_purge(
+        &self,
+        principal: &str,
+        policy: &str,
+        matcher: Regex,
+        target: &str,
+    ) -> Result<JobId> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        let targets = vec![format!("{}/{}", policy, target)];
+        let id = self.jobs.start(principal, "purge", targets.clone());
+        let jobs = self.jobs.clone();
+        let audit = self.audit.clone();
+        let principal = principal.to_string();
+        tokio::spawn(async move {
+            info!("[Admin] purge job #{} started: {:?}", id, targets);
+            let result = purge_matching(&cache, &matcher, |scanned, removed| {
+                jobs.report(id, scanned, removed)
+            })
+            .await;
+            let status = jobs.finish(id, &result);
+            match (&result, &status) {
+                (Ok(_), Some(status)) => info!(
+                    "[Admin] purge job #{} completed: {} of {} keys removed",
+                    id, status.removed, status.scanned
+                ),
+                (Err(e), _) => error!("[Admin] purge job #{} failed: {}", id, e),
+                _ => {}
+            }
+            if let Some(audit) = audit {
+                let outcome = match result {
+                    Ok(_) => Outcome::Success,
+                    Err(e) => Outcome::Failure(e.to_string()),
+                };
+                let entry = AuditEntry {
+                    timestamp: util::now(),
+                    principal,
+                    operation: "purge".to_string(),
+                    targets,
+                    outcome,
+                };
+                if let Err(e) = audit.record(&entry) {
+                    error!("failed to record purge job #{}: {}", id, e);
                 }
+            }
+        });
+        Ok(id)
+    }
+
+    /// Remove all the entries of the cache of `policy` and their files, while
+    /// it keeps serving. Returns what was removed.
+    pub async fn purge_all(&self, principal: &str, policy: &str) -> Result<PurgeReport> {
+        let cache = self
+            .get_cache_for_policy(policy)
+            .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+        info!(
+            "[Admin] purge of all entries of {} started by {}",
+            policy, principal
+        );
+        let result = purge_all(&cache).await;
+        match &result {
+            Ok(report) => info!(
+                "[Admin] purged all entries of {}: {} entries, {} bytes",
+                policy, report.entries, report.bytes
+            ),
+            Err(e) => error!("[Admin] purge of all entries of {} failed: {}", policy, e),
+        }
+        if let Some(audit) = &self.audit {
+            let outcome = match &result {
+                Ok(_) => Outcome::Success,
+                Err(e) => Outcome::Failure(e.to_string()),
             };
-            Self::taskset_remove(task_list_ptr.clone(), &task_clone).await;
-            Self::taskset_len(task_list_ptr).await;
+            let entry = AuditEntry {
+                timestamp: util::now(),
+                principal: principal.to_string(),
+                operation: "purge_all".to_string(),
+                targets: vec![policy.to_string()],
+                outcome,
+            };
+            if let Err(e) = audit.record(&entry) {
+                error!("failed to record purge of all entries of {}: {}", policy, e);
+            }
+        }
+        result
+    }
+
+    /// Start a job fetching the files of `tasks` that are not cached yet, as
+    /// low priority background tasks. The job completes once they are all
+    /// queued, the fetches in progress are listed by `running_tasks`.
+    /// Returns the id of the job.
+    pub fn spawn_warmup(&self, principal: &str, tasks: Vec<Task>) -> JobId {
+        let targets: Vec<String> = tasks.iter().map(|task| task.url.clone()).collect();
+        let id = self.jobs.start(principal, "warmup", targets.clone());
+        let tm = self.clone();
+        let principal = principal.to_string();
+        tokio::spawn(async move {
+            info!("[Admin] warmup job #{} started: {} files", id, tasks.len());
+            let cached = tm.cached_tasks(&tasks).await;
+            for (task, cached) in tasks.into_iter().zip(cached) {
+                if !cached {
+                    tm.spawn_task(task, Priority::Low).await;
+                    tm.jobs.report_queued(id, 1);
+                }
+                tm.jobs.report(id, 1, 0);
+            }
+            if let Some(status) = tm.jobs.finish(id, &Ok(())) {
+                info!(
+                    "[Admin] warmup job #{} completed: {} of {} files queued",
+                    id, status.queued, status.scanned
+                );
+            }
+            if let Some(audit) = &tm.audit {
+                let entry = AuditEntry {
+                    timestamp: util::now(),
+                    principal,
+                    operation: "warmup".to_string(),
+                    targets,
+                    outcome: Outcome::Success,
+                };
+                if let Err(e) = audit.record(&entry) {
+                    error!("failed to record warmup job #{}: {}", id, e);
+                }
+            }
         });
+        id
+    }
+
+    /// The task of a `GET` of the mirror path serving the upstream `url`, see
+    /// `RuleMatcher::task_for_url`. `None` if no rule serves it.
+    pub fn task_for_url(&self, url: &str) -> Option<Task> {
+        self.rule_matcher.task_for_url(url).map(|(task, _)| task)
+    }
+
+    /// Fetch the files of `spec` into the caches with a high priority, and
+    /// wait for them to be cached. Files already cached are left untouched.
+    /// Run by `POST /admin/fetch`, see `FetchSpec` for the specs.
+    pub async fn fetch(&self, principal: &str, spec: &str) -> Result<FetchReport> {
+        let parsed: FetchSpec = spec.parse()?;
+        let no_rule = |what: &str| Error::BadRequest(format!("no rule matches {}", what));
+        let mut files = Vec::new();
+        match &parsed {
+            FetchSpec::Url(url) => {
+                let task = self
+                    .task_for_url(url)
+                    .ok_or_else(|| no_rule(url.as_str()))?;
+                files.push(self.fetch_task(&task).await);
+            }
+            FetchSpec::Pypi { project, version } => {
+                let (index, _) = self
+                    .rule_matcher
+                    .pypi_index_task(project)
+                    .ok_or_else(|| no_rule(&format!("the index of {}", project)))?;
+                let fetched = self.fetch_task(&index).await;
+                let failed = matches!(fetched.outcome, FetchOutcome::Failed(_));
+                files.push(fetched);
+                if let (Some(version), false) = (version, failed) {
+                    let links = self.distribution_links(&index, project, version).await;
+                    if links.is_empty() {
+                        return Err(Error::NotFound(format!(
+                            "files of {}=={} in {}",
+                            project, version, index.url
+                        )));
+                    }
+                    for link in links {
+                        files.push(match self.task_for_url(link.as_str()) {
+                            Some(task) => self.fetch_task(&task).await,
+                            None => FetchedFile {
+                                url: link.to_string(),
+                                key: None,
+                                size: None,
+                                outcome: FetchOutcome::Failed("no rule matches".to_string()),
+                            },
+                        });
+                    }
+                }
+            }
+            FetchSpec::Anaconda { channel, path } => {
+                let task = match self.rule_matcher.conda_task(channel, path) {
+                    Some((task, _)) => task,
+                    None => {
+                        let url = FetchSpec::anaconda_url(channel, path);
+                        self.task_for_url(&url).ok_or_else(|| no_rule(&url))?
+                    }
+                };
+                files.push(self.fetch_task(&task).await);
+            }
+        }
+        let report = FetchReport {
+            spec: spec.to_string(),
+            files,
+        };
+        info!(
+            "[Admin] fetch of {} by {}: {} files, {} failed",
+            spec,
+            principal,
+            report.files.len(),
+            report.failed()
+        );
+        if let Some(audit) = &self.audit {
+            let outcome = match report.failed() {
+                0 => Outcome::Success,
+                failed => Outcome::Failure(format!("{} files failed", failed)),
+            };
+            let entry = AuditEntry {
+                timestamp: util::now(),
+                principal: principal.to_string(),
+                operation: "fetch".to_string(),
+                targets: vec![spec.to_string()],
+                outcome,
+            };
+            if let Err(e) = audit.record(&entry) {
+                error!("failed to record fetch of {}: {}", spec, e);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Fetch the file of `task` unless it is cached, and wait for it to be
+    /// cached. A task already running, e.g. for a request, is waited for
+    /// instead of being spawned again.
+    async fn fetch_task(&self, task: &Task) -> FetchedFile {
+        let key = task.to_key();
+        let file = |size, outcome| FetchedFile {
+            url: task.url.clone(),
+            key: Some(key.clone()),
+            size,
+            outcome,
+        };
+        if let Some(size) = self.cached_file_size(task, &key).await {
+            return file(size, FetchOutcome::Cached);
+        }
+        let refused = if self.get_cache_for_task(task).is_none() || self.is_no_cache(task) {
+            Some("the policy of the rule caches nothing")
+        } else if self.cache_mode(task) == CacheMode::ReadOnly {
+            Some("the rule is read-only")
+        } else if self.is_offline(task.rule_id) {
+            Some("the rule is offline")
+        } else {
+            None
+        };
+        if let Some(reason) = refused {
+            return file(None, FetchOutcome::Failed(reason.to_string()));
+        }
+        match self.spawn_task(task.clone(), Priority::High).await {
+            Some(handle) => {
+                let _ = handle.await;
+            }
+            None => {
+                let deadline = Instant::now() + self.max_task_duration();
+                while self.taskset_contains(task).await && Instant::now() < deadline {
+                    tokio::time::sleep(FETCH_POLL_INTERVAL).await;
+                }
+            }
+        }
+        match self.cached_file_size(task, &key).await {
+            Some(size) => file(size, FetchOutcome::Fetched),
+            None => file(
+                None,
+                FetchOutcome::Failed("not cached, see the logs".to_string()),
+            ),
+        }
+    }
+
+    /// Size of the cached file of `key`, also in caches that do not record
+    /// sizes. `None` if it is not cached, `Some(None)` if its size is unknown.
+    async fn cached_file_size(&self, task: &Task, key: &str) -> Option<Option<u64>> {
+        if let Some(size) = self.cached_size(task).await {
+            return Some(Some(size));
+        }
+        let cache = self.get_cache_for_task(task)?;
+        let data = cache.read().await.get(key).await?;
+        Some(match data {
+            CacheData::ByteStream(_, size) => size,
+            data => Some(data.len()),
+        })
+    }
+
+    /// The distributions of `version` of `project` linked by its cached index
+    async fn distribution_links(
+        &self,
+        index: &Task,
+        project: &str,
+        version: &str,
+    ) -> Vec<reqwest::Url> {
+        let page = match reqwest::Url::parse(&index.url) {
+            Ok(page) => page,
+            Err(_) => return Vec::new(),
+        };
+        let data = match self.get(index, &index.to_key()).await {
+            Some(data) => data,
+            None => return Vec::new(),
+        };
+        let content = String::from_utf8_lossy(&data.into_vec_u8().await).into_owned();
+        fetch::distribution_links(&content, &page, project, version)
+    }
+
+    /// Whether the rule only serves cache hits, and never contacts its upstream
+    pub fn is_offline(&self, rule_id: RuleId) -> bool {
+        let rule = match self.config.rules.get(rule_id) {
+            Some(rule) => rule,
+            None => return false,
+        };
+        self.offline.overrides().is_offline(
+            &rule_label(rule),
+            rule.options.as_ref().and_then(|options| options.offline),
+            self.config.offline,
+        )
+    }
+
+    /// Status of responses to cache misses in offline mode
+    fn offline_miss_status(&self) -> warp::http::StatusCode {
+        self.config
+            .offline_miss_status
+            .and_then(|status| warp::http::StatusCode::from_u16(status).ok())
+            .unwrap_or(warp::http::StatusCode::NOT_FOUND)
+    }
+
+    /// Whether each rule is offline, and the overrides set at runtime
+    pub fn offline_status(&self) -> OfflineStatus {
+        OfflineStatus {
+            rules: self
+                .config
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(idx, rule)| (rule_label(rule), self.is_offline(idx)))
+                .collect(),
+            overrides: self.offline.overrides(),
+        }
+    }
+
+    /// Override the offline mode of the rule named `rule`, or of all rules if
+    /// `None`. `enabled` is `None` to fall back to the settings.
+    pub fn set_offline(
+        &self,
+        principal: &str,
+        rule: Option<&str>,
+        enabled: Option<bool>,
+    ) -> Result<OfflineStatus> {
+        if let Some(rule) = rule {
+            if !self.config.rules.iter().any(|r| rule_label(r) == rule) {
+                return Err(Error::NotFound(format!("rule {}", rule)));
+            }
+        }
+        self.offline.set(rule, enabled);
+        let target = rule.unwrap_or("*").to_string();
+        info!("[Admin] offline mode of {} set to {:?}", target, enabled);
+        if let Some(audit) = &self.audit {
+            let entry = AuditEntry {
+                timestamp: util::now(),
+                principal: principal.to_string(),
+                operation: "offline".to_string(),
+                targets: vec![target],
+                outcome: Outcome::Success,
+            };
+            if let Err(e) = audit.record(&entry) {
+                error!("failed to record offline mode change: {}", e);
+            }
+        }
+        Ok(self.offline_status())
+    }
+
+    /// Whether requests of the rule are served from its upstream without the
+    /// caches, see `BypassSwitch`
+    pub fn is_bypassed(&self, rule_id: RuleId) -> bool {
+        match self.config.rules.get(rule_id) {
+            Some(rule) => self.bypass.is_bypassed(&rule_label(rule)),
+            None => false,
+        }
+    }
+
+    /// Whether each rule bypasses the caches
+    pub fn bypass_status(&self) -> BypassStatus {
+        BypassStatus {
+            global: self.bypass.is_global(),
+            rules: self
+                .config
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(idx, rule)| (rule_label(rule), self.is_bypassed(idx)))
+                .collect(),
+            persisted: self.bypass_store.is_some(),
+        }
+    }
+
+    /// Switch the cache bypass of the rule named `rule`, or of all rules if
+    /// `None`. It is kept in redis first with `persist_bypass`, so that a
+    /// switch that other instances miss fails.
+    pub fn set_bypass(
+        &self,
+        principal: &str,
+        rule: Option<&str>,
+        enabled: bool,
+    ) -> Result<BypassStatus> {
+        if let Some(rule) = rule {
+            if !self.config.rules.iter().any(|r| rule_label(r) == rule) {
+                return Err(Error::NotFound(format!("rule {}", rule)));
+            }
+        }
+        if let Some(client) = &self.bypass_store {
+            BypassSwitch::persist(client, rule, enabled)?;
+        }
+        self.bypass.set(rule, enabled);
+        let target = rule.unwrap_or("*").to_string();
+        warn!("[Admin] cache bypass of {} set to {}", target, enabled);
+        if let Some(audit) = &self.audit {
+            let entry = AuditEntry {
+                timestamp: util::now(),
+                principal: principal.to_string(),
+                operation: "bypass".to_string(),
+                targets: vec![target],
+                outcome: Outcome::Success,
+            };
+            if let Err(e) = audit.record(&entry) {
+                error!("failed to record cache bypass change: {}", e);
+            }
+        }
+        Ok(self.bypass_status())
+    }
+
+    /// Read the cache bypass kept in redis again, if `persist_bypass`
+    pub fn sync_bypass(&self) {
+        if let Some(client) = &self.bypass_store {
+            if let Err(e) = self.bypass.load(client) {
+                warn!("failed to read the cache bypass from redis: {}", e);
+            }
+        }
+    }
+
+    /// Tell that the LRU caches with redis metadata are in use, so that
+    /// `migrate-cache-id` does not rename them meanwhile
+    pub fn write_heartbeats(&self) {
+        if let Some(client) = &self.heartbeats {
+            let ids = self.config.redis_lru_cache_ids();
+            if let Err(e) = migrate::write_heartbeats(client, &ids) {
+                warn!("failed to write the heartbeats of the caches: {}", e);
+            }
+        }
+    }
+
+    fn snapshot_store(&self) -> Result<&SnapshotStore> {
+        self.snapshots
+            .as_deref()
+            .ok_or_else(|| Error::BadRequest("snapshots need snapshot_storage".to_string()))
+    }
+
+    /// Take a snapshot of the pages cached by the rules of `request`, which
+    /// must be of TTL policies. Pages expiring while the caches are scanned
+    /// may be left out.
+    pub async fn create_snapshot(
+        &self,
+        principal: &str,
+        request: &SnapshotRequest,
+    ) -> Result<SnapshotInfo> {
+        let store = self.snapshot_store()?;
+        let now = util::now();
+        let id = request
+            .id
+            .clone()
+            .unwrap_or_else(|| snapshot::default_id(now));
+        snapshot::validate_id(&id)?;
+        if request.rules.is_empty() {
+            return Err(Error::BadRequest("no rules to snapshot".to_string()));
+        }
+        let mut rule_ids = HashSet::new();
+        for name in &request.rules {
+            let rule_id = self
+                .config
+                .rules
+                .iter()
+                .position(|rule| rule.name.as_ref() == Some(name))
+                .ok_or_else(|| Error::NotFound(format!("rule {}", name)))?;
+            let policy = &self.config.rules[rule_id].policy;
+            let is_ttl = self
+                .cache_policy(policy)
+                .map_or(false, |p| p.typ == PolicyType::Ttl);
+            if !is_ttl {
+                return Err(Error::BadRequest(format!(
+                    "rule {}: only pages of TTL policies are snapshotted",
+                    name
+                )));
+            }
+            rule_ids.insert(rule_id);
+        }
+        let mut info = SnapshotInfo {
+            id: id.clone(),
+            created_at: now,
+            principal: principal.to_string(),
+            rules: request.rules.clone(),
+            entries: 0,
+            bytes: 0,
+        };
+        store.reserve(&info)?;
+        info!(
+            "[Admin] snapshot {} of {:?} started by {}",
+            id, request.rules, principal
+        );
+        let mut entries = BTreeMap::new();
+        let result = match self
+            .copy_snapshot_pages(store, &id, &rule_ids, &mut entries)
+            .await
+        {
+            Ok(()) => {
+                info.entries = entries.len();
+                info.bytes = entries.values().map(|entry| entry.size).sum();
+                store.save(&info, &entries).map(|_| info)
+            }
+            Err(e) => Err(e),
+        };
+        match &result {
+            Ok(info) => info!(
+                "[Admin] snapshot {} taken: {} pages, {} bytes",
+                id, info.entries, info.bytes
+            ),
+            Err(e) => {
+                error!("[Admin] snapshot {} failed: {}", id, e);
+                store.remove_pages(&id, &entries).await;
+                if let Err(e) = store.delete(&id).await {
+                    error!("failed to remove the failed snapshot {}: {}", id, e);
+                }
+            }
+        }
+        self.record_snapshot_operation(principal, "snapshot", &id, &result);
+        result
+    }
+
+    /// Copy the pages of `rule_ids` into the snapshot `id`, by key
+    async fn copy_snapshot_pages(
+        &self,
+        store: &SnapshotStore,
+        id: &str,
+        rule_ids: &HashSet<RuleId>,
+        entries: &mut BTreeMap<String, snapshot::SnapshotEntry>,
+    ) -> Result<()> {
+        // rules of a policy share its cache
+        let policies: HashSet<&str> = rule_ids
+            .iter()
+            .map(|rule_id| self.config.rules[*rule_id].policy.as_str())
+            .collect();
+        for policy in policies {
+            let cache = self
+                .get_cache_for_policy(policy)
+                .ok_or_else(|| Error::NotFound(format!("cache {}", policy)))?;
+            let all_rules = self
+                .config
+                .rules
+                .iter()
+                .enumerate()
+                .filter(|(_, rule)| rule.policy == policy)
+                .all(|(rule_id, _)| rule_ids.contains(&rule_id));
+            let mut cursor = String::new();
+            loop {
+                let (keys, next) = cache.read().await.scan_keys(&cursor, SNAPSHOT_BATCH_SIZE)?;
+                for key in keys {
+                    if entries.contains_key(&key) || !self.is_key_of(&key, rule_ids, all_rules) {
+                        continue;
+                    }
+                    // expired in the meantime
+                    let data = match cache.read().await.get(&key).await {
+                        Some(data) => data,
+                        None => continue,
+                    };
+                    let entry = store.put_page(id, data).await?;
+                    entries.insert(key, entry);
+                }
+                if next.is_empty() {
+                    break;
+                }
+                cursor = next;
+                tokio::task::yield_now().await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the entry of `key` is cached by one of `rule_ids`, told by its
+    /// upstream url. Keys that are not urls are of the rules if `all_rules`
+    /// of their cache are.
+    fn is_key_of(&self, key: &str, rule_ids: &HashSet<RuleId>, all_rules: bool) -> bool {
+        let key = key.strip_suffix(pep691::JSON_KEY_SUFFIX).unwrap_or(key);
+        match upstream_url_of_key(key).and_then(|url| self.task_for_url(&url)) {
+            Some(task) => rule_ids.contains(&task.rule_id),
+            None => all_rules,
+        }
+    }
+
+    /// All snapshots, from the oldest
+    pub fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        self.snapshot_store()?.list()
+    }
+
+    /// A snapshot and the hashes of its pages
+    pub fn snapshot_manifest(&self, id: &str) -> Result<SnapshotManifest> {
+        self.snapshot_store()?
+            .manifest(id)?
+            .ok_or_else(|| Error::NotFound(format!("snapshot {}", id)))
+    }
+
+    /// Remove a snapshot and its pages
+    pub async fn delete_snapshot(&self, principal: &str, id: &str) -> Result<SnapshotInfo> {
+        let result = self
+            .snapshot_store()?
+            .delete(id)
+            .await
+            .and_then(|info| info.ok_or_else(|| Error::NotFound(format!("snapshot {}", id))));
+        if let Ok(info) = &result {
+            info!(
+                "[Admin] snapshot {} deleted by {}: {} pages",
+                id, principal, info.entries
+            );
+        }
+        self.record_snapshot_operation(principal, "delete_snapshot", id, &result);
+        result
+    }
+
+    fn record_snapshot_operation(
+        &self,
+        principal: &str,
+        operation: &str,
+        id: &str,
+        result: &Result<SnapshotInfo>,
+    ) {
+        if let Some(audit) = &self.audit {
+            let outcome = match result {
+                Ok(_) => Outcome::Success,
+                Err(e) => Outcome::Failure(e.to_string()),
+            };
+            let entry = AuditEntry {
+                timestamp: util::now(),
+                principal: principal.to_string(),
+                operation: operation.to_string(),
+                targets: vec![id.to_string()],
+                outcome,
+            };
+            if let Err(e) = audit.record(&entry) {
+                error!("failed to record {} of {}: {}", operation, id, e);
+            }
+        }
+    }
+
+    /// The page of `task` in the snapshot `id`. `None` if the rule of the
+    /// task is not snapshotted, and files are served by the rule as usual.
+    /// Pages the snapshot does not record are not found: they are never
+    /// fetched from upstream.
+    pub async fn snapshot_page(&self, id: &str, task: &Task) -> Result<Option<CacheData>> {
+        let store = self.snapshot_store()?;
+        let info = store
+            .get(id)?
+            .ok_or_else(|| Error::NotFound(format!("snapshot {}", id)))?;
+        let rule = self.config.rules.get(task.rule_id);
+        match rule.and_then(|rule| rule.name.as_ref()) {
+            Some(name) if info.rules.contains(name) => {}
+            _ => return Ok(None),
+        }
+        let key = task.to_key();
+        match store.read_page(id, &key).await? {
+            Some(data) => Ok(Some(data)),
+            None => Err(Error::NotFound(format!("{} in snapshot {}", key, id))),
+        }
+    }
+
+    fn rule_label(&self, task: &Task) -> String {
+        self.config
+            .rules
+            .get(task.rule_id)
+            .map_or_else(|| "unnamed_rules".to_string(), rule_label)
+    }
+
+    /// Names of headers that make a response of the task uncacheable, `None`
+    /// if the task's rule caches all responses
+    fn uncacheable_headers(&self, task: &Task) -> Option<Vec<String>> {
+        let force_cache = self
+            .config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| rule.options.as_ref())
+            .and_then(|options| options.force_cache)
+            .unwrap_or(false);
+        if force_cache {
+            return None;
+        }
+        Some(self.config.uncacheable_headers.clone().unwrap_or_default())
+    }
+
+    fn is_cacheable(&self, task: &Task, res: &reqwest::Response) -> bool {
+        let headers = self.uncacheable_headers(task);
+        !is_uncacheable(res, headers.as_deref(), &self.rule_label(task), task)
+    }
+
+    /// Whether the policy of the task's rule is `NONE`
+    fn is_no_cache(&self, task: &Task) -> bool {
+        self.config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| self.config.policies.iter().find(|p| p.name == rule.policy))
+            .map_or(false, |policy| policy.typ == PolicyType::NoCache)
+    }
+
+    /// Whether the policy of the task's rule is `NONE` with a micro-cache
+    fn has_micro_cache(&self, task: &Task) -> bool {
+        self.config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| self.config.policies.iter().find(|p| p.name == rule.policy))
+            .map_or(false, |policy| policy.micro_cache.is_some())
+    }
+
+    fn cache_mode(&self, task: &Task) -> CacheMode {
+        self.config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| rule.cache_mode)
+            .unwrap_or_default()
+    }
+
+    /// Options of the client of a rule's upstream
+    fn client_config(&self, rule_id: RuleId) -> util::ClientConfig {
+        let options = self
+            .config
+            .rules
+            .get(rule_id)
+            .and_then(|rule| rule.options.as_ref());
+        let http = options
+            .and_then(|options| options.http_client.as_ref())
+            .or_else(|| self.config.http_client.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        let mut config = util::ClientConfig::new(http);
+        if let Some(policy) = options.and_then(|options| options.redirect.as_ref()) {
+            config.max_hops = policy.max_hops.unwrap_or(config.max_hops);
+            config.cross_host = policy.cross_host.unwrap_or(config.cross_host);
+        }
+        // cached redirects are never followed
+        if let Some(true) = options.and_then(|options| options.cache_redirects) {
+            config.max_hops = 0;
+        }
+        config
+    }
+
+    /// The client requesting the upstream of the task
+    pub fn upstream_client(&self, task: &Task) -> reqwest::Client {
+        match self.client_map.get(&task.rule_id) {
+            Some(client) => client.clone(),
+            // e.g. rules added without reloading the config
+            None => util::build_client(&self.client_config(task.rule_id)).unwrap(),
+        }
+    }
+
+    /// Whether the task fetches a binary package, judging from its key
+    pub fn is_binary_package(&self, task: &Task) -> bool {
+        let key = task.to_key();
+        let options = self
+            .config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| rule.options.as_ref());
+        match options.and_then(|options| options.binary_suffixes.as_ref()) {
+            Some(suffixes) => suffixes.iter().any(|suffix| key.ends_with(suffix.as_str())),
+            None => DEFAULT_BINARY_SUFFIXES
+                .iter()
+                .any(|suffix| key.ends_with(suffix)),
+        }
+    }
+
+    pub fn get_task_size_limit(&self, task: &Task) -> usize {
+        self.rule_map.get(&task.rule_id).map_or(0, |rule| rule.1)
+    }
+}
+
+/// Whether an upstream response is an html page
+fn is_html_response(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("text/html"))
+}
+
+/// Why an upstream response may be personalized and must not be cached:
+/// `Set-Cookie`, `Cache-Control: private` or `no-store`, or one of `headers`
+fn uncacheable_reason(res: &reqwest::Response, headers: &[String]) -> Option<String> {
+    let res_headers = res.headers();
+    if res_headers.contains_key(reqwest::header::SET_COOKIE) {
+        return Some("Set-Cookie".to_string());
+    }
+    for value in res_headers.get_all(reqwest::header::CACHE_CONTROL) {
+        for directive in value.to_str().unwrap_or_default().split(',') {
+            let directive = directive.trim();
+            let name = directive.split('=').next().unwrap_or_default();
+            if name.eq_ignore_ascii_case("private") || name.eq_ignore_ascii_case("no-store") {
+                return Some(format!("Cache-Control: {}", directive));
+            }
+        }
+    }
+    headers
+        .iter()
+        .find(|name| res_headers.contains_key(name.as_str()))
+        .cloned()
+}
+
+/// Whether a response must not be cached, it is counted and logged if so.
+/// `uncacheable_headers` is `None` if the rule caches all responses.
+fn is_uncacheable(
+    res: &reqwest::Response,
+    uncacheable_headers: Option<&[String]>,
+    rule_label: &str,
+    task: &Task,
+) -> bool {
+    match uncacheable_headers.and_then(|headers| uncacheable_reason(res, headers)) {
+        Some(reason) => {
+            debug!("[TASK] not caching a response with {}: {:?}", reason, task);
+            increment_counter!(metric::CNT_UNCACHEABLE, "rule" => rule_label.to_string());
+            true
+        }
+        None => false,
+    }
+}
+
+type TeeSender = mpsc::Sender<Result<Bytes>>;
+
+/// How the response of a task is written to the cache
+struct EntryOptions {
+    rewrites: Option<Vec<Rewrite>>,
+    /// Secs the entry expires after instead of the TTL of the policy, if set
+    ttl: Option<u64>,
+    /// Secs the entry expires after if it is a directory listing, if listings
+    /// are rewritten
+    dir_listing_ttl: Option<u64>,
+    /// Most bytes of a response read whole to be rewritten
+    max_rewrite_body: u64,
+    /// Label of the rule in metrics
+    rule: String,
+    /// Cache of the files whose hashes are appended to the links of html
+    /// pages, see `Options::hash_links_from`
+    hash_source: Option<Arc<RwLock<dyn Cache>>>,
+    /// Called with the metadata files advertised by an index page once it is
+    /// read, see `Options::prefetch_metadata`
+    metadata_prefetch: Option<MetadataPrefetch>,
+    /// Url of a task whose key is a digest, recorded for the entry listing
+    url: Option<String>,
+    /// How an interrupted response body is resumed, `None` if it is not
+    resume: Option<Resume>,
+}
+
+/// Resume an interrupted response body, see `resumable_body`
+struct Resume {
+    client: reqwest::Client,
+    max_resumes: u32,
+}
+
+type MetadataPrefetch = Box<dyn FnOnce(Vec<reqwest::Url>) + Send>;
+
+/// Log and count a response served or cached unrewritten, since it is over
+/// `max_rewrite_body_bytes`
+fn skip_rewrite(key: &str, rule: &str, len: Option<u64>) {
+    warn!(
+        key,
+        size = ?len,
+        "{} is not rewritten, its body is over max_rewrite_body_bytes",
+        key
+    );
+    increment_counter!(metric::CNT_REWRITES_SKIPPED, "rule" => rule.to_string());
+}
+
+/// Stream a body to the client as is, holding `permit` until it is done
+fn pass_through(body: ByteStream, permit: InflightPermit) -> TaskResponse {
+    TaskResponse::StreamResponse(Box::pin(body.map(move |x| {
+        let _ = &permit;
+        x
+    })))
+}
+
+/// Put an entry in the cache, expiring after `ttl` secs if set. The `url` of
+/// an entry keyed by a digest is recorded, see `Cache::set_entry_url`.
+async fn put_entry(
+    c: &Arc<RwLock<dyn Cache>>,
+    key: &str,
+    entry: CacheData,
+    ttl: Option<u64>,
+    url: Option<&str>,
+) {
+    let mut cache = c.write().await;
+    match ttl {
+        Some(ttl) => cache.put_with_ttl(key, entry, ttl).await,
+        None => cache.put(key, entry).await,
     }
+    if let Some(url) = url {
+        if let Err(e) = cache.set_entry_url(key, url) {
+            warn!("failed to record the url of {}: {}", key, e);
+        }
+    }
+}
 
-    /// get task result from cache
-    pub async fn get(&self, task: &Task, key: &str) -> Option<CacheData> {
-        let rule_id = task.rule_id;
-        match self.get_cache_for_cache_rule(rule_id) {
-            Some(cache) => cache.read().await.get(key).await,
-            None => {
-                error!("Failed to get cache for rule #{} from cache map", rule_id);
-                None
+/// Append the hashes of the files cached in `files` to the links of an index
+/// page served at `page` lacking one, looked up in a single batch
+async fn append_file_hashes(
+    content: String,
+    page: &reqwest::Url,
+    files: &Arc<RwLock<dyn Cache>>,
+) -> String {
+    let links = hashes::unhashed_links(&content, page);
+    if links.is_empty() {
+        return content;
+    }
+    let keys: Vec<String> = links
+        .iter()
+        .map(|url| {
+            Task {
+                rule_id: 0,
+                url: url.to_string(),
+                key: None,
+                tenant: None,
+                simple_json: false,
             }
+            .to_key()
+        })
+        .collect();
+    let found = match files.read().await.entry_sha256s(&keys) {
+        Ok(found) => found,
+        Err(e) => {
+            warn!(
+                "[TASK] failed to look up the hashes of the files of {}: {}",
+                page, e
+            );
+            return content;
         }
+    };
+    let hashes: HashMap<String, String> = links
+        .into_iter()
+        .zip(found)
+        .filter_map(|(url, sha256)| Some((url.to_string(), sha256?)))
+        .collect();
+    if hashes.is_empty() {
+        return content;
     }
+    trace!(
+        "[TASK] appending {} hashes to the links of {}",
+        hashes.len(),
+        page
+    );
+    hashes::append_hashes(&content, page, &hashes)
+}
 
-    pub fn rewrite_upstream(content: String, rewrites: &[Rewrite]) -> String {
-        let mut content = content;
-        for rewrite in rewrites {
-            content = content.replace(&rewrite.from, &rewrite.to);
-        }
+/// Rewrite a page read whole: the links of a directory listing if
+/// `dir_listing` is set, then `rewrites`. Returns whether it is a listing.
+fn rewrite_page(
+    content: String,
+    page: &reqwest::Url,
+    rewrites: Option<&[Rewrite]>,
+    dir_listing: bool,
+) -> (String, bool) {
+    let is_listing = dir_listing && listing::is_dir_listing(&content);
+    let content = if is_listing {
+        listing::rewrite_hrefs(&content, page)
+    } else {
         content
-    }
+    };
+    let content = match rewrites {
+        Some(rewrites) => TaskManager::rewrite_upstream(content, rewrites),
+        None => content,
+    };
+    (content, is_listing)
+}
 
-    pub fn resolve_task_upstream(&self, task_type: &Task) -> String {
-        task_type.url.clone()
+/// Write an upstream response to the cache, rewriting it as set by `entry`,
+/// chunk by chunk if possible. Concurrent readers can follow the download while
+/// it is written. If `tee` is set, the written content is sent to it as well;
+/// the cache is still populated if its receiver is dropped, e.g. when the
+/// client disconnects. Returns whether the response is cached.
+async fn cache_response(
+    c: Arc<RwLock<dyn Cache>>,
+    key: &str,
+    res: reqwest::Response,
+    entry: EntryOptions,
+    downloads: Arc<RwLock<HashMap<String, Download>>>,
+    tee: Option<TeeSender>,
+) -> bool {
+    let EntryOptions {
+        rewrites,
+        mut ttl,
+        dir_listing_ttl,
+        max_rewrite_body,
+        rule,
+        hash_source,
+        metadata_prefetch,
+        url,
+        resume,
+    } = entry;
+    let rewriter = rewrites
+        .as_ref()
+        .and_then(|rewrites| StreamRewriter::for_response(&res, rewrites));
+    let html = is_html_response(&res);
+    let json = pep691::is_json_response(&res);
+    // listings are told apart from other pages by their content
+    let listing_ttl = dir_listing_ttl.filter(|_| html);
+    // index pages have their links looked up as a whole
+    let hash_source = hash_source.filter(|_| html);
+    let metadata_prefetch = metadata_prefetch.filter(|_| html || json);
+    // pages that cannot be rewritten chunk by chunk are read whole
+    let read_whole = (rewrites.is_some() && rewriter.is_none())
+        || listing_ttl.is_some()
+        || hash_source.is_some()
+        || metadata_prefetch.is_some();
+    let (body, len, rewriter): (ByteStream, _, _) = if read_whole {
+        let page = res.url().clone();
+        match util::response_text(res, max_rewrite_body).await {
+            Ok(TextBody::Text(content)) => {
+                // the links still point to the upstream
+                if let Some(prefetch) = metadata_prefetch {
+                    prefetch(pep658::metadata_urls(&content, &page, json));
+                }
+                let content = match &hash_source {
+                    Some(files) => append_file_hashes(content, &page, files).await,
+                    None => content,
+                };
+                let (content, is_listing) =
+                    rewrite_page(content, &page, rewrites.as_deref(), listing_ttl.is_some());
+                if is_listing {
+                    ttl = listing_ttl;
+                }
+                let size = content.len();
+                put_entry(&c, key, content.clone().into(), ttl, url.as_deref())
+                    .instrument(debug_span!("cache_put", key, size))
+                    .await;
+                if let Some(mut tee) = tee {
+                    let _ = tee.send(Ok(content.into())).await;
+                }
+                return true;
+            }
+            // cached as is, like a response that is not rewritten
+            Ok(TextBody::TooLarge(body, len)) => {
+                skip_rewrite(key, &rule, len);
+                (body, len, None)
+            }
+            Err(e) => {
+                error!("[TASK] failed to read the response of {}: {}", key, e);
+                if let Some(mut tee) = tee {
+                    let _ = tee.send(Err(e)).await;
+                }
+                return false;
+            }
+        }
+    } else {
+        // the length of rewritten content is unknown
+        let len = match rewriter {
+            Some(_) => None,
+            None => res.content_length(),
+        };
+        (resumable_body(res, resume), len, rewriter)
+    };
+    // let concurrent readers follow the file being written
+    let path = c.read().await.write_path(key);
+    let progress = match path {
+        Some(path) => {
+            let (tx, rx) = watch::channel(DownloadProgress::Downloading(0));
+            let download = Download {
+                path,
+                expected_size: len,
+                progress: rx,
+            };
+            downloads.write().await.insert(key.to_string(), download);
+            Some(Arc::new(tx))
+        }
+        None => None,
+    };
+    let mut received: u64 = 0;
+    let tx = progress.clone();
+    let received_chunks = Box::pin(body.map(move |x| {
+        if let (Ok(bytes), Some(tx)) = (&x, &tx) {
+            received += bytes.len() as u64;
+            let _ = tx.send(DownloadProgress::Downloading(received));
+        }
+        x
+    }));
+    let chunks: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>> = match rewriter {
+        Some(rewriter) => Box::pin(rewrite::rewrite_stream(received_chunks, rewriter)),
+        None => received_chunks,
+    };
+    let bytestream = Box::pin(chunks.then(move |x| {
+        let tee = tee.clone();
+        async move {
+            if let Some(mut tee) = tee {
+                let teed = match &x {
+                    Ok(bytes) => Ok(bytes.clone()),
+                    Err(e) => Err(Error::UpstreamUnavailable(format!(
+                        "response body interrupted: {}",
+                        e
+                    ))),
+                };
+                // the client may be gone, the cache is populated anyway
+                let _ = tee.send(teed).await;
+            }
+            x
+        }
+    }));
+    let entry = CacheData::ByteStream(Box::new(bytestream), len);
+    put_entry(&c, key, entry, ttl, url.as_deref())
+        .instrument(debug_span!("cache_put", key, size = ?len))
+        .await;
+    if let Some(tx) = progress {
+        let _ = tx.send(DownloadProgress::Done);
+        downloads.write().await.remove(key);
     }
+    true
+}
 
-    pub fn get_cache_for_cache_rule(&self, rule_id: RuleId) -> Option<Arc<RwLock<dyn Cache>>> {
-        self.rule_map.get(&rule_id).map(|tuple| tuple.0.clone())
+/// Follow the pages of a paginated response and cache them merged under
+/// `key`, rewritten as set by `entry`. Documents with more than `max_pages`
+/// pages are not cached. Returns whether the document is cached.
+async fn cache_pages(
+    c: Arc<RwLock<dyn Cache>>,
+    key: &str,
+    res: reqwest::Response,
+    client: &reqwest::Client,
+    accept: Option<&str>,
+    paginate: &Pagination,
+    entry: EntryOptions,
+) -> bool {
+    let max_pages = paginate.max_pages();
+    let max_body = entry.max_rewrite_body;
+    let pages = match pagination::follow(client, res, accept, max_pages, max_body).await {
+        Ok(pages) if pages.complete => pages.pages,
+        Ok(_) => {
+            warn!(
+                "[TASK] {} has more than {} pages, not cached",
+                key, max_pages
+            );
+            return false;
+        }
+        Err(e) => {
+            error!("[TASK] failed to read the pages of {}: {}", key, e);
+            return false;
+        }
+    };
+    let count = pages.len();
+    let content = match pagination::merge(pages, paginate.merge()) {
+        Ok(content) => content,
+        Err(e) => {
+            error!(
+                "[TASK] failed to merge the {} pages of {}: {}",
+                count, key, e
+            );
+            return false;
+        }
+    };
+    let content = match &entry.rewrites {
+        Some(rewrites) => TaskManager::rewrite_upstream(content, rewrites),
+        None => content,
+    };
+    debug!("[TASK] caching the {} pages of {} merged", count, key);
+    let size = content.len();
+    put_entry(&c, key, content.into(), entry.ttl, entry.url.as_deref())
+        .instrument(debug_span!("cache_put", key, size))
+        .await;
+    true
+}
+
+/// Insert an anaconda.org channel token after the host of `url`:
+/// `https://conda.anaconda.org/<channel>/...` ->
+/// `https://conda.anaconda.org/t/<token>/<channel>/...`
+fn inject_conda_token(url: &str, token: &str) -> String {
+    let host_start = url.find("://").map_or(0, |idx| idx + 3);
+    let host_end = url[host_start..]
+        .find('/')
+        .map_or(url.len(), |idx| host_start + idx);
+    format!("{}/t/{}{}", &url[..host_end], token, &url[host_end..])
+}
+
+fn redact_token(message: &str, token: Option<&str>) -> String {
+    match token {
+        Some(token) if !token.is_empty() => message.replace(token, "<token>"),
+        _ => message.to_string(),
     }
+}
 
-    pub fn get_task_size_limit(&self, task: &Task) -> usize {
-        self.rule_map.get(&task.rule_id).unwrap().1
+fn rewrite_json_value(value: &mut serde_json::Value, field: &str, from: &str, to: &str) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                match child {
+                    serde_json::Value::String(s) if key == field => {
+                        *s = s.replace(from, to);
+                    }
+                    _ => rewrite_json_value(child, field, from, to),
+                }
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for child in array.iter_mut() {
+                rewrite_json_value(child, field, from, to);
+            }
+        }
+        _ => {}
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::settings::{Options, RedirectPolicy, Rule};
 
     #[test]
     fn rewrite_upstream() {
@@ -433,10 +4393,12 @@ mod test {
             Rewrite {
                 from: "flower".to_string(),
                 to: "vegetable".to_string(),
+                json_field: None,
             },
             Rewrite {
                 from: "cat".to_string(),
                 to: "dog".to_string(),
+                json_field: None,
             },
         ];
         assert_eq!(
@@ -444,4 +4406,1428 @@ mod test {
             "vegetable dog"
         );
     }
+
+    #[test]
+    fn task_to_key_sanitized() {
+        let task = Task {
+            rule_id: 0,
+            url: "https://dl.flathub.org/repo//objects/ab/../cdef.filez/".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        assert_eq!(
+            task.to_key(),
+            "https/dl.flathub.org/repo/objects/ab/cdef.filez"
+        );
+    }
+
+    #[test]
+    fn task_to_key_digest() {
+        let task = |url: String, simple_json| Task {
+            rule_id: 0,
+            url,
+            key: None,
+            tenant: None,
+            simple_json,
+        };
+        // short keys are left as they are
+        let short = task("https://pypi.org/simple/flask/".to_string(), false);
+        assert_eq!(short.to_key(), "https/pypi.org/simple/flask");
+        assert!(!is_digest_key(&short.to_key()));
+        assert!(!is_digest_key("https/example.com/~sha256-notahash"));
+
+        let long_path = format!("https://pypi.org/simple/{}", "a/".repeat(600));
+        let long_segment = format!("https://pypi.org/simple/{}", "b".repeat(300));
+        for url in &[long_path, long_segment] {
+            let key = task(url.clone(), false).to_key();
+            assert!(key.starts_with("https/pypi.org/~sha256-"), "{}", key);
+            assert_eq!(key.len(), "https/pypi.org/~sha256-".len() + 64);
+            assert!(is_digest_key(&key));
+            assert_eq!(upstream_url_of_key(&key), None);
+            // the same url, the same key
+            assert_eq!(task(url.clone(), false).to_key(), key);
+            let json = task(url.clone(), true).to_key();
+            assert_eq!(json, format!("{}{}", key, pep691::JSON_KEY_SUFFIX));
+            assert!(is_digest_key(&json));
+        }
+        let other = format!("https://pypi.org/simple/{}", "c".repeat(300));
+        assert_ne!(
+            task(other, false).to_key(),
+            task(
+                format!("https://pypi.org/simple/{}", "b".repeat(300)),
+                false
+            )
+            .to_key()
+        );
+        // a host too long is not kept either
+        let long_host = format!("https://{}.org/simple/", "h".repeat(300));
+        let key = task(long_host, false).to_key();
+        assert!(key.starts_with("https/~sha256-"), "{}", key);
+    }
+
+    #[test]
+    fn task_serde_round_trip() {
+        let task = Task {
+            rule_id: 3,
+            url: "https://raw.githubusercontent.com/org/repo/../README.md".to_string(),
+            key: Some("gh/org/repo/../README.md".to_string()),
+            tenant: None,
+            simple_json: false,
+        };
+        let json = serde_json::to_string(&task).unwrap();
+        let parsed: Task = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, task);
+        assert_eq!(parsed.to_key(), "gh/org/repo/README.md");
+        // tasks without a key are serialized as before
+        let parsed: Task =
+            serde_json::from_str(r#"{"rule_id":0,"url":"https://pypi.org/simple/"}"#).unwrap();
+        assert_eq!(parsed.key, None);
+        assert_eq!(parsed.to_key(), "https/pypi.org/simple");
+    }
+
+    #[test]
+    fn conda_token_only_in_upstream_url() {
+        let mut tm = TaskManager::empty();
+        tm.token_map.insert(0, "xy-0123456789".to_string());
+        let task = Task {
+            rule_id: 0,
+            url: "https://conda.anaconda.org/private/linux-64/repodata.json".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        assert_eq!(
+            tm.resolve_task_upstream(&task),
+            "https://conda.anaconda.org/t/xy-0123456789/private/linux-64/repodata.json"
+        );
+        assert!(!task.to_key().contains("xy-0123456789"));
+        assert!(!format!("{:?}", task).contains("xy-0123456789"));
+        // rules are logged when they are created
+        let options = Options {
+            conda_token: Some(crate::secret::Secret::new("xy-0123456789".into())),
+            ..Default::default()
+        };
+        assert!(!format!("{:?}", options).contains("xy-0123456789"));
+        let error = format!(
+            "error sending request for url ({})",
+            tm.resolve_task_upstream(&task)
+        );
+        assert_eq!(
+            tm.redact(&task, &error),
+            "error sending request for url (https://conda.anaconda.org/t/<token>/private/linux-64/repodata.json)"
+        );
+        // tasks of other rules are untouched
+        let other = Task {
+            rule_id: 1,
+            url: task.url.clone(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        assert_eq!(tm.resolve_task_upstream(&other), task.url);
+    }
+
+    /// A trimmed copy of https://api.nuget.org/v3/index.json
+    static NUGET_SERVICE_INDEX: &str = r#"{
+  "version": "3.0.0",
+  "resources": [
+    {
+      "@id": "https://azuresearch-usnc.nuget.org/query",
+      "@type": "SearchQueryService",
+      "comment": "Query endpoint of NuGet Search service (primary)"
+    },
+    {
+      "@id": "https://api.nuget.org/v3-flatcontainer/",
+      "@type": "PackageBaseAddress/3.0.0",
+      "comment": "Base URL of where NuGet packages are stored, in the format https://api.nuget.org/v3-flatcontainer/{id-lower}/{version-lower}/{id-lower}.{version-lower}.nupkg"
+    },
+    {
+      "@id": "https://api.nuget.org/v3/registration5-semver1/",
+      "@type": "RegistrationsBaseUrl",
+      "comment": "Base URL of Azure storage where NuGet package registration info is stored"
+    }
+  ],
+  "@context": {
+    "@vocab": "http://schema.nuget.org/services#",
+    "comment": "http://www.w3.org/2000/01/rdf-schema#comment"
+  }
+}"#;
+
+    #[test]
+    fn rewrite_nuget_service_index() {
+        let rewrites = vec![Rewrite {
+            from: "https://api.nuget.org/".to_string(),
+            to: "http://localhost:9000/nuget/".to_string(),
+            json_field: Some("@id".to_string()),
+        }];
+        let content = TaskManager::rewrite_upstream(NUGET_SERVICE_INDEX.to_string(), &rewrites);
+        let value: serde_json::Value = serde_json::from_str(&content).unwrap();
+        let resources = value["resources"].as_array().unwrap();
+        assert_eq!(
+            resources[0]["@id"],
+            "https://azuresearch-usnc.nuget.org/query"
+        );
+        assert_eq!(
+            resources[1]["@id"],
+            "http://localhost:9000/nuget/v3-flatcontainer/"
+        );
+        assert_eq!(
+            resources[2]["@id"],
+            "http://localhost:9000/nuget/v3/registration5-semver1/"
+        );
+        // other fields are left untouched
+        assert!(resources[1]["comment"]
+            .as_str()
+            .unwrap()
+            .contains("https://api.nuget.org/v3-flatcontainer/"));
+    }
+
+    static SLOW_UPSTREAM_HITS: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+
+    /// An upstream serving 5 chunks of 1 KiB, one every 200ms
+    fn slow_upstream(
+    ) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+    {
+        use warp::Filter;
+        warp::path!("slow.bin").map(|| {
+            SLOW_UPSTREAM_HITS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let chunks = futures::stream::iter(0..5u8).then(|i| async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok::<_, std::io::Error>(Bytes::from(vec![i; 1024]))
+            });
+            Response::builder()
+                .header("Content-Length", 5 * 1024)
+                .body(warp::hyper::Body::wrap_stream(chunks))
+                .unwrap()
+        })
+    }
+
+    async fn response_bytes(resp: TaskResponse) -> Vec<u8> {
+        match resp {
+            TaskResponse::StreamResponse(stream) | TaskResponse::SizedResponse(stream, _) => {
+                stream.map(|bytes| bytes.unwrap().to_vec()).concat().await
+            }
+            _ => panic!("expected a stream response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_reader_follows_download() {
+        tokio::spawn(warp::serve(slow_upstream()).run(([127, 0, 0, 1], 3003)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let _ = std::fs::remove_dir_all("cache/follow_download");
+        let _ = std::fs::remove_dir_all("cache/follow_download_sled");
+        let storage = FsBackend {
+            root_dir: "cache/follow_download".to_string(),
+            sharded: false,
+            chunk_size: None,
+            fsync: Default::default(),
+            permissions: Default::default(),
+        };
+        let cache = LruCache::new(
+            1024 * 1024,
+            Arc::new(SledMetadataDb::new_lru(
+                "cache/follow_download_sled",
+                "follow",
+            )),
+            Arc::new(storage),
+            "follow",
+        );
+        let mut tm = TaskManager::empty();
+        tm.rule_map.insert(0, (Arc::new(RwLock::new(cache)), 0));
+        let task = Task {
+            rule_id: 0,
+            url: "http://127.0.0.1:3003/slow.bin".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+
+        let (first, outcome) = tm.resolve_task(&task, None).await;
+        assert_eq!(outcome.status, CacheStatus::Miss);
+        // the second client arrives while the background download is in progress
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let (second, outcome) = tm.resolve_task(&task, None).await;
+        assert_eq!(outcome.status, CacheStatus::Hit);
+
+        let (first, second) = tokio::join!(
+            response_bytes(first.unwrap()),
+            response_bytes(second.unwrap())
+        );
+        let expected: Vec<u8> = (0..5u8).flat_map(|i| vec![i; 1024]).collect();
+        assert_eq!(first, expected);
+        assert_eq!(second, expected);
+        // the foreground fetch of the first client and the background download
+        assert_eq!(
+            SLOW_UPSTREAM_HITS.load(std::sync::atomic::Ordering::SeqCst),
+            2
+        );
+    }
+
+    fn ttl_sled_task_manager(name: &str, stale_window: u64) -> TaskManager {
+        let dir = format!("cache/{}", name);
+        let cache = TtlCache::new(
+            1,
+            Arc::new(SledMetadataDb::new_ttl(&format!("{}_sled", dir), name, 1)),
+            Arc::new(FsBackend {
+                root_dir: dir,
+                sharded: false,
+                chunk_size: None,
+                fsync: Default::default(),
+                permissions: Default::default(),
+            }),
+        )
+        .with_stale_window(stale_window);
+        let mut tm = TaskManager::empty();
+        tm.rule_map.insert(0, (Arc::new(RwLock::new(cache)), 0));
+        tm
+    }
+
+    // nothing listens on port 3009
+    const UNAVAILABLE_URL: &str = "http://127.0.0.1:3009/index.json";
+
+    #[tokio::test]
+    async fn serve_stale_on_upstream_error() {
+        let tm = ttl_sled_task_manager("serve_stale", 60);
+        let task = Task {
+            rule_id: 0,
+            url: UNAVAILABLE_URL.to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let cache = tm.get_cache_for_cache_rule(0).unwrap();
+        cache
+            .write()
+            .await
+            .put(&task.to_key(), Bytes::from("stale").into())
+            .await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let (resp, _) = tm.resolve_task(&task, None).await;
+        match resp.unwrap() {
+            TaskResponse::StaleResponse(stream) => {
+                let content = stream.map(|b| b.unwrap().to_vec()).concat().await;
+                assert_eq!(content, b"stale");
+            }
+            _ => panic!("expected a stale response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_error_without_stale_window() {
+        let tm = ttl_sled_task_manager("no_stale", 0);
+        let task = Task {
+            rule_id: 0,
+            url: UNAVAILABLE_URL.to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let cache = tm.get_cache_for_cache_rule(0).unwrap();
+        cache
+            .write()
+            .await
+            .put(&task.to_key(), Bytes::from("expired").into())
+            .await;
+        tokio::time::sleep(Duration::from_millis(3000)).await;
+        let (resp, _) = tm.resolve_task(&task, None).await;
+        assert!(matches!(resp, Err(Error::UpstreamUnavailable(_))));
+    }
+
+    /// An upstream redirecting packages to a CDN, and serving an html error
+    /// page for a package
+    fn redirecting_upstream(
+    ) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+    {
+        use warp::Filter;
+        let moved = warp::path!("moved.whl").map(|| {
+            Response::builder()
+                .status(302)
+                .header("Location", "/cdn/pkg.whl")
+                .header("Content-Type", "text/html")
+                .body("<html>Moved</html>".into())
+                .unwrap()
+        });
+        let cdn = warp::path!("cdn" / "pkg.whl").map(|| {
+            Response::builder()
+                .header("Content-Type", "application/octet-stream")
+                .body("wheel".into())
+                .unwrap()
+        });
+        let error_page = warp::path!("error.whl").map(|| {
+            Response::builder()
+                .header("Content-Type", "text/html")
+                .body("<html>Error</html>".into())
+                .unwrap()
+        });
+        moved.or(cdn).unify().or(error_page).unify()
+    }
+
+    fn redirect_test_rule(max_hops: Option<usize>) -> Rule {
+        Rule {
+            name: None,
+            path: "wheels/".to_string(),
+            path_pattern: None,
+            methods: None,
+            max_body_size: None,
+            policy: "policy_lru".to_string(),
+            upstream: "http://127.0.0.1:3004/".to_string(),
+            upstream_template: None,
+            size_limit: None,
+            max_inflight: None,
+            rewrite: None,
+            rewrite_from: None,
+            options: Some(Options {
+                content_type: None,
+                conda_token: None,
+                pep503: None,
+                root_index_ttl: None,
+                hash_links_from: None,
+                prefetch_metadata: None,
+                rewrite_dir_listing: None,
+                dir_listing_ttl: None,
+                redirect: Some(RedirectPolicy {
+                    max_hops,
+                    cross_host: Some(false),
+                }),
+                cache_redirects: None,
+                binary_suffixes: None,
+                force_cache: None,
+                http_client: None,
+                offline: None,
+                conda_channels: None,
+                unknown_conda_channel: None,
+                refresh_param: None,
+                paginate: None,
+                mirrors: None,
+                upstream_selection: None,
+                nuget: None,
+            }),
+            cache_mode: None,
+            query: None,
+            admission: None,
+            revalidate_after: None,
+            key_classes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_final_artifact_of_redirects() {
+        tokio::spawn(warp::serve(redirecting_upstream()).run(([127, 0, 0, 1], 3004)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let cache = LruCache::new(
+            1024 * 1024,
+            Arc::new(SledMetadataDb::new_lru("cache/redirect_sled", "redirect")),
+            Arc::new(FsBackend {
+                root_dir: "cache/redirect".to_string(),
+                sharded: false,
+                chunk_size: None,
+                fsync: Default::default(),
+                permissions: Default::default(),
+            }),
+            "redirect",
+        );
+        let cache: Arc<RwLock<dyn Cache>> = Arc::new(RwLock::new(cache));
+        let mut tm = TaskManager::empty();
+        tm.rule_map.insert(0, (cache.clone(), 0));
+        tm.rule_map.insert(1, (cache.clone(), 0));
+        tm.config.rules = vec![redirect_test_rule(None), redirect_test_rule(Some(0))];
+
+        // the redirect is followed, and the artifact is cached
+        let task = Task {
+            rule_id: 0,
+            url: "http://127.0.0.1:3004/moved.whl".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let (resp, _) = tm.resolve_task(&task, None).await;
+        assert_eq!(response_bytes(resp.unwrap()).await, b"wheel");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let cached = cache.read().await.get(&task.to_key()).await.unwrap();
+        assert_eq!(cached.into_vec_u8().await, b"wheel");
+
+        // the redirect is not followed, and the redirect page is not cached
+        let task = Task {
+            rule_id: 1,
+            url: "http://127.0.0.1:3004/moved.whl?no-follow".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let (resp, _) = tm.resolve_task(&task, None).await;
+        match resp {
+            Err(Error::UpstreamRequestError(res)) => assert_eq!(res.status(), 302),
+            _ => panic!("expected the redirect response"),
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(cache.read().await.get(&task.to_key()).await.is_none());
+
+        // an html page served for a package is not cached
+        let task = Task {
+            rule_id: 0,
+            url: "http://127.0.0.1:3004/error.whl".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let (resp, _) = tm.resolve_task(&task, None).await;
+        assert!(resp.is_ok());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(cache.read().await.get(&task.to_key()).await.is_none());
+
+        // the redirect itself is cached, with its location rewritten
+        let mut rule = redirect_test_rule(None);
+        rule.options.as_mut().unwrap().cache_redirects = Some(true);
+        tm.config.rules.push(rule);
+        tm.rule_map.insert(2, (cache.clone(), 0));
+        tm.rewrite_map.insert(
+            2,
+            vec![Rewrite {
+                from: "http://127.0.0.1:3004/".to_string(),
+                to: "http://mirror.corp/wheels/".to_string(),
+                json_field: None,
+            }],
+        );
+        let task = Task {
+            rule_id: 2,
+            url: "http://127.0.0.1:3004/moved.whl?cached".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let redirect_key = redirects::redirect_key(&task.to_key());
+        // cached by an earlier run
+        let _ = cache.read().await.remove(&redirect_key).await;
+        for status in &[CacheStatus::Miss, CacheStatus::Hit] {
+            let (resp, outcome) = tm.resolve_task(&task, None).await;
+            assert_eq!(outcome.status, *status);
+            let resp = warp::Reply::into_response(resp.unwrap());
+            assert_eq!(resp.status(), 302);
+            assert_eq!(
+                resp.headers()["Location"],
+                "http://mirror.corp/wheels/cdn/pkg.whl"
+            );
+        }
+        let cached = cache.read().await.get(&redirect_key).await.unwrap();
+        let redirect = CachedRedirect::parse(&cached.into_vec_u8().await).unwrap();
+        assert_eq!(redirect.location, "http://mirror.corp/wheels/cdn/pkg.whl");
+        assert!(cache.read().await.get(&task.to_key()).await.is_none());
+    }
+
+    static MODE_UPSTREAM_HITS: [std::sync::atomic::AtomicUsize; 3] = [
+        std::sync::atomic::AtomicUsize::new(0),
+        std::sync::atomic::AtomicUsize::new(0),
+        std::sync::atomic::AtomicUsize::new(0),
+    ];
+
+    /// An upstream serving `/<mode id>/pkg.bin`, counting requests per mode
+    fn counting_upstream(
+    ) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+    {
+        use warp::Filter;
+        warp::path!(usize / "pkg.bin").map(|id: usize| {
+            MODE_UPSTREAM_HITS[id].fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Response::builder()
+                .header("Content-Length", 7)
+                .body("package".into())
+                .unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn cache_mode_upstream_requests() {
+        tokio::spawn(warp::serve(counting_upstream()).run(([127, 0, 0, 1], 3005)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let modes = [
+            CacheMode::WriteBack,
+            CacheMode::WriteThrough,
+            CacheMode::ReadOnly,
+        ];
+        let mut tm = TaskManager::empty();
+        let mut caches = Vec::new();
+        for (id, mode) in modes.iter().enumerate() {
+            let name = format!("cache_mode_{}", id);
+            let _ = std::fs::remove_dir_all(format!("cache/{}", name));
+            let _ = std::fs::remove_dir_all(format!("cache/{}_sled", name));
+            let cache = LruCache::new(
+                1024 * 1024,
+                Arc::new(SledMetadataDb::new_lru(
+                    &format!("cache/{}_sled", name),
+                    &name,
+                )),
+                Arc::new(FsBackend {
+                    root_dir: format!("cache/{}", name),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
+                }),
+                &name,
+            );
+            let cache: Arc<RwLock<dyn Cache>> = Arc::new(RwLock::new(cache));
+            tm.rule_map.insert(id, (cache.clone(), 0));
+            caches.push(cache);
+            tm.config.rules.push(Rule {
+                name: None,
+                path: format!("{}/", id),
+                path_pattern: None,
+                methods: None,
+                max_body_size: None,
+                policy: "policy_lru".to_string(),
+                upstream: "http://127.0.0.1:3005/".to_string(),
+                upstream_template: None,
+                size_limit: None,
+                max_inflight: None,
+                rewrite: None,
+                rewrite_from: None,
+                options: None,
+                cache_mode: Some(*mode),
+                query: None,
+                admission: None,
+                revalidate_after: None,
+                key_classes: None,
+            });
+        }
+
+        for (id, expected_hits) in [2, 1, 2].iter().enumerate() {
+            let task = Task {
+                rule_id: id,
+                url: format!("http://127.0.0.1:3005/{}/pkg.bin", id),
+                key: None,
+                tenant: None,
+                simple_json: false,
+            };
+            for _ in 0..2 {
+                let (resp, _) = tm.resolve_task(&task, None).await;
+                assert_eq!(response_bytes(resp.unwrap()).await, b"package");
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            assert_eq!(
+                MODE_UPSTREAM_HITS[id].load(std::sync::atomic::Ordering::SeqCst),
+                *expected_hits,
+                "{:?}",
+                modes[id]
+            );
+        }
+        // read-only rules never populate the cache
+        let key = Task {
+            rule_id: 2,
+            url: "http://127.0.0.1:3005/2/pkg.bin".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        }
+        .to_key();
+        assert!(caches[2].read().await.get(&key).await.is_none());
+    }
+
+    /// An upstream serving `/<trigger>`, with the header of the trigger
+    fn personalized_upstream(
+    ) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+    {
+        use warp::Filter;
+        warp::path!(String).map(|trigger: String| {
+            let builder = Response::builder();
+            let builder = match trigger.as_str() {
+                "cookie" => builder.header("Set-Cookie", "session=abc; HttpOnly"),
+                "private" => builder.header("Cache-Control", "max-age=60, private"),
+                "no-store" => builder.header("Cache-Control", "No-Store"),
+                "custom" => builder.header("X-Personalized", "1"),
+                _ => builder.header("Cache-Control", "public, max-age=60"),
+            };
+            builder.body("personal".into()).unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn uncacheable_responses_not_cached() {
+        tokio::spawn(warp::serve(personalized_upstream()).run(([127, 0, 0, 1], 3006)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut tm = TaskManager::empty();
+        tm.config.uncacheable_headers = Some(vec!["x-personalized".to_string()]);
+        // write-back, write-through, and write-back of a rule caching all responses
+        let rules = [
+            (CacheMode::WriteBack, false),
+            (CacheMode::WriteThrough, false),
+            (CacheMode::WriteBack, true),
+        ];
+        let mut caches = Vec::new();
+        for (id, (mode, force_cache)) in rules.iter().enumerate() {
+            let name = format!("uncacheable_{}", id);
+            let _ = std::fs::remove_dir_all(format!("cache/{}", name));
+            let cache = LruCache::new(
+                1024 * 1024,
+                Arc::new(SledMetadataDb::new_lru(
+                    &format!("cache/{}_sled", name),
+                    &name,
+                )),
+                Arc::new(FsBackend {
+                    root_dir: format!("cache/{}", name),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
+                }),
+                &name,
+            );
+            let cache: Arc<RwLock<dyn Cache>> = Arc::new(RwLock::new(cache));
+            tm.rule_map.insert(id, (cache.clone(), 0));
+            caches.push(cache);
+            tm.config.rules.push(Rule {
+                name: None,
+                path: format!("{}/", id),
+                path_pattern: None,
+                methods: None,
+                max_body_size: None,
+                policy: "policy_lru".to_string(),
+                upstream: "http://127.0.0.1:3006/".to_string(),
+                upstream_template: None,
+                size_limit: None,
+                max_inflight: None,
+                rewrite: None,
+                rewrite_from: None,
+                options: Some(Options {
+                    content_type: None,
+                    conda_token: None,
+                    pep503: None,
+                    root_index_ttl: None,
+                    hash_links_from: None,
+                    prefetch_metadata: None,
+                    rewrite_dir_listing: None,
+                    dir_listing_ttl: None,
+                    redirect: None,
+                    cache_redirects: None,
+                    binary_suffixes: None,
+                    force_cache: Some(*force_cache),
+                    http_client: None,
+                    offline: None,
+                    conda_channels: None,
+                    unknown_conda_channel: None,
+                    refresh_param: None,
+                    paginate: None,
+                    mirrors: None,
+                    upstream_selection: None,
+                    nuget: None,
+                }),
+                cache_mode: Some(*mode),
+                query: None,
+                admission: None,
+                revalidate_after: None,
+                key_classes: None,
+            });
+        }
+
+        for (trigger, cacheable) in [
+            ("cookie", false),
+            ("private", false),
+            ("no-store", false),
+            ("custom", false),
+            ("public", true),
+        ]
+        .iter()
+        {
+            for (id, (mode, force_cache)) in rules.iter().enumerate() {
+                let task = Task {
+                    rule_id: id,
+                    url: format!("http://127.0.0.1:3006/{}", trigger),
+                    key: None,
+                    tenant: None,
+                    simple_json: false,
+                };
+                let (resp, _) = tm.resolve_task(&task, None).await;
+                // uncacheable responses are still proxied
+                assert_eq!(response_bytes(resp.unwrap()).await, b"personal");
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                let cached = caches[id].read().await.get(&task.to_key()).await;
+                assert_eq!(
+                    cached.is_some(),
+                    *cacheable || *force_cache,
+                    "{} {:?}",
+                    trigger,
+                    mode
+                );
+            }
+        }
+    }
+
+    const PYPI_INDEX: &str = "<a href=\"https://files.pythonhosted.org/packages/flask.whl\">";
+
+    /// An upstream serving a PyPI index page compressed with `<encoding>`
+    /// regardless of `Accept-Encoding`, at `/<encoding>/index.html`
+    fn compressing_upstream(
+    ) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+    {
+        use std::io::Write;
+        use warp::Filter;
+        warp::path!(String / "index.html").map(|encoding: String| {
+            let body = match encoding.as_str() {
+                "gzip" => {
+                    let mut gzip =
+                        flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                    gzip.write_all(PYPI_INDEX.as_bytes()).unwrap();
+                    gzip.finish().unwrap()
+                }
+                _ => zstd::stream::encode_all(PYPI_INDEX.as_bytes(), 0).unwrap(),
+            };
+            Response::builder()
+                .header("Content-Type", "text/html")
+                .header("Content-Encoding", encoding)
+                .body(body.into())
+                .unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn rewrite_compressed_upstream() {
+        tokio::spawn(warp::serve(compressing_upstream()).run(([127, 0, 0, 1], 3012)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let rewrites = vec![Rewrite {
+            from: "https://files.pythonhosted.org/".to_string(),
+            to: "http://localhost:9000/pypi/".to_string(),
+            json_field: None,
+        }];
+        let expected = "<a href=\"http://localhost:9000/pypi/packages/flask.whl\">";
+        let modes = [CacheMode::WriteBack, CacheMode::WriteThrough];
+        let mut tm = TaskManager::empty();
+        let mut caches = Vec::new();
+        for (id, mode) in modes.iter().enumerate() {
+            let name = format!("compressed_{}", id);
+            let _ = std::fs::remove_dir_all(format!("cache/{}", name));
+            let cache = LruCache::new(
+                1024 * 1024,
+                Arc::new(SledMetadataDb::new_lru(
+                    &format!("cache/{}_sled", name),
+                    &name,
+                )),
+                Arc::new(FsBackend {
+                    root_dir: format!("cache/{}", name),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
+                }),
+                &name,
+            );
+            let cache: Arc<RwLock<dyn Cache>> = Arc::new(RwLock::new(cache));
+            tm.rule_map.insert(id, (cache.clone(), 0));
+            tm.rewrite_map.insert(id, rewrites.clone());
+            caches.push(cache);
+            tm.config.rules.push(Rule {
+                name: None,
+                path: format!("{}/", id),
+                path_pattern: None,
+                methods: None,
+                max_body_size: None,
+                policy: "policy_lru".to_string(),
+                upstream: "http://127.0.0.1:3012/".to_string(),
+                upstream_template: None,
+                size_limit: None,
+                max_inflight: None,
+                rewrite: Some(rewrites.clone()),
+                rewrite_from: None,
+                options: None,
+                cache_mode: Some(*mode),
+                query: None,
+                admission: None,
+                revalidate_after: None,
+                key_classes: None,
+            });
+        }
+
+        for id in 0..modes.len() {
+            for encoding in &["gzip", "zstd"] {
+                let task = Task {
+                    rule_id: id,
+                    url: format!("http://127.0.0.1:3012/{}/index.html", encoding),
+                    key: None,
+                    tenant: None,
+                    simple_json: false,
+                };
+                // rewritten in memory, or streamed through a rewriter
+                let content = match tm.resolve_task(&task, None).await {
+                    (Ok(TaskResponse::StringResponse(content)), _) => content.into_bytes(),
+                    (Ok(resp), _) => response_bytes(resp).await,
+                    (Err(e), _) => panic!("expected a rewritten page: {}", e),
+                };
+                assert_eq!(content, expected.as_bytes(), "{:?} {}", modes[id], encoding);
+                // the page is cached decompressed
+                let mut cached = None;
+                for _ in 0..20 {
+                    cached = caches[id].read().await.get(&task.to_key()).await;
+                    if cached.is_some() {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                let cached = cached.unwrap().into_vec_u8().await;
+                assert_eq!(cached, expected.as_bytes(), "{:?} {}", modes[id], encoding);
+            }
+        }
+    }
+
+    /// A forward proxy answering with the host requested through it
+    fn echo_host_proxy() -> impl warp::Filter<Extract = (String,), Error = warp::Rejection> + Clone
+    {
+        use warp::Filter;
+        warp::header::optional::<String>("host")
+            .map(|host: Option<String>| host.unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn upstream_clients_per_rule() {
+        use crate::settings::HttpClient;
+        tokio::spawn(warp::serve(echo_host_proxy()).run(([127, 0, 0, 1], 3011)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let proxied = HttpClient {
+            proxy: Some("http://127.0.0.1:3011".into()),
+            ..Default::default()
+        };
+        let direct = HttpClient {
+            no_proxy: Some(true),
+            ..Default::default()
+        };
+        let mut tm = TaskManager::empty();
+        tm.config.http_client = Some(proxied.clone());
+        for (id, http_client) in [None, Some(direct), Some(proxied)].iter().enumerate() {
+            let cache: Arc<RwLock<dyn Cache>> = Arc::new(RwLock::new(NoCache::default()));
+            tm.rule_map.insert(id, (cache, 0));
+            tm.config.rules.push(Rule {
+                name: None,
+                path: format!("{}/", id),
+                path_pattern: None,
+                methods: None,
+                max_body_size: None,
+                policy: "policy_none".to_string(),
+                upstream: "http://mirror.test/".to_string(),
+                upstream_template: None,
+                size_limit: None,
+                max_inflight: None,
+                rewrite: None,
+                rewrite_from: None,
+                options: Some(Options {
+                    content_type: None,
+                    conda_token: None,
+                    pep503: None,
+                    root_index_ttl: None,
+                    hash_links_from: None,
+                    prefetch_metadata: None,
+                    rewrite_dir_listing: None,
+                    dir_listing_ttl: None,
+                    redirect: None,
+                    cache_redirects: None,
+                    binary_suffixes: None,
+                    force_cache: None,
+                    http_client: http_client.clone(),
+                    offline: None,
+                    conda_channels: None,
+                    unknown_conda_channel: None,
+                    refresh_param: None,
+                    paginate: None,
+                    mirrors: None,
+                    upstream_selection: None,
+                    nuget: None,
+                }),
+                cache_mode: Some(CacheMode::ReadOnly),
+                query: None,
+                admission: None,
+                revalidate_after: None,
+                key_classes: None,
+            });
+        }
+        // rules with the same options share a client
+        assert_eq!(tm.client_config(0), tm.client_config(2));
+        assert_ne!(tm.client_config(0), tm.client_config(1));
+
+        for (id, url, expected_host) in [
+            (0, "http://mirror.test/pkg.bin", "mirror.test"),
+            (1, "http://127.0.0.1:3011/pkg.bin", "127.0.0.1:3011"),
+            (2, "http://mirror.test/pkg.bin", "mirror.test"),
+        ]
+        .iter()
+        {
+            let task = Task {
+                rule_id: *id,
+                url: url.to_string(),
+                key: None,
+                tenant: None,
+                simple_json: false,
+            };
+            let (resp, _) = tm.resolve_task(&task, None).await;
+            assert_eq!(
+                response_bytes(resp.unwrap()).await,
+                expected_host.as_bytes()
+            );
+        }
+    }
+
+    /// A cache policy that panics when an entry is written
+    struct PanickingCache;
+
+    #[async_trait::async_trait]
+    impl Cache for PanickingCache {
+        async fn put(&mut self, _key: &str, _entry: CacheData) {
+            panic!("injected put failure");
+        }
+        async fn get(&self, _key: &str) -> Option<CacheData> {
+            None
+        }
+    }
+
+    async fn wait_for_tasks(tm: &TaskManager) {
+        for _ in 0..50 {
+            if tm.task_set.read().await.is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("background tasks did not complete");
+    }
+
+    #[tokio::test]
+    async fn panicked_task_is_retryable() {
+        use warp::Filter;
+        let upstream = warp::path!("pkg.bin").map(|| "package");
+        tokio::spawn(warp::serve(upstream).run(([127, 0, 0, 1], 3010)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut tm = TaskManager::empty();
+        let cache: Arc<RwLock<dyn Cache>> = Arc::new(RwLock::new(PanickingCache));
+        tm.rule_map.insert(0, (cache, 0));
+        let task = Task {
+            rule_id: 0,
+            url: "http://127.0.0.1:3010/pkg.bin".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        for _ in 0..2 {
+            tm.spawn_task(task.clone(), Priority::High).await;
+            assert!(tm.taskset_contains(&task).await);
+            // the key is released although the task panicked
+            wait_for_tasks(&tm).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn remove_stuck_tasks() {
+        let mut tm = TaskManager::empty();
+        let task = Task {
+            rule_id: 0,
+            url: "http://127.0.0.1:3009/stuck.bin".to_string(),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        let spawned_at = tm.taskset_add(task.clone()).await;
+        assert_eq!(tm.remove_stuck_tasks().await, 0);
+        assert!(tm.taskset_contains(&task).await);
+
+        tm.config.background_tasks = Some(crate::settings::BackgroundTasks {
+            max_concurrent: None,
+            idle_threshold: None,
+            max_task_duration: Some(0),
+            max_resumes: None,
+        });
+        assert_eq!(tm.remove_stuck_tasks().await, 1);
+        assert!(!tm.taskset_contains(&task).await);
+
+        // the stuck task completing later does not remove a retry of it
+        let retried_at = tm.taskset_add(task.clone()).await;
+        TaskManager::taskset_remove(tm.task_set.clone(), &task, spawned_at).await;
+        assert!(tm.taskset_contains(&task).await);
+        TaskManager::taskset_remove(tm.task_set.clone(), &task, retried_at).await;
+        assert!(!tm.taskset_contains(&task).await);
+    }
+
+    /// Rules `offline-a`, and `offline-b` offline by its option, whose
+    /// upstreams are unavailable
+    fn offline_task_manager() -> TaskManager {
+        let mut tm = TaskManager::empty();
+        for (id, offline) in [None, Some(true)].iter().enumerate() {
+            let name = format!("offline_{}", id);
+            let cache = LruCache::new(
+                1024 * 1024,
+                Arc::new(SledMetadataDb::new_lru(
+                    &format!("cache/{}_sled", name),
+                    &name,
+                )),
+                Arc::new(FsBackend {
+                    root_dir: format!("cache/{}", name),
+                    sharded: false,
+                    chunk_size: None,
+                    fsync: Default::default(),
+                    permissions: Default::default(),
+                }),
+                &name,
+            );
+            tm.rule_map.insert(id, (Arc::new(RwLock::new(cache)), 0));
+            tm.config.rules.push(Rule {
+                name: Some(if id == 0 { "offline-a" } else { "offline-b" }.to_string()),
+                path: format!("{}/", id),
+                path_pattern: None,
+                methods: None,
+                max_body_size: None,
+                policy: "policy_lru".to_string(),
+                upstream: "http://127.0.0.1:3009/".to_string(),
+                upstream_template: None,
+                size_limit: None,
+                max_inflight: None,
+                rewrite: None,
+                rewrite_from: None,
+                options: Some(Options {
+                    content_type: None,
+                    conda_token: None,
+                    pep503: None,
+                    root_index_ttl: None,
+                    hash_links_from: None,
+                    prefetch_metadata: None,
+                    rewrite_dir_listing: None,
+                    dir_listing_ttl: None,
+                    redirect: None,
+                    cache_redirects: None,
+                    binary_suffixes: None,
+                    force_cache: None,
+                    http_client: None,
+                    offline: *offline,
+                    conda_channels: None,
+                    unknown_conda_channel: None,
+                    refresh_param: None,
+                    paginate: None,
+                    mirrors: None,
+                    upstream_selection: None,
+                    nuget: None,
+                }),
+                cache_mode: None,
+                query: None,
+                admission: None,
+                revalidate_after: None,
+                key_classes: None,
+            });
+        }
+        tm
+    }
+
+    #[tokio::test]
+    async fn offline_serves_cache_hits_only() {
+        let mut tm = offline_task_manager();
+        let task = |rule_id: RuleId, name: &str| Task {
+            rule_id,
+            url: format!("http://127.0.0.1:3009/{}", name),
+            key: None,
+            tenant: None,
+            simple_json: false,
+        };
+        for rule_id in 0..2 {
+            let cache = tm.get_cache_for_cache_rule(rule_id).unwrap();
+            cache
+                .write()
+                .await
+                .put(
+                    &task(rule_id, "cached.bin").to_key(),
+                    Bytes::from("cached").into(),
+                )
+                .await;
+        }
+
+        // offline by the option of the rule
+        let (resp, outcome) = tm.resolve_task(&task(1, "cached.bin"), None).await;
+        assert_eq!(response_bytes(resp.unwrap()).await, b"cached");
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        let (resp, outcome) = tm.resolve_task(&task(1, "missing.bin"), None).await;
+        assert!(matches!(
+            resp,
+            Err(Error::OfflineMiss(warp::http::StatusCode::NOT_FOUND))
+        ));
+        assert_eq!(outcome.status, CacheStatus::Offline);
+        assert!(tm.task_set.read().await.is_empty());
+        let (resp, _) = tm.resolve_task(&task(0, "missing.bin"), None).await;
+        assert!(matches!(resp, Err(Error::UpstreamUnavailable(_))));
+
+        // offline globally
+        tm.config.offline = Some(true);
+        tm.config.offline_miss_status = Some(503);
+        let (resp, _) = tm.resolve_task(&task(0, "cached.bin"), None).await;
+        assert_eq!(response_bytes(resp.unwrap()).await, b"cached");
+        let (resp, _) = tm.resolve_task(&task(0, "missing.bin"), None).await;
+        assert!(matches!(
+            resp,
+            Err(Error::OfflineMiss(
+                warp::http::StatusCode::SERVICE_UNAVAILABLE
+            ))
+        ));
+
+        // overrides of the admin API win over the settings
+        let status = tm
+            .set_offline("test", Some("offline-a"), Some(false))
+            .unwrap();
+        assert_eq!(status.rules.get("offline-a"), Some(&false));
+        assert_eq!(status.rules.get("offline-b"), Some(&true));
+        let (resp, _) = tm.resolve_task(&task(0, "missing.bin"), None).await;
+        assert!(matches!(resp, Err(Error::UpstreamUnavailable(_))));
+        tm.set_offline("test", None, Some(false)).unwrap();
+        assert!(!tm.is_offline(1));
+        assert!(matches!(
+            tm.set_offline("test", Some("offline-c"), Some(true)),
+            Err(Error::NotFound(_))
+        ));
+
+        // back to the settings
+        tm.set_offline("test", Some("offline-a"), None).unwrap();
+        let status = tm.set_offline("test", None, None).unwrap();
+        assert_eq!(status.overrides, Default::default());
+        assert!(tm.is_offline(0) && tm.is_offline(1));
+    }
+
+    /// Rule of policy `name` whose cache holds 30 bytes, in front of the
+    /// revalidating upstream
+    fn protect_task_manager(name: &str) -> TaskManager {
+        let _ = std::fs::remove_dir_all(format!("cache/{}", name));
+        let _ = std::fs::remove_dir_all(format!("cache/{}_sled", name));
+        let mut tm = TaskManager::empty();
+        let cache = LruCache::new(
+            30,
+            Arc::new(SledMetadataDb::new_lru(
+                &format!("cache/{}_sled", name),
+                name,
+            )),
+            Arc::new(FsBackend {
+                root_dir: format!("cache/{}", name),
+                sharded: false,
+                chunk_size: None,
+                fsync: Default::default(),
+                permissions: Default::default(),
+            }),
+            name,
+        );
+        tm.rule_map.insert(0, (Arc::new(RwLock::new(cache)), 0));
+        tm.config.rules.push(Rule {
+            name: Some(name.to_string()),
+            path: "protect/".to_string(),
+            path_pattern: None,
+            methods: None,
+            max_body_size: None,
+            policy: name.to_string(),
+            upstream: "http://127.0.0.1:3013/".to_string(),
+            upstream_template: None,
+            size_limit: None,
+            max_inflight: None,
+            rewrite: None,
+            rewrite_from: None,
+            options: None,
+            cache_mode: None,
+            query: None,
+            admission: None,
+            revalidate_after: None,
+            key_classes: None,
+        });
+        tm
+    }
+
+    /// Puts 10 bytes in the cache of `tm` for each name, after removing
+    /// leftovers of previous runs
+    async fn put_protect_entries(tm: &TaskManager, names: &[&str]) -> Vec<String> {
+        let cache = tm.get_cache_for_cache_rule(0).unwrap();
+        let mut keys = Vec::new();
+        for name in names {
+            let key = Task {
+                rule_id: 0,
+                url: format!("http://127.0.0.1:3013/{}", name),
+                key: None,
+                tenant: None,
+                simple_json: false,
+            }
+            .to_key();
+            cache.read().await.remove(&key).await.unwrap();
+            cache
+                .write()
+                .await
+                .put(&key, Bytes::from("0123456789").into())
+                .await;
+            util::sleep_ms(10);
+            keys.push(key);
+        }
+        keys
+    }
+
+    fn protect_options(min_hits: u64, revalidate: bool) -> ProtectiveRefresh {
+        ProtectiveRefresh {
+            interval: None,
+            cold_percent: Some(100),
+            min_hits: Some(min_hits),
+            min_size: None,
+            max_bytes_per_run: None,
+            revalidate: Some(revalidate),
+        }
+    }
+
+    #[tokio::test]
+    async fn protective_refresh_keeps_hot_entries() {
+        let tm = protect_task_manager("protect_eviction");
+        let cache = tm.get_cache_for_cache_rule(0).unwrap();
+        let hot = &put_protect_entries(&tm, &["hot.bin"]).await[0];
+        for _ in 0..3 {
+            assert!(cache.read().await.get(hot).await.is_some());
+        }
+        util::sleep_ms(10);
+        let cold = &put_protect_entries(&tm, &["cold.bin"]).await[0];
+        // `hot` is less recently used than `cold`, but hit more often
+        let report = tm
+            .protective_refresh("protect_eviction", &protect_options(2, false))
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ProtectionReport {
+                inspected: 2,
+                protected: 1,
+                protected_bytes: 10,
+                unprotected: 1,
+            }
+        );
+        // the cache is full after the next entry, the one after evicts `cold`
+        put_protect_entries(&tm, &["new.bin", "newer.bin"]).await;
+        assert!(cache.read().await.get(cold).await.is_none());
+        assert!(cache.read().await.get(hot).await.is_some());
+        assert!(matches!(
+            tm.protective_refresh("policy_missing", &protect_options(2, false))
+                .await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    /// An upstream answering conditional GETs of `/fresh.bin` with `304 Not
+    /// Modified`, of `/gone.bin` with `404 Not Found`, and of other files with
+    /// new content
+    fn revalidating_upstream(
+    ) -> impl warp::Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+    {
+        use warp::Filter;
+        warp::path!(String)
+            .and(warp::header::optional::<String>("if-modified-since"))
+            .map(|name: String, since: Option<String>| {
+                let status = match (name.as_str(), since) {
+                    ("fresh.bin", Some(_)) => 304,
+                    ("gone.bin", _) => 404,
+                    _ => 200,
+                };
+                Response::builder()
+                    .status(status)
+                    .body("changed".into())
+                    .unwrap()
+            })
+    }
+
+    #[tokio::test]
+    async fn protective_refresh_revalidates() {
+        tokio::spawn(warp::serve(revalidating_upstream()).run(([127, 0, 0, 1], 3013)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let tm = protect_task_manager("protect_revalidation");
+        let cache = tm.get_cache_for_cache_rule(0).unwrap();
+        let keys = put_protect_entries(&tm, &["fresh.bin", "changed.bin", "gone.bin"]).await;
+        for key in &keys {
+            assert!(cache.read().await.get(key).await.is_some());
+        }
+        let report = tm
+            .protective_refresh("protect_revalidation", &protect_options(1, true))
+            .await
+            .unwrap();
+        assert_eq!((report.protected, report.unprotected), (1, 2));
+        let coldest = cache.read().await.coldest_entries(100).unwrap();
+        assert_eq!(coldest.last().unwrap().key, keys[0]);
+    }
+
+    #[tokio::test]
+    async fn exact_size_stream_reports_mismatch() {
+        let chunks = || {
+            futures::stream::iter(vec![
+                Ok::<_, Error>(Bytes::from("0123")),
+                Ok(Bytes::from("45")),
+            ])
+        };
+        let exact: Vec<_> = exact_size_stream(chunks(), 6, |_| panic!("no mismatch"))
+            .collect()
+            .await;
+        assert_eq!(exact.len(), 2);
+        for (size, received) in &[(8, 6), (5, 6)] {
+            let reported = Arc::new(std::sync::Mutex::new(None));
+            let reported_clone = reported.clone();
+            let items: Vec<_> = exact_size_stream(chunks(), *size, move |n| {
+                *reported_clone.lock().unwrap() = Some(n)
+            })
+            .collect()
+            .await;
+            // the body fails instead of ending early or running over
+            assert!(matches!(
+                items.last(),
+                Some(Err(Error::SizeMismatch(s, r))) if s == size && r == received
+            ));
+            assert_eq!(*reported.lock().unwrap(), Some(*received));
+        }
+    }
+
+    #[tokio::test]
+    async fn hit_truncated_while_served() {
+        let dir = "cache/truncated_hit";
+        let cache = LruCache::new(
+            1024 * 1024,
+            Arc::new(SledMetadataDb::new_lru(
+                &format!("{}_sled", dir),
+                "truncated_hit",
+            )),
+            Arc::new(FsBackend {
+                root_dir: dir.to_string(),
+                sharded: false,
+                chunk_size: None,
+                fsync: Default::default(),
+                permissions: Default::default(),
+            }),
+            "truncated_hit",
+        );
+        let mut tm = TaskManager::empty();
+        tm.rule_map.insert(0, (Arc::new(RwLock::new(cache)), 0));
+        let task = Task {
+            rule_id: 0,
+            url: "http://127.0.0.1:3009/pkg.bin".to_string(),
+            key: Some("truncated/pkg.bin".to_string()),
+            tenant: None,
+            simple_json: false,
+        };
+        let cache = tm.get_cache_for_cache_rule(0).unwrap();
+        cache
+            .write()
+            .await
+            .put(&task.to_key(), Bytes::from(vec![1; 4096]).into())
+            .await;
+
+        // HEAD reports the size a GET is served with
+        assert_eq!(tm.cached_size(&task).await, Some(4096));
+        let (result, outcome) = tm.resolve_task(&task, None).await;
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        let resp = warp::Reply::into_response(result.unwrap());
+        assert_eq!(resp.headers()["Content-Length"], "4096");
+        assert_eq!(
+            warp::hyper::body::to_bytes(resp.into_body())
+                .await
+                .unwrap()
+                .len(),
+            4096
+        );
+
+        // the file is cut after the response is started
+        let (result, _) = tm.resolve_task(&task, None).await;
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(format!("{}/truncated/pkg.bin", dir))
+            .unwrap();
+        file.set_len(1000).unwrap();
+        let resp = warp::Reply::into_response(result.unwrap());
+        assert_eq!(resp.headers()["Content-Length"], "4096");
+        assert!(warp::hyper::body::to_bytes(resp.into_body()).await.is_err());
+        // the entry is removed in the background, to be fetched again
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.read().await.get(&task.to_key()).await.is_none());
+        assert_eq!(tm.cached_size(&task).await, None);
+    }
 }
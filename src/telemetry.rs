@@ -0,0 +1,133 @@
+//! Log lines and tracing spans: ids of requests, the subscriber printing
+//! events, and the optional OTLP exporter.
+
+use crate::settings::{LogFormat, Settings};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Header of the id of a request, honored in requests and set in responses
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Incoming request ids longer than this are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Modules only logged at the error level, they are too verbose otherwise
+const QUIET_MODULES: &[&str] = &[
+    "hyper::proto",
+    "tracing::span",
+    "tokio_util::codec",
+    "sled::pagecache",
+];
+
+/// The id of a request: the incoming `X-Request-Id` if it is printable ASCII
+/// of at most 128 bytes, otherwise a new one of 16 hex digits.
+pub fn request_id(incoming: Option<&str>) -> String {
+    match incoming {
+        Some(id) if is_valid_request_id(id) => id.to_string(),
+        _ => new_request_id(),
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn new_request_id() -> String {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    // randomly keyed, ids differ across restarts
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(SEQ.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+/// Install the global subscriber, printing events at `log_level` in
+/// `log_format`, and exporting spans if `otlp_endpoint` is set. Records of
+/// the `log` crate are printed as events as well.
+pub fn init(settings: &Settings) {
+    let mut filter = EnvFilter::new(settings.get_log_level().to_string().to_lowercase());
+    for module in QUIET_MODULES {
+        filter = filter.add_directive(format!("{}=error", module).parse().unwrap());
+    }
+    let (otlp, otlp_error) = match otlp_layer(settings) {
+        Ok(layer) => (layer, None),
+        Err(e) => (None, Some(e)),
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(otlp);
+    match settings.log_format.unwrap_or(LogFormat::Pretty) {
+        LogFormat::Pretty => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+    }
+    if let Some(e) = otlp_error {
+        tracing::error!("spans are not exported: {}", e);
+    }
+}
+
+/// Export spans not exported yet, e.g. on shutdown
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>(
+    settings: &Settings,
+) -> Result<
+    Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry::sdk::trace::Tracer>>,
+    String,
+>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry_otlp::WithExportConfig;
+
+    let endpoint = match &settings.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let resource = opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+        "service.name",
+        "mirror-cache",
+    )]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint.as_str()),
+        )
+        .with_trace_config(opentelemetry::sdk::trace::config().with_resource(resource))
+        .install_batch(opentelemetry::runtime::Tokio)
+        .map_err(|e| e.to_string())?;
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp_layer(settings: &Settings) -> Result<Option<tracing_subscriber::layer::Identity>, String> {
+    match settings.otlp_endpoint {
+        Some(_) => Err("mirror-cache is built without the `otlp` feature".to_string()),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_ids() {
+        assert_eq!(request_id(Some("req-42")), "req-42");
+        for invalid in &[Some(""), Some("with space"), Some("中文"), None] {
+            let id = request_id(*invalid);
+            assert_eq!(id.len(), 16);
+            assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+        }
+        assert_ne!(request_id(None), request_id(None));
+        assert_eq!(request_id(Some(&"x".repeat(129))).len(), 16);
+    }
+}
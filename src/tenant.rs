@@ -0,0 +1,155 @@
+//! Tenants: teams served by the same rules, each with caches of its own, see
+//! `Tenant`. A request is of a tenant by the prefix of its path or by its
+//! token, and admin tokens of a tenant are limited to the caches of the
+//! tenant.
+
+use crate::settings::Tenant;
+
+/// Prefix of the paths of requests of a tenant, `t/<tenant>/<path>`
+const PATH_PREFIX: &str = "t/";
+
+/// The tenant of a request, and its path without the tenant prefix: the
+/// tenant of the `t/<tenant>/` prefix of the path, or else the tenant of its
+/// `Authorization: Bearer` token. Requests of no tenant use the shared caches.
+pub fn select<'a, 'p>(
+    tenants: &'a [Tenant],
+    path: &'p str,
+    authorization: Option<&str>,
+) -> (Option<&'a Tenant>, &'p str) {
+    if tenants.is_empty() {
+        return (None, path);
+    }
+    if let Some((name, rest)) = path
+        .strip_prefix(PATH_PREFIX)
+        .and_then(|path| path.split_once('/'))
+    {
+        if let Some(tenant) = tenants.iter().find(|tenant| tenant.name == name) {
+            return (Some(tenant), rest);
+        }
+    }
+    let token = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    let tenant = tenants.iter().find(|tenant| {
+        tenant
+            .tokens
+            .iter()
+            .flatten()
+            .any(|t| token == Some(t.expose().as_str()))
+    });
+    (tenant, path)
+}
+
+/// The tenant of the admin token sent as `Authorization: Bearer <token>`,
+/// and the label of the token, as `<tenant>/<label>`
+pub fn admin_label<'a>(
+    tenants: &'a [Tenant],
+    authorization: Option<&str>,
+) -> Option<(&'a Tenant, String)> {
+    let token = authorization.and_then(|value| value.strip_prefix("Bearer "))?;
+    tenants.iter().find_map(|tenant| {
+        tenant
+            .admin_tokens
+            .iter()
+            .flatten()
+            .find(|admin| admin.token.expose() == token)
+            .map(|admin| (tenant, format!("{}/{}", tenant.name, admin.label)))
+    })
+}
+
+/// The tenant of a cache id `<tenant>_<policy>`, and the policy. Names of
+/// tenants have no `_`, so an id is of one tenant at most.
+pub fn split_cache_id<'a, 'i>(tenants: &'a [Tenant], id: &'i str) -> Option<(&'a Tenant, &'i str)> {
+    tenants.iter().find_map(|tenant| {
+        let policy = id.strip_prefix(tenant.name.as_str())?.strip_prefix('_')?;
+        Some((tenant, policy))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secret::Secret;
+    use crate::settings::AdminToken;
+
+    fn tenants() -> Vec<Tenant> {
+        let tenant = |name: &str| Tenant {
+            name: name.to_string(),
+            tokens: Some(vec![Secret::new(format!("{}-token", name))]),
+            admin_tokens: Some(vec![AdminToken {
+                label: "ops".to_string(),
+                token: Secret::new(format!("{}-admin", name)),
+            }]),
+            size_share: None,
+        };
+        vec![tenant("team"), tenant("team-b")]
+    }
+
+    #[test]
+    fn select_tenant() {
+        let tenants = tenants();
+        let name = |selected: (Option<&Tenant>, &str)| {
+            (
+                selected.0.map(|tenant| tenant.name.clone()),
+                selected.1.to_string(),
+            )
+        };
+        assert_eq!(
+            name(select(&tenants, "t/team-b/simple/flask/", None)),
+            (Some("team-b".into()), "simple/flask/".into())
+        );
+        // the prefix wins over the token
+        assert_eq!(
+            name(select(
+                &tenants,
+                "t/team/simple/",
+                Some("Bearer team-b-token")
+            )),
+            (Some("team".into()), "simple/".into())
+        );
+        assert_eq!(
+            name(select(&tenants, "simple/", Some("Bearer team-b-token"))),
+            (Some("team-b".into()), "simple/".into())
+        );
+        // unknown tenants and admin tokens select no tenant
+        assert_eq!(
+            name(select(
+                &tenants,
+                "t/other/simple/",
+                Some("Bearer team-admin")
+            )),
+            (None, "t/other/simple/".into())
+        );
+        assert_eq!(
+            name(select(&[], "t/team/simple/", None)),
+            (None, "t/team/simple/".into())
+        );
+    }
+
+    #[test]
+    fn tenant_admin_label() {
+        let tenants = tenants();
+        let (tenant, label) = admin_label(&tenants, Some("Bearer team-b-admin")).unwrap();
+        assert_eq!(tenant.name, "team-b");
+        assert_eq!(label, "team-b/ops");
+        assert!(admin_label(&tenants, Some("Bearer team-b-token")).is_none());
+        assert!(admin_label(&tenants, None).is_none());
+    }
+
+    #[test]
+    fn split_tenant_cache_id() {
+        let tenants = tenants();
+        let split = |id: &str| {
+            let (tenant, policy) = split_cache_id(&tenants, id)?;
+            Some((tenant.name.clone(), policy.to_string()))
+        };
+        assert_eq!(
+            split("team_pypi_lru"),
+            Some(("team".into(), "pypi_lru".into()))
+        );
+        assert_eq!(
+            split("team-b_pypi_lru"),
+            Some(("team-b".into(), "pypi_lru".into()))
+        );
+        assert_eq!(split("pypi_lru"), None);
+        assert_eq!(split("team"), None);
+    }
+}
@@ -0,0 +1,1923 @@
+//! Harness of end-to-end tests: a mock upstream with programmable responses,
+//! and a `TaskManager` of a throwaway cache in front of it.
+//!
+//! Compiled in tests, and with the `test-util` feature so that forks can
+//! test their own rules and policies the same way:
+//!
+//! ```ignore
+//! let harness = Harness::builder("my_test").lru(1024).build().await;
+//! harness.upstream.mock("pkg.bin", MockResponse::ok("content"));
+//! let (body, status) = harness.get_body("mock/pkg.bin").await;
+//! ```
+
+use crate::cache::CacheData;
+use crate::error::{Error, Result};
+use crate::rules::RuleMatcher;
+use crate::settings::Settings;
+use crate::storage::{MemBackend, PersistReport, StorageBackend, StorageMeta};
+use crate::task::{CacheStatus, ResolveOutcome, Task, TaskManager, TaskResponse};
+use crate::tenant;
+use crate::upstreams::Status;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::hyper::Body;
+use warp::Filter;
+
+/// Redis of tests with redis metadata
+pub const REDIS_URL: &str = "redis://localhost:3001/";
+
+/// Longest time to wait for background tasks
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A response of the mock upstream. The body is sent in chunks, with the
+/// `Content-Length` of all of them.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    chunks: Vec<Bytes>,
+    /// Before the headers are sent
+    delay: Duration,
+    /// Between two chunks
+    chunk_delay: Duration,
+    /// The connection is reset instead of sending the chunk of this index
+    fail_at_chunk: Option<usize>,
+}
+
+impl MockResponse {
+    /// `200 OK` with `body`
+    pub fn ok(body: impl Into<Bytes>) -> Self {
+        Self::chunked(vec![body.into()], Duration::from_secs(0))
+    }
+
+    /// `200 OK` with a body streamed in `chunks`, `chunk_delay` apart
+    pub fn chunked(chunks: Vec<Bytes>, chunk_delay: Duration) -> Self {
+        MockResponse {
+            status: 200,
+            headers: Vec::new(),
+            chunks,
+            delay: Duration::from_secs(0),
+            chunk_delay,
+            fail_at_chunk: None,
+        }
+    }
+
+    /// An empty response of `status`
+    pub fn status(status: u16) -> Self {
+        MockResponse {
+            status,
+            ..Self::chunked(Vec::new(), Duration::from_secs(0))
+        }
+    }
+
+    /// Set a header, e.g. a `Content-Length` other than the one of the chunks
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Wait before sending the headers
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Reset the connection after sending `chunks` chunks of the body
+    pub fn fail_after(mut self, chunks: usize) -> Self {
+        self.fail_at_chunk = Some(chunks);
+        self
+    }
+
+    async fn into_response(self) -> warp::reply::Response {
+        tokio::time::sleep(self.delay).await;
+        let len: usize = self.chunks.iter().map(Bytes::len).sum();
+        let chunk_delay = self.chunk_delay;
+        let fail_at_chunk = self.fail_at_chunk.unwrap_or(usize::MAX);
+        let body = futures::stream::iter(self.chunks.into_iter().enumerate()).then(
+            move |(idx, chunk)| async move {
+                if idx > 0 {
+                    tokio::time::sleep(chunk_delay).await;
+                }
+                if idx >= fail_at_chunk {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "failure injected by the mock upstream",
+                    ));
+                }
+                Ok(chunk)
+            },
+        );
+        let mut resp = warp::http::Response::builder()
+            .status(self.status)
+            .header("Content-Length", len)
+            .body(Body::wrap_stream(body))
+            .unwrap();
+        for (name, value) in &self.headers {
+            resp.headers_mut().insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        resp
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Responses by path, without the leading `/`
+    responses: Mutex<HashMap<String, MockResponse>>,
+    /// Responses by path and media type of the `Accept` header, preferred
+    /// over the ones of the path alone
+    negotiated: Mutex<HashMap<(String, String), MockResponse>>,
+    /// Responses by path, `Range` and `If-Range` headers, preferred over
+    /// the others
+    ranged: Mutex<HashMap<(String, String, String), MockResponse>>,
+    hits: Mutex<HashMap<String, usize>>,
+}
+
+/// An HTTP server on an ephemeral port, answering paths with the responses
+/// they are mocked with, and others with `404 Not Found`
+#[derive(Clone)]
+pub struct MockUpstream {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    /// Served over HTTPS, see `start_tls`
+    tls: bool,
+}
+
+/// Certificate of `localhost` signed by `TLS_CA_CERT`, and its key, see
+/// `testdata/tls/gen.sh`
+const TLS_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/localhost.pem");
+const TLS_KEY: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/testdata/tls/localhost-key.pem"
+);
+
+/// The CA certificate to trust for upstreams started with `start_tls`, e.g.
+/// as `http_client.ca_cert`
+pub const TLS_CA_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/ca.pem");
+
+impl MockUpstream {
+    /// Start serving in the background of the current runtime
+    pub fn start() -> Self {
+        Self::serve(false)
+    }
+
+    /// Start serving over HTTPS with a certificate of `localhost` signed by
+    /// `TLS_CA_CERT`
+    pub fn start_tls() -> Self {
+        Self::serve(true)
+    }
+
+    fn serve(tls: bool) -> Self {
+        let state = Arc::new(MockState::default());
+        let server_state = state.clone();
+        let routes = warp::path::tail()
+            .and(warp::header::optional::<String>("accept"))
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::header::optional::<String>("if-range"))
+            .and_then(
+                move |tail: warp::filters::path::Tail,
+                      accept: Option<String>,
+                      range: Option<String>,
+                      if_range: Option<String>| {
+                    let state = server_state.clone();
+                    async move {
+                        let path = tail.as_str().to_string();
+                        *state.hits.lock().unwrap().entry(path.clone()).or_insert(0) += 1;
+                        let ranged = range.and_then(|range| {
+                            let key = (path.clone(), range, if_range.unwrap_or_default());
+                            state.ranged.lock().unwrap().get(&key).cloned()
+                        });
+                        let negotiated = accept.and_then(|accept| {
+                            let media_type = accept.split(',').next()?.trim().to_string();
+                            let key = (path.clone(), media_type);
+                            state.negotiated.lock().unwrap().get(&key).cloned()
+                        });
+                        let mock = ranged
+                            .or(negotiated)
+                            .or_else(|| state.responses.lock().unwrap().get(&path).cloned());
+                        Ok::<_, Infallible>(match mock {
+                            Some(mock) => mock.into_response().await,
+                            None => not_found(),
+                        })
+                    }
+                },
+            );
+        let server = warp::serve(routes);
+        let addr = if tls {
+            let (addr, server) = server
+                .tls()
+                .cert_path(TLS_CERT)
+                .key_path(TLS_KEY)
+                .bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            addr
+        } else {
+            let (addr, server) = server.bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            addr
+        };
+        MockUpstream { addr, state, tls }
+    }
+
+    /// e.g. `http://127.0.0.1:34567/`, or `https://localhost:34567/` over
+    /// HTTPS, the name its certificate is valid for
+    pub fn url(&self) -> String {
+        if self.tls {
+            format!("https://localhost:{}/", self.addr.port())
+        } else {
+            format!("http://{}/", self.addr)
+        }
+    }
+
+    /// Answer requests of `path` with `response` from now on
+    pub fn mock(&self, path: &str, response: MockResponse) {
+        self.state
+            .responses
+            .lock()
+            .unwrap()
+            .insert(path.trim_start_matches('/').to_string(), response);
+    }
+
+    /// Answer requests of `path` asking first for `media_type` in their
+    /// `Accept` header with `response` from now on
+    pub fn mock_accepting(&self, path: &str, media_type: &str, response: MockResponse) {
+        let key = (
+            path.trim_start_matches('/').to_string(),
+            media_type.to_string(),
+        );
+        self.state.negotiated.lock().unwrap().insert(key, response);
+    }
+
+    /// Answer requests of `path` with the headers `Range: <range>` and
+    /// `If-Range: <if_range>` with `response` from now on
+    pub fn mock_range(&self, path: &str, range: &str, if_range: &str, response: MockResponse) {
+        let path = path.trim_start_matches('/').to_string();
+        let key = (path, range.to_string(), if_range.to_string());
+        self.state.ranged.lock().unwrap().insert(key, response);
+    }
+
+    /// The number of requests of `path`, mocked or not
+    pub fn hits(&self, path: &str) -> usize {
+        let hits = self.state.hits.lock().unwrap();
+        *hits.get(path.trim_start_matches('/')).unwrap_or(&0)
+    }
+}
+
+fn not_found() -> warp::reply::Response {
+    warp::http::Response::builder()
+        .status(404)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// An operation of a storage backend, see `MockBackend::fail`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StorageOp {
+    Read,
+    Persist,
+    Remove,
+    Metadata,
+}
+
+/// A storage backend keeping objects in memory, whose operations can be made
+/// to fail or block, e.g. to test how a cache copes with a full, flaky or slow
+/// disk
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    inner: MemBackend,
+    failing: Arc<Mutex<HashSet<StorageOp>>>,
+    delays: Arc<Mutex<HashMap<StorageOp, Duration>>>,
+    calls: Arc<Mutex<HashMap<StorageOp, usize>>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail `op` with an IO error from now on, or stop failing it
+    pub fn fail(&self, op: StorageOp, failing: bool) {
+        let mut ops = self.failing.lock().unwrap();
+        if failing {
+            ops.insert(op);
+        } else {
+            ops.remove(&op);
+        }
+    }
+
+    /// Block the thread calling `op` for `delay` from now on, like a slow disk
+    pub fn delay(&self, op: StorageOp, delay: Duration) {
+        self.delays.lock().unwrap().insert(op, delay);
+    }
+
+    /// The number of calls of `op`, failed or not
+    pub fn calls(&self, op: StorageOp) -> usize {
+        *self.calls.lock().unwrap().get(&op).unwrap_or(&0)
+    }
+
+    fn call(&self, op: StorageOp) -> Result<()> {
+        *self.calls.lock().unwrap().entry(op).or_insert(0) += 1;
+        let delay = self.delays.lock().unwrap().get(&op).copied();
+        if let Some(delay) = delay {
+            std::thread::sleep(delay);
+        }
+        if self.failing.lock().unwrap().contains(&op) {
+            return Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("injected failure of {:?}", op),
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MockBackend {
+    async fn read(&self, name: &str) -> Result<CacheData> {
+        self.call(StorageOp::Read)?;
+        self.inner.read(name).await
+    }
+
+    async fn persist_changed(
+        &self,
+        name: &str,
+        data: CacheData,
+        known_sha256: Option<&str>,
+    ) -> Result<PersistReport> {
+        self.call(StorageOp::Persist)?;
+        self.inner.persist_changed(name, data, known_sha256).await
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        self.call(StorageOp::Remove)?;
+        self.inner.remove(name).await
+    }
+
+    async fn metadata(&self, name: &str) -> Result<StorageMeta> {
+        self.call(StorageOp::Metadata)?;
+        self.inner.metadata(name).await
+    }
+}
+
+/// A directory removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mirror-cache-{}-{}", name, unique_suffix()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Unique across tests and runs, so that tests sharing a redis do not see
+/// the keys of each other
+fn unique_suffix() -> String {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!(
+        "{}_{}_{}",
+        std::process::id(),
+        nanos,
+        SEQ.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+/// Builds a `Harness`. By default, the cache is an LRU cache of 1 GiB with
+/// sled metadata.
+pub struct HarnessBuilder {
+    name: String,
+    /// YAML of the fields of the policy specific to its type
+    policy: String,
+    metadata_db: &'static str,
+    /// YAML of the `options` field of the `mock/` rule, if any
+    rule_options: String,
+    /// YAML of the `admission` field of the `mock/` rule, if any
+    admission: String,
+    /// YAML of the `revalidate_after` field of the `mock/` rule, if any
+    revalidate_after: String,
+    /// Regex of the mutable keys of the `mock/` rule, and the timeout of the
+    /// TTL cache they are routed to, if any
+    mutable_keys: Option<(String, u64)>,
+    /// Upstream of the `mock/` rule instead of the mock upstream
+    upstream: Option<String>,
+    /// YAML of the `rewrite` and `rewrite_from` fields of the `mock/` rule
+    rewrite: String,
+    /// YAML of the global `max_rewrite_body_bytes`, if any
+    max_rewrite_body: String,
+    /// YAML of the `tenants`, if any
+    tenants: String,
+    /// Whether the `mock/` rule appends the hashes of its cached files to
+    /// the links of its pages
+    hash_links: bool,
+    /// YAML of the global `snapshot_storage`, if any
+    snapshot_storage: String,
+}
+
+impl HarnessBuilder {
+    /// An LRU cache of `size` bytes
+    pub fn lru(mut self, size: u64) -> Self {
+        self.policy = format!("type: LRU\n    size: {}", size);
+        self
+    }
+
+    /// A TTL cache whose entries expire after `timeout` secs
+    pub fn ttl(mut self, timeout: u64) -> Self {
+        self.policy = format!("type: TTL\n    timeout: {}", timeout);
+        self
+    }
+
+    /// A NONE policy caching nothing, with the micro-cache given as YAML
+    /// unless it is empty
+    pub fn pass_through(mut self, micro_cache: &str) -> Self {
+        self.policy = "type: NONE".to_string();
+        if !micro_cache.is_empty() {
+            self.policy.push_str("\n    micro_cache:");
+            for line in micro_cache.lines() {
+                self.policy.push_str(&format!("\n      {}", line));
+            }
+        }
+        self
+    }
+
+    /// Keep the metadata in the redis at `REDIS_URL`, under keys prefixed by
+    /// a unique cache id
+    pub fn redis(mut self) -> Self {
+        self.metadata_db = "redis";
+        self
+    }
+
+    /// Set the `options` of the `mock/` rule, given as YAML
+    pub fn rule_options(mut self, options: &str) -> Self {
+        self.rule_options = options
+            .lines()
+            .fold("\n    options:".to_string(), |yaml, line| {
+                format!("{}\n      {}", yaml, line)
+            });
+        self
+    }
+
+    /// Cache objects of the `mock/` rule from their `admit_after`th request
+    pub fn admission(mut self, admit_after: u32) -> Self {
+        self.admission = format!("\n    admission:\n      admit_after: {}", admit_after);
+        self
+    }
+
+    /// Revalidate files of the `mock/` rule cached more than `secs` ago
+    pub fn revalidate_after(mut self, secs: u64) -> Self {
+        self.revalidate_after = format!("\n    revalidate_after: {}", secs);
+        self
+    }
+
+    /// Cache the keys of the `mock/` rule matching `pattern` in a TTL cache
+    /// whose entries expire after `timeout` secs, the other keys are
+    /// immutable
+    pub fn mutable_keys(mut self, pattern: &str, timeout: u64) -> Self {
+        self.mutable_keys = Some((pattern.to_string(), timeout));
+        self
+    }
+
+    /// Fetch files of the `mock/` rule from `url` instead of the mock
+    /// upstream, e.g. from another harness served over HTTP
+    pub fn upstream(mut self, url: &str) -> Self {
+        self.upstream = Some(url.to_string());
+        self
+    }
+
+    /// Rewrite `from` to `to` in responses of the `mock/` rule
+    pub fn rewrite(mut self, from: &str, to: &str) -> Self {
+        if !self.rewrite.contains("\n    rewrite:") {
+            self.rewrite.push_str("\n    rewrite:");
+        }
+        self.rewrite.push_str(&format!(
+            "\n      - from: \"{}\"\n        to: \"{}\"",
+            from, to
+        ));
+        self
+    }
+
+    /// Set the `rewrite_from` of the `mock/` rule
+    pub fn rewrite_from(mut self, base: &str) -> Self {
+        self.rewrite = format!("\n    rewrite_from: \"{}\"{}", base, self.rewrite);
+        self
+    }
+
+    /// Pass bodies over `bytes` through unrewritten
+    pub fn max_rewrite_body_bytes(mut self, bytes: u64) -> Self {
+        self.max_rewrite_body = format!("\nmax_rewrite_body_bytes: {}", bytes);
+        self
+    }
+
+    /// Tenants with the given names and no tokens, whose requests are
+    /// prefixed by `t/<tenant>/`
+    pub fn tenants(mut self, names: &[&str]) -> Self {
+        self.tenants = names.iter().fold("\ntenants:".to_string(), |yaml, name| {
+            format!("{}\n  - name: \"{}\"", yaml, name)
+        });
+        self
+    }
+
+    /// Append the hashes of the files cached by the `mock/` rule to the
+    /// links of its html pages, see `Options::hash_links_from`
+    pub fn hash_links(mut self) -> Self {
+        self.hash_links = true;
+        self
+    }
+
+    /// Keep the pages of snapshots in the storage of the cache
+    pub fn snapshots(mut self) -> Self {
+        self.snapshot_storage = "\nsnapshot_storage: fs".to_string();
+        self
+    }
+
+    pub async fn build(mut self) -> Harness {
+        let upstream = MockUpstream::start();
+        let dir = TempDir::new(&self.name);
+        let policy = format!("{}_{}", self.name, unique_suffix());
+        let (key_classes, mutable_policy) = match &self.mutable_keys {
+            Some((pattern, timeout)) => (
+                format!(
+                    "\n    key_classes:\n      mutable: [\"{}\"]\n      \
+                     mutable_policy: \"{}_mutable\"",
+                    pattern, policy
+                ),
+                format!(
+                    "\n  - name: \"{}_mutable\"\n    type: TTL\n    timeout: {}\n    \
+                     metadata_db: {}\n    storage: fs",
+                    policy, timeout, self.metadata_db
+                ),
+            ),
+            None => (String::new(), String::new()),
+        };
+        if self.hash_links {
+            if self.rule_options.is_empty() {
+                self.rule_options.push_str("\n    options:");
+            }
+            self.rule_options
+                .push_str(&format!("\n      hash_links_from: \"{}\"", policy));
+        }
+        let config = format!(
+            r#"
+port: 9000
+metrics_port: 9001
+log_level: trace{max_rewrite_body}{tenants}{snapshot_storage}
+redis:
+  url: "{redis}"
+sled:
+  metadata_path: "{dir}/sled"
+rules:
+  - name: mock
+    path: "mock/"
+    upstream: "{upstream}"
+    policy: "{policy}"{rule_options}{admission}{revalidate_after}{key_classes}{rewrite}
+policies:
+  - name: "{policy}"
+    {policy_fields}
+    metadata_db: {metadata_db}
+    storage: fs{mutable_policy}
+storages:
+  - name: fs
+    config:
+      Fs:
+        path: "{dir}/storage"
+"#,
+            redis = REDIS_URL,
+            dir = dir.path().display(),
+            upstream = self.upstream.unwrap_or_else(|| upstream.url()),
+            policy = policy,
+            policy_fields = self.policy,
+            metadata_db = self.metadata_db,
+            rule_options = self.rule_options,
+            admission = self.admission,
+            revalidate_after = self.revalidate_after,
+            key_classes = key_classes,
+            mutable_policy = mutable_policy,
+            rewrite = self.rewrite,
+            max_rewrite_body = self.max_rewrite_body,
+            tenants = self.tenants,
+            snapshot_storage = self.snapshot_storage,
+        );
+        let config_path = dir.path().join("config.yml");
+        std::fs::write(&config_path, config).unwrap();
+        std::fs::create_dir_all(dir.path().join("storage")).unwrap();
+        let settings = Settings::new(config_path.to_str().unwrap()).unwrap();
+        let mut tm = TaskManager::new(settings.clone());
+        tm.refresh_config(&settings);
+        Harness {
+            tm,
+            rules: RuleMatcher::new(&settings.rules).unwrap(),
+            settings,
+            upstream,
+            _dir: dir,
+        }
+    }
+}
+
+/// A `TaskManager` in front of a `MockUpstream`, with the rule `mock/`
+/// mapping request paths like `mock/pkg.bin` to `pkg.bin` of the upstream.
+/// Its files and sled metadata are removed when it is dropped.
+pub struct Harness {
+    pub tm: TaskManager,
+    pub upstream: MockUpstream,
+    pub settings: Settings,
+    rules: RuleMatcher,
+    _dir: TempDir,
+}
+
+impl Harness {
+    pub fn builder(name: &str) -> HarnessBuilder {
+        HarnessBuilder {
+            name: name.to_string(),
+            policy: format!("type: LRU\n    size: {}", 1u64 << 30),
+            metadata_db: "sled",
+            rule_options: String::new(),
+            admission: String::new(),
+            revalidate_after: String::new(),
+            mutable_keys: None,
+            upstream: None,
+            rewrite: String::new(),
+            max_rewrite_body: String::new(),
+            tenants: String::new(),
+            hash_links: false,
+            snapshot_storage: String::new(),
+        }
+    }
+
+    /// Serve GET requests with the `TaskManager` in the background, with
+    /// the cache status headers of the route handler. Returns the url, e.g.
+    /// `http://127.0.0.1:34567/`.
+    pub fn serve(&self) -> String {
+        let tm = self.tm.clone();
+        let rules = Arc::new(RuleMatcher::new(&self.settings.rules).unwrap());
+        let routes = warp::path::tail().and_then(move |tail: warp::filters::path::Tail| {
+            let tm = tm.clone();
+            let rules = rules.clone();
+            async move {
+                let task = rules
+                    .resolve("GET", tail.as_str(), None)
+                    .map(|(task, _)| task);
+                let (result, outcome) = match task {
+                    Some(task) => tm.resolve_task(&task, None).await,
+                    None => return Ok::<_, Infallible>(not_found()),
+                };
+                let mut resp = match result {
+                    Ok(resp) => warp::Reply::into_response(resp),
+                    Err(e) => warp::http::Response::builder()
+                        .status(e.status_code())
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+                outcome.set_headers(resp.headers_mut());
+                Ok(resp)
+            }
+        });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/", addr)
+    }
+
+    /// The task of a GET request of `path`, as the route handler resolves
+    /// it, e.g. of a tenant for a path prefixed by `t/<tenant>/`
+    pub fn task(&self, path: &str) -> Task {
+        let tenants = self.settings.tenants();
+        let (tenant, path) = tenant::select(tenants, path.trim_start_matches('/'), None);
+        let (mut task, _) = self
+            .rules
+            .resolve("GET", path, None)
+            .expect("no rule matches the path");
+        task.tenant = tenant.map(|tenant| tenant.name.clone());
+        task
+    }
+
+    pub async fn get(&self, path: &str) -> (Result<TaskResponse>, ResolveOutcome) {
+        self.tm.resolve_task(&self.task(path), None).await
+    }
+
+    /// Like `get`, with the body of the response read. Failures of the body
+    /// are returned as errors.
+    pub async fn get_body(&self, path: &str) -> (Result<Bytes>, CacheStatus) {
+        let (result, outcome) = self.get(path).await;
+        let body = match result {
+            Ok(resp) => {
+                let body = warp::Reply::into_response(resp).into_body();
+                warp::hyper::body::to_bytes(body)
+                    .await
+                    .map_err(|e| Error::OtherError(e.to_string()))
+            }
+            Err(e) => Err(e),
+        };
+        (body, outcome.status)
+    }
+
+    /// Whether the cache has an entry of `path`. It counts as a hit.
+    pub async fn is_cached(&self, path: &str) -> bool {
+        let task = self.task(path);
+        match self.tm.get_cache_for_task(&task) {
+            Some(cache) => cache.read().await.get(&task.to_key()).await.is_some(),
+            None => false,
+        }
+    }
+
+    /// Wait for a background task to populate the cache with `path`.
+    /// Returns whether it is cached in time.
+    pub async fn wait_until_cached(&self, path: &str) -> bool {
+        let started = std::time::Instant::now();
+        while started.elapsed() < WAIT_TIMEOUT {
+            if self.is_cached(path).await {
+                return true;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        false
+    }
+
+    /// Wait for all background tasks to complete, successfully or not
+    pub async fn wait_for_background_tasks(&self) {
+        let started = std::time::Instant::now();
+        while !self.tm.running_tasks().await.is_empty() {
+            assert!(
+                started.elapsed() < WAIT_TIMEOUT,
+                "background tasks still running"
+            );
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pep691;
+
+    async fn miss_then_hit(harness: Harness) {
+        let chunks = vec!["0123".into(), "4567".into(), "89".into()];
+        harness.upstream.mock(
+            "pkg.bin",
+            MockResponse::chunked(chunks, Duration::from_millis(50))
+                .delayed(Duration::from_millis(100)),
+        );
+        let (body, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "0123456789");
+        // the background task fetches the file again to populate the cache
+        assert!(harness.wait_until_cached("mock/pkg.bin").await);
+        let (result, outcome) = harness.get("mock/pkg.bin").await;
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        let resp = warp::Reply::into_response(result.unwrap());
+        assert_eq!(resp.headers()["Content-Length"], "10");
+        let body = warp::hyper::body::to_bytes(resp.into_body()).await;
+        assert_eq!(body.unwrap(), "0123456789");
+        assert_eq!(harness.upstream.hits("pkg.bin"), 2);
+    }
+
+    #[tokio::test]
+    async fn e2e_miss_then_hit() {
+        miss_then_hit(Harness::builder("e2e_hit").build().await).await;
+        miss_then_hit(Harness::builder("e2e_hit_redis").redis().build().await).await;
+        miss_then_hit(Harness::builder("e2e_hit_ttl").ttl(60).build().await).await;
+    }
+
+    async fn forced_refresh(harness: Harness) {
+        harness.upstream.mock("index.json", MockResponse::ok("v1"));
+        let (body, _) = harness.get_body("mock/index.json").await;
+        assert_eq!(body.unwrap(), "v1");
+        assert!(harness.wait_until_cached("mock/index.json").await);
+        // republished upstream, the cached file is served until it is refreshed
+        harness.upstream.mock("index.json", MockResponse::ok("v2"));
+        let (body, status) = harness.get_body("mock/index.json").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v1");
+        let task = harness.task("mock/index.json");
+        let (result, outcome) = harness.tm.refresh_task(&task).await;
+        assert_eq!(outcome.status, CacheStatus::Miss);
+        let body = warp::Reply::into_response(result.unwrap()).into_body();
+        assert_eq!(warp::hyper::body::to_bytes(body).await.unwrap(), "v2");
+        harness.wait_for_background_tasks().await;
+        // the fresh file replaces the cached one
+        let (body, status) = harness.get_body("mock/index.json").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v2");
+        assert_eq!(harness.upstream.hits("index.json"), 4);
+    }
+
+    #[tokio::test]
+    async fn e2e_forced_refresh() {
+        forced_refresh(Harness::builder("e2e_refresh").build().await).await;
+        forced_refresh(Harness::builder("e2e_refresh_ttl").ttl(60).build().await).await;
+    }
+
+    #[tokio::test]
+    async fn e2e_eviction_under_pressure() {
+        let harness = Harness::builder("e2e_eviction").lru(24).build().await;
+        for name in &["a.bin", "b.bin", "c.bin"] {
+            harness
+                .upstream
+                .mock(name, MockResponse::ok(vec![b'x'; 10]));
+            let path = format!("mock/{}", name);
+            let (_, status) = harness.get_body(&path).await;
+            assert_eq!(status, CacheStatus::Miss);
+            assert!(harness.wait_until_cached(&path).await);
+        }
+        // the least recently used file makes room for the third one
+        assert!(!harness.is_cached("mock/a.bin").await);
+        assert!(harness.is_cached("mock/b.bin").await);
+        assert!(harness.is_cached("mock/c.bin").await);
+        let policy = &harness.settings.rules[0].policy;
+        let stats = harness.tm.cache_stats(policy).await.unwrap();
+        assert_eq!((stats.entries, stats.bytes), (2, 20));
+        let (body, status) = harness.get_body("mock/a.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap().len(), 10);
+    }
+
+    async fn digest_keys(harness: Harness) {
+        let name = format!("{}.bin", "x".repeat(300));
+        let path = format!("mock/{}", name);
+        harness
+            .upstream
+            .mock(&name, MockResponse::ok(vec![b'x'; 10]));
+        let task = harness.task(&path);
+        let key = task.to_key();
+        assert!(key.contains("/~sha256-") && key.len() < 128, "{}", key);
+        let (body, status) = harness.get_body(&path).await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap().len(), 10);
+        assert!(harness.wait_until_cached(&path).await);
+        let (body, status) = harness.get_body(&path).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap().len(), 10);
+        // listed with the url it stands for
+        let policy = &harness.settings.rules[0].policy;
+        let page = harness.tm.list_entries(policy, "", 100).await.unwrap();
+        let entry = page.entries.iter().find(|entry| entry.key == key).unwrap();
+        assert_eq!(entry.url.as_ref(), Some(&task.url));
+    }
+
+    #[tokio::test]
+    async fn e2e_digest_keys() {
+        digest_keys(Harness::builder("e2e_digest").redis().build().await).await;
+        digest_keys(Harness::builder("e2e_digest_ttl").ttl(60).build().await).await;
+        // evicted like any other entry
+        let harness = Harness::builder("e2e_digest_evict")
+            .lru(24)
+            .redis()
+            .build()
+            .await;
+        let long = format!("mock/{}.bin", "x".repeat(300));
+        for path in &[long.as_str(), "mock/b.bin", "mock/c.bin"] {
+            let name = path.trim_start_matches("mock/");
+            harness
+                .upstream
+                .mock(name, MockResponse::ok(vec![b'x'; 10]));
+            let (body, status) = harness.get_body(path).await;
+            assert_eq!(status, CacheStatus::Miss);
+            assert_eq!(body.unwrap(), vec![b'x'; 10]);
+            assert!(harness.wait_until_cached(path).await);
+        }
+        assert!(!harness.is_cached(&long).await);
+        assert!(harness.is_cached("mock/c.bin").await);
+        let (_, status) = harness.get_body(&long).await;
+        assert_eq!(status, CacheStatus::Miss);
+    }
+
+    #[tokio::test]
+    async fn e2e_upstream_failures() {
+        let harness = Harness::builder("e2e_failure").build().await;
+        harness.upstream.mock(
+            "down.bin",
+            MockResponse::status(503).with_header("Retry-After", "5"),
+        );
+        let (result, outcome) = harness.get("mock/down.bin").await;
+        assert!(matches!(
+            result,
+            Err(Error::UpstreamRequestError(res))
+                if res.status() == 503 && res.headers()["Retry-After"] == "5"
+        ));
+        assert_eq!(outcome.status, CacheStatus::Uncacheable);
+        let (result, _) = harness.get("mock/not-mocked.bin").await;
+        assert!(matches!(result, Err(Error::UpstreamRequestError(res)) if res.status() == 404));
+
+        // the connection is reset in the middle of the body
+        let chunks = vec!["0123".into(), "4567".into()];
+        harness.upstream.mock(
+            "cut.bin",
+            MockResponse::chunked(chunks, Duration::from_millis(10)).fail_after(1),
+        );
+        let (body, status) = harness.get_body("mock/cut.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(body.is_err());
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("cut.bin"), 2);
+        assert!(!harness.is_cached("mock/cut.bin").await);
+        assert!(!harness.is_cached("mock/down.bin").await);
+    }
+
+    #[tokio::test]
+    async fn e2e_resume_interrupted_download() {
+        let harness = Harness::builder("e2e_resume_interrupted_download")
+            .build()
+            .await;
+        let cut = || {
+            let chunks = vec!["0123".into(), "4567".into()];
+            MockResponse::chunked(chunks, Duration::from_millis(10))
+                .with_header("ETag", "\"v1\"")
+                .fail_after(1)
+        };
+        // the background task fetches the rest of the same object
+        harness.upstream.mock("pkg.bin", cut());
+        let rest = MockResponse::ok("4567").with_header("Content-Range", "bytes 4-7/8");
+        let rest = MockResponse {
+            status: 206,
+            ..rest
+        };
+        harness
+            .upstream
+            .mock_range("pkg.bin", "bytes=4-", "\"v1\"", rest);
+        let (_, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(harness.wait_until_cached("mock/pkg.bin").await);
+        let (body, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "01234567");
+        // the client and the background task fetch it, then the task the rest
+        assert_eq!(harness.upstream.hits("pkg.bin"), 3);
+
+        // an object changed meanwhile is answered whole, and never assembled
+        // from both versions
+        harness.upstream.mock("new.bin", cut());
+        harness.upstream.mock_range(
+            "new.bin",
+            "bytes=4-",
+            "\"v1\"",
+            MockResponse::ok("ABCDEFGH"),
+        );
+        // the client is served the interrupted body
+        let (body, status) = harness.get_body("mock/new.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(body.is_err());
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("new.bin"), 3);
+        assert!(!harness.is_cached("mock/new.bin").await);
+        harness
+            .upstream
+            .mock("new.bin", MockResponse::ok("ABCDEFGH"));
+        let (body, status) = harness.get_body("mock/new.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "ABCDEFGH");
+        assert!(harness.wait_until_cached("mock/new.bin").await);
+        let (body, _) = harness.get_body("mock/new.bin").await;
+        assert_eq!(body.unwrap(), "ABCDEFGH");
+    }
+
+    #[tokio::test]
+    async fn e2e_pass_through() {
+        let harness = Harness::builder("e2e_pass_through")
+            .pass_through("")
+            .build()
+            .await;
+        harness
+            .upstream
+            .mock("pkg.bin", MockResponse::ok("0123456789"));
+        for _ in 0..2 {
+            let (body, status) = harness.get_body("mock/pkg.bin").await;
+            assert_eq!(status, CacheStatus::Bypass);
+            assert_eq!(body.unwrap(), "0123456789");
+        }
+        // nothing is fetched in the background to be thrown away
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("pkg.bin"), 2);
+
+        let harness = Harness::builder("e2e_micro_cache")
+            .pass_through("ttl: 60\nnegative_ttl: 60\nmax_entry_size: 8 B")
+            .build()
+            .await;
+        harness.upstream.mock("small.bin", MockResponse::ok("0123"));
+        harness
+            .upstream
+            .mock("big.bin", MockResponse::ok("0123456789"));
+        let (body, status) = harness.get_body("mock/small.bin").await;
+        assert_eq!(status, CacheStatus::Bypass);
+        assert_eq!(body.unwrap(), "0123");
+        assert!(harness.wait_until_cached("mock/small.bin").await);
+        let (body, status) = harness.get_body("mock/small.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "0123");
+        assert_eq!(harness.upstream.hits("small.bin"), 1);
+        // too large to be kept, but served in full
+        let (body, _) = harness.get_body("mock/big.bin").await;
+        assert_eq!(body.unwrap(), "0123456789");
+        let (body, status) = harness.get_body("mock/big.bin").await;
+        assert_eq!(status, CacheStatus::Bypass);
+        assert_eq!(body.unwrap(), "0123456789");
+        // upstream errors are remembered too
+        let (result, _) = harness.get("mock/missing.bin").await;
+        assert!(matches!(result, Err(Error::UpstreamRequestError(res)) if res.status() == 404));
+        let (result, outcome) = harness.get("mock/missing.bin").await;
+        assert!(matches!(result, Err(Error::UpstreamStatus(404))));
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        assert_eq!(harness.upstream.hits("missing.bin"), 1);
+    }
+
+    #[tokio::test]
+    async fn e2e_conda_channels() {
+        let (main, forge) = (MockUpstream::start(), MockUpstream::start());
+        let file = "linux-64/pkg-1.0-0.conda";
+        main.mock(file, MockResponse::ok("from main"));
+        forge.mock(file, MockResponse::ok("from conda-forge"));
+        let channels = format!(
+            "conda_channels:\n  main: \"{}\"\n  conda-forge: \"{}\"",
+            main.url(),
+            forge.url()
+        );
+        let harness = Harness::builder("e2e_conda")
+            .rule_options(&channels)
+            .build()
+            .await;
+        let main_path = format!("mock/main/{}", file);
+        let forge_path = format!("mock/conda-forge/{}", file);
+        assert_ne!(
+            harness.task(&main_path).to_key(),
+            harness.task(&forge_path).to_key()
+        );
+        for (path, body) in &[(&main_path, "from main"), (&forge_path, "from conda-forge")] {
+            assert_eq!(harness.get_body(path).await.0.unwrap(), *body);
+            assert!(harness.wait_until_cached(path).await);
+            let (cached, status) = harness.get_body(path).await;
+            assert_eq!(status, CacheStatus::Hit);
+            assert_eq!(cached.unwrap(), *body);
+        }
+        assert_eq!(main.hits(file), 2);
+        assert_eq!(forge.hits(file), 2);
+        // unknown channels are not found
+        let unknown = format!("mock/bioconda/{}", file);
+        assert!(harness.rules.resolve("GET", &unknown, None).is_none());
+
+        let harness = Harness::builder("e2e_conda_passthrough")
+            .rule_options(&format!("{}\nunknown_conda_channel: upstream", channels))
+            .build()
+            .await;
+        harness.upstream.mock(
+            &format!("bioconda/{}", file),
+            MockResponse::ok("from default"),
+        );
+        assert_eq!(harness.get_body(&unknown).await.0.unwrap(), "from default");
+        assert_eq!(harness.get_body(&main_path).await.0.unwrap(), "from main");
+    }
+
+    #[tokio::test]
+    async fn e2e_admission() {
+        let harness = Harness::builder("e2e_admission").admission(2).build().await;
+        harness
+            .upstream
+            .mock("pkg.bin", MockResponse::ok("0123456789"));
+        // the first request is served pass-through
+        let (body, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Bypass);
+        assert_eq!(body.unwrap(), "0123456789");
+        harness.wait_for_background_tasks().await;
+        assert!(!harness.is_cached("mock/pkg.bin").await);
+        assert_eq!(harness.upstream.hits("pkg.bin"), 1);
+        // the second one populates the cache
+        let (body, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "0123456789");
+        assert!(harness.wait_until_cached("mock/pkg.bin").await);
+        let (body, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "0123456789");
+        assert_eq!(harness.upstream.hits("pkg.bin"), 3);
+    }
+
+    async fn revalidate_after(harness: Harness) {
+        harness
+            .upstream
+            .mock("latest/pkg.bin", MockResponse::ok("v1"));
+        let (_, status) = harness.get_body("mock/latest/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(harness.wait_until_cached("mock/latest/pkg.bin").await);
+        harness
+            .upstream
+            .mock("latest/pkg.bin", MockResponse::ok("v2.0"));
+        // a recent entry is served without asking the upstream
+        let (body, status) = harness.get_body("mock/latest/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v1");
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("latest/pkg.bin"), 2);
+        // an old entry is still served, and replaced in the background
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (body, status) = harness.get_body("mock/latest/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v1");
+        harness.wait_for_background_tasks().await;
+        let (body, status) = harness.get_body("mock/latest/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v2.0");
+        assert_eq!(harness.upstream.hits("latest/pkg.bin"), 3);
+        let task = harness.task("mock/latest/pkg.bin");
+        let cache = harness.tm.get_cache_for_cache_rule(task.rule_id).unwrap();
+        let sizes = cache.read().await.entry_sizes(&[task.to_key()]).unwrap();
+        assert_eq!(sizes, vec![4]);
+        // an entry the upstream did not modify is kept, and its age restarts
+        harness
+            .upstream
+            .mock("latest/pkg.bin", MockResponse::status(304));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (body, _) = harness.get_body("mock/latest/pkg.bin").await;
+        assert_eq!(body.unwrap(), "v2.0");
+        harness.wait_for_background_tasks().await;
+        let (body, status) = harness.get_body("mock/latest/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v2.0");
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("latest/pkg.bin"), 4);
+    }
+
+    #[tokio::test]
+    async fn e2e_revalidate_after() {
+        let harness = Harness::builder("e2e_revalidate").revalidate_after(1);
+        revalidate_after(harness.build().await).await;
+        let harness = Harness::builder("e2e_revalidate_redis").revalidate_after(1);
+        revalidate_after(harness.redis().build().await).await;
+    }
+
+    async fn key_classes(harness: Harness) {
+        harness
+            .upstream
+            .mock("v1.2.3/pkg.bin", MockResponse::ok("v1.2.3"));
+        harness
+            .upstream
+            .mock("latest/pkg.bin", MockResponse::ok("v1.2.3"));
+        for path in &["mock/v1.2.3/pkg.bin", "mock/latest/pkg.bin"] {
+            let (_, status) = harness.get_body(path).await;
+            assert_eq!(status, CacheStatus::Miss);
+            assert!(harness.wait_until_cached(path).await);
+        }
+        // each key is in the cache of its class
+        let lru = harness
+            .tm
+            .get_cache_for_policy(&harness.settings.rules[0].policy);
+        let lru = lru.unwrap();
+        let versioned = harness.task("mock/v1.2.3/pkg.bin").to_key();
+        let latest = harness.task("mock/latest/pkg.bin").to_key();
+        assert!(lru.read().await.get(&versioned).await.is_some());
+        assert!(lru.read().await.get(&latest).await.is_none());
+        harness
+            .upstream
+            .mock("v1.2.3/pkg.bin", MockResponse::ok("changed"));
+        harness
+            .upstream
+            .mock("latest/pkg.bin", MockResponse::ok("v1.2.4"));
+        // `latest` expires, the versioned file is kept
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (body, status) = harness.get_body("mock/v1.2.3/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v1.2.3");
+        let (body, status) = harness.get_body("mock/latest/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "v1.2.4");
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("v1.2.3/pkg.bin"), 2);
+    }
+
+    #[tokio::test]
+    async fn e2e_key_classes() {
+        let harness = Harness::builder("e2e_key_classes").mutable_keys("/latest/", 1);
+        key_classes(harness.build().await).await;
+        let harness = Harness::builder("e2e_key_classes_redis").mutable_keys("/latest/", 1);
+        key_classes(harness.redis().build().await).await;
+    }
+
+    #[tokio::test]
+    async fn e2e_pypi_root_index() {
+        let harness = Harness::builder("e2e_pypi_root_index")
+            .ttl(1)
+            .rule_options("pep503: true\nroot_index_ttl: 3600")
+            .build()
+            .await;
+        // a link is split across chunks
+        let chunks = vec![
+            Bytes::from_static(b"<a href=\"/simple/flask/\">flask</a>\n<a href=\"/sim"),
+            Bytes::from_static(b"ple/torch/\">torch</a>\n"),
+        ];
+        harness.upstream.mock(
+            "simple/",
+            MockResponse::chunked(chunks, Duration::from_millis(10)),
+        );
+        harness
+            .upstream
+            .mock("simple/flask/", MockResponse::ok("flask index"));
+        let rewritten = "<a href=\"flask/\">flask</a>\n<a href=\"torch/\">torch</a>\n";
+        let (body, status) = harness.get_body("mock/simple/").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), rewritten);
+        assert!(harness.wait_until_cached("mock/simple/").await);
+        // project indexes are cached next to the root index, and expire first
+        let (body, _) = harness.get_body("mock/simple/flask/").await;
+        assert_eq!(body.unwrap(), "flask index");
+        assert!(harness.wait_until_cached("mock/simple/flask/").await);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!harness.is_cached("mock/simple/flask/").await);
+        let (body, status) = harness.get_body("mock/simple/").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), rewritten);
+        assert_eq!(harness.upstream.hits("simple/"), 2);
+    }
+
+    /// The JSON representation of the index page `path`, and the outcome
+    async fn get_simple_json(harness: &Harness, path: &str) -> (Bytes, ResolveOutcome) {
+        let mut task = harness.task(path);
+        task.simple_json = true;
+        let (result, outcome) = harness.tm.resolve_task(&task, None).await;
+        let body = warp::Reply::into_response(result.unwrap()).into_body();
+        (warp::hyper::body::to_bytes(body).await.unwrap(), outcome)
+    }
+
+    #[tokio::test]
+    async fn e2e_pypi_json_index() {
+        let harness = Harness::builder("e2e_pypi_json_index")
+            .rule_options("pep503: true")
+            .build()
+            .await;
+        let json = r#"{"meta": {"api-version": "1.0"}, "name": "flask", "files": []}"#;
+        harness.upstream.mock(
+            "simple/flask/",
+            MockResponse::ok("flask index").with_header("Content-Type", "text/html"),
+        );
+        harness.upstream.mock_accepting(
+            "simple/flask/",
+            pep691::JSON_MEDIA_TYPE,
+            MockResponse::ok(json).with_header("Content-Type", pep691::JSON_MEDIA_TYPE),
+        );
+        let (body, outcome) = get_simple_json(&harness, "mock/simple/flask/").await;
+        assert_eq!(outcome.status, CacheStatus::Miss);
+        assert_eq!(outcome.content_type, Some(pep691::JSON_MEDIA_TYPE));
+        assert_eq!(body, json);
+        harness.wait_for_background_tasks().await;
+        // both representations are cached, each under its own key
+        let (body, status) = harness.get_body("mock/simple/flask/").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "flask index");
+        assert!(harness.wait_until_cached("mock/simple/flask/").await);
+        let (body, outcome) = get_simple_json(&harness, "mock/simple/flask/").await;
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        assert_eq!(body, json);
+        let (body, status) = harness.get_body("mock/simple/flask/").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "flask index");
+        assert_eq!(harness.upstream.hits("simple/flask/"), 4);
+
+        // an upstream serving HTML only
+        harness.upstream.mock(
+            "simple/torch/",
+            MockResponse::ok("torch index").with_header("Content-Type", "text/html"),
+        );
+        let (body, outcome) = get_simple_json(&harness, "mock/simple/torch/").await;
+        assert_eq!(outcome.content_type, None);
+        assert_eq!(body, "torch index");
+        assert!(harness.wait_until_cached("mock/simple/torch/").await);
+        harness.wait_for_background_tasks().await;
+        let hits = harness.upstream.hits("simple/torch/");
+        // it is not asked for JSON again for a while
+        let (body, outcome) = get_simple_json(&harness, "mock/simple/torch/").await;
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        assert_eq!(outcome.content_type, None);
+        assert_eq!(body, "torch index");
+        assert_eq!(harness.upstream.hits("simple/torch/"), hits);
+    }
+
+    #[tokio::test]
+    async fn e2e_dir_listing() {
+        let harness = Harness::builder("e2e_dir_listing")
+            .ttl(3600)
+            .rule_options("rewrite_dir_listing: true\ndir_listing_ttl: 1")
+            .build()
+            .await;
+        let listing = format!(
+            "<html>\r\n<head><title>Index of /pub/</title></head>\r\n<body>\r\n\
+             <h1>Index of /pub/</h1><hr><pre><a href=\"../\">../</a>\r\n\
+             <a href=\"{}pub/a.tar.gz\">a.tar.gz</a>\r\n\
+             <a href=\"/pub/v1/\">v1/</a>\r\n</pre><hr></body>\r\n</html>\r\n",
+            harness.upstream.url()
+        );
+        harness.upstream.mock(
+            "pub/",
+            MockResponse::ok(listing).with_header("Content-Type", "text/html"),
+        );
+        harness.upstream.mock(
+            "pub",
+            MockResponse::status(301).with_header("Location", "/pub/"),
+        );
+        harness
+            .upstream
+            .mock("pub/a.tar.gz", MockResponse::ok("archive"));
+        let rewritten = "<a href=\"a.tar.gz\">a.tar.gz</a>\r\n<a href=\"v1/\">v1/</a>";
+        let (body, status) = harness.get_body("mock/pub/").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(String::from_utf8(body.unwrap().to_vec())
+            .unwrap()
+            .contains(rewritten));
+        assert!(harness.wait_until_cached("mock/pub/").await);
+        let (body, status) = harness.get_body("mock/pub/").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert!(String::from_utf8(body.unwrap().to_vec())
+            .unwrap()
+            .contains(rewritten));
+        // files are cached next to the listing, and outlive it
+        let (body, _) = harness.get_body("mock/pub/a.tar.gz").await;
+        assert_eq!(body.unwrap(), "archive");
+        assert!(harness.wait_until_cached("mock/pub/a.tar.gz").await);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!harness.is_cached("mock/pub/").await);
+        assert!(harness.is_cached("mock/pub/a.tar.gz").await);
+
+        // the directory without a trailing slash is redirected, not cached
+        let (result, outcome) = harness.get("mock/pub").await;
+        assert_eq!(outcome.status, CacheStatus::Bypass);
+        let resp = warp::Reply::into_response(result.unwrap());
+        assert_eq!(resp.status(), 301);
+        assert_eq!(resp.headers()["Location"], "pub/");
+        harness.wait_for_background_tasks().await;
+        assert!(!harness.is_cached("mock/pub").await);
+    }
+
+    #[tokio::test]
+    async fn e2e_rewrite_body_limit() {
+        let harness = Harness::builder("e2e_rewrite_body_limit")
+            .max_rewrite_body_bytes(1024)
+            .rule_options("rewrite_dir_listing: true")
+            .build()
+            .await;
+        let listing = |path: &str, files: usize| {
+            let links: String = (0..files)
+                .map(|i| format!("<a href=\"/{}f{}\">f{}</a>\r\n", path, i, i))
+                .collect();
+            format!(
+                "<html><head><title>Index of /{}</title></head><body>\r\n{}</body></html>",
+                path, links
+            )
+        };
+        let small = listing("small/", 4);
+        let large = listing("large/", 100);
+        assert!(large.len() > 1024);
+        for (path, page) in [("small/", &small), ("large/", &large)].iter() {
+            harness.upstream.mock(
+                path,
+                MockResponse::ok(page.to_string()).with_header("Content-Type", "text/html"),
+            );
+        }
+
+        let (body, _) = harness.get_body("mock/small/").await;
+        let body = String::from_utf8(body.unwrap().to_vec()).unwrap();
+        assert!(body.contains("<a href=\"f0\">f0</a>"));
+
+        // the large listing is passed through and cached as is
+        let (body, status) = harness.get_body("mock/large/").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), large);
+        assert!(harness.wait_until_cached("mock/large/").await);
+        let (body, status) = harness.get_body("mock/large/").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), large);
+    }
+
+    async fn hash_links(harness: Harness) {
+        use sha2::{Digest, Sha256};
+        let index = "<html><body>\n\
+            <a href=\"../../packages/a-1.0.tar.gz\">a-1.0.tar.gz</a>\n\
+            <a href=\"../../packages/a-1.1.tar.gz\">a-1.1.tar.gz</a>\n\
+            </body></html>\n";
+        harness.upstream.mock(
+            "simple/a/",
+            MockResponse::ok(index).with_header("Content-Type", "text/html"),
+        );
+        harness
+            .upstream
+            .mock("packages/a-1.0.tar.gz", MockResponse::ok("package"));
+        let (body, status) = harness.get_body("mock/packages/a-1.0.tar.gz").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "package");
+        assert!(
+            harness
+                .wait_until_cached("mock/packages/a-1.0.tar.gz")
+                .await
+        );
+        // only the link of the cached file gets a hash
+        let hashed = format!(
+            "<a href=\"../../packages/a-1.0.tar.gz#sha256={:x}\">a-1.0.tar.gz</a>\n\
+             <a href=\"../../packages/a-1.1.tar.gz\">a-1.1.tar.gz</a>",
+            Sha256::digest(b"package")
+        );
+        let (body, status) = harness.get_body("mock/simple/a/").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(String::from_utf8(body.unwrap().to_vec())
+            .unwrap()
+            .contains(&hashed));
+        assert!(harness.wait_until_cached("mock/simple/a/").await);
+        let (body, status) = harness.get_body("mock/simple/a/").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert!(String::from_utf8(body.unwrap().to_vec())
+            .unwrap()
+            .contains(&hashed));
+    }
+
+    #[tokio::test]
+    async fn e2e_hash_links() {
+        hash_links(
+            Harness::builder("e2e_hash_links")
+                .hash_links()
+                .build()
+                .await,
+        )
+        .await;
+        let harness = Harness::builder("e2e_hash_links_redis")
+            .redis()
+            .hash_links()
+            .build()
+            .await;
+        hash_links(harness).await;
+    }
+
+    #[tokio::test]
+    async fn e2e_prefetch_metadata() {
+        let harness = Harness::builder("e2e_prefetch_metadata")
+            .rule_options("pep503: true\nprefetch_metadata: true")
+            .build()
+            .await;
+        let index = "<html><body>\n\
+            <a href=\"../../packages/tool-1.0-py3-none-any.whl\" \
+            data-dist-info-metadata=\"sha256=cd\" data-core-metadata=\"sha256=cd\">w</a>\n\
+            <a href=\"../../packages/tool-1.0.tar.gz\">tool-1.0.tar.gz</a>\n\
+            </body></html>\n";
+        harness.upstream.mock(
+            "simple/tool/",
+            MockResponse::ok(index).with_header("Content-Type", "text/html"),
+        );
+        let metadata = "packages/tool-1.0-py3-none-any.whl.metadata";
+        harness.upstream.mock(
+            metadata,
+            MockResponse::ok("Metadata-Version: 2.1\nName: tool\n"),
+        );
+        let (body, status) = harness.get_body("mock/simple/tool/").await;
+        assert_eq!(status, CacheStatus::Miss);
+        // the attributes are served as is
+        assert_eq!(body.unwrap(), index);
+        assert!(harness.wait_until_cached("mock/simple/tool/").await);
+        assert!(
+            harness
+                .wait_until_cached(&format!("mock/{}", metadata))
+                .await
+        );
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits(metadata), 1);
+        // files without advertised metadata are left alone
+        assert_eq!(
+            harness.upstream.hits("packages/tool-1.0.tar.gz.metadata"),
+            0
+        );
+        let (body, status) = harness.get_body(&format!("mock/{}", metadata)).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "Metadata-Version: 2.1\nName: tool\n");
+    }
+
+    #[tokio::test]
+    async fn e2e_bypass() {
+        let harness = Harness::builder("e2e_bypass").build().await;
+        harness.upstream.mock("pkg.bin", MockResponse::ok("v1"));
+        harness.upstream.mock("new.bin", MockResponse::ok("new"));
+        let (body, _) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(body.unwrap(), "v1");
+        assert!(harness.wait_until_cached("mock/pkg.bin").await);
+        harness.wait_for_background_tasks().await;
+        let fetched = harness.upstream.hits("pkg.bin");
+        let (_, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(harness.upstream.hits("pkg.bin"), fetched);
+
+        // every request reaches the upstream, and the cache is left alone
+        harness.upstream.mock("pkg.bin", MockResponse::ok("v2"));
+        let status = harness.tm.set_bypass("test", Some("mock"), true).unwrap();
+        assert!(status.rules["mock"]);
+        assert!(!status.global);
+        for _ in 0..3 {
+            let (body, status) = harness.get_body("mock/pkg.bin").await;
+            assert_eq!(status, CacheStatus::Bypass);
+            assert_eq!(body.unwrap(), "v2");
+        }
+        let (body, status) = harness.get_body("mock/new.bin").await;
+        assert_eq!(status, CacheStatus::Bypass);
+        assert_eq!(body.unwrap(), "new");
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("pkg.bin"), fetched + 3);
+        assert_eq!(harness.upstream.hits("new.bin"), 1);
+        assert!(!harness.is_cached("mock/new.bin").await);
+
+        // back to the cache, which was not refreshed meanwhile
+        harness.tm.set_bypass("test", None, true).unwrap();
+        assert!(harness.tm.bypass_status().global);
+        harness.tm.set_bypass("test", None, false).unwrap();
+        harness.tm.set_bypass("test", Some("mock"), false).unwrap();
+        let (body, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v1");
+        assert_eq!(harness.upstream.hits("pkg.bin"), fetched + 3);
+        assert!(harness
+            .tm
+            .set_bypass("test", Some("no-such-rule"), true)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn e2e_upstream_health() {
+        let harness = Harness::builder("e2e_upstream_health").build().await;
+        harness
+            .upstream
+            .mock("pkg.bin", MockResponse::ok("package"));
+        harness
+            .upstream
+            .mock("broken.bin", MockResponse::status(503));
+        let (body, _) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(body.unwrap(), "package");
+        harness.wait_for_background_tasks().await;
+        let report = harness.tm.upstream_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].upstream, harness.upstream.url());
+        assert_eq!(report[0].status, Status::Up);
+        assert!(report[0].requests >= 1);
+        assert!(report[0].p50_ms.is_some());
+
+        for _ in 0..3 {
+            let (body, _) = harness.get_body("mock/broken.bin").await;
+            assert!(body.is_err());
+        }
+        let report = harness.tm.upstream_report();
+        assert_eq!(report[0].status, Status::Down);
+        assert_eq!(
+            report[0].last_error.as_deref(),
+            Some("answered 503 Service Unavailable")
+        );
+        // the upstream has traffic, it is not probed
+        harness.tm.probe_idle_upstreams().await;
+        assert_eq!(harness.upstream.hits(""), 0);
+    }
+
+    #[tokio::test]
+    async fn e2e_upstream_cooldown() {
+        let harness = Harness::builder("e2e_upstream_cooldown")
+            .revalidate_after(1)
+            .build()
+            .await;
+        harness.upstream.mock("pkg.bin", MockResponse::ok("v1"));
+        let (_, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(harness.wait_until_cached("mock/pkg.bin").await);
+        harness.wait_for_background_tasks().await;
+        harness.upstream.mock(
+            "other.bin",
+            MockResponse::status(429).with_header("Retry-After", "3"),
+        );
+        let (result, outcome) = harness.get("mock/other.bin").await;
+        assert!(matches!(result, Err(Error::UpstreamThrottled(secs)) if secs <= 3));
+        assert_eq!(outcome.status, CacheStatus::Miss);
+        // no request is sent during the cooldown
+        let ok = MockResponse::ok("other");
+        harness.upstream.mock("other.bin", ok);
+        let (result, _) = harness.get("mock/other.bin").await;
+        assert!(matches!(result, Err(Error::UpstreamThrottled(_))));
+        assert_eq!(harness.upstream.hits("other.bin"), 1);
+        let report = harness.tm.upstream_report();
+        assert_eq!(report[0].cooldowns, 1);
+        assert!(report[0].cooldown_until.is_some());
+        // hits are served, their revalidation waits for the cooldown
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (body, status) = harness.get_body("mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), "v1");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(harness.upstream.hits("pkg.bin"), 2);
+        harness.wait_for_background_tasks().await;
+        assert_eq!(harness.upstream.hits("pkg.bin"), 3);
+        let (body, status) = harness.get_body("mock/other.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "other");
+        assert!(harness.tm.upstream_report()[0].cooldown_until.is_none());
+    }
+
+    #[tokio::test]
+    async fn e2e_fastest_mirror() {
+        let mirror = MockUpstream::start();
+        let harness = Harness::builder("e2e_fastest_mirror")
+            .rule_options(&format!(
+                "mirrors: [\"{}\"]\nupstream_selection: fastest",
+                mirror.url()
+            ))
+            .build()
+            .await;
+        let files: Vec<String> = (0..20).map(|i| format!("pkg-{}.bin", i)).collect();
+        for file in &files {
+            let slow = MockResponse::ok("package").delayed(Duration::from_millis(200));
+            harness.upstream.mock(file, slow);
+            mirror.mock(file, MockResponse::ok("package"));
+        }
+        for file in &files {
+            let (body, _) = harness.get_body(&format!("mock/{}", file)).await;
+            assert_eq!(body.unwrap(), "package");
+            harness.wait_for_background_tasks().await;
+        }
+        let slow: usize = files.iter().map(|file| harness.upstream.hits(file)).sum();
+        let fast: usize = files.iter().map(|file| mirror.hits(file)).sum();
+        // the slow upstream is only tried first, then explored once in a while
+        assert!(
+            fast > 3 * slow,
+            "{} fetches from the mirror, {} upstream",
+            fast,
+            slow
+        );
+        let report = harness.tm.upstream_report();
+        let mirror_report = report.iter().find(|r| r.upstream == mirror.url()).unwrap();
+        assert!(mirror_report.selected > 0);
+        assert!(mirror_report.ewma_latency_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn e2e_purge_all() {
+        let harness = Harness::builder("e2e_purge_all").build().await;
+        for name in &["a.bin", "b.bin"] {
+            harness.upstream.mock(name, MockResponse::ok("package"));
+            let (body, status) = harness.get_body(&format!("mock/{}", name)).await;
+            assert_eq!(status, CacheStatus::Miss);
+            assert_eq!(body.unwrap(), "package");
+            assert!(harness.wait_until_cached(&format!("mock/{}", name)).await);
+        }
+        let policy = &harness.settings.rules[0].policy;
+        let report = harness.tm.purge_all("test", policy).await.unwrap();
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.bytes, 2 * "package".len() as u64);
+        assert!(!harness.is_cached("mock/a.bin").await);
+        assert!(!harness.is_cached("mock/b.bin").await);
+        let (body, status) = harness.get_body("mock/a.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "package");
+        harness.wait_for_background_tasks().await;
+        // fetched again, for the response and to populate the cache
+        assert_eq!(harness.upstream.hits("a.bin"), 4);
+    }
+
+    #[tokio::test]
+    async fn e2e_tenant_caches() {
+        let harness = Harness::builder("e2e_tenant_caches")
+            .tenants(&["team-a", "team-b"])
+            .build()
+            .await;
+        harness
+            .upstream
+            .mock("pkg.bin", MockResponse::ok("package"));
+        let policy = &harness.settings.rules[0].policy;
+        let (body, status) = harness.get_body("t/team-a/mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "package");
+        assert!(harness.wait_until_cached("t/team-a/mock/pkg.bin").await);
+        let (_, outcome) = harness.get("t/team-a/mock/pkg.bin").await;
+        assert_eq!(outcome.status, CacheStatus::Hit);
+        assert_eq!(outcome.cache_id, format!("team-a_{}", policy));
+
+        // neither the other tenants nor the shared cache see the entry
+        assert!(!harness.is_cached("t/team-b/mock/pkg.bin").await);
+        assert!(!harness.is_cached("mock/pkg.bin").await);
+        let (body, status) = harness.get_body("t/team-b/mock/pkg.bin").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), "package");
+
+        let stats = harness
+            .tm
+            .cache_stats(&format!("team-a_{}", policy))
+            .await
+            .unwrap();
+        assert_eq!(stats.entries, 1);
+        assert!(harness.tm.get_cache_for_policy("team-c_pkg").is_none());
+        harness.wait_for_background_tasks().await;
+    }
+
+    /// Fetch `path` from a harness, with the cache status headers
+    async fn get_with_headers(harness: &Harness, path: &str) -> (String, warp::http::HeaderMap) {
+        let (result, outcome) = harness.get(path).await;
+        let mut resp = warp::Reply::into_response(result.unwrap());
+        outcome.set_headers(resp.headers_mut());
+        let headers = resp.headers().clone();
+        let body = warp::hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (String::from_utf8(body.to_vec()).unwrap(), headers)
+    }
+
+    #[tokio::test]
+    async fn e2e_hierarchical_caching() {
+        // mock upstream -> central -> edge
+        let central = Harness::builder("e2e_central")
+            .rewrite("https://files.example/", "http://central.test/mock/")
+            .build()
+            .await;
+        let edge = Harness::builder("e2e_edge")
+            .upstream(&format!("{}mock/", central.serve()))
+            .rewrite("https://files.example/", "http://edge.test/mock/")
+            .rewrite_from("http://central.test/mock/")
+            .build()
+            .await;
+        let index = |project: &str| {
+            format!(
+                "<a href=\"https://files.example/{}-1.0.whl\">{}-1.0.whl</a>",
+                project, project
+            )
+        };
+        let rewritten = |project: &str| {
+            format!(
+                "<a href=\"http://edge.test/mock/{}-1.0.whl\">{}-1.0.whl</a>",
+                project, project
+            )
+        };
+        central
+            .upstream
+            .mock("simple/flask/", MockResponse::ok(index("flask")));
+        let (body, headers) = get_with_headers(&edge, "mock/simple/flask/").await;
+        assert_eq!(body, rewritten("flask"));
+        assert_eq!(headers["X-Cache"], "MISS");
+        assert_eq!(headers["X-Cache-Hierarchy"], "MISS, MISS");
+        assert!(central.wait_until_cached("mock/simple/flask/").await);
+        assert!(edge.wait_until_cached("mock/simple/flask/").await);
+        // the central instance caches its own rewrite
+        let (body, _) = central.get_body("mock/simple/flask/").await;
+        assert_eq!(
+            body.unwrap(),
+            index("flask").replace("https://files.example/", "http://central.test/mock/")
+        );
+        let (body, headers) = get_with_headers(&edge, "mock/simple/flask/").await;
+        assert_eq!(body, rewritten("flask"));
+        assert_eq!(headers["X-Cache"], "HIT");
+        assert!(headers.get("X-Cache-Hierarchy").is_none());
+
+        // a miss of the edge filled by a hit of the central instance
+        central
+            .upstream
+            .mock("simple/torch/", MockResponse::ok(index("torch")));
+        let (_, status) = central.get_body("mock/simple/torch/").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert!(central.wait_until_cached("mock/simple/torch/").await);
+        let (body, headers) = get_with_headers(&edge, "mock/simple/torch/").await;
+        assert_eq!(body, rewritten("torch"));
+        assert_eq!(headers["X-Cache"], "MISS");
+        assert_eq!(headers["X-Cache-Hierarchy"], "MISS, HIT");
+        assert!(edge.wait_until_cached("mock/simple/torch/").await);
+        edge.wait_for_background_tasks().await;
+        assert_eq!(central.upstream.hits("simple/torch/"), 2);
+    }
+
+    /// Pages of the tag list of `tool`, the first one linking to the second
+    /// one relatively and the second one to the third one absolutely
+    fn mock_tag_pages(upstream: &MockUpstream) {
+        let page = |tags: &str, next: Option<&str>| {
+            let page = MockResponse::ok(format!(r#"{{"name": "tool", "tags": [{}]}}"#, tags));
+            match next {
+                Some(next) => page.with_header("Link", &format!("<{}>; rel=\"next\"", next)),
+                None => page,
+            }
+        };
+        upstream.mock("tool/tags/list", page(r#""1.0", "1.1""#, Some("list-2")));
+        upstream.mock(
+            "tool/tags/list-2",
+            page(r#""2.0""#, Some("/tool/tags/list-3")),
+        );
+        upstream.mock("tool/tags/list-3", page(r#""3.0""#, None));
+    }
+
+    #[tokio::test]
+    async fn e2e_pagination() {
+        let merged = r#"{"name":"tool","tags":["1.0","1.1","2.0","3.0"]}"#;
+        let harness = Harness::builder("e2e_pagination")
+            .rule_options("paginate:\n  merge: json")
+            .build()
+            .await;
+        mock_tag_pages(&harness.upstream);
+        // within the page budget, the merged document is served right away
+        let (body, status) = harness.get_body("mock/tool/tags/list").await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(body.unwrap(), merged);
+        assert!(harness.wait_until_cached("mock/tool/tags/list").await);
+        harness.wait_for_background_tasks().await;
+        let (body, status) = harness.get_body("mock/tool/tags/list").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), merged);
+        assert!(!harness.is_cached("mock/tool/tags/list-2").await);
+
+        // over the page budget, the first page is served and the document is
+        // merged in the background
+        let harness = Harness::builder("e2e_pagination_background")
+            .rule_options("paginate:\n  foreground_pages: 1")
+            .build()
+            .await;
+        mock_tag_pages(&harness.upstream);
+        let (body, _) = harness.get_body("mock/tool/tags/list").await;
+        assert_eq!(body.unwrap(), r#"{"name": "tool", "tags": ["1.0", "1.1"]}"#);
+        assert!(harness.wait_until_cached("mock/tool/tags/list").await);
+        let (body, status) = harness.get_body("mock/tool/tags/list").await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(body.unwrap(), merged);
+
+        // truncated documents are not cached
+        let harness = Harness::builder("e2e_pagination_too_many_pages")
+            .rule_options("paginate:\n  max_pages: 2")
+            .build()
+            .await;
+        mock_tag_pages(&harness.upstream);
+        let (body, _) = harness.get_body("mock/tool/tags/list").await;
+        assert_eq!(body.unwrap(), r#"{"name": "tool", "tags": ["1.0", "1.1"]}"#);
+        harness.wait_for_background_tasks().await;
+        assert!(!harness.is_cached("mock/tool/tags/list").await);
+        assert_eq!(harness.upstream.hits("tool/tags/list-3"), 0);
+    }
+
+    #[tokio::test]
+    async fn e2e_snapshot() {
+        use crate::snapshot::SnapshotRequest;
+        let harness = Harness::builder("e2e_snapshot")
+            .ttl(3600)
+            .snapshots()
+            .build()
+            .await;
+        harness
+            .upstream
+            .mock("simple/tool/", MockResponse::ok("tool 1.0"));
+        let (body, _) = harness.get_body("mock/simple/tool/").await;
+        assert_eq!(body.unwrap(), "tool 1.0");
+        assert!(harness.wait_until_cached("mock/simple/tool/").await);
+        let id = format!("e2e-{}", unique_suffix());
+        let request = SnapshotRequest {
+            id: Some(id.clone()),
+            rules: vec!["mock".to_string()],
+        };
+        let info = harness.tm.create_snapshot("admin", &request).await.unwrap();
+        assert_eq!((info.entries, info.bytes), (1, 8));
+        assert!(harness.tm.create_snapshot("admin", &request).await.is_err());
+
+        // the page is served as recorded once the cached one is gone
+        harness
+            .upstream
+            .mock("simple/tool/", MockResponse::ok("tool 2.0"));
+        let task = harness.task("mock/simple/tool/");
+        let cache = harness.tm.get_cache_for_cache_rule(0).unwrap();
+        assert!(cache.read().await.remove(&task.to_key()).await.unwrap());
+        let (body, _) = harness.get_body("mock/simple/tool/").await;
+        assert_eq!(body.unwrap(), "tool 2.0");
+        let page = harness.tm.snapshot_page(&id, &task).await.unwrap().unwrap();
+        assert_eq!(page.into_vec_u8().await, b"tool 1.0");
+        // pages it does not record are never fetched
+        let missing = harness.task("mock/simple/missing/");
+        let result = harness.tm.snapshot_page(&id, &missing).await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+        assert_eq!(harness.upstream.hits("simple/missing/"), 0);
+
+        let manifest = harness.tm.snapshot_manifest(&id).unwrap();
+        assert!(manifest.entries.contains_key(&task.to_key()));
+        let snapshots = harness.tm.list_snapshots().unwrap();
+        assert!(snapshots.iter().any(|snapshot| snapshot.id == id));
+        harness.tm.delete_snapshot("admin", &id).await.unwrap();
+        let result = harness.tm.snapshot_page(&id, &task).await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+        assert!(harness.tm.delete_snapshot("admin", &id).await.is_err());
+
+        // only pages of TTL policies are recorded
+        let harness = Harness::builder("e2e_snapshot_lru")
+            .snapshots()
+            .build()
+            .await;
+        let request = SnapshotRequest {
+            id: None,
+            rules: vec!["mock".to_string()],
+        };
+        let result = harness.tm.create_snapshot("admin", &request).await;
+        assert!(matches!(result, Err(Error::BadRequest(_))));
+    }
+}
@@ -0,0 +1,673 @@
+//! Health of upstreams. Each upstream fetch records whether it failed and how
+//! long it took until the response headers, in a rolling window per upstream
+//! origin, e.g. `https://pypi.org/`. Origins without recent traffic are
+//! probed, so that their status stays current. Reported by
+//! `GET /admin/upstreams` and exported as gauges per upstream.
+//!
+//! Rules with `mirrors` fetch from one of their upstreams picked by
+//! `UpstreamHealth::select`, from moving averages of the latency and errors
+//! of each upstream.
+//!
+//! An upstream answering `429 Too Many Requests` cools down for the delay of
+//! its `Retry-After` header: its fetches are deferred meanwhile, see
+//! `UpstreamHealth::cooldown`, so that retries do not prolong the ban.
+
+use crate::error::{Error, Result};
+use crate::metric;
+use crate::settings::UpstreamSelection;
+use crate::util;
+use metrics::{gauge, increment_counter};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most fetches kept in the window of an upstream
+const WINDOW_SAMPLES: usize = 256;
+
+/// Fetches older than this leave the window
+const WINDOW: Duration = Duration::from_secs(300);
+
+/// An upstream not fetched from for this long is probed
+pub const IDLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Error rates from which an upstream is degraded, and down
+const DEGRADED_ERROR_RATE: f64 = 0.1;
+const DOWN_ERROR_RATE: f64 = 0.5;
+
+/// An upstream whose last fetches all failed is down, whatever its error rate
+const DOWN_AFTER_FAILURES: usize = 3;
+
+/// p95 latency from which an upstream is degraded
+const DEGRADED_LATENCY: Duration = Duration::from_secs(5);
+
+/// Weight of the last fetch in the moving averages of an upstream
+const EWMA_ALPHA: f64 = 0.2;
+
+/// Latency in millisecs assumed of an upstream that never answered
+const UNANSWERED_LATENCY_MS: f64 = 10_000.0;
+
+/// Weight of the error rate in the score of an upstream: one failing half
+/// of the time scores like one 6 times slower
+const ERROR_PENALTY: f64 = 10.0;
+
+/// Cooldown of an upstream answering `429 Too Many Requests` without a valid
+/// `Retry-After` header
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Bounds of the cooldown of an upstream, whatever its `Retry-After` header
+const MIN_COOLDOWN: Duration = Duration::from_secs(1);
+const MAX_COOLDOWN: Duration = Duration::from_secs(3600);
+
+/// One selection in this many goes to another upstream than the best one,
+/// in turn, so that a recovered or faster upstream is noticed
+const EXPLORE_EVERY: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Not fetched from within the window
+    Unknown,
+    Up,
+    Degraded,
+    Down,
+}
+
+impl Status {
+    /// Value of the `upstream_status` gauge
+    fn gauge_value(&self) -> f64 {
+        match self {
+            Status::Unknown => -1.0,
+            Status::Up => 0.0,
+            Status::Degraded => 1.0,
+            Status::Down => 2.0,
+        }
+    }
+}
+
+/// Status of an upstream over the window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamReport {
+    /// Origin of the upstream, e.g. `https://pypi.org/`
+    pub upstream: String,
+    pub status: Status,
+    /// Fetches and probes in the window
+    pub requests: usize,
+    pub error_rate: f64,
+    /// Latency percentiles of the successful fetches, `None` if there are none
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    /// The last failure, and when it happened (secs), even out of the window
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+    /// Moving averages of the latency of successful fetches and of the
+    /// error rate, which favour the last fetches, even out of the window.
+    /// `None` before the first fetch, or the first success for the latency
+    pub ewma_latency_ms: Option<u64>,
+    pub ewma_error_rate: Option<f64>,
+    /// Fetches the upstream was picked for among the upstreams of a rule
+    /// with `mirrors`, see `UpstreamHealth::select`
+    pub selected: u64,
+    /// When the cooldown of the upstream ends (secs), `None` if it is not
+    /// cooling down, see `UpstreamHealth::cooldown`
+    pub cooldown_until: Option<i64>,
+    /// Cooldowns started since startup
+    pub cooldowns: u64,
+}
+
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    ok: bool,
+}
+
+/// Exponentially weighted moving averages of the fetches of an upstream
+#[derive(Debug, Clone, Copy)]
+struct Ewma {
+    /// Of the successful fetches, `None` until one succeeds
+    latency_ms: Option<f64>,
+    error_rate: f64,
+}
+
+impl Ewma {
+    fn new(latency: Duration, ok: bool) -> Self {
+        Self {
+            latency_ms: Some(millis(latency)).filter(|_| ok),
+            error_rate: if ok { 0.0 } else { 1.0 },
+        }
+    }
+
+    fn update(&mut self, latency: Duration, ok: bool) {
+        let weigh = |average: f64, value: f64| EWMA_ALPHA * value + (1.0 - EWMA_ALPHA) * average;
+        if ok {
+            let latency = millis(latency);
+            self.latency_ms = Some(self.latency_ms.map_or(latency, |avg| weigh(avg, latency)));
+        }
+        self.error_rate = weigh(self.error_rate, if ok { 0.0 } else { 1.0 });
+    }
+
+    /// Lower is better: the latency, penalized by the error rate
+    fn score(&self) -> f64 {
+        let latency = self.latency_ms.unwrap_or(UNANSWERED_LATENCY_MS);
+        latency * (1.0 + ERROR_PENALTY * self.error_rate)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Default)]
+struct Window {
+    samples: VecDeque<Sample>,
+    last_error: Option<(String, i64)>,
+    /// The last fetch of a request, as opposed to a probe
+    last_fetch: Option<Instant>,
+    ewma: Option<Ewma>,
+    selected: u64,
+    /// End of the last cooldown, possibly over
+    cooldown_until: Option<Instant>,
+    cooldowns: u64,
+}
+
+impl Window {
+    fn push(&mut self, sample: Sample) {
+        if self.samples.len() == WINDOW_SAMPLES {
+            self.samples.pop_front();
+        }
+        match &mut self.ewma {
+            Some(ewma) => ewma.update(sample.latency, sample.ok),
+            None => self.ewma = Some(Ewma::new(sample.latency, sample.ok)),
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// The rest of the cooldown, `None` if it is over
+    fn cooldown(&self, now: Instant) -> Option<Duration> {
+        let rest = self.cooldown_until?.checked_duration_since(now)?;
+        Some(rest).filter(|rest| !rest.is_zero())
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(sample) = self.samples.front() {
+            if now.saturating_duration_since(sample.at) < WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    fn report(&self, upstream: &str) -> UpstreamReport {
+        let requests = self.samples.len();
+        let failures = self.samples.iter().filter(|s| !s.ok).count();
+        let error_rate = if requests == 0 {
+            0.0
+        } else {
+            failures as f64 / requests as f64
+        };
+        let mut latencies: Vec<Duration> = self
+            .samples
+            .iter()
+            .filter(|s| s.ok)
+            .map(|s| s.latency)
+            .collect();
+        latencies.sort();
+        let p50 = percentile(&latencies, 50);
+        let p95 = percentile(&latencies, 95);
+        let failing = requests >= DOWN_AFTER_FAILURES
+            && self
+                .samples
+                .iter()
+                .rev()
+                .take(DOWN_AFTER_FAILURES)
+                .all(|s| !s.ok);
+        let status = if requests == 0 {
+            Status::Unknown
+        } else if failing || error_rate >= DOWN_ERROR_RATE {
+            Status::Down
+        } else if error_rate >= DEGRADED_ERROR_RATE || p95.map_or(false, |p| p >= DEGRADED_LATENCY)
+        {
+            Status::Degraded
+        } else {
+            Status::Up
+        };
+        UpstreamReport {
+            upstream: upstream.to_string(),
+            status,
+            requests,
+            error_rate,
+            p50_ms: p50.map(|p| p.as_millis() as u64),
+            p95_ms: p95.map(|p| p.as_millis() as u64),
+            last_error: self.last_error.as_ref().map(|(error, _)| error.clone()),
+            last_error_at: self.last_error.as_ref().map(|(_, at)| *at),
+            ewma_latency_ms: self
+                .ewma
+                .and_then(|ewma| ewma.latency_ms)
+                .map(|latency| latency.round() as u64),
+            ewma_error_rate: self.ewma.map(|ewma| ewma.error_rate),
+            selected: self.selected,
+            cooldown_until: self
+                .cooldown(Instant::now())
+                .map(|rest| util::now() + retry_after_secs(rest) as i64),
+            cooldowns: self.cooldowns,
+        }
+    }
+}
+
+/// The nearest-rank percentile `p` of sorted `values`
+fn percentile(values: &[Duration], p: usize) -> Option<Duration> {
+    if values.is_empty() {
+        return None;
+    }
+    let rank = (values.len() * p + 99) / 100;
+    Some(values[rank.max(1) - 1])
+}
+
+/// Secs of the `Retry-After` header of a request deferred by a cooldown of
+/// `rest`, rounded up so that the client retries once it is over
+pub fn retry_after_secs(rest: Duration) -> u64 {
+    rest.as_secs() + u64::from(rest.subsec_nanos() > 0)
+}
+
+/// The origin of the upstream of `url`, e.g. `https://pypi.org/`
+pub fn origin(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    Some(format!("{}/", url.origin().ascii_serialization()))
+}
+
+/// Why an upstream fetch failed, `None` if the upstream answered. Errors of
+/// the client are described without the url, which may hold a token.
+fn failure(resp: &Result<reqwest::Response>) -> Option<String> {
+    let e = match resp {
+        Ok(res) if res.status().is_server_error() => {
+            return Some(format!("answered {}", res.status()))
+        }
+        Ok(_) => return None,
+        Err(Error::RequestError(e)) | Err(Error::UpstreamTimeout(e)) => e,
+        Err(e) => return Some(e.to_string()),
+    };
+    let kind = if e.is_timeout() {
+        "timed out"
+    } else if e.is_connect() {
+        "failed to connect"
+    } else {
+        "request failed"
+    };
+    Some(match e.source() {
+        Some(source) => format!("{}: {}", kind, source),
+        None => kind.to_string(),
+    })
+}
+
+/// Rolling windows of the fetches of upstreams, kept across config reloads
+pub struct UpstreamHealth {
+    /// origin -> window
+    windows: Mutex<HashMap<String, Window>>,
+    /// Selections made, to explore every `EXPLORE_EVERY`th one
+    selections: AtomicUsize,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> Self {
+        Self {
+            windows: Mutex::new(HashMap::new()),
+            selections: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick one of `upstreams`, base urls serving the same files, for a
+    /// fetch. Returns its index. Upstreams are told apart by their origin.
+    ///
+    /// `Ordered` picks the first upstream that is not down. `Fastest` picks
+    /// the upstream with the lowest latency penalized by its error rate, see
+    /// `Ewma::score`, or one never fetched from yet, and every
+    /// `EXPLORE_EVERY`th selection another one in turn. Upstreams that are
+    /// down or cooling down are only picked if they all are.
+    pub fn select(&self, upstreams: &[String], selection: UpstreamSelection) -> usize {
+        if upstreams.len() < 2 {
+            return 0;
+        }
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let origins: Vec<String> = upstreams
+            .iter()
+            .map(|upstream| origin(upstream).unwrap_or_else(|| upstream.clone()))
+            .collect();
+        let states: Vec<(bool, Option<Ewma>)> = origins
+            .iter()
+            .map(|origin| match windows.get_mut(origin) {
+                Some(window) => {
+                    window.prune(now);
+                    let down = window.report(origin).status == Status::Down;
+                    (down || window.cooldown(now).is_some(), window.ewma)
+                }
+                None => (false, None),
+            })
+            .collect();
+        let mut candidates: Vec<usize> = (0..upstreams.len()).filter(|i| !states[*i].0).collect();
+        if candidates.is_empty() {
+            candidates = (0..upstreams.len()).collect();
+        }
+        let picked = match selection {
+            UpstreamSelection::Ordered => candidates[0],
+            UpstreamSelection::Fastest => {
+                let score = |i: usize| states[i].1.map_or(0.0, |ewma| ewma.score());
+                let mut best = candidates[0];
+                for &i in &candidates[1..] {
+                    if score(i) < score(best) {
+                        best = i;
+                    }
+                }
+                let n = self.selections.fetch_add(1, Ordering::Relaxed) + 1;
+                let others: Vec<usize> = candidates.into_iter().filter(|i| *i != best).collect();
+                if n % EXPLORE_EVERY == 0 && !others.is_empty() {
+                    others[(n / EXPLORE_EVERY - 1) % others.len()]
+                } else {
+                    best
+                }
+            }
+        };
+        if let Some(origin) = origins.get(picked) {
+            windows.entry(origin.clone()).or_default().selected += 1;
+        }
+        picked
+    }
+
+    /// Record a fetch of `url` for a request, answered after `latency`. A
+    /// fetch fails if the upstream cannot be reached or answers `5xx`. An
+    /// answer `429 Too Many Requests` starts a cooldown of the upstream.
+    pub fn record_fetch(&self, url: &str, latency: Duration, resp: &Result<reqwest::Response>) {
+        let origin = match origin(url) {
+            Some(origin) => origin,
+            None => return,
+        };
+        self.record(&origin, latency, failure(resp), true);
+        if let Ok(res) = resp {
+            if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = res
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| util::parse_retry_after(value, util::now()));
+                self.cool_down(&origin, retry_after.unwrap_or(DEFAULT_COOLDOWN));
+            }
+        }
+    }
+
+    /// Defer the fetches of `origin` for `delay`, within `MIN_COOLDOWN` and
+    /// `MAX_COOLDOWN`. A cooldown in progress is only extended.
+    fn cool_down(&self, origin: &str, delay: Duration) {
+        let now = Instant::now();
+        let until = now + delay.max(MIN_COOLDOWN).min(MAX_COOLDOWN);
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(origin.to_string()).or_default();
+        if window.cooldown(now).is_none() {
+            warn!(
+                "upstream {} is rate limiting, its fetches are deferred for {} secs",
+                origin,
+                until.duration_since(now).as_secs()
+            );
+            window.cooldowns += 1;
+            increment_counter!(metric::CNT_UPSTREAM_COOLDOWNS, "upstream" => origin.to_string());
+            window.cooldown_until = Some(until);
+        } else {
+            window.cooldown_until = window.cooldown_until.max(Some(until));
+        }
+    }
+
+    /// The rest of the cooldown of the upstream of `url`, during which its
+    /// fetches are deferred: background tasks wait, and requests missing
+    /// the cache are told to retry later. `None` if it is not cooling down.
+    pub fn cooldown(&self, url: &str) -> Option<Duration> {
+        let origin = origin(url)?;
+        let windows = self.windows.lock().unwrap();
+        windows.get(&origin)?.cooldown(Instant::now())
+    }
+
+    /// Record a probe of an idle upstream, see `check::probe_upstream`
+    pub fn record_probe(&self, origin: &str, latency: Duration, result: &Result<()>) {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.record(origin, latency, error, false);
+    }
+
+    fn record(&self, origin: &str, latency: Duration, error: Option<String>, fetch: bool) {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(origin.to_string()).or_default();
+        window.push(Sample {
+            at: now,
+            latency,
+            ok: error.is_none(),
+        });
+        if let Some(error) = error {
+            window.last_error = Some((error, util::now()));
+        }
+        if fetch {
+            window.last_fetch = Some(now);
+        }
+    }
+
+    /// Whether no request has fetched from `origin` for `IDLE_AFTER`
+    pub fn is_idle(&self, origin: &str) -> bool {
+        let windows = self.windows.lock().unwrap();
+        windows
+            .get(origin)
+            .and_then(|window| window.last_fetch)
+            .map_or(true, |at| at.elapsed() >= IDLE_AFTER)
+    }
+
+    /// Status of the upstreams fetched from, by origin
+    pub fn report(&self) -> Vec<UpstreamReport> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let mut reports: Vec<UpstreamReport> = windows
+            .iter_mut()
+            .map(|(origin, window)| {
+                window.prune(now);
+                window.report(origin)
+            })
+            .collect();
+        reports.sort_by(|a, b| a.upstream.cmp(&b.upstream));
+        reports
+    }
+
+    /// Set the gauges of the upstreams to their status
+    pub fn export_gauges(&self) {
+        for report in self.report() {
+            let upstream = report.upstream;
+            let status = report.status.gauge_value();
+            gauge!(metric::GAUGE_UPSTREAM_STATUS, status, "upstream" => upstream.clone());
+            let error_rate = report.error_rate;
+            gauge!(metric::GAUGE_UPSTREAM_ERROR_RATE, error_rate, "upstream" => upstream.clone());
+            if let Some(p95) = report.p95_ms {
+                let p95 = p95 as f64 / 1000.0;
+                gauge!(metric::GAUGE_UPSTREAM_P95_LATENCY, p95, "upstream" => upstream);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn millis(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|v| Duration::from_millis(*v)).collect()
+    }
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(percentile(&[], 50), None);
+        let values = millis(&[10, 20, 30, 40, 50, 60, 70, 80, 90, 100]);
+        assert_eq!(percentile(&values, 50), Some(Duration::from_millis(50)));
+        assert_eq!(percentile(&values, 95), Some(Duration::from_millis(100)));
+        assert_eq!(
+            percentile(&millis(&[7]), 95),
+            Some(Duration::from_millis(7))
+        );
+    }
+
+    #[test]
+    fn upstream_status() {
+        let health = UpstreamHealth::new();
+        let origin = "https://pypi.org/";
+        let ok = || -> Result<()> { Ok(()) };
+        let failed = || -> Result<()> { Err(Error::UpstreamUnavailable("answered 503".into())) };
+        let status = || health.report()[0].status;
+        for _ in 0..18 {
+            health.record_probe(origin, Duration::from_millis(20), &ok());
+        }
+        assert_eq!(status(), Status::Up);
+        assert_eq!(health.report()[0].p50_ms, Some(20));
+        health.record_probe(origin, Duration::from_millis(20), &failed());
+        health.record_probe(origin, Duration::from_millis(20), &failed());
+        let report = &health.report()[0];
+        assert_eq!(report.status, Status::Degraded);
+        assert_eq!(report.requests, 20);
+        assert!(report.last_error.as_deref().unwrap().contains("503"));
+        // the last fetches all failed
+        health.record_probe(origin, Duration::from_millis(20), &failed());
+        assert_eq!(status(), Status::Down);
+        health.record_probe(origin, Duration::from_millis(20), &ok());
+        assert_eq!(status(), Status::Degraded);
+
+        // slow answers
+        for _ in 0..WINDOW_SAMPLES {
+            health.record_probe(origin, Duration::from_secs(6), &ok());
+        }
+        let report = &health.report()[0];
+        assert_eq!(report.status, Status::Degraded);
+        assert_eq!(report.requests, WINDOW_SAMPLES);
+        assert_eq!(report.error_rate, 0.0);
+        // failures are remembered out of the window
+        assert!(report.last_error.is_some());
+    }
+
+    #[test]
+    fn ordered_selection() {
+        let health = UpstreamHealth::new();
+        let upstreams = vec![
+            "https://pypi.org/".to_string(),
+            "https://mirror.corp/pypi/".into(),
+        ];
+        let select = || health.select(&upstreams, UpstreamSelection::Ordered);
+        assert_eq!(select(), 0);
+        // the mirror is picked while the upstream is down, whatever its speed
+        for _ in 0..DOWN_AFTER_FAILURES {
+            health.record(
+                "https://pypi.org/",
+                Duration::from_millis(5),
+                Some("503".into()),
+                true,
+            );
+        }
+        health.record("https://mirror.corp/", Duration::from_secs(2), None, true);
+        assert_eq!(select(), 1);
+        // degraded, but no longer down
+        for _ in 0..DOWN_AFTER_FAILURES + 1 {
+            health.record("https://pypi.org/", Duration::from_millis(5), None, true);
+        }
+        assert_eq!(select(), 0);
+        let report = health.report();
+        assert_eq!(
+            (report[0].upstream.as_str(), report[0].selected),
+            ("https://mirror.corp/", 1)
+        );
+        assert_eq!(report[1].selected, 2);
+    }
+
+    #[test]
+    fn fastest_selection() {
+        let health = UpstreamHealth::new();
+        let upstreams = vec![
+            "https://slow.corp/".to_string(),
+            "https://fast.corp/".into(),
+            "https://flaky.corp/".into(),
+        ];
+        let select = || health.select(&upstreams, UpstreamSelection::Fastest);
+        // upstreams never fetched from are tried first
+        assert_eq!(select(), 0);
+        health.record("https://slow.corp/", Duration::from_millis(300), None, true);
+        assert_eq!(select(), 1);
+        health.record("https://fast.corp/", Duration::from_millis(20), None, true);
+        assert_eq!(select(), 2);
+        // faster, but failing too often to be worth it
+        health.record("https://flaky.corp/", Duration::from_millis(10), None, true);
+        health.record("https://flaky.corp/", Duration::from_millis(10), None, true);
+        health.record(
+            "https://flaky.corp/",
+            Duration::from_millis(10),
+            Some("503".into()),
+            true,
+        );
+        let mut picks = vec![0; upstreams.len()];
+        for _ in 0..97 {
+            picks[select()] += 1;
+        }
+        // one selection in `EXPLORE_EVERY` goes to the others in turn
+        assert_eq!(picks, vec![5, 87, 5]);
+        let report = health.report();
+        assert_eq!(report[1].upstream, "https://flaky.corp/");
+        assert_eq!(report[1].ewma_error_rate, Some(EWMA_ALPHA));
+        assert_eq!(report[1].ewma_latency_ms, Some(10));
+        // a faster upstream takes over once noticed
+        for _ in 0..20 {
+            health.record("https://slow.corp/", Duration::from_millis(5), None, true);
+        }
+        assert_eq!(select(), 0);
+    }
+
+    #[test]
+    fn idle_upstreams() {
+        let health = UpstreamHealth::new();
+        let files = "http://files.corp/";
+        assert!(health.is_idle(files));
+        // probes do not count as traffic
+        health.record_probe(files, Duration::from_millis(5), &Ok(()));
+        assert!(health.is_idle(files));
+        health.record(files, Duration::from_millis(5), None, true);
+        assert!(!health.is_idle(files));
+        assert!(health.is_idle("https://pypi.org/"));
+        assert_eq!(
+            origin("http://files.corp:8080/pub/a.tar.gz").as_deref(),
+            Some("http://files.corp:8080/")
+        );
+    }
+
+    #[test]
+    fn cooldowns() {
+        let health = UpstreamHealth::new();
+        let upstreams = vec![
+            "https://pypi.org/".to_string(),
+            "https://mirror.corp/pypi/".into(),
+        ];
+        let url = "https://pypi.org/simple/numpy/";
+        let too_many = |retry_after: &str| -> Result<reqwest::Response> {
+            let res = warp::http::Response::builder()
+                .status(429)
+                .header("Retry-After", retry_after)
+                .body("")
+                .unwrap();
+            Ok(res.into())
+        };
+        assert_eq!(health.cooldown(url), None);
+        health.record_fetch(url, Duration::from_millis(5), &too_many("0"));
+        // at least `MIN_COOLDOWN`
+        let rest = health.cooldown(url).unwrap();
+        assert!(rest > Duration::from_millis(500));
+        assert_eq!(retry_after_secs(rest), 1);
+        assert_eq!(retry_after_secs(Duration::from_secs(2)), 2);
+        // the mirror is picked meanwhile
+        assert_eq!(health.select(&upstreams, UpstreamSelection::Ordered), 1);
+        // extended up to `MAX_COOLDOWN`, but counted once
+        health.record_fetch(url, Duration::from_millis(5), &too_many("7200"));
+        assert!(health.cooldown(url).unwrap() > MAX_COOLDOWN - Duration::from_secs(1));
+        let report = &health.report()[1];
+        assert_eq!(report.upstream, "https://pypi.org/");
+        assert_eq!(report.cooldowns, 1);
+        assert!(report.cooldown_until.unwrap() >= util::now() + 3599);
+        // a cooldown is not a failure
+        assert_eq!(report.status, Status::Up);
+    }
+}
@@ -0,0 +1,330 @@
+use crate::cache::{Cache, CacheSizeType};
+use crate::error::Result;
+use crate::metric;
+use crate::util;
+
+use metrics::gauge;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+
+/// Number of keys scanned at a time
+const USAGE_BATCH_SIZE: usize = 256;
+
+/// Number of groups aggregated while scanning. Keys of further groups are
+/// counted in `other`, so that the memory of a scan is bounded.
+const MAX_TRACKED_GROUPS: usize = 10_000;
+
+/// Number of groups in a report by default
+pub const DEFAULT_TOP_GROUPS: usize = 20;
+
+/// Maximum number of groups in a report
+pub const MAX_TOP_GROUPS: usize = 1000;
+
+/// Secs a report is reused by default
+pub const DEFAULT_REPORT_TTL: u64 = 300;
+
+/// Name of the group of keys not counted in any reported group
+const OTHER_GROUP: &str = "(other)";
+
+/// How keys are grouped in a usage report
+pub enum Grouping {
+    /// The first N segments of keys
+    Prefix(usize),
+    /// The captured groups of a regex, joined with `/`, or the whole match if
+    /// the regex has no groups. Keys not matching the regex are in `other`.
+    Regex(Regex),
+}
+
+impl Grouping {
+    /// Describes the grouping in reports, e.g. `prefix:2`
+    pub fn describe(&self) -> String {
+        match self {
+            Grouping::Prefix(segments) => format!("prefix:{}", segments),
+            Grouping::Regex(regex) => format!("regex:{}", regex.as_str()),
+        }
+    }
+
+    /// The group of a key, `None` if it does not match the regex
+    pub fn group(&self, key: &str) -> Option<String> {
+        match self {
+            Grouping::Prefix(segments) => {
+                Some(key.split('/').take(*segments).collect::<Vec<_>>().join("/"))
+            }
+            Grouping::Regex(regex) => {
+                let captures = regex.captures(key)?;
+                if captures.len() == 1 {
+                    return Some(captures[0].to_string());
+                }
+                let groups: Vec<&str> = captures
+                    .iter()
+                    .skip(1)
+                    .flatten()
+                    .map(|m| m.as_str())
+                    .collect();
+                Some(groups.join("/"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct GroupUsage {
+    pub group: String,
+    /// Number of entries
+    pub count: u64,
+    pub bytes: CacheSizeType,
+}
+
+/// Entries and bytes of a cache by groups of keys
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    /// Name of the policy of the cache
+    pub cache: String,
+    pub grouping: String,
+    /// Unix timestamp in seconds
+    pub generated_at: i64,
+    /// Number of entries scanned
+    pub count: u64,
+    pub bytes: CacheSizeType,
+    /// The largest groups by bytes
+    pub groups: Vec<GroupUsage>,
+    /// Entries of the other groups, and of keys not matching the regex
+    pub other: GroupUsage,
+}
+
+/// Aggregates entries batch by batch
+struct UsageAggregator {
+    groups: HashMap<String, (u64, CacheSizeType)>,
+    other: (u64, CacheSizeType),
+}
+
+impl UsageAggregator {
+    fn new() -> Self {
+        Self {
+            groups: HashMap::new(),
+            other: (0, 0),
+        }
+    }
+
+    fn add(&mut self, group: Option<String>, bytes: CacheSizeType) {
+        let usage = match group {
+            Some(group) if self.groups.len() < MAX_TRACKED_GROUPS => {
+                self.groups.entry(group).or_insert((0, 0))
+            }
+            Some(group) => match self.groups.get_mut(&group) {
+                Some(usage) => usage,
+                None => &mut self.other,
+            },
+            None => &mut self.other,
+        };
+        usage.0 += 1;
+        usage.1 += bytes;
+    }
+
+    /// Keep the `top` largest groups, the others are added up in `other`
+    fn into_report(self, cache: &str, grouping: &Grouping, top: usize) -> UsageReport {
+        let mut groups: Vec<GroupUsage> = self
+            .groups
+            .into_iter()
+            .map(|(group, (count, bytes))| GroupUsage {
+                group,
+                count,
+                bytes,
+            })
+            .collect();
+        groups.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then(b.count.cmp(&a.count))
+                .then(a.group.cmp(&b.group))
+        });
+        let mut other = GroupUsage {
+            group: OTHER_GROUP.to_string(),
+            count: self.other.0,
+            bytes: self.other.1,
+        };
+        for usage in groups.drain(top.min(groups.len())..) {
+            other.count += usage.count;
+            other.bytes += usage.bytes;
+        }
+        UsageReport {
+            cache: cache.to_string(),
+            grouping: grouping.describe(),
+            generated_at: util::now(),
+            count: groups.iter().map(|g| g.count).sum::<u64>() + other.count,
+            bytes: groups.iter().map(|g| g.bytes).sum::<CacheSizeType>() + other.bytes,
+            groups,
+            other,
+        }
+    }
+}
+
+/// Scan all entries of a cache, and aggregate them by groups of keys
+pub async fn compute_usage(
+    cache: &Arc<RwLock<dyn Cache>>,
+    id: &str,
+    grouping: &Grouping,
+    top: usize,
+) -> Result<UsageReport> {
+    let mut aggregator = UsageAggregator::new();
+    let mut cursor = String::new();
+    loop {
+        let (keys, sizes, next) = {
+            let cache = cache.read().await;
+            let (keys, next) = cache.scan_keys(&cursor, USAGE_BATCH_SIZE)?;
+            let sizes = cache.entry_sizes(&keys)?;
+            (keys, sizes, next)
+        };
+        for (key, size) in keys.iter().zip(sizes) {
+            aggregator.add(grouping.group(key), size);
+        }
+        if next.is_empty() {
+            return Ok(aggregator.into_report(id, grouping, top));
+        }
+        cursor = next;
+        // let requests for the cache in between batches
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Recently computed usage reports, reused since scans are expensive.
+/// The groups of the latest reports are exported as gauges.
+pub struct UsageReports {
+    reports: Mutex<HashMap<(String, String, usize), UsageReport>>,
+}
+
+impl UsageReports {
+    pub fn new() -> Self {
+        Self {
+            reports: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A report computed less than `max_age` secs ago
+    pub fn get(
+        &self,
+        cache: &str,
+        grouping: &Grouping,
+        top: usize,
+        max_age: u64,
+    ) -> Option<UsageReport> {
+        let key = (cache.to_string(), grouping.describe(), top);
+        self.reports
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|report| util::now() - report.generated_at < max_age as i64)
+            .cloned()
+    }
+
+    /// Keep a report, and update the gauges of its groups. Groups of the
+    /// previous report that are no longer reported are reset.
+    pub fn insert(&self, report: UsageReport, top: usize) {
+        let key = (report.cache.clone(), report.grouping.clone(), top);
+        let previous = self.reports.lock().unwrap().insert(key, report.clone());
+        let groups = report.groups.iter().chain(std::iter::once(&report.other));
+        for usage in groups {
+            set_usage_gauges(&report, usage);
+        }
+        for usage in previous.iter().flat_map(|previous| &previous.groups) {
+            if !report.groups.iter().any(|g| g.group == usage.group) {
+                set_usage_gauges(
+                    &report,
+                    &GroupUsage {
+                        count: 0,
+                        bytes: 0,
+                        ..usage.clone()
+                    },
+                );
+            }
+        }
+    }
+}
+
+fn set_usage_gauges(report: &UsageReport, usage: &GroupUsage) {
+    gauge!(
+        metric::GAUGE_USAGE_GROUP_BYTES,
+        usage.bytes as f64,
+        "cache" => report.cache.clone(),
+        "grouping" => report.grouping.clone(),
+        "group" => usage.group.clone()
+    );
+    gauge!(
+        metric::GAUGE_USAGE_GROUP_ENTRIES,
+        usage.count as f64,
+        "cache" => report.cache.clone(),
+        "grouping" => report.grouping.clone(),
+        "group" => usage.group.clone()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_keys() {
+        let prefix = Grouping::Prefix(2);
+        assert_eq!(
+            prefix.group("pypi/packages/ab/torch-2.0.whl").unwrap(),
+            "pypi/packages"
+        );
+        assert_eq!(prefix.group("README.md").unwrap(), "README.md");
+        assert_eq!(prefix.describe(), "prefix:2");
+
+        let regex = Grouping::Regex(Regex::new(r"/(torch|tensorflow)[-_]").unwrap());
+        assert_eq!(
+            regex.group("pypi/packages/ab/torch-2.0.whl").unwrap(),
+            "torch"
+        );
+        assert_eq!(regex.group("pypi/packages/ab/flask-2.0.whl"), None);
+        let regex = Grouping::Regex(Regex::new(r"\.whl$").unwrap());
+        assert_eq!(
+            regex.group("pypi/packages/ab/torch-2.0.whl").unwrap(),
+            ".whl"
+        );
+    }
+
+    #[test]
+    fn aggregate_top_groups() {
+        let grouping = Grouping::Prefix(1);
+        let mut aggregator = UsageAggregator::new();
+        let entries = [
+            ("torch/a.whl", 100),
+            ("torch/b.whl", 300),
+            ("flask/a.whl", 10),
+            ("numpy/a.whl", 50),
+            ("numpy/b.whl", 50),
+        ];
+        for (key, bytes) in &entries {
+            aggregator.add(grouping.group(key), *bytes);
+        }
+        aggregator.add(None, 5);
+        let report = aggregator.into_report("policy_lru", &grouping, 2);
+        assert_eq!((report.count, report.bytes), (6, 515));
+        let groups: Vec<(&str, u64, u64)> = report
+            .groups
+            .iter()
+            .map(|g| (g.group.as_str(), g.count, g.bytes))
+            .collect();
+        assert_eq!(groups, vec![("torch", 2, 400), ("numpy", 2, 100)]);
+        // flask and the unmatched key
+        assert_eq!((report.other.count, report.other.bytes), (2, 15));
+    }
+
+    #[test]
+    fn reuse_recent_reports() {
+        let reports = UsageReports::new();
+        let grouping = Grouping::Prefix(1);
+        let report = UsageAggregator::new().into_report("policy_lru", &grouping, 10);
+        reports.insert(report, 10);
+        assert!(reports.get("policy_lru", &grouping, 10, 60).is_some());
+        assert!(reports.get("policy_lru", &grouping, 5, 60).is_none());
+        assert!(reports
+            .get("policy_lru", &Grouping::Prefix(2), 10, 60)
+            .is_none());
+        assert!(reports.get("policy_lru", &grouping, 10, 0).is_none());
+    }
+}
@@ -1,10 +1,18 @@
 use crate::error::Error;
 use crate::error::Result;
 use crate::metric;
+use crate::settings::{HttpClient, QueryMode};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
 use metrics::increment_counter;
 use reqwest::ClientBuilder;
+use sha2::{Digest, Sha256};
 use sled::IVec;
 use std::convert::TryInto;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
 
 pub fn now() -> i64 {
     chrono::offset::Local::now().timestamp()
@@ -14,14 +22,190 @@ pub fn now_nanos() -> i64 {
     chrono::offset::Local::now().timestamp_nanos()
 }
 
-pub async fn make_request(url: &str, head: bool) -> Result<reqwest::Response> {
-    increment_counter!(metric::CNT_OUT_REQUESTS);
-    let client = ClientBuilder::new().build().unwrap();
+pub fn now_millis() -> i64 {
+    chrono::offset::Local::now().timestamp_millis()
+}
+
+/// Return `now`, or one more than the last returned value if `now` is not
+/// greater than it, so that values are strictly increasing.
+fn monotonic(last: &AtomicI64, now: i64) -> i64 {
+    let prev = last
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(std::cmp::max(now, last + 1))
+        })
+        .unwrap();
+    std::cmp::max(now, prev + 1)
+}
+
+/// Access time of LRU entries in millisecs. Entries touched in the same
+/// millisec are ordered by the time they are touched within the process.
+pub fn atime_millis() -> i64 {
+    static LAST: AtomicI64 = AtomicI64::new(0);
+    monotonic(&LAST, now_millis())
+}
+
+/// Like `atime_millis`, in nanosecs
+pub fn atime_nanos() -> i64 {
+    static LAST: AtomicI64 = AtomicI64::new(0);
+    monotonic(&LAST, now_nanos())
+}
+
+/// Format a unix timestamp in secs as an HTTP date, e.g. for `If-Modified-Since`
+pub fn http_date(secs: i64) -> String {
+    chrono::NaiveDateTime::from_timestamp(secs, 0)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// The delay of a `Retry-After` header from `now` (secs): a number of secs,
+/// or an HTTP date
+pub fn parse_retry_after(value: &str, now: i64) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(Duration::from_secs((date.timestamp() - now).max(0) as u64))
+}
+
+/// Maximum number of redirects followed by default, the same as reqwest
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// Options of an upstream client. Clients are built once per distinct
+/// options, since the redirect policy is an option of a client as well.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientConfig {
+    pub http: HttpClient,
+    /// Maximum number of redirects followed
+    pub max_hops: usize,
+    /// Whether redirects to other hosts are followed
+    pub cross_host: bool,
+}
+
+impl ClientConfig {
+    pub fn new(http: HttpClient) -> Self {
+        Self {
+            http,
+            max_hops: DEFAULT_MAX_REDIRECTS,
+            cross_host: true,
+        }
+    }
+}
+
+/// Build a client following at most `max_hops` redirects, and only redirects
+/// to the same host unless `cross_host` is set. If a redirect is not followed,
+/// the redirect response itself is returned.
+pub fn build_client(config: &ClientConfig) -> Result<reqwest::Client> {
+    let (max_hops, cross_host) = (config.max_hops, config.cross_host);
+    let policy = reqwest::redirect::Policy::custom(move |attempt| {
+        let same_host = attempt
+            .previous()
+            .first()
+            .map_or(true, |first| first.host_str() == attempt.url().host_str());
+        if attempt.previous().len() > max_hops || !(cross_host || same_host) {
+            attempt.stop()
+        } else {
+            attempt.follow()
+        }
+    });
+    let http = &config.http;
+    let mut builder = ClientBuilder::new().redirect(policy);
+    if let Some(proxy) = &http.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(Error::RequestError)?);
+    }
+    if http.no_proxy == Some(true) {
+        builder = builder.no_proxy();
+    }
+    if let Some(secs) = http.connect_timeout {
+        builder = builder.connect_timeout(std::time::Duration::from_secs(secs));
+    }
+    if let Some(secs) = http.timeout {
+        builder = builder.timeout(std::time::Duration::from_secs(secs));
+    }
+    // without a TLS stack, both are rejected by `HttpClient::validate`
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    {
+        if let Some(ca_cert) = &http.ca_cert {
+            let pem = std::fs::read(ca_cert).map_err(Error::IoError)?;
+            let cert = reqwest::Certificate::from_pem(&pem).map_err(Error::RequestError)?;
+            builder = builder.add_root_certificate(cert);
+        }
+        if http.danger_accept_invalid_certs == Some(true) {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+    builder = match http.http2 {
+        Some(true) => builder.http2_prior_knowledge(),
+        Some(false) => builder.http1_only(),
+        None => builder,
+    };
+    builder.build().map_err(Error::RequestError)
+}
+
+/// Request an url with a client built by `build_client`
+pub async fn make_request(
+    client: &reqwest::Client,
+    url: &str,
+    head: bool,
+) -> Result<reqwest::Response> {
     let req = if !head {
         client.get(url)
     } else {
         client.head(url)
     };
+    send_request(req).await
+}
+
+/// A GET of `url` asking for the media type `accept` if set, e.g. the JSON
+/// representation of a PyPI index page
+pub async fn make_negotiated_request(
+    client: &reqwest::Client,
+    url: &str,
+    accept: Option<&str>,
+) -> Result<reqwest::Response> {
+    let mut req = client.get(url);
+    if let Some(accept) = accept {
+        req = req.header(reqwest::header::ACCEPT, accept);
+    }
+    send_request(req).await
+}
+
+/// A GET of `url` answered with `304 Not Modified` if it has not changed
+/// since `since` (secs), asking for the media type `accept` if set
+pub async fn make_conditional_request(
+    client: &reqwest::Client,
+    url: &str,
+    since: i64,
+    accept: Option<&str>,
+) -> Result<reqwest::Response> {
+    let mut req = client
+        .get(url)
+        .header(reqwest::header::IF_MODIFIED_SINCE, http_date(since));
+    if let Some(accept) = accept {
+        req = req.header(reqwest::header::ACCEPT, accept);
+    }
+    send_request(req).await
+}
+
+/// A GET of `url` from byte `offset` to the end, answered with the whole
+/// object instead if it is no longer the one of the strong validator
+/// `if_range`, e.g. an `ETag`
+pub async fn make_range_request(
+    client: &reqwest::Client,
+    url: &str,
+    offset: u64,
+    if_range: &str,
+) -> Result<reqwest::Response> {
+    let req = client
+        .get(url)
+        .header(reqwest::header::RANGE, format!("bytes={}-", offset))
+        .header(reqwest::header::IF_RANGE, if_range);
+    send_request(req).await
+}
+
+/// Send a request to an upstream, counted in the outbound requests
+pub async fn send_request(req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    increment_counter!(metric::CNT_OUT_REQUESTS);
     let resp = req.send().await;
     match resp {
         Ok(res) => {
@@ -31,15 +215,382 @@ pub async fn make_request(url: &str, head: bool) -> Result<reqwest::Response> {
         }
         Err(e) => {
             increment_counter!(metric::CNT_OUT_REQUESTS_FAILURE);
-            Err(Error::RequestError(e))
+            if e.is_timeout() {
+                Err(Error::UpstreamTimeout(e))
+            } else {
+                Err(Error::RequestError(e))
+            }
+        }
+    }
+}
+
+/// The body of a response read whole to be rewritten, see `response_text`
+pub enum TextBody {
+    Text(String),
+    /// The body is over the limit: the bytes read so far followed by the
+    /// rest of the body, as received, and its `Content-Length` if known
+    TooLarge(
+        Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        Option<u64>,
+    ),
+}
+
+/// The body of a response as text, decoded as declared by its
+/// `Content-Encoding`. Some upstreams compress text regardless of
+/// `Accept-Encoding`, and compressed bytes must never be rewritten or cached
+/// as text.
+///
+/// At most `limit` bytes are buffered: a larger body, by its
+/// `Content-Length` or once that many bytes are received, is returned as a
+/// stream instead, so that it can be served as is.
+pub async fn response_text(res: reqwest::Response, limit: u64) -> Result<TextBody> {
+    let len = res.content_length();
+    let encoding = res
+        .headers()
+        .get(reqwest::header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let mut body: Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>> =
+        Box::pin(res.bytes_stream().map(|x| x.map_err(Error::RequestError)));
+    if len.map_or(false, |len| len > limit) {
+        return Ok(TextBody::TooLarge(body, len));
+    }
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() as u64 > limit {
+            let head = futures::stream::once(async move { Ok(Bytes::from(buf)) });
+            return Ok(TextBody::TooLarge(Box::pin(head.chain(body)), len));
+        }
+    }
+    let decoded = decode_content(&buf, encoding.as_deref())?;
+    Ok(TextBody::Text(
+        String::from_utf8_lossy(&decoded).into_owned(),
+    ))
+}
+
+/// Decode a body of the `Content-Encoding` `encoding`, e.g. `gzip`. Encodings
+/// listed as `gzip, zstd` were applied in this order, so they are decoded in
+/// reverse.
+pub fn decode_content(bytes: &[u8], encoding: Option<&str>) -> Result<Vec<u8>> {
+    fn read_all(mut reader: impl Read) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+    let mut decoded = bytes.to_vec();
+    let encodings = encoding.unwrap_or_default().split(',').rev();
+    for encoding in encodings.map(|e| e.trim().to_ascii_lowercase()) {
+        let result = match encoding.as_str() {
+            "" | "identity" => continue,
+            "gzip" | "x-gzip" => read_all(flate2::read::MultiGzDecoder::new(&decoded[..])),
+            // the zlib format, as specified by HTTP
+            "deflate" => read_all(flate2::read::ZlibDecoder::new(&decoded[..])),
+            "zstd" => zstd::stream::decode_all(&decoded[..]),
+            _ => {
+                return Err(Error::OtherError(format!(
+                    "unsupported content encoding: {}",
+                    encoding
+                )))
+            }
+        };
+        decoded = result.map_err(Error::IoError)?;
+    }
+    Ok(decoded)
+}
+
+/// Parse the value of a `Range` header against a resource of `total` bytes.
+/// Only a single byte range is supported, e.g. `bytes=0-499`, `bytes=500-` or
+/// `bytes=-500`. Returns the inclusive `(start, end)` offsets, or `None` if the
+/// range is malformed or not satisfiable, in which case the whole resource
+/// should be served.
+pub fn parse_range(range: &str, total: u64) -> Option<(u64, u64)> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') || total == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (total.saturating_sub(suffix), total - 1)
+        }
+        (start, "") => (start.parse::<u64>().ok()?, total - 1),
+        (start, end) => (
+            start.parse::<u64>().ok()?,
+            std::cmp::min(end.parse::<u64>().ok()?, total - 1),
+        ),
+    };
+    if start > end || start >= total {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// 64-bit FNV-1a hash. Unlike `DefaultHasher`, the result is stable across
+/// Rust releases, so it is safe to persist.
+pub fn fnv1a_64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Normalize a Python project name as specified in PEP 503: lowercase, with
+/// runs of `-`, `_` and `.` replaced by a single `-`.
+pub fn pep503_normalize(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    let mut in_separator = false;
+    for c in name.chars() {
+        if c == '-' || c == '_' || c == '.' {
+            if !in_separator {
+                normalized.push('-');
+            }
+            in_separator = true;
+        } else {
+            normalized.extend(c.to_lowercase());
+            in_separator = false;
+        }
+    }
+    normalized
+}
+
+/// The canonical form of a PyPI simple index path (`.../simple/<project>/`),
+/// or `None` if it is already canonical: the project name normalized, and
+/// a trailing slash, so that relative links of the pages resolve.
+pub fn pep503_canonical_path(path: &str) -> Option<String> {
+    let mut segments: Vec<String> = path.split('/').map(String::from).collect();
+    let idx = segments.iter().position(|s| s == "simple")? + 1;
+    if idx == segments.len() {
+        return Some(format!("{}/", path));
+    }
+    let project = segments.get(idx).filter(|project| !project.is_empty())?;
+    let normalized = pep503_normalize(project);
+    let slash = idx + 1 == segments.len();
+    if &normalized == project && !slash {
+        return None;
+    }
+    segments[idx] = normalized;
+    if slash {
+        segments.push(String::new());
+    }
+    Some(segments.join("/"))
+}
+
+/// Cache key of the root PyPI simple index under the key of `simple`, which
+/// is also the directory of the project indexes
+pub const PYPI_ROOT_INDEX_KEY: &str = "index.html";
+
+/// Cache key of a directory listing of a `rewrite_dir_listing` rule under the
+/// key of the directory, which is also the directory of the files it lists
+pub const DIR_LISTING_KEY: &str = "index.html";
+
+/// Whether `path` is the root PyPI simple index (`.../simple/`) listing all
+/// projects
+pub fn is_pypi_root_index(path: &str) -> bool {
+    path.trim_end_matches('/').rsplit('/').next() == Some("simple")
+}
+
+/// `Content-Disposition` of a file served under `key`, named after the last
+/// segment of the key, e.g. `attachment; filename="flask-2.0-py3-none-any.whl"`
+pub fn content_disposition(key: &str) -> Option<String> {
+    let filename = key.rsplit('/').next().filter(|name| !name.is_empty())?;
+    let quoted = filename.replace('\\', "\\\\").replace('"', "\\\"");
+    Some(format!("attachment; filename=\"{}\"", quoted))
+}
+
+/// Translate a glob of cache keys to a regex. `*` and `?` match within a path
+/// segment, `**` matches across segments. A glob without `/` matches the last
+/// segment, e.g. `flask-*` matches `pypi/packages/ab/cd/flask-2.0.whl`.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from(if glob.contains('/') { "^" } else { "(^|/)" });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// The part of a query string identifying a cached entry according to
+/// `mode`, hashed to keep file names short and free of secrets. `None` if the
+/// query string is not part of the key.
+///
+/// Parameters are percent-decoded and encoded again, so `a=%41` and `a=A`
+/// are the same. Duplicate parameters are kept in order.
+pub fn query_key(query: &str, mode: &QueryMode) -> Option<String> {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+    match mode {
+        QueryMode::IgnoreQuery => return None,
+        QueryMode::IncludeQuery => {}
+        QueryMode::Whitelist(names) => {
+            params.retain(|(name, _)| names.contains(name));
+            // stable, so values of a duplicate parameter keep their order
+            params.sort_by(|a, b| a.0.cmp(&b.0));
         }
     }
+    if params.is_empty() {
+        return None;
+    }
+    let canonical = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", percent_encode(name), percent_encode(value)))
+        .collect::<Vec<String>>()
+        .join("&");
+    let digest = format!("{:x}", Sha256::digest(canonical.as_bytes()));
+    Some(format!("q-{}", &digest[..32]))
+}
+
+/// Decode `%XX` escapes, invalid escapes are kept as is
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escape all bytes except the unreserved characters of RFC 3986
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// The canonical form of a request path: duplicate slashes collapsed, escapes
+/// of unreserved characters decoded, e.g. `%7E` -> `~`, and the hex digits of
+/// other escapes in upper case, e.g. `%2f` -> `%2F`. A trailing slash is kept,
+/// whether it matters is up to the route.
+pub fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = match bytes.get(i..i + 3) {
+            Some([b'%', high, low]) if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                Some([*high, *low])
+            }
+            _ => None,
+        };
+        match escape {
+            Some(hex) => {
+                // two hex digits always parse
+                let byte = u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap();
+                if is_unreserved(byte) {
+                    normalized.push(byte);
+                } else {
+                    normalized.push(b'%');
+                    normalized.extend_from_slice(&hex.to_ascii_uppercase());
+                }
+                i += 3;
+            }
+            None => {
+                if bytes[i] != b'/' || normalized.last() != Some(&b'/') {
+                    normalized.push(bytes[i]);
+                }
+                i += 1;
+            }
+        }
+    }
+    // only ASCII escapes are replaced by ASCII characters
+    String::from_utf8(normalized).unwrap()
+}
+
+/// The unreserved characters of RFC 3986, never escaped in a canonical path
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Remove the parameter `name` from a query string. Returns the rest of the
+/// query, `None` if nothing is left, and whether the parameter is `1` or
+/// `true`.
+pub fn take_query_param(query: &str, name: &str) -> (Option<String>, bool) {
+    let mut set = false;
+    let rest: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            let (param_name, value) = param.split_once('=').unwrap_or((param, ""));
+            if percent_decode(param_name) != name {
+                return true;
+            }
+            set |= matches!(percent_decode(value).as_str(), "1" | "true");
+            false
+        })
+        .collect();
+    let rest = Some(rest.join("&")).filter(|rest| !rest.is_empty());
+    (rest, set)
+}
+
+/// Whether the `Cache-Control` or `Pragma` header of a request has the
+/// `no-cache` directive, i.e. the client wants a response fresh from the
+/// upstream
+pub fn requests_no_cache(cache_control: Option<&str>, pragma: Option<&str>) -> bool {
+    cache_control
+        .into_iter()
+        .chain(pragma)
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
 }
 
 pub fn sleep_ms(ms: u64) {
     std::thread::sleep(std::time::Duration::from_millis(ms));
 }
 
+/// The message of a panic payload, empty if it is not a string
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
 pub fn ivec_to_u64(ivec: &IVec) -> u64 {
     u64::from_be_bytes(ivec.as_ref().try_into().unwrap())
 }
@@ -52,6 +603,35 @@ pub fn u64_to_array(u: u64) -> [u8; 8] {
 mod tests {
     use super::*;
 
+    #[test]
+    fn decode_compressed_content() {
+        use std::io::Write;
+        let text = "<a href=\"https://files.pythonhosted.org/a.whl\">a.whl</a>";
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(text.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(
+            decode_content(&gzip, Some("gzip")).unwrap(),
+            text.as_bytes()
+        );
+        let zstd = zstd::stream::encode_all(text.as_bytes(), 0).unwrap();
+        assert_eq!(
+            decode_content(&zstd, Some("zstd")).unwrap(),
+            text.as_bytes()
+        );
+        let both = zstd::stream::encode_all(&gzip[..], 0).unwrap();
+        assert_eq!(
+            decode_content(&both, Some("gzip, zstd")).unwrap(),
+            text.as_bytes()
+        );
+        assert_eq!(
+            decode_content(text.as_bytes(), None).unwrap(),
+            text.as_bytes()
+        );
+        assert!(decode_content(text.as_bytes(), Some("gzip")).is_err());
+        assert!(decode_content(&gzip, Some("br")).is_err());
+    }
+
     #[test]
     fn enough_precision_for_key_usage() {
         let mut set = std::collections::HashSet::new();
@@ -61,9 +641,242 @@ mod tests {
         assert_eq!(set.len(), 100);
     }
 
+    #[test]
+    fn atime_strictly_increasing() {
+        let atimes: Vec<i64> = (0..1000).map(|_| atime_millis()).collect();
+        assert!(atimes.windows(2).all(|pair| pair[0] < pair[1]));
+        let atimes: Vec<i64> = (0..1000).map(|_| atime_nanos()).collect();
+        assert!(atimes.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
     #[test]
     fn ivec_u64_conversion() {
         let n: u64 = 233;
         assert_eq!(ivec_to_u64(&(&u64_to_array(n)).into()), n);
     }
+
+    #[test]
+    fn fnv1a_64_known_values() {
+        assert_eq!(fnv1a_64(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a_64(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn pep503_normalization() {
+        // the examples of PEP 503
+        for name in &[
+            "friendly-bard",
+            "Friendly-Bard",
+            "FRIENDLY-BARD",
+            "friendly.bard",
+            "friendly_bard",
+            "friendly--bard",
+            "FrIeNdLy-._.-bArD",
+        ] {
+            assert_eq!(pep503_normalize(name), "friendly-bard");
+        }
+    }
+
+    #[test]
+    fn pep503_canonical_simple_path() {
+        assert_eq!(
+            pep503_canonical_path("pypi/simple/Flask_Login/"),
+            Some("pypi/simple/flask-login/".to_string())
+        );
+        assert_eq!(pep503_canonical_path("pypi/simple/flask-login/"), None);
+        assert_eq!(
+            pep503_canonical_path("pypi/simple/Flask_Login"),
+            Some("pypi/simple/flask-login/".to_string())
+        );
+        assert_eq!(pep503_canonical_path("pypi/simple/"), None);
+        assert_eq!(
+            pep503_canonical_path("pypi/simple"),
+            Some("pypi/simple/".to_string())
+        );
+        assert_eq!(
+            pep503_canonical_path("pypi/packages/Flask-2.0.tar.gz"),
+            None
+        );
+    }
+
+    #[test]
+    fn pypi_root_index() {
+        assert!(is_pypi_root_index("pypi/simple/"));
+        assert!(is_pypi_root_index("pypi/simple"));
+        assert!(is_pypi_root_index("/simple/"));
+        assert!(!is_pypi_root_index("pypi/simple/flask/"));
+        assert!(!is_pypi_root_index("pypi/simpler/"));
+    }
+
+    #[test]
+    fn http_date_of_timestamp() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(1650000000), "Fri, 15 Apr 2022 05:20:00 GMT");
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let now = 1650000000;
+        let secs = |secs| Some(Duration::from_secs(secs));
+        assert_eq!(parse_retry_after("120", now), secs(120));
+        let date = "Fri, 15 Apr 2022 05:21:30 GMT";
+        assert_eq!(parse_retry_after(date, now), secs(90));
+        // a date in the past
+        assert_eq!(parse_retry_after(&http_date(now - 10), now), secs(0));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn parse_range_header() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some((0, 499)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,4-5", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn glob_to_regex_matches_keys() {
+        let re = regex::Regex::new(&glob_to_regex("flask-*")).unwrap();
+        assert!(re.is_match("pypi/packages/ab/cd/flask-2.0.whl"));
+        assert!(re.is_match("flask-2.0.whl"));
+        assert!(!re.is_match("pypi/packages/ab/cd/flask_login-0.5.whl"));
+        assert!(!re.is_match("pypi/simple/flask-login/index.html"));
+        let re = regex::Regex::new(&glob_to_regex("pypi/simple/*")).unwrap();
+        assert!(re.is_match("pypi/simple/flask"));
+        assert!(!re.is_match("pypi/simple/flask/index.html"));
+        assert!(!re.is_match("mirror/pypi/simple/flask"));
+        let re = regex::Regex::new(&glob_to_regex("anaconda/**/flask-?.*")).unwrap();
+        assert!(re.is_match("anaconda/pkgs/main/linux-64/flask-2.0.tar.bz2"));
+        assert!(!re.is_match("anaconda/pkgs/main/linux-64/flask-12.0.tar.bz2"));
+    }
+
+    #[test]
+    fn content_disposition_of_key() {
+        assert_eq!(
+            content_disposition("pypi/packages/ab/cd/flask-2.0-py3-none-any.whl").unwrap(),
+            "attachment; filename=\"flask-2.0-py3-none-any.whl\""
+        );
+        assert_eq!(
+            content_disposition("mock/a\"b\\c.zip").unwrap(),
+            "attachment; filename=\"a\\\"b\\\\c.zip\""
+        );
+        assert_eq!(content_disposition("pypi/simple/"), None);
+    }
+
+    #[test]
+    fn query_key_modes() {
+        let include = QueryMode::IncludeQuery;
+        assert_eq!(query_key("a=1&b=2", &QueryMode::IgnoreQuery), None);
+        let key = query_key("a=1&b=2", &include).unwrap();
+        assert!(key.starts_with("q-"));
+        assert_eq!(key.len(), 34);
+        assert!(!key.contains('='));
+        assert_ne!(query_key("a=1&b=3", &include), Some(key.clone()));
+        // the order of parameters is kept
+        assert_ne!(query_key("b=2&a=1", &include), Some(key));
+        assert_eq!(query_key("", &include), None);
+        assert_eq!(query_key("&", &include), None);
+    }
+
+    #[test]
+    fn query_key_whitelist() {
+        let whitelist = QueryMode::Whitelist(vec!["arch".to_string(), "v".to_string()]);
+        let key = query_key("v=1&arch=x86", &whitelist);
+        assert!(key.is_some());
+        // sorted by name, other parameters are dropped
+        assert_eq!(query_key("token=abc&arch=x86&v=1", &whitelist), key);
+        assert_eq!(query_key("token=abc", &whitelist), None);
+        // values of duplicate parameters keep their order
+        assert_eq!(
+            query_key("v=1&arch=x86&v=2", &whitelist),
+            query_key("arch=x86&v=1&v=2", &whitelist)
+        );
+        assert_ne!(
+            query_key("v=1&v=2", &whitelist),
+            query_key("v=2&v=1", &whitelist)
+        );
+        assert_ne!(
+            query_key("v=1&v=2", &whitelist),
+            query_key("v=1", &whitelist)
+        );
+    }
+
+    #[test]
+    fn query_key_percent_encoding() {
+        let include = QueryMode::IncludeQuery;
+        assert_eq!(query_key("a=%41", &include), query_key("a=A", &include));
+        assert_eq!(query_key("%61=A", &include), query_key("a=%41", &include));
+        // an encoded `&` is part of the value
+        assert_ne!(query_key("a=1%262", &include), query_key("a=1&2", &include));
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%2F"), "%zz/");
+        assert_eq!(percent_encode("a b/&"), "a%20b%2F%26");
+    }
+
+    #[test]
+    fn normalized_paths() {
+        for (path, normalized) in &[
+            ("/simple/flask/", "/simple/flask/"),
+            ("//simple///flask", "/simple/flask"),
+            ("/pkg/%7euser/%41%2d1", "/pkg/~user/A-1"),
+            ("/a%2fb%3a/%e4%b8%ad", "/a%2Fb%3A/%E4%B8%AD"),
+            // invalid escapes are kept as is
+            ("/100%/%zz/%4", "/100%/%zz/%4"),
+            ("/中文//包", "/中文/包"),
+        ] {
+            assert_eq!(normalize_path(path), *normalized, "{}", path);
+        }
+    }
+
+    #[test]
+    fn take_refresh_query_param() {
+        let take = |query| take_query_param(query, "no_cache");
+        assert_eq!(take("no_cache=1"), (None, true));
+        assert_eq!(
+            take("v=2&no_cache=true&a"),
+            (Some("v=2&a".to_string()), true)
+        );
+        assert_eq!(take("no%5Fcache=1&v=2"), (Some("v=2".to_string()), true));
+        // removed, but not set
+        assert_eq!(take("no_cache=0&v=2"), (Some("v=2".to_string()), false));
+        assert_eq!(
+            take("no_cache_x=1"),
+            (Some("no_cache_x=1".to_string()), false)
+        );
+    }
+
+    #[test]
+    fn no_cache_directives() {
+        assert!(requests_no_cache(Some("no-cache"), None));
+        assert!(requests_no_cache(Some("max-age=0, No-Cache"), None));
+        assert!(requests_no_cache(None, Some("no-cache")));
+        assert!(!requests_no_cache(Some("max-age=0"), None));
+        assert!(!requests_no_cache(Some("no-store"), Some("")));
+        assert!(!requests_no_cache(None, None));
+    }
+
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    #[tokio::test]
+    async fn https_upstream_with_ca_cert() {
+        use crate::test_util::{MockResponse, MockUpstream, TLS_CA_CERT};
+        let upstream = MockUpstream::start_tls();
+        upstream.mock("pkg.tgz", MockResponse::ok("pkg"));
+        let url = format!("{}pkg.tgz", upstream.url());
+        let client = build_client(&ClientConfig::new(HttpClient {
+            ca_cert: Some(TLS_CA_CERT.to_string()),
+            ..Default::default()
+        }))
+        .unwrap();
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.bytes().await.unwrap(), "pkg");
+        // the CA is not among the roots of the TLS stack
+        let client = build_client(&ClientConfig::new(HttpClient::default())).unwrap();
+        assert!(client.get(&url).send().await.is_err());
+        assert_eq!(upstream.hits("pkg.tgz"), 1);
+    }
 }
@@ -0,0 +1,16 @@
+#!/bin/sh
+# Certificates of the HTTPS mock upstream of tests, see
+# `MockUpstream::start_tls`: a CA, and a certificate of `localhost` it signs.
+# Valid for 100 years.
+set -e
+cd "$(dirname "$0")"
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=mirror-cache test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign" \
+    -keyout ca-key.pem -out ca.pem
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+    -keyout localhost-key.pem -out localhost.csr
+printf "basicConstraints=CA:FALSE\nkeyUsage=digitalSignature,keyEncipherment\n\
+extendedKeyUsage=serverAuth\nsubjectAltName=DNS:localhost\n" > localhost.ext
+openssl x509 -req -in localhost.csr -CA ca.pem -CAkey ca-key.pem -CAcreateserial -days 36500 \
+    -extfile localhost.ext -out localhost.pem
+rm -f ca-key.pem ca.srl localhost.csr localhost.ext